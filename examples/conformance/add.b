@@ -0,0 +1,2 @@
+Adds two digits
+,>,[-<+>]<------------------------------------------------.
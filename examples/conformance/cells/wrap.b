@@ -0,0 +1,2 @@
+Wraps below zero
+-.
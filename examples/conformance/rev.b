@@ -0,0 +1,2 @@
+Reverses its input
+>,[>,]<[.<]
@@ -0,0 +1,63 @@
+/* C interface to brainfuck-rs; see src/ffi.rs. Link against the library
+ * built by `cargo rustc --release --lib --crate-type cdylib`
+ * (libbrainfuck_rs.so, .dylib or .dll). */
+#ifndef BRAINFUCK_H
+#define BRAINFUCK_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BF_OK 0
+#define BF_ERR_NULL (-1)
+#define BF_ERR_PARSE (-2)
+#define BF_ERR_STEP_LIMIT (-3)
+#define BF_ERR_OUT_OF_BOUNDS (-4)
+#define BF_ERR_IO (-5)
+#define BF_ERR_ARG (-6)
+
+typedef struct BfProgram BfProgram;
+typedef struct BfInterp BfInterp;
+
+/* A static description of an error code. */
+const char *bf_strerror(int code);
+
+/* Parses and optimizes code; free *out with bf_program_free. */
+int bf_parse(const uint8_t *code, size_t len, BfProgram **out);
+/* The same at optimization level 0 to 3; bf_parse uses 2. */
+int bf_parse_level(const uint8_t *code, size_t len, int level, BfProgram **out);
+size_t bf_program_len(const BfProgram *program);
+void bf_program_free(BfProgram *program);
+
+/* Runs a program, storing its output in *out and *out_len unless the
+ * arguments or the program are invalid; free it with bf_free. A
+ * max_steps of 0 means no limit. */
+int bf_run_program(const BfProgram *program, const uint8_t *input,
+                   size_t input_len, uint64_t max_steps, uint8_t **out,
+                   size_t *out_len);
+int bf_run(const uint8_t *code, size_t code_len, const uint8_t *input,
+           size_t input_len, uint64_t max_steps, uint8_t **out,
+           size_t *out_len);
+void bf_free(uint8_t *buf, size_t len);
+
+/* Step-wise execution. Input is appended with bf_interp_feed, and `,` sees
+ * EOF once it is used up. Pass UINT64_MAX as steps to run to the end. The
+ * tape pointer is valid until the next run or bf_interp_free. */
+int bf_interp_new(const BfProgram *program, uint64_t max_steps, BfInterp **out);
+int bf_interp_feed(BfInterp *interp, const uint8_t *input, size_t len);
+int bf_interp_run_for(BfInterp *interp, uint64_t steps, int *finished);
+int bf_interp_take_output(BfInterp *interp, uint8_t **out, size_t *out_len);
+const uint8_t *bf_interp_tape(const BfInterp *interp, size_t *len);
+size_t bf_interp_ptr(const BfInterp *interp);
+size_t bf_interp_pc(const BfInterp *interp);
+uint64_t bf_interp_steps(const BfInterp *interp);
+void bf_interp_free(BfInterp *interp);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
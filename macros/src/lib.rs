@@ -0,0 +1,242 @@
+//! The `bf!` macro of brainfuck-rs, re-exported from there with its
+//! `macros` feature.
+//!
+//! Cargo doesn't allow the macro to depend on the crate that re-exports
+//! it, so this crate carries its own small parser, evaluator and code
+//! generator for plain brainfuck.
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Cells on the tape, as in the interpreter's default configuration.
+const TAPE_SIZE: usize = 30_000;
+
+/// How many ops a program without input may run at compile time.
+const MAX_STEPS: u64 = 10_000_000;
+
+/// Runs a brainfuck program while the crate using it compiles.
+///
+/// A program that doesn't read input is run to the end, and the macro
+/// expands to its output as a `&'static [u8; N]`:
+///
+/// ```ignore
+/// const HI: &[u8] = bf!("++++++++[>+++++++++<-]>.+.");
+/// assert_eq!(HI, b"HI");
+/// ```
+///
+/// A program with `,` expands to the program compiled to a Rust function
+/// `fn(&mut dyn Read, &mut dyn Write) -> io::Result<()>`, where `,` leaves
+/// the cell unchanged at the end of input:
+///
+/// ```ignore
+/// let cat = bf!(",[.[-],]");
+/// cat(&mut io::stdin(), &mut io::stdout())?;
+/// ```
+///
+/// Unmatched brackets, leaving the 30,000-cell tape and running for more
+/// than 10,000,000 ops without input are compile errors.
+#[proc_macro]
+pub fn bf(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(tokens) => tokens,
+        Err(message) => compile_error(&message),
+    }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, String> {
+    let ops = parse(source(input)?.as_bytes())?;
+    if ops.contains(&Op::Input) {
+        return generate(&ops)
+            .parse()
+            .map_err(|e| format!("bf!: generated invalid code: {}", e));
+    }
+    let output = eval(&ops)?;
+    Ok(TokenTree::from(Literal::byte_string(&output)).into())
+}
+
+/// The text of the string literal the macro was given. Escapes are left as
+/// they are, since none of them spell a command.
+fn source(input: TokenStream) -> Result<String, String> {
+    let mut tokens = input.into_iter();
+    let token = match (tokens.next(), tokens.next()) {
+        (Some(token), None) => token,
+        _ => return Err("bf! expects a single string literal".to_string()),
+    };
+    match token {
+        // Literals passed on by `macro_rules!` arrive in an invisible group.
+        TokenTree::Group(group) if group.delimiter() == Delimiter::None => source(group.stream()),
+        TokenTree::Literal(literal) => {
+            let repr = literal.to_string();
+            repr.trim_start_matches('r')
+                .trim_matches('#')
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .map(str::to_string)
+                .ok_or_else(|| "bf! expects a string literal".to_string())
+        }
+        _ => Err("bf! expects a string literal".to_string()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add(u8),
+    Move(isize),
+    Output,
+    Input,
+    /// `[`, with the index of its `]`.
+    Open(usize),
+    /// `]`, with the index of its `[`.
+    Close(usize),
+}
+
+/// Parses `code`, folding runs of `+-` and `<>`.
+fn parse(code: &[u8]) -> Result<Vec<Op>, String> {
+    let mut ops = Vec::new();
+    let mut open = Vec::new();
+    for (offset, &byte) in code.iter().enumerate() {
+        let op = match byte {
+            b'+' => Op::Add(1),
+            b'-' => Op::Add(u8::MAX),
+            b'>' => Op::Move(1),
+            b'<' => Op::Move(-1),
+            b'.' => Op::Output,
+            b',' => Op::Input,
+            b'[' => {
+                open.push(ops.len());
+                Op::Open(0)
+            }
+            b']' => {
+                let start = open
+                    .pop()
+                    .ok_or_else(|| format!("bf!: unmatched ']' at offset {}", offset))?;
+                ops[start] = Op::Open(ops.len());
+                Op::Close(start)
+            }
+            _ => continue,
+        };
+        match (ops.last_mut(), op) {
+            (Some(Op::Add(n)), Op::Add(m)) => *n = n.wrapping_add(m),
+            (Some(Op::Move(n)), Op::Move(m)) => *n += m,
+            _ => ops.push(op),
+        }
+    }
+    if !open.is_empty() {
+        return Err("bf!: unmatched '['".to_string());
+    }
+    Ok(ops)
+}
+
+/// Runs `ops`, which don't read input, and returns what they write.
+fn eval(ops: &[Op]) -> Result<Vec<u8>, String> {
+    let mut tape = vec![0u8; TAPE_SIZE];
+    let mut ptr = 0usize;
+    let mut output = Vec::new();
+    let mut pc = 0;
+    let mut steps = 0;
+    while pc < ops.len() {
+        steps += 1;
+        if steps > MAX_STEPS {
+            return Err(format!(
+                "bf!: the program ran for more than {} ops",
+                MAX_STEPS
+            ));
+        }
+        if let Op::Move(n) = ops[pc] {
+            ptr = ptr.wrapping_add_signed(n);
+            pc += 1;
+            continue;
+        }
+        let cell = tape
+            .get_mut(ptr)
+            .ok_or_else(|| format!("bf!: the program left the {}-cell tape", TAPE_SIZE))?;
+        match ops[pc] {
+            Op::Add(n) => *cell = cell.wrapping_add(n),
+            Op::Output => output.push(*cell),
+            Op::Open(end) if *cell == 0 => pc = end,
+            Op::Close(start) if *cell != 0 => pc = start,
+            _ => {}
+        }
+        pc += 1;
+    }
+    Ok(output)
+}
+
+/// `ops` as the body of a block that evaluates to a function running them.
+fn generate(ops: &[Op]) -> String {
+    let mut code = format!(
+        "{{ #[allow(unused_mut)] fn bf(input: &mut dyn ::std::io::Read, \
+         output: &mut dyn ::std::io::Write) -> ::std::io::Result<()> {{ \
+         let mut tape = [0u8; {}]; let mut p: usize = 0; ",
+        TAPE_SIZE
+    );
+    for op in ops {
+        let line = match *op {
+            Op::Add(n) => format!("tape[p] = tape[p].wrapping_add({});", n),
+            Op::Move(n) if n < 0 => format!("p = p.wrapping_sub({});", n.unsigned_abs()),
+            Op::Move(n) => format!("p = p.wrapping_add({});", n),
+            Op::Output => "output.write_all(&tape[p..=p])?;".to_string(),
+            Op::Input => "{ let mut byte = [0u8]; match input.read_exact(&mut byte) { \
+                          Ok(()) => tape[p] = byte[0], \
+                          Err(e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => {} \
+                          Err(e) => return Err(e), } }"
+                .to_string(),
+            Op::Open(_) => "while tape[p] != 0 {".to_string(),
+            Op::Close(_) => "}".to_string(),
+        };
+        code.push_str(&line);
+        code.push(' ');
+    }
+    code.push_str("Ok(()) } bf }");
+    code
+}
+
+/// `::core::compile_error!("message")`.
+fn compile_error(message: &str) -> TokenStream {
+    let span = Span::call_site();
+    let path = [
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("core", span)),
+        TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+        TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("compile_error", span)),
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenTree::Literal(Literal::string(message)).into(),
+        )),
+    ];
+    path.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        let ops = parse(b"++++++++[>+++++++++<-]>.+.").unwrap();
+        assert_eq!(ops[0], Op::Add(8));
+        assert_eq!(eval(&ops).unwrap(), b"HI");
+        assert!(
+            eval(&parse(b"<+").unwrap())
+                .unwrap_err()
+                .contains("left the")
+        );
+        assert!(
+            eval(&parse(b"+[]").unwrap())
+                .unwrap_err()
+                .contains("more than")
+        );
+        assert!(parse(b"[[]").is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let code = generate(&parse(b",[-<.]").unwrap());
+        assert!(code.contains(
+            "while tape[p] != 0 { tape[p] = tape[p].wrapping_add(255); \
+             p = p.wrapping_sub(1); output.write_all(&tape[p..=p])?; }"
+        ));
+    }
+}
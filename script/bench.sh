@@ -38,6 +38,10 @@ echo "Running Benchmark..."
 hyperfine --warmup 3 \
     --export-markdown bench_results.md \
     -n "Interpreter (bfi)" "./target/release/bfi examples/mandelbrot.bf" \
+    -n "Threaded code (bfi --engine threaded)" "./target/release/bfi --engine threaded examples/mandelbrot.bf" \
+    -n "Packed bytecode (bfi --engine packed)" "./target/release/bfi --engine packed examples/mandelbrot.bf" \
+    -n "Unchecked (bfi --unchecked)" "./target/release/bfi --unchecked examples/mandelbrot.bf" \
+    -n "JIT (bfi --jit)" "./target/release/bfi --jit examples/mandelbrot.bf" \
     -n "Compiler (bfc -> native)" "./target/mandelbrot_native"
 
 echo "Done! Results saved to bench_results.md"
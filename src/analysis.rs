@@ -0,0 +1,447 @@
+//! Proofs about a program without trusting a run of it: whether it ends,
+//! and which cells it can touch.
+//!
+//! Each question gets a [`Verdict`], and the verdicts are conservative: a
+//! proof holds for every run, on any input unless one was given, and what
+//! can't be shown either way is [`Unknown`](Verdict::Unknown).
+//!
+//! A program that never reads, or is analyzed for one input, is first run
+//! for a budget of ops; if it ends within it, that run answers both
+//! questions. Otherwise its ops are checked by shape, with the optimizer's
+//! known-value analysis. When every loop leaves the pointer where it found
+//! it, each op touches cells known ahead of time. A loop whose body adds
+//! the same odd amount to its cell each time round, with nothing else
+//! writing it, must end, since the cell wraps around through every value.
+
+use std::fmt;
+use std::io;
+use std::ops::RangeInclusive;
+
+use crate::Op;
+use crate::cell::{Cell, CellWidth};
+use crate::hooks::Hooks;
+use crate::interpreter::{BoundsPolicy, Config, Interpreter, RuntimeError};
+use crate::optimizer::Known;
+
+/// Ops [`analyze`] runs a program for before falling back to its shape,
+/// when no other budget is given.
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// The answer to one of the questions [`analyze`] asks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Proved,
+    Disproved,
+    Unknown,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Verdict::Proved => "proved",
+            Verdict::Disproved => "disproved",
+            Verdict::Unknown => "unknown",
+        })
+    }
+}
+
+/// What [`analyze`] found out about a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    /// Whether the program ends, if only with an error.
+    pub termination: Verdict,
+    /// Whether it stays on the tape: every cell it touches is one of the
+    /// config's `tape_size`.
+    pub bounds: Verdict,
+    /// The cells it may touch, counting from the first, when `bounds` is
+    /// proved. A tape of `cells.end() + 1` cells is enough for it.
+    pub cells: Option<RangeInclusive<isize>>,
+}
+
+/// Tries to prove that `ops` end and stay on the tape of `config`, on cells
+/// of `cell_width`. With `input`, the proofs are for that input only; a
+/// program that never reads is run either way. `fuel` is the ops to run it
+/// for, such as [`DEFAULT_FUEL`].
+///
+/// Only the tape size, the end-of-input behavior and the newline settings
+/// of `config` are used: runs stop at the edges of the tape whatever its
+/// bounds policy, since leaving it is what's being asked about.
+pub fn analyze(
+    ops: &[Op],
+    config: &Config,
+    cell_width: CellWidth,
+    input: Option<&[u8]>,
+    fuel: u64,
+) -> Analysis {
+    let reads = ops
+        .iter()
+        .any(|op| matches!(op, Op::Input | Op::InputAt(_)));
+    if input.is_some() || !reads {
+        let input = input.unwrap_or_default();
+        let ran = match cell_width {
+            CellWidth::U8 => run::<u8>(ops, config, input, fuel),
+            CellWidth::U16 => run::<u16>(ops, config, input, fuel),
+            CellWidth::U32 => run::<u32>(ops, config, input, fuel),
+        };
+        if let Some(analysis) = ran {
+            return analysis;
+        }
+    }
+    shape(ops, config.tape_size, cell_width)
+}
+
+/// Records the cells a run touches.
+struct Extent<'a> {
+    ops: &'a [Op],
+    cells: RangeInclusive<isize>,
+}
+
+impl Hooks for Extent<'_> {
+    fn on_step(&mut self, pc: usize, ptr: usize) {
+        if let Some(reach) = reach(&self.ops[pc]) {
+            cover(&mut self.cells, ptr as isize, reach);
+        }
+    }
+}
+
+/// Runs `ops` on `input` for up to `fuel` ops, and what that shows if it
+/// ended.
+fn run<C: Cell>(ops: &[Op], config: &Config, input: &[u8], fuel: u64) -> Option<Analysis> {
+    let config = Config {
+        bounds: BoundsPolicy::Error,
+        max_steps: Some(fuel),
+        timeout: None,
+        ..config.clone()
+    };
+    let mut interp = Interpreter::<_, _, C>::with_config(ops.to_vec(), input, io::sink(), &config);
+    let mut extent = Extent { ops, cells: 0..=0 };
+    let (bounds, cells) = match interp.run_hooked(&mut extent) {
+        Err(RuntimeError::StepLimitExceeded(_)) => return None,
+        Err(RuntimeError::PointerOutOfBounds { .. }) => (Verdict::Disproved, None),
+        // Any other error ends the run too, on the tape.
+        _ => (Verdict::Proved, Some(extent.cells)),
+    };
+    Some(Analysis {
+        termination: Verdict::Proved,
+        bounds,
+        cells,
+    })
+}
+
+/// What a loop does to its own cell each time round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LoopKind {
+    /// It must end, whatever it's entered with.
+    Counted,
+    /// It never ends once entered.
+    Endless,
+    Unknown,
+}
+
+/// What can be shown from the shape of `ops`, for any input.
+fn shape(ops: &[Op], tape_size: usize, cell_width: CellWidth) -> Analysis {
+    let unknown = Analysis {
+        termination: Verdict::Unknown,
+        bounds: Verdict::Unknown,
+        cells: None,
+    };
+    let Some(positions) = positions(ops) else {
+        return unknown;
+    };
+    let on_tape =
+        |cells: &RangeInclusive<isize>| *cells.start() >= 0 && (*cells.end() as usize) < tape_size;
+    let has_end = ops.contains(&Op::End);
+
+    let mut known = Known::at_start();
+    let mut cells = 0..=0;
+    let mut left_tape = false;
+    let mut endless = false;
+    let mut counted = true;
+    // Whether every run gets this far: each loop around here is known to
+    // be entered, each one before it to end, and nothing has ended the
+    // program.
+    let mut certain = true;
+    // The `Jnz` of each loop the walk is in, what kind it is, `certain`
+    // outside it, and whether it's known to be entered.
+    let mut loops: Vec<(usize, LoopKind, bool, bool)> = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let op = &ops[i];
+        if let Some(reach) = reach(op) {
+            let mut touched = positions[i]..=positions[i];
+            cover(&mut touched, positions[i], reach);
+            left_tape |= certain && !on_tape(&touched);
+            cover(&mut cells, 0, touched);
+        }
+        match *op {
+            Op::Jz(end) => {
+                // A loop whose cell is known to be zero never runs, so
+                // what's in it doesn't matter.
+                if known.get(0) == Some(0) {
+                    i = end + 1;
+                    continue;
+                }
+                let kind = classify(ops, &positions, i, end, cell_width);
+                // Reached with a non-zero cell, it's entered, and an error
+                // or `@` before it is the only way out. It's reached unless
+                // a loop before it doesn't end, which does just as well.
+                let entered = known.get(0).is_some();
+                endless |= kind == LoopKind::Endless
+                    && entered
+                    && loops.iter().all(|&(.., entered)| entered)
+                    && !has_end
+                    && on_tape(&cells);
+                counted &= kind == LoopKind::Counted;
+                loops.push((end, kind, certain, entered));
+                certain &= entered;
+            }
+            Op::Jnz(_) => {
+                if let Some((_, kind, outside, _)) = loops.pop() {
+                    certain = outside && kind == LoopKind::Counted;
+                }
+            }
+            Op::End => certain = false,
+            _ => {}
+        }
+        known.apply(op);
+        i += 1;
+    }
+
+    let bounds = match on_tape(&cells) {
+        true => Verdict::Proved,
+        false if left_tape => Verdict::Disproved,
+        false => Verdict::Unknown,
+    };
+    let termination = match (endless, counted) {
+        (true, _) => Verdict::Disproved,
+        (false, true) => Verdict::Proved,
+        (false, false) => Verdict::Unknown,
+    };
+    Analysis {
+        termination,
+        bounds,
+        cells: (bounds == Verdict::Proved).then_some(cells),
+    }
+}
+
+/// Where the pointer is before each op, counting from the first cell, if
+/// that is the same on every run: every loop leaves the pointer where it
+/// found it, and nothing moves it in a way that depends on the tape.
+fn positions(ops: &[Op]) -> Option<Vec<isize>> {
+    let mut positions = Vec::with_capacity(ops.len());
+    let mut ptr = 0;
+    let mut loops = Vec::new();
+    for op in ops {
+        positions.push(ptr);
+        match *op {
+            Op::PtrAdd(n) => ptr += n,
+            Op::Jz(_) => loops.push(ptr),
+            Op::Jnz(_) if loops.pop() != Some(ptr) => return None,
+            Op::ScanLeft
+            | Op::ScanRight
+            | Op::Scan(_)
+            | Op::ClearUntilZero(_)
+            | Op::DefProc(_)
+            | Op::EndProc
+            | Op::CallProc
+            | Op::Fork
+            | Op::SwitchTape(_) => return None,
+            _ => {}
+        }
+    }
+    Some(positions)
+}
+
+/// Whether the loop from the `Jz` at `start` to the `Jnz` at `end` must
+/// end, or never does once entered, from what its body does to its cell.
+fn classify(
+    ops: &[Op],
+    positions: &[isize],
+    start: usize,
+    end: usize,
+    cell_width: CellWidth,
+) -> LoopKind {
+    /// What the body has done to the loop's cell so far.
+    enum Effect {
+        Add(u32),
+        Set(u32),
+    }
+    let cell = positions[start];
+    let mut effect = Effect::Add(0);
+    let mut depth = 0;
+    for i in start + 1..end {
+        let offset = cell - positions[i];
+        let op = &ops[i];
+        match *op {
+            Op::Jz(_) => depth += 1,
+            Op::Jnz(_) => depth -= 1,
+            _ => {}
+        }
+        if !writes(op, offset) {
+            continue;
+        }
+        let delta = match op {
+            Op::ValAdd(_, n) => Some(*n),
+            Op::ValSub(_, n) => Some(n.wrapping_neg()),
+            Op::BulkAdd(deltas) => Some(
+                deltas
+                    .iter()
+                    .filter(|&&(o, _)| o == offset)
+                    .fold(0u32, |sum, &(_, n)| sum.wrapping_add(n)),
+            ),
+            _ => None,
+        };
+        effect = match (op, delta, effect) {
+            _ if depth > 0 => return LoopKind::Unknown,
+            (_, Some(n), Effect::Add(sum)) => Effect::Add(sum.wrapping_add(n)),
+            (_, Some(n), Effect::Set(value)) => Effect::Set(value.wrapping_add(n)),
+            (Op::Clear(_) | Op::BulkClear(_) | Op::ClearRange(..), ..) => Effect::Set(0),
+            (Op::Set(_, value), ..) => Effect::Set(*value),
+            _ => return LoopKind::Unknown,
+        };
+    }
+    match effect {
+        Effect::Add(sum) if sum % 2 == 1 => LoopKind::Counted,
+        Effect::Add(sum) if cell_width.truncate(sum) == 0 => LoopKind::Endless,
+        Effect::Set(value) if cell_width.truncate(value) == 0 => LoopKind::Counted,
+        Effect::Set(_) => LoopKind::Endless,
+        Effect::Add(_) => LoopKind::Unknown,
+    }
+}
+
+/// Whether `op` may change the cell `offset` away from the pointer.
+pub(crate) fn writes(op: &Op, offset: isize) -> bool {
+    match op {
+        Op::ValAdd(o, _)
+        | Op::ValSub(o, _)
+        | Op::Clear(o)
+        | Op::Set(o, _)
+        | Op::MulAdd(o, _)
+        | Op::AddTo(o)
+        | Op::SubFrom(o)
+        | Op::InputAt(o) => *o == offset,
+        Op::BulkAdd(deltas) => deltas.iter().any(|&(o, _)| o == offset),
+        Op::Transfer(targets) => offset == 0 || targets.iter().any(|&(o, _)| o == offset),
+        Op::BulkClear(offsets) => offsets.contains(&offset),
+        Op::ClearRange(o, n) => (*o..*o + *n as isize).contains(&offset),
+        Op::Input | Op::Random | Op::Retrieve | Op::Bitwise(_) => offset == 0,
+        Op::DivMod => (0..=6).contains(&offset),
+        _ => false,
+    }
+}
+
+/// The cells `op` touches, relative to the pointer, if any.
+pub(crate) fn reach(op: &Op) -> Option<RangeInclusive<isize>> {
+    match op {
+        Op::PtrAdd(_)
+        | Op::OutputConst(_)
+        | Op::Debug
+        | Op::End
+        | Op::DefProc(_)
+        | Op::EndProc
+        | Op::SwitchTape(_) => None,
+        Op::ValAdd(o, _)
+        | Op::ValSub(o, _)
+        | Op::Clear(o)
+        | Op::Set(o, _)
+        | Op::InputAt(o)
+        | Op::OutputAt(o)
+        | Op::OutputRepeat(o, _) => Some(*o..=*o),
+        Op::MulAdd(o, _) | Op::AddTo(o) | Op::SubFrom(o) => Some((*o).min(0)..=(*o).max(0)),
+        Op::BulkAdd(deltas) => span(deltas.iter().map(|&(o, _)| o)),
+        Op::Transfer(targets) => span(targets.iter().map(|&(o, _)| o).chain([0])),
+        Op::BulkClear(offsets) => span(offsets.iter().copied()),
+        Op::ClearRange(o, n) => span([*o, *o + *n as isize - 1]),
+        // It only does anything when these are all on the tape.
+        Op::DivMod => Some(0..=6),
+        _ => Some(0..=0),
+    }
+}
+
+/// The smallest range holding every one of `offsets`, if there are any.
+fn span(offsets: impl IntoIterator<Item = isize>) -> Option<RangeInclusive<isize>> {
+    offsets.into_iter().fold(None, |span, o| match span {
+        Some(span) => Some((*span.start()).min(o)..=(*span.end()).max(o)),
+        None => Some(o..=o),
+    })
+}
+
+/// Widens `cells` to take in `reach`, relative to `at`.
+fn cover(cells: &mut RangeInclusive<isize>, at: isize, reach: RangeInclusive<isize>) {
+    let start = (*cells.start()).min(at + reach.start());
+    let end = (*cells.end()).max(at + reach.end());
+    *cells = start..=end;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimize, parse};
+
+    fn analysis(
+        source: &[u8],
+        input: Option<&[u8]>,
+        fuel: u64,
+    ) -> (Verdict, Verdict, Option<RangeInclusive<isize>>) {
+        let ops = optimize(parse(source).unwrap());
+        let config = Config {
+            tape_size: 100,
+            ..Config::default()
+        };
+        let analysis = analyze(&ops, &config, CellWidth::U8, input, fuel);
+        (analysis.termination, analysis.bounds, analysis.cells)
+    }
+
+    #[test]
+    fn test_analyze() {
+        use Verdict::*;
+        // Runs that end answer both questions.
+        assert_eq!(
+            analysis(b"++++++++[>++++++++<-]>+.", None, DEFAULT_FUEL),
+            (Proved, Proved, Some(0..=1))
+        );
+        assert_eq!(
+            analysis(b">>>+[>+]", None, DEFAULT_FUEL),
+            (Proved, Disproved, None)
+        );
+        assert_eq!(
+            analysis(b",[>,]", Some(b"abc"), DEFAULT_FUEL),
+            (Proved, Proved, Some(0..=3))
+        );
+
+        // For any input, counted loops end, and balanced ones keep to
+        // cells known ahead of time.
+        assert_eq!(
+            analysis(b",[->+>,[-<+>]<<]>>.", None, DEFAULT_FUEL),
+            (Proved, Proved, Some(0..=2))
+        );
+        assert_eq!(
+            analysis(b",[>+<--]", None, DEFAULT_FUEL),
+            (Unknown, Proved, Some(0..=1))
+        );
+        assert_eq!(
+            analysis(b",[>,]", None, DEFAULT_FUEL),
+            (Unknown, Unknown, None)
+        );
+        assert_eq!(
+            analysis(b",[>+<-]<<.", None, DEFAULT_FUEL),
+            (Proved, Disproved, None)
+        );
+        // A loop entered on a cell its body never changes doesn't end, but
+        // one that may not be entered could.
+        assert_eq!(
+            analysis(b",>+[<.>]", None, DEFAULT_FUEL),
+            (Disproved, Proved, Some(0..=1))
+        );
+        assert_eq!(analysis(b",[[-]+[.]]", None, DEFAULT_FUEL).0, Unknown);
+        // A run out of fuel leaves it to the shape.
+        assert_eq!(
+            analysis(b"+[>+<+]", None, 10),
+            (Proved, Proved, Some(0..=1))
+        );
+        assert_eq!(
+            analysis(b"+[>+<++]", None, 10),
+            (Unknown, Proved, Some(0..=1))
+        );
+    }
+}
@@ -0,0 +1,472 @@
+//! A small assembly language for writing brainfuck by hand, which
+//! [`assemble`] turns into plain brainfuck for [`parse`](crate::parse) and
+//! the rest of the toolchain.
+//!
+//! ```text
+//! # Comments run to the end of the line.
+//! cell count 0             # names cell 0 'count'
+//! cell sum 1
+//! macro clear { [-] }      # a macro, expanded where it's named
+//! @count +*10              # moves to 'count' and adds 10
+//! [ @sum +*2 @count - ]    # doubles it into 'sum'
+//! @sum .  clear*2          # a macro can be repeated too
+//! include "lib.bfa"        # assembles another file in place
+//! ```
+//!
+//! The eight commands stand for themselves, and any of them or a macro's
+//! name can be followed by `*N` to repeat it N times. Everything else is a
+//! name, so text can't be left around as comments the way brainfuck
+//! allows. `@name` moves to a named cell from wherever the pointer is,
+//! which the assembler follows through `>` and `<` and across loops that
+//! end where they started; after a loop that doesn't, the position is
+//! lost until the program ends.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Why [`assemble`] failed, and where: a 1-based `line` of `file`, the
+/// name a source was assembled or included under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+/// What an [`AsmError`] is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    /// A character that starts nothing in the language.
+    Unexpected(char),
+    /// Something else was there, such as a keyword without its operands.
+    Expected(&'static str),
+    /// A name that isn't a macro, or after `@`, a cell.
+    UnknownName(String),
+    /// A cell or macro named twice.
+    Redefined(String),
+    /// `@name` after a loop that may move the pointer.
+    UnknownPosition(String),
+    /// A macro or include that ends up expanding itself.
+    Recursive(String),
+    /// A `[` without a `]`.
+    UnmatchedOpen,
+    /// A `]` without a `[`.
+    UnmatchedClose,
+    /// An included file couldn't be read.
+    Include { path: String, message: String },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.file, self.line)?;
+        match &self.kind {
+            AsmErrorKind::Unexpected(c) => write!(f, "unexpected {:?}", c),
+            AsmErrorKind::Expected(what) => write!(f, "expected {}", what),
+            AsmErrorKind::UnknownName(name) => write!(f, "'{}' isn't defined", name),
+            AsmErrorKind::Redefined(name) => write!(f, "'{}' is already defined", name),
+            AsmErrorKind::UnknownPosition(name) => write!(
+                f,
+                "can't move to '{}': a loop before it may leave the pointer anywhere",
+                name
+            ),
+            AsmErrorKind::Recursive(name) => write!(f, "'{}' expands itself", name),
+            AsmErrorKind::UnmatchedOpen => write!(f, "unmatched '['"),
+            AsmErrorKind::UnmatchedClose => write!(f, "unmatched ']'"),
+            AsmErrorKind::Include { path, message } => write!(f, "{}: {}", path, message),
+        }
+    }
+}
+
+impl core::error::Error for AsmError {}
+
+/// Assembles `source`, called `name` in errors, into brainfuck. `include`
+/// gives the text of each file an `include` names, or why it couldn't.
+pub fn assemble(
+    name: &str,
+    source: &str,
+    include: &mut dyn FnMut(&str) -> Result<String, String>,
+) -> Result<Vec<u8>, AsmError> {
+    let mut asm = Assembler {
+        include,
+        cells: BTreeMap::new(),
+        macros: BTreeMap::new(),
+        expanding: Vec::new(),
+        out: Vec::new(),
+        ptr: Some(0),
+        loops: Vec::new(),
+    };
+    asm.run(name, 1, source)?;
+    match asm.loops.pop() {
+        Some((_, file, line)) => Err(AsmError {
+            file,
+            line,
+            kind: AsmErrorKind::UnmatchedOpen,
+        }),
+        None => Ok(asm.out),
+    }
+}
+
+/// A macro's body, with where it was defined for errors in it.
+#[derive(Debug, Clone)]
+struct Macro {
+    file: String,
+    line: usize,
+    body: String,
+}
+
+struct Assembler<'a> {
+    include: &'a mut dyn FnMut(&str) -> Result<String, String>,
+    cells: BTreeMap<String, isize>,
+    macros: BTreeMap<String, Macro>,
+    /// The macros and files being expanded, outermost first.
+    expanding: Vec<String>,
+    out: Vec<u8>,
+    /// Where the pointer is, while that's known.
+    ptr: Option<isize>,
+    /// Where the pointer was at each open `[`, and where the `[` is.
+    loops: Vec<(Option<isize>, String, usize)>,
+}
+
+/// A place in one source: its text and how far into it the assembler is.
+struct Cursor<'s> {
+    file: &'s str,
+    text: &'s str,
+    at: usize,
+    line: usize,
+}
+
+impl Cursor<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.at).copied()
+    }
+
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        AsmError {
+            file: self.file.to_string(),
+            line: self.line,
+            kind,
+        }
+    }
+
+    /// Skips whitespace and comments.
+    fn skip_blank(&mut self) {
+        while let Some(b) = self.peek() {
+            match b {
+                b'\n' => self.line += 1,
+                b'#' => {
+                    while self.peek().is_some_and(|b| b != b'\n') {
+                        self.at += 1;
+                    }
+                    continue;
+                }
+                _ if b.is_ascii_whitespace() => {}
+                _ => return,
+            }
+            self.at += 1;
+        }
+    }
+
+    /// A name, if one starts here.
+    fn word(&mut self) -> Option<&str> {
+        let start = self.at;
+        while self
+            .peek()
+            .is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_')
+        {
+            self.at += 1;
+        }
+        (self.at > start).then(|| &self.text[start..self.at])
+    }
+
+    /// The name after blanks, or an error expecting `what`.
+    fn expect_word(&mut self, what: &'static str) -> Result<String, AsmError> {
+        self.skip_blank();
+        match self.peek() {
+            Some(b) if b.is_ascii_alphabetic() || b == b'_' => {
+                Ok(self.word().unwrap_or_default().to_string())
+            }
+            _ => Err(self.error(AsmErrorKind::Expected(what))),
+        }
+    }
+
+    /// A number, if one starts here.
+    fn number(&mut self) -> Option<usize> {
+        let start = self.at;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.at += 1;
+        }
+        self.text[start..self.at].parse().ok()
+    }
+
+    /// The count of a `*N` after what was just read, or 1 without one.
+    fn repeat(&mut self) -> Result<usize, AsmError> {
+        if self.peek() != Some(b'*') {
+            return Ok(1);
+        }
+        self.at += 1;
+        self.number()
+            .ok_or_else(|| self.error(AsmErrorKind::Expected("a count after '*'")))
+    }
+}
+
+impl Assembler<'_> {
+    /// Assembles `text`, which starts on `line` of `file`.
+    fn run(&mut self, file: &str, line: usize, text: &str) -> Result<(), AsmError> {
+        let mut cursor = Cursor {
+            file,
+            text,
+            at: 0,
+            line,
+        };
+        loop {
+            cursor.skip_blank();
+            let Some(b) = cursor.peek() else {
+                return Ok(());
+            };
+            match b {
+                b'+' | b'-' | b'<' | b'>' | b'[' | b']' | b'.' | b',' => {
+                    cursor.at += 1;
+                    for _ in 0..cursor.repeat()? {
+                        self.emit(b, &cursor)?;
+                    }
+                }
+                b'@' => {
+                    cursor.at += 1;
+                    let Some(name) = cursor.word().map(str::to_string) else {
+                        return Err(cursor.error(AsmErrorKind::Expected("a cell name after '@'")));
+                    };
+                    self.goto(&name, &cursor)?;
+                }
+                _ if b.is_ascii_alphabetic() || b == b'_' => {
+                    let word = cursor.word().unwrap_or_default().to_string();
+                    match word.as_str() {
+                        "cell" => self.define_cell(&mut cursor)?,
+                        "macro" => self.define_macro(&mut cursor)?,
+                        "include" => self.include(&mut cursor)?,
+                        _ => {
+                            for _ in 0..cursor.repeat()? {
+                                self.expand(&word, &cursor)?;
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    let c = cursor.text[cursor.at..].chars().next().unwrap_or('\0');
+                    return Err(cursor.error(AsmErrorKind::Unexpected(c)));
+                }
+            }
+        }
+    }
+
+    fn emit(&mut self, command: u8, cursor: &Cursor) -> Result<(), AsmError> {
+        match command {
+            b'>' => self.ptr = self.ptr.map(|ptr| ptr + 1),
+            b'<' => self.ptr = self.ptr.map(|ptr| ptr - 1),
+            b'[' => self
+                .loops
+                .push((self.ptr, cursor.file.to_string(), cursor.line)),
+            b']' => {
+                let (start, _, _) = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| cursor.error(AsmErrorKind::UnmatchedClose))?;
+                if start != self.ptr {
+                    self.ptr = None;
+                }
+            }
+            _ => {}
+        }
+        self.out.push(command);
+        Ok(())
+    }
+
+    /// `@name`: moves to the cell `name`.
+    fn goto(&mut self, name: &str, cursor: &Cursor) -> Result<(), AsmError> {
+        let &cell = self
+            .cells
+            .get(name)
+            .ok_or_else(|| cursor.error(AsmErrorKind::UnknownName(name.to_string())))?;
+        let ptr = self
+            .ptr
+            .ok_or_else(|| cursor.error(AsmErrorKind::UnknownPosition(name.to_string())))?;
+        let command = if cell > ptr { b'>' } else { b'<' };
+        for _ in 0..cell.abs_diff(ptr) {
+            self.emit(command, cursor)?;
+        }
+        Ok(())
+    }
+
+    /// `cell NAME N`.
+    fn define_cell(&mut self, cursor: &mut Cursor) -> Result<(), AsmError> {
+        let name = cursor.expect_word("a cell name after 'cell'")?;
+        cursor.skip_blank();
+        let cell = cursor
+            .number()
+            .and_then(|n| isize::try_from(n).ok())
+            .ok_or_else(|| cursor.error(AsmErrorKind::Expected("a cell number")))?;
+        if self.cells.insert(name.clone(), cell).is_some() {
+            return Err(cursor.error(AsmErrorKind::Redefined(name)));
+        }
+        Ok(())
+    }
+
+    /// `macro NAME { BODY }`, with any braces in the body matched.
+    fn define_macro(&mut self, cursor: &mut Cursor) -> Result<(), AsmError> {
+        let name = cursor.expect_word("a macro name after 'macro'")?;
+        cursor.skip_blank();
+        if cursor.peek() != Some(b'{') {
+            return Err(cursor.error(AsmErrorKind::Expected("'{' before the macro's body")));
+        }
+        cursor.at += 1;
+        let (start, line) = (cursor.at, cursor.line);
+        let mut depth = 1;
+        while depth > 0 {
+            match cursor.peek() {
+                Some(b'{') => depth += 1,
+                Some(b'}') => depth -= 1,
+                Some(b'\n') => cursor.line += 1,
+                Some(_) => {}
+                None => return Err(cursor.error(AsmErrorKind::Expected("'}' after the body"))),
+            }
+            cursor.at += 1;
+        }
+        let body = Macro {
+            file: cursor.file.to_string(),
+            line,
+            body: cursor.text[start..cursor.at - 1].to_string(),
+        };
+        if self.macros.insert(name.clone(), body).is_some() {
+            return Err(cursor.error(AsmErrorKind::Redefined(name)));
+        }
+        Ok(())
+    }
+
+    /// `include "PATH"`.
+    fn include(&mut self, cursor: &mut Cursor) -> Result<(), AsmError> {
+        cursor.skip_blank();
+        let expected = || AsmErrorKind::Expected("a quoted path after 'include'");
+        if cursor.peek() != Some(b'"') {
+            return Err(cursor.error(expected()));
+        }
+        let rest = &cursor.text[cursor.at + 1..];
+        let len = rest
+            .find(['"', '\n'])
+            .filter(|&len| rest[len..].starts_with('"'))
+            .ok_or_else(|| cursor.error(expected()))?;
+        let path = rest[..len].to_string();
+        cursor.at += len + 2;
+        if self.expanding.contains(&path) || path == cursor.file {
+            return Err(cursor.error(AsmErrorKind::Recursive(path)));
+        }
+        let text = (self.include)(&path).map_err(|message| {
+            cursor.error(AsmErrorKind::Include {
+                path: path.clone(),
+                message,
+            })
+        })?;
+        self.expanding.push(path.clone());
+        let result = self.run(&path, 1, &text);
+        self.expanding.pop();
+        result
+    }
+
+    /// A use of the macro `name`.
+    fn expand(&mut self, name: &str, cursor: &Cursor) -> Result<(), AsmError> {
+        let Some(body) = self.macros.get(name).cloned() else {
+            return Err(cursor.error(AsmErrorKind::UnknownName(name.to_string())));
+        };
+        if self.expanding.iter().any(|expanding| expanding == name) {
+            return Err(cursor.error(AsmErrorKind::Recursive(name.to_string())));
+        }
+        self.expanding.push(name.to_string());
+        let result = self.run(&body.file, body.line, &body.body);
+        self.expanding.pop();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    fn asm(source: &str) -> Result<Vec<u8>, AsmErrorKind> {
+        let mut files = BTreeMap::new();
+        files.insert("lib.bfa", "macro clear { [-] }\ncell tmp 2\n");
+        files.insert("loop.bfa", "include \"loop.bfa\"");
+        let mut include = |path: &str| match files.get(path) {
+            Some(text) => Ok(text.to_string()),
+            None => Err("not found".to_string()),
+        };
+        assemble("main.bfa", source, &mut include).map_err(|e| e.kind)
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "\
+# Doubles 10 into 'sum'.
+cell count 0
+cell sum 1
+include \"lib.bfa\"
+macro double { [ @sum +*2 @count - ] }
+@count +*10 double
+@tmp clear*2 @sum .
+";
+        assert_eq!(
+            asm(source).unwrap(),
+            b"++++++++++[>++<-]>>[-][-]<.".to_vec()
+        );
+        let ops = crate::parse(&asm(source).unwrap()).unwrap();
+        let mut out = vec![];
+        crate::exec::run(
+            &ops,
+            &mut [0u8; 4],
+            crate::BoundsPolicy::Error,
+            crate::EofBehavior::Unchanged,
+            || None,
+            |b| out.push(b),
+        )
+        .unwrap();
+        assert_eq!(out, [20]);
+
+        // A loop that ends where it started keeps the position.
+        assert_eq!(
+            asm("cell a 3 [>] @a").unwrap_err(),
+            AsmErrorKind::UnknownPosition("a".into())
+        );
+        assert_eq!(asm("cell a 3 [>+<-] @a").unwrap(), b"[>+<-]>>>");
+        assert_eq!(asm("+*0 >*3").unwrap(), b">>>");
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error = assemble("main.bfa", "+\n\nfoo", &mut |_| Err(String::new())).unwrap_err();
+        assert_eq!(error.to_string(), "main.bfa:3: 'foo' isn't defined");
+        assert_eq!(asm("[[]").unwrap_err(), AsmErrorKind::UnmatchedOpen);
+        assert_eq!(asm("]").unwrap_err(), AsmErrorKind::UnmatchedClose);
+        assert_eq!(asm("+ ;").unwrap_err(), AsmErrorKind::Unexpected(';'));
+        assert_eq!(
+            asm("+*").unwrap_err(),
+            AsmErrorKind::Expected("a count after '*'")
+        );
+        assert_eq!(
+            asm("macro m { m }\nm").unwrap_err(),
+            AsmErrorKind::Recursive("m".into())
+        );
+        assert_eq!(
+            asm("include \"loop.bfa\"").unwrap_err(),
+            AsmErrorKind::Recursive("loop.bfa".into())
+        );
+        assert_eq!(
+            asm("cell a 1 cell a 2").unwrap_err(),
+            AsmErrorKind::Redefined("a".into())
+        );
+        assert!(matches!(
+            asm("include \"nope.bfa\"").unwrap_err(),
+            AsmErrorKind::Include { .. }
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+//! An interpreter whose `,` and `.` await non-blocking I/O, so a server can
+//! run a program per connection without giving each one a thread.
+//!
+//! The program runs on the ordinary [`Interpreter`] a slice of ops at a
+//! time, the way [`Playground`](crate::playground::Playground) drives it:
+//! output collects in memory and goes to the writer after every slice, and
+//! a `,` with no input left stops the slice until the reader has more.
+//!
+//! The reader and writer are the `futures-io` [`AsyncRead`] and
+//! [`AsyncWrite`], re-exported here. A tokio stream gets them by wrapping
+//! it with `tokio_util::compat`.
+
+use std::future::{self, Future};
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::Poll;
+
+pub use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::playground::Feed;
+use crate::{Cell, Config, Interpreter, Op, RuntimeError, StepResult};
+
+/// Ops run between chances for other tasks to go.
+const SLICE: u64 = 1 << 16;
+
+/// An [`Interpreter`] reading from `R` and writing to `W` asynchronously.
+pub struct AsyncInterpreter<R, W, C = u8> {
+    interp: Interpreter<Feed, Vec<u8>, C>,
+    input: R,
+    output: W,
+}
+
+impl<R, W, C> AsyncInterpreter<R, W, C>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    C: Cell,
+{
+    pub fn with_config(ops: Vec<Op>, input: R, output: W, config: &Config) -> Self {
+        AsyncInterpreter {
+            interp: Interpreter::with_config(ops, Feed::default(), Vec::new(), config),
+            input,
+            output,
+        }
+    }
+
+    /// Runs from the current instruction until the program ends. Steps
+    /// count against [`Config::max_steps`]; [`Config::timeout`] doesn't
+    /// apply, since the time spent waiting for I/O isn't the program's.
+    ///
+    /// The output written before a failure is still sent, and the machine
+    /// state is left where the run stopped, as with [`Interpreter::run`].
+    pub async fn run(&mut self) -> Result<(), RuntimeError> {
+        let mut buf = [0; 4096];
+        loop {
+            let result = self.interp.run_steps(SLICE);
+            self.send().await?;
+            match result {
+                StepResult::Running => yield_now().await,
+                StepResult::NeedsInput => {
+                    // A prompt should show before the program waits on it.
+                    future::poll_fn(|cx| Pin::new(&mut self.output).poll_flush(cx)).await?;
+                    let n = future::poll_fn(|cx| Pin::new(&mut self.input).poll_read(cx, &mut buf))
+                        .await?;
+                    let feed = self.interp.io_mut().0;
+                    if n == 0 {
+                        feed.closed = true;
+                    } else {
+                        feed.buf.extend(&buf[..n]);
+                    }
+                }
+                StepResult::Finished => {
+                    future::poll_fn(|cx| Pin::new(&mut self.output).poll_flush(cx)).await?;
+                    return Ok(());
+                }
+                StepResult::Error(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Writes the output collected so far.
+    async fn send(&mut self) -> io::Result<()> {
+        let pending = mem::take(self.interp.io_mut().1);
+        let mut rest = &pending[..];
+        while !rest.is_empty() {
+            let n = future::poll_fn(|cx| Pin::new(&mut self.output).poll_write(cx, rest)).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            rest = &rest[n..];
+        }
+        Ok(())
+    }
+
+    pub fn tape(&self) -> &[C] {
+        self.interp.tape()
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.interp.ptr()
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.interp.steps()
+    }
+
+    /// Returns the I/O handles. Input the program was sent but didn't read
+    /// is lost.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncInterpreter<R, W> {
+    pub fn new(ops: Vec<Op>, input: R, output: W) -> Self {
+        Self::with_config(ops, input, output, &Config::default())
+    }
+}
+
+/// Returns `Pending` once, so the executor can run other tasks.
+fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    future::poll_fn(move |cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EofBehavior, optimize, parse};
+    use std::task::{Context, Waker};
+
+    /// Polls `fut` to the end, counting how often it was pending.
+    fn block_on<F: Future>(fut: F) -> (F::Output, usize) {
+        let mut fut = std::pin::pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pending = 0;
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return (out, pending);
+            }
+            pending += 1;
+        }
+    }
+
+    /// Has nothing to read every other time it's asked, and otherwise the
+    /// next byte.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = self.data.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn test_async_interpreter() {
+        let config = Config {
+            eof: EofBehavior::Zero,
+            ..Config::default()
+        };
+        let ops = optimize(parse(b",[.,]").unwrap());
+        let input = Trickle {
+            data: b"abc",
+            ready: false,
+        };
+        let mut interp = AsyncInterpreter::<_, _, u8>::with_config(ops, input, Vec::new(), &config);
+        let (result, pending) = block_on(interp.run());
+        result.unwrap();
+        // Once per byte and once for the end of input.
+        assert_eq!(pending, 4);
+        assert_eq!(interp.into_inner().1, b"abc");
+
+        // Output written before a failure is still sent.
+        let ops = optimize(parse(b"+.[>+]").unwrap());
+        let mut interp = AsyncInterpreter::new(ops, &b""[..], Vec::new());
+        let (result, _) = block_on(interp.run());
+        assert!(matches!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds { .. })
+        ));
+        assert_eq!(interp.into_inner().1, [1]);
+    }
+}
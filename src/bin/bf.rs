@@ -0,0 +1,5 @@
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    brainfuck_rs::cli::bf(std::env::args_os().skip(1))
+}
@@ -0,0 +1,618 @@
+//! Brainloller, brainfuck drawn as a PNG image.
+//!
+//! The program counter starts at the top-left pixel heading right, and
+//! stops when it leaves the image. Eight colors are the eight commands,
+//! cyan and dark cyan turn it 90 degrees clockwise and anticlockwise, and
+//! every other color does nothing:
+//!
+//! | Color           | Command |
+//! |-----------------|---------|
+//! | `#ff0000`       | `>`     |
+//! | `#800000`       | `<`     |
+//! | `#00ff00`       | `+`     |
+//! | `#008000`       | `-`     |
+//! | `#0000ff`       | `.`     |
+//! | `#000080`       | `,`     |
+//! | `#ffff00`       | `[`     |
+//! | `#808000`       | `]`     |
+//! | `#00ffff`       | turn clockwise |
+//! | `#008080`       | turn anticlockwise |
+//!
+//! [`to_brainfuck`] follows the path and returns the commands on it, for
+//! the usual parser. The PNG decoder is a small one of our own, which
+//! reads every color type and bit depth but not interlaced images.
+
+use std::fmt;
+
+/// Why [`to_brainfuck`] couldn't read a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BrainlollerError {
+    /// The input doesn't start with the PNG signature.
+    NotPng,
+    /// The image uses a PNG feature the decoder doesn't read.
+    Unsupported(&'static str),
+    /// The input ends early or isn't a valid PNG.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for BrainlollerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrainlollerError::NotPng => write!(f, "not a PNG image"),
+            BrainlollerError::Unsupported(what) => write!(f, "unsupported PNG: {}", what),
+            BrainlollerError::Corrupt(what) => write!(f, "corrupt PNG: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for BrainlollerError {}
+
+type Result<T> = std::result::Result<T, BrainlollerError>;
+
+/// Decodes the PNG image `png` and returns the brainfuck commands on the
+/// Brainloller path through it.
+pub fn to_brainfuck(png: &[u8]) -> Result<Vec<u8>> {
+    let image = decode_png(png)?;
+    let mut code = Vec::new();
+    // Headings are numbered clockwise from east. Each step can be undone,
+    // and none leads back to the start, so the path can't go in circles.
+    let mut heading = 0;
+    let (mut x, mut y) = (0, 0);
+    while x < image.width && y < image.height {
+        match image.pixels[y * image.width + x] {
+            [0xff, 0x00, 0x00] => code.push(b'>'),
+            [0x80, 0x00, 0x00] => code.push(b'<'),
+            [0x00, 0xff, 0x00] => code.push(b'+'),
+            [0x00, 0x80, 0x00] => code.push(b'-'),
+            [0x00, 0x00, 0xff] => code.push(b'.'),
+            [0x00, 0x00, 0x80] => code.push(b','),
+            [0xff, 0xff, 0x00] => code.push(b'['),
+            [0x80, 0x80, 0x00] => code.push(b']'),
+            [0x00, 0xff, 0xff] => heading = (heading + 1) % 4,
+            [0x00, 0x80, 0x80] => heading = (heading + 3) % 4,
+            _ => {}
+        }
+        let next = match heading {
+            0 => x.checked_add(1).map(|x| (x, y)),
+            1 => y.checked_add(1).map(|y| (x, y)),
+            2 => x.checked_sub(1).map(|x| (x, y)),
+            _ => y.checked_sub(1).map(|y| (x, y)),
+        };
+        let Some(next) = next else {
+            break;
+        };
+        (x, y) = next;
+    }
+    Ok(code)
+}
+
+/// A decoded image, as rows of RGB pixels from the top.
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// The `IHDR` fields the decoder needs.
+struct Header {
+    width: usize,
+    height: usize,
+    depth: u8,
+    color: u8,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        }
+    }
+}
+
+fn decode_png(png: &[u8]) -> Result<Image> {
+    let mut rest = png
+        .strip_prefix(SIGNATURE.as_slice())
+        .ok_or(BrainlollerError::NotPng)?;
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+    loop {
+        if rest.len() < 12 {
+            return Err(BrainlollerError::Corrupt("unexpected end of input"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let kind = &rest[4..8];
+        let data = rest
+            .get(8..8 + len)
+            .ok_or(BrainlollerError::Corrupt("unexpected end of input"))?;
+        // The CRC after the data isn't checked.
+        rest = rest
+            .get(12 + len..)
+            .ok_or(BrainlollerError::Corrupt("unexpected end of input"))?;
+        match kind {
+            b"IHDR" => header = Some(read_header(data)?),
+            b"PLTE" => palette = data,
+            b"IDAT" => compressed.extend_from_slice(data),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+    let header = header.ok_or(BrainlollerError::Corrupt("missing IHDR chunk"))?;
+    let data = inflate_zlib(&compressed)?;
+    let bits = header.channels() * header.depth as usize;
+    let stride = header
+        .width
+        .checked_mul(bits)
+        .map(|bits| bits.div_ceil(8))
+        .ok_or(BrainlollerError::Unsupported("image too large"))?;
+    let rows = unfilter(&data, stride, header.height, bits.div_ceil(8))?;
+
+    let mut pixels = Vec::with_capacity(header.width * header.height);
+    for row in rows.chunks(stride) {
+        for x in 0..header.width {
+            let sample = |channel| sample(row, x * header.channels() + channel, header.depth);
+            pixels.push(match header.color {
+                2 | 6 => [sample(0), sample(1), sample(2)],
+                3 => {
+                    let i = sample_bits(row, x, header.depth) as usize * 3;
+                    let rgb = palette
+                        .get(i..i + 3)
+                        .ok_or(BrainlollerError::Corrupt("palette index out of range"))?;
+                    [rgb[0], rgb[1], rgb[2]]
+                }
+                _ => [sample(0); 3],
+            });
+        }
+    }
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        pixels,
+    })
+}
+
+fn read_header(data: &[u8]) -> Result<Header> {
+    let [
+        w0,
+        w1,
+        w2,
+        w3,
+        h0,
+        h1,
+        h2,
+        h3,
+        depth,
+        color,
+        compression,
+        filter,
+        interlace,
+    ] = *data
+    else {
+        return Err(BrainlollerError::Corrupt("bad IHDR chunk"));
+    };
+    let width = u32::from_be_bytes([w0, w1, w2, w3]) as usize;
+    let height = u32::from_be_bytes([h0, h1, h2, h3]) as usize;
+    if width == 0 || height == 0 {
+        return Err(BrainlollerError::Corrupt("empty image"));
+    }
+    let depths: &[u8] = match color {
+        0 => &[1, 2, 4, 8, 16],
+        3 => &[1, 2, 4, 8],
+        2 | 4 | 6 => &[8, 16],
+        _ => return Err(BrainlollerError::Corrupt("bad color type")),
+    };
+    if !depths.contains(&depth) || compression != 0 || filter != 0 {
+        return Err(BrainlollerError::Corrupt("bad IHDR chunk"));
+    }
+    if interlace != 0 {
+        return Err(BrainlollerError::Unsupported("interlaced images"));
+    }
+    Ok(Header {
+        width,
+        height,
+        depth,
+        color,
+    })
+}
+
+/// The `i`th sample of `row`, as it is stored.
+fn sample_bits(row: &[u8], i: usize, depth: u8) -> u16 {
+    match depth {
+        16 => u16::from_be_bytes([row[2 * i], row[2 * i + 1]]),
+        8 => row[i] as u16,
+        _ => {
+            let per_byte = 8 / depth as usize;
+            let shift = 8 - depth as usize * (i % per_byte + 1);
+            (row[i / per_byte] >> shift) as u16 & ((1 << depth) - 1)
+        }
+    }
+}
+
+/// The `i`th sample of `row`, scaled to 8 bits.
+fn sample(row: &[u8], i: usize, depth: u8) -> u8 {
+    let value = sample_bits(row, i, depth);
+    match depth {
+        16 => (value >> 8) as u8,
+        8 => value as u8,
+        _ => (value * 255 / ((1 << depth) - 1)) as u8,
+    }
+}
+
+/// Undoes the filter each of the `height` rows of `data` starts with.
+fn unfilter(data: &[u8], stride: usize, height: usize, bpp: usize) -> Result<Vec<u8>> {
+    if data.len() < (stride + 1) * height {
+        return Err(BrainlollerError::Corrupt("too little image data"));
+    }
+    let mut out = vec![0u8; stride * height];
+    for y in 0..height {
+        let filter = data[y * (stride + 1)];
+        let line = &data[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, row) = out.split_at_mut(y * stride);
+        let row = &mut row[..stride];
+        let up = done.get(done.len().wrapping_sub(stride)..);
+        for i in 0..stride {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = up.map_or(0, |up| up[i]);
+            let c = match up {
+                Some(up) if i >= bpp => up[i - bpp],
+                _ => 0,
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                _ => return Err(BrainlollerError::Corrupt("bad filter type")),
+            };
+            row[i] = line[i].wrapping_add(predicted);
+        }
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = (
+        (p - a as i16).abs(),
+        (p - b as i16).abs(),
+        (p - c as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Decompresses a zlib stream. The Adler-32 checksum isn't checked.
+fn inflate_zlib(data: &[u8]) -> Result<Vec<u8>> {
+    let [cmf, flg, ..] = *data else {
+        return Err(BrainlollerError::Corrupt("unexpected end of input"));
+    };
+    if cmf & 0x0f != 8 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 || flg & 0x20 != 0 {
+        return Err(BrainlollerError::Corrupt("bad zlib header"));
+    }
+    inflate(&data[2..])
+}
+
+/// A reader of a deflate stream's bits, least significant first.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or(BrainlollerError::Corrupt("unexpected end of input"))?;
+            self.pos += 1;
+            self.buf |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary and takes `n` bytes.
+    fn bytes(&mut self, n: usize) -> Result<&[u8]> {
+        self.buf = 0;
+        self.count = 0;
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or(BrainlollerError::Corrupt("unexpected end of input"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+}
+
+/// A canonical Huffman code: how many codes there are of each length, and
+/// the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // Codes left to hand out at each length.
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - i32::from(count);
+            if left < 0 {
+                return Err(BrainlollerError::Corrupt("bad Huffman code"));
+            }
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits<'_>) -> Result<u16> {
+        // The code read so far, the first code of its length, and the
+        // index of that code's symbol.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(BrainlollerError::Corrupt("bad Huffman code"))
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses a raw deflate stream.
+fn inflate(data: &[u8]) -> Result<Vec<u8>> {
+    let mut bits = Bits {
+        data,
+        pos: 0,
+        buf: 0,
+        count: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                let header = bits.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(BrainlollerError::Corrupt("bad stored block"));
+                }
+                out.extend_from_slice(bits.bytes(len as usize)?);
+            }
+            1 => {
+                let mut lengths = [8u8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let lit = Huffman::new(&lengths)?;
+                let dist = Huffman::new(&[5; 30])?;
+                inflate_block(&mut bits, &lit, &dist, &mut out)?;
+            }
+            2 => {
+                let (lit, dist) = read_codes(&mut bits)?;
+                inflate_block(&mut bits, &lit, &dist, &mut out)?;
+            }
+            _ => return Err(BrainlollerError::Corrupt("bad block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Reads a dynamic block's literal/length and distance codes.
+fn read_codes(bits: &mut Bits<'_>) -> Result<(Huffman, Huffman)> {
+    let lits = bits.bits(5)? as usize + 257;
+    let dists = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths)?;
+    let mut lengths = Vec::with_capacity(lits + dists);
+    while lengths.len() < lits + dists {
+        let (len, repeat) = match code.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or(BrainlollerError::Corrupt("bad code lengths"))?;
+                (previous, 3 + bits.bits(2)?)
+            }
+            17 => (0, 3 + bits.bits(3)?),
+            _ => (0, 11 + bits.bits(7)?),
+        };
+        for _ in 0..repeat {
+            lengths.push(len);
+        }
+    }
+    if lengths.len() > lits + dists {
+        return Err(BrainlollerError::Corrupt("bad code lengths"));
+    }
+    Ok((
+        Huffman::new(&lengths[..lits])?,
+        Huffman::new(&lengths[lits..])?,
+    ))
+}
+
+/// Decodes a compressed block's symbols up to its end-of-block code.
+fn inflate_block(
+    bits: &mut Bits<'_>,
+    lit: &Huffman,
+    dist: &Huffman,
+    out: &mut Vec<u8>,
+) -> Result<()> {
+    loop {
+        let symbol = lit.decode(bits)? as usize;
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let i = symbol - 257;
+        let (&base, &extra) = LENGTH_BASE
+            .get(i)
+            .zip(LENGTH_EXTRA.get(i))
+            .ok_or(BrainlollerError::Corrupt("bad length code"))?;
+        let len = base as usize + bits.bits(extra as u32)? as usize;
+        let i = dist.decode(bits)? as usize;
+        let (&base, &extra) = DIST_BASE
+            .get(i)
+            .zip(DIST_EXTRA.get(i))
+            .ok_or(BrainlollerError::Corrupt("bad distance code"))?;
+        let distance = base as usize + bits.bits(extra as u32)? as usize;
+        let start = out
+            .len()
+            .checked_sub(distance)
+            .ok_or(BrainlollerError::Corrupt("distance too far back"))?;
+        // The copy can overlap what it writes.
+        for i in start..start + len {
+            out.push(out[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 3] = [0xff, 0, 0];
+    const GREEN: [u8; 3] = [0, 0xff, 0];
+    const BLUE: [u8; 3] = [0, 0, 0xff];
+    const CYAN: [u8; 3] = [0, 0xff, 0xff];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    /// Encodes an RGB image as a PNG with a stored zlib stream.
+    fn png(width: u32, pixels: &[[u8; 3]]) -> Vec<u8> {
+        let height = pixels.len() as u32 / width;
+        let mut raw = Vec::new();
+        for row in pixels.chunks(width as usize) {
+            raw.push(0);
+            raw.extend(row.iter().flatten());
+        }
+        let mut zlib = vec![0x78, 0x01, 1];
+        zlib.extend((raw.len() as u16).to_le_bytes());
+        zlib.extend((!(raw.len() as u16)).to_le_bytes());
+        zlib.extend(&raw);
+        zlib.extend([0; 4]);
+
+        let mut ihdr = Vec::new();
+        ihdr.extend(width.to_be_bytes());
+        ihdr.extend(height.to_be_bytes());
+        ihdr.extend([8, 2, 0, 0, 0]);
+        let mut out = SIGNATURE.to_vec();
+        for (kind, data) in [(b"IHDR", ihdr), (b"IDAT", zlib), (b"IEND", Vec::new())] {
+            out.extend((data.len() as u32).to_be_bytes());
+            out.extend(kind);
+            out.extend(data);
+            out.extend([0; 4]);
+        }
+        out
+    }
+
+    #[test]
+    fn test_turns() {
+        // Right along the top, down the right edge, then left along the
+        // bottom and out.
+        let image = png(
+            3,
+            &[
+                GREEN, RED, CYAN, //
+                BLACK, BLACK, GREEN, //
+                BLUE, RED, CYAN,
+            ],
+        );
+        assert_eq!(to_brainfuck(&image).unwrap(), b"+>+>.");
+
+        assert_eq!(to_brainfuck(b"+>+"), Err(BrainlollerError::NotPng));
+    }
+
+    #[test]
+    fn test_compressed_palette() {
+        // Made by zlib at level 9, with the Sub filter on each row: the
+        // commands run right along the top row and left along the bottom.
+        let image = [
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48,
+            0x44, 0x52, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x02, 0x08, 0x03, 0x00, 0x00,
+            0x00, 0x5f, 0x54, 0x1d, 0x98, 0x00, 0x00, 0x00, 0x1b, 0x50, 0x4c, 0x54, 0x45, 0x00,
+            0xff, 0x00, 0xff, 0xff, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0xff, 0x80, 0x80, 0x00, 0x00, 0x80, 0x00, 0x80, 0x00, 0x00, 0x1f, 0x7d,
+            0x91, 0x48, 0x00, 0x00, 0x00, 0x1e, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x64,
+            0x80, 0x02, 0x46, 0xc6, 0x7f, 0x0c, 0x0c, 0xcc, 0x8c, 0x2c, 0x0c, 0x8c, 0xbf, 0x99,
+            0x58, 0x18, 0x19, 0x7f, 0x80, 0xc4, 0x98, 0x01, 0x24, 0xa5, 0x03, 0x09, 0x7e, 0xae,
+            0x0c, 0x02, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+        ];
+        assert_eq!(to_brainfuck(&image).unwrap(), b"++++++++[>++++++++<-]>+.");
+    }
+
+    #[test]
+    fn test_inflate_dynamic() {
+        let data = [
+            0x78, 0xda, 0x0d, 0xc1, 0x01, 0x11, 0x00, 0x30, 0x0c, 0x02, 0x31, 0x41, 0x3c, 0x28,
+            0xd8, 0x61, 0xa4, 0x57, 0xff, 0x36, 0xba, 0xe4, 0x79, 0x0c, 0xa4, 0x46, 0x89, 0x98,
+            0x7a, 0xf5, 0xa5, 0x51, 0xa1, 0xdb, 0x32, 0x8a, 0x0f, 0xa9, 0x74, 0x08, 0x67,
+        ];
+        assert_eq!(
+            inflate_zlib(&data).unwrap(),
+            b"<-[-,,,.>-,+..+,[>-]++++.>.+>,,>]>>,[+.-"
+        );
+    }
+}
@@ -0,0 +1,878 @@
+//! A compact binary encoding of optimized ops, so large programs needn't be
+//! parsed and optimized on every run.
+//!
+//! A file is [`MAGIC`], the format [`VERSION`] as a little-endian `u16`, the
+//! op count, then each op as a tag byte followed by its operands. Integers
+//! are LEB128 varints; offsets are zigzag-encoded first so small negative
+//! ones stay short.
+//!
+//! A checkpoint, written by [`save_checkpoint`], is [`CHECKPOINT_MAGIC`],
+//! [`CHECKPOINT_VERSION`], the cell width, bounds policy and EOF behavior as
+//! one byte each, the tape size, program counter, data pointer and step
+//! count, the non-zero cells as (gap since the previous one, value) pairs,
+//! and then the program as a bytecode file. The pbrain procedures a run has
+//! defined aren't saved, so a resumed run starts without them, and only
+//! the current Brainfork thread is. Nor is Extended Type I's storage
+//! register, which starts out zero again, any tape but the current one
+//! of the multitape dialect, or where `?`'s random bytes had got to: a
+//! resumed run draws them afresh from its own seed.
+//!
+//! A core dump, written by [`save_core`] when a run fails, is
+//! [`CORE_MAGIC`], [`CORE_VERSION`], the reason as a length-prefixed UTF-8
+//! string, the last ops executed as (step, program counter, data pointer,
+//! cell) entries, and then the machine at the failure as a checkpoint.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::interpreter::State;
+use crate::trace::Entry;
+use crate::{BitOp, BoundsPolicy, Cell, CellWidth, Config, EofBehavior, Op};
+
+/// The first four bytes of every bytecode file.
+pub const MAGIC: &[u8; 4] = b"BFBC";
+
+/// The format version written by [`save_bytecode`].
+pub const VERSION: u16 = 1;
+
+/// Why [`load_bytecode`] rejected its input.
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(io::Error),
+    /// The input doesn't start with [`MAGIC`].
+    NotBytecode,
+    /// The input doesn't start with [`CHECKPOINT_MAGIC`].
+    NotCheckpoint,
+    /// The input doesn't start with [`CORE_MAGIC`].
+    NotCore,
+    /// The file was written by a different version of the format.
+    UnsupportedVersion(u16),
+    /// The input ends early or doesn't describe a valid program.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::Io(e) => write!(f, "{}", e),
+            BytecodeError::NotBytecode => write!(f, "not a bytecode file"),
+            BytecodeError::NotCheckpoint => write!(f, "not a checkpoint file"),
+            BytecodeError::NotCore => write!(f, "not a core dump"),
+            BytecodeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported bytecode version {}", v)
+            }
+            BytecodeError::Corrupt(what) => write!(f, "corrupt bytecode: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BytecodeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BytecodeError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            BytecodeError::Corrupt("unexpected end of input")
+        } else {
+            BytecodeError::Io(e)
+        }
+    }
+}
+
+/// Returns whether `data` starts like a bytecode file.
+pub fn is_bytecode(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// The first four bytes of every checkpoint file.
+pub const CHECKPOINT_MAGIC: &[u8; 4] = b"BFCK";
+
+/// The checkpoint format version written by [`save_checkpoint`].
+pub const CHECKPOINT_VERSION: u16 = 1;
+
+/// Returns whether `data` starts like a checkpoint file.
+pub fn is_checkpoint(data: &[u8]) -> bool {
+    data.starts_with(CHECKPOINT_MAGIC)
+}
+
+/// The first four bytes of every core dump.
+pub const CORE_MAGIC: &[u8; 4] = b"BFCR";
+
+/// The core dump format version written by [`save_core`].
+pub const CORE_VERSION: u16 = 1;
+
+/// Returns whether `data` starts like a core dump.
+pub fn is_core(data: &[u8]) -> bool {
+    data.starts_with(CORE_MAGIC)
+}
+
+const PTR_ADD: u8 = 0;
+const VAL_ADD: u8 = 1;
+const VAL_SUB: u8 = 2;
+const OUTPUT: u8 = 3;
+const INPUT: u8 = 4;
+const OUTPUT_AT: u8 = 5;
+const INPUT_AT: u8 = 6;
+const OUTPUT_CONST: u8 = 7;
+const JZ: u8 = 8;
+const JNZ: u8 = 9;
+const CLEAR: u8 = 10;
+const SET: u8 = 11;
+const MUL_ADD: u8 = 12;
+const SCAN_LEFT: u8 = 13;
+const SCAN_RIGHT: u8 = 14;
+const BULK_ADD: u8 = 15;
+const BULK_CLEAR: u8 = 16;
+const DEBUG: u8 = 17;
+const SCAN: u8 = 18;
+const DEF_PROC: u8 = 19;
+const END_PROC: u8 = 20;
+const CALL_PROC: u8 = 21;
+const FORK: u8 = 22;
+const END: u8 = 23;
+const STORE: u8 = 24;
+const RETRIEVE: u8 = 25;
+const BITWISE: u8 = 26;
+const SWITCH_TAPE: u8 = 27;
+const DIV_MOD: u8 = 28;
+const CLEAR_RANGE: u8 = 29;
+const CLEAR_UNTIL_ZERO: u8 = 30;
+const ADD_TO: u8 = 31;
+const SUB_FROM: u8 = 32;
+const TRANSFER: u8 = 33;
+const OUTPUT_REPEAT: u8 = 34;
+const RANDOM: u8 = 35;
+
+/// What the operand of `BITWISE` stands for: the index of its variant.
+const BIT_OPS: [BitOp; 6] = [
+    BitOp::ShiftLeft,
+    BitOp::ShiftRight,
+    BitOp::Not,
+    BitOp::Xor,
+    BitOp::And,
+    BitOp::Or,
+];
+
+struct Encoder<'a, W> {
+    out: &'a mut W,
+}
+
+impl<W: Write> Encoder<'_, W> {
+    fn byte(&mut self, b: u8) -> io::Result<()> {
+        self.out.write_all(&[b])
+    }
+
+    fn varint(&mut self, mut n: u64) -> io::Result<()> {
+        while n >= 0x80 {
+            self.byte(n as u8 | 0x80)?;
+            n >>= 7;
+        }
+        self.byte(n as u8)
+    }
+
+    fn offset(&mut self, n: isize) -> io::Result<()> {
+        let n = n as i64;
+        self.varint(((n << 1) ^ (n >> 63)) as u64)
+    }
+
+    fn index(&mut self, n: usize) -> io::Result<()> {
+        self.varint(n as u64)
+    }
+
+    fn value(&mut self, n: u32) -> io::Result<()> {
+        self.varint(n.into())
+    }
+}
+
+/// Writes `ops` to `out` in the bytecode format.
+pub fn save_bytecode<W: Write>(ops: &[Op], out: &mut W) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    let mut enc = Encoder { out };
+    enc.index(ops.len())?;
+    for op in ops {
+        match op {
+            Op::PtrAdd(n) => {
+                enc.byte(PTR_ADD)?;
+                enc.offset(*n)?;
+            }
+            Op::ValAdd(offset, n) | Op::ValSub(offset, n) => {
+                enc.byte(if matches!(op, Op::ValAdd(..)) {
+                    VAL_ADD
+                } else {
+                    VAL_SUB
+                })?;
+                enc.offset(*offset)?;
+                enc.value(*n)?;
+            }
+            Op::Output => enc.byte(OUTPUT)?,
+            Op::Input => enc.byte(INPUT)?,
+            Op::OutputAt(offset) => {
+                enc.byte(OUTPUT_AT)?;
+                enc.offset(*offset)?;
+            }
+            Op::InputAt(offset) => {
+                enc.byte(INPUT_AT)?;
+                enc.offset(*offset)?;
+            }
+            Op::OutputConst(bytes) => {
+                enc.byte(OUTPUT_CONST)?;
+                enc.index(bytes.len())?;
+                enc.out.write_all(bytes)?;
+            }
+            Op::Jz(target) => {
+                enc.byte(JZ)?;
+                enc.index(*target)?;
+            }
+            Op::Jnz(target) => {
+                enc.byte(JNZ)?;
+                enc.index(*target)?;
+            }
+            Op::Clear(offset) => {
+                enc.byte(CLEAR)?;
+                enc.offset(*offset)?;
+            }
+            Op::Set(offset, n) => {
+                enc.byte(SET)?;
+                enc.offset(*offset)?;
+                enc.value(*n)?;
+            }
+            Op::MulAdd(offset, factor) => {
+                enc.byte(MUL_ADD)?;
+                enc.offset(*offset)?;
+                enc.value(*factor)?;
+            }
+            Op::AddTo(offset) => {
+                enc.byte(ADD_TO)?;
+                enc.offset(*offset)?;
+            }
+            Op::SubFrom(offset) => {
+                enc.byte(SUB_FROM)?;
+                enc.offset(*offset)?;
+            }
+            Op::ScanLeft => enc.byte(SCAN_LEFT)?,
+            Op::Debug => enc.byte(DEBUG)?,
+            Op::ScanRight => enc.byte(SCAN_RIGHT)?,
+            Op::Scan(stride) => {
+                enc.byte(SCAN)?;
+                enc.offset(*stride)?;
+            }
+            Op::BulkAdd(deltas) | Op::Transfer(deltas) => {
+                enc.byte(if matches!(op, Op::BulkAdd(_)) {
+                    BULK_ADD
+                } else {
+                    TRANSFER
+                })?;
+                enc.index(deltas.len())?;
+                for (offset, n) in deltas {
+                    enc.offset(*offset)?;
+                    enc.value(*n)?;
+                }
+            }
+            Op::BulkClear(offsets) => {
+                enc.byte(BULK_CLEAR)?;
+                enc.index(offsets.len())?;
+                for offset in offsets {
+                    enc.offset(*offset)?;
+                }
+            }
+            Op::ClearRange(offset, n) => {
+                enc.byte(CLEAR_RANGE)?;
+                enc.offset(*offset)?;
+                enc.index(*n)?;
+            }
+            Op::OutputRepeat(offset, count) => {
+                enc.byte(OUTPUT_REPEAT)?;
+                enc.offset(*offset)?;
+                enc.index(*count)?;
+            }
+            Op::ClearUntilZero(stride) => {
+                enc.byte(CLEAR_UNTIL_ZERO)?;
+                enc.offset(*stride)?;
+            }
+            Op::DefProc(end) => {
+                enc.byte(DEF_PROC)?;
+                enc.index(*end)?;
+            }
+            Op::EndProc => enc.byte(END_PROC)?,
+            Op::CallProc => enc.byte(CALL_PROC)?,
+            Op::Fork => enc.byte(FORK)?,
+            Op::End => enc.byte(END)?,
+            Op::Store => enc.byte(STORE)?,
+            Op::Retrieve => enc.byte(RETRIEVE)?,
+            Op::Bitwise(bit) => {
+                enc.byte(BITWISE)?;
+                enc.byte(*bit as u8)?;
+            }
+            Op::SwitchTape(n) => {
+                enc.byte(SWITCH_TAPE)?;
+                enc.offset(*n)?;
+            }
+            Op::DivMod => enc.byte(DIV_MOD)?,
+            Op::Random => enc.byte(RANDOM)?,
+        }
+    }
+    Ok(())
+}
+
+/// Writes everything needed to pick a run up again later: the program,
+/// the tape settings from `config` and the machine `state`. The step limit,
+/// timeout and I/O aren't saved.
+pub fn save_checkpoint<C: Cell, W: Write>(
+    ops: &[Op],
+    config: &Config,
+    state: &State<C>,
+    out: &mut W,
+) -> io::Result<()> {
+    out.write_all(CHECKPOINT_MAGIC)?;
+    out.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+    let mut enc = Encoder { out };
+    enc.byte(C::WIDTH.bits() as u8)?;
+    enc.byte(match config.bounds {
+        BoundsPolicy::Error => 0,
+        BoundsPolicy::Wrap => 1,
+        BoundsPolicy::Clamp => 2,
+        BoundsPolicy::Grow => 3,
+        BoundsPolicy::Unchecked => 4,
+        BoundsPolicy::Bidirectional => 5,
+    })?;
+    enc.byte(match config.eof {
+        EofBehavior::Unchanged => 0,
+        EofBehavior::Zero => 1,
+        EofBehavior::MinusOne => 2,
+    })?;
+    enc.index(state.tape().len())?;
+    enc.index(state.pc())?;
+    enc.index(state.ptr())?;
+    enc.varint(state.steps())?;
+    let cells: Vec<(usize, u32)> = state
+        .tape()
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| !v.is_zero())
+        .map(|(i, v)| (i, v.to_u32()))
+        .collect();
+    enc.index(cells.len())?;
+    let mut next = 0;
+    for (i, v) in cells {
+        enc.index(i - next)?;
+        enc.value(v)?;
+        next = i + 1;
+    }
+    save_bytecode(ops, out)
+}
+
+/// A run saved by [`save_checkpoint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Checkpoint {
+    pub ops: Vec<Op>,
+    /// The saved tape settings, with no step limit or timeout.
+    pub config: Config,
+    pub cell_width: CellWidth,
+    state: State<u32>,
+}
+
+impl Checkpoint {
+    /// The machine state, for an interpreter with cells of type `C`, which
+    /// should match [`cell_width`](Self::cell_width).
+    pub fn state<C: Cell>(&self) -> State<C> {
+        State::new(
+            self.state.pc(),
+            self.state.ptr(),
+            self.state.steps(),
+            self.state.tape().iter().map(|&v| C::from_u32(v)).collect(),
+        )
+    }
+}
+
+/// Writes what a failed run left behind: why it failed, the last ops it
+/// executed, oldest first, and a checkpoint of the machine at the failure.
+pub fn save_core<C: Cell, W: Write>(
+    reason: &str,
+    recent: &[Entry],
+    ops: &[Op],
+    config: &Config,
+    state: &State<C>,
+    out: &mut W,
+) -> io::Result<()> {
+    out.write_all(CORE_MAGIC)?;
+    out.write_all(&CORE_VERSION.to_le_bytes())?;
+    let mut enc = Encoder { out };
+    enc.index(reason.len())?;
+    enc.out.write_all(reason.as_bytes())?;
+    enc.index(recent.len())?;
+    for entry in recent {
+        enc.varint(entry.step)?;
+        enc.index(entry.pc)?;
+        enc.index(entry.ptr)?;
+        enc.value(entry.value)?;
+    }
+    save_checkpoint(ops, config, state, out)
+}
+
+/// A failed run saved by [`save_core`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Core {
+    pub reason: String,
+    /// The last ops executed, oldest first; the last one is where the run
+    /// failed.
+    pub recent: Vec<Entry>,
+    /// The machine at the failure. For a run that failed inside an op, the
+    /// tape may already show part of that op's effect.
+    pub checkpoint: Checkpoint,
+}
+
+struct Decoder<'a, R> {
+    input: &'a mut R,
+}
+
+impl<R: Read> Decoder<'_, R> {
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        let mut b = 0;
+        self.input.read_exact(std::slice::from_mut(&mut b))?;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, BytecodeError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            n |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(BytecodeError::Corrupt("varint too long"))
+    }
+
+    fn offset(&mut self) -> Result<isize, BytecodeError> {
+        let n = self.varint()?;
+        let n = ((n >> 1) as i64) ^ -((n & 1) as i64);
+        isize::try_from(n).map_err(|_| BytecodeError::Corrupt("offset out of range"))
+    }
+
+    fn index(&mut self) -> Result<usize, BytecodeError> {
+        usize::try_from(self.varint()?).map_err(|_| BytecodeError::Corrupt("length out of range"))
+    }
+
+    fn value(&mut self) -> Result<u32, BytecodeError> {
+        u32::try_from(self.varint()?).map_err(|_| BytecodeError::Corrupt("value out of range"))
+    }
+
+    /// A length prefix, capped so a corrupt one can't exhaust memory before
+    /// the input runs out.
+    fn len(&mut self) -> Result<usize, BytecodeError> {
+        Ok(self.index()?.min(1 << 16))
+    }
+
+    /// A length-prefixed list of (offset, value) pairs.
+    fn deltas(&mut self) -> Result<Vec<(isize, u32)>, BytecodeError> {
+        let len = self.len()?;
+        let mut deltas = Vec::with_capacity(len);
+        for _ in 0..len {
+            deltas.push((self.offset()?, self.value()?));
+        }
+        Ok(deltas)
+    }
+}
+
+/// Reads a program written by [`save_bytecode`], checking that its loops are
+/// balanced and their jump targets consistent.
+pub fn load_bytecode<R: Read>(input: &mut R) -> Result<Vec<Op>, BytecodeError> {
+    let mut header = [0; 6];
+    match input.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(BytecodeError::NotBytecode);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if !is_bytecode(&header) {
+        return Err(BytecodeError::NotBytecode);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let mut dec = Decoder { input };
+    let count = dec.index()?;
+    let mut ops = Vec::with_capacity(count.min(1 << 16));
+    let mut loops = Vec::new();
+    for pc in 0..count {
+        let op = match dec.byte()? {
+            PTR_ADD => Op::PtrAdd(dec.offset()?),
+            VAL_ADD => Op::ValAdd(dec.offset()?, dec.value()?),
+            VAL_SUB => Op::ValSub(dec.offset()?, dec.value()?),
+            OUTPUT => Op::Output,
+            INPUT => Op::Input,
+            OUTPUT_AT => Op::OutputAt(dec.offset()?),
+            INPUT_AT => Op::InputAt(dec.offset()?),
+            OUTPUT_CONST => {
+                let len = dec.index()?;
+                let mut bytes = Vec::new();
+                dec.input.take(len as u64).read_to_end(&mut bytes)?;
+                if bytes.len() != len {
+                    return Err(BytecodeError::Corrupt("unexpected end of input"));
+                }
+                Op::OutputConst(bytes)
+            }
+            JZ => {
+                loops.push(pc);
+                Op::Jz(dec.index()?)
+            }
+            JNZ => {
+                let target = dec.index()?;
+                let start = loops
+                    .pop()
+                    .ok_or(BytecodeError::Corrupt("unmatched loop end"))?;
+                if target != start || ops[start] != Op::Jz(pc) {
+                    return Err(BytecodeError::Corrupt("inconsistent jump target"));
+                }
+                Op::Jnz(target)
+            }
+            DEF_PROC => {
+                loops.push(pc);
+                Op::DefProc(dec.index()?)
+            }
+            END_PROC => {
+                let start = loops
+                    .pop()
+                    .ok_or(BytecodeError::Corrupt("unmatched procedure end"))?;
+                if ops[start] != Op::DefProc(pc) {
+                    return Err(BytecodeError::Corrupt("inconsistent jump target"));
+                }
+                Op::EndProc
+            }
+            CALL_PROC => Op::CallProc,
+            FORK => Op::Fork,
+            SWITCH_TAPE => Op::SwitchTape(dec.offset()?),
+            DIV_MOD => Op::DivMod,
+            RANDOM => Op::Random,
+            END => Op::End,
+            STORE => Op::Store,
+            RETRIEVE => Op::Retrieve,
+            BITWISE => {
+                let index = dec.byte()?;
+                Op::Bitwise(
+                    *BIT_OPS
+                        .get(index as usize)
+                        .ok_or(BytecodeError::Corrupt("unknown bitwise op"))?,
+                )
+            }
+            CLEAR => Op::Clear(dec.offset()?),
+            SET => Op::Set(dec.offset()?, dec.value()?),
+            MUL_ADD => Op::MulAdd(dec.offset()?, dec.value()?),
+            SCAN_LEFT => Op::ScanLeft,
+            DEBUG => Op::Debug,
+            SCAN_RIGHT => Op::ScanRight,
+            SCAN => Op::Scan(dec.offset()?),
+            CLEAR_RANGE => Op::ClearRange(dec.offset()?, dec.index()?),
+            CLEAR_UNTIL_ZERO => Op::ClearUntilZero(dec.offset()?),
+            ADD_TO => Op::AddTo(dec.offset()?),
+            SUB_FROM => Op::SubFrom(dec.offset()?),
+            BULK_ADD => Op::BulkAdd(dec.deltas()?),
+            TRANSFER => Op::Transfer(dec.deltas()?),
+            OUTPUT_REPEAT => Op::OutputRepeat(dec.offset()?, dec.index()?),
+            BULK_CLEAR => {
+                let len = dec.len()?;
+                let mut offsets = Vec::with_capacity(len);
+                for _ in 0..len {
+                    offsets.push(dec.offset()?);
+                }
+                Op::BulkClear(offsets)
+            }
+            _ => return Err(BytecodeError::Corrupt("unknown op")),
+        };
+        ops.push(op);
+    }
+    if !loops.is_empty() {
+        return Err(BytecodeError::Corrupt("unmatched loop start"));
+    }
+    Ok(ops)
+}
+
+/// Reads a run written by [`save_checkpoint`].
+pub fn load_checkpoint<R: Read>(input: &mut R) -> Result<Checkpoint, BytecodeError> {
+    let mut header = [0; 6];
+    match input.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(BytecodeError::NotCheckpoint);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if !is_checkpoint(&header) {
+        return Err(BytecodeError::NotCheckpoint);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != CHECKPOINT_VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let mut dec = Decoder { input: &mut *input };
+    let cell_width = CellWidth::from_bits(dec.byte()?.into())
+        .ok_or(BytecodeError::Corrupt("unknown cell width"))?;
+    let bounds = match dec.byte()? {
+        0 => BoundsPolicy::Error,
+        1 => BoundsPolicy::Wrap,
+        2 => BoundsPolicy::Clamp,
+        3 => BoundsPolicy::Grow,
+        4 => BoundsPolicy::Unchecked,
+        5 => BoundsPolicy::Bidirectional,
+        _ => return Err(BytecodeError::Corrupt("unknown bounds policy")),
+    };
+    let eof = match dec.byte()? {
+        0 => EofBehavior::Unchanged,
+        1 => EofBehavior::Zero,
+        2 => EofBehavior::MinusOne,
+        _ => return Err(BytecodeError::Corrupt("unknown EOF behavior")),
+    };
+    let tape_size = dec.index()?;
+    if tape_size == 0 {
+        return Err(BytecodeError::Corrupt("empty tape"));
+    }
+    let pc = dec.index()?;
+    let ptr = dec.index()?;
+    let steps = dec.varint()?;
+    let count = dec.index()?;
+    let mut tape = vec![0; tape_size];
+    let mut next = 0usize;
+    for _ in 0..count {
+        let i = next
+            .checked_add(dec.index()?)
+            .filter(|&i| i < tape_size)
+            .ok_or(BytecodeError::Corrupt("cell outside the tape"))?;
+        tape[i] = cell_width.truncate(dec.value()?);
+        next = i + 1;
+    }
+
+    let ops = load_bytecode(input)?;
+    if pc > ops.len() {
+        return Err(BytecodeError::Corrupt(
+            "program counter outside the program",
+        ));
+    }
+    Ok(Checkpoint {
+        ops,
+        config: Config {
+            tape_size,
+            bounds,
+            eof,
+            ..Config::default()
+        },
+        cell_width,
+        state: State::new(pc, ptr, steps, tape),
+    })
+}
+
+/// Reads a failed run written by [`save_core`].
+pub fn load_core<R: Read>(input: &mut R) -> Result<Core, BytecodeError> {
+    let mut header = [0; 6];
+    match input.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(BytecodeError::NotCore);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if !is_core(&header) {
+        return Err(BytecodeError::NotCore);
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != CORE_VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let mut dec = Decoder { input: &mut *input };
+    let mut reason = vec![0; dec.len()?];
+    dec.input.read_exact(&mut reason)?;
+    let reason =
+        String::from_utf8(reason).map_err(|_| BytecodeError::Corrupt("reason isn't UTF-8"))?;
+    let count = dec.len()?;
+    let mut recent = Vec::with_capacity(count);
+    for _ in 0..count {
+        recent.push(Entry {
+            step: dec.varint()?,
+            pc: dec.index()?,
+            ptr: dec.index()?,
+            value: dec.value()?,
+        });
+    }
+    let checkpoint = load_checkpoint(input)?;
+    if recent.iter().any(|entry| entry.pc >= checkpoint.ops.len()) {
+        return Err(BytecodeError::Corrupt(
+            "program counter outside the program",
+        ));
+    }
+    Ok(Core {
+        reason,
+        recent,
+        checkpoint,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Interpreter, optimize, parse};
+
+    fn round_trip(ops: &[Op]) -> Vec<Op> {
+        let mut out = Vec::new();
+        save_bytecode(ops, &mut out).unwrap();
+        load_bytecode(&mut out.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let code = std::fs::read("examples/mandelbrot.bf").unwrap();
+        let ops = optimize(parse(&code).unwrap());
+        assert_eq!(round_trip(&ops), ops);
+
+        let ops = vec![
+            Op::OutputConst(b"hi\n".to_vec()),
+            Op::Set(-70, u32::MAX),
+            Op::BulkAdd(vec![(-1, 3), (isize::MAX, 1)]),
+            Op::BulkClear(vec![isize::MIN, 2]),
+            Op::InputAt(-1),
+            Op::Scan(-3),
+            Op::ClearRange(-2, 5),
+            Op::ClearUntilZero(-2),
+            Op::AddTo(3),
+            Op::SubFrom(-1),
+            Op::Transfer(vec![(2, 3), (-1, u32::MAX)]),
+            Op::OutputRepeat(-1, 300),
+            Op::DivMod,
+            Op::Debug,
+            Op::Random,
+            Op::DefProc(23),
+            Op::CallProc,
+            Op::Fork,
+            Op::End,
+            Op::Store,
+            Op::Retrieve,
+            Op::Bitwise(BitOp::Or),
+            Op::SwitchTape(-2),
+            Op::EndProc,
+        ];
+        assert_eq!(round_trip(&ops), ops);
+    }
+
+    #[test]
+    fn test_checkpoint_round_trip() {
+        let code = b"+++++[>+++++++++<-]>[>++<-]>.";
+        let ops = optimize(parse(code).unwrap());
+        let config = Config {
+            tape_size: 100,
+            bounds: BoundsPolicy::Wrap,
+            eof: EofBehavior::Zero,
+            ..Config::default()
+        };
+        let mut interp = Interpreter::<_, _, u16>::with_config(
+            ops.clone(),
+            std::io::empty(),
+            Vec::new(),
+            &config,
+        );
+        for _ in 0..12 {
+            interp.step().unwrap();
+        }
+        let mut out = Vec::new();
+        save_checkpoint(interp.ops(), &config, &interp.snapshot(), &mut out).unwrap();
+        assert!(is_checkpoint(&out));
+
+        let checkpoint = load_checkpoint(&mut out.as_slice()).unwrap();
+        assert_eq!(checkpoint.ops, ops);
+        assert_eq!(checkpoint.config, config);
+        assert_eq!(checkpoint.cell_width, CellWidth::U16);
+        assert_eq!(checkpoint.state::<u16>(), interp.snapshot());
+
+        out.truncate(out.len() - 1);
+        assert!(matches!(
+            load_checkpoint(&mut out.as_slice()),
+            Err(BytecodeError::Corrupt(_))
+        ));
+        assert!(matches!(
+            load_checkpoint(&mut &b"BFBC\x01\x00"[..]),
+            Err(BytecodeError::NotCheckpoint)
+        ));
+    }
+
+    #[test]
+    fn test_core_round_trip() {
+        let ops = parse(b"+[>+]").unwrap();
+        let recent = [
+            Entry {
+                step: 7,
+                pc: 2,
+                ptr: 3,
+                value: 0,
+            },
+            Entry {
+                step: 8,
+                pc: 3,
+                ptr: 3,
+                value: 1,
+            },
+        ];
+        let state = State::new(3, 3, 8, vec![1u8, 1, 1, 1]);
+        let config = Config {
+            tape_size: 4,
+            ..Config::default()
+        };
+        let mut out = Vec::new();
+        save_core(
+            "the pointer left the tape",
+            &recent,
+            &ops,
+            &config,
+            &state,
+            &mut out,
+        )
+        .unwrap();
+        assert!(is_core(&out));
+
+        let core = load_core(&mut out.as_slice()).unwrap();
+        assert_eq!(core.reason, "the pointer left the tape");
+        assert_eq!(core.recent, recent);
+        assert_eq!(core.checkpoint.ops, ops);
+        assert_eq!(core.checkpoint.state::<u8>(), state);
+        assert!(matches!(
+            load_core(&mut &b"BFCK\x01\x00"[..]),
+            Err(BytecodeError::NotCore)
+        ));
+    }
+
+    #[test]
+    fn test_load_rejects_bad_input() {
+        assert!(matches!(
+            load_bytecode(&mut &b"+[-]"[..]),
+            Err(BytecodeError::NotBytecode)
+        ));
+        assert!(matches!(
+            load_bytecode(&mut &b"BFBC\x02\x00"[..]),
+            Err(BytecodeError::UnsupportedVersion(2))
+        ));
+
+        let mut out = Vec::new();
+        save_bytecode(&[Op::Jz(1), Op::Jnz(0)], &mut out).unwrap();
+        let last = out.len() - 1;
+        out[last] = 1;
+        assert_eq!(
+            load_bytecode(&mut out.as_slice()).unwrap_err().to_string(),
+            "corrupt bytecode: inconsistent jump target"
+        );
+        out.truncate(last);
+        assert_eq!(
+            load_bytecode(&mut out.as_slice()).unwrap_err().to_string(),
+            "corrupt bytecode: unexpected end of input"
+        );
+    }
+}
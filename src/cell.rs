@@ -0,0 +1,138 @@
+use core::fmt;
+
+/// A tape cell. Implemented for `u8`, `u16` and `u32`.
+///
+/// Op operands are `u32`; a cell narrower than that uses the low bits, which
+/// gives the same result as doing all the arithmetic in the cell's width.
+pub trait Cell: Copy + Default + Eq + fmt::Debug + Send + Sync + 'static {
+    const WIDTH: CellWidth;
+
+    /// Truncating conversion from an op operand.
+    fn from_u32(v: u32) -> Self;
+    fn to_u32(self) -> u32;
+    fn wrapping_add(self, rhs: Self) -> Self;
+    fn wrapping_sub(self, rhs: Self) -> Self;
+    fn wrapping_mul(self, rhs: Self) -> Self;
+
+    fn from_byte(b: u8) -> Self {
+        Self::from_u32(b as u32)
+    }
+
+    /// The low byte of the cell, which is what `.` writes.
+    fn to_byte(self) -> u8 {
+        self.to_u32() as u8
+    }
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+
+    /// Index of the first zero cell in `cells`.
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|c| c.is_zero())
+    }
+
+    /// Index of the last zero cell in `cells`.
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|c| c.is_zero())
+    }
+}
+
+macro_rules! impl_cell {
+    ($t:ty, $width:ident $(, $extra:item)*) => {
+        impl Cell for $t {
+            const WIDTH: CellWidth = CellWidth::$width;
+
+            #[inline(always)]
+            fn from_u32(v: u32) -> Self {
+                v as $t
+            }
+
+            #[inline(always)]
+            fn to_u32(self) -> u32 {
+                self as u32
+            }
+
+            #[inline(always)]
+            fn wrapping_add(self, rhs: Self) -> Self {
+                <$t>::wrapping_add(self, rhs)
+            }
+
+            #[inline(always)]
+            fn wrapping_sub(self, rhs: Self) -> Self {
+                <$t>::wrapping_sub(self, rhs)
+            }
+
+            #[inline(always)]
+            fn wrapping_mul(self, rhs: Self) -> Self {
+                <$t>::wrapping_mul(self, rhs)
+            }
+
+            $($extra)*
+        }
+    };
+}
+
+// Byte cells are searched with memchr, which is vectorized.
+impl_cell!(
+    u8,
+    U8,
+    #[inline(always)]
+    fn find_zero(cells: &[u8]) -> Option<usize> {
+        memchr::memchr(0, cells)
+    },
+    #[inline(always)]
+    fn rfind_zero(cells: &[u8]) -> Option<usize> {
+        memchr::memrchr(0, cells)
+    }
+);
+impl_cell!(u16, U16);
+impl_cell!(u32, U32);
+
+/// Run-time choice of cell type, for front ends that pick it from a flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    /// Parses a width in bits (`8`, `16` or `32`).
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            8 => Some(CellWidth::U8),
+            16 => Some(CellWidth::U16),
+            32 => Some(CellWidth::U32),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+
+    /// Truncates an op operand to this width.
+    pub fn truncate(self, v: u32) -> u32 {
+        match self {
+            CellWidth::U8 => v as u8 as u32,
+            CellWidth::U16 => v as u16 as u32,
+            CellWidth::U32 => v,
+        }
+    }
+
+    /// Name of the matching Rust integer type.
+    pub fn rust_type(self) -> &'static str {
+        match self {
+            CellWidth::U8 => "u8",
+            CellWidth::U16 => "u16",
+            CellWidth::U32 => "u32",
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! `bf analyze`: tries to prove that a program ends and stays on its tape.
+
+use std::fs;
+
+use super::{Error, Result, Settings, pass_help, read_source, usage_error};
+use crate::analysis::{DEFAULT_FUEL, analyze};
+use crate::parse_with_spans;
+
+pub(super) const USAGE: &str =
+    "Usage: bf analyze [OPTIONS] [input.bf]\nTry 'bf analyze --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf analyze [OPTIONS] [input.bf]
+
+Tries to prove that a program, read from stdin without a file, ends, and
+that it never touches a cell off its tape. Prints 'proved', 'disproved' or
+'unknown' for each, and the cells it touches when they're known. The
+answers hold for any input unless --input gives one.
+
+A program that doesn't read, or is given its input, is run for up to
+--fuel ops first; if it ends, that run is the answer. Otherwise the
+answers come from the shape of its optimized ops.
+
+Options:
+      --input <FILE>        Analyze runs on this input only
+      --fuel <N>            Ops to run the program for [default: 10000000]
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --tape-size <N>       Cells on the tape [default: 30000]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut source = None;
+    let mut input = None;
+    let mut fuel = DEFAULT_FUEL;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--input" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--input expects a file"))?;
+                input =
+                    Some(fs::read(&path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?);
+            }
+            "--fuel" => {
+                fuel = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| usage_error("--fuel expects a number of ops"))?;
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+
+    let (name, raw) = read_source(source.as_deref())?;
+    let (ops, spans) = parse_with_spans(&raw, &settings.parse)
+        .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    let (ops, _) = settings.optimize(ops, spans);
+    let analysis = analyze(
+        &ops,
+        &settings.config(),
+        settings.options.cell_width,
+        input.as_deref(),
+        fuel,
+    );
+    println!("termination: {}", analysis.termination);
+    match analysis.cells {
+        Some(cells) => println!(
+            "bounds: {} (cells {} to {})",
+            analysis.bounds,
+            cells.start(),
+            cells.end()
+        ),
+        None => println!("bounds: {}", analysis.bounds),
+    }
+    Ok(())
+}
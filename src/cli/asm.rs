@@ -0,0 +1,102 @@
+//! `bf asm`: assembles the language of [`crate::asm`] into brainfuck.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::{Error, Result, Settings, pass_help, read_source, usage_error};
+use crate::format::{self, FormatOptions};
+use crate::{ParseOptions, assemble, fmt_ops, parse_with_spans};
+
+pub(super) const USAGE: &str =
+    "Usage: bf asm [OPTIONS] [input.bfa]\nTry 'bf asm --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf asm [OPTIONS] [input.bfa]
+
+Assembles a program, read from stdin without a file, into plain brainfuck.
+Besides the eight commands, which '*N' repeats, a program can name cells
+with 'cell NAME N' and move to them with '@NAME', define macros with
+'macro NAME { ... }', and include files with 'include \"PATH\"', relative to
+the program's directory. '#' starts a comment.
+
+Options:
+      --pretty              Indent loop bodies and wrap long lines
+      --emit-ir             Print the optimized ops instead
+  -O0, -O1, -O2, -O3        Optimization level for --emit-ir [default: -O2]
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+  -o <FILE>                 Write the output to FILE instead of stdout
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut source = None;
+    let mut output = None;
+    let mut pretty = false;
+    let mut emit_ir = false;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--pretty" => pretty = true,
+            "--emit-ir" => emit_ir = true,
+            "-o" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("-o expects a path"))?,
+                )
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    if pretty && emit_ir {
+        return Err(usage_error("--pretty can't be combined with --emit-ir"));
+    }
+
+    let (name, raw) = read_source(source.as_deref())?;
+    let text = String::from_utf8(raw)
+        .map_err(|_| Error::Failed(format!("{}: the program isn't UTF-8", name)))?;
+    let dir = source
+        .as_deref()
+        .and_then(|path| Path::new(path).parent())
+        .unwrap_or(Path::new(""));
+    let code = assemble(&name, &text, &mut |path| {
+        fs::read_to_string(dir.join(path)).map_err(|e| e.to_string())
+    })
+    .map_err(|e| Error::Failed(e.to_string()))?;
+
+    let out = if emit_ir {
+        let (ops, spans) = parse_with_spans(&code, &ParseOptions::default())
+            .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+        let (ops, _) = settings.optimize(ops, spans);
+        fmt_ops(&ops).into_bytes()
+    } else if pretty {
+        let tokens = format::pieces(&code, &ParseOptions::default());
+        format::pretty(&tokens, &FormatOptions::default())
+    } else {
+        let mut code = code;
+        code.push(b'\n');
+        code
+    };
+    match output {
+        Some(path) => fs::write(&path, &out).map_err(|e| Error::Failed(format!("{}: {}", path, e))),
+        None => io::stdout()
+            .lock()
+            .write_all(&out)
+            .map_err(|e| Error::Failed(e.to_string())),
+    }
+}
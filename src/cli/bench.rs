@@ -0,0 +1,364 @@
+//! `bf bench-compare`: times a program at several optimization levels on
+//! the same input, after checking that every level writes the same output.
+
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::Duration;
+
+use super::json::Json;
+use super::{Error, Result, Settings, pass_help, read_source, usage_error};
+use crate::{
+    BuiltinExecutor, Cell, CellWidth, Config, Executor, Op, OptLevel, Program, eval,
+    parse_with_spans, partial_eval,
+};
+
+pub(super) const USAGE: &str = "Usage: bf bench-compare [OPTIONS] [input.bf]\nTry 'bf bench-compare --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf bench-compare [OPTIONS] [input.bf]
+
+Runs a program, read from stdin without a file, at each optimization
+level on the same input, and fails if any level's output differs from the
+first's. Then times each level and prints a table of the ops it optimized
+to, the ops it executed, its fastest run and its speedup over the first
+level.
+
+Options:
+      --against <LEVELS>    The levels to compare, comma-separated, the first
+                            one the baseline [default: -O0,-O1,-O2,-O3]
+      --input <FILE>        Give the program the contents of FILE as its
+                            input [default: none]
+      --runs <N>            Time N runs at each level and keep the fastest
+                            [default: 5]
+      --engine <NAME>       Run with interpreter, threaded, packed, threads,
+                            jit or tiered [default: interpreter]
+      --json                Print the table as JSON
+      --disable-pass <LIST> Leave these passes out at every level
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --tape-size <N>       Cells on the tape [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap, grow,
+                            bidirectional or unchecked [default: error]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+  -h, --help                Print this help";
+
+const ENGINES: &str =
+    "--engine expects 'interpreter', 'threaded', 'packed', 'threads', 'jit' or 'tiered'";
+
+/// Runs timed at each level unless `--runs` says otherwise.
+const RUNS: usize = 5;
+
+/// How a program did at one level.
+#[derive(Debug, Clone, PartialEq)]
+struct Row {
+    level: OptLevel,
+    /// Ops the program optimized to.
+    ops: usize,
+    /// Ops executed in a run.
+    steps: u64,
+    /// The fastest run.
+    time: Duration,
+}
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut levels = vec![OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3];
+    let mut input = Vec::new();
+    let mut runs = RUNS;
+    let mut engine = BuiltinExecutor::Interpreter;
+    let mut json = false;
+    let mut source = None;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--against" => {
+                levels = args
+                    .next()
+                    .as_deref()
+                    .and_then(parse_levels)
+                    .ok_or_else(|| usage_error("--against expects levels such as -O1,-O2,-O3"))?;
+            }
+            "--input" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--input expects a file"))?;
+                input = fs::read(&path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+            }
+            "--runs" => {
+                runs = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| usage_error("--runs expects a positive number"))?;
+            }
+            "--engine" => {
+                engine = args
+                    .next()
+                    .as_deref()
+                    .and_then(BuiltinExecutor::from_name)
+                    .ok_or_else(|| usage_error(ENGINES))?;
+            }
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    if settings.passes.is_some() {
+        return Err(usage_error(
+            "--passes can't be used with bench-compare, which runs each level's",
+        ));
+    }
+
+    let (name, raw) = read_source(source.as_deref())?;
+    settings.apply_pragmas(&name, &raw)?;
+    let (ops, spans) = parse_with_spans(&raw, &settings.parse)
+        .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    let compare = match settings.options.cell_width {
+        CellWidth::U8 => compare::<u8>,
+        CellWidth::U16 => compare::<u16>,
+        CellWidth::U32 => compare::<u32>,
+    };
+    let rows = compare(&ops, &spans, &mut settings, &levels, &input, engine, runs)
+        .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    let report = if json {
+        format!("{}\n", json_report(&rows))
+    } else {
+        report(&rows)
+    };
+    io::stdout()
+        .lock()
+        .write_all(report.as_bytes())
+        .map_err(|e| Error::Failed(e.to_string()))
+}
+
+/// Parses `--against`'s list, such as `-O1,-O2,-O3`. The dashes and the
+/// `O`s are optional, so `1,2,3` does too.
+fn parse_levels(list: &str) -> Option<Vec<OptLevel>> {
+    let levels = list
+        .split(',')
+        .map(|level| OptLevel::from_name(level.strip_prefix('-').unwrap_or(level)))
+        .collect::<Option<Vec<_>>>()?;
+    Some(levels)
+}
+
+/// Optimizes `ops` at each of `levels` and runs them on `input`, failing
+/// if a level's output differs from the first level's, then times `runs`
+/// runs of each with `engine`.
+fn compare<C: Cell>(
+    ops: &[Op],
+    spans: &[Range<usize>],
+    settings: &mut Settings,
+    levels: &[OptLevel],
+    input: &[u8],
+    mut engine: BuiltinExecutor,
+    runs: usize,
+) -> std::result::Result<Vec<Row>, String> {
+    let config = settings.config();
+    let counting = Config {
+        max_steps: Some(u64::MAX),
+        ..config.clone()
+    };
+    let mut first: Option<(OptLevel, Vec<u8>)> = None;
+    let mut rows = Vec::with_capacity(levels.len());
+    for &level in levels {
+        settings.level = level;
+        let (mut ops, _) = settings.optimize(ops.to_vec(), spans.to_vec());
+        if level >= OptLevel::O3 {
+            ops = partial_eval(
+                ops,
+                &config,
+                settings.options.cell_width,
+                eval::DEFAULT_FUEL,
+            );
+        }
+
+        // An untimed run counts the ops and catches the output.
+        let mut io = (input, Vec::new());
+        let stats = Executor::<C>::run(
+            &mut BuiltinExecutor::Interpreter,
+            &Program::new(ops.clone(), counting.clone()),
+            &mut io,
+        )
+        .map_err(|e| format!("-{:?}: {}", level, e))?;
+        match &first {
+            Some((baseline, output)) if *output != io.1 => {
+                return Err(format!(
+                    "the output at -{:?} differs from the output at -{:?}",
+                    level, baseline
+                ));
+            }
+            Some(_) => {}
+            None => first = Some((level, io.1)),
+        }
+
+        let program = Program::new(ops, config.clone());
+        let mut time = Duration::MAX;
+        for _ in 0..runs {
+            let run = Executor::<C>::run(&mut engine, &program, &mut (input, io::sink()))
+                .map_err(|e| format!("-{:?}: {}", level, e))?;
+            time = time.min(run.elapsed);
+        }
+        rows.push(Row {
+            level,
+            ops: program.ops.len(),
+            steps: stats.steps,
+            time,
+        });
+    }
+    Ok(rows)
+}
+
+/// How many times faster `row` ran than `baseline`, if it took any time
+/// to measure.
+fn speedup(baseline: &Row, row: &Row) -> Option<f64> {
+    let secs = row.time.as_secs_f64();
+    (secs > 0.0).then(|| baseline.time.as_secs_f64() / secs)
+}
+
+/// The table `bf bench-compare` prints.
+fn report(rows: &[Row]) -> String {
+    let mut report = format!(
+        "{:<5}{:>10}{:>14}{:>12}{:>9}\n",
+        "level", "ops", "steps", "time (ms)", "speedup"
+    );
+    let Some(baseline) = rows.first() else {
+        return report;
+    };
+    for row in rows {
+        let speedup = match speedup(baseline, row) {
+            Some(speedup) => format!("{:.2}x", speedup),
+            None => "-".to_string(),
+        };
+        report.push_str(&format!(
+            "-{:<4?}{:>10}{:>14}{:>12.3}{:>9}\n",
+            row.level,
+            row.ops,
+            row.steps,
+            row.time.as_secs_f64() * 1000.0,
+            speedup
+        ));
+    }
+    report
+}
+
+/// The table as `--json` prints it: `{"levels": [...]}`, a row for each
+/// level with its `level`, `ops`, `steps`, `time_ms` and `speedup`, which
+/// is null for a run too quick to measure.
+fn json_report(rows: &[Row]) -> Json {
+    let levels = rows
+        .iter()
+        .map(|row| {
+            let speedup = rows
+                .first()
+                .and_then(|baseline| speedup(baseline, row))
+                .map_or(Json::Null, Json::Number);
+            Json::object([
+                ("level", format!("-{:?}", row.level).into()),
+                ("ops", row.ops.into()),
+                ("steps", row.steps.into()),
+                ("time_ms", Json::Number(row.time.as_secs_f64() * 1000.0)),
+                ("speedup", speedup),
+            ])
+        })
+        .collect::<Vec<_>>();
+    Json::object([("levels", levels.into())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_levels() {
+        assert_eq!(
+            parse_levels("-O1,-O2,-O3"),
+            Some(vec![OptLevel::O1, OptLevel::O2, OptLevel::O3])
+        );
+        assert_eq!(parse_levels("3,O0"), Some(vec![OptLevel::O3, OptLevel::O0]));
+        assert_eq!(parse_levels("-O1,-O4"), None);
+        assert_eq!(parse_levels(""), None);
+    }
+
+    #[test]
+    fn test_compare() {
+        let hello = include_bytes!("../../examples/helloworld.bf");
+        let (ops, spans) = parse_with_spans(hello, &Default::default()).unwrap();
+        let mut settings = Settings::default();
+        let levels = [OptLevel::O0, OptLevel::O2, OptLevel::O3];
+        let rows = compare::<u8>(
+            &ops,
+            &spans,
+            &mut settings,
+            &levels,
+            b"",
+            BuiltinExecutor::Interpreter,
+            1,
+        )
+        .unwrap();
+        assert_eq!(rows.iter().map(|row| row.level).collect::<Vec<_>>(), levels);
+        assert!(rows[0].steps > rows[1].steps);
+        // Hello World needs no input, so -O3 evaluates all of it.
+        assert!(rows[2].ops < rows[1].ops);
+
+        let report = report(&rows);
+        assert!(report.starts_with("level"));
+        assert!(report.contains("\n-O0 "));
+        assert!(report.contains("1.00x"));
+        let json = json_report(&rows);
+        let first = &json.get("levels").and_then(Json::as_array).unwrap()[0];
+        assert_eq!(first.get("level").and_then(Json::as_str), Some("-O0"));
+        assert_eq!(
+            first.get("steps").and_then(Json::as_u64),
+            Some(rows[0].steps)
+        );
+    }
+
+    #[test]
+    fn test_compare_input() {
+        let (ops, spans) = parse_with_spans(b",.,.,.,.", &Default::default()).unwrap();
+        let mut settings = Settings::default();
+        let levels = [OptLevel::O1, OptLevel::O3];
+        let rows = compare::<u16>(
+            &ops,
+            &spans,
+            &mut settings,
+            &levels,
+            b"echo",
+            BuiltinExecutor::Packed,
+            2,
+        )
+        .unwrap();
+        assert_eq!(rows.len(), 2);
+
+        // A level that fails fails the comparison, saying which it was.
+        let (ops, spans) = parse_with_spans(b"+.<+", &Default::default()).unwrap();
+        let result = compare::<u8>(
+            &ops,
+            &spans,
+            &mut settings,
+            &levels,
+            b"",
+            BuiltinExecutor::Interpreter,
+            1,
+        );
+        assert!(result.is_err_and(|e| e.starts_with("-O1: ")));
+    }
+}
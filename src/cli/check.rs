@@ -0,0 +1,83 @@
+//! `bf check`: reports bracket errors without running the program, and
+//! with `--lint`, code that is valid but suspicious.
+
+use super::diagnostic::{ColorChoice, Diagnostic};
+use super::{Error, Result, read_source, usage_error};
+use crate::{LintKind, ParseOptions, bracket_errors, lint, parse_with_spans};
+
+pub(super) const USAGE: &str =
+    "Usage: bf check [OPTIONS] [input.bf]\nTry 'bf check --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf check [OPTIONS] [input.bf]
+
+Checks a program, read from stdin without a file, for unmatched brackets.
+Reports every one to stderr with where it is in the source, and exits with
+status 1 if there are any. Prints nothing and exits with status 0 if there
+are none.
+
+With --lint, also warns about loops that never run or never end, code after
+an endless loop and a pointer that must move off the left of the tape.
+Warnings don't change the exit status.
+
+Options:
+      --lint                Also warn about suspicious code
+      --color <WHEN>        Color the reports: auto, always or never
+                            [default: auto]
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut source = None;
+    let mut color = ColorChoice::default();
+    let mut lints = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lint" => lints = true,
+            "--color" => {
+                color = args
+                    .next()
+                    .as_deref()
+                    .and_then(ColorChoice::from_name)
+                    .ok_or_else(|| usage_error("--color expects 'auto', 'always' or 'never'"))?;
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let (name, raw) = read_source(source.as_deref())?;
+    let errors = bracket_errors(&raw, &ParseOptions::default());
+    let color = color.enabled();
+    for error in &errors {
+        let report = Diagnostic::parse_error(error).render(&name, &raw, color);
+        eprintln!("bf check: {}\n", report);
+    }
+    if lints && let Ok((ops, spans)) = parse_with_spans(&raw, &ParseOptions::default()) {
+        for lint in lint(&ops, &spans) {
+            let report = Diagnostic::warning(lint.kind.to_string())
+                .label(lint.span, label(lint.kind))
+                .render(&name, &raw, color);
+            eprintln!("bf check: {}\n", report);
+        }
+    }
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(Error::Failed(format!("{}: 1 bracket error", name))),
+        n => Err(Error::Failed(format!("{}: {} bracket errors", name, n))),
+    }
+}
+
+/// What to say under the code a lint of `kind` is about.
+fn label(kind: LintKind) -> &'static str {
+    match kind {
+        LintKind::DeadLoop => "its cell is always zero here",
+        LintKind::EndlessLoop => "entered on a non-zero cell that its body never changes",
+        LintKind::EndlessIfEntered => "its body never changes the cell it tests",
+        LintKind::WrappingClear => "counts up through every value; '[-]' counts down",
+        LintKind::UnreachableCode => "the loop before this never ends",
+        LintKind::PointerUnderflow => "this is left of the first cell on every run",
+    }
+}
@@ -0,0 +1,519 @@
+//! `bf compile` / `bfc`: translates a program to another language or a
+//! native executable.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{self, Command};
+
+use super::config::Defaults;
+use super::diagnostic::{ColorChoice, Diagnostic};
+use super::{Error, Result, Settings, pass_help, read_sources, usage_error};
+use crate::{
+    CellWidth, CodegenOptions, Op, OptLevel, SourceMap, emit_c, emit_elf, emit_llvm_ir,
+    emit_rust_mapped, eval, parse_with_spans, partial_eval, to_brainfuck,
+};
+
+pub(super) fn usage(prog: &str) -> String {
+    format!(
+        "Usage: {} [OPTIONS] [input.bf...]\nTry '{} --help' for more information.",
+        prog, prog
+    )
+}
+
+fn help(prog: &str) -> String {
+    format!(
+        "\
+Usage: {} [OPTIONS] [input.bf...]
+
+Compiles a brainfuck program, read from stdin without a file and joined in
+order from several files. The defaults of -O, --tape-size, --cell-size and
+--eof can be changed in ~/.config/bf/config.toml or with BF_* variables.
+
+Options:
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]; -O3 also
+                            evaluates like --evaluate
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --opt-stats           Print what each pass did to stderr: ops and loops
+                            before and after it, and the ops it made
+      --tape-size <N>       Cells on the tape [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap, grow,
+                            bidirectional or unchecked [default: error]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --debug-char          Make '#' print the pointer and nearby cells to stderr
+      --unsafe              Reach cells without bounds checks in the Rust output
+      --evaluate            Run the program up to its first ',' while
+                            compiling, and emit only what it printed and the
+                            tape it left
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+      --target <T>          Output language: rust, c, llvm-ir, elf or bf
+                            [default: rust]
+  -o <FILE>                 Write the output to FILE instead of stdout
+      --build               Compile the output to an executable
+      --emit-project <DIR>  Write a Cargo crate to DIR
+  -h, --help                Print this help
+  -V, --version             Print the version",
+        prog
+    )
+}
+
+/// The language `bfc` writes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Rust,
+    C,
+    LlvmIr,
+    Elf,
+    /// Plain brainfuck, for using the optimizer on its own.
+    Brainfuck,
+}
+
+pub(super) fn main(prog: &str, mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    Defaults::load()?.apply(&mut settings)?;
+    let mut target = Target::Rust;
+    let mut files = Vec::new();
+    let mut output = None;
+    let mut build = false;
+    let mut evaluate = false;
+    let mut project = None;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--target" => {
+                target = match args.next().as_deref() {
+                    Some("rust") => Target::Rust,
+                    Some("c") => Target::C,
+                    Some("llvm-ir") => Target::LlvmIr,
+                    Some("elf") => Target::Elf,
+                    Some("bf") => Target::Brainfuck,
+                    _ => {
+                        return Err(usage_error(
+                            "--target expects 'rust', 'c', 'llvm-ir', 'elf' or 'bf'",
+                        ));
+                    }
+                };
+            }
+            "-o" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("-o expects a path"))?,
+                )
+            }
+            "--build" => build = true,
+            "--evaluate" => evaluate = true,
+            "--unsafe" => settings.options.unsafe_indexing = true,
+            "--emit-project" => {
+                project = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--emit-project expects a directory"))?,
+                );
+            }
+            "-h" | "--help" => {
+                println!("{}", help(prog));
+                return Ok(());
+            }
+            "-V" | "--version" => {
+                println!("{} {}", prog, env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            _ if !arg.starts_with('-') => files.push(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    if project.is_some() && (target != Target::Rust || build || output.is_some()) {
+        return Err(usage_error(
+            "--emit-project writes a Rust crate and can't be combined with --target, -o or --build",
+        ));
+    }
+
+    let (sources, raw) = read_sources(&files)?;
+    settings.apply_pragmas(sources.name(), &raw)?;
+    // The first file names the outputs.
+    let source = files.first().map(String::as_str);
+    let Settings {
+        level,
+        ref options,
+        ref parse,
+        ..
+    } = settings;
+    let evaluate = evaluate || level >= OptLevel::O3;
+
+    // 1. Parse (RLE + Offset Optimization)
+    let (ops, spans) = parse_with_spans(&raw, parse).map_err(|e| {
+        let color = ColorChoice::default().enabled();
+        Error::Failed(Diagnostic::parse_error(&e).render_in(&sources, &raw, color))
+    })?;
+
+    // 2. Optimize
+    let (ops, spans) = settings.optimize(ops, spans);
+    // The comments quote the commands, which a token map spells differently.
+    let map = SourceMap {
+        source: &raw,
+        spans: if parse.tokens.is_some() { &[] } else { &spans },
+    };
+
+    if let Some(dir) = project {
+        return emit_project(Path::new(&dir), source, &ops, &map, evaluate, options)
+            .map_err(|e| Error::Failed(format!("{}: {}", dir, e)));
+    }
+
+    // 3. Code Generation
+    // The emitters only fail on settings they can't support.
+    let code =
+        generate(ops, &map, evaluate, options, target).map_err(|e| usage_error(e.to_string()))?;
+
+    // 4. Output, or hand the code to a native compiler
+    if build && target != Target::Elf {
+        let binary = output.unwrap_or_else(|| default_binary(source));
+        run_compiler(target, &code, &binary)
+    } else if let Some(path) = output.or_else(|| {
+        // An executable needs a file to live in.
+        build.then(|| default_binary(source))
+    }) {
+        let failed = |e: io::Error| Error::Failed(format!("{}: {}", path, e));
+        fs::write(&path, &code).map_err(failed)?;
+        if target == Target::Elf {
+            make_executable(&path).map_err(failed)?;
+        }
+        Ok(())
+    } else {
+        io::stdout()
+            .lock()
+            .write_all(&code)
+            .map_err(|e| Error::Failed(e.to_string()))
+    }
+}
+
+/// Generates code for `target` from optimized ops, evaluating the
+/// input-free prefix first if `evaluate`. Rust gets comments pointing back
+/// into the source the ops came from, unless that was evaluated.
+fn generate(
+    mut ops: Vec<Op>,
+    map: &SourceMap<'_>,
+    evaluate: bool,
+    options: &CodegenOptions,
+    target: Target,
+) -> io::Result<Vec<u8>> {
+    let mut map = *map;
+    if evaluate {
+        map.spans = &[];
+        // Run the input-free prefix now and emit only its result.
+        let settings = Settings {
+            options: options.clone(),
+            ..Settings::default()
+        };
+        ops = partial_eval(
+            ops,
+            &settings.config(),
+            options.cell_width,
+            eval::DEFAULT_FUEL,
+        );
+    }
+    let mut code = Vec::new();
+    match target {
+        Target::Rust => emit_rust_mapped(&ops, &map, options, &mut code),
+        Target::C => emit_c(&ops, options, &mut code),
+        Target::LlvmIr => emit_llvm_ir(&ops, options, &mut code),
+        Target::Elf => emit_elf(&ops, options, &mut code),
+        Target::Brainfuck => {
+            code = to_brainfuck(&ops).into_bytes();
+            code.push(b'\n');
+            Ok(())
+        }
+    }?;
+    Ok(code)
+}
+
+/// A Cargo package name for the program in `source`.
+fn package_name(source: Option<&str>) -> String {
+    let stem = source
+        .and_then(|path| Path::new(path).file_stem())
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut name: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    name = name.trim_matches('-').to_string();
+    if name.is_empty() {
+        "bf-program".to_string()
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("bf-{}", name)
+    } else {
+        name
+    }
+}
+
+/// The tape sizes `--emit-project` crates offer as features, smallest
+/// first, besides the `--tape-size` they default to.
+const PROJECT_TAPES: [(&str, usize); 2] = [("tape-64k", 1 << 16), ("tape-1m", 1 << 20)];
+
+/// Writes a Cargo crate running the program to `dir`. Each cell width and
+/// tape size gets its own copy of the code, picked by the `cell16` and
+/// `cell32` features and those in [`PROJECT_TAPES`]; `--cell-size` and
+/// `--tape-size` only set the default.
+fn emit_project(
+    dir: &Path,
+    source: Option<&str>,
+    ops: &[Op],
+    map: &SourceMap<'_>,
+    evaluate: bool,
+    options: &CodegenOptions,
+) -> io::Result<()> {
+    let src = dir.join("src");
+    fs::create_dir_all(&src)?;
+    // The copy for each width and tape, and what its `cfg` requires. The
+    // widest cells and the biggest tape enabled win.
+    let feature = |name: &str| format!("feature = \"{}\"", name);
+    let not = |name: &str| format!("not(feature = \"{}\")", name);
+    let widths = [
+        (CellWidth::U32, vec![feature("cell32")]),
+        (CellWidth::U16, vec![feature("cell16"), not("cell32")]),
+        (CellWidth::U8, vec![not("cell16"), not("cell32")]),
+    ];
+    let mut tapes = Vec::new();
+    let mut bigger = Vec::new();
+    for &(name, size) in PROJECT_TAPES.iter().rev() {
+        let mut cfg = vec![feature(name)];
+        cfg.extend(bigger.iter().map(|&name| not(name)));
+        tapes.push((format!("_tape{}", size), size, cfg));
+        bigger.push(name);
+    }
+    let cfg = bigger.iter().map(|&name| not(name)).collect();
+    tapes.push((String::new(), options.tape_size, cfg));
+
+    let mut includes = String::new();
+    for (suffix, tape_size, tape_cfg) in &tapes {
+        for (width, width_cfg) in &widths {
+            let options = CodegenOptions {
+                cell_width: *width,
+                tape_size: *tape_size,
+                ..options.clone()
+            };
+            let code = generate(ops.to_vec(), map, evaluate, &options, Target::Rust)?;
+            let file = format!("cell{}{}.rs", width.bits(), suffix);
+            fs::write(src.join(&file), code)?;
+            let cfg = [&width_cfg[..], &tape_cfg[..]].concat().join(", ");
+            includes.push_str(&format!("#[cfg(all({}))]\ninclude!(\"{}\");\n", cfg, file));
+        }
+    }
+
+    let default = match options.cell_width {
+        CellWidth::U8 => "",
+        CellWidth::U16 => "\"cell16\"",
+        CellWidth::U32 => "\"cell32\"",
+    };
+    let tape_features: String = PROJECT_TAPES
+        .iter()
+        .map(|(feature, size)| format!("# A tape of {} cells.\n{} = []\n", size, feature))
+        .collect();
+    let manifest = format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+# Cells are 8 bits unless one of these is enabled; cell32 wins over cell16.
+# The tape has {tape} cells unless a tape feature is; the biggest wins.
+[features]
+default = [{default}]
+cell16 = []
+cell32 = []
+{tape_features}
+[profile.release]
+codegen-units = 1
+lto = true
+panic = "abort"
+"#,
+        name = package_name(source),
+        tape = options.tape_size,
+    );
+    fs::write(dir.join("Cargo.toml"), manifest)?;
+    fs::write(dir.join(".gitignore"), "/target\n")?;
+    let main = format!(
+        "//! Generated by bfc from {}, with a {}-cell tape by default.\n\n{}",
+        source.unwrap_or("stdin"),
+        options.tape_size,
+        includes
+    );
+    fs::write(src.join("main.rs"), main)
+}
+
+/// The binary `--build` writes without `-o`: the input's name without its
+/// extension, or `a.out` for stdin.
+fn default_binary(source: Option<&str>) -> String {
+    source
+        .and_then(|path| Path::new(path).file_stem())
+        .map_or_else(
+            || "a.out".to_string(),
+            |stem| stem.to_string_lossy().into_owned(),
+        )
+}
+
+/// Compiles `code` to `binary` with the toolchain for `target`.
+fn run_compiler(target: Target, code: &[u8], binary: &str) -> Result<()> {
+    let (compiler, extension, flags): (&str, &str, &[&str]) = match target {
+        // Lints on generated code aren't actionable.
+        Target::Rust => ("rustc", "rs", &["-O", "--cap-lints", "allow"]),
+        Target::C => ("cc", "c", &["-O2"]),
+        Target::LlvmIr => ("clang", "ll", &["-O2"]),
+        Target::Brainfuck => {
+            return Err(usage_error("--build can't be used with --target bf"));
+        }
+        Target::Elf => unreachable!("ELF output needs no compiler"),
+    };
+    let source = env::temp_dir().join(format!("bfc-{}.{}", process::id(), extension));
+    fs::write(&source, code).map_err(|e| Error::Failed(format!("{}: {}", source.display(), e)))?;
+    let status = Command::new(compiler)
+        .args(flags)
+        .arg(&source)
+        .arg("-o")
+        .arg(binary)
+        .status();
+    let _ = fs::remove_file(&source);
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(Error::Failed(format!(
+            "{} failed to compile the generated code",
+            compiler
+        ))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(Error::Failed(format!(
+            "--build needs `{}` on the PATH; install it, or drop --build to write the generated code instead",
+            compiler
+        ))),
+        Err(e) => Err(Error::Failed(format!("could not run {}: {}", compiler, e))),
+    }
+}
+
+#[cfg(unix)]
+fn make_executable(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &str) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(package_name(Some("examples/Hello World.bf")), "hello-world");
+        assert_eq!(package_name(Some("99bottles.bf")), "bf-99bottles");
+        assert_eq!(package_name(None), "bf-program");
+    }
+
+    #[test]
+    fn test_generate_evaluate() {
+        let source = b"++++++++[>++++++++<-]>+.+.";
+        let ops = crate::optimize(crate::parse(source).unwrap());
+        let map = SourceMap { source, spans: &[] };
+        let options = CodegenOptions::default();
+        let code = generate(ops.clone(), &map, true, &options, Target::Rust).unwrap();
+        let code = String::from_utf8(code).unwrap();
+        assert!(code.contains("    std::io::stdout().write_all(b\"AB\").unwrap();\n"));
+        assert!(!code.contains("tape"));
+        let code = generate(ops, &map, false, &options, Target::Rust).unwrap();
+        assert!(String::from_utf8(code).unwrap().contains("tape"));
+    }
+
+    #[test]
+    fn test_output_and_build() {
+        let dir = env::temp_dir().join(format!("bfc-build-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("ab.b");
+        fs::write(&source, "++++++++[>++++++++<-]>+.+.").unwrap();
+        let source = source.to_str().unwrap();
+
+        let code = dir.join("ab.rs");
+        let args = [source, "-o", code.to_str().unwrap()];
+        main("bfc", args.map(String::from).into_iter()).unwrap();
+        assert!(fs::read_to_string(&code).unwrap().contains("fn main()"));
+
+        // Building needs rustc on the PATH.
+        if Command::new("rustc").arg("--version").output().is_ok() {
+            let binary = dir.join("ab");
+            let args = [source, "--build", "-o", binary.to_str().unwrap()];
+            main("bfc", args.map(String::from).into_iter()).unwrap();
+            let output = Command::new(&binary).output().unwrap();
+            assert!(output.status.success());
+            assert_eq!(output.stdout, b"AB");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_emit_project_tape_features() {
+        let dir = env::temp_dir().join(format!("bfc-project-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // Cell 40,000 is past the default tape but on the 64k one.
+        let source = dir.join("far.b");
+        fs::write(&source, format!("{}+.", ">".repeat(40_000))).unwrap();
+        let project = dir.join("far");
+        let args = [
+            source.to_str().unwrap(),
+            "--emit-project",
+            project.to_str().unwrap(),
+        ];
+        main("bfc", args.map(String::from).into_iter()).unwrap();
+        let manifest = fs::read_to_string(project.join("Cargo.toml")).unwrap();
+        assert!(manifest.contains("\ntape-64k = []\n") && manifest.contains("\ntape-1m = []\n"));
+
+        // Building it takes the Cargo running the tests.
+        if let Some(cargo) = env::var_os("CARGO") {
+            let run = |args: &[&str]| {
+                Command::new(&cargo)
+                    .args(["run", "--quiet", "--manifest-path"])
+                    .arg(project.join("Cargo.toml"))
+                    .args(args)
+                    .env("CARGO_TARGET_DIR", dir.join("target"))
+                    .output()
+                    .unwrap()
+            };
+            let output = run(&[]);
+            assert!(!output.status.success());
+            assert!(String::from_utf8_lossy(&output.stderr).contains("outside the tape"));
+            let output = run(&["--features", "tape-64k,cell16"]);
+            assert!(output.status.success());
+            assert_eq!(output.stdout, [1]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_emit_project_rejects_other_targets() {
+        let args = ["--target", "c", "--emit-project", "out"].map(String::from);
+        assert!(matches!(
+            main("bfc", args.into_iter()),
+            Err(Error::Usage(_))
+        ));
+    }
+}
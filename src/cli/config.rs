@@ -0,0 +1,225 @@
+//! Defaults for `run` and `compile` from `~/.config/bf/config.toml` and
+//! `BF_*` environment variables, for users who always want the same
+//! machine:
+//!
+//! ```toml
+//! tape_size = 65536
+//! cell_size = 16
+//! eof = "0"
+//! opt_level = 3
+//! engine = "threaded"
+//! ```
+//!
+//! `BF_TAPE_SIZE`, `BF_CELL_SIZE`, `BF_EOF`, `BF_OPT_LEVEL` and
+//! `BF_ENGINE` override the file. Each stands for a flag given before the
+//! command line's, so the command line overrides both, but unlike a flag
+//! it gives way to a program's pragmas.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use super::{Error, Result, Settings};
+use crate::Pragmas;
+
+/// The keys of the config file, the variables that override them, and
+/// the flags they stand for.
+const KEYS: [(&str, &str, &str); 5] = [
+    ("tape_size", "BF_TAPE_SIZE", "--tape-size"),
+    ("cell_size", "BF_CELL_SIZE", "--cell-size"),
+    ("eof", "BF_EOF", "--eof"),
+    ("opt_level", "BF_OPT_LEVEL", "-O"),
+    ("engine", "BF_ENGINE", "--engine"),
+];
+
+/// A default: the key it sets, the flag it stands for, its value, and
+/// where it was set, for errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Setting {
+    key: &'static str,
+    flag: &'static str,
+    value: String,
+    origin: String,
+}
+
+/// The defaults in effect, the environment's after the file's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Defaults(Vec<Setting>);
+
+impl Defaults {
+    /// Reads the config file, if there is one, and the environment.
+    pub(super) fn load() -> Result<Defaults> {
+        let mut defaults = match config_path() {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(text) => from_toml(&path.display().to_string(), &text)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(Error::Failed(format!("{}: {}", path.display(), e))),
+            },
+            None => Vec::new(),
+        };
+        defaults.extend(from_env(|name| env::var(name).ok()));
+        Ok(Defaults(defaults))
+    }
+
+    /// Sets `settings` as the defaults' flags would. The flags parsed
+    /// after this still override them, and a program's pragmas too.
+    pub(super) fn apply(&self, settings: &mut Settings) -> Result<()> {
+        for setting in self.0.iter().filter(|s| s.key != "engine") {
+            let (flag, value) = match setting.flag {
+                "-O" => (format!("-O{}", setting.value), None),
+                flag => (flag.to_string(), Some(setting.value.clone())),
+            };
+            let failed = |msg: String| Error::Failed(format!("{}: {}", setting.origin, msg));
+            match settings.parse(&flag, &mut value.into_iter()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(failed("opt_level expects 0, 1, 2 or 3".to_string()));
+                }
+                Err(Error::Usage(msg) | Error::Failed(msg)) => return Err(failed(msg)),
+            }
+        }
+        settings.flags = Pragmas::default();
+        Ok(())
+    }
+
+    /// The default engine, read by `from_name`, if one was set.
+    pub(super) fn engine<T>(&self, from_name: impl Fn(&str) -> Result<T>) -> Result<Option<T>> {
+        let Some(setting) = self.0.iter().rfind(|s| s.key == "engine") else {
+            return Ok(None);
+        };
+        from_name(&setting.value)
+            .map(Some)
+            .map_err(|(Error::Usage(msg) | Error::Failed(msg))| {
+                Error::Failed(format!("{}: {}", setting.origin, msg))
+            })
+    }
+}
+
+/// `$XDG_CONFIG_HOME/bf/config.toml`, or `~/.config/bf/config.toml`.
+fn config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".config")))?;
+    Some(dir.join("bf").join("config.toml"))
+}
+
+/// Reads the `key = value` lines of a config file called `name`. Values
+/// are integers or double-quoted strings, and `#` starts a comment.
+fn from_toml(name: &str, text: &str) -> Result<Vec<Setting>> {
+    let mut defaults = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let origin = format!("{}:{}", name, i + 1);
+        let line = match line.split_once('#') {
+            // A `#` inside a quoted string isn't a comment.
+            Some((before, _)) if before.matches('"').count() % 2 == 0 => before,
+            _ => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(Error::Failed(format!("{}: expected 'key = value'", origin)));
+        };
+        let Some(&(key, _, flag)) = KEYS.iter().find(|(k, _, _)| *k == key.trim()) else {
+            return Err(Error::Failed(format!(
+                "{}: unknown key '{}'; expected tape_size, cell_size, eof, opt_level or engine",
+                origin,
+                key.trim()
+            )));
+        };
+        let value = value.trim();
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(string) => string,
+            None if value.parse::<i64>().is_ok() => value,
+            None => {
+                return Err(Error::Failed(format!(
+                    "{}: expected an integer or a quoted string",
+                    origin
+                )));
+            }
+        };
+        defaults.push(Setting {
+            key,
+            flag,
+            value: value.to_string(),
+            origin,
+        });
+    }
+    Ok(defaults)
+}
+
+/// The defaults set by the `BF_*` variables that `var` finds.
+fn from_env(var: impl Fn(&str) -> Option<String>) -> Vec<Setting> {
+    KEYS.iter()
+        .filter_map(|&(key, name, flag)| {
+            Some(Setting {
+                key,
+                flag,
+                value: var(name)?,
+                origin: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CellWidth, EofBehavior, OptLevel};
+
+    #[test]
+    fn test_defaults() {
+        let text = "# mine\ntape_size = 100\neof = \"-1\" # for old programs\nopt_level = 3\n\n";
+        let mut defaults = from_toml("config.toml", text).unwrap();
+        defaults.extend(from_env(|name| {
+            (name == "BF_ENGINE" || name == "BF_OPT_LEVEL").then(|| "1".to_string())
+        }));
+        let defaults = Defaults(defaults);
+
+        let mut settings = Settings::default();
+        assert_eq!(defaults.apply(&mut settings), Ok(()));
+        assert_eq!(settings.options.tape_size, 100);
+        assert_eq!(settings.options.eof, EofBehavior::MinusOne);
+        assert_eq!(settings.options.cell_width, CellWidth::U8);
+        // The environment's level comes after the file's.
+        assert_eq!(settings.level, OptLevel::O1);
+        // Pragmas aren't overridden.
+        assert_eq!(settings.flags, Pragmas::default());
+        assert_eq!(defaults.engine(|name| Ok(name.len())), Ok(Some(1)));
+        assert_eq!(
+            defaults.engine(|_| Err::<(), _>(Error::Usage("no".to_string()))),
+            Err(Error::Failed("BF_ENGINE: no".to_string()))
+        );
+
+        let bad =
+            |text| from_toml("c", text).and_then(|d| Defaults(d).apply(&mut Settings::default()));
+        assert_eq!(
+            bad("tape = 1"),
+            Err(Error::Failed(
+                "c:1: unknown key 'tape'; expected tape_size, cell_size, eof, opt_level or engine"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            bad("\neof = 0 1"),
+            Err(Error::Failed(
+                "c:2: expected an integer or a quoted string".to_string()
+            ))
+        );
+        assert_eq!(
+            bad("opt_level = 4"),
+            Err(Error::Failed(
+                "c:1: opt_level expects 0, 1, 2 or 3".to_string()
+            ))
+        );
+        assert_eq!(
+            bad("cell_size = 7"),
+            Err(Error::Failed(
+                "c:1: --cell-size expects 8, 16 or 32".to_string()
+            ))
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! `bf test`: runs a directory of programs and checks their output against
+//! what's expected.
+//!
+//! A test is a program `foo.b` (or `foo.bf`) next to `foo.expected`, the
+//! output it should write, and optionally `foo.in`, its input. Programs
+//! without an `.expected` file aren't tests and are left alone.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{Error, Result, Settings, pass_help, usage_error};
+use crate::{Cell, CellWidth, Config, Interpreter, OptLevel, parse_with_spans};
+
+pub(super) const USAGE: &str =
+    "Usage: bf test [OPTIONS] <dir>\nTry 'bf test --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf test [OPTIONS] <dir>
+
+Runs every program in dir and its subdirectories that has an expected
+output: foo.b or foo.bf with foo.expected, reading foo.in if there is one.
+Reports each test that fails with a diff of its output, and exits with
+status 1 if any did.
+
+Options:
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --all-levels          Run each test at every optimization level
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --tape-size <N>       Cells on the tape [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap, grow,
+                            bidirectional or unchecked [default: error]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+      --max-steps <N>       Fail a test after it executes N ops
+      --timeout <SECS>      Fail a test after it runs for SECS seconds
+                            [default: 10]
+  -v, --verbose             Also list the tests that pass
+  -h, --help                Print this help";
+
+/// How long a test may run unless `--timeout` says otherwise.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unchanged lines shown around each change in a diff.
+const CONTEXT: usize = 1;
+
+/// A program with the output it should write.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Test {
+    program: PathBuf,
+    input: Option<PathBuf>,
+    expected: PathBuf,
+}
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut all_levels = false;
+    let mut verbose = false;
+    let mut dir = None;
+    let mut config = Config {
+        timeout: Some(TIMEOUT),
+        ..Config::default()
+    };
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--all-levels" => all_levels = true,
+            "--max-steps" => {
+                config.max_steps = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| usage_error("--max-steps expects a number of ops"))?,
+                );
+            }
+            "--timeout" => {
+                config.timeout = Some(
+                    args.next()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs.parse().ok()?).ok())
+                        .filter(|timeout| !timeout.is_zero())
+                        .ok_or_else(|| {
+                            usage_error("--timeout expects a positive number of seconds")
+                        })?,
+                );
+            }
+            "-v" | "--verbose" => verbose = true,
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if dir.is_none() && !arg.starts_with('-') => dir = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    let dir = dir.ok_or_else(|| usage_error("expected a directory of tests"))?;
+    if all_levels && settings.passes.is_some() {
+        return Err(usage_error("--all-levels can't be used with --passes"));
+    }
+
+    let mut tests = Vec::new();
+    discover(Path::new(&dir), &mut tests).map_err(|e| Error::Failed(format!("{}: {}", dir, e)))?;
+    if tests.is_empty() {
+        return Err(Error::Failed(format!("{}: no tests found", dir)));
+    }
+    let levels = if all_levels {
+        vec![OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3]
+    } else {
+        vec![settings.level]
+    };
+    config = Config {
+        tape_size: settings.options.tape_size,
+        bounds: settings.options.bounds,
+        eof: settings.options.eof,
+        ..config
+    };
+
+    let mut out = io::stdout().lock();
+    let mut failures = 0;
+    let mut runs = 0;
+    for test in &tests {
+        for &level in &levels {
+            settings.level = level;
+            let result = match settings.options.cell_width {
+                CellWidth::U8 => check::<u8>(test, &settings, &config),
+                CellWidth::U16 => check::<u16>(test, &settings, &config),
+                CellWidth::U32 => check::<u32>(test, &settings, &config),
+            };
+            runs += 1;
+            let name = match all_levels {
+                true => format!("{} (-{:?})", test.program.display(), level),
+                false => test.program.display().to_string(),
+            };
+            let report = match result {
+                Ok(()) if verbose => format!("PASS {}\n", name),
+                Ok(()) => continue,
+                Err(why) => {
+                    failures += 1;
+                    format!("FAIL {}\n{}", name, why)
+                }
+            };
+            out.write_all(report.as_bytes())
+                .map_err(|e| Error::Failed(e.to_string()))?;
+        }
+    }
+    writeln!(out, "{} passed, {} failed", runs - failures, failures)
+        .map_err(|e| Error::Failed(e.to_string()))?;
+    match failures {
+        0 => Ok(()),
+        n => Err(Error::Failed(format!("{} of {} tests failed", n, runs))),
+    }
+}
+
+/// Adds the tests in `dir` and its subdirectories to `tests`, in order of
+/// their paths.
+fn discover(dir: &Path, tests: &mut Vec<Test>) -> io::Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            discover(&path, tests)?;
+            continue;
+        }
+        let is_program = path
+            .extension()
+            .is_some_and(|ext| ext == "b" || ext == "bf");
+        let expected = path.with_extension("expected");
+        if is_program && expected.is_file() {
+            let input = Some(path.with_extension("in")).filter(|input| input.is_file());
+            tests.push(Test {
+                program: path,
+                input,
+                expected,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Runs `test` and says how it went wrong, if it did.
+fn check<C: Cell>(
+    test: &Test,
+    settings: &Settings,
+    config: &Config,
+) -> std::result::Result<(), String> {
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("  {}: {}\n", path.display(), e));
+    let source = read(&test.program)?;
+    let input = match &test.input {
+        Some(path) => read(path)?,
+        None => Vec::new(),
+    };
+    let expected = read(&test.expected)?;
+    let actual = run::<C>(&source, &input, settings, config).map_err(|e| format!("  {}\n", e))?;
+    if actual == expected {
+        return Ok(());
+    }
+    Err(diff(&expected, &actual))
+}
+
+/// Parses, optimizes and runs `source` on `input`, returning its output.
+fn run<C: Cell>(
+    source: &[u8],
+    input: &[u8],
+    settings: &Settings,
+    config: &Config,
+) -> std::result::Result<Vec<u8>, String> {
+    let (ops, spans) = parse_with_spans(source, &settings.parse).map_err(|e| e.to_string())?;
+    let (ops, _) = settings.optimize(ops, spans);
+    let mut output = Vec::new();
+    let mut interp = Interpreter::<_, _, C>::with_config(ops, input, &mut output, config);
+    interp.run().map_err(|e| e.to_string())?;
+    Ok(output)
+}
+
+/// The lines of `expected` that `actual` is missing, marked `-`, and those
+/// it has instead, marked `+`, each with a line of context. Lines are
+/// escaped, with their newlines, so a missing newline or a stray control
+/// byte shows.
+fn diff(expected: &[u8], actual: &[u8]) -> String {
+    let old: Vec<&[u8]> = expected.split_inclusive(|&b| b == b'\n').collect();
+    let new: Vec<&[u8]> = actual.split_inclusive(|&b| b == b'\n').collect();
+    // lcs[i][j]: the longest common subsequence of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(('-', old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', new[j]));
+            j += 1;
+        }
+    }
+
+    let near_change = |at: usize| {
+        let from = at.saturating_sub(CONTEXT);
+        let to = (at + CONTEXT + 1).min(lines.len());
+        lines[from..to].iter().any(|&(mark, _)| mark != ' ')
+    };
+    let mut report = String::new();
+    let mut skipped = false;
+    for (at, &(mark, line)) in lines.iter().enumerate() {
+        if !near_change(at) {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            report.push_str("  ...\n");
+            skipped = false;
+        }
+        report.push_str(&format!("  {}{}\n", mark, line.escape_ascii()));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            diff(b"a\nb\nc\nd\ne\n", b"a\nb\nx\nd\ne"),
+            "  ...\n   b\\n\n  -c\\n\n  +x\\n\n   d\\n\n  -e\\n\n  +e\n"
+        );
+        assert_eq!(diff(b"", b"hi"), "  +hi\n");
+    }
+
+    #[test]
+    fn test_conformance() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/conformance");
+        let mut tests = Vec::new();
+        discover(&dir, &mut tests).unwrap();
+        assert!(tests.len() >= 3);
+        assert!(tests.iter().any(|test| test.input.is_some()));
+
+        let mut settings = Settings::default();
+        let config = Config::default();
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            settings.level = level;
+            for test in &tests {
+                let result = check::<u8>(test, &settings, &config);
+                assert_eq!(result, Ok(()), "{}", test.program.display());
+            }
+        }
+
+        // A wrong expectation fails with the diff.
+        let test = Test {
+            expected: tests[0].program.clone(),
+            ..tests[0].clone()
+        };
+        let why = check::<u8>(&test, &settings, &config).unwrap_err();
+        assert!(why.contains("  -"));
+    }
+}
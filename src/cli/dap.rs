@@ -0,0 +1,630 @@
+//! `bf dap`: a Debug Adapter Protocol server on stdin and stdout, so
+//! editors like VS Code can debug brainfuck with breakpoints on source
+//! lines, stepping (also backwards) and the tape shown as variables.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use super::json::{Json, read_message, write_message};
+use super::{Error, Result, usage_error};
+use crate::debugger::{DEFAULT_CHECKPOINT_INTERVAL, Debugger, Stop};
+use crate::{
+    Cell, CellWidth, Config, EofBehavior, Interpreter, ParseOptions, bytecode, fmt_op, line_column,
+    parse_with_spans,
+};
+
+pub(super) const USAGE: &str = "Usage: bf dap\nTry 'bf dap --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf dap
+
+Serves the Debug Adapter Protocol on stdin and stdout, for debugging from an
+editor. The launch request takes these arguments:
+
+  program      Path of the brainfuck source to debug (required)
+  input        File to read the program's input from [default: none]
+  stopOnEntry  Stop before the first op [default: false]
+  tapeSize     Cells on the tape [default: 30000]
+  cellSize     Cell width: 8, 16 or 32 [default: 8]
+  eof          What ',' stores at end of input: \"unchanged\", \"0\" or \"-1\"
+               [default: \"unchanged\"]
+  debugChar    Make '#' print the pointer and nearby cells to stderr
+
+Options:
+  -h, --help                Print this help";
+
+/// Ops to run between checks for a `pause` request.
+const SLICE: u64 = 100_000;
+
+/// The only thread the adapter reports.
+const THREAD_ID: u64 = 1;
+
+/// `variablesReference`s of the two scopes.
+const MACHINE_SCOPE: u64 = 1;
+const TAPE_SCOPE: u64 = 2;
+
+/// Cells shown for the tape when the client doesn't ask for a range.
+const TAPE_PAGE: usize = 256;
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    match args.next().as_deref() {
+        None => {}
+        Some("-h" | "--help") => {
+            println!("{}", HELP);
+            return Ok(());
+        }
+        Some(arg) => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+    }
+    let messages = spawn_reader();
+    let mut adapter = Adapter {
+        seq: 0,
+        lines_start_at1: true,
+    };
+    // Only `initialize` and `launch` make sense before there is a program.
+    while let Some(request) = next(&messages)? {
+        match command(&request) {
+            "initialize" => {
+                let arguments = arguments(&request);
+                adapter.lines_start_at1 = arguments
+                    .get("linesStartAt1")
+                    .and_then(Json::as_bool)
+                    .unwrap_or(true);
+                let capabilities = Json::object([
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsStepBack", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ]);
+                adapter.respond(&request, capabilities)?;
+            }
+            "launch" => {
+                let arguments = arguments(&request);
+                let width = match arguments.get("cellSize").and_then(Json::as_u64) {
+                    None => Some(CellWidth::U8),
+                    Some(bits) => u32::try_from(bits).ok().and_then(CellWidth::from_bits),
+                };
+                let launched = match width {
+                    Some(CellWidth::U8) => launch::<u8>(&mut adapter, &request, &messages),
+                    Some(CellWidth::U16) => launch::<u16>(&mut adapter, &request, &messages),
+                    Some(CellWidth::U32) => launch::<u32>(&mut adapter, &request, &messages),
+                    None => {
+                        adapter.fail(&request, "cellSize must be 8, 16 or 32")?;
+                        continue;
+                    }
+                };
+                if launched? {
+                    return Ok(());
+                }
+            }
+            "disconnect" | "terminate" => {
+                adapter.respond(&request, Json::Null)?;
+                return Ok(());
+            }
+            _ => adapter.fail(&request, "no program has been launched")?,
+        }
+    }
+    Ok(())
+}
+
+/// Reads messages from stdin on a thread of its own, so a running program
+/// can be paused.
+fn spawn_reader() -> Receiver<io::Result<Json>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            let message = read_message(&mut stdin).transpose();
+            let done = !matches!(message, Some(Ok(_)));
+            if let Some(message) = message
+                && sender.send(message).is_err()
+            {
+                break;
+            }
+            if done {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Waits for the next message, or `None` once the client is gone.
+fn next(messages: &Receiver<io::Result<Json>>) -> Result<Option<Json>> {
+    match messages.recv() {
+        Ok(message) => message.map(Some).map_err(|e| Error::Failed(e.to_string())),
+        Err(_) => Ok(None),
+    }
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").and_then(Json::as_str).unwrap_or("")
+}
+
+fn arguments(request: &Json) -> &Json {
+    request.get("arguments").unwrap_or(&Json::Null)
+}
+
+/// The client's side of the conversation.
+struct Adapter {
+    seq: u64,
+    /// Whether the client counts lines from 1, as the debugger does.
+    lines_start_at1: bool,
+}
+
+impl Adapter {
+    /// Sends `message` with the next sequence number, and `body` unless
+    /// it is null.
+    fn send(&mut self, mut message: Json, body: Json) -> Result<()> {
+        self.seq += 1;
+        if let Json::Object(members) = &mut message {
+            members.insert(0, ("seq".to_string(), self.seq.into()));
+            if body != Json::Null {
+                members.push(("body".to_string(), body));
+            }
+        }
+        write_message(&mut io::stdout().lock(), &message).map_err(|e| Error::Failed(e.to_string()))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> Result<()> {
+        self.send(
+            Json::object([
+                ("type", "response".into()),
+                (
+                    "request_seq",
+                    request.get("seq").cloned().unwrap_or(Json::Null),
+                ),
+                ("success", true.into()),
+                ("command", command(request).into()),
+            ]),
+            body,
+        )
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> Result<()> {
+        self.send(
+            Json::object([
+                ("type", "response".into()),
+                (
+                    "request_seq",
+                    request.get("seq").cloned().unwrap_or(Json::Null),
+                ),
+                ("success", false.into()),
+                ("command", command(request).into()),
+                ("message", message.into()),
+            ]),
+            Json::Null,
+        )
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<()> {
+        self.send(
+            Json::object([("type", "event".into()), ("event", event.into())]),
+            body,
+        )
+    }
+
+    /// Converts a 1-based line to the client's numbering.
+    fn client_line(&self, line: usize) -> usize {
+        if self.lines_start_at1 { line } else { line - 1 }
+    }
+
+    /// Converts a line in the client's numbering to a 1-based one.
+    fn line(&self, line: u64) -> usize {
+        line as usize + usize::from(!self.lines_start_at1)
+    }
+}
+
+/// Sets up the program named by a `launch` request and serves requests
+/// until the client disconnects. Returns `false` if the program couldn't
+/// be loaded, after telling the client why.
+fn launch<C: Cell>(
+    adapter: &mut Adapter,
+    request: &Json,
+    messages: &Receiver<io::Result<Json>>,
+) -> Result<bool> {
+    let mut session = match Session::<C>::load(arguments(request)) {
+        Ok(session) => session,
+        Err(message) => {
+            adapter.fail(request, &message)?;
+            return Ok(false);
+        }
+    };
+    adapter.respond(request, Json::Null)?;
+    // Breakpoints can only be placed once the source is known.
+    adapter.event("initialized", Json::Null)?;
+
+    let mut running = false;
+    loop {
+        let message = if running {
+            match messages.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(true),
+            }
+        } else {
+            match messages.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return Ok(true),
+            }
+        };
+        if let Some(message) = message {
+            let request = message.map_err(|e| Error::Failed(e.to_string()))?;
+            match session.handle(adapter, &request, running)? {
+                Control::Stay => {}
+                Control::Run => running = true,
+                Control::Halt => running = false,
+                Control::Exit => return Ok(true),
+            }
+        }
+        if running {
+            let stop = session.debugger.resume_for(SLICE);
+            session.flush_output(adapter)?;
+            match stop {
+                Ok(None) => {}
+                Ok(Some(stop)) => {
+                    running = false;
+                    session.stopped(adapter, stop)?;
+                }
+                Err(e) => {
+                    running = false;
+                    session.crashed(adapter, e)?;
+                }
+            }
+        }
+    }
+}
+
+/// What the request loop does after a request.
+enum Control {
+    Stay,
+    Run,
+    Halt,
+    Exit,
+}
+
+struct Session<C> {
+    debugger: Debugger<Box<dyn Read>, Vec<u8>, C>,
+    path: String,
+    /// The line and column of each op.
+    positions: Vec<(usize, usize)>,
+    stop_on_entry: bool,
+}
+
+impl<C: Cell> Session<C> {
+    fn load(arguments: &Json) -> std::result::Result<Self, String> {
+        let path = arguments
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a 'program' to debug")?;
+        let source = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        if bytecode::is_bytecode(&source) {
+            return Err(format!(
+                "{}: bf dap needs brainfuck source, not bytecode",
+                path
+            ));
+        }
+        let flag = |name| arguments.get(name).and_then(Json::as_bool).unwrap_or(false);
+        let options = ParseOptions {
+            debug_char: flag("debugChar"),
+            ..ParseOptions::default()
+        };
+        let (ops, spans) =
+            parse_with_spans(&source, &options).map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut config = Config::default();
+        if let Some(size) = arguments.get("tapeSize") {
+            config.tape_size = match size.as_u64() {
+                Some(size) if size > 0 => size as usize,
+                _ => return Err("tapeSize must be a positive number of cells".to_string()),
+            };
+        }
+        if let Some(eof) = arguments.get("eof") {
+            config.eof = match eof.as_str() {
+                Some("unchanged") => EofBehavior::Unchanged,
+                Some("0") => EofBehavior::Zero,
+                Some("-1") => EofBehavior::MinusOne,
+                _ => return Err("eof must be \"unchanged\", \"0\" or \"-1\"".to_string()),
+            };
+        }
+        let input: Box<dyn Read> = match arguments.get("input").and_then(Json::as_str) {
+            Some(input) => Box::new(File::open(input).map_err(|e| format!("{}: {}", input, e))?),
+            None => Box::new(io::empty()),
+        };
+
+        let mut interp = Interpreter::<_, _, C>::with_config(ops, input, Vec::new(), &config);
+        interp.set_debug_output(io::stderr());
+        let mut debugger = Debugger::new(interp);
+        debugger.start_recording(DEFAULT_CHECKPOINT_INTERVAL);
+        Ok(Session {
+            debugger,
+            path: path.to_string(),
+            positions: spans
+                .iter()
+                .map(|span| line_column(&source, span.start))
+                .collect(),
+            stop_on_entry: flag("stopOnEntry"),
+        })
+    }
+
+    fn handle(&mut self, adapter: &mut Adapter, request: &Json, running: bool) -> Result<Control> {
+        let arguments = arguments(request);
+        match command(request) {
+            "setBreakpoints" => {
+                let body = self.set_breakpoints(adapter, arguments);
+                adapter.respond(request, body)?;
+            }
+            "setExceptionBreakpoints" => adapter.respond(request, Json::object([]))?,
+            "configurationDone" => {
+                adapter.respond(request, Json::Null)?;
+                if !self.stop_on_entry {
+                    return Ok(Control::Run);
+                }
+                adapter.event("stopped", stopped_body("entry"))?;
+            }
+            "threads" => {
+                let thread = Json::object([("id", THREAD_ID.into()), ("name", "main".into())]);
+                adapter.respond(request, Json::object([("threads", vec![thread].into())]))?;
+            }
+            "stackTrace" => {
+                let body = self.stack_trace(adapter);
+                adapter.respond(request, body)?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: u64| {
+                    Json::object([
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                let mut tape = scope("Tape", TAPE_SCOPE);
+                if let Json::Object(members) = &mut tape {
+                    let cells = self.debugger.interpreter().tape().len();
+                    members.push(("indexedVariables".to_string(), cells.into()));
+                }
+                let scopes = vec![scope("Machine", MACHINE_SCOPE), tape];
+                adapter.respond(request, Json::object([("scopes", scopes.into())]))?;
+            }
+            "variables" => {
+                let variables = self.variables(arguments);
+                adapter.respond(request, Json::object([("variables", variables.into())]))?;
+            }
+            "continue" => {
+                let body = Json::object([("allThreadsContinued", true.into())]);
+                adapter.respond(request, body)?;
+                return Ok(Control::Run);
+            }
+            "next" | "stepIn" | "stepOut" => {
+                adapter.respond(request, Json::Null)?;
+                let stepped = self.debugger.step();
+                self.flush_output(adapter)?;
+                match stepped {
+                    Ok(_) if self.debugger.interpreter().is_finished() => {
+                        self.stopped(adapter, Stop::Finished)?
+                    }
+                    Ok(_) => adapter.event("stopped", stopped_body("step"))?,
+                    Err(e) => self.crashed(adapter, e)?,
+                }
+            }
+            "stepBack" => {
+                adapter.respond(request, Json::Null)?;
+                self.debugger
+                    .step_back(1)
+                    .map_err(|e| Error::Failed(e.to_string()))?;
+                adapter.event("stopped", stopped_body("step"))?;
+            }
+            "reverseContinue" => {
+                adapter.respond(request, Json::Null)?;
+                let stop = self
+                    .debugger
+                    .reverse_resume()
+                    .map_err(|e| Error::Failed(e.to_string()))?;
+                self.stopped(adapter, stop)?;
+            }
+            "pause" => {
+                adapter.respond(request, Json::Null)?;
+                if running {
+                    adapter.event("stopped", stopped_body("pause"))?;
+                    return Ok(Control::Halt);
+                }
+            }
+            "disconnect" | "terminate" => {
+                adapter.respond(request, Json::Null)?;
+                return Ok(Control::Exit);
+            }
+            "initialize" | "launch" => adapter.fail(request, "a program is already running")?,
+            _ => adapter.fail(request, "unsupported request")?,
+        }
+        Ok(Control::Stay)
+    }
+
+    /// Replaces the breakpoints with one at the first op on or after each
+    /// requested line.
+    fn set_breakpoints(&mut self, adapter: &Adapter, arguments: &Json) -> Json {
+        let old: Vec<usize> = self.debugger.breakpoints().collect();
+        for pc in old {
+            self.debugger.remove_breakpoint(pc);
+        }
+        let requested = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default();
+        let breakpoints = requested
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint
+                    .get("line")
+                    .and_then(Json::as_u64)
+                    .map_or(0, |line| adapter.line(line));
+                match self.positions.iter().position(|&(l, _)| l >= line) {
+                    Some(pc) => {
+                        self.debugger.set_breakpoint(pc);
+                        let line = self.positions[pc].0;
+                        Json::object([
+                            ("verified", true.into()),
+                            ("line", adapter.client_line(line).into()),
+                        ])
+                    }
+                    None => Json::object([
+                        ("verified", false.into()),
+                        ("message", "no code on or after this line".into()),
+                    ]),
+                }
+            })
+            .collect::<Vec<_>>();
+        Json::object([("breakpoints", breakpoints.into())])
+    }
+
+    fn stack_trace(&self, adapter: &Adapter) -> Json {
+        let interp = self.debugger.interpreter();
+        let pc = interp.pc();
+        let frames = match (interp.ops().get(pc), self.positions.get(pc)) {
+            (Some(op), Some(&(line, column))) => {
+                let name = Path::new(&self.path)
+                    .file_name()
+                    .map_or_else(|| self.path.clone(), |name| name.to_string_lossy().into());
+                let source =
+                    Json::object([("name", name.into()), ("path", self.path.as_str().into())]);
+                vec![Json::object([
+                    ("id", 0u64.into()),
+                    ("name", format!("op {}: {}", pc, fmt_op(op)).into()),
+                    ("source", source),
+                    ("line", adapter.client_line(line).into()),
+                    ("column", column.into()),
+                ])]
+            }
+            _ => Vec::new(),
+        };
+        let total = frames.len();
+        Json::object([
+            ("stackFrames", frames.into()),
+            ("totalFrames", total.into()),
+        ])
+    }
+
+    fn variables(&self, arguments: &Json) -> Vec<Json> {
+        let interp = self.debugger.interpreter();
+        let tape = interp.tape();
+        let variable = |name: String, value: String| {
+            Json::object([
+                ("name", name.into()),
+                ("value", value.into()),
+                ("variablesReference", 0u64.into()),
+            ])
+        };
+        match arguments.get("variablesReference").and_then(Json::as_u64) {
+            Some(MACHINE_SCOPE) => {
+                let cell = tape.get(interp.ptr()).map_or(0, |cell| cell.to_u32());
+                vec![
+                    variable("ptr".to_string(), interp.ptr().to_string()),
+                    variable("cell".to_string(), cell.to_string()),
+                    variable("pc".to_string(), interp.pc().to_string()),
+                    variable("steps".to_string(), interp.steps().to_string()),
+                ]
+            }
+            Some(TAPE_SCOPE) => {
+                let start = arguments
+                    .get("start")
+                    .and_then(Json::as_u64)
+                    .map_or(0, |start| start as usize)
+                    .min(tape.len());
+                // Without a range, show up to the pointer or the last cell
+                // in use, whichever is further.
+                let end = match arguments.get("count").and_then(Json::as_u64) {
+                    Some(count) if count > 0 => start.saturating_add(count as usize),
+                    _ => {
+                        let used = tape.iter().rposition(|cell| !cell.is_zero()).unwrap_or(0);
+                        start + (used.max(interp.ptr()) + 1).min(TAPE_PAGE)
+                    }
+                }
+                .min(tape.len());
+                (start..end)
+                    .map(|i| variable(format!("tape[{}]", i), tape[i].to_u32().to_string()))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Sends what the program has written since the last call as an
+    /// `output` event.
+    fn flush_output(&mut self, adapter: &mut Adapter) -> Result<()> {
+        let (_, output) = self.debugger.interpreter_mut().io_mut();
+        let bytes = std::mem::take(output.get_mut());
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let body = Json::object([
+            ("category", "stdout".into()),
+            (
+                "output",
+                String::from_utf8_lossy(&bytes).into_owned().into(),
+            ),
+        ]);
+        adapter.event("output", body)
+    }
+
+    fn stopped(&mut self, adapter: &mut Adapter, stop: Stop) -> Result<()> {
+        match stop {
+            Stop::Breakpoint(_) => adapter.event("stopped", stopped_body("breakpoint")),
+            Stop::Watchpoint { .. } | Stop::Condition(_) => {
+                adapter.event("stopped", stopped_body("data breakpoint"))
+            }
+            Stop::RecordingStart => adapter.event("stopped", stopped_body("entry")),
+            Stop::Finished => {
+                self.flush_output(adapter)?;
+                adapter.event("exited", Json::object([("exitCode", 0u64.into())]))?;
+                adapter.event("terminated", Json::Null)
+            }
+        }
+    }
+
+    /// Reports an I/O error from the program and ends the session.
+    fn crashed(&mut self, adapter: &mut Adapter, e: io::Error) -> Result<()> {
+        let body = Json::object([
+            ("category", "stderr".into()),
+            ("output", format!("{}\n", e).into()),
+        ]);
+        adapter.event("output", body)?;
+        adapter.event("exited", Json::object([("exitCode", 1u64.into())]))?;
+        adapter.event("terminated", Json::Null)
+    }
+}
+
+fn stopped_body(reason: &str) -> Json {
+    Json::object([
+        ("reason", reason.into()),
+        ("threadId", THREAD_ID.into()),
+        ("allThreadsStopped", true.into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_breakpoints() {
+        let path = std::env::temp_dir().join(format!("bf-dap-{}.bf", std::process::id()));
+        fs::write(&path, "+++\n\n>[-]\n").unwrap();
+        let arguments = Json::object([("program", path.to_string_lossy().into_owned().into())]);
+        let mut session = Session::<u8>::load(&arguments).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let adapter = Adapter {
+            seq: 0,
+            lines_start_at1: true,
+        };
+        let lines = [2u64, 9].map(|line| Json::object([("line", line.into())]));
+        let request = Json::object([("breakpoints", lines.to_vec().into())]);
+        let body = session.set_breakpoints(&adapter, &request);
+        assert_eq!(
+            body.to_string(),
+            r#"{"breakpoints":[{"verified":true,"line":3},{"verified":false,"message":"no code on or after this line"}]}"#
+        );
+        assert_eq!(session.debugger.breakpoints().collect::<Vec<_>>(), [1]);
+    }
+}
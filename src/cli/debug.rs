@@ -0,0 +1,361 @@
+//! `bfi --debug`: an interactive prompt around [`Debugger`].
+
+use std::io::{self, BufRead, Read, Write};
+use std::ops::Range;
+
+use super::{Error, Result};
+use crate::debugger::{Condition, DEFAULT_CHECKPOINT_INTERVAL, Debugger, Stop};
+use crate::{Cell, Config, Interpreter, Op, fmt_op, fmt_ops, line_column};
+
+const HELP: &str = "\
+Commands:
+  step [N], s        Execute the next op, or the next N
+  continue, c        Run until a breakpoint or the end of the program
+  break [N], b       Stop before op N; without N, list breakpoints,
+                     watchpoints and conditions
+  delete N, d        Remove the breakpoint at op N
+  watch [cell] N     Stop after an op changes cell N
+  unwatch N          Stop watching cell N
+  break-if COND      Stop when COND becomes true, e.g. 'ptr>1000',
+                     'cell==0' or 'tape[42]!=7'
+  delete-if N        Remove condition N
+  record [N]         Record from here, checkpointing every N ops, so the
+                     program can run backwards; 'record stop' ends it
+  step-back [N], sb  Go back one op, or N
+  reverse-continue, rc
+                     Run backwards to the last point 'continue' would
+                     have stopped at
+  print ptr, p       Print the data pointer
+  print tape[A..B]   Print cells A to B (or tape[A] for one)
+  list, l            Show the source around the current op
+  help, h            Print this help
+  quit, q            Leave the debugger
+An empty line repeats the last command.";
+
+/// The program being debugged, as parsed.
+pub(super) struct Program<'a> {
+    pub(super) name: &'a str,
+    pub(super) source: &'a [u8],
+    pub(super) ops: Vec<Op>,
+    /// Where each op is in `source`; empty for bytecode.
+    pub(super) spans: Vec<Range<usize>>,
+}
+
+/// Reads commands from stdin until the user quits, while the program reads
+/// its own input from `input`.
+pub(super) fn run<C: Cell>(program: Program, input: Box<dyn Read>, config: &Config) -> Result<()> {
+    let Program {
+        name,
+        source,
+        ops,
+        spans,
+    } = program;
+    let mut interp = Interpreter::<_, _, C>::with_config(ops, input, io::stdout(), config);
+    interp.set_debug_output(io::stderr());
+    let mut session = Session {
+        debugger: Debugger::new(interp),
+        source,
+        spans,
+    };
+    let failed = |e: io::Error| Error::Failed(e.to_string());
+
+    println!(
+        "Debugging {} ({} ops). Type 'help' for commands.",
+        name,
+        session.debugger.interpreter().ops().len()
+    );
+    session.show_position();
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(bfdb) ");
+        io::stdout().flush().map_err(failed)?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(failed)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let line = match line.trim() {
+            "" => last.clone(),
+            line => line.to_string(),
+        };
+        let (command, rest) = line.split_once(' ').unwrap_or((&line, ""));
+        let rest = rest.trim();
+        let arg = rest.split_whitespace().next();
+        match command {
+            "q" | "quit" => return Ok(()),
+            "h" | "help" => println!("{}", HELP),
+            "s" | "step" => match arg.map(str::parse::<u64>) {
+                None => session.step(1).map_err(failed)?,
+                Some(Ok(n)) => session.step(n).map_err(failed)?,
+                Some(Err(_)) => println!("step expects a number of ops"),
+            },
+            "c" | "continue" => session.resume().map_err(failed)?,
+            "b" | "break" => match arg.map(str::parse::<usize>) {
+                None => session.list_breakpoints(),
+                Some(Ok(pc)) if session.debugger.set_breakpoint(pc) => {
+                    println!("Breakpoint at op {}", pc)
+                }
+                Some(Ok(pc)) => println!("There is no op {}", pc),
+                Some(Err(_)) => println!("break expects an op index"),
+            },
+            "d" | "delete" => match arg.map(str::parse::<usize>) {
+                Some(Ok(pc)) if session.debugger.remove_breakpoint(pc) => {
+                    println!("Deleted the breakpoint at op {}", pc)
+                }
+                Some(Ok(pc)) => println!("No breakpoint at op {}", pc),
+                _ => println!("delete expects an op index"),
+            },
+            "watch" => match rest.strip_prefix("cell").unwrap_or(rest).trim().parse() {
+                Ok(cell) if session.debugger.set_watchpoint(cell) => {
+                    println!("Watching tape[{}]", cell)
+                }
+                Ok(cell) => println!("There is no cell {}", cell),
+                Err(_) => println!("watch expects a cell index"),
+            },
+            "unwatch" => match arg.map(str::parse::<usize>) {
+                Some(Ok(cell)) if session.debugger.remove_watchpoint(cell) => {
+                    println!("Stopped watching tape[{}]", cell)
+                }
+                Some(Ok(cell)) => println!("tape[{}] isn't watched", cell),
+                _ => println!("unwatch expects a cell index"),
+            },
+            "break-if" => match Condition::parse(rest) {
+                Some(condition) => {
+                    let i = session.debugger.add_condition(condition);
+                    println!("Condition {}: {}", i, condition);
+                }
+                None if rest.is_empty() => session.list_breakpoints(),
+                None => println!(
+                    "break-if expects 'ptr', 'cell' or 'tape[N]', a comparison and a number"
+                ),
+            },
+            "delete-if" => match arg.map(str::parse::<usize>) {
+                Some(Ok(i)) => match session.debugger.remove_condition(i) {
+                    Some(condition) => println!("Deleted condition {}: {}", i, condition),
+                    None => println!("No condition {}", i),
+                },
+                _ => println!("delete-if expects a condition number"),
+            },
+            "record" => match arg {
+                Some("stop") => {
+                    session.debugger.stop_recording();
+                    println!("Stopped recording");
+                }
+                _ => match arg.map_or(Ok(DEFAULT_CHECKPOINT_INTERVAL), str::parse) {
+                    Ok(interval) => {
+                        session.debugger.start_recording(interval);
+                        println!("Recording, with a checkpoint every {} ops", interval.max(1));
+                    }
+                    Err(_) => println!("record expects a number of ops or 'stop'"),
+                },
+            },
+            "sb" | "step-back" => match arg.map_or(Ok(1), str::parse::<u64>) {
+                Ok(n) => session.step_back(n).map_err(failed)?,
+                Err(_) => println!("step-back expects a number of ops"),
+            },
+            "rc" | "reverse-continue" => session.reverse_resume().map_err(failed)?,
+            "p" | "print" => session.print(arg.unwrap_or("")),
+            "l" | "list" => session.list(),
+            _ => println!("Unknown command '{}'. Type 'help' for commands.", command),
+        }
+        last = line;
+    }
+}
+
+struct Session<'a, C> {
+    debugger: Debugger<Box<dyn Read>, io::Stdout, C>,
+    source: &'a [u8],
+    spans: Vec<Range<usize>>,
+}
+
+impl<C: Cell> Session<'_, C> {
+    /// Executes `n` ops, stopping early at the end of the program or when
+    /// a watchpoint or condition triggers.
+    fn step(&mut self, n: u64) -> io::Result<()> {
+        for _ in 0..n {
+            if self.debugger.interpreter().is_finished() {
+                break;
+            }
+            if let Some(stop) = self.debugger.step()? {
+                self.report(stop);
+                break;
+            }
+        }
+        self.show_position();
+        Ok(())
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        if self.debugger.interpreter().is_finished() {
+            println!("The program has finished");
+            return Ok(());
+        }
+        let stop = self.debugger.resume()?;
+        self.report(stop);
+        self.show_position();
+        Ok(())
+    }
+
+    fn step_back(&mut self, n: u64) -> io::Result<()> {
+        if !self.debugger.is_recording() {
+            println!("Not recording; 'record' lets the program run backwards from there");
+            return Ok(());
+        }
+        if self.debugger.step_back(n)? < n {
+            self.report(Stop::RecordingStart);
+        }
+        self.show_position();
+        Ok(())
+    }
+
+    fn reverse_resume(&mut self) -> io::Result<()> {
+        if !self.debugger.is_recording() {
+            println!("Not recording; 'record' lets the program run backwards from there");
+            return Ok(());
+        }
+        let stop = self.debugger.reverse_resume()?;
+        self.report(stop);
+        self.show_position();
+        Ok(())
+    }
+
+    /// Says why the program stopped, unless it simply ended.
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Breakpoint(pc) => println!("Breakpoint at op {}", pc),
+            Stop::Watchpoint { cell, old, new } => {
+                println!("Watchpoint: tape[{}] changed from {} to {}", cell, old, new)
+            }
+            Stop::Condition(i) => {
+                if let Some(condition) = self.debugger.conditions().nth(i) {
+                    println!("Condition {} is true: {}", i, condition);
+                }
+            }
+            Stop::RecordingStart => println!("Reached the start of the recording"),
+            Stop::Finished => {}
+        }
+    }
+
+    /// Prints the next op, or that the program has ended.
+    fn show_position(&self) {
+        let interp = self.debugger.interpreter();
+        let pc = interp.pc();
+        let Some(op) = interp.ops().get(pc) else {
+            println!("The program has finished after {} ops", interp.steps());
+            return;
+        };
+        let op_text = fmt_op(op);
+        match self.spans.get(pc) {
+            Some(span) => {
+                let (line, column) = line_column(self.source, span.start);
+                println!("op {}: {} at line {}, column {}", pc, op_text, line, column);
+            }
+            None => println!("op {}: {}", pc, op_text),
+        }
+    }
+
+    fn list_breakpoints(&self) {
+        let breakpoints: Vec<String> = self
+            .debugger
+            .breakpoints()
+            .map(|pc| pc.to_string())
+            .collect();
+        if breakpoints.is_empty() {
+            println!("No breakpoints");
+        } else {
+            println!("Breakpoints at ops {}", breakpoints.join(", "));
+        }
+        let watchpoints: Vec<String> = self
+            .debugger
+            .watchpoints()
+            .map(|cell| format!("tape[{}]", cell))
+            .collect();
+        if !watchpoints.is_empty() {
+            println!("Watching {}", watchpoints.join(", "));
+        }
+        for (i, condition) in self.debugger.conditions().enumerate() {
+            println!("Condition {}: {}", i, condition);
+        }
+    }
+
+    fn print(&self, what: &str) {
+        let interp = self.debugger.interpreter();
+        if what == "ptr" {
+            println!("ptr = {}", interp.ptr());
+            return;
+        }
+        let Some(range) = what
+            .strip_prefix("tape[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(parse_range)
+        else {
+            println!("print expects 'ptr', 'tape[A..B]' or 'tape[A]'");
+            return;
+        };
+        let tape = interp.tape();
+        if range.start >= range.end || range.end > tape.len() {
+            println!("The tape has cells 0 to {}", tape.len() - 1);
+            return;
+        }
+        let cells: Vec<String> = tape[range.clone()]
+            .iter()
+            .map(|cell| cell.to_u32().to_string())
+            .collect();
+        println!("{} = [{}]", what, cells.join(", "));
+    }
+
+    /// Prints the source lines around the current op, marking the op.
+    fn list(&self) {
+        let interp = self.debugger.interpreter();
+        let pc = interp.pc();
+        let Some(span) = self.spans.get(pc) else {
+            // Bytecode has no source; list the ops instead.
+            let ops = interp.ops();
+            let start = pc.saturating_sub(3);
+            let end = (pc + 4).min(ops.len());
+            let listing = fmt_ops(ops);
+            for (i, line) in listing.lines().enumerate().take(end).skip(start) {
+                let marker = if i == pc { "=>" } else { "  " };
+                println!("{}{}", marker, line);
+            }
+            return;
+        };
+        let (line, column) = line_column(self.source, span.start);
+        let lines: Vec<&[u8]> = self.source.split(|&b| b == b'\n').collect();
+        let first = line.saturating_sub(3);
+        let last = (line + 2).min(lines.len());
+        for (i, text) in lines.iter().enumerate().take(last).skip(first) {
+            let number = i + 1;
+            let marker = if number == line { "=>" } else { "  " };
+            println!("{}{:5} | {}", marker, number, String::from_utf8_lossy(text));
+            if number == line {
+                let width = span.len().min(text.len() + 1 - column).max(1);
+                println!("        | {}{}", " ".repeat(column - 1), "^".repeat(width));
+            }
+        }
+    }
+}
+
+/// Parses `A..B` or `A` into a range of cells.
+fn parse_range(text: &str) -> Option<Range<usize>> {
+    match text.split_once("..") {
+        Some((start, end)) => Some(start.trim().parse().ok()?..end.trim().parse().ok()?),
+        None => {
+            let cell: usize = text.trim().parse().ok()?;
+            Some(cell..cell + 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("3..7"), Some(3..7));
+        assert_eq!(parse_range("5"), Some(5..6));
+        assert_eq!(parse_range("a..2"), None);
+    }
+}
@@ -0,0 +1,303 @@
+//! Errors about places in a program, shown the way rustc shows them: the
+//! message, where it happened, and the lines of source involved with each
+//! place underlined and labelled.
+//!
+//! ```text
+//! unmatched '['
+//!  --> prog.b:3:1
+//!   |
+//! 3 | [[-]
+//!   | ^ unmatched '[' opened here
+//! ```
+
+use std::env;
+use std::io::{self, IsTerminal};
+use std::ops::Range;
+
+use crate::{ParseError, line_column};
+
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+/// `--color`: whether diagnostics are colored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(super) enum ColorChoice {
+    /// When stderr is a terminal and `NO_COLOR` isn't set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub(super) fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether to color what goes to stderr.
+    pub(super) fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Where the files of a program joined from several are in its source,
+/// so diagnostics can name the file a place is in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Sources {
+    /// Each file's name and its part of the source, in order.
+    files: Vec<(String, Range<usize>)>,
+}
+
+impl Sources {
+    /// A program from a single file, or stdin or `-e`, called `name`.
+    pub(super) fn single(name: impl Into<String>, len: usize) -> Self {
+        Sources {
+            files: vec![(name.into(), 0..len)],
+        }
+    }
+
+    /// Adds the `len` bytes of the file `name` after the files so far.
+    pub(super) fn push(&mut self, name: impl Into<String>, len: usize) {
+        let start = self.files.last().map_or(0, |(_, part)| part.end);
+        self.files.push((name.into(), start..start + len));
+    }
+
+    /// The first file's name, which errors about the whole program go
+    /// under.
+    pub(super) fn name(&self) -> &str {
+        self.files.first().map_or("", |(name, _)| name)
+    }
+
+    /// The name and part of the file `offset` is in.
+    pub(super) fn file_at(&self, offset: usize) -> (&str, Range<usize>) {
+        self.files
+            .iter()
+            .rfind(|(_, part)| part.start <= offset)
+            .or(self.files.first())
+            .map_or(("", 0..0), |(name, part)| (name, part.clone()))
+    }
+}
+
+/// A message about one or more places in a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Diagnostic {
+    message: String,
+    /// Whether it's a warning rather than an error.
+    warning: bool,
+    /// Byte ranges of the source and what to say about each. The first is
+    /// where the problem is; any others explain it.
+    labels: Vec<(Range<usize>, String)>,
+}
+
+impl Diagnostic {
+    pub(super) fn new(message: impl Into<String>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            warning: false,
+            labels: Vec::new(),
+        }
+    }
+
+    /// A diagnostic that's shown as a warning.
+    pub(super) fn warning(message: impl Into<String>) -> Self {
+        Diagnostic {
+            warning: true,
+            ..Diagnostic::new(message)
+        }
+    }
+
+    /// Adds a label under `span`, the main one if it's the first.
+    pub(super) fn label(mut self, span: Range<usize>, text: impl Into<String>) -> Self {
+        self.labels.push((span, text.into()));
+        self
+    }
+
+    /// The diagnostic about `error`.
+    pub(super) fn parse_error(error: &ParseError) -> Self {
+        let (message, label) = match *error {
+            ParseError::UnmatchedOpen { .. } => ("unmatched '['", "unmatched '[' opened here"),
+            ParseError::UnmatchedClose { .. } => {
+                ("unmatched ']'", "there is no loop to close here")
+            }
+            ParseError::UnmatchedProcStart { .. } => ("unmatched '('", "unmatched '(' opened here"),
+            ParseError::UnmatchedProcEnd { .. } => {
+                ("unmatched ')'", "there is no procedure to close here")
+            }
+            ParseError::TooDeep { .. } => ("nested too deeply", "this goes past the nesting limit"),
+            ParseError::TooLarge { .. } | ParseError::Read { .. } => {
+                return Diagnostic::new(error.to_string());
+            }
+        };
+        let diagnostic = Diagnostic::new(message);
+        match error.offset() {
+            Some(offset) => diagnostic.label(offset..offset + 1, label),
+            None => diagnostic,
+        }
+    }
+
+    /// Renders the diagnostic like [`Diagnostic::render`], for a `source`
+    /// joined from `sources`: in the file its main place is in, with the
+    /// labels in other files left out.
+    pub(super) fn render_in(&self, sources: &Sources, source: &[u8], color: bool) -> String {
+        let Some((main, _)) = self.labels.first() else {
+            return self.render(sources.name(), source, color);
+        };
+        let (name, part) = sources.file_at(main.start);
+        let part = part.start.min(source.len())..part.end.min(source.len());
+        let rebased = Diagnostic {
+            labels: self
+                .labels
+                .iter()
+                .filter(|(span, _)| part.contains(&span.start) || span.start == part.end)
+                .map(|(span, text)| (span.start - part.start..span.end - part.start, text.clone()))
+                .collect(),
+            ..self.clone()
+        };
+        rebased.render(name, &source[part], color)
+    }
+
+    /// Renders the diagnostic for `source`, called `name`, with ANSI colors
+    /// if `color` is set. Labels on lines that aren't next to each other
+    /// are separated by `...`.
+    pub(super) fn render(&self, name: &str, source: &[u8], color: bool) -> String {
+        let paint = |style: &str, text: &str| match color {
+            true => format!("{}{}{}", style, text, RESET),
+            false => text.to_string(),
+        };
+        let mut out = match self.warning {
+            true => format!(
+                "{}{}",
+                paint(YELLOW, "warning:"),
+                paint(BOLD, &format!(" {}", self.message))
+            ),
+            false => paint(BOLD, &self.message),
+        };
+        let Some((main, _)) = self.labels.first() else {
+            return out;
+        };
+        let (line, column) = line_column(source, main.start.min(source.len()));
+        let mut labels: Vec<(usize, usize)> = self
+            .labels
+            .iter()
+            .enumerate()
+            .map(|(i, (span, _))| (line_column(source, span.start.min(source.len())).0, i))
+            .collect();
+        labels.sort();
+        let width = labels.iter().map(|&(line, _)| line).max().unwrap_or(line);
+        let pad = " ".repeat(width.to_string().len());
+        let gutter = paint(BLUE, &format!("{} |", pad));
+
+        out.push_str(&format!(
+            "\n{}{} {}:{}:{}\n{}",
+            pad,
+            paint(BLUE, "-->"),
+            name,
+            line,
+            column,
+            gutter
+        ));
+        let mut shown = None;
+        for (line, i) in labels {
+            let (span, text) = &self.labels[i];
+            let start = span.start.min(source.len());
+            let line_start = start + 1 - line_column(source, start).1;
+            let line_end = source[start..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(source.len(), |n| start + n);
+            if shown != Some(line) {
+                if shown.is_some_and(|shown| line > shown + 1) {
+                    out.push_str(&format!("\n{}", paint(BLUE, "...")));
+                }
+                let text = String::from_utf8_lossy(&source[line_start..line_end]);
+                out.push_str(&format!(
+                    "\n{} {}",
+                    paint(BLUE, &format!("{:>w$} |", line, w = pad.len())),
+                    text.trim_end_matches('\r')
+                ));
+                shown = Some(line);
+            }
+            // Tabs stay tabs, so the underline lines up however wide they
+            // show.
+            let indent: String = String::from_utf8_lossy(&source[line_start..start])
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let end = span.end.clamp(start, line_end);
+            let len = String::from_utf8_lossy(&source[start..end])
+                .chars()
+                .count()
+                .max(1);
+            let (mark, style) = match i {
+                0 if self.warning => ('^', YELLOW),
+                0 => ('^', RED),
+                _ => ('-', BLUE),
+            };
+            let underline = format!("{} {}", mark.to_string().repeat(len), text);
+            out.push_str(&format!(
+                "\n{} {}{}",
+                gutter,
+                indent,
+                paint(style, underline.trim_end())
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseOptions, bracket_errors};
+
+    #[test]
+    fn test_render() {
+        let source = b"+[\n\t>]]\n";
+        let errors = bracket_errors(source, &ParseOptions::default());
+        assert_eq!(
+            Diagnostic::parse_error(&errors[0]).render("a.b", source, false),
+            "unmatched ']'\n --> a.b:2:4\n  |\n2 | \t>]]\n  | \t  ^ there is no loop to close here"
+        );
+
+        // Labels go in line order, with a gap between lines apart, and
+        // underline the whole span.
+        let source = b"+\n>>>\n\n\n[<]\n";
+        let diagnostic = Diagnostic::new("the pointer left the tape")
+            .label(9..10, "this touched a cell outside the tape")
+            .label(2..5, "moved here");
+        assert_eq!(
+            diagnostic.render("-e", source, false),
+            "the pointer left the tape\n --> -e:5:2\n  |\n2 | >>>\n  | --- moved here\n...\n\
+             5 | [<]\n  |  ^ this touched a cell outside the tape"
+        );
+        assert!(diagnostic.render("-e", source, true).contains(RED));
+
+        // In a program joined from two files, the second one's lines.
+        let mut sources = Sources::single("a.b", 3);
+        sources.push("c.d", 4);
+        let errors = bracket_errors(b"+[\n-\n]]", &ParseOptions::default());
+        assert_eq!(
+            Diagnostic::parse_error(&errors[0]).render_in(&sources, b"+[\n-\n]]", false),
+            "unmatched ']'\n --> c.d:2:2\n  |\n2 | ]]\n  |  ^ there is no loop to close here"
+        );
+
+        let warning =
+            Diagnostic::warning("loop never runs").label(0..1, "its cell is always zero here");
+        assert_eq!(
+            warning.render("-e", b"[]", false),
+            "warning: loop never runs\n --> -e:1:1\n  |\n1 | []\n  | ^ its cell is always zero here"
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//! `bf fmt`: strips comments from a program, or lays it out.
+
+use std::io::{self, Write};
+
+use super::{Error, Result, read_source, read_token_map, usage_error};
+use crate::ParseOptions;
+use crate::format::{self, FormatOptions};
+use crate::pragma::header_len;
+
+pub(super) const USAGE: &str =
+    "Usage: bf fmt [OPTIONS] [input.bf]\nTry 'bf fmt --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf fmt [OPTIONS] [input.bf]
+
+Prints a program, read from stdin without a file, with everything but the
+eight commands removed. With --pretty, each loop's brackets go on lines of
+their own, with the body indented. The '#!' lines at the top, a shebang and
+pragmas, are kept as they are.
+
+Options:
+      --pretty              Indent loop bodies and wrap long lines
+      --width <N>           Wrap --pretty lines at N columns [default: 80]
+      --comments            Keep comments with --pretty, on lines of their own
+      --map <FILE>          Read the commands with the tokens listed in FILE
+                            and print them as standard brainfuck
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut source = None;
+    let mut parse = ParseOptions::default();
+    let mut pretty = false;
+    let mut options = FormatOptions::default();
+    // Whether --width or --comments were given, which need --pretty.
+    let mut layout = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            "--pretty" => pretty = true,
+            "--width" => {
+                options.width = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| usage_error("--width expects a number of columns"))?;
+                layout = true;
+            }
+            "--comments" => {
+                options.comments = true;
+                layout = true;
+            }
+            "--map" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--map expects a token map file"))?;
+                parse.tokens = Some(read_token_map(&path)?);
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if layout && !pretty {
+        return Err(usage_error("--width and --comments need --pretty"));
+    }
+    let (_, raw) = read_source(source.as_deref())?;
+    // The shebang and pragma lines stay, so a script still runs.
+    let (header, body) = raw.split_at(header_len(&raw));
+    let tokens = format::pieces(body, &parse);
+    let mut code = header.to_vec();
+    if !code.is_empty() && !code.ends_with(b"\n") {
+        code.push(b'\n');
+    }
+    if pretty {
+        code.extend(format::pretty(&tokens, &options));
+    } else {
+        code.extend(format::minify(&tokens));
+        code.push(b'\n');
+    }
+    io::stdout()
+        .lock()
+        .write_all(&code)
+        .map_err(|e| Error::Failed(e.to_string()))
+}
@@ -0,0 +1,51 @@
+//! `bf gen`: writes a program that prints a text.
+
+use std::io::{self, Write};
+
+use super::{Error, Result, read_source, usage_error};
+use crate::text_to_brainfuck;
+
+pub(super) const USAGE: &str =
+    "Usage: bf gen [--text TEXT | file]\nTry 'bf gen --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf gen [--text TEXT | file]
+
+Prints a short brainfuck program that prints TEXT, or the contents of a
+file, read from stdin without one. The program works with any cell size.
+
+Options:
+      --text <TEXT>         Print TEXT instead of a file's contents
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut source = None;
+    let mut text = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            "--text" => {
+                text = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--text expects a text"))?,
+                );
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let bytes = match (text, source) {
+        (Some(_), Some(_)) => return Err(usage_error("--text can't be combined with a file")),
+        (Some(text), None) => text.into_bytes(),
+        (None, source) => read_source(source.as_deref())?.1,
+    };
+    let mut code = text_to_brainfuck(&bytes);
+    code.push('\n');
+    io::stdout()
+        .lock()
+        .write_all(code.as_bytes())
+        .map_err(|e| Error::Failed(e.to_string()))
+}
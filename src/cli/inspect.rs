@@ -0,0 +1,154 @@
+//! `bf inspect-core`: shows what a core dump from `bfi --core` recorded.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use super::{Error, Result, dump_tape, read_source, usage_error};
+use crate::bytecode::{Core, load_core};
+use crate::interpreter::{fmt_cells, window};
+use crate::trace::{Event, TextTracer, Tracer};
+use crate::{fmt_op, fmt_ops};
+
+pub(super) const USAGE: &str =
+    "Usage: bf inspect-core [OPTIONS] <core>\nTry 'bf inspect-core --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf inspect-core [OPTIONS] <core>
+
+Shows why a run saved with 'bfi --core' failed, where the program and the
+pointer were, the cells around the pointer and the last ops executed.
+
+Options:
+      --tape                Also print the whole tape, up to its last non-zero
+                            cell, in hex
+      --emit-ir             Also print the program's ops
+  -h, --help                Print this help";
+
+pub(super) fn main(args: impl Iterator<Item = String>) -> Result<()> {
+    let mut path = None;
+    let mut tape = false;
+    let mut emit_ir = false;
+    for arg in args {
+        match arg.as_str() {
+            "--tape" => tape = true,
+            "--emit-ir" => emit_ir = true,
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let path = path.ok_or_else(|| usage_error("missing <core>"))?;
+    let (name, raw) = read_source(Some(&path))?;
+    let core =
+        load_core(&mut raw.as_slice()).map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    let failed = |e: io::Error| Error::Failed(e.to_string());
+
+    let mut stdout = io::stdout().lock();
+    stdout
+        .write_all(summary(&name, &core).as_bytes())
+        .map_err(failed)?;
+    if !core.recent.is_empty() {
+        writeln!(stdout, "\nlast {} ops:", core.recent.len()).map_err(failed)?;
+        let mut tracer = TextTracer::new(&mut stdout);
+        for entry in &core.recent {
+            let event = Event {
+                step: entry.step,
+                pc: entry.pc,
+                op: &core.checkpoint.ops[entry.pc],
+                ptr: entry.ptr,
+                value: entry.value,
+            };
+            tracer.trace(&event).map_err(failed)?;
+        }
+    }
+    let state = core.checkpoint.state::<u32>();
+    if tape {
+        writeln!(stdout, "\ntape:").map_err(failed)?;
+        dump_tape(state.tape(), false, &mut stdout).map_err(failed)?;
+    }
+    if emit_ir {
+        writeln!(stdout, "\nops:").map_err(failed)?;
+        stdout
+            .write_all(fmt_ops(&core.checkpoint.ops).as_bytes())
+            .map_err(failed)?;
+    }
+    stdout.flush().map_err(failed)
+}
+
+/// Why the run failed, where it was, and the cells around the pointer.
+fn summary(name: &str, core: &Core) -> String {
+    let state = core.checkpoint.state::<u32>();
+    let mut out = String::new();
+    let _ = writeln!(out, "{}: {}", name, core.reason);
+    match core.checkpoint.ops.get(state.pc()) {
+        Some(op) => {
+            let _ = writeln!(
+                out,
+                "stopped at op {} ({}) after {} steps",
+                state.pc(),
+                fmt_op(op),
+                state.steps()
+            );
+        }
+        None => {
+            let _ = writeln!(
+                out,
+                "stopped at the end of the program after {} steps",
+                state.steps()
+            );
+        }
+    }
+    let range = window(state.ptr(), state.tape().len());
+    let _ = writeln!(
+        out,
+        "ptr {}, {}",
+        state.ptr(),
+        fmt_cells(state.tape(), state.ptr(), range)
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::save_core;
+    use crate::interpreter::State;
+    use crate::trace::Entry;
+    use crate::{Config, parse};
+
+    #[test]
+    fn test_summary() {
+        let ops = parse(b"+[>+]").unwrap();
+        let recent = [Entry {
+            step: 9,
+            pc: 2,
+            ptr: 3,
+            value: 1,
+        }];
+        let config = Config {
+            tape_size: 4,
+            ..Config::default()
+        };
+        let state = State::new(2, 4, 9, vec![1u8, 1, 1, 1]);
+        let mut out = Vec::new();
+        save_core(
+            "op 2 left the tape",
+            &recent,
+            &ops,
+            &config,
+            &state,
+            &mut out,
+        )
+        .unwrap();
+        let core = load_core(&mut out.as_slice()).unwrap();
+        assert_eq!(
+            summary("x.core", &core),
+            "x.core: op 2 left the tape\n\
+             stopped at op 2 (ValAdd(1, 1)) after 9 steps\n\
+             ptr 4, cells 0..4: 1 1 1 1\n"
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! `bf lsp`: a Language Server Protocol server on stdin and stdout with
+//! bracket diagnostics, hover and go-to-definition between matching
+//! brackets, and the loops of a file as document symbols.
+
+use std::collections::HashMap;
+use std::io;
+
+use super::json::{Json, read_message, write_message};
+use super::{Error, Result, usage_error};
+use crate::{OptLevel, ParseOptions, Pipeline, Token, TokenKind, fmt_ops, parse, tokenize};
+
+pub(super) const USAGE: &str = "Usage: bf lsp\nTry 'bf lsp --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf lsp
+
+Serves the Language Server Protocol on stdin and stdout, for editing
+brainfuck in an editor: unmatched brackets are reported as diagnostics,
+hovering over a bracket shows its loop and what it optimizes to, go to
+definition jumps to the matching bracket, and loops are listed as symbols.
+
+Options:
+  -h, --help                Print this help";
+
+/// Loops whose optimized ops take more lines than this are summarized on
+/// hover.
+const HOVER_OPS: usize = 12;
+
+/// `SymbolKind.Array` in the protocol; loops have no better match.
+const LOOP_SYMBOL: u64 = 18;
+
+/// `DiagnosticSeverity.Error`.
+const ERROR_SEVERITY: u64 = 1;
+
+/// `ErrorCodes.MethodNotFound` in JSON-RPC.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    match args.next().as_deref() {
+        None => {}
+        Some("-h" | "--help") => {
+            println!("{}", HELP);
+            return Ok(());
+        }
+        Some(arg) => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+    }
+    let failed = |e: io::Error| Error::Failed(e.to_string());
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+    let mut server = Server::default();
+    while let Some(message) = read_message(&mut stdin).map_err(failed)? {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        if method == "exit" {
+            break;
+        }
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let (result, notifications) = server.handle(method, params);
+        if let Some(id) = message.get("id") {
+            let reply = match result {
+                Some(result) => ("result", result),
+                None => (
+                    "error",
+                    Json::object([
+                        ("code", Json::Number(METHOD_NOT_FOUND as f64)),
+                        ("message", format!("unsupported method '{}'", method).into()),
+                    ]),
+                ),
+            };
+            let response = Json::object([("jsonrpc", "2.0".into()), ("id", id.clone()), reply]);
+            write_message(&mut io::stdout().lock(), &response).map_err(failed)?;
+        }
+        for notification in notifications {
+            write_message(&mut io::stdout().lock(), &notification).map_err(failed)?;
+        }
+    }
+    Ok(())
+}
+
+/// The open documents, by URI.
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    /// Handles a request or notification. Returns the result for a
+    /// request, or `None` if the method is unknown, and the notifications
+    /// to send.
+    fn handle(&mut self, method: &str, params: &Json) -> (Option<Json>, Vec<Json>) {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or("")
+            .to_string();
+        let position = params
+            .get("position")
+            .and_then(|position| self.documents.get(&uri)?.offset(position));
+        let result = match method {
+            "initialize" => Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        // Whole documents on every change.
+                        ("textDocumentSync", 1u64.into()),
+                        ("hoverProvider", true.into()),
+                        ("definitionProvider", true.into()),
+                        ("documentSymbolProvider", true.into()),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object([
+                        ("name", "bf lsp".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ]),
+            "shutdown" => Json::Null,
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = params
+                    .get("textDocument")
+                    .and_then(|document| document.get("text"))
+                    .or_else(|| {
+                        params
+                            .get("contentChanges")?
+                            .as_array()?
+                            .last()?
+                            .get("text")
+                    })
+                    .and_then(Json::as_str);
+                let Some(text) = text else {
+                    return (None, Vec::new());
+                };
+                let document = Document::new(text.to_string());
+                let diagnostics = document.diagnostics(&uri);
+                self.documents.insert(uri, document);
+                return (None, vec![diagnostics]);
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                let diagnostics = Document::new(String::new()).diagnostics(&uri);
+                return (None, vec![diagnostics]);
+            }
+            "textDocument/hover" => self
+                .documents
+                .get(&uri)
+                .zip(position)
+                .and_then(|(document, offset)| document.hover(offset))
+                .unwrap_or(Json::Null),
+            "textDocument/definition" => self
+                .documents
+                .get(&uri)
+                .zip(position)
+                .and_then(|(document, offset)| {
+                    let target = document.matching(offset)?;
+                    Some(Json::object([
+                        ("uri", uri.as_str().into()),
+                        ("range", document.range(target, target + 1)),
+                    ]))
+                })
+                .unwrap_or(Json::Null),
+            "textDocument/documentSymbol" => self
+                .documents
+                .get(&uri)
+                .map_or(Json::Null, |document| document.symbols().into()),
+            // Notifications such as `initialized` need no reply.
+            _ => return (None, Vec::new()),
+        };
+        (Some(result), Vec::new())
+    }
+}
+
+/// An open file and its brackets.
+struct Document {
+    text: String,
+    /// Byte offset of the start of each line.
+    line_starts: Vec<usize>,
+    /// The commands and comments of the text.
+    tokens: Vec<Token>,
+    /// Each bracket's offset with its partner's, or `None` if unmatched.
+    brackets: Vec<(usize, Option<usize>)>,
+}
+
+impl Document {
+    fn new(text: String) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let tokens: Vec<Token> = tokenize(text.as_bytes(), &ParseOptions::default()).collect();
+        let mut brackets: Vec<(usize, Option<usize>)> = Vec::new();
+        let mut open = Vec::new();
+        for token in &tokens {
+            let offset = token.span.start;
+            match token.kind {
+                TokenKind::Command(b'[') => {
+                    open.push(brackets.len());
+                    brackets.push((offset, None));
+                }
+                TokenKind::Command(b']') => match open.pop() {
+                    Some(i) => {
+                        brackets[i].1 = Some(offset);
+                        brackets.push((offset, Some(brackets[i].0)));
+                    }
+                    None => brackets.push((offset, None)),
+                },
+                _ => {}
+            }
+        }
+        Document {
+            text,
+            line_starts,
+            tokens,
+            brackets,
+        }
+    }
+
+    /// The protocol position of a byte offset: a 0-based line and a
+    /// character counted in UTF-16 code units.
+    fn position(&self, offset: usize) -> Json {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.text[start..offset].encode_utf16().count();
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn range(&self, start: usize, end: usize) -> Json {
+        Json::object([("start", self.position(start)), ("end", self.position(end))])
+    }
+
+    /// The byte offset of a protocol position, if it is in the text.
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = position.get("line")?.as_u64()? as usize;
+        let character = position.get("character")?.as_u64()? as usize;
+        let start = *self.line_starts.get(line)?;
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(self.text.len(), |&next| next);
+        let mut units = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            if units >= character {
+                return Some(start + i);
+            }
+            units += c.len_utf16();
+        }
+        None
+    }
+
+    /// The partner of the bracket at `offset`.
+    fn matching(&self, offset: usize) -> Option<usize> {
+        let i = self
+            .brackets
+            .binary_search_by_key(&offset, |&(o, _)| o)
+            .ok()?;
+        self.brackets[i].1
+    }
+
+    /// A `publishDiagnostics` notification for every unmatched bracket.
+    fn diagnostics(&self, uri: &str) -> Json {
+        let diagnostics: Vec<Json> = self
+            .brackets
+            .iter()
+            .filter(|(_, partner)| partner.is_none())
+            .map(|&(offset, _)| {
+                let message = if self.text.as_bytes()[offset] == b'[' {
+                    "unmatched '[': this loop is never closed"
+                } else {
+                    "unmatched ']': there is no loop to close"
+                };
+                Json::object([
+                    ("range", self.range(offset, offset + 1)),
+                    ("severity", ERROR_SEVERITY.into()),
+                    ("source", "bf".into()),
+                    ("message", message.into()),
+                ])
+            })
+            .collect();
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+            ),
+        ])
+    }
+
+    /// Where the bracket at `offset` leads, and what its loop optimizes to.
+    fn hover(&self, offset: usize) -> Option<Json> {
+        let i = self
+            .brackets
+            .binary_search_by_key(&offset, |&(o, _)| o)
+            .ok()?;
+        let Some(partner) = self.brackets[i].1 else {
+            return Some(self.hover_text(offset, "This bracket has no partner.".to_string()));
+        };
+        let (open, close) = (offset.min(partner), offset.max(partner));
+        let (line, column) = self.line_column(partner);
+        let mut text = format!(
+            "Matches `{}` at line {}, column {}.",
+            self.text.as_bytes()[partner] as char,
+            line,
+            column
+        );
+        if let Ok(ops) = parse(&self.text.as_bytes()[open..=close]) {
+            let ops = Pipeline::for_fragment(OptLevel::O2).run(ops);
+            let listing = fmt_ops(&ops);
+            if listing.lines().count() <= HOVER_OPS {
+                text.push_str(&format!(
+                    "\n\nAt -O2 the loop becomes:\n```\n{}```",
+                    listing
+                ));
+            } else {
+                text.push_str(&format!("\n\nAt -O2 the loop becomes {} ops.", ops.len()));
+            }
+        }
+        Some(self.hover_text(offset, text))
+    }
+
+    fn hover_text(&self, offset: usize, text: String) -> Json {
+        Json::object([
+            (
+                "contents",
+                Json::object([("kind", "markdown".into()), ("value", text.into())]),
+            ),
+            ("range", self.range(offset, offset + 1)),
+        ])
+    }
+
+    /// The 1-based line and column of `offset`, as the CLI reports them.
+    fn line_column(&self, offset: usize) -> (usize, usize) {
+        crate::line_column(self.text.as_bytes(), offset)
+    }
+
+    /// The matched loops as a tree of `DocumentSymbol`s, named after the
+    /// comment that leads up to each.
+    fn symbols(&self) -> Vec<Json> {
+        let mut stack: Vec<(usize, Vec<Json>)> = vec![(0, Vec::new())];
+        for &(offset, partner) in &self.brackets {
+            match (self.text.as_bytes()[offset], partner) {
+                (b'[', Some(_)) => stack.push((offset, Vec::new())),
+                (b']', Some(open)) => {
+                    let Some((_, children)) = stack.pop() else {
+                        continue;
+                    };
+                    let symbol = Json::object([
+                        ("name", self.loop_name(open).into()),
+                        ("kind", LOOP_SYMBOL.into()),
+                        ("range", self.range(open, offset + 1)),
+                        ("selectionRange", self.range(open, open + 1)),
+                        ("children", children.into()),
+                    ]);
+                    if let Some((_, siblings)) = stack.last_mut() {
+                        siblings.push(symbol);
+                    }
+                }
+                _ => {}
+            }
+        }
+        stack.swap_remove(0).1
+    }
+
+    /// The comment just before the loop at `open`, or where it starts.
+    fn loop_name(&self, open: usize) -> String {
+        let i = self.tokens.partition_point(|token| token.span.start < open);
+        let before = match i.checked_sub(1).map(|i| &self.tokens[i]) {
+            Some(token) if token.kind == TokenKind::Comment => &self.text[token.span.clone()],
+            _ => "",
+        };
+        let comment = before.split_whitespace().collect::<Vec<_>>().join(" ");
+        if comment.is_empty() {
+            let (line, column) = self.line_column(open);
+            format!("loop at {}:{}", line, column)
+        } else {
+            comment
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brackets() {
+        let document = Document::new("é [\n copy [->+<]\n]]".to_string());
+        assert_eq!(document.matching(3), Some(18));
+        assert_eq!(document.matching(18), Some(3));
+        assert_eq!(document.matching(19), None);
+        let position = Json::object([("line", 1u64.into()), ("character", 6u64.into())]);
+        assert_eq!(document.offset(&position), Some(11));
+        assert_eq!(
+            document.position(3).to_string(),
+            r#"{"line":0,"character":2}"#
+        );
+
+        let diagnostics = document.diagnostics("file:///a.bf").to_string();
+        assert_eq!(diagnostics.matches("unmatched").count(), 1);
+        let symbols = document.symbols();
+        assert_eq!(symbols.len(), 1);
+        let children = symbols[0].get("children").and_then(Json::as_array).unwrap();
+        assert_eq!(children[0].get("name").and_then(Json::as_str), Some("copy"));
+    }
+
+    #[test]
+    fn test_hover_shows_optimized_loop() {
+        let document = Document::new("[->+<]".to_string());
+        let hover = document.hover(0).unwrap().to_string();
+        assert!(hover.contains("Matches `]` at line 1, column 6."));
+        assert!(hover.contains("AddTo(1)"));
+    }
+}
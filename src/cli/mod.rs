@@ -0,0 +1,653 @@
+//! The command-line front ends behind the `bf` multitool. `bfi` and `bfc`
+//! are aliases for `bf run` and `bf compile`.
+//!
+//! Each command takes its arguments without the program name and reports
+//! failures as an [`Error`] rather than exiting, so the binaries stay thin
+//! wrappers around [`bf`], [`bfi`] and [`bfc`].
+
+mod analyze;
+mod asm;
+mod bench;
+mod check;
+mod compile;
+mod config;
+mod conformance;
+mod dap;
+mod debug;
+mod diagnostic;
+mod fmt;
+mod generate;
+mod inspect;
+mod json;
+mod lsp;
+mod obfuscate;
+mod optimize;
+mod pipe;
+mod repl;
+mod run;
+mod serve;
+mod stats;
+mod verify;
+#[cfg(unix)]
+mod visualize;
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::ops::Range;
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use diagnostic::Sources;
+
+use crate::pragma::header_len;
+use crate::{
+    BoundsPolicy, BuiltinPass, Cell, CellWidth, CodegenOptions, Config, Dialect, EofBehavior, Op,
+    OptLevel, ParseOptions, Pass, PassStats, Pipeline, Pragmas, Semantics, TokenMap, pragmas,
+};
+
+/// Why a command failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The arguments were wrong; printed with the command's usage, exit
+    /// status 2.
+    Usage(String),
+    /// The command ran but failed; exit status 1.
+    Failed(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn usage_error(msg: impl Into<String>) -> Error {
+    Error::Usage(msg.into())
+}
+
+/// The arguments as strings, or a usage error for the first one that isn't
+/// valid UTF-8.
+fn utf8_args(args: impl IntoIterator<Item = impl Into<OsString>>) -> Result<Vec<String>> {
+    args.into_iter()
+        .map(|arg| {
+            arg.into().into_string().map_err(|arg| {
+                usage_error(format!(
+                    "argument '{}' is not valid UTF-8",
+                    arg.to_string_lossy()
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Turns a command's outcome into an exit code, printing the error.
+fn finish(prog: &str, usage: &str, result: Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Usage(msg)) => {
+            eprintln!("{}: {}\n{}", prog, msg, usage);
+            ExitCode::from(2)
+        }
+        Err(Error::Failed(msg)) => {
+            eprintln!("{}: {}", prog, msg);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+const BF_USAGE: &str = "Usage: bf <COMMAND> [ARGS]\nTry 'bf help' for more information.";
+
+const BF_HELP: &str = "\
+Usage: bf <COMMAND> [ARGS]
+
+Commands:
+  run      Run a program (also available as bfi)
+  compile  Compile a program to Rust, C, LLVM IR or an executable (also
+           available as bfc)
+  check    Check a program for bracket errors without running it
+  analyze  Try to prove that a program ends and stays on its tape
+  asm      Assemble a program with named cells, macros and includes into
+           brainfuck
+  fmt      Strip everything but the eight commands from a program, or
+           indent it
+  gen      Write a program that prints a given text
+  optimize Rewrite a program as shorter brainfuck through the optimizer
+  obfuscate
+           Rewrite a program as scrambled brainfuck that does the same
+  pipe     Run programs as a pipeline, each reading what the one before
+           wrote
+  stats    Report on a program's commands, loops and optimization
+  bench-compare
+           Time a program at several optimization levels and check that
+           they agree on its output
+  inspect-core
+           Show where a run saved with 'bfi --core' failed
+  dap      Serve the Debug Adapter Protocol on stdio, for editors
+  lsp      Serve the Language Server Protocol on stdio, for editors
+  repl     Run brainfuck interactively, a line at a time
+  serve    Run programs sent over HTTP or a WebSocket, streaming their
+           output back
+  test     Run a directory of programs and check their output against
+           .expected files
+  help     Print this help
+
+Run 'bf <COMMAND> --help' for a command's options. 'bf FILE' runs FILE, so
+a program can start with a '#!/usr/bin/env bf' line.";
+
+/// The `bf` multitool: dispatches to the subcommand named by the first
+/// argument.
+pub fn bf(args: impl IntoIterator<Item = impl Into<OsString>>) -> ExitCode {
+    let mut args = match utf8_args(args) {
+        Ok(args) => args.into_iter(),
+        Err(e) => return finish("bf", BF_USAGE, Err(e)),
+    };
+    let Some(command) = args.next() else {
+        return finish("bf", BF_USAGE, Err(usage_error("missing command")));
+    };
+    match command.as_str() {
+        "run" => finish("bf run", &run::usage("bf run"), run::main("bf run", args)),
+        "compile" => finish(
+            "bf compile",
+            &compile::usage("bf compile"),
+            compile::main("bf compile", args),
+        ),
+        "check" => finish("bf check", check::USAGE, check::main(args)),
+        "analyze" => finish("bf analyze", analyze::USAGE, analyze::main(args)),
+        "asm" => finish("bf asm", asm::USAGE, asm::main(args)),
+        "fmt" => finish("bf fmt", fmt::USAGE, fmt::main(args)),
+        "gen" => finish("bf gen", generate::USAGE, generate::main(args)),
+        "optimize" => finish("bf optimize", optimize::USAGE, optimize::main(args)),
+        "obfuscate" => finish("bf obfuscate", obfuscate::USAGE, obfuscate::main(args)),
+        "pipe" => finish("bf pipe", pipe::USAGE, pipe::main(args)),
+        "stats" => finish("bf stats", stats::USAGE, stats::main(args)),
+        "bench-compare" => finish("bf bench-compare", bench::USAGE, bench::main(args)),
+        "inspect-core" => finish("bf inspect-core", inspect::USAGE, inspect::main(args)),
+        "dap" => finish("bf dap", dap::USAGE, dap::main(args)),
+        "lsp" => finish("bf lsp", lsp::USAGE, lsp::main(args)),
+        "repl" => finish("bf repl", repl::USAGE, repl::main(args)),
+        "serve" => finish("bf serve", serve::USAGE, serve::main(args)),
+        "test" => finish("bf test", conformance::USAGE, conformance::main(args)),
+        "help" | "-h" | "--help" => {
+            println!("{}", BF_HELP);
+            ExitCode::SUCCESS
+        }
+        "-V" | "--version" => {
+            println!("bf {}", env!("CARGO_PKG_VERSION"));
+            ExitCode::SUCCESS
+        }
+        // A script starting with `#!/usr/bin/env bf` runs as `bf FILE`.
+        _ if Path::new(&command).is_file() => finish(
+            "bf",
+            &run::usage("bf run"),
+            run::main("bf", iter::once(command).chain(args)),
+        ),
+        _ => finish(
+            "bf",
+            BF_USAGE,
+            Err(usage_error(format!("unknown command '{}'", command))),
+        ),
+    }
+}
+
+/// `bfi`, the same as `bf run`.
+pub fn bfi(args: impl IntoIterator<Item = impl Into<OsString>>) -> ExitCode {
+    finish(
+        "bfi",
+        &run::usage("bfi"),
+        utf8_args(args).and_then(|args| run::main("bfi", args.into_iter())),
+    )
+}
+
+/// `bfc`, the same as `bf compile`.
+pub fn bfc(args: impl IntoIterator<Item = impl Into<OsString>>) -> ExitCode {
+    finish(
+        "bfc",
+        &compile::usage("bfc"),
+        utf8_args(args).and_then(|args| compile::main("bfc", args.into_iter())),
+    )
+}
+
+/// The optimization level and machine flags shared by `run` and `compile`.
+#[derive(Debug, Default)]
+struct Settings {
+    level: OptLevel,
+    /// `--passes`, run instead of the level's.
+    passes: Option<Vec<BuiltinPass>>,
+    /// `--disable-pass`.
+    disabled: Vec<BuiltinPass>,
+    /// `--passes help`: list the passes instead of doing anything else.
+    list_passes: bool,
+    /// `--opt-stats`: report what each pass did to stderr.
+    opt_stats: bool,
+    options: CodegenOptions,
+    /// The machine flags given, which a program's pragmas don't override.
+    flags: Pragmas,
+    parse: ParseOptions,
+}
+
+impl Settings {
+    /// Consumes `arg` (and its value, from `args`) if it is a shared flag.
+    fn parse(&mut self, arg: &str, args: &mut impl Iterator<Item = String>) -> Result<bool> {
+        if let Some((flag @ ("--passes" | "--disable-pass"), list)) = arg.split_once('=') {
+            self.parse_passes(flag, list)?;
+            return Ok(true);
+        }
+        match arg {
+            "-O0" | "-O1" | "-O2" | "-O3" => {
+                self.level = OptLevel::from_name(&arg[1..]).unwrap_or_default();
+            }
+            "--passes" | "--disable-pass" => {
+                let list = args.next().ok_or_else(|| {
+                    usage_error(format!("{} expects a comma-separated list of passes", arg))
+                })?;
+                self.parse_passes(arg, &list)?;
+            }
+            "--tape-size" => {
+                self.options.tape_size = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) if n > 0 => n,
+                    _ => {
+                        return Err(usage_error(
+                            "--tape-size expects a positive number of cells",
+                        ));
+                    }
+                };
+                self.flags.tape_size = Some(self.options.tape_size);
+            }
+            "--bounds" => {
+                self.options.bounds = match args.next().as_deref() {
+                    Some("error") => BoundsPolicy::Error,
+                    Some("clamp") => BoundsPolicy::Clamp,
+                    Some("wrap") => BoundsPolicy::Wrap,
+                    Some("grow") => BoundsPolicy::Grow,
+                    Some("bidirectional") => BoundsPolicy::Bidirectional,
+                    Some("unchecked") => BoundsPolicy::Unchecked,
+                    _ => {
+                        return Err(usage_error(
+                            "--bounds expects 'error', 'clamp', 'wrap', 'grow', 'bidirectional' \
+                             or 'unchecked'",
+                        ));
+                    }
+                };
+            }
+            "--cell-size" => {
+                self.options.cell_width = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .and_then(CellWidth::from_bits)
+                    .ok_or_else(|| usage_error("--cell-size expects 8, 16 or 32"))?;
+                self.flags.cell_width = Some(self.options.cell_width);
+            }
+            "--eof" => {
+                self.options.eof = match args.next().as_deref() {
+                    Some("unchanged") => EofBehavior::Unchanged,
+                    Some("0") => EofBehavior::Zero,
+                    Some("-1") => EofBehavior::MinusOne,
+                    _ => return Err(usage_error("--eof expects 'unchanged', '0' or '-1'")),
+                };
+                self.flags.eof = Some(self.options.eof);
+            }
+            "--opt-stats" => self.opt_stats = true,
+            "--strict" => self.parse.semantics = Semantics::Strict,
+            "--debug-char" => self.parse.debug_char = true,
+            "--dialect" => {
+                self.parse.dialect = match args.next().as_deref() {
+                    Some("standard") => Dialect::Standard,
+                    Some("pbrain") => Dialect::Pbrain,
+                    Some("brainfork") => Dialect::Brainfork,
+                    Some("extended1") => Dialect::Extended1,
+                    Some("multitape") => Dialect::Multitape,
+                    _ => {
+                        return Err(usage_error(
+                            "--dialect expects 'standard', 'pbrain', 'brainfork', 'extended1' or 'multitape'",
+                        ));
+                    }
+                };
+            }
+            "--ext" => {
+                let list = args.next().unwrap_or_default();
+                for name in list.split(',') {
+                    match name {
+                        "random" => self.parse.random = true,
+                        _ => return Err(usage_error("--ext expects 'random'")),
+                    }
+                }
+            }
+            "--map" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--map expects a token map file"))?;
+                self.parse.tokens = Some(read_token_map(&path)?);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Takes the tape size, cell width and end of input that `source`,
+    /// called `name`, asks for in its pragma lines, where no flag gave
+    /// them.
+    fn apply_pragmas(&mut self, name: &str, source: &[u8]) -> Result<()> {
+        let pragmas = pragmas(source).map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+        self.options.tape_size = self
+            .flags
+            .tape_size
+            .or(pragmas.tape_size)
+            .unwrap_or(self.options.tape_size);
+        self.options.cell_width = self
+            .flags
+            .cell_width
+            .or(pragmas.cell_width)
+            .unwrap_or(self.options.cell_width);
+        self.options.eof = self.flags.eof.or(pragmas.eof).unwrap_or(self.options.eof);
+        Ok(())
+    }
+
+    /// Takes the value of `--passes` or `--disable-pass`.
+    fn parse_passes(&mut self, flag: &str, list: &str) -> Result<()> {
+        if flag == "--passes" && list == "help" {
+            self.list_passes = true;
+            return Ok(());
+        }
+        let passes = list
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                BuiltinPass::from_name(name).ok_or_else(|| {
+                    usage_error(format!(
+                        "unknown pass '{}'; '--passes help' lists them",
+                        name
+                    ))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if flag == "--passes" {
+            self.passes = Some(passes);
+        } else {
+            self.disabled.extend(passes);
+        }
+        Ok(())
+    }
+
+    /// The passes to optimize with: `--passes`, or the level's, without
+    /// the disabled ones, the ones that could move a trap with `--strict`
+    /// and the ones unsound under `--bounds`.
+    fn pipeline(&self) -> Pipeline {
+        let mut pipeline = Pipeline::new();
+        let passes = self.passes.as_deref().unwrap_or(self.level.passes());
+        for &pass in passes {
+            if !self.disabled.contains(&pass)
+                && self.parse.semantics.allows(pass)
+                && pass.sound_under(self.options.bounds)
+            {
+                pipeline.push(pass);
+            }
+        }
+        pipeline
+    }
+
+    /// Runs [`pipeline`](Self::pipeline) on `ops`, reporting what each pass
+    /// did with `--opt-stats`.
+    fn optimize(&self, ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+        if !self.opt_stats {
+            return self.pipeline().run_with_spans(ops, spans);
+        }
+        let (ops, spans, stats) = self.pipeline().run_with_stats(ops, spans);
+        eprint!("{}", opt_stats_report(&stats));
+        (ops, spans)
+    }
+
+    /// Runs the passes of [`pipeline`](Self::pipeline) on `ops` until
+    /// `deadline`, leaving out the ones still to run then, so that a time
+    /// limit can cover optimizing as well as running.
+    fn optimize_until(
+        &self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+        deadline: Instant,
+    ) -> (Vec<Op>, Vec<Range<usize>>) {
+        run_until(&self.pipeline(), ops, spans, deadline)
+    }
+
+    fn config(&self) -> Config {
+        Config {
+            tape_size: self.options.tape_size,
+            bounds: self.options.bounds,
+            eof: self.options.eof,
+            ..Config::default()
+        }
+    }
+}
+
+/// Runs the passes of `pipeline` on `ops` in order, leaving out the ones
+/// still to run once `deadline` has passed.
+fn run_until(
+    pipeline: &Pipeline,
+    ops: Vec<Op>,
+    spans: Vec<Range<usize>>,
+    deadline: Instant,
+) -> (Vec<Op>, Vec<Range<usize>>) {
+    pipeline.passes().fold((ops, spans), |(ops, spans), pass| {
+        if Instant::now() < deadline {
+            pass.run_with_spans(ops, spans)
+        } else {
+            (ops, spans)
+        }
+    })
+}
+
+/// What `--passes help` prints.
+fn pass_help() -> String {
+    let mut help = String::from("Passes, as -O2 runs them:\n");
+    for pass in OptLevel::O2.passes() {
+        help.push_str(&format!("  {:<14}{}\n", pass.name(), pass.description()));
+    }
+    help.push_str("\n-O1 runs only ");
+    let o1: Vec<&str> = OptLevel::O1
+        .passes()
+        .iter()
+        .map(|pass| pass.name())
+        .collect();
+    help.push_str(&o1.join(","));
+    help.push_str(", and -O3 runs the same passes as -O2.");
+    help
+}
+
+/// What `--opt-stats` prints: each pass's ops and loops before and after
+/// it, and the kinds of op it made more or fewer of.
+fn opt_stats_report(stats: &[PassStats]) -> String {
+    let row = |name: &str, ops: (usize, usize), loops: (usize, usize), changes: &str| {
+        let line = format!(
+            "{:<14}{:>8}{:>9}{:>10}{:>11}  {}",
+            name, ops.0, ops.1, loops.0, loops.1, changes
+        );
+        format!("{}\n", line.trim_end())
+    };
+    let mut report = format!(
+        "{:<14}{:>8}{:>9}{:>10}{:>11}  changes\n",
+        "pass", "ops in", "ops out", "loops in", "loops out"
+    );
+    for pass in stats {
+        let changes: Vec<String> = pass
+            .kinds
+            .iter()
+            .filter(|(kind, _)| !matches!(*kind, "Jz" | "Jnz"))
+            .map(|(kind, n)| format!("{:+} {}", n, kind))
+            .collect();
+        report.push_str(&row(&pass.name, pass.ops, pass.loops, &changes.join(", ")));
+    }
+    if let (Some(first), Some(last)) = (stats.first(), stats.last()) {
+        let ops = (first.ops.0, last.ops.1);
+        report.push_str(&row("total", ops, (first.loops.0, last.loops.1), ""));
+    }
+    report
+}
+
+/// Reads the program at `path`, or stdin without one, and returns it with
+/// the name to report errors under.
+fn read_source(path: Option<&str>) -> Result<(String, Vec<u8>)> {
+    match path {
+        Some(path) => match fs::read(path) {
+            Ok(raw) => Ok((path.to_string(), raw)),
+            Err(e) => Err(Error::Failed(format!("{}: {}", path, e))),
+        },
+        None => {
+            let mut raw = Vec::new();
+            io::stdin()
+                .read_to_end(&mut raw)
+                .map_err(|e| Error::Failed(format!("<stdin>: {}", e)))?;
+            Ok(("<stdin>".to_string(), raw))
+        }
+    }
+}
+
+/// Reads the programs at `paths` and joins them in order, or reads stdin
+/// without any, and returns the source with where each file is in it.
+/// Only the first file's pragmas count: the `#!` lines at the top of the
+/// others are blanked, keeping their line breaks so positions still match.
+fn read_sources(paths: &[String]) -> Result<(Sources, Vec<u8>)> {
+    let Some((first, rest)) = paths.split_first() else {
+        let (name, raw) = read_source(None)?;
+        return Ok((Sources::single(name, raw.len()), raw));
+    };
+    let (name, mut raw) = read_source(Some(first))?;
+    let mut sources = Sources::single(name, raw.len());
+    for path in rest {
+        let (name, mut part) = read_source(Some(path))?;
+        let header = header_len(&part);
+        for b in part[..header].iter_mut().filter(|b| **b != b'\n') {
+            *b = b' ';
+        }
+        sources.push(name, part.len());
+        raw.extend(part);
+    }
+    Ok((sources, raw))
+}
+
+/// Reads the token map for `--map`.
+fn read_token_map(path: &str) -> Result<TokenMap> {
+    let text = fs::read_to_string(path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+    TokenMap::from_text(&text).map_err(|e| Error::Failed(format!("{}: {}", path, e)))
+}
+
+/// Writes `tape` up to its last non-zero cell: as raw little-endian
+/// cells, or as lines of 16 hex cells after the index of the first.
+fn dump_tape<C: Cell>(tape: &[C], raw: bool, out: &mut impl Write) -> io::Result<()> {
+    let len = tape
+        .iter()
+        .rposition(|cell| !cell.is_zero())
+        .map_or(0, |i| i + 1);
+    let bytes = C::WIDTH.bits() as usize / 8;
+    if raw {
+        for cell in &tape[..len] {
+            out.write_all(&cell.to_u32().to_le_bytes()[..bytes])?;
+        }
+        return out.flush();
+    }
+    for (row, cells) in tape[..len].chunks(16).enumerate() {
+        write!(out, "{:08x}", row * 16)?;
+        for cell in cells {
+            write!(out, " {:0width$x}", cell.to_u32(), width = bytes * 2)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_parse() {
+        let mut settings = Settings::default();
+        let mut args = ["32".to_string(), "file.bf".to_string()].into_iter();
+        assert_eq!(settings.parse("-O1", &mut args), Ok(true));
+        assert_eq!(settings.parse("--cell-size", &mut args), Ok(true));
+        assert_eq!(settings.parse("file.bf", &mut args), Ok(false));
+        assert_eq!(settings.level, OptLevel::O1);
+        assert_eq!(settings.options.cell_width, CellWidth::U32);
+
+        let mut args = ["7".to_string()].into_iter();
+        assert!(matches!(
+            settings.parse("--cell-size", &mut args),
+            Err(Error::Usage(_))
+        ));
+
+        let mut args = ["random".to_string(), "rand".to_string()].into_iter();
+        assert_eq!(settings.parse("--ext", &mut args), Ok(true));
+        assert!(settings.parse.random);
+        assert_eq!(
+            settings.parse("--ext", &mut args),
+            Err(usage_error("--ext expects 'random'"))
+        );
+    }
+
+    #[test]
+    fn test_apply_pragmas() {
+        // The flags win over the pragmas, which win over the defaults.
+        let mut settings = Settings::default();
+        let mut args = ["0".to_string()].into_iter();
+        assert_eq!(settings.parse("--eof", &mut args), Ok(true));
+        let source = b"#!tape 100\n#!eof -1\n+.";
+        assert_eq!(settings.apply_pragmas("a.b", source), Ok(()));
+        assert_eq!(settings.options.tape_size, 100);
+        assert_eq!(settings.options.eof, EofBehavior::Zero);
+        assert_eq!(settings.options.cell_width, CellWidth::U8);
+        assert!(matches!(
+            settings.apply_pragmas("a.b", b"#!cells 7"),
+            Err(Error::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn test_settings_passes() {
+        let mut settings = Settings::default();
+        let mut args = ["clear,move-loop".to_string()].into_iter();
+        assert_eq!(
+            settings.parse("--passes=rle,scan,move-loop", &mut args),
+            Ok(true)
+        );
+        assert_eq!(settings.parse("--disable-pass", &mut args), Ok(true));
+        let pipeline = settings.pipeline();
+        let names: Vec<&str> = pipeline.passes().map(|pass| pass.name()).collect();
+        assert_eq!(names, ["rle", "scan"]);
+
+        // Without --passes, passes are left out of the level's.
+        settings.passes = None;
+        assert_eq!(
+            settings.pipeline().passes().count(),
+            OptLevel::O2.passes().len() - 2
+        );
+        // --strict leaves out the rest but scan and offset.
+        assert_eq!(settings.parse("--strict", &mut args), Ok(true));
+        let pipeline = settings.pipeline();
+        let names: Vec<&str> = pipeline.passes().map(|pass| pass.name()).collect();
+        assert_eq!(names, ["scan", "offset"]);
+
+        assert!(matches!(
+            settings.parse("--passes=rle,nope", &mut args),
+            Err(Error::Usage(_))
+        ));
+        assert_eq!(settings.parse("--passes=help", &mut args), Ok(true));
+        assert!(settings.list_passes);
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        assert_eq!(bf(["frobnicate".to_string()]), ExitCode::from(2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_argument() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let arg = OsString::from_vec(b"prog\xff.b".to_vec());
+        assert_eq!(
+            utf8_args([OsString::from("-O1"), arg.clone()]),
+            Err(usage_error("argument 'prog\u{fffd}.b' is not valid UTF-8"))
+        );
+        assert_eq!(bfi([arg.clone()]), ExitCode::from(2));
+        assert_eq!(bfc([arg.clone()]), ExitCode::from(2));
+        assert_eq!(bf([OsString::from("run"), arg]), ExitCode::from(2));
+    }
+}
@@ -0,0 +1,87 @@
+//! `bf obfuscate`: rewrites a program into a scrambled one that does the
+//! same.
+
+use std::fs;
+use std::io::{self, Write};
+
+use super::{Error, Result, Settings, read_source, usage_error};
+use crate::{BoundsPolicy, ObfuscateOptions, obfuscate};
+
+pub(super) const USAGE: &str =
+    "Usage: bf obfuscate [OPTIONS] [input.bf]\nTry 'bf obfuscate --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf obfuscate [OPTIONS] [input.bf]
+
+Rewrites a program, read from stdin without a file, as one that does the same
+but is hard to read: pairs of commands that cancel out are added, runs of +
+and - are built with multiplication loops where a cell next to them is known
+to be zero, and junk text is mixed in. The same seed always gives the same
+program.
+
+Options:
+      --seed <N>            Seed the choices [default: 0]
+      --tape-size <N>       Cells on the tape the program runs on, which the
+                            loops stay inside [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap,
+                            grow, bidirectional or unchecked [default: error]
+      --debug-char          Keep '#' as a command
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+  -o <FILE>                 Write the program to FILE instead of stdout
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut seed = 0;
+    let mut source = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--seed" => {
+                seed = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| usage_error("--seed expects a number"))?;
+            }
+            "-o" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("-o expects a path"))?,
+                )
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let (name, raw) = read_source(source.as_deref())?;
+    let options = ObfuscateOptions {
+        seed,
+        tape_size: (!matches!(
+            settings.options.bounds,
+            BoundsPolicy::Grow | BoundsPolicy::Bidirectional
+        ))
+        .then_some(settings.options.tape_size),
+    };
+    let code = obfuscate(&raw, &settings.parse, &options)
+        .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    match output {
+        Some(path) => {
+            fs::write(&path, &code).map_err(|e| Error::Failed(format!("{}: {}", path, e)))
+        }
+        None => io::stdout()
+            .lock()
+            .write_all(&code)
+            .map_err(|e| Error::Failed(e.to_string())),
+    }
+}
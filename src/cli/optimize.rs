@@ -0,0 +1,129 @@
+//! `bf optimize`: rewrites a program as plain brainfuck through the
+//! optimizer.
+
+use std::fs;
+use std::io::{self, Write};
+
+use super::{Error, Result, Settings, pass_help, read_source, usage_error};
+use crate::format::{minify, pieces};
+use crate::{OptLevel, ParseError, eval, parse_with, partial_eval, to_brainfuck};
+
+pub(super) const USAGE: &str =
+    "Usage: bf optimize [OPTIONS] [input.bf]\nTry 'bf optimize --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf optimize [OPTIONS] [input.bf]
+
+Optimizes a program, read from stdin without a file, and writes it back out
+as plain brainfuck that does the same, for interpreters without an
+optimizer of their own. Runs of commands are merged, dead code is removed
+and loops are rebuilt. If that doesn't make the program shorter, its
+commands are written as they are.
+
+Options:
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]; -O3 also runs
+                            the part of the program before its first input
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --opt-stats           Print what each pass did to stderr: ops and loops
+                            before and after it, and the ops it made
+      --tape-size <N>       Cells on the tape, for -O3 [default: 30000]
+      --bounds <P>          What leaving the tape does, for -O3: error, clamp,
+                            wrap, grow, bidirectional or unchecked
+                            [default: error]
+      --cell-size <BITS>    Cell width, for -O3: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --debug-char          Keep '#' as a command
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+  -o <FILE>                 Write the program to FILE instead of stdout
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut source = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-o" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("-o expects a path"))?,
+                )
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    let (name, raw) = read_source(source.as_deref())?;
+    let mut code =
+        optimize(&raw, &settings).map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    code.push(b'\n');
+    match output {
+        Some(path) => {
+            fs::write(&path, &code).map_err(|e| Error::Failed(format!("{}: {}", path, e)))
+        }
+        None => io::stdout()
+            .lock()
+            .write_all(&code)
+            .map_err(|e| Error::Failed(e.to_string())),
+    }
+}
+
+/// The shorter of `source` lowered through the optimizer and its commands.
+fn optimize(source: &[u8], settings: &Settings) -> std::result::Result<Vec<u8>, ParseError> {
+    let ops = parse_with(source, &settings.parse)?;
+    let spans = vec![0..0; ops.len()];
+    let (mut ops, _) = settings.optimize(ops, spans);
+    if settings.level >= OptLevel::O3 {
+        ops = partial_eval(
+            ops,
+            &settings.config(),
+            settings.options.cell_width,
+            eval::DEFAULT_FUEL,
+        );
+    }
+    let optimized = to_brainfuck(&ops).into_bytes();
+    let original = minify(&pieces(source, &settings.parse));
+    Ok(if optimized.len() < original.len() {
+        optimized
+    } else {
+        original
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_optimize() {
+        let settings = Settings::default();
+        // Neither loop runs on the zeroed tape, and the rest cancels out.
+        assert_eq!(optimize(b"[<>]+-><[>+<-]>+.", &settings).unwrap(), b">+.");
+        // Nothing to gain: the output is built in cells that are put back.
+        let hello = include_bytes!("../../examples/helloworld.bf");
+        assert_eq!(
+            optimize(hello, &settings).unwrap(),
+            minify(&pieces(hello, &settings.parse))
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! `bf pipe`: runs programs as a chain, each one's output the next one's
+//! input, like a shell pipeline without the processes.
+//!
+//! The programs take turns on one thread, a slice of ops each, the way
+//! [`Playground`](crate::playground::Playground) drives a program. What one
+//! writes is queued for the next, and a program whose queue is full waits
+//! for the next to catch up, so a fast producer doesn't fill memory.
+
+use std::io::{self, Read, Write};
+
+use super::{Error, Result, Settings, pass_help, read_source, usage_error};
+use crate::playground::Feed;
+use crate::{Cell, CellWidth, Config, Interpreter, Op, StepResult, parse_with_spans};
+
+pub(super) const USAGE: &str =
+    "Usage: bf pipe [OPTIONS] <file>...\nTry 'bf pipe --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf pipe [OPTIONS] <file>...
+
+Runs the programs as a pipeline: the first reads stdin, each one after it
+reads what the one before wrote, and the last writes to stdout. The same as
+'bfi a.b | bfi b.b' in a shell, but in one process. The pipeline ends when
+the last program does.
+
+Options:
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --tape-size <N>       Cells on each tape [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap, grow,
+                            bidirectional or unchecked [default: error]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --dialect <NAME>      Also parse the commands of pbrain, extended1 or
+                            multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+  -h, --help                Print this help";
+
+/// Ops a program runs before the next one gets a turn.
+const SLICE: u64 = 1 << 16;
+
+/// Bytes queued for a program before the one writing them waits.
+const QUEUE: usize = 1 << 16;
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut sources = Vec::new();
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if !arg.starts_with('-') => sources.push(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    if sources.is_empty() {
+        return Err(usage_error("expected at least one program"));
+    }
+
+    let mut programs = Vec::new();
+    for source in &sources {
+        let (name, raw) = read_source(Some(source))?;
+        let (ops, spans) = parse_with_spans(&raw, &settings.parse)
+            .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+        programs.push((name, settings.optimize(ops, spans).0));
+    }
+    let config = settings.config();
+    let stdin = io::stdin().lock();
+    let stdout = io::stdout().lock();
+    match settings.options.cell_width {
+        CellWidth::U8 => run::<u8>(programs, &config, stdin, stdout),
+        CellWidth::U16 => run::<u16>(programs, &config, stdin, stdout),
+        CellWidth::U32 => run::<u32>(programs, &config, stdin, stdout),
+    }
+}
+
+/// Runs `programs`, each with its name, as a pipeline from `input` to
+/// `output`.
+fn run<C: Cell>(
+    programs: Vec<(String, Vec<Op>)>,
+    config: &Config,
+    mut input: impl Read,
+    mut output: impl Write,
+) -> Result<()> {
+    let failed = |e: io::Error| Error::Failed(e.to_string());
+    let (names, programs): (Vec<String>, Vec<Vec<Op>>) = programs.into_iter().unzip();
+    let mut stages: Vec<Interpreter<Feed, Vec<u8>, C>> = programs
+        .into_iter()
+        .map(|ops| {
+            let mut stage = Interpreter::with_config(ops, Feed::default(), Vec::new(), config);
+            stage.set_debug_output(io::stderr());
+            stage
+        })
+        .collect();
+    let last = stages.len() - 1;
+    let mut finished = vec![false; stages.len()];
+    let mut buf = [0; 4096];
+    while !finished[last] {
+        let mut hungry = false;
+        for i in 0..stages.len() {
+            if finished[i]
+                || stages
+                    .get(i + 1)
+                    .is_some_and(|next| next.io().0.buf.len() >= QUEUE)
+            {
+                continue;
+            }
+            let result = stages[i].run_steps(SLICE);
+            let written = std::mem::take(stages[i].io_mut().1);
+            match result {
+                StepResult::Running => {}
+                StepResult::NeedsInput => hungry |= i == 0,
+                StepResult::Finished => finished[i] = true,
+                StepResult::Error(e) => {
+                    output.write_all(&written).map_err(failed)?;
+                    return Err(Error::Failed(format!("{}: {}", names[i], e)));
+                }
+            }
+            match stages.get_mut(i + 1) {
+                Some(next) => {
+                    let feed = next.io_mut().0;
+                    feed.buf.extend(written);
+                    feed.closed = finished[i];
+                }
+                None => output.write_all(&written).map_err(failed)?,
+            }
+        }
+        output.flush().map_err(failed)?;
+        if hungry {
+            // Only the first program waits on something outside the
+            // pipeline, so it's the only one to block for.
+            let n = input.read(&mut buf).map_err(failed)?;
+            let feed = stages[0].io_mut().0;
+            if n == 0 {
+                feed.closed = true;
+            } else {
+                feed.buf.extend(&buf[..n]);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EofBehavior, optimize, parse};
+
+    fn program(name: &str, source: &[u8]) -> (String, Vec<Op>) {
+        (name.to_string(), optimize(parse(source).unwrap()))
+    }
+
+    #[test]
+    fn test_pipe() {
+        let config = Config {
+            eof: EofBehavior::Zero,
+            ..Config::default()
+        };
+        // Adds one to each byte, twice, and reverses what's left.
+        let programs = vec![
+            program("inc", b",[+.,]"),
+            program("inc2", b",[+.,]"),
+            program("rev", b">,[>,]<[.<]"),
+        ];
+        let mut out = Vec::new();
+        run::<u8>(programs, &config, &b"abc"[..], &mut out).unwrap();
+        assert_eq!(out, b"edc");
+
+        // The pipeline ends with the last program, even if the first never
+        // does, and a program that fails is reported by name.
+        let programs = vec![program("yes", b"+[.]"), program("head", b",.,.")];
+        let mut out = Vec::new();
+        run::<u8>(programs, &config, &b""[..], &mut out).unwrap();
+        assert_eq!(out, [1, 1]);
+
+        let programs = vec![program("ok", b",[.,]"), program("bad", b"<.")];
+        let result = run::<u8>(programs, &config, &b"x"[..], &mut Vec::new());
+        assert!(matches!(result, Err(Error::Failed(msg)) if msg.starts_with("bad: ")));
+    }
+}
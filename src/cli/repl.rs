@@ -0,0 +1,332 @@
+//! `bf repl`: an interactive prompt that runs brainfuck a line at a time on
+//! one tape.
+
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use super::{Error, Result, Settings, usage_error};
+use crate::interpreter::fmt_cells;
+use crate::{
+    Cell, CellWidth, Config, Fragment, Interpreter, OptLevel, ParseOptions, Pipeline,
+    SessionParser, State,
+};
+
+pub(super) const USAGE: &str =
+    "Usage: bf repl [OPTIONS]\nTry 'bf repl --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf repl [OPTIONS]
+
+Runs brainfuck interactively. Each line runs on the tape the previous lines
+left behind and is added to the session's program; the output and the
+non-zero part of the tape are shown after it. A line with an unclosed '['
+continues on the next one. Lines starting with ':' are commands; ':help'
+lists them.
+
+Options:
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --tape-size <N>       Cells on the tape [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap, grow,
+                            bidirectional or unchecked [default: error]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --debug-char          Make '#' print the pointer and nearby cells to stderr
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+      --max-steps <N>       Give up on a line after N ops [default: 10000000]
+  -h, --help                Print this help";
+
+const COMMANDS: &str = "\
+Commands:
+  :program     Print the lines run so far
+  :tape        Show the non-zero part of the tape
+  :input TEXT  Queue TEXT and a newline for ',' to read
+  :reset       Clear the tape, the program and queued input
+  :help        Print this help
+  :quit        Leave the REPL
+Anything else is brainfuck. A line that fails, by leaving the tape or
+running past the step limit, is undone.";
+
+const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+/// Cells shown at most after each line.
+const WINDOW: usize = 32;
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut max_steps = DEFAULT_MAX_STEPS;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--max-steps" => {
+                max_steps = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| usage_error("--max-steps expects a number of ops"))?;
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let config = Config {
+        max_steps: Some(max_steps),
+        ..settings.config()
+    };
+    match settings.options.cell_width {
+        CellWidth::U8 => repl(Session::<u8>::new(config, settings.level, settings.parse)),
+        CellWidth::U16 => repl(Session::<u16>::new(config, settings.level, settings.parse)),
+        CellWidth::U32 => repl(Session::<u32>::new(config, settings.level, settings.parse)),
+    }
+}
+
+/// Reads lines from stdin until the user quits or closes it.
+fn repl<C: Cell>(mut session: Session<C>) -> Result<()> {
+    let failed = |e: io::Error| Error::Failed(e.to_string());
+    // A line that leaves an unchecked tape panics the interpreter; the REPL
+    // reports that itself, without the panic message and backtrace hint.
+    panic::set_hook(Box::new(|_| {}));
+
+    println!("Type ':help' for commands.");
+    let stdin = io::stdin();
+    loop {
+        print!("{}", if session.is_pending() { "... " } else { "bf> " });
+        io::stdout().flush().map_err(failed)?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(failed)? == 0 {
+            println!();
+            return Ok(());
+        }
+        if !session.is_pending()
+            && let Some(command) = line.trim().strip_prefix(':')
+        {
+            let (command, rest) = command.split_once(' ').unwrap_or((command, ""));
+            match command {
+                "q" | "quit" => return Ok(()),
+                "h" | "help" => println!("{}", COMMANDS),
+                "program" => print!("{}", session.program()),
+                "tape" => println!("{}", session.show_tape()),
+                "input" => session.queue_input(rest),
+                "reset" => session.reset(),
+                _ => println!("Unknown command ':{}'. Type ':help' for commands.", command),
+            }
+            continue;
+        }
+        let (output, error) = match session.eval(&line) {
+            Eval::Done { output } => (output, None),
+            Eval::Failed { output, error } => (output, Some(error)),
+            Eval::Incomplete => continue,
+        };
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&output).map_err(failed)?;
+        if !output.is_empty() && !output.ends_with(b"\n") {
+            writeln!(stdout).map_err(failed)?;
+        }
+        match error {
+            Some(error) => writeln!(stdout, "error: {}; the line was undone", error),
+            None => writeln!(stdout, "{}", session.show_tape()),
+        }
+        .map_err(failed)?;
+    }
+}
+
+/// What running one line did.
+#[derive(Debug, PartialEq, Eq)]
+enum Eval {
+    /// The line ran and was added to the program.
+    Done { output: Vec<u8> },
+    /// The line opens a loop it doesn't close; more is needed.
+    Incomplete,
+    /// The line failed and left the session as it was, after printing
+    /// `output`.
+    Failed { output: Vec<u8>, error: String },
+}
+
+/// The state a REPL carries from line to line.
+struct Session<C> {
+    config: Config,
+    level: OptLevel,
+    parser: SessionParser,
+    /// The lines run so far.
+    program: String,
+    tape: Vec<C>,
+    ptr: usize,
+    /// Input queued with `:input` and not read yet.
+    input: Vec<u8>,
+}
+
+impl<C: Cell> Session<C> {
+    fn new(config: Config, level: OptLevel, parse: ParseOptions) -> Self {
+        Session {
+            tape: vec![C::default(); config.tape_size],
+            config,
+            level,
+            parser: SessionParser::with_options(parse),
+            program: String::new(),
+            ptr: 0,
+            input: Vec::new(),
+        }
+    }
+
+    /// Runs `line`, with any lines before it that opened a loop, from the
+    /// current pointer. If it fails, the tape, the pointer and the queued
+    /// input stay as they were.
+    fn eval(&mut self, line: &str) -> Eval {
+        let (code, ops) = match self.parser.push(line.as_bytes()) {
+            Ok(Fragment::Complete { source, ops }) => (source, ops),
+            Ok(Fragment::Incomplete) => return Eval::Incomplete,
+            Err(e) => {
+                return Eval::Failed {
+                    output: Vec::new(),
+                    error: e.to_string(),
+                };
+            }
+        };
+        // Lines start on whatever the tape holds, so dead-code elimination
+        // can't assume the current cell is zero.
+        let ops = Pipeline::for_fragment(self.level).run(ops);
+
+        let mut output = Vec::new();
+        let mut interp =
+            Interpreter::<_, _, C>::with_config(ops, &self.input[..], &mut output, &self.config);
+        interp.set_debug_output(io::stderr());
+        interp.restore(&State::new(0, self.ptr, 0, self.tape.clone()));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            interp.run()?;
+            Ok::<_, io::Error>(interp)
+        }));
+        let error = match result {
+            Ok(Ok(interp)) if interp.ptr() >= self.tape.len() => {
+                "the pointer left the tape".to_string()
+            }
+            Ok(Ok(interp)) => {
+                let state = interp.snapshot();
+                let (rest, _) = interp.into_inner();
+                let read = self.input.len() - rest.len();
+                self.input.drain(..read);
+                self.ptr = state.ptr();
+                self.tape = state.tape().to_vec();
+                self.program.push_str(&String::from_utf8_lossy(&code));
+                if !self.program.ends_with('\n') {
+                    self.program.push('\n');
+                }
+                return Eval::Done { output };
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "the pointer left the tape".to_string(),
+        };
+        Eval::Failed { output, error }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.parser.is_pending()
+    }
+
+    fn program(&self) -> &str {
+        &self.program
+    }
+
+    fn queue_input(&mut self, text: &str) {
+        self.input.extend_from_slice(text.as_bytes());
+        self.input.push(b'\n');
+    }
+
+    fn reset(&mut self) {
+        self.tape.fill(C::default());
+        self.ptr = 0;
+        self.program.clear();
+        self.parser.clear();
+        self.input.clear();
+    }
+
+    /// The pointer and the cells from the first non-zero one (or the
+    /// pointer) to the last, at most [`WINDOW`] of them around the pointer.
+    fn show_tape(&self) -> String {
+        let nonzero = |cell: &C| cell.to_u32() != 0;
+        let first = self
+            .tape
+            .iter()
+            .position(nonzero)
+            .map_or(self.ptr, |i| i.min(self.ptr));
+        let last = self
+            .tape
+            .iter()
+            .rposition(nonzero)
+            .map_or(self.ptr, |i| i.max(self.ptr));
+        let start = first.max(self.ptr.saturating_sub(WINDOW / 2));
+        let end = (last + 1).min(start + WINDOW);
+        format!(
+            "ptr {}, {}",
+            self.ptr,
+            fmt_cells(&self.tape, self.ptr, start..end)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session<u8> {
+        let config = Config {
+            max_steps: Some(1000),
+            ..Config::default()
+        };
+        Session::new(config, OptLevel::O2, ParseOptions::default())
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut session = session();
+        let done = |output: &[u8]| Eval::Done {
+            output: output.to_vec(),
+        };
+        assert_eq!(session.eval("+++>++\n"), done(b""));
+        assert_eq!(session.show_tape(), "ptr 1, cells 0..2: 3 [2]");
+        // The loop runs on the 3 left behind, which dead-code elimination
+        // would assume to be zero.
+        assert_eq!(session.eval("<[->++<]>"), done(b""));
+        assert_eq!(session.show_tape(), "ptr 1, cells 1..2: [8]");
+
+        assert_eq!(session.eval("[-<+\n"), Eval::Incomplete);
+        assert!(session.is_pending());
+        assert_eq!(session.eval(">]<.\n"), done(&[8]));
+        assert_eq!(session.program(), "+++>++\n<[->++<]>\n[-<+\n>]<.\n");
+
+        session.queue_input("A");
+        assert_eq!(session.eval(",.>,."), done(b"A\n"));
+        assert_eq!(session.show_tape(), "ptr 1, cells 0..2: 65 [10]");
+    }
+
+    #[test]
+    fn test_failed_lines_are_undone() {
+        let mut session = session();
+        session.eval("++>+");
+        assert!(matches!(
+            session.eval("[]"),
+            Eval::Failed { error, .. } if error == "step limit of 1000 exceeded"
+        ));
+        assert!(matches!(session.eval("]"), Eval::Failed { .. }));
+        assert!(matches!(session.eval("<<<+"), Eval::Failed { .. }));
+        assert_eq!(
+            session.eval(".<<<"),
+            Eval::Failed {
+                output: vec![1],
+                error: "the pointer left the tape".to_string()
+            }
+        );
+        assert_eq!(session.show_tape(), "ptr 1, cells 0..2: 2 [1]");
+        assert_eq!(session.program(), "++>+\n");
+
+        session.reset();
+        assert_eq!(session.show_tape(), "ptr 0, cells 0..1: [0]");
+    }
+}
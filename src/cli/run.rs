@@ -0,0 +1,2478 @@
+//! `bf run` / `bfi`: interprets a program.
+
+use std::fmt::{self, Write as _};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::{Range, RangeInclusive};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::config::Defaults;
+use super::diagnostic::{ColorChoice, Diagnostic, Sources};
+use super::json::Json;
+#[cfg(unix)]
+use super::visualize;
+use super::{
+    Error, Result, Settings, debug, dump_tape, pass_help, read_source, read_sources, usage_error,
+    verify,
+};
+use crate::bytecode::{Checkpoint, load_checkpoint, save_checkpoint, save_core};
+use crate::profile::{CostModel, Profile, REPORT_LOOPS};
+use crate::trace::{Entry, JsonTracer, MemStats, RingTracer, TextTracer, Tracer};
+use crate::{
+    BoundsPolicy, BuiltinExecutor, BuiltinPass, Cell, CellWidth, Config, Dialect, FlushPolicy,
+    Interpreter, LoopDepthExceeded, Newline, Op, OptLevel, OutOfBounds, OutputLimitExceeded,
+    RandomInput, ResourceLimits, RuntimeError, State, StepLimitExceeded, TapeLimitExceeded,
+    TimeLimitExceeded, UndefinedProcedure, bytecode, eval, fmt_ops, line_column, load_bytecode,
+    op_name, parse_with_spans, partial_eval, save_bytecode, split_input,
+};
+
+pub(super) fn usage(prog: &str) -> String {
+    format!(
+        "Usage: {} [OPTIONS] <file... | - | -e CODE | --resume FILE>\nTry '{} --help' for more information.",
+        prog, prog
+    )
+}
+
+fn help(prog: &str) -> String {
+    format!(
+        "\
+Usage: {} [OPTIONS] <file... | - | -e CODE | --resume FILE>
+
+Runs a brainfuck program, or bytecode saved with --compile. Several files
+are joined in order into one program. With '-' the program is read from
+stdin, and with -e it is CODE itself. With --resume it continues a run
+saved with --checkpoint, with the saved tape settings. The defaults of -O,
+--tape-size, --cell-size, --eof and --engine can be changed in
+~/.config/bf/config.toml or with BF_* variables.
+
+Options:
+  -e <CODE>                 Run CODE instead of a file
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --no-optimize         Same as -O0
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --opt-stats           Print what each pass did to stderr: ops and loops
+                            before and after it, and the ops it made
+      --tape-size <N>       Cells on the tape [default: 30000]
+      --bounds <P>          What leaving the tape does: error, clamp, wrap, grow,
+                            bidirectional or unchecked [default: error]
+      --cell-size <BITS>    Cell width: 8, 16 or 32 [default: 8]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+      --debug-char          Make '#' print the pointer and nearby cells to stderr
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --seed <N>            Draw '?'s bytes from seed N, so the run can be
+                            repeated [default: the time]
+      --map <FILE>          Read the commands with the tokens listed in FILE
+      --lang <LANG>         brainfuck, or brainloller to read the program from a
+                            PNG image (image feature) [default: brainfuck]
+      --input <FILE>        Read the program's input from FILE instead of stdin
+      --input-str <TEXT>    Give the program TEXT as its input instead
+      --input-random <SEED[:LEN]>
+                            Give the program LEN pseudo-random bytes drawn from
+                            SEED as its input instead, or endless ones
+      --bang-input          Treat everything after the program's first '!' as
+                            its input, for programs stored as code!input
+      --raw-tty             Give ',' each key as it's pressed, without echoing
+                            it, when the input is a terminal (Unix)
+      --record-input <FILE> Save every byte the program reads to FILE
+      --replay-input <FILE> Give the program the input saved with
+                            --record-input, to repeat a run exactly
+      --max-steps <N>       Fail after executing N ops
+      --timeout <SECS>      Fail after running for SECS seconds
+      --max-output <N>      Fail after writing N bytes of output
+      --sandbox             Run an untrusted program: limit it to 16 MiB of
+                            source, 10^9 ops, 10 seconds, 1 MiB of output, a
+                            tape of a million cells and loops nested 1000
+                            deep unless given other limits, with no input but
+                            --input, --input-str or --input-random,
+                            and print a JSON report of how it ended to
+                            stderr; the time limit covers parsing and
+                            optimizing, which stops when it runs out
+      --flush <P>           When output is flushed: always, newline, N for every
+                            N bytes, input or exit; all but exit also flush
+                            before reading input [default: newline, or exit
+                            with --output]
+      --color <WHEN>        Color error messages: auto, always or never
+                            [default: auto]
+      --output <FILE>       Write the program's output to FILE instead of
+                            stdout, leaving the terminal to traces, profiles
+                            and errors
+      --newline <NL>        The line ending the program uses, lf, cr or crlf:
+                            each line ending it reads is given to it as NL,
+                            and each NL it writes goes out as LF [default: lf]
+      --input-newline <NL>  Only translate what it reads
+      --output-newline <NL> Only translate what it writes
+      --unicode             Make '.' write the cell as a Unicode character in
+                            UTF-8 and ',' read one; needs --cell-size 32
+      --profile             Print how often each loop and kind of op ran to
+                            stderr afterwards; op indices match --emit-ir
+      --profile-record <FILE>
+                            Save how often each op ran to FILE afterwards, as
+                            JSON, for --profile-use
+      --profile-use <FILE>  Run tiered, compiling the loops that FILE, from
+                            --profile-record with the same program and
+                            settings, found hot before the run starts
+      --flamegraph <FILE>   Write the ops each loop ran to FILE afterwards, as
+                            collapsed stacks for flamegraph tools
+      --count-cycles        Print the cycles the run cost to stderr afterwards,
+                            one per op executed unless --cost-model says
+      --cost-model <FILE>   Charge each kind of op the cycles FILE lists, one
+                            'Kind N' per line; implies --count-cycles
+      --metrics-json        Print a JSON object of how the run went to stderr
+                            afterwards: how it ended, the time parsing,
+                            optimizing and running took, the ops run by kind
+                            and the peak memory
+      --mem-stats           Print how much of the tape the run used to stderr
+                            afterwards: the rightmost cell reached, the cells
+                            written and the ones left non-zero
+      --trace               Log every executed op with the pointer and current
+                            cell to stderr
+      --trace-file <FILE>   Log the trace to FILE instead
+      --trace-format <F>    text, or json for JSON Lines ending with the final
+                            state [default: text]
+      --trace-from <N>      Start the trace at step N, counting from 0
+      --trace-to <N>        End the trace after step N
+      --checkpoint <FILE>   Save the run to FILE now and then, and when it
+                            stops, to continue later with --resume
+      --checkpoint-every <SECS>
+                            Time between checkpoints [default: 60]
+      --resume <FILE>       Continue the run saved in FILE
+      --dump-tape-on-exit   Write the tape up to its last non-zero cell to stderr
+                            when the program stops, also on Ctrl-C
+      --dump-tape-file <FILE>
+                            Write the dump to FILE instead
+      --dump-format <F>     hex, or raw for the cells' bytes [default: hex]
+      --core <FILE>         If the run fails, save the program, the machine and
+                            the last ops executed to FILE, for bf inspect-core
+      --engine <E>          How the optimized program runs: interpreter;
+                            threaded, lowering each op to a closure first;
+                            packed, lowering each op to a 64-bit word first;
+                            jit or tiered (x86-64 Unix); threads; or
+                            unchecked [default: interpreter]
+      --jit                 Compile to native code before running, the same as
+                            --engine jit (x86-64 Unix)
+      --tiered              Compile loops once they get hot, the same as
+                            --engine tiered (x86-64 Unix)
+      --threads             Run each Brainfork thread on a thread of its own
+                            instead of taking turns, the same as --engine threads
+      --unchecked           Interpret without bounds checks, the same as
+                            --engine unchecked, if the cells the program
+                            touches can be worked out up front; any other
+                            program is checked as usual
+      --compile             Save the optimized program as <file>.bfbc instead of
+                            running it
+      --emit-ir             Print the optimized ops instead of running them
+      --debug               Run the unoptimized program under an interactive
+                            debugger; its input comes from --input
+      --visualize           Run the unoptimized program slowly in the terminal,
+                            showing the tape, the current command and the
+                            output, with keys to pause, step and change the
+                            speed; its input comes from --input (Unix)
+      --verify              Also run the source under a naive interpreter on the
+                            same input and fail if its output or final tape
+                            differs; the input and output are held in memory
+      --bench <N>           Run the optimized program N times with its output
+                            discarded, and print the time and ops per second
+                            of each run and the fastest and median; the input
+                            is held in memory
+      --watch               Run the program again, on a cleared screen,
+                            whenever it or its --input file changes
+  -h, --help                Print this help
+  -V, --version             Print the version",
+        prog
+    )
+}
+
+/// Runs with `args` whenever one of `paths` changes, on a cleared screen,
+/// until interrupted. A failed run is reported and waited out like any
+/// other.
+fn watch_files(prog: &str, args: &[String], paths: &[String]) -> Result<()> {
+    let modified = || -> Vec<_> {
+        paths
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+    loop {
+        print!("\x1b[2J\x1b[H");
+        io::stdout()
+            .flush()
+            .map_err(|e| Error::Failed(e.to_string()))?;
+        // Taken before running, so a change made while it runs counts.
+        let before = modified();
+        if let Err(Error::Usage(msg) | Error::Failed(msg)) = main(prog, args.iter().cloned()) {
+            eprintln!("{}: {}", prog, msg);
+        }
+        eprintln!("{}: watching {} for changes", prog, paths.join(", "));
+        while modified() == before {
+            thread::sleep(WATCH_INTERVAL);
+        }
+    }
+}
+
+/// How often `--watch` checks its files.
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What `--trace` covers, and how it is written.
+struct TraceSettings {
+    /// `--trace-file`, or stderr.
+    file: Option<String>,
+    steps: RangeInclusive<u64>,
+    json: bool,
+}
+
+impl Default for TraceSettings {
+    fn default() -> Self {
+        TraceSettings {
+            file: None,
+            steps: 0..=u64::MAX,
+            json: false,
+        }
+    }
+}
+
+/// Where `--checkpoint` saves the run, and how often.
+struct CheckpointSettings {
+    path: String,
+    every: Duration,
+}
+
+/// Where `--dump-tape-on-exit` writes the tape, and how.
+#[derive(Default)]
+struct DumpSettings {
+    /// `--dump-tape-file`, or stderr.
+    file: Option<String>,
+    raw: bool,
+}
+
+/// Ops run between looks at the clock while checkpointing, and for an
+/// interrupt while dumping.
+const SLICE: u64 = 1 << 24;
+
+/// Set by the SIGINT handler `--dump-tape-on-exit` installs.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Why a run stopped on Ctrl-C.
+#[derive(Debug)]
+struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// `--sandbox`'s timeout where none is given. The other limits are
+/// [`ResourceLimits::sandbox`].
+const SANDBOX_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most source `--sandbox` parses.
+const SANDBOX_SOURCE: usize = 16 << 20;
+
+/// Bytes of program output buffered between flushes.
+const OUTPUT_BUFFER: usize = 1 << 16;
+
+/// Bytes of program output buffered for `--output`, which nobody reads
+/// while it's written.
+const OUTPUT_FILE_BUFFER: usize = 1 << 20;
+
+/// Ops a core dump keeps from before the failure.
+const CORE_TRACE: usize = 32;
+
+/// Why a run with `--core` failed, and where the dump went.
+#[derive(Debug)]
+struct CoreDumped {
+    reason: String,
+    path: String,
+}
+
+impl fmt::Display for CoreDumped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}; core dumped to {}", self.reason, self.path)
+    }
+}
+
+impl std::error::Error for CoreDumped {}
+
+/// Where the program comes from.
+enum Source {
+    /// Files, joined in order.
+    File(Vec<String>),
+    Stdin,
+    /// Code given with `-e`.
+    Inline(String),
+    /// A run saved with `--checkpoint`, given with `--resume`.
+    Checkpoint(String),
+}
+
+fn set_source(source: &mut Option<Source>, new: Source) -> Result<()> {
+    if source.is_some() {
+        return Err(usage_error("expected a single program"));
+    }
+    *source = Some(new);
+    Ok(())
+}
+
+/// Where the program's input comes from, if not stdin.
+enum Input {
+    File(String),
+    /// Text given with `--input-str`, or after the program's `!` with
+    /// `--bang-input`.
+    Inline(Vec<u8>),
+    /// `--input-random`'s seed and length.
+    Random(u64, Option<u64>),
+}
+
+fn set_input(input: &mut Option<Input>, new: Input) -> Result<()> {
+    if input.is_some() {
+        return Err(usage_error(
+            "expected a single --input, --input-str, --input-random or --replay-input",
+        ));
+    }
+    *input = Some(new);
+    Ok(())
+}
+
+/// What `--engine` says without a known engine.
+const ENGINES: &str = "--engine expects 'interpreter', 'threaded', 'jit', 'tiered', 'threads', 'unchecked' or 'packed'";
+
+/// How the optimized program runs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Engine {
+    Interpret,
+    /// Compile everything up front.
+    Jit,
+    /// Interpret, compiling hot loops.
+    Tiered,
+    /// Interpret, with an OS thread for each Brainfork thread.
+    Threads,
+    /// Run the ops lowered to closures.
+    Threaded,
+    /// Interpret without bounds checks.
+    Unchecked,
+    /// Run the ops lowered to packed words.
+    Packed,
+}
+
+impl Engine {
+    /// The engine `--engine` calls `name`.
+    fn from_name(name: &str) -> Result<Engine> {
+        let engine = match name {
+            "interpreter" => Engine::Interpret,
+            "threaded" => Engine::Threaded,
+            "jit" => Engine::Jit,
+            "tiered" => Engine::Tiered,
+            "threads" => Engine::Threads,
+            "unchecked" => Engine::Unchecked,
+            "packed" => Engine::Packed,
+            _ => {
+                return Err(usage_error(ENGINES));
+            }
+        };
+        if matches!(engine, Engine::Jit | Engine::Tiered)
+            && !cfg!(all(unix, target_arch = "x86_64"))
+        {
+            return Err(usage_error(format!(
+                "the {} engine is only supported on x86-64 Unix",
+                name
+            )));
+        }
+        Ok(engine)
+    }
+
+    /// The library's executor for the engine. `unchecked` has none, since
+    /// making one is `unsafe`, and interprets where one is needed.
+    pub(super) fn executor(self) -> BuiltinExecutor {
+        match self {
+            Engine::Jit => BuiltinExecutor::Jit,
+            Engine::Tiered => BuiltinExecutor::Tiered,
+            Engine::Threads => BuiltinExecutor::Threads,
+            Engine::Threaded => BuiltinExecutor::Threaded,
+            Engine::Packed => BuiltinExecutor::Packed,
+            Engine::Interpret | Engine::Unchecked => BuiltinExecutor::Interpreter,
+        }
+    }
+}
+
+pub(super) fn main(prog: &str, args: impl Iterator<Item = String>) -> Result<()> {
+    // 1. Parse Arguments
+    // Kept so `--watch` can run again with the same arguments.
+    let all: Vec<String> = args.collect();
+    let mut args = all.iter().cloned();
+    let defaults = Defaults::load()?;
+    let mut settings = Settings::default();
+    defaults.apply(&mut settings)?;
+    let mut max_steps = None;
+    let mut timeout = None;
+    let mut max_output = None;
+    let mut sandbox = false;
+    let mut flush = None;
+    let mut output = None;
+    let mut input_newline = Newline::Lf;
+    let mut output_newline = Newline::Lf;
+    let mut unicode = false;
+    let mut seed = None;
+    let mut color = ColorChoice::default();
+    let mut source = None;
+    let mut engine = defaults
+        .engine(Engine::from_name)?
+        .unwrap_or(Engine::Interpret);
+    let mut compile = false;
+    let mut emit_ir = false;
+    let mut debug = false;
+    let mut visualize = false;
+    let mut verify = false;
+    let mut bench = None;
+    let mut profile = false;
+    let mut profile_record = None;
+    let mut profile_use = None;
+    let mut mem_stats = false;
+    let mut metrics = false;
+    let mut flamegraph = None;
+    let mut cycles = None;
+    let mut trace: Option<TraceSettings> = None;
+    let mut checkpoint = None;
+    let mut checkpoint_every = Duration::from_secs(60);
+    let mut dump: Option<DumpSettings> = None;
+    let mut core = None;
+    let mut input = None;
+    let mut record_input = None;
+    let mut bang_input = false;
+    let mut raw_tty = false;
+    let mut brainloller = false;
+    // The position of `--watch` among the arguments.
+    let mut watch = None;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "--no-optimize" => settings.level = OptLevel::O0,
+            "--jit" | "--tiered" | "--threads" | "--unchecked" => {
+                engine = Engine::from_name(&arg[2..])?
+            }
+            "--engine" => {
+                let name = args.next().ok_or_else(|| usage_error(ENGINES))?;
+                engine = Engine::from_name(&name)?;
+            }
+            "--lang" => {
+                brainloller = match args.next().as_deref() {
+                    Some("brainfuck") => false,
+                    Some("brainloller") if !cfg!(feature = "image") => {
+                        return Err(usage_error("--lang brainloller needs the image feature"));
+                    }
+                    Some("brainloller") => true,
+                    _ => return Err(usage_error("--lang expects 'brainfuck' or 'brainloller'")),
+                };
+            }
+            "--input" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--input expects a file"))?;
+                set_input(&mut input, Input::File(path))?;
+            }
+            "--input-str" => {
+                let text = args
+                    .next()
+                    .ok_or_else(|| usage_error("--input-str expects the input"))?;
+                set_input(&mut input, Input::Inline(text.into_bytes()))?;
+            }
+            "--input-random" => {
+                let (seed, len) =
+                    args.next()
+                        .and_then(|arg| parse_random(&arg))
+                        .ok_or_else(|| {
+                            usage_error(
+                                "--input-random expects a seed and optional length, SEED[:LEN]",
+                            )
+                        })?;
+                set_input(&mut input, Input::Random(seed, len))?;
+            }
+            "--bang-input" => bang_input = true,
+            "--raw-tty" => raw_tty = true,
+            "--unicode" => unicode = true,
+            "--seed" => {
+                seed = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| usage_error("--seed expects a number"))?,
+                );
+            }
+            "--color" => {
+                color = args
+                    .next()
+                    .as_deref()
+                    .and_then(ColorChoice::from_name)
+                    .ok_or_else(|| usage_error("--color expects 'auto', 'always' or 'never'"))?;
+            }
+            "--output" => {
+                output = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--output expects a file"))?,
+                );
+            }
+            "--record-input" => {
+                record_input = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--record-input expects a file"))?,
+                );
+            }
+            "--replay-input" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--replay-input expects a file"))?;
+                set_input(&mut input, Input::File(path))?;
+            }
+            "--max-steps" => {
+                max_steps = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| usage_error("--max-steps expects a number of ops"))?,
+                );
+            }
+            "--timeout" => {
+                timeout = Some(
+                    args.next()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs.parse().ok()?).ok())
+                        .filter(|timeout| !timeout.is_zero())
+                        .ok_or_else(|| {
+                            usage_error("--timeout expects a positive number of seconds")
+                        })?,
+                );
+            }
+            "--max-output" => {
+                max_output = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .ok_or_else(|| usage_error("--max-output expects a number of bytes"))?,
+                );
+            }
+            "--sandbox" => sandbox = true,
+            "--flush" => {
+                flush = Some(match args.next().as_deref() {
+                    Some("always") => FlushPolicy::Always,
+                    Some("newline") => FlushPolicy::Newline,
+                    Some("input") => FlushPolicy::Input,
+                    Some("exit") => FlushPolicy::Exit,
+                    Some(n) if let Ok(n @ 1..) = n.parse() => FlushPolicy::Bytes(n),
+                    _ => {
+                        return Err(usage_error(
+                            "--flush expects 'always', 'newline', 'input', 'exit' or a positive number of bytes",
+                        ));
+                    }
+                });
+            }
+            "--newline" | "--input-newline" | "--output-newline" => {
+                let newline = args
+                    .next()
+                    .as_deref()
+                    .and_then(parse_newline)
+                    .ok_or_else(|| usage_error(format!("{} expects 'lf', 'cr' or 'crlf'", arg)))?;
+                if arg != "--output-newline" {
+                    input_newline = newline;
+                }
+                if arg != "--input-newline" {
+                    output_newline = newline;
+                }
+            }
+            "-e" => {
+                let code = args.next().ok_or_else(|| usage_error("-e expects code"))?;
+                set_source(&mut source, Source::Inline(code))?;
+            }
+            "-" => set_source(&mut source, Source::Stdin)?,
+            "--resume" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--resume expects a file"))?;
+                set_source(&mut source, Source::Checkpoint(path))?;
+            }
+            "--checkpoint" => {
+                checkpoint = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--checkpoint expects a file"))?,
+                );
+            }
+            "--checkpoint-every" => {
+                checkpoint_every = args
+                    .next()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs.parse().ok()?).ok())
+                    .ok_or_else(|| usage_error("--checkpoint-every expects a number of seconds"))?;
+            }
+            "--dump-tape-on-exit" => {
+                dump.get_or_insert_default();
+            }
+            "--dump-tape-file" => {
+                dump.get_or_insert_default().file = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--dump-tape-file expects a file"))?,
+                );
+            }
+            "--dump-format" => {
+                dump.get_or_insert_default().raw = match args.next().as_deref() {
+                    Some("hex") => false,
+                    Some("raw") => true,
+                    _ => return Err(usage_error("--dump-format expects 'hex' or 'raw'")),
+                };
+            }
+            "--core" => {
+                core = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--core expects a file"))?,
+                );
+            }
+            "--compile" => compile = true,
+            "--emit-ir" | "--dump-ir" => emit_ir = true,
+            "--debug" => debug = true,
+            "--visualize" => visualize = true,
+            "--watch" => watch = Some(all.len() - args.len() - 1),
+            "--verify" => verify = true,
+            "--bench" => {
+                bench = Some(
+                    args.next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or_else(|| usage_error("--bench expects a positive number of runs"))?,
+                );
+            }
+            "--profile" => profile = true,
+            "--profile-record" => {
+                profile_record = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--profile-record expects a path"))?,
+                );
+            }
+            "--profile-use" => {
+                profile_use = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--profile-use expects a path"))?,
+                );
+            }
+            "--mem-stats" => mem_stats = true,
+            "--metrics-json" => metrics = true,
+            "--count-cycles" => {
+                cycles.get_or_insert_default();
+            }
+            "--cost-model" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| usage_error("--cost-model expects a file"))?;
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+                cycles = Some(
+                    CostModel::from_text(&text)
+                        .map_err(|e| Error::Failed(format!("{}: {}", path, e)))?,
+                );
+            }
+            "--flamegraph" => {
+                flamegraph = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--flamegraph expects a path"))?,
+                );
+            }
+            "--trace" => {
+                trace.get_or_insert_default();
+            }
+            "--trace-file" => {
+                trace.get_or_insert_default().file = Some(
+                    args.next()
+                        .ok_or_else(|| usage_error("--trace-file expects a file"))?,
+                );
+            }
+            "--trace-format" => {
+                trace.get_or_insert_default().json = match args.next().as_deref() {
+                    Some("text") => false,
+                    Some("json") => true,
+                    _ => return Err(usage_error("--trace-format expects 'text' or 'json'")),
+                };
+            }
+            "--trace-from" | "--trace-to" => {
+                let step = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or_else(|| usage_error(format!("{} expects a step number", arg)))?;
+                let trace = trace.get_or_insert_default();
+                trace.steps = if arg == "--trace-from" {
+                    step..=*trace.steps.end()
+                } else {
+                    *trace.steps.start()..=step
+                };
+            }
+            "-h" | "--help" => {
+                println!("{}", help(prog));
+                return Ok(());
+            }
+            "-V" | "--version" => {
+                println!("{} {}", prog, env!("CARGO_PKG_VERSION"));
+                return Ok(());
+            }
+            _ if !arg.starts_with('-') => match &mut source {
+                Some(Source::File(paths)) => paths.push(arg),
+                _ => set_source(&mut source, Source::File(vec![arg]))?,
+            },
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    let source = source.ok_or_else(|| usage_error("missing <file>"))?;
+    if let Some(at) = watch {
+        let Source::File(files) = &source else {
+            return Err(usage_error("--watch needs a source file"));
+        };
+        if debug || visualize || compile || bench.is_some() || checkpoint.is_some() {
+            return Err(usage_error(
+                "--watch can't be combined with --debug, --visualize, --compile, --bench or \
+                 --checkpoint",
+            ));
+        }
+        let mut paths = files.clone();
+        if let Some(Input::File(input)) = &input {
+            paths.push(input.clone());
+        }
+        let mut args = all;
+        args.remove(at);
+        return watch_files(prog, &args, &paths);
+    }
+    if brainloller && matches!(&source, Source::File(paths) if paths.len() > 1) {
+        return Err(usage_error("--lang brainloller reads a single image"));
+    }
+    if compile && !matches!(source, Source::File(_)) {
+        return Err(usage_error("--compile needs a source file"));
+    }
+    if debug && matches!(source, Source::Stdin) {
+        return Err(usage_error(
+            "--debug reads commands from stdin, so the program can't come from there",
+        ));
+    }
+    if debug && matches!(source, Source::Checkpoint(_)) {
+        return Err(usage_error("--debug can't resume a checkpoint"));
+    }
+    if visualize && (debug || verify || matches!(source, Source::Checkpoint(_))) {
+        return Err(usage_error(
+            "--visualize can't be combined with --debug, --verify or --resume",
+        ));
+    }
+    if visualize && matches!(source, Source::Stdin) {
+        return Err(usage_error(
+            "--visualize reads keys from stdin, so the program can't come from there",
+        ));
+    }
+    if checkpoint.is_some() && trace.is_some() {
+        return Err(usage_error("--checkpoint can't be combined with --trace"));
+    }
+    if core.is_some() && (trace.is_some() || checkpoint.is_some()) {
+        return Err(usage_error(
+            "--core can't be combined with --trace or --checkpoint",
+        ));
+    }
+    if mem_stats && (trace.is_some() || checkpoint.is_some() || core.is_some()) {
+        return Err(usage_error(
+            "--mem-stats can't be combined with --trace, --checkpoint or --core",
+        ));
+    }
+    if verify
+        && (debug
+            || matches!(source, Source::Checkpoint(_))
+            || profile
+            || profile_record.is_some()
+            || profile_use.is_some()
+            || flamegraph.is_some()
+            || cycles.is_some()
+            || mem_stats
+            || metrics
+            || trace.is_some()
+            || checkpoint.is_some()
+            || dump.is_some()
+            || core.is_some())
+    {
+        return Err(usage_error(
+            "--verify can't be combined with --debug, --resume, --profile, --profile-record, \
+             --profile-use, --flamegraph, --count-cycles, --mem-stats, --metrics-json, --trace, \
+             --checkpoint, --dump-tape-on-exit or --core",
+        ));
+    }
+    if bench.is_some()
+        && (debug
+            || visualize
+            || verify
+            || matches!(source, Source::Checkpoint(_))
+            || profile
+            || profile_record.is_some()
+            || flamegraph.is_some()
+            || cycles.is_some()
+            || mem_stats
+            || metrics
+            || trace.is_some()
+            || checkpoint.is_some()
+            || dump.is_some()
+            || core.is_some())
+    {
+        return Err(usage_error(
+            "--bench can't be combined with --debug, --visualize, --verify, --resume, \
+             --profile, --profile-record, --flamegraph, --count-cycles, --mem-stats, \
+             --metrics-json, --trace, --checkpoint, --dump-tape-on-exit or --core",
+        ));
+    }
+    if sandbox
+        && (debug
+            || visualize
+            || verify
+            || bench.is_some()
+            || matches!(source, Source::Checkpoint(_))
+            || matches!(engine, Engine::Threads | Engine::Unchecked))
+    {
+        return Err(usage_error(
+            "--sandbox can't be combined with --debug, --visualize, --verify, --bench, \
+             --resume, --threads or --unchecked",
+        ));
+    }
+    // Each forked thread gets a tape of its own, so forks would let the
+    // program take memory without limit.
+    if sandbox
+        && (settings.options.bounds == BoundsPolicy::Unchecked
+            || settings.parse.dialect == Dialect::Brainfork)
+    {
+        return Err(usage_error(
+            "--sandbox can't be combined with --bounds unchecked or --dialect brainfork",
+        ));
+    }
+    let mut limits = if sandbox {
+        timeout.get_or_insert(SANDBOX_TIMEOUT);
+        ResourceLimits::sandbox()
+    } else {
+        ResourceLimits::default()
+    };
+    // Checked while parsing too, so a program of nothing but '[' can't
+    // take memory before the run's limits apply.
+    if sandbox {
+        settings.parse.max_depth = limits.max_loop_depth;
+        settings.parse.max_size = Some(SANDBOX_SOURCE);
+    }
+    if max_steps.is_some() {
+        limits.max_steps = max_steps;
+    }
+    if max_output.is_some() {
+        limits.max_output_bytes = max_output;
+    }
+    if let Some(max) = limits.max_tape_cells
+        && settings.options.tape_size > max
+    {
+        return Err(usage_error(format!(
+            "--sandbox allows at most {} cells on the tape",
+            max
+        )));
+    }
+    if output.is_some() && (compile || emit_ir || debug || visualize || bench.is_some()) {
+        return Err(usage_error(
+            "--output can't be combined with --compile, --emit-ir, --debug, --visualize \
+             or --bench",
+        ));
+    }
+    // Nobody watches a file fill, so it's only flushed at the end.
+    let flush = flush.unwrap_or(match output {
+        Some(_) => FlushPolicy::Exit,
+        None => FlushPolicy::Newline,
+    });
+    // Both use the terminal for themselves.
+    if raw_tty && (debug || visualize) {
+        return Err(usage_error(
+            "--raw-tty can't be combined with --debug or --visualize",
+        ));
+    }
+    if bang_input && input.is_some() {
+        return Err(usage_error(
+            "--bang-input can't be combined with --input, --input-str, --input-random \
+             or --replay-input",
+        ));
+    }
+    if bang_input
+        && (brainloller
+            || matches!(source, Source::Checkpoint(_))
+            || settings.parse.dialect == Dialect::Extended1)
+    {
+        return Err(usage_error(
+            "--bang-input can't be combined with --lang brainloller, --resume or \
+             --dialect extended1, where '!' is a command",
+        ));
+    }
+    // Both read all their input before running, whatever the program
+    // uses of it.
+    if (verify || bench.is_some()) && record_input.is_some() {
+        return Err(usage_error(
+            "--record-input can't be combined with --verify or --bench",
+        ));
+    }
+    // Both read all their input before running.
+    if (verify || bench.is_some()) && matches!(input, Some(Input::Random(_, None))) {
+        return Err(usage_error(
+            "--verify and --bench need --input-random with a length",
+        ));
+    }
+    // The reference interpreter only knows the eight standard commands.
+    if verify
+        && (settings.parse.tokens.is_some()
+            || settings.parse.dialect != Dialect::Standard
+            || settings.parse.random)
+    {
+        return Err(usage_error(
+            "--verify can't be combined with --map, --dialect or --ext",
+        ));
+    }
+    // The profile only picks the loops the tiered engine compiles first.
+    if profile_use.is_some() {
+        engine = match engine {
+            Engine::Interpret | Engine::Tiered => Engine::Tiered,
+            _ => {
+                return Err(usage_error(
+                    "--profile-use can't be combined with another engine than tiered",
+                ));
+            }
+        };
+    }
+    let checkpoint = checkpoint.map(|path| CheckpointSettings {
+        path,
+        every: checkpoint_every,
+    });
+    let level = settings.level;
+    let mut cell_width = settings.options.cell_width;
+    let mut config = Config {
+        max_steps: limits.max_steps,
+        timeout,
+        flush,
+        input_newline,
+        output_newline,
+        unicode,
+        seed: seed.unwrap_or_else(clock_seed),
+        ..settings.config()
+    };
+
+    // 2. Load Code
+    let (mut sources, raw) = match &source {
+        Source::File(paths) => read_sources(paths)?,
+        Source::Stdin => read_sources(&[])?,
+        Source::Checkpoint(path) => {
+            let (name, raw) = read_source(Some(path))?;
+            (Sources::single(name, raw.len()), raw)
+        }
+        Source::Inline(code) => (Sources::single("-e", code.len()), code.clone().into_bytes()),
+    };
+    let name = sources.name().to_string();
+    let raw = if brainloller {
+        let raw = from_brainloller(&name, &raw)?;
+        sources = Sources::single(&name, raw.len());
+        raw
+    } else if bang_input {
+        let (code, rest) = split_input(&raw);
+        if let Some(rest) = rest {
+            input = Some(Input::Inline(rest.to_vec()));
+        }
+        code.to_vec()
+    } else {
+        raw
+    };
+    // A checkpoint brings the machine it was saved on.
+    if !matches!(source, Source::Checkpoint(_)) {
+        settings.apply_pragmas(&name, &raw)?;
+        cell_width = settings.options.cell_width;
+        config.tape_size = settings.options.tape_size;
+        config.eof = settings.options.eof;
+        if let Some(max) = limits.max_tape_cells
+            && config.tape_size > max
+        {
+            return Err(Error::Failed(format!(
+                "{}: --sandbox allows at most {} cells on the tape",
+                name, max
+            )));
+        }
+    }
+    let resume = match source {
+        Source::Checkpoint(_) => {
+            let resume = load_checkpoint(&mut raw.as_slice())
+                .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+            cell_width = resume.cell_width;
+            config = Config {
+                max_steps,
+                timeout,
+                flush,
+                input_newline,
+                output_newline,
+                unicode,
+                ..resume.config.clone()
+            };
+            Some(resume)
+        }
+        _ => None,
+    };
+    if unicode {
+        if cell_width != CellWidth::U32 {
+            return Err(usage_error("--unicode needs --cell-size 32"));
+        }
+        // It would print known cells as bytes.
+        settings.disabled.push(BuiltinPass::ConstOutput);
+    }
+
+    if debug || visualize {
+        // The program runs as parsed, so every op maps back to the source.
+        let (ops, spans) = if bytecode::is_bytecode(&raw) {
+            let ops = load_bytecode(&mut raw.as_slice())
+                .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+            (ops, Vec::new())
+        } else {
+            parse_with_spans(&raw, &settings.parse).map_err(|e| {
+                Error::Failed(Diagnostic::parse_error(&e).render_in(
+                    &sources,
+                    &raw,
+                    color.enabled(),
+                ))
+            })?
+        };
+        let program = debug::Program {
+            name: &name,
+            source: &raw,
+            ops,
+            spans,
+        };
+        let mut input = match &input {
+            Some(input) => open_input(input)?,
+            None => Box::new(io::empty()),
+        };
+        if let Some(path) = &record_input {
+            input = Box::new(Recorder::create(path, input)?);
+        }
+        if visualize {
+            return run_visualized(program, input, &config, cell_width);
+        }
+        return match cell_width {
+            CellWidth::U8 => debug::run::<u8>(program, input, &config),
+            CellWidth::U16 => debug::run::<u16>(program, input, &config),
+            CellWidth::U32 => debug::run::<u32>(program, input, &config),
+        };
+    }
+
+    // 3. Parse and optimize, unless this is already bytecode
+    let started = Instant::now();
+    // A sandboxed run's timeout covers parsing and optimizing too.
+    let deadline = timeout.filter(|_| sandbox).map(|timeout| started + timeout);
+    let mut parse_time = Duration::ZERO;
+    let (mut ops, mut spans) = if let Some(resume) = &resume {
+        (resume.ops.clone(), Vec::new())
+    } else if bytecode::is_bytecode(&raw) {
+        let ops = load_bytecode(&mut raw.as_slice())
+            .map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+        parse_time = started.elapsed();
+        (ops, Vec::new())
+    } else {
+        let (ops, spans) = parse_with_spans(&raw, &settings.parse).map_err(|e| {
+            Error::Failed(Diagnostic::parse_error(&e).render_in(&sources, &raw, color.enabled()))
+        })?;
+        parse_time = started.elapsed();
+        match deadline {
+            Some(deadline) => settings.optimize_until(ops, spans, deadline),
+            None => settings.optimize(ops, spans),
+        }
+    };
+
+    if compile {
+        // Partial evaluation depends on the tape settings, so it is left
+        // for when the bytecode runs.
+        let path = Path::new(&name).with_extension("bfbc");
+        let failed = |e: io::Error| Error::Failed(format!("{}: {}", path.display(), e));
+        let mut out = io::BufWriter::new(File::create(&path).map_err(failed)?);
+        save_bytecode(&ops, &mut out).map_err(failed)?;
+        return out.flush().map_err(failed);
+    }
+
+    // 4. Evaluate the input-free prefix, unless the run is already underway
+    if level >= OptLevel::O3
+        && resume.is_none()
+        && deadline.is_none_or(|deadline| Instant::now() < deadline)
+    {
+        ops = partial_eval(ops, &config, cell_width, eval::DEFAULT_FUEL);
+        // What was evaluated no longer maps to the source.
+        spans.clear();
+    }
+    // The run gets what is left of the sandbox's time.
+    if let (Some(deadline), Some(limit)) = (deadline, timeout) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            let stop = TimeLimitExceeded { limit };
+            eprintln!("{}", sandbox_report(&Err(io::Error::other(stop)), 0, 0));
+            return Err(Error::Failed(stop.to_string()));
+        }
+        config.timeout = Some(left);
+    }
+    let metrics = metrics.then(|| Metrics {
+        parse: parse_time,
+        optimize: started.elapsed() - parse_time,
+    });
+
+    let hot = match &profile_use {
+        Some(path) => {
+            let text =
+                fs::read_to_string(path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+            Some(read_profile(&text, &ops).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?)
+        }
+        None => None,
+    };
+
+    if emit_ir {
+        return io::stdout()
+            .lock()
+            .write_all(fmt_ops(&ops).as_bytes())
+            .map_err(|e| Error::Failed(e.to_string()));
+    }
+
+    let reads_stdin = input.is_none() && !sandbox;
+    let mut input = match &input {
+        Some(input) => open_input(input)?,
+        None if sandbox => Box::new(io::empty()),
+        // Threads share the handle, which they can't do with a lock.
+        None => Box::new(io::stdin()),
+    };
+    if let Some(path) = &record_input {
+        input = Box::new(Recorder::create(path, input)?);
+    }
+
+    if verify {
+        if bytecode::is_bytecode(&raw) {
+            return Err(usage_error(
+                "--verify needs the program's source, not bytecode",
+            ));
+        }
+        // Both runs read the same input, so it is read up front, if the
+        // program reads any.
+        let mut recorded = Vec::new();
+        if raw.contains(&b',') {
+            input
+                .read_to_end(&mut recorded)
+                .map_err(|e| Error::Failed(format!("input: {}", e)))?;
+        }
+        let out = &mut open_output(output.as_deref())?;
+        return match cell_width {
+            CellWidth::U8 => verify::verify::<u8>(ops, &raw, &recorded, &config, engine, out),
+            CellWidth::U16 => verify::verify::<u16>(ops, &raw, &recorded, &config, engine, out),
+            CellWidth::U32 => verify::verify::<u32>(ops, &raw, &recorded, &config, engine, out),
+        };
+    }
+
+    if let Some(runs) = bench {
+        // Every run reads the same input.
+        let mut recorded = Vec::new();
+        input
+            .read_to_end(&mut recorded)
+            .map_err(|e| Error::Failed(format!("input: {}", e)))?;
+        return match cell_width {
+            CellWidth::U8 => run_bench::<u8>(&ops, &recorded, &config, engine, hot.as_ref(), runs),
+            CellWidth::U16 => {
+                run_bench::<u16>(&ops, &recorded, &config, engine, hot.as_ref(), runs)
+            }
+            CellWidth::U32 => {
+                run_bench::<u32>(&ops, &recorded, &config, engine, hot.as_ref(), runs)
+            }
+        };
+    }
+
+    let trace = match trace {
+        Some(TraceSettings { file, steps, json }) => {
+            let output: Box<dyn Write> = match file {
+                Some(path) => Box::new(
+                    File::create(&path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?,
+                ),
+                None => Box::new(io::stderr()),
+            };
+            let output = io::BufWriter::new(output);
+            let tracer: Box<dyn Tracer> = if json {
+                Box::new(JsonTracer::new(output))
+            } else {
+                Box::new(TextTracer::new(output))
+            };
+            Some((tracer, steps))
+        }
+        None => None,
+    };
+
+    // 5. Execution
+    let instruments = Instruments {
+        profile,
+        profile_record,
+        flamegraph,
+        cycles,
+        mem_stats,
+        metrics,
+        trace,
+        checkpoint,
+        dump,
+        core,
+        report: sandbox,
+    };
+    let run = Run {
+        ops,
+        source: SourceMap {
+            sources: &sources,
+            source: &raw,
+            spans,
+            color: color.enabled(),
+        },
+        input,
+        output: open_output(output.as_deref())?,
+        config: &config,
+        limits,
+        resume: resume.as_ref(),
+        hot,
+    };
+    let _raw_tty = match raw_tty && reads_stdin {
+        true => RawTty::enable()?,
+        false => None,
+    };
+    match cell_width {
+        CellWidth::U8 => execute::<u8>(run, engine, instruments),
+        CellWidth::U16 => execute::<u16>(run, engine, instruments),
+        CellWidth::U32 => execute::<u32>(run, engine, instruments),
+    }
+}
+
+/// Reads the brainfuck commands from the Brainloller image `png`.
+#[cfg(feature = "image")]
+fn from_brainloller(name: &str, png: &[u8]) -> Result<Vec<u8>> {
+    crate::brainloller::to_brainfuck(png).map_err(|e| Error::Failed(format!("{}: {}", name, e)))
+}
+
+#[cfg(not(feature = "image"))]
+fn from_brainloller(_: &str, _: &[u8]) -> Result<Vec<u8>> {
+    Err(usage_error("--lang brainloller needs the image feature"))
+}
+
+#[cfg(unix)]
+fn run_visualized(
+    program: debug::Program,
+    input: Box<dyn Read>,
+    config: &Config,
+    cell_width: CellWidth,
+) -> Result<()> {
+    match cell_width {
+        CellWidth::U8 => visualize::run::<u8>(program, input, config),
+        CellWidth::U16 => visualize::run::<u16>(program, input, config),
+        CellWidth::U32 => visualize::run::<u32>(program, input, config),
+    }
+}
+
+#[cfg(not(unix))]
+fn run_visualized(_: debug::Program, _: Box<dyn Read>, _: &Config, _: CellWidth) -> Result<()> {
+    Err(usage_error("--visualize needs a Unix terminal"))
+}
+
+fn open_input(input: &Input) -> Result<Box<dyn Read + Send>> {
+    match input {
+        Input::File(path) => {
+            let file = File::open(path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+            Ok(Box::new(io::BufReader::new(file)))
+        }
+        Input::Inline(text) => Ok(Box::new(io::Cursor::new(text.clone()))),
+        &Input::Random(seed, len) => Ok(Box::new(RandomInput::new(seed, len))),
+    }
+}
+
+/// Where the program's output goes: the `--output` file, or stdout. Either
+/// is buffered, and the interpreter flushes it as `config.flush` says.
+fn open_output(path: Option<&str>) -> Result<Box<dyn Write + Send>> {
+    match path {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+            Ok(Box::new(io::BufWriter::with_capacity(
+                OUTPUT_FILE_BUFFER,
+                file,
+            )))
+        }
+        None => Ok(Box::new(io::BufWriter::with_capacity(
+            OUTPUT_BUFFER,
+            io::stdout(),
+        ))),
+    }
+}
+
+/// Passes input through, saving it to a file for `--record-input`. The
+/// interpreter reads a byte per `,`, so what's saved is exactly what the
+/// program read. Each read is written straight away, so a run that's
+/// killed still leaves the input up to that point.
+struct Recorder<R> {
+    input: R,
+    file: File,
+}
+
+impl<R> Recorder<R> {
+    fn create(path: &str, input: R) -> Result<Self> {
+        let file = File::create(path).map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+        Ok(Recorder { input, file })
+    }
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.file.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Takes `--input-random`'s `SEED[:LEN]`.
+fn parse_random(arg: &str) -> Option<(u64, Option<u64>)> {
+    match arg.split_once(':') {
+        Some((seed, len)) => Some((seed.parse().ok()?, Some(len.parse().ok()?))),
+        None => Some((arg.parse().ok()?, None)),
+    }
+}
+
+/// A seed for `?` that differs from run to run, without `--seed`.
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+}
+
+/// Takes the value of `--newline` and its one-way forms.
+fn parse_newline(arg: &str) -> Option<Newline> {
+    match arg {
+        "lf" => Some(Newline::Lf),
+        "cr" => Some(Newline::Cr),
+        "crlf" => Some(Newline::CrLf),
+        _ => None,
+    }
+}
+
+/// The program to execute and where it starts.
+struct Run<'a> {
+    ops: Vec<Op>,
+    /// Where each op came from, to point at in errors.
+    source: SourceMap<'a>,
+    input: Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+    config: &'a Config,
+    /// The config's step limit, and those of `--max-output` and
+    /// `--sandbox`.
+    limits: ResourceLimits,
+    /// The saved run to continue, if any.
+    resume: Option<&'a Checkpoint>,
+    /// `--profile-use`: an earlier run's profile of the same ops.
+    hot: Option<Profile>,
+}
+
+/// The source a program was parsed from, and the span of it each op came
+/// from, or no spans if they aren't known.
+struct SourceMap<'a> {
+    sources: &'a Sources,
+    source: &'a [u8],
+    spans: Vec<Range<usize>>,
+    /// Whether to color diagnostics.
+    color: bool,
+}
+
+impl SourceMap<'_> {
+    /// A diagnostic saying `message`, with `label` under the op at `pc`, or
+    /// just `error` if where that op came from isn't known.
+    fn locate(&self, pc: usize, error: &dyn fmt::Display, message: &str, label: &str) -> String {
+        match self.spans.get(pc) {
+            Some(span) => Diagnostic::new(message)
+                .label(span.clone(), label)
+                .render_in(self.sources, self.source, self.color),
+            None => error.to_string(),
+        }
+    }
+
+    /// The hottest loops of `profile` as they are in the source, each with
+    /// where it is, its iterations, its share of the ops executed and its
+    /// first line; nothing if the ops don't map back to the source.
+    fn hot_loops(&self, profile: &Profile, ops: &[Op]) -> String {
+        let total = profile.total();
+        let mut out = String::new();
+        let loops = profile.loops(ops);
+        for l in loops.iter().take(REPORT_LOOPS) {
+            let (Some(open), Some(close)) = (self.spans.get(l.start), self.spans.get(l.end)) else {
+                return String::new();
+            };
+            let (name, part) = self.sources.file_at(open.start);
+            let file = &self.source[part.start..part.end.min(self.source.len())];
+            let (start, end) = (open.start - part.start, close.end - part.start);
+            let (line, col) = line_column(file, start);
+            let (end_line, end_col) = line_column(file, end - 1);
+            let place = match end_line == line {
+                true => format!("line {}, cols {}-{}", line, col, end_col),
+                false => format!("lines {}-{}", line, end_line),
+            };
+            let share = match total {
+                0 => 0.0,
+                total => 100.0 * l.ops as f64 / total as f64,
+            };
+            if out.is_empty() {
+                out.push_str("\nhottest loops in the source:\n");
+            }
+            let _ = writeln!(
+                out,
+                "  loop at {} of {} ran {} iterations ({:.1}% of steps)",
+                place, name, l.iterations, share
+            );
+            let code = &file[start..end];
+            let first = code.split(|&b| b == b'\n').next().unwrap_or(code);
+            let more = first.len() < code.len() || first.len() > SNIPPET;
+            let first = String::from_utf8_lossy(&first[..first.len().min(SNIPPET)]);
+            let _ = writeln!(
+                out,
+                "    {}{}",
+                first.trim_end(),
+                if more { " ..." } else { "" }
+            );
+        }
+        out
+    }
+}
+
+/// Bytes of a loop's source shown under [`SourceMap::hot_loops`].
+const SNIPPET: usize = 60;
+
+/// How long `--metrics-json`'s run took to get ready.
+struct Metrics {
+    parse: Duration,
+    /// Optimizing, partial evaluation included.
+    optimize: Duration,
+}
+
+/// What to record about a run besides its output. Any of them makes the run
+/// interpreted, whatever the engine.
+struct Instruments {
+    profile: bool,
+    /// `--profile-record`.
+    profile_record: Option<String>,
+    /// `--flamegraph`.
+    flamegraph: Option<String>,
+    /// `--count-cycles`, with the `--cost-model` to count them by.
+    cycles: Option<CostModel>,
+    /// `--mem-stats`.
+    mem_stats: bool,
+    /// `--metrics-json`, with the time taken before the run.
+    metrics: Option<Metrics>,
+    trace: Option<(Box<dyn Tracer>, RangeInclusive<u64>)>,
+    checkpoint: Option<CheckpointSettings>,
+    dump: Option<DumpSettings>,
+    /// `--core`.
+    core: Option<String>,
+    /// `--sandbox`: report how the run ended.
+    report: bool,
+}
+
+fn execute<C: Cell>(run: Run<'_>, engine: Engine, instruments: Instruments) -> Result<()> {
+    let config = run.config;
+    let mut interp = Interpreter::<_, _, C>::with_config(run.ops, run.input, run.output, config);
+    interp.set_limits(run.limits);
+    interp.set_debug_output(io::stderr());
+    if let Some(resume) = run.resume {
+        interp.restore(&resume.state());
+    }
+    if instruments.profile
+        || instruments.profile_record.is_some()
+        || instruments.metrics.is_some()
+        || instruments.flamegraph.is_some()
+        || instruments.cycles.is_some()
+    {
+        interp.enable_profiling();
+    }
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    if let Some(profile) = &run.hot {
+        interp.compile_hot_loops(profile);
+    }
+    let sliced = instruments.checkpoint.is_some() || instruments.dump.is_some();
+    let mut mem_stats = instruments
+        .mem_stats
+        .then(|| MemStats::new(config.tape_size, config.bounds));
+    let started = Instant::now();
+    let result = match (engine, instruments.trace) {
+        (_, Some((mut tracer, steps))) => interp
+            .run_traced(&mut *tracer, steps)
+            .map_err(io::Error::from),
+        (_, None) if let Some(stats) = &mut mem_stats => interp
+            .run_traced(stats, 0..=u64::MAX)
+            .map_err(io::Error::from),
+        (_, None) if let Some(path) = &instruments.core => run_recorded(&mut interp, config, path),
+        (_, None) if sliced => {
+            if instruments.dump.is_some() {
+                catch_interrupt();
+            }
+            run_sliced(&mut interp, config, instruments.checkpoint.as_ref())
+        }
+        (_, None) => run_engine(&mut interp, engine),
+    };
+    let run_time = started.elapsed();
+    // Also after a failure, which is when a profile is most wanted.
+    if let Some(profile) = interp.profile() {
+        if instruments.profile {
+            eprint!("{}", profile.report(interp.ops()));
+            eprint!("{}", run.source.hot_loops(profile, interp.ops()));
+        }
+        if let Some(path) = &instruments.flamegraph {
+            fs::write(path, profile.collapsed(interp.ops()))
+                .map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+        }
+        if let Some(model) = &instruments.cycles {
+            eprintln!("{} cycles", profile.cycles(interp.ops(), model));
+        }
+        if let Some(path) = &instruments.profile_record {
+            fs::write(path, profile_json(profile, interp.ops()).to_string())
+                .map_err(|e| Error::Failed(format!("{}: {}", path, e)))?;
+        }
+    }
+    if let Some(stats) = &mem_stats {
+        eprint!("{}", stats.report());
+    }
+    if let Some(dump) = &instruments.dump {
+        let failed = |path: &str, e: io::Error| Error::Failed(format!("{}: {}", path, e));
+        match &dump.file {
+            Some(path) => {
+                let mut out = File::create(path).map_err(|e| failed(path, e))?;
+                dump_tape(interp.tape(), dump.raw, &mut out).map_err(|e| failed(path, e))?;
+            }
+            None => dump_tape(interp.tape(), dump.raw, &mut io::stderr().lock())
+                .map_err(|e| failed("<stderr>", e))?,
+        }
+    }
+    if instruments.report {
+        eprintln!(
+            "{}",
+            sandbox_report(&result, interp.steps(), interp.bytes_written())
+        );
+    }
+    if let Some(metrics) = &instruments.metrics {
+        // Without a limit, only the profile counts the steps.
+        let steps = interp.profile().map_or(interp.steps(), Profile::total);
+        let mut report = outcome(&result, steps, interp.bytes_written());
+        let ms = |time: Duration| Json::Number(time.as_micros() as f64 / 1000.0);
+        report.push(("parse_ms", ms(metrics.parse)));
+        report.push(("optimize_ms", ms(metrics.optimize)));
+        report.push(("run_ms", ms(run_time)));
+        let kinds = interp
+            .profile()
+            .map_or_else(Vec::new, |p| p.kinds(interp.ops()));
+        report.push((
+            "ops",
+            Json::object(kinds.into_iter().map(|(kind, n)| (kind, n.into()))),
+        ));
+        report.push((
+            "peak_memory_bytes",
+            peak_memory().map_or(Json::Null, Json::from),
+        ));
+        eprintln!("{}", Json::object(report));
+    }
+    result.map_err(|e| {
+        Error::Failed(match e.get_ref() {
+            Some(stop)
+                if stop.is::<StepLimitExceeded>()
+                    || stop.is::<TimeLimitExceeded>()
+                    || stop.is::<TapeLimitExceeded>()
+                    || stop.is::<OutputLimitExceeded>()
+                    || stop.is::<LoopDepthExceeded>()
+                    || stop.is::<Interrupted>()
+                    || stop.is::<CoreDumped>() =>
+            {
+                stop.to_string()
+            }
+            Some(stop) if let Some(oob) = stop.downcast_ref::<OutOfBounds>() => run.source.locate(
+                oob.pc,
+                oob,
+                "the pointer left the tape",
+                "this touched a cell outside the tape",
+            ),
+            Some(stop) if let Some(call) = stop.downcast_ref::<UndefinedProcedure>() => {
+                run.source.locate(
+                    call.pc,
+                    call,
+                    &format!("procedure {} isn't defined", call.id),
+                    "called here",
+                )
+            }
+            _ => format!("I/O error: {}", e),
+        })
+    })
+}
+
+/// What `--sandbox` prints about a run that ended with `result` after
+/// `steps` ops and `output` bytes: whether it finished, ran into a limit
+/// (which leaving the tape counts as) or failed otherwise.
+pub(super) fn sandbox_report(result: &io::Result<()>, steps: u64, output: u64) -> Json {
+    Json::object(outcome(result, steps, output))
+}
+
+/// The members of a [`sandbox_report`], for reports that add to them.
+fn outcome(result: &io::Result<()>, steps: u64, output: u64) -> Vec<(&'static str, Json)> {
+    let (status, limit) = match result {
+        Ok(()) => ("finished", None),
+        Err(e) => match e.get_ref() {
+            Some(stop) if stop.is::<StepLimitExceeded>() => ("limit", Some("steps")),
+            Some(stop) if stop.is::<TimeLimitExceeded>() => ("limit", Some("time")),
+            Some(stop) if stop.is::<OutputLimitExceeded>() => ("limit", Some("output")),
+            Some(stop) if stop.is::<TapeLimitExceeded>() || stop.is::<OutOfBounds>() => {
+                ("limit", Some("tape"))
+            }
+            Some(stop) if stop.is::<LoopDepthExceeded>() => ("limit", Some("loops")),
+            _ => ("error", None),
+        },
+    };
+    let mut report = vec![("status", Json::from(status))];
+    if let Some(limit) = limit {
+        report.push(("limit", limit.into()));
+    }
+    if let Err(e) = result {
+        report.push(("message", e.to_string().into()));
+    }
+    report.push(("steps", steps.into()));
+    report.push(("output_bytes", output.into()));
+    report
+}
+
+/// The most memory the process has had resident, if the OS says.
+fn peak_memory() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        // SAFETY: `getrusage` only writes to the struct it is given.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            return None;
+        }
+        // macOS counts bytes, everyone else KiB.
+        let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+        u64::try_from(usage.ru_maxrss).ok().map(|n| n * unit)
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// `--profile-record`'s file: the name of each op, how often it ran, and
+/// the cells its scans moved over.
+fn profile_json(profile: &Profile, ops: &[Op]) -> Json {
+    let numbers = |values: &[u64]| Json::from(values.iter().map(|&n| n.into()).collect::<Vec<_>>());
+    Json::object([
+        (
+            "ops",
+            Json::from(ops.iter().map(|op| op_name(op).into()).collect::<Vec<_>>()),
+        ),
+        ("counts", numbers(&profile.counts)),
+        ("scanned", numbers(&profile.scanned)),
+    ])
+}
+
+/// Reads a [`profile_json`] file, which must be of `ops`.
+fn read_profile(text: &str, ops: &[Op]) -> std::result::Result<Profile, &'static str> {
+    let json = Json::parse(text).ok_or("not a profile")?;
+    let numbers = |key| -> Option<Vec<u64>> {
+        json.get(key)?
+            .as_array()?
+            .iter()
+            .map(Json::as_u64)
+            .collect()
+    };
+    let names = json.get("ops").and_then(Json::as_array);
+    let (Some(names), Some(counts), Some(scanned)) = (names, numbers("counts"), numbers("scanned"))
+    else {
+        return Err("not a profile");
+    };
+    let same = names.len() == ops.len()
+        && names
+            .iter()
+            .zip(ops)
+            .all(|(name, op)| name.as_str() == Some(op_name(op)));
+    if !same || counts.len() != ops.len() || scanned.len() != ops.len() {
+        return Err("the profile is of another program, or of other settings");
+    }
+    Ok(Profile { counts, scanned })
+}
+
+/// Runs `interp` to the end with `engine`.
+fn run_engine<R: Read + Send, W: Write + Send, C: Cell>(
+    interp: &mut Interpreter<R, W, C>,
+    engine: Engine,
+) -> io::Result<()> {
+    match engine {
+        // SAFETY: checked just above. Any other program runs checked.
+        Engine::Unchecked if interp.unchecked_is_safe() => {
+            unsafe { interp.run_unchecked() }.map_err(io::Error::from)
+        }
+        _ => engine
+            .executor()
+            .run_interpreter(interp)
+            .map_err(io::Error::from),
+    }
+}
+
+/// Runs `ops` `runs` times with `engine` on `input`, discarding the output,
+/// and prints how long each run took. The ops each run executes are
+/// counted once up front by the interpreter, under a step limit so it
+/// counts them at all.
+fn run_bench<C: Cell>(
+    ops: &[Op],
+    input: &[u8],
+    config: &Config,
+    engine: Engine,
+    hot: Option<&Profile>,
+    runs: usize,
+) -> Result<()> {
+    let failed = |e: io::Error| Error::Failed(e.to_string());
+    let counting = Config {
+        max_steps: Some(config.max_steps.unwrap_or(u64::MAX)),
+        ..config.clone()
+    };
+    let mut counter =
+        Interpreter::<_, _, C>::with_config(ops.to_vec(), input, io::sink(), &counting);
+    counter.run().map_err(|e| failed(e.into()))?;
+    let steps = counter.steps();
+    let mut out = io::stdout().lock();
+    let mut times = Vec::with_capacity(runs);
+    for run in 1..=runs {
+        let mut interp =
+            Interpreter::<_, _, C>::with_config(ops.to_vec(), input, io::sink(), config);
+        let start = Instant::now();
+        #[cfg(all(unix, target_arch = "x86_64"))]
+        if let Some(profile) = hot {
+            interp.compile_hot_loops(profile);
+        }
+        run_engine(&mut interp, engine).map_err(failed)?;
+        let time = start.elapsed();
+        writeln!(out, "run {}: {}", run, bench_line(time, steps)).map_err(failed)?;
+        times.push(time);
+    }
+    times.sort();
+    writeln!(out, "min: {}", bench_line(times[0], steps)).map_err(failed)?;
+    writeln!(out, "median: {}", bench_line(times[times.len() / 2], steps)).map_err(failed)
+}
+
+/// A line of `--bench`'s report, for a run of `steps` ops taking `time`.
+fn bench_line(time: Duration, steps: u64) -> String {
+    let secs = time.as_secs_f64();
+    let rate = if secs > 0.0 {
+        format!("{:.0}", steps as f64 / secs)
+    } else {
+        "-".to_string()
+    };
+    format!("{:.3} ms, {} ops, {} ops/s", secs * 1000.0, steps, rate)
+}
+
+/// Runs to the end like [`Interpreter::run`], keeping the last ops in a
+/// [`RingTracer`]. If the run fails, including by leaving the tape, saves
+/// a core dump to `path` and fails with [`CoreDumped`].
+fn run_recorded<R: Read, W: Write, C: Cell>(
+    interp: &mut Interpreter<R, W, C>,
+    config: &Config,
+    path: &str,
+) -> io::Result<()> {
+    let mut tracer = RingTracer::new(CORE_TRACE);
+    // Leaving an unchecked tape panics; the core dump reports it instead.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        interp.run_traced(&mut tracer, 0..=u64::MAX)
+    }));
+    panic::set_hook(hook);
+
+    let recent: Vec<Entry> = tracer.entries().copied().collect();
+    // A run that stops at a limit or a checked bounds error is at an op
+    // boundary, where the interpreter's own state is exact; otherwise it
+    // stopped inside the last traced op.
+    let at_last = |interp: &Interpreter<R, W, C>| {
+        recent.last().map_or_else(
+            || interp.snapshot(),
+            |last| State::new(last.pc, last.ptr, last.step, interp.tape().to_vec()),
+        )
+    };
+    let (reason, state) = match result {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(RuntimeError::Io(e))) => (format!("I/O error: {}", e), at_last(interp)),
+        Ok(Err(stop)) => (stop.to_string(), interp.snapshot()),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<String>()
+                .map(String::as_str)
+                .or_else(|| payload.downcast_ref::<&str>().copied())
+                .unwrap_or("panic");
+            let pc = recent.last().map_or(interp.pc(), |last| last.pc);
+            (
+                format!("op {} left the tape ({})", pc, message),
+                at_last(interp),
+            )
+        }
+    };
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+    let mut out = io::BufWriter::new(File::create(path).map_err(with_path)?);
+    save_core(&reason, &recent, interp.ops(), config, &state, &mut out).map_err(with_path)?;
+    out.flush().map_err(with_path)?;
+    Err(io::Error::other(CoreDumped {
+        reason,
+        path: path.to_string(),
+    }))
+}
+
+/// Makes SIGINT set [`INTERRUPTED`] and stop the run at the next slice, so
+/// the tape can still be dumped. A second one, say while the program waits
+/// for input, kills the process as usual.
+#[cfg(unix)]
+fn catch_interrupt() {
+    extern "C" fn handle(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: the handler only stores to an atomic, which is
+    // async-signal-safe, and `action` is fully initialized before use.
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESETHAND | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_interrupt() {}
+
+/// The terminal's settings before [`RawTty`] changed them.
+#[cfg(unix)]
+static TTY_SETTINGS: std::sync::OnceLock<libc::termios> = std::sync::OnceLock::new();
+
+/// Stdin's terminal without line buffering or echo for `--raw-tty`, so
+/// `,` gets each key as it's pressed, until dropped, which also happens
+/// when a panic unwinds past it. Ctrl-C still interrupts the program, and
+/// puts the terminal back first.
+struct RawTty;
+
+#[cfg(unix)]
+impl RawTty {
+    /// Switches the terminal over, or does nothing if stdin isn't one.
+    fn enable() -> Result<Option<RawTty>> {
+        extern "C" fn restore_and_die(signal: libc::c_int) {
+            // SAFETY: tcsetattr, signal and raise are async-signal-safe,
+            // and the settings were stored before the handler was set.
+            unsafe {
+                if let Some(original) = TTY_SETTINGS.get() {
+                    libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+                }
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
+        let failed = |e: io::Error| Error::Failed(format!("--raw-tty: {}", e));
+        // SAFETY: isatty only inspects the descriptor.
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            return Ok(None);
+        }
+        // SAFETY: `original` is filled in by tcgetattr before it is used.
+        let original = unsafe {
+            let mut original = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(failed(io::Error::last_os_error()));
+            }
+            original
+        };
+        let original = *TTY_SETTINGS.get_or_init(|| original);
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a termios read from the same terminal, and the
+        // handler is a plain function.
+        unsafe {
+            for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM] {
+                libc::signal(
+                    signal,
+                    restore_and_die as extern "C" fn(libc::c_int) as libc::sighandler_t,
+                );
+            }
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(failed(io::Error::last_os_error()));
+            }
+        }
+        Ok(Some(RawTty))
+    }
+}
+
+#[cfg(not(unix))]
+impl RawTty {
+    fn enable() -> Result<Option<RawTty>> {
+        Err(usage_error("--raw-tty needs a Unix terminal"))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawTty {
+    fn drop(&mut self) {
+        if let Some(original) = TTY_SETTINGS.get() {
+            // SAFETY: `original` is the terminal's own earlier settings.
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+/// Runs to the end like [`Interpreter::run`], a slice at a time, stopping
+/// early on Ctrl-C once [`catch_interrupt`] is set up. With `checkpoint`,
+/// saves one every so often and once the run stops, unless it failed with
+/// an I/O error that may have left the machine mid-op.
+fn run_sliced<R: Read, W: Write, C: Cell>(
+    interp: &mut Interpreter<R, W, C>,
+    config: &Config,
+    checkpoint: Option<&CheckpointSettings>,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut saved = start;
+    let result = loop {
+        match interp.run_for(SLICE) {
+            Ok(true) => break Ok(()),
+            Ok(false) => {}
+            Err(e @ RuntimeError::StepLimitExceeded(_)) => break Err(e.into()),
+            Err(e) => return Err(e.into()),
+        }
+        if let Some(limit) = config.timeout
+            && start.elapsed() >= limit
+        {
+            break Err(io::Error::other(TimeLimitExceeded { limit }));
+        }
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            break Err(io::Error::other(Interrupted));
+        }
+        if let Some(checkpoint) = checkpoint
+            && saved.elapsed() >= checkpoint.every
+        {
+            save(interp, config, &checkpoint.path)?;
+            saved = Instant::now();
+        }
+    };
+    if let Some(checkpoint) = checkpoint {
+        save(interp, config, &checkpoint.path)?;
+    }
+    result
+}
+
+/// Writes a checkpoint next to `path` and then moves it into place, so an
+/// interruption never leaves a half-written one behind.
+fn save<R: Read, W: Write, C: Cell>(
+    interp: &Interpreter<R, W, C>,
+    config: &Config,
+    path: &str,
+) -> io::Result<()> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+    let temp = format!("{}.tmp", path);
+    let mut out = io::BufWriter::new(File::create(&temp).map_err(with_path)?);
+    save_checkpoint(interp.ops(), config, &interp.snapshot(), &mut out).map_err(with_path)?;
+    out.into_inner()
+        .map_err(|e| with_path(e.into_error()))?
+        .sync_all()
+        .map_err(with_path)?;
+    fs::rename(&temp, path).map_err(with_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(args: &[&str]) -> Result<()> {
+        main("bfi", args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_watch_needs_file() {
+        assert_eq!(
+            run(&["--watch", "-e", "+"]),
+            Err(usage_error("--watch needs a source file"))
+        );
+    }
+
+    #[test]
+    fn test_inline_code() {
+        assert_eq!(
+            run(&["--max-steps", "100", "-e", "+[]"]),
+            Err(Error::Failed("step limit of 100 exceeded".to_string()))
+        );
+        assert_eq!(
+            run(&["--timeout", "0.25", "-e", "+[]"]),
+            Err(Error::Failed("time limit of 250ms exceeded".to_string()))
+        );
+        assert!(matches!(
+            run(&["--timeout", "0", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(run(&["-e", "+", "x.bf"]), Err(Error::Usage(_))));
+        assert!(matches!(
+            run(&["--compile", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_input_str() {
+        assert_eq!(
+            run(&["--input-str", "ab", "--eof", "0", "-e", ",[,]"]),
+            Ok(())
+        );
+        assert!(matches!(
+            run(&["--input-str", "a", "--input", "in.txt", "-e", ","]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_bang_input() {
+        assert_eq!(
+            run(&["--bang-input", "--eof", "0", "-e", ",[.,]!echo"]),
+            Ok(())
+        );
+        assert!(matches!(
+            run(&["--bang-input", "--input-str", "x", "-e", ",!y"]),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            run(&["--bang-input", "--dialect", "extended1", "-e", ",!y"]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_newline() {
+        assert_eq!(
+            run(&["--newline", "cr", "--input-str", "a\n", "-e", ",.,."]),
+            Ok(())
+        );
+        assert!(matches!(
+            run(&["--input-newline", "nl", "-e", ","]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(
+            run(&[
+                "--unicode",
+                "--cell-size",
+                "32",
+                "--input-str",
+                "é",
+                "-e",
+                ",."
+            ]),
+            Ok(())
+        );
+        assert!(matches!(
+            run(&["--unicode", "-e", ",."]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_raw_tty() {
+        // Without a terminal to read, there's nothing to switch.
+        assert_eq!(run(&["--raw-tty", "--input-str", "a", "-e", ",."]), Ok(()));
+        assert!(matches!(
+            run(&["--raw-tty", "--debug", "-e", ","]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_record_input() {
+        let dir = std::env::temp_dir().join(format!("bfi-record-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let record = dir.join("input");
+        let record = record.to_str().unwrap();
+        // Only what the program reads is saved.
+        assert_eq!(
+            run(&["--input-str", "abc", "--record-input", record, "-e", ",>,"]),
+            Ok(())
+        );
+        assert_eq!(fs::read(record).unwrap(), b"ab");
+        assert_eq!(
+            run(&["--replay-input", record, "--eof", "0", "-e", ",[,]"]),
+            Ok(())
+        );
+        assert!(matches!(
+            run(&["--replay-input", record, "--input-str", "x", "-e", ","]),
+            Err(Error::Usage(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let result = run(&["--color", "never", "-e", "+\n <."]);
+        assert_eq!(
+            result,
+            Err(Error::Failed(
+                "the pointer left the tape\n --> -e:2:3\n  |\n2 |  <.\n  |   ^ this touched a cell outside the tape"
+                    .to_string()
+            ))
+        );
+        let result = run(&["--color", "never", "-e", "+["]);
+        assert!(
+            matches!(result, Err(Error::Failed(msg)) if msg.ends_with("^ unmatched '[' opened here"))
+        );
+    }
+
+    #[test]
+    fn test_unchecked() {
+        // The cells of `+[>+]` can't be worked out, so it runs checked.
+        for engine in [&["--unchecked"][..], &["--engine", "unchecked"]] {
+            let mut args = engine.to_vec();
+            args.extend(["--color", "never", "--tape-size", "10", "-e", "+[>+]"]);
+            assert!(matches!(
+                run(&args),
+                Err(Error::Failed(msg)) if msg.starts_with("the pointer left the tape")
+            ));
+        }
+        assert_eq!(run(&["--unchecked", "-e", "++[->+<]>."]), Ok(()));
+    }
+
+    #[test]
+    fn test_output() {
+        let dir = std::env::temp_dir().join(format!("bfi-output-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("output");
+        let output = output.to_str().unwrap();
+        assert_eq!(
+            run(&["--output", output, "--input-str", "hi", "-e", ",.,."]),
+            Ok(())
+        );
+        assert_eq!(fs::read(output).unwrap(), b"hi");
+        // Also with --verify, and for a run that fails partway.
+        assert_eq!(run(&["--output", output, "--verify", "-e", "+++."]), Ok(()));
+        assert_eq!(fs::read(output).unwrap(), [3]);
+        assert!(run(&["--output", output, "-e", "++.<."]).is_err());
+        assert_eq!(fs::read(output).unwrap(), [2]);
+        assert!(matches!(
+            run(&["--output", output, "--emit-ir", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_several_files() {
+        let dir = std::env::temp_dir().join(format!("bfi-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, code: &str| {
+            let path = dir.join(name);
+            fs::write(&path, code).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let (a, b, c) = (
+            write("a.b", "#!tape 2\n+[\n"),
+            write("b.b", "#!eof -1\n-]>"),
+            write("c.b", ">+"),
+        );
+        let output = dir.join("output");
+        let output = output.to_str().unwrap();
+        assert_eq!(
+            run(&["--output", output, &a, &b, "-e", "+"]),
+            Err(usage_error("expected a single program"))
+        );
+        // The second file's pragma is left out, and the first's counts.
+        let d = write("d.b", ",.");
+        assert_eq!(
+            run(&["--output", output, "--input-str", "", &a, &b, &d]),
+            Ok(())
+        );
+        assert_eq!(fs::read(output).unwrap(), [0]);
+        assert_eq!(
+            run(&["--output", output, "--color", "never", &a, &b, &c]),
+            Err(Error::Failed(
+                "the pointer left the tape\n --> ".to_string()
+                    + &c
+                    + ":1:2\n  |\n1 | >+\n  |  ^ this touched a cell outside the tape"
+            ))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_input_random() {
+        assert_eq!(parse_random("42"), Some((42, None)));
+        assert_eq!(parse_random("42:8"), Some((42, Some(8))));
+        assert_eq!(parse_random("42:"), None);
+        assert_eq!(
+            run(&["--input-random", "1:64", "--eof", "0", "-e", ",[,]"]),
+            Ok(())
+        );
+        assert_eq!(
+            run(&["--input-random", "1", "--max-steps", "1000", "-e", ",[,]"]),
+            Err(Error::Failed("step limit of 1000 exceeded".to_string()))
+        );
+        assert!(matches!(
+            run(&["--input-random", "1", "--verify", "-e", ","]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_sandbox() {
+        assert_eq!(
+            run(&["--sandbox", "--max-steps", "100", "-e", "+[]"]),
+            Err(Error::Failed("step limit of 100 exceeded".to_string()))
+        );
+        assert_eq!(
+            run(&["--sandbox", "--max-output", "0", "-e", "+."]),
+            Err(Error::Failed(
+                "output limit of 0 bytes exceeded".to_string()
+            ))
+        );
+        // Without --input, ',' sees the end of input instead of waiting.
+        assert_eq!(run(&["--sandbox", "--eof", "0", "-e", ",[,]"]), Ok(()));
+        assert_eq!(
+            run(&["--sandbox", "--bounds", "grow", "-e", "+[>+]"]),
+            Err(Error::Failed(
+                "tape limit of 1048576 cells exceeded".to_string()
+            ))
+        );
+        assert!(matches!(
+            run(&["--sandbox", "--bounds", "unchecked", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            run(&["--sandbox", "--unchecked", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        // Nesting is limited while parsing, before anything runs.
+        let deep = "[".repeat(1001);
+        assert!(matches!(
+            run(&["--sandbox", "-e", &deep]),
+            Err(Error::Failed(msg)) if msg.contains("nested too deeply")
+        ));
+        // The timeout starts before parsing, which takes longer than this.
+        assert_eq!(
+            run(&["--sandbox", "--timeout", "1e-9", "-e", "+[>+<-]"]),
+            Err(Error::Failed("time limit of 1ns exceeded".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_sandbox_report() {
+        let limit = io::Error::other(StepLimitExceeded { limit: 5 });
+        assert_eq!(
+            sandbox_report(&Err(limit), 5, 2).to_string(),
+            r#"{"status":"limit","limit":"steps","message":"step limit of 5 exceeded","steps":5,"output_bytes":2}"#
+        );
+        assert_eq!(
+            sandbox_report(&Ok(()), 3, 0).to_string(),
+            r#"{"status":"finished","steps":3,"output_bytes":0}"#
+        );
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let source = b"+++[>++\n[->+<]<-]";
+        let (ops, spans) = parse_with_spans(source, &crate::ParseOptions::default()).unwrap();
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        interp.enable_profiling();
+        interp.run().unwrap();
+        let profile = interp.profile().unwrap();
+        let sources = Sources::single("a.bf", source.len());
+        let mut map = SourceMap {
+            sources: &sources,
+            source,
+            spans,
+            color: false,
+        };
+        assert_eq!(
+            map.hot_loops(profile, interp.ops()),
+            "\nhottest loops in the source:\n  \
+             loop at lines 1-2 of a.bf ran 3 iterations (97.4% of steps)\n    [>++ ...\n  \
+             loop at line 2, cols 1-6 of a.bf ran 6 iterations (55.3% of steps)\n    [->+<]\n"
+        );
+        map.spans.clear();
+        assert_eq!(map.hot_loops(profile, interp.ops()), "");
+    }
+
+    #[test]
+    fn test_metrics_json() {
+        assert_eq!(run(&["--metrics-json", "-e", "++[>+<-]"]), Ok(()));
+        assert!(matches!(
+            run(&["--metrics-json", "--bench", "2", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        #[cfg(unix)]
+        assert!(peak_memory().is_some_and(|bytes| bytes > 0));
+    }
+
+    #[test]
+    fn test_bench() {
+        assert_eq!(
+            run(&["--bench", "2", "--input-str", "", "-e", "++[>+++<-]>."]),
+            Ok(())
+        );
+        assert_eq!(
+            run(&[
+                "--bench",
+                "1",
+                "--input-str",
+                "",
+                "--max-steps",
+                "100",
+                "-e",
+                "+[]"
+            ]),
+            Err(Error::Failed("step limit of 100 exceeded".to_string()))
+        );
+        assert!(matches!(
+            run(&["--bench", "0", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            run(&["--bench", "2", "--verify", "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+        assert_eq!(
+            bench_line(Duration::from_millis(2), 1000),
+            "2.000 ms, 1000 ops, 500000 ops/s"
+        );
+    }
+
+    #[test]
+    fn test_profile_record_and_use() {
+        let path = std::env::temp_dir().join(format!("bfi-test-{}.prof", std::process::id()));
+        let path = path.to_str().unwrap();
+        let code = ",[>+++<-]>";
+        let args = |flag| ["--input-str", "a", flag, path, "-e", code];
+        assert_eq!(run(&args("--profile-record")), Ok(()));
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            r#"{"ops":["Input","MulAdd","BulkClear","PtrAdd"],"counts":[1,1,1,1],"scanned":[0,0,0,0]}"#
+        );
+        assert_eq!(run(&args("--profile-use")), Ok(()));
+        assert_eq!(
+            run(&["--profile-use", path, "-e", "+."]),
+            Err(Error::Failed(format!(
+                "{}: the profile is of another program, or of other settings",
+                path
+            )))
+        );
+        assert!(matches!(
+            run(&["--profile-use", path, "--engine", "packed", "-e", code]),
+            Err(Error::Usage(_))
+        ));
+        fs::write(path, "[]").unwrap();
+        assert_eq!(
+            run(&["--profile-use", path, "-e", code]),
+            Err(Error::Failed(format!("{}: not a profile", path)))
+        );
+        fs::remove_file(path).unwrap();
+
+        let ops = [Op::Jz(1), Op::Jnz(0)];
+        let profile = Profile {
+            counts: vec![2, 1000],
+            scanned: vec![0, 0],
+        };
+        let json = profile_json(&profile, &ops).to_string();
+        assert_eq!(read_profile(&json, &ops), Ok(profile));
+    }
+
+    #[test]
+    fn test_dump_tape() {
+        let mut out = Vec::new();
+        let tape: Vec<u8> = (1..=18).chain([0, 0]).collect();
+        dump_tape(&tape, false, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "00000000 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f 10\n00000010 11 12\n"
+        );
+        let mut out = Vec::new();
+        dump_tape(&[0x4142u16, 0, 1, 0], true, &mut out).unwrap();
+        assert_eq!(out, [0x42, 0x41, 0, 0, 1, 0]);
+
+        let path = std::env::temp_dir().join(format!("bfi-test-{}.dump", std::process::id()));
+        let path = path.to_str().unwrap();
+        let args = ["--dump-tape-file", path, "--dump-format", "raw"];
+        assert_eq!(run(&[&args[..], &["-e", "++>+++>"]].concat()), Ok(()));
+        assert_eq!(fs::read(path).unwrap(), [2, 3]);
+        assert_eq!(
+            run(&[&args[..], &["--max-steps", "5", "-O0", "-e", "+>+>+[]"]].concat()),
+            Err(Error::Failed("step limit of 5 exceeded".to_string()))
+        );
+        assert_eq!(fs::read(path).unwrap(), [1, 1, 1]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_core_dump() {
+        let path = std::env::temp_dir().join(format!("bfi-test-{}.core", std::process::id()));
+        let path = path.to_str().unwrap();
+        let failed = |reason: &str| {
+            Err(Error::Failed(format!(
+                "{}; core dumped to {}",
+                reason, path
+            )))
+        };
+        assert_eq!(
+            run(&["--core", path, "--max-steps", "50", "-e", "+[>+<]"]),
+            failed("step limit of 50 exceeded")
+        );
+        let core = crate::bytecode::load_core(&mut fs::read(path).unwrap().as_slice()).unwrap();
+        assert_eq!(core.recent.len(), CORE_TRACE);
+        assert_eq!(core.recent.last().map(|e| e.step), Some(49));
+        assert_eq!(core.checkpoint.state::<u8>().steps(), 50);
+
+        let result = run(&["--core", path, "--tape-size", "4", "-e", "+[>+]"]);
+        let Err(Error::Failed(message)) = result else {
+            panic!("leaving the tape fails the run");
+        };
+        assert!(
+            message.starts_with("op 2 touched a cell outside the tape;"),
+            "{}",
+            message
+        );
+        let core = crate::bytecode::load_core(&mut fs::read(path).unwrap().as_slice()).unwrap();
+        let state = core.checkpoint.state::<u8>();
+        assert_eq!((state.pc(), state.ptr()), (2, 3));
+        assert_eq!(state.tape(), [1, 1, 1, 1]);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(run(&["--core", path, "-e", "+."]), Ok(()));
+        assert!(fs::metadata(path).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_and_resume() {
+        let path = std::env::temp_dir().join(format!("bfi-test-{}.bfck", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(
+            run(&[
+                "-O0",
+                "--checkpoint",
+                path,
+                "--max-steps",
+                "10",
+                "-e",
+                "+++++[>+++<-]>[-]"
+            ]),
+            Err(Error::Failed("step limit of 10 exceeded".to_string()))
+        );
+        let checkpoint = load_checkpoint(&mut fs::read(path).unwrap().as_slice()).unwrap();
+        assert_eq!(checkpoint.state::<u8>().steps(), 10);
+
+        assert_eq!(run(&["--resume", path, "--checkpoint", path]), Ok(()));
+        let checkpoint = load_checkpoint(&mut fs::read(path).unwrap().as_slice()).unwrap();
+        let state = checkpoint.state::<u8>();
+        assert_eq!(state.pc(), checkpoint.ops.len());
+        assert_eq!(&state.tape()[..2], &[0, 0]);
+        fs::remove_file(path).unwrap();
+
+        assert!(matches!(
+            run(&["--resume", path, "-e", "+"]),
+            Err(Error::Usage(_))
+        ));
+    }
+}
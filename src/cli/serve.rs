@@ -0,0 +1,750 @@
+//! `bf serve`: runs programs sent over HTTP, the backend an online
+//! playground needs.
+//!
+//! Every program runs under [`ResourceLimits::sandbox`], on a thread of its
+//! own, and its output is sent as it's written rather than when it ends.
+//! `POST /run` takes the program as the body and its input from the query,
+//! and `GET /ws` opens a WebSocket that also streams input while the
+//! program runs. Either way, a JSON report like `bfi --sandbox` prints says
+//! how the run ended.
+//!
+//! HTTP, WebSocket framing and the SHA-1 the WebSocket handshake needs are
+//! implemented here, as far as this server uses them, since the crate has no
+//! HTTP dependency.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::json::Json;
+use super::run::sandbox_report;
+use super::{Error, Result, run_until, usage_error};
+use crate::{
+    Config, EofBehavior, FlushPolicy, Interpreter, Op, OptLevel, ParseError, ParseOptions,
+    Pipeline, ResourceLimits, TimeLimitExceeded, parse_with,
+};
+
+pub(super) const USAGE: &str =
+    "Usage: bf serve [OPTIONS]\nTry 'bf serve --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf serve [OPTIONS]
+
+Runs programs sent over HTTP under the limits of 'bfi --sandbox', streaming
+their output back:
+
+  POST /run?input=TEXT  The body is the program, and TEXT, percent-encoded,
+                        its input. The output comes back chunked, with the
+                        report of how the run ended in an X-Report trailer.
+  GET /ws               A WebSocket. The first message is the program, and
+                        later ones are its input; an empty message ends the
+                        input. The output comes back as binary messages, and
+                        the report as a last text message.
+
+Options:
+      --addr <ADDR>         Address to listen on [default: 127.0.0.1:8080]
+      --max-clients <N>     Programs to run at once [default: 64]
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --eof <E>             What ',' stores at end of input: unchanged, 0 or -1
+                            [default: unchanged]
+  -h, --help                Print this help";
+
+/// How long a `POST /run` program may take, parsing and optimizing it
+/// included. WebSocket programs wait on their client for input, so only
+/// the step limit bounds their run, but this still bounds optimizing them.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client may leave the server waiting to hear from it.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// The longest request head, program or message accepted.
+const MAX_HEAD: usize = 8 << 10;
+const MAX_BODY: usize = 1 << 20;
+
+/// Appended to a client's key for the handshake, from RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// How programs are run.
+#[derive(Debug, Clone, Copy)]
+struct Options {
+    level: OptLevel,
+    eof: EofBehavior,
+}
+
+impl Options {
+    /// Parses `source` and optimizes it, with the passes that fit before
+    /// `deadline`.
+    fn compile(
+        &self,
+        source: &[u8],
+        deadline: Instant,
+    ) -> std::result::Result<Vec<Op>, ParseError> {
+        let options = ParseOptions {
+            max_depth: ResourceLimits::sandbox().max_loop_depth,
+            ..ParseOptions::default()
+        };
+        let ops = parse_with(source, &options)?;
+        let spans = vec![0..0; ops.len()];
+        Ok(run_until(&Pipeline::for_level(self.level), ops, spans, deadline).0)
+    }
+
+    fn config(&self, timeout: Option<Duration>) -> Config {
+        Config {
+            eof: self.eof,
+            timeout,
+            flush: FlushPolicy::Newline,
+            ..Config::default()
+        }
+    }
+}
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut addr = "127.0.0.1:8080".to_string();
+    let mut max_clients = 64;
+    let mut options = Options {
+        level: OptLevel::default(),
+        eof: EofBehavior::default(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => {
+                addr = args
+                    .next()
+                    .ok_or_else(|| usage_error("--addr expects an address"))?;
+            }
+            "--max-clients" => {
+                max_clients = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| usage_error("--max-clients expects a positive number"))?;
+            }
+            "-O0" | "-O1" | "-O2" | "-O3" => {
+                options.level = OptLevel::from_name(&arg[1..]).unwrap_or_default();
+            }
+            "--eof" => {
+                options.eof = match args.next().as_deref() {
+                    Some("unchanged") => EofBehavior::Unchanged,
+                    Some("0") => EofBehavior::Zero,
+                    Some("-1") => EofBehavior::MinusOne,
+                    _ => return Err(usage_error("--eof expects 'unchanged', '0' or '-1'")),
+                };
+            }
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    let listener =
+        TcpListener::bind(&addr).map_err(|e| Error::Failed(format!("{}: {}", addr, e)))?;
+    if let Ok(addr) = listener.local_addr() {
+        eprintln!("bf serve: listening on http://{}", addr);
+    }
+    let clients = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        if clients.fetch_add(1, Ordering::SeqCst) >= max_clients {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            let _ = respond(
+                &mut stream,
+                "503 Service Unavailable",
+                "too many programs running\n",
+            );
+            continue;
+        }
+        let clients = Arc::clone(&clients);
+        thread::spawn(move || {
+            // A client that goes away is no concern of the others.
+            let _ = handle(stream, &options);
+            clients.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+/// Serves the one request a connection makes.
+fn handle(mut stream: TcpStream, options: &Options) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let Some(request) = read_request(&mut reader)? else {
+        return Ok(());
+    };
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/run") => {
+            let Some(len) = request
+                .header("content-length")
+                .and_then(|n| n.parse().ok())
+            else {
+                return respond(
+                    &mut stream,
+                    "411 Length Required",
+                    "expected a Content-Length\n",
+                );
+            };
+            if len > MAX_BODY {
+                return respond(&mut stream, "413 Content Too Large", "program too long\n");
+            }
+            let mut program = vec![0; len];
+            reader.read_exact(&mut program)?;
+            let input = request
+                .param("input")
+                .map(percent_decode)
+                .unwrap_or_default();
+            run_http(stream, &program, &input, options)
+        }
+        ("GET", "/ws") => match request.header("sec-websocket-key") {
+            Some(key) => run_websocket(stream, reader, key, options),
+            None => respond(
+                &mut stream,
+                "400 Bad Request",
+                "expected a WebSocket upgrade\n",
+            ),
+        },
+        (_, "/run" | "/ws") => respond(&mut stream, "405 Method Not Allowed", "wrong method\n"),
+        _ => respond(&mut stream, "404 Not Found", "no such endpoint\n"),
+    }
+}
+
+/// Runs `program` for `POST /run`, sending its output as chunks.
+fn run_http(
+    mut stream: TcpStream,
+    program: &[u8],
+    input: &[u8],
+    options: &Options,
+) -> io::Result<()> {
+    let started = Instant::now();
+    let ops = match options.compile(program, started + TIMEOUT) {
+        Ok(ops) => ops,
+        Err(e) => return respond(&mut stream, "400 Bad Request", &format!("{}\n", e)),
+    };
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: application/octet-stream\r\n\
+          Transfer-Encoding: chunked\r\n\
+          Trailer: X-Report\r\n\
+          Access-Control-Allow-Origin: *\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    let output = BufWriter::new(Chunked(stream));
+    // The run gets what is left of the time.
+    let left = TIMEOUT.saturating_sub(started.elapsed());
+    let config = options.config(Some(left));
+    let mut interp = Interpreter::<_, _, u8>::with_config(ops, input, output, &config);
+    interp.set_limits(ResourceLimits::sandbox());
+    let result = if left.is_zero() {
+        Err(io::Error::other(TimeLimitExceeded { limit: TIMEOUT }))
+    } else {
+        interp.run().map_err(io::Error::from)
+    };
+    let report = sandbox_report(&result, interp.steps(), interp.bytes_written());
+    let output = interp.io_mut().1;
+    output.flush()?;
+    write!(output.get_mut().0, "0\r\nX-Report: {}\r\n\r\n", report)
+}
+
+/// Runs the program a WebSocket client sends, streaming its input and
+/// output as messages.
+fn run_websocket(
+    mut stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    key: &str,
+    options: &Options,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    let mut input = WsInput {
+        reader,
+        writer: stream.try_clone()?,
+        buf: VecDeque::new(),
+        closed: false,
+    };
+    let Some(program) = input.next_message()? else {
+        return Ok(());
+    };
+    let report = match options.compile(&program, Instant::now() + TIMEOUT) {
+        Ok(ops) => {
+            let output = BufWriter::new(WsOutput(stream.try_clone()?));
+            let mut interp =
+                Interpreter::<_, _, u8>::with_config(ops, input, output, &options.config(None));
+            interp.set_limits(ResourceLimits::sandbox());
+            let result = interp.run().map_err(io::Error::from);
+            interp.io_mut().1.flush()?;
+            sandbox_report(&result, interp.steps(), interp.bytes_written())
+        }
+        Err(e) => Json::object([
+            ("status", "error".into()),
+            ("message", e.to_string().into()),
+        ]),
+    };
+    write_frame(&mut stream, TEXT, report.to_string().as_bytes())?;
+    write_frame(&mut stream, CLOSE, &[])
+}
+
+/// A request's first line and headers.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    /// With lowercase names.
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The raw value of `name` in the query string.
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|&(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Reads a request's head, or `None` if the client closed the connection
+/// without sending one.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut head = 0;
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let n = reader.read_line(&mut line)?;
+        head += n;
+        if head > MAX_HEAD {
+            return Err(invalid("request head too long"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if n == 0 && lines.is_empty() {
+            return Ok(None);
+        }
+        if n == 0 || line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+    let mut first = lines[0].split(' ');
+    let (Some(method), Some(target)) = (first.next(), first.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+    }))
+}
+
+/// Sends a complete plain-text response.
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Access-Control-Allow-Origin: *\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Decodes `%XX` escapes and `+` for a space. Malformed escapes are kept
+/// as they are.
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Writes each buffer as a chunk of a chunked response.
+struct Chunked(TcpStream);
+
+impl Write for Chunked {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Writes each buffer as a binary WebSocket message.
+struct WsOutput(TcpStream);
+
+impl Write for WsOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            write_frame(&mut self.0, BINARY, buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// The program's input from a WebSocket: the bytes of each message, until
+/// an empty one or the connection closes.
+struct WsInput {
+    reader: BufReader<TcpStream>,
+    /// For answering pings.
+    writer: TcpStream,
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+impl WsInput {
+    /// The next data message, or `None` once the client closes.
+    fn next_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = Vec::new();
+        loop {
+            let (fin, opcode, payload) = read_frame(&mut self.reader)?;
+            match opcode {
+                CONTINUATION | TEXT | BINARY => {
+                    if message.len() + payload.len() > MAX_BODY {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "message too long",
+                        ));
+                    }
+                    message.extend(payload);
+                    if fin {
+                        return Ok(Some(message));
+                    }
+                }
+                CLOSE => return Ok(None),
+                PING => write_frame(&mut self.writer, PONG, &payload)?,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Read for WsInput {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.buf.is_empty() && !self.closed {
+            match self.next_message()? {
+                Some(message) if !message.is_empty() => self.buf.extend(message),
+                _ => self.closed = true,
+            }
+        }
+        self.buf.read(out)
+    }
+}
+
+/// Reads a frame from a client, returning whether it ends its message,
+/// its opcode and its unmasked payload.
+fn read_frame(reader: &mut impl Read) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if len > MAX_BODY as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+    }
+    let mut mask = [0; 4];
+    if head[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((head[0] & 0x80 != 0, head[0] & 0x0f, payload))
+}
+
+/// Writes `payload` as a single unmasked frame, as a server sends them.
+fn write_frame(writer: &mut impl Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..126 => frame.push(len as u8),
+        len @ 126..65536 => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    writer.write_all(&frame)
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (w, word) in w.iter_mut().zip(block.chunks(4)) {
+            *w = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &w) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..20 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..40 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..60 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(w);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+    let mut digest = [0; 20];
+    for (out, h) in digest.chunks_mut(4).zip(h) {
+        out.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves one connection on a free port and returns a client for it.
+    fn connect() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let options = Options {
+                level: OptLevel::O2,
+                eof: EofBehavior::Zero,
+            };
+            handle(stream, &options).unwrap();
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // The example from RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(percent_decode("a%2Cb+c%"), b"a,b c%");
+    }
+
+    #[test]
+    fn test_compile_deadline() {
+        let options = Options {
+            level: OptLevel::O2,
+            eof: EofBehavior::Zero,
+        };
+        let later = Instant::now() + TIMEOUT;
+        assert_eq!(
+            options.compile(b"+[-]", later),
+            Ok(vec![Op::BulkClear(vec![0])])
+        );
+        // Out of time, the program is left as parsed.
+        assert_eq!(
+            options.compile(b"+[-]", Instant::now()),
+            Ok(vec![
+                Op::ValAdd(0, 1),
+                Op::Jz(3),
+                Op::ValSub(0, 1),
+                Op::Jnz(1)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_serve_http() {
+        let mut client = connect();
+        let program = b",[.,]";
+        write!(
+            client,
+            "POST /run?input=hi%21 HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            program.len()
+        )
+        .unwrap();
+        client.write_all(program).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        // Output is flushed before each ',', so it comes a byte a chunk.
+        let mut body = response.split_once("\r\n\r\n").unwrap().1;
+        let mut output = String::new();
+        loop {
+            let (len, rest) = body.split_once("\r\n").unwrap();
+            let len = usize::from_str_radix(len, 16).unwrap();
+            if len == 0 {
+                body = rest;
+                break;
+            }
+            output.push_str(&rest[..len]);
+            body = &rest[len + 2..];
+        }
+        assert_eq!(output, "hi!");
+        assert!(body.starts_with("X-Report: {\"status\":\"finished\""));
+
+        let mut client = connect();
+        client
+            .write_all(b"POST /run HTTP/1.1\r\nContent-Length: 1\r\n\r\n[")
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_serve_websocket() {
+        let mut client = connect();
+        client
+            .write_all(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(client.try_clone().unwrap());
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "HTTP/1.1 101 Switching Protocols\r\n");
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+
+        // Echoes each line of input as it comes, until it ends.
+        let send = |client: &mut TcpStream, payload: &[u8]| {
+            let mask = [1, 2, 3, 4];
+            let mut frame = vec![0x80 | BINARY, 0x80 | payload.len() as u8];
+            frame.extend(mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            client.write_all(&frame).unwrap();
+        };
+        send(&mut client, b",[.,]");
+        send(&mut client, b"ab");
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            (true, BINARY, b"a".to_vec())
+        );
+        send(&mut client, b"c");
+        send(&mut client, b"");
+        let mut output = Vec::new();
+        let report = loop {
+            match read_frame(&mut reader).unwrap() {
+                (_, BINARY, payload) => output.extend(payload),
+                (_, TEXT, report) => break report,
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        };
+        assert_eq!(output, b"bc");
+        assert!(report.starts_with(b"{\"status\":\"finished\""));
+        assert_eq!(read_frame(&mut reader).unwrap().1, CLOSE);
+    }
+}
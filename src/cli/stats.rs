@@ -0,0 +1,202 @@
+//! `bf stats`: summarizes what a program is made of and what the optimizer
+//! makes of it.
+
+use std::io::{self, Write};
+use std::mem::{size_of, size_of_val};
+
+use super::{Error, Result, Settings, opt_stats_report, pass_help, read_source, usage_error};
+use crate::optimizer::PassStats;
+use crate::{Op, ParseError, ParseOptions, TokenKind, parse_with, tokenize};
+
+pub(super) const USAGE: &str =
+    "Usage: bf stats [OPTIONS] [input.bf]\nTry 'bf stats --help' for more information.";
+
+const HELP: &str = "\
+Usage: bf stats [OPTIONS] [input.bf]
+
+Reports on a program, read from stdin without a file: how often each
+command appears, how deeply its loops nest, the ops it parses to, what
+each optimization pass did to them, how many loops became a Clear, MulAdds
+or a scan, and roughly how much memory the optimized ops take.
+
+Options:
+  -O0, -O1, -O2, -O3        Optimization level [default: -O2]
+      --passes <LIST>       Run these passes, comma-separated, instead of the
+                            level's; 'help' lists them
+      --disable-pass <LIST> Leave these passes out
+      --strict              Only optimize in ways that keep a run that leaves
+                            the tape trapping where it would unoptimized
+      --debug-char          Keep '#' as a command
+      --dialect <NAME>      Also parse the commands of pbrain, brainfork,
+                            extended1 or multitape [default: standard]
+      --ext <LIST>          Also parse these extensions, comma-separated: random,
+                            for '?', which stores a random byte
+      --map <FILE>          Read the commands with the tokens listed in FILE
+  -h, --help                Print this help";
+
+pub(super) fn main(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut settings = Settings::default();
+    let mut source = None;
+    while let Some(arg) = args.next() {
+        if settings.parse(&arg, &mut args)? {
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", HELP);
+                return Ok(());
+            }
+            _ if source.is_none() && !arg.starts_with('-') => source = Some(arg),
+            _ => return Err(usage_error(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    if settings.list_passes {
+        println!("{}", pass_help());
+        return Ok(());
+    }
+    let (name, raw) = read_source(source.as_deref())?;
+    let report = report(&raw, &settings).map_err(|e| Error::Failed(format!("{}: {}", name, e)))?;
+    io::stdout()
+        .lock()
+        .write_all(report.as_bytes())
+        .map_err(|e| Error::Failed(e.to_string()))
+}
+
+/// The report on `source`, parsed and optimized with `settings`.
+fn report(source: &[u8], settings: &Settings) -> std::result::Result<String, ParseError> {
+    let ops = parse_with(source, &settings.parse)?;
+    let parsed = ops.len();
+    let depth = max_depth(&ops);
+    let spans = vec![0..0; ops.len()];
+    let (ops, _, stats) = settings.pipeline().run_with_stats(ops, spans);
+
+    let mut report = String::new();
+    let counts = command_counts(source, &settings.parse);
+    let total: usize = counts.iter().map(|&(_, n)| n).sum();
+    report.push_str(&format!("commands  {:>9}\n", total));
+    for (command, n) in counts {
+        report.push_str(&format!("  {}       {:>9}\n", command as char, n));
+    }
+    report.push_str(&format!("max depth {:>9}\n", depth));
+    report.push_str(&format!("parsed    {:>9} ops\n", parsed));
+    report.push_str(&format!(
+        "optimized {:>9} ops at -{:?}\n",
+        ops.len(),
+        settings.level
+    ));
+    let converted = converted_loops(&stats);
+    let total: usize = converted.iter().map(|&(_, n)| n).sum();
+    report.push_str(&format!("converted {:>9} loops\n", total));
+    for (kind, n) in converted {
+        report.push_str(&format!("  {:<8}{:>9}\n", kind, n));
+    }
+    report.push_str(&format!("size      {:>9} bytes of ops\n", ir_size(&ops)));
+    if !stats.is_empty() {
+        report.push('\n');
+        report.push_str(&opt_stats_report(&stats));
+    }
+    Ok(report)
+}
+
+/// How often each command appears in `source`, as `options` read it: the
+/// eight standard ones, then any others in the order they first appear.
+fn command_counts(source: &[u8], options: &ParseOptions) -> Vec<(u8, usize)> {
+    let mut counts = b"+-<>[].,".map(|command| (command, 0)).to_vec();
+    for token in tokenize(source, options) {
+        let TokenKind::Command(b) = token.kind else {
+            continue;
+        };
+        match counts.iter_mut().find(|(command, _)| *command == b) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((b, 1)),
+        }
+    }
+    counts
+}
+
+/// How deeply the loops in `ops` nest, 0 without any.
+fn max_depth(ops: &[Op]) -> usize {
+    let mut depth = 0usize;
+    let mut max = 0;
+    for op in ops {
+        match op {
+            Op::Jz(_) => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            Op::Jnz(_) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// The loops the `clear`, `move-loop` and `scan` passes took out, by the
+/// op they became.
+fn converted_loops(stats: &[PassStats]) -> [(&'static str, usize); 3] {
+    let taken = |name: &str| -> usize {
+        stats
+            .iter()
+            .filter(|pass| pass.name == name)
+            .map(|pass| pass.loops.0.saturating_sub(pass.loops.1))
+            .sum()
+    };
+    [
+        ("Clear", taken("clear")),
+        ("MulAdd", taken("move-loop")),
+        ("Scan", taken("scan")),
+    ]
+}
+
+/// Roughly how many bytes `ops` take in memory: each op, and the bytes
+/// and cells the ops that list them hold.
+fn ir_size(ops: &[Op]) -> usize {
+    let held: usize = ops
+        .iter()
+        .map(|op| match op {
+            Op::OutputConst(bytes) => bytes.len(),
+            Op::BulkAdd(adds) | Op::Transfer(adds) => adds.len() * size_of::<(isize, u32)>(),
+            Op::BulkClear(cells) => cells.len() * size_of::<isize>(),
+            _ => 0,
+        })
+        .sum();
+    size_of_val(ops) + held
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_command_counts() {
+        let options = ParseOptions::default();
+        let counts = command_counts(b"++[->+<]. comment", &options);
+        assert_eq!(counts[0], (b'+', 3));
+        assert_eq!(counts[1], (b'-', 1));
+        assert_eq!(counts[6], (b'.', 1));
+        assert_eq!(counts[7], (b',', 0));
+        assert_eq!(counts.len(), 8);
+
+        let options = ParseOptions {
+            debug_char: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(command_counts(b"+#", &options)[8], (b'#', 1));
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(max_depth(&parse(b"+[>[-]<[[.]]]").unwrap()), 3);
+        assert_eq!(max_depth(&parse(b"+.").unwrap()), 0);
+
+        let source = b",[-]>,[->+<]>,[<]>,[[-]]";
+        let report = report(source, &Settings::default()).unwrap();
+        assert!(report.contains("max depth         2\n"));
+        assert!(report.contains("converted         5 loops\n"));
+        assert!(report.contains("  Clear           3\n"));
+        assert!(report.contains("  MulAdd          1\n"));
+        assert!(report.contains("  Scan            1\n"));
+        assert!(report.contains("\npass "));
+    }
+}
@@ -0,0 +1,203 @@
+//! `bfi --verify`: runs the optimized program and the source under the
+//! [`reference`](crate::reference) interpreter on the same input, and
+//! reports the first place they disagree.
+
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+
+use super::run::Engine;
+use super::{Error, Result};
+use crate::{Cell, Config, Interpreter, Op, RuntimeError, reference};
+
+/// What a run left behind.
+#[derive(Debug)]
+struct Outcome<C> {
+    output: Vec<u8>,
+    tape: Vec<C>,
+    ptr: usize,
+    /// Why the run failed, if it did.
+    error: Option<String>,
+}
+
+/// Runs `optimized` with `engine` and `source` under the reference
+/// interpreter, both on `input`. Writes the optimized run's output to
+/// `output`, then fails if the runs differ, or like a normal run if both
+/// failed.
+pub(super) fn verify<C: Cell>(
+    optimized: Vec<Op>,
+    source: &[u8],
+    input: &[u8],
+    config: &Config,
+    engine: Engine,
+    output: &mut dyn Write,
+) -> Result<()> {
+    // `-O3` may evaluate the whole program into its output, dropping the
+    // tape it would have left.
+    let keeps_tape = !optimized.iter().all(|op| matches!(op, Op::OutputConst(_)));
+    // Leaving an unchecked tape panics; that is reported like any failure.
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let actual = run::<C>(optimized, input, config, engine);
+    panic::set_hook(hook);
+    let expected = run_reference::<C>(source, input, config);
+
+    output
+        .write_all(&actual.output)
+        .and_then(|()| output.flush())
+        .map_err(|e| Error::Failed(e.to_string()))?;
+    if let Some(difference) = compare(&actual, &expected, keeps_tape) {
+        return Err(Error::Failed(format!(
+            "the optimized and reference runs differ: {}",
+            difference
+        )));
+    }
+    match actual.error {
+        Some(error) => Err(Error::Failed(error)),
+        None => Ok(()),
+    }
+}
+
+fn run<C: Cell>(ops: Vec<Op>, input: &[u8], config: &Config, engine: Engine) -> Outcome<C> {
+    let mut interp = Interpreter::<_, _, C>::with_config(ops, input, Vec::new(), config);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        engine.executor().run_interpreter(&mut interp)
+    }));
+    let error = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("the program left the tape".to_string()),
+    };
+    Outcome {
+        tape: interp.tape().to_vec(),
+        ptr: interp.ptr(),
+        output: interp.into_inner().1,
+        error,
+    }
+}
+
+fn run_reference<C: Cell>(source: &[u8], input: &[u8], config: &Config) -> Outcome<C> {
+    let mut output = Vec::new();
+    match reference::run(source, config, input, &mut output) {
+        Ok((tape, ptr)) => Outcome {
+            output,
+            tape,
+            ptr,
+            error: None,
+        },
+        Err(e) => Outcome {
+            output,
+            tape: Vec::new(),
+            ptr: 0,
+            // The reference counts commands in the source, not ops.
+            error: Some(match e {
+                crate::Error::Runtime(RuntimeError::PointerOutOfBounds { pc, .. }) => format!(
+                    "the command at offset {} touched a cell outside the tape",
+                    pc
+                ),
+                e => e.to_string(),
+            }),
+        },
+    }
+}
+
+/// The first difference between what the optimized run did and what the
+/// reference run did, if any. Once both have failed, only their output so
+/// far is compared, and the final machine only with `tape`.
+fn compare<C: Cell>(actual: &Outcome<C>, expected: &Outcome<C>, tape: bool) -> Option<String> {
+    let both_failed = actual.error.is_some() && expected.error.is_some();
+    let at = actual
+        .output
+        .iter()
+        .zip(&expected.output)
+        .position(|(a, b)| a != b);
+    if let Some(i) = at {
+        return Some(format!(
+            "output byte {} is {} but should be {}",
+            i, actual.output[i], expected.output[i]
+        ));
+    }
+    // A failed run may have stopped at a different point.
+    if actual.output.len() != expected.output.len() && !both_failed {
+        return Some(format!(
+            "the output is {} bytes but should be {}",
+            actual.output.len(),
+            expected.output.len()
+        ));
+    }
+    match (&actual.error, &expected.error) {
+        (Some(error), None) => return Some(format!("only the optimized run failed: {}", error)),
+        (None, Some(error)) => return Some(format!("only the reference run failed: {}", error)),
+        (Some(_), Some(_)) => return None,
+        (None, None) if !tape => return None,
+        (None, None) => {}
+    }
+    if let Some(i) = (0..actual.tape.len().max(expected.tape.len()))
+        .find(|&i| actual.tape.get(i) != expected.tape.get(i))
+    {
+        let cell = |tape: &[C]| {
+            tape.get(i)
+                .map_or("missing".to_string(), |c| format!("{:?}", c))
+        };
+        return Some(format!(
+            "cell {} ends up {} but should be {}",
+            i,
+            cell(&actual.tape),
+            cell(&expected.tape)
+        ));
+    }
+    (actual.ptr != expected.ptr).then(|| {
+        format!(
+            "the pointer ends up at {} but should be at {}",
+            actual.ptr, expected.ptr
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OptLevel, optimize_with, parse};
+
+    fn outcome(code: &[u8], level: OptLevel) -> Outcome<u8> {
+        let ops = optimize_with(parse(code).unwrap(), level);
+        run(ops, b"", &Config::default(), Engine::Interpret)
+    }
+
+    fn expected(code: &[u8]) -> Outcome<u8> {
+        run_reference(code, b"", &Config::default())
+    }
+
+    #[test]
+    fn test_compare() {
+        let code = b"++[>+++<-]>.[-]<";
+        let reference = expected(code);
+        assert_eq!(reference.output, [6]);
+        assert_eq!(
+            compare(&outcome(code, OptLevel::O3), &reference, true),
+            None
+        );
+
+        let reference = expected(b"+++.>+");
+        assert_eq!(
+            compare(&outcome(b"++.>+", OptLevel::O2), &reference, true).as_deref(),
+            Some("output byte 0 is 2 but should be 3")
+        );
+        assert_eq!(
+            compare(&outcome(b"+++.>++", OptLevel::O2), &reference, true).as_deref(),
+            Some("cell 1 ends up 2 but should be 1")
+        );
+        let evaluated = outcome(b"+++.>++", OptLevel::O2);
+        assert_eq!(compare(&evaluated, &reference, false), None);
+        assert_eq!(
+            compare(
+                &outcome(b"+++.+", OptLevel::O2),
+                &expected(b"+++.+<+"),
+                true
+            )
+            .as_deref(),
+            Some(
+                "only the reference run failed: the command at offset 6 touched a cell outside the tape"
+            )
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! `bfi --visualize`: runs a program slowly enough to watch, drawing the
+//! tape, the current command and the output in the terminal.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::debug::Program;
+use super::{Error, Result};
+use crate::{Cell, Config, Interpreter, fmt_op, line_column};
+
+/// The speeds to choose from, in ops per second.
+const SPEEDS: [u32; 13] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 10_000, 100_000, 1_000_000,
+];
+
+/// Where in [`SPEEDS`] a run starts.
+const START_SPEED: usize = 3;
+
+/// Time between frames while the program runs.
+const FRAME: Duration = Duration::from_millis(33);
+
+/// Lines of source shown around the current command.
+const CONTEXT: usize = 2;
+
+const KEYS: &str = "space pause/run  s step  + faster  - slower  q quit";
+
+const REVERSE: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+/// A key that does something.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Pause,
+    Step,
+    Faster,
+    Slower,
+    Quit,
+}
+
+/// The program's output, shared between the interpreter and the screen.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `program` on screen until the user quits, reading its input from
+/// `input`, and then prints what it wrote.
+pub(super) fn run<C: Cell>(program: Program, input: Box<dyn Read>, config: &Config) -> Result<()> {
+    let failed = |e: io::Error| Error::Failed(format!("--visualize: {}", e));
+    let output = Output::default();
+    let mut interp =
+        Interpreter::<_, _, C>::with_config(program.ops, input, output.clone(), config);
+    interp.set_debug_output(io::stderr());
+    let terminal = Terminal::enable().map_err(failed)?;
+    let mut speed = START_SPEED;
+    let mut paused = false;
+    // Ops owed to the run since the last frame, at the current speed.
+    let mut owed = 0.0;
+    let mut last = Instant::now();
+    let mut error = None;
+    loop {
+        let done = interp.is_finished() || error.is_some();
+        let status = match &error {
+            Some(error) => format!("error: {}", error),
+            None if done => "finished".to_string(),
+            None if paused => "paused".to_string(),
+            None => format!("running at {} ops/s", SPEEDS[speed]),
+        };
+        let screen = Screen {
+            name: program.name,
+            source: program.source,
+            spans: &program.spans,
+            status: &status,
+            output: &output.0.borrow(),
+        };
+        let frame = screen.render(&interp, terminal.size());
+        terminal.draw(&frame).map_err(failed)?;
+
+        let wait = (!done && !paused).then_some(FRAME);
+        match read_key(wait).map_err(failed)? {
+            Some(Key::Quit) => break,
+            Some(Key::Pause) => paused = !paused,
+            Some(Key::Faster) => speed = (speed + 1).min(SPEEDS.len() - 1),
+            Some(Key::Slower) => speed = speed.saturating_sub(1),
+            Some(Key::Step) if !done => {
+                paused = true;
+                if let Err(e) = interp.step() {
+                    error = Some(e.to_string());
+                }
+            }
+            Some(Key::Step) | None => {}
+        }
+        let now = Instant::now();
+        if done || paused {
+            owed = 0.0;
+        } else {
+            // A frame that ran late doesn't make the next one catch up.
+            let elapsed = now.duration_since(last).min(FRAME * 4).as_secs_f64();
+            owed += f64::from(SPEEDS[speed]) * elapsed;
+            let ops = owed as u64;
+            owed -= ops as f64;
+            if let Err(e) = interp.run_for(ops) {
+                error = Some(e.to_string());
+            }
+        }
+        last = now;
+    }
+    drop(terminal);
+    io::stdout()
+        .lock()
+        .write_all(&output.0.borrow())
+        .map_err(|e| Error::Failed(e.to_string()))
+}
+
+/// What the screen shows besides the machine.
+struct Screen<'a> {
+    name: &'a str,
+    source: &'a [u8],
+    /// Where each op is in `source`; empty for bytecode.
+    spans: &'a [Range<usize>],
+    status: &'a str,
+    output: &'a [u8],
+}
+
+impl Screen<'_> {
+    /// The lines of a frame for a terminal of `columns` by `rows`.
+    fn render<R: Read, W: Write, C: Cell>(
+        &self,
+        interp: &Interpreter<R, W, C>,
+        (columns, rows): (usize, usize),
+    ) -> Vec<String> {
+        let pc = interp.pc();
+        let mut lines = vec![
+            format!("{}  step {}  {}", self.name, interp.steps(), self.status),
+            String::new(),
+        ];
+        lines.extend(tape(interp.tape(), interp.ptr(), columns));
+        lines.push(String::new());
+        match interp.ops().get(pc) {
+            Some(op) => lines.push(format!("op {}: {}", pc, fmt_op(op))),
+            None => lines.push("end of the program".to_string()),
+        }
+        if let Some(span) = self.spans.get(pc) {
+            lines.extend(source(self.source, span, columns));
+        }
+        lines.push(String::new());
+        lines.push("Output:".to_string());
+        // The output gets whatever room the rest leaves.
+        let room = rows.saturating_sub(lines.len() + 2).max(1);
+        let text = String::from_utf8_lossy(self.output);
+        let text: Vec<&str> = text.split('\n').collect();
+        for line in &text[text.len().saturating_sub(room)..] {
+            lines.push(printable(line).chars().take(columns).collect());
+        }
+        lines.push(String::new());
+        lines.push(KEYS.chars().take(columns).collect());
+        lines
+    }
+}
+
+/// The cells around `ptr`, as rows of indices, values and characters, with
+/// the pointer's cell highlighted, as many as fit in `columns`.
+fn tape<C: Cell>(tape: &[C], ptr: usize, columns: usize) -> [String; 4] {
+    let width = match size_of::<C>() {
+        1 => 3,
+        2 => 5,
+        _ => 10,
+    };
+    let count = (columns / (width + 1)).clamp(1, tape.len());
+    let start = ptr.saturating_sub(count / 2).min(tape.len() - count);
+    let mut rows = [const { String::new() }; 4];
+    for (cell, value) in tape.iter().enumerate().skip(start).take(count) {
+        let here = cell == ptr;
+        let shown = char::from_u32(value.to_u32())
+            .filter(|c| c.is_ascii_graphic())
+            .unwrap_or(' ');
+        let texts = [
+            format!("{:>width$}", cell),
+            format!("{:>width$}", value.to_u32()),
+            format!("{:>width$}", shown),
+            format!("{:>width$}", if here { "^" } else { "" }),
+        ];
+        for (row, text) in rows.iter_mut().zip(texts) {
+            if here {
+                row.push_str(&format!("{}{}{} ", REVERSE, text, RESET));
+            } else {
+                row.push_str(&text);
+                row.push(' ');
+            }
+        }
+    }
+    rows
+}
+
+/// The source lines around `span`, numbered, with `span` highlighted and
+/// scrolled into view if the lines are wider than `columns`.
+fn source(source: &[u8], span: &Range<usize>, columns: usize) -> Vec<String> {
+    let (line, column) = line_column(source, span.start);
+    let text: Vec<&[u8]> = source.split(|&b| b == b'\n').collect();
+    let room = columns.saturating_sub(7).max(1);
+    let scroll = (column - 1).saturating_sub(room / 2);
+    let mut lines = Vec::new();
+    let first = line.saturating_sub(CONTEXT + 1);
+    let last = (line + CONTEXT).min(text.len());
+    let mut start = text[..first]
+        .iter()
+        .map(|text| text.len() + 1)
+        .sum::<usize>();
+    for (i, text) in text.iter().enumerate().take(last).skip(first) {
+        let visible = |range: Range<usize>| {
+            let range = range.start.max(scroll)..range.end.min(scroll + room).max(scroll);
+            String::from_utf8_lossy(&text[range.start.min(text.len())..range.end.min(text.len())])
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .collect::<String>()
+        };
+        let mut shown = format!("{:5} | ", i + 1);
+        if i + 1 == line {
+            // The span may run on past this line; only this line's part is
+            // highlighted.
+            let from = span.start - start;
+            let to = (span.end - start).min(text.len());
+            shown.push_str(&visible(0..from));
+            shown.push_str(REVERSE);
+            shown.push_str(&visible(from..to));
+            shown.push_str(RESET);
+            shown.push_str(&visible(to..text.len()));
+        } else {
+            shown.push_str(&visible(0..text.len()));
+        }
+        lines.push(shown);
+        start += text.len() + 1;
+    }
+    lines
+}
+
+/// `text` with control characters, which would move the cursor, shown as
+/// `?`.
+fn printable(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() && c != '\t' { '?' } else { c })
+        .collect()
+}
+
+/// Waits up to `wait`, or without one until a key comes, for a key.
+fn read_key(wait: Option<Duration>) -> io::Result<Option<Key>> {
+    let mut poll = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout = wait.map_or(-1, |wait| wait.as_millis() as libc::c_int);
+    // SAFETY: `poll` is a valid pollfd and the count is 1.
+    match unsafe { libc::poll(&mut poll, 1, timeout) } {
+        0 => return Ok(None),
+        n if n < 0 => {
+            let e = io::Error::last_os_error();
+            // A signal interrupts the wait; the frame is just redrawn.
+            return if e.kind() == io::ErrorKind::Interrupted {
+                Ok(None)
+            } else {
+                Err(e)
+            };
+        }
+        _ => {}
+    }
+    // Read directly, since keys left in stdin's buffer wouldn't wake poll.
+    let mut buf = [0u8; 8];
+    // SAFETY: `buf` is valid for writes of its length.
+    let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+    let n = usize::try_from(n).map_err(|_| io::Error::last_os_error())?;
+    Ok(match &buf[..n] {
+        b" " | b"p" => Some(Key::Pause),
+        b"s" | b"n" | b"\r" | b"\x1b[C" => Some(Key::Step),
+        b"+" | b"=" | b"\x1b[A" => Some(Key::Faster),
+        b"-" | b"_" | b"\x1b[B" => Some(Key::Slower),
+        // Ctrl-C and Ctrl-D, which raw mode doesn't turn into signals.
+        b"q" | b"\x1b" | b"\x03" | b"\x04" | b"" => Some(Key::Quit),
+        _ => None,
+    })
+}
+
+/// The terminal in raw mode on the alternate screen, until dropped.
+struct Terminal {
+    original: libc::termios,
+}
+
+impl Terminal {
+    fn enable() -> io::Result<Terminal> {
+        // SAFETY: isatty only inspects the descriptor.
+        if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 {
+            return Err(io::Error::other(
+                "needs a terminal, so the input can't come from stdin",
+            ));
+        }
+        // SAFETY: `original` is filled in by tcgetattr before it is used.
+        let original = unsafe {
+            let mut original = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            original
+        };
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a termios read from the same terminal.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let terminal = Terminal { original };
+        // The alternate screen, without the cursor.
+        let mut out = io::stdout().lock();
+        out.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        out.flush()?;
+        Ok(terminal)
+    }
+
+    /// Columns and rows, or 80 by 24 if the terminal doesn't say.
+    fn size(&self) -> (usize, usize) {
+        // SAFETY: TIOCGWINSZ fills in `size`, which is plain data.
+        unsafe {
+            let mut size: libc::winsize = std::mem::zeroed();
+            if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0
+                && size.ws_col > 0
+                && size.ws_row > 0
+            {
+                (size.ws_col.into(), size.ws_row.into())
+            } else {
+                (80, 24)
+            }
+        }
+    }
+
+    /// Redraws the screen with `lines`.
+    fn draw(&self, lines: &[String]) -> io::Result<()> {
+        let mut frame = String::from("\x1b[H");
+        for line in lines {
+            frame.push_str(line);
+            frame.push_str("\x1b[K\r\n");
+        }
+        frame.push_str("\x1b[J");
+        let mut out = io::stdout().lock();
+        out.write_all(frame.as_bytes())?;
+        out.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut out = io::stdout().lock();
+        let _ = out.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = out.flush();
+        // SAFETY: `original` is the terminal's own earlier settings.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_with_spans;
+
+    #[test]
+    fn test_render() {
+        let source = b"++++++++[>++++++++<-]>+.\n,.";
+        let (ops, spans) = parse_with_spans(source, &Default::default()).unwrap();
+        let mut interp = Interpreter::<_, _, u8>::new(ops, io::empty(), Vec::new());
+        interp.run_for(29).unwrap();
+        let screen = Screen {
+            name: "a.b",
+            source,
+            spans: &spans,
+            status: "paused",
+            output: b"one\ntwo\n\x07three",
+        };
+        let lines = screen.render(&interp, (24, 16));
+        let lit = |text: &str| format!("{}{}{}", REVERSE, text, RESET);
+        assert_eq!(lines[0], "a.b  step 29  paused");
+        // Six 8-bit cells fit, starting from the left end of the tape.
+        assert_eq!(lines[2], format!("  0 {}   2   3   4   5 ", lit("  1")));
+        assert_eq!(lines[3], format!("  0 {}   0   0   0   0 ", lit(" 65")));
+        assert_eq!(lines[4], format!("    {}                 ", lit("  A")));
+        assert_eq!(lines[7], "op 8: Input");
+        // Long lines are cut to fit.
+        assert_eq!(lines[8], "    1 | ++++++++[>+++++++");
+        assert_eq!(lines[9], format!("    2 | {}.", lit(",")));
+        // The output fills what is left, from its end.
+        assert_eq!(lines[12..14], ["two", "?three"]);
+        assert_eq!(lines.len(), 16);
+    }
+}
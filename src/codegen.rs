@@ -0,0 +1,1530 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+mod backend;
+mod brainfuck;
+mod c;
+mod elf;
+mod llvm;
+
+pub use backend::{Backend, emit_ops, emit_program};
+pub use brainfuck::to_brainfuck;
+pub use c::emit_c;
+pub use elf::emit_elf;
+pub use llvm::emit_llvm_ir;
+
+use crate::cell::CellWidth;
+use crate::interpreter::{BoundsPolicy, DEFAULT_TAPE_SIZE, EofBehavior, tape_reach};
+use crate::{
+    BitOp, Op, ParseError, optimize, parse, uses_forks, uses_procedures, uses_random, uses_storage,
+    uses_tapes,
+};
+
+/// Settings for the generated program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodegenOptions {
+    /// Number of cells on the generated program's tape.
+    pub tape_size: usize,
+    /// What the generated program does when the pointer leaves the tape.
+    pub bounds: BoundsPolicy,
+    /// Integer type of the generated program's cells.
+    pub cell_width: CellWidth,
+    /// What `,` stores once stdin is exhausted.
+    pub eof: EofBehavior,
+    /// Reach cells with `get_unchecked_mut` instead of indexing, in the Rust
+    /// output only. See [`emit_rust`].
+    pub unsafe_indexing: bool,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            tape_size: DEFAULT_TAPE_SIZE,
+            bounds: BoundsPolicy::default(),
+            cell_width: CellWidth::default(),
+            eof: EofBehavior::default(),
+            unsafe_indexing: false,
+        }
+    }
+}
+
+/// Where each op of a program came from, for [`emit_rust_mapped`]: the
+/// source and, per op, the byte range of it, as
+/// [`optimize_with_spans`](crate::optimize_with_spans) returns them.
+#[derive(Debug, Clone, Copy)]
+pub struct SourceMap<'a> {
+    pub source: &'a [u8],
+    pub spans: &'a [Range<usize>],
+}
+
+/// Commands shown in a source-map comment before it is cut short.
+const MAPPED_COMMANDS: usize = 40;
+
+impl SourceMap<'_> {
+    /// The comment for the op at `pc`, such as `// bf: 120..126 [->+<]`,
+    /// unless it has no source or the op before it came from the same.
+    fn comment(&self, pc: usize) -> Option<String> {
+        let span = self.spans.get(pc)?;
+        if span.is_empty() || pc > 0 && self.spans.get(pc - 1) == Some(span) {
+            return None;
+        }
+        let commands: Vec<u8> = self.source[span.clone()]
+            .iter()
+            .copied()
+            .filter(|b| b"+-<>.,[]#".contains(b))
+            .collect();
+        let text = match commands.len() {
+            n if n > MAPPED_COMMANDS => format!(
+                "{}...",
+                String::from_utf8_lossy(&commands[..MAPPED_COMMANDS - 3])
+            ),
+            _ => String::from_utf8_lossy(&commands).into_owned(),
+        };
+        Some(format!("// bf: {}..{} {}", span.start, span.end, text))
+    }
+}
+
+/// Rust expression for the tape index `offset` cells from `ptr`.
+fn index(offset: isize, options: &CodegenOptions) -> String {
+    match options.bounds {
+        _ if offset == 0 => "ptr".to_string(),
+        BoundsPolicy::Clamp => format!(
+            "ptr.saturating_add_signed({}isize).min({})",
+            offset,
+            options.tape_size - 1
+        ),
+        BoundsPolicy::Wrap => {
+            let len = options.tape_size as isize;
+            format!("(ptr + {}) % {}", offset.rem_euclid(len), len)
+        }
+        _ => format!("ptr.wrapping_add_signed({}isize)", offset),
+    }
+}
+
+/// Rust place for the cell at the tape index `idx`, which is read and
+/// written through the `cell!` macro [`emit_cell_macro`] defines under
+/// [`CodegenOptions::unsafe_indexing`].
+fn cell_at(idx: &str, options: &CodegenOptions) -> String {
+    if options.unsafe_indexing {
+        format!("cell!({})", idx)
+    } else {
+        format!("tape[{}]", idx)
+    }
+}
+
+/// Writes `cell!`, which reaches a cell of `tape` without a bounds check,
+/// for a function whose tape may be indexed unchecked.
+fn emit_cell_macro<W: Write + ?Sized>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    if !options.unsafe_indexing {
+        return Ok(());
+    }
+    writeln!(out, "    #[allow(unused_macros)]")?;
+    writeln!(out, "    macro_rules! cell {{")?;
+    writeln!(out, "        ($idx:expr) => {{")?;
+    writeln!(
+        out,
+        "            *unsafe {{ tape.get_unchecked_mut($idx) }}"
+    )?;
+    writeln!(out, "        }};")?;
+    writeln!(out, "    }}")
+}
+
+/// Writes the check op `pc` makes of the cell `offset` from `ptr`, under
+/// the policies whose indices can leave the tape and aren't left to Rust's
+/// own bounds checks.
+fn emit_check<W: Write + ?Sized>(
+    out: &mut W,
+    indent: &str,
+    offset: isize,
+    pc: usize,
+    options: &CodegenOptions,
+) -> io::Result<()> {
+    match check(offset, pc, options) {
+        Some(check) => writeln!(out, "{}{}", indent, check),
+        None => Ok(()),
+    }
+}
+
+fn check(offset: isize, pc: usize, options: &CodegenOptions) -> Option<String> {
+    let tape = match options.bounds {
+        BoundsPolicy::Error => "&tape",
+        BoundsPolicy::Grow => "&mut tape",
+        _ => return None,
+    };
+    Some(format!(
+        "check({}, {}, {});",
+        tape,
+        index(offset, options),
+        pc
+    ))
+}
+
+/// Literal stored by `,` at EOF, if the cell changes at all.
+fn eof_value(options: &CodegenOptions) -> Option<u32> {
+    match options.eof {
+        EofBehavior::Unchanged => None,
+        EofBehavior::Zero => Some(0),
+        EofBehavior::MinusOne => Some(options.cell_width.truncate(u32::MAX)),
+    }
+}
+
+/// Writes the cell `offset` away `count` times over.
+fn emit_output<W: Write + ?Sized>(
+    out: &mut W,
+    offset: isize,
+    count: usize,
+    pc: usize,
+    options: &CodegenOptions,
+) -> io::Result<()> {
+    emit_check(out, "    ", offset, pc, options)?;
+    let mut byte = cell_at(&index(offset, options), options);
+    if options.cell_width != CellWidth::U8 {
+        byte = format!("{} as u8", byte);
+    }
+    if count == 1 {
+        writeln!(
+            out,
+            "    std::io::stdout().write_all(&[{}]).unwrap();",
+            byte
+        )
+    } else {
+        writeln!(
+            out,
+            "    std::io::stdout().write_all(&[{}; {}]).unwrap();",
+            byte, count
+        )
+    }
+}
+
+fn emit_input<W: Write + ?Sized>(
+    out: &mut W,
+    offset: isize,
+    pc: usize,
+    options: &CodegenOptions,
+) -> io::Result<()> {
+    emit_check(out, "    ", offset, pc, options)?;
+    let idx = cell_at(&index(offset, options), options);
+    if options.cell_width == CellWidth::U8 && options.eof == EofBehavior::Unchanged {
+        return writeln!(
+            out,
+            "    std::io::stdin().read_exact(std::slice::from_mut(&mut {})).ok();",
+            idx
+        );
+    }
+    writeln!(out, "    {{")?;
+    writeln!(out, "        let mut byte = [0u8];")?;
+    writeln!(
+        out,
+        "        if std::io::stdin().read_exact(&mut byte).is_ok() {{"
+    )?;
+    writeln!(
+        out,
+        "            {} = byte[0] as {};",
+        idx,
+        options.cell_width.rust_type()
+    )?;
+    if let Some(v) = eof_value(options) {
+        writeln!(out, "        }} else {{")?;
+        writeln!(out, "            {} = {};", idx, v)?;
+    }
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")
+}
+
+/// Why [`compile_to_rust`] failed.
+#[derive(Debug)]
+pub enum CompileError {
+    Parse(ParseError),
+    /// The emitter doesn't support the program or the options, such as
+    /// [`BoundsPolicy::Bidirectional`], with an error of kind
+    /// [`Unsupported`](io::ErrorKind::Unsupported).
+    Emit(io::Error),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Parse(e) => e.fmt(f),
+            CompileError::Emit(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Parse(e) => Some(e),
+            CompileError::Emit(e) => Some(e),
+        }
+    }
+}
+
+impl From<ParseError> for CompileError {
+    fn from(e: ParseError) -> Self {
+        CompileError::Parse(e)
+    }
+}
+
+impl From<io::Error> for CompileError {
+    fn from(e: io::Error) -> Self {
+        CompileError::Emit(e)
+    }
+}
+
+/// Parses and optimizes `source` and returns it as a standalone Rust
+/// program, the code `bfc --target rust` prints. Meant for build scripts,
+/// which can write it out as a binary of their own.
+pub fn compile_to_rust(source: &[u8], options: &CodegenOptions) -> Result<String, CompileError> {
+    let mut code = Vec::new();
+    emit_rust(&optimize(parse(source)?), options, &mut code)?;
+    Ok(String::from_utf8_lossy(&code).into_owned())
+}
+
+/// Writes a standalone Rust program equivalent to `ops` to `out`.
+///
+/// With [`CodegenOptions::unsafe_indexing`], the cells are reached without
+/// Rust's bounds checks. That is sound wherever the bounds policy keeps the
+/// pointer on the tape or checks it first; under
+/// [`BoundsPolicy::Unchecked`], a program whose pointer can be followed
+/// from the start is checked to stay on the tape, and indexed as usual if
+/// it doesn't. Any other program is trusted to.
+pub fn emit_rust<W: Write>(ops: &[Op], options: &CodegenOptions, out: &mut W) -> io::Result<()> {
+    emit_rust_with(ops, None, options, out)
+}
+
+/// Like [`emit_rust`], with a comment before the code of each op naming the
+/// range of `map`'s source it came from, and the commands in it.
+pub fn emit_rust_mapped<W: Write>(
+    ops: &[Op],
+    map: &SourceMap<'_>,
+    options: &CodegenOptions,
+    out: &mut W,
+) -> io::Result<()> {
+    emit_rust_with(ops, Some(map), options, out)
+}
+
+fn emit_rust_with<W: Write>(
+    ops: &[Op],
+    map: Option<&SourceMap<'_>>,
+    options: &CodegenOptions,
+    out: &mut W,
+) -> io::Result<()> {
+    emit_program(&mut RustBackend::new(map.copied(), options), ops, out)
+}
+
+/// The largest tape `main` keeps on the stack; a larger one is a `Vec`, as
+/// some platforms give the main thread as little as 1 MiB of stack.
+const STACK_TAPE_BYTES: usize = 256 * 1024;
+
+/// The Rust emitter, as a [`Backend`].
+struct RustBackend<'a> {
+    map: Option<SourceMap<'a>>,
+    options: CodegenOptions,
+    /// Whether long code is split into segment functions, which only take
+    /// the tape and the pointer.
+    segmented: bool,
+    /// Loops open around the code being written outside segments.
+    depth: usize,
+    /// For each loop open in `main`, whether its body is split into
+    /// segments.
+    split: Vec<bool>,
+    /// The segment being filled.
+    segment: Option<Segment>,
+    segments: Vec<Segment>,
+    /// The `DefProc`s met so far; each body becomes a function.
+    bodies: Vec<(usize, usize)>,
+}
+
+/// The code of a segment function.
+#[derive(Default)]
+struct Segment {
+    code: Vec<u8>,
+    /// Ops in it.
+    ops: usize,
+    /// Loops open in it.
+    depth: usize,
+    /// Whether it has an `Op::End`.
+    ends: bool,
+}
+
+/// Writes generated Rust to `out`, indenting each line by another four
+/// spaces for each loop open around it.
+struct Code<'a> {
+    out: &'a mut dyn Write,
+    depth: usize,
+    /// Whether the next byte starts a line.
+    fresh: bool,
+}
+
+impl<'a> Code<'a> {
+    fn new(out: &'a mut dyn Write, depth: usize) -> Self {
+        Code {
+            out,
+            depth,
+            fresh: true,
+        }
+    }
+}
+
+impl Write for Code<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            if self.fresh && line != b"\n" {
+                for _ in 0..self.depth {
+                    self.out.write_all(b"    ")?;
+                }
+            }
+            self.out.write_all(line)?;
+            self.fresh = line.ends_with(b"\n");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Ops past which code is split into segment functions, as rustc slows
+/// down badly on one huge `main`.
+const SEGMENT_OPS: usize = 1000;
+
+impl<'a> RustBackend<'a> {
+    fn new(map: Option<SourceMap<'a>>, options: &CodegenOptions) -> Self {
+        RustBackend {
+            map,
+            options: options.clone(),
+            segmented: false,
+            depth: 0,
+            split: Vec::new(),
+            segment: None,
+            segments: Vec::new(),
+            bodies: Vec::new(),
+        }
+    }
+
+    /// Whether the code being written outside segments is split into them:
+    /// code of more than [`SEGMENT_OPS`] ops is, between loops, and so is
+    /// the body of a loop too long for one segment.
+    fn splitting(&self, ops: &[Op]) -> bool {
+        match self.split.last() {
+            Some(&split) => split,
+            None => self.segmented && ops.len() > SEGMENT_OPS,
+        }
+    }
+
+    /// Takes out the segment that the op at `pc`, and the rest of the loop
+    /// it opens, go in, after writing the call of a new one; or `None` if
+    /// they are written in place.
+    fn route(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<Option<Segment>> {
+        if self.segment.as_ref().is_some_and(|seg| seg.depth > 0) {
+            return Ok(self.segment.take());
+        }
+        if !self.splitting(ops) {
+            return Ok(None);
+        }
+        let len = match ops[pc] {
+            Op::Jz(end) => end + 1 - pc,
+            _ => 1,
+        };
+        if self
+            .segment
+            .as_ref()
+            .is_some_and(|seg| seg.ops + len > SEGMENT_OPS)
+        {
+            self.segments.extend(self.segment.take());
+        }
+        if len > SEGMENT_OPS {
+            return Ok(None);
+        }
+        let mut seg = match self.segment.take() {
+            Some(seg) => seg,
+            None => {
+                let n = self.segments.len();
+                let mut out = Code::new(out, self.depth);
+                writeln!(out, "    ptr = seg_{}(&mut tape, ptr);", n)?;
+                Segment::default()
+            }
+        };
+        seg.ops += len;
+        Ok(Some(seg))
+    }
+
+    /// Writes the op at `pc` where [`route`](Self::route) sends it.
+    fn emit(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()> {
+        match self.route(ops, pc, out)? {
+            Some(mut seg) => {
+                let mut code = Code::new(&mut seg.code, seg.depth);
+                self.write(ops, pc, &mut code)?;
+                seg.depth = code.depth;
+                seg.ends |= ops[pc] == Op::End;
+                self.segment = Some(seg);
+            }
+            None => self.write_in_place(ops, pc, out)?,
+        }
+        Ok(())
+    }
+
+    /// Writes the op at `pc` outside segments.
+    fn write_in_place(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()> {
+        let mut code = Code::new(out, self.depth);
+        self.write(ops, pc, &mut code)?;
+        self.depth = code.depth;
+        Ok(())
+    }
+}
+
+impl Backend for RustBackend<'_> {
+    fn begin(&mut self, ops: &[Op], out: &mut dyn Write) -> io::Result<()> {
+        if self.options.bounds == BoundsPolicy::Bidirectional {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the Rust backend doesn't support a bidirectional tape",
+            ));
+        }
+        if uses_forks(ops) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the Rust backend doesn't support Brainfork's forks",
+            ));
+        }
+        if uses_random(ops) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the Rust backend doesn't support '?', the random byte",
+            ));
+        }
+        // Procedures get the tape but not the names of the others.
+        if uses_tapes(ops) && uses_procedures(ops) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the Rust backend doesn't support switching tapes inside procedures",
+            ));
+        }
+        let options = &mut self.options;
+        if options.unsafe_indexing
+            && options.bounds == BoundsPolicy::Unchecked
+            && tape_reach(ops).is_some_and(|reach| {
+                *reach.start() < 0 || *reach.end() >= options.tape_size as isize
+            })
+        {
+            options.unsafe_indexing = false;
+        }
+        let options = &self.options;
+        // Code after `@` may be left over.
+        if ops.contains(&Op::End) {
+            writeln!(out, "#[allow(unreachable_code)]")?;
+        }
+        self.segmented = !uses_procedures(ops) && !uses_tapes(ops) && !uses_storage(ops);
+        // So can the last stores to the tape and the pointer, the last
+        // switch's tape number, or the pointer the last segment returns.
+        writeln!(out, "#[allow(unused_assignments)]")?;
+        writeln!(out, "fn main() {{")?;
+        writeln!(out, "    #[allow(unused_imports)]")?;
+        writeln!(out, "    use std::io::{{Read, Write}};")?;
+        let cell = options.cell_width;
+        // Procedures take the tape and give it back, which is cheap for a
+        // `Vec`.
+        let procs = uses_procedures(ops);
+        let tape_bytes = options.tape_size.saturating_mul(cell.bits() as usize / 8);
+        // A fully evaluated program only prints, and needs no tape.
+        if !ops.iter().all(|op| matches!(op, Op::OutputConst(_))) {
+            let (open, close) =
+                if options.bounds == BoundsPolicy::Grow || procs || tape_bytes > STACK_TAPE_BYTES {
+                    ("vec![", "]")
+                } else {
+                    ("[", "]")
+                };
+            let fresh = format!(
+                "{}0{}; {}{}",
+                open,
+                cell.rust_type(),
+                options.tape_size,
+                close
+            );
+            writeln!(out, "    let mut tape = {};", fresh)?;
+            writeln!(out, "    let mut ptr = 0usize;")?;
+            emit_cell_macro(out, options)?;
+            if uses_tapes(ops) {
+                // The tapes switched away from, by number, with their pointers.
+                writeln!(out, "    let mut tapes = std::collections::HashMap::new();")?;
+                writeln!(out, "    let mut current = 0isize;")?;
+                writeln!(out, "    let fresh = || {};", fresh)?;
+            }
+        }
+        if procs {
+            writeln!(out, "    let procs = &mut Procs::default();")?;
+        }
+        if uses_storage(ops) {
+            writeln!(out, "    #[allow(unused)]")?;
+            writeln!(out, "    let mut storage = 0{};", cell.rust_type())?;
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()> {
+        self.emit(ops, pc, out)
+    }
+
+    fn loop_begin(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()> {
+        let split = self.splitting(ops);
+        let in_place = self.segment.as_ref().is_none_or(|seg| seg.depth == 0);
+        self.emit(ops, pc, out)?;
+        // Written in place, so its body is too.
+        if in_place && self.segment.is_none() {
+            let len = match ops[pc] {
+                Op::Jz(end) => end - pc - 1,
+                _ => 0,
+            };
+            self.split.push(split && len > SEGMENT_OPS);
+        }
+        Ok(())
+    }
+
+    fn loop_end(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()> {
+        if self.segment.as_ref().is_some_and(|seg| seg.depth > 0) {
+            return self.emit(ops, pc, out);
+        }
+        // The loop was written in place, and its body's last segment ends
+        // with it.
+        self.segments.extend(self.segment.take());
+        self.split.pop();
+        self.write_in_place(ops, pc, out)
+    }
+
+    fn end(&mut self, ops: &[Op], out: &mut dyn Write) -> io::Result<()> {
+        self.segments.extend(self.segment.take());
+        writeln!(out, "}}")?;
+        let cell = self.options.cell_width;
+        for (i, seg) in self.segments.iter().enumerate() {
+            let tape = match self.options.bounds {
+                BoundsPolicy::Grow => format!("mut tape: &mut Vec<{}>", cell.rust_type()),
+                _ => format!("tape: &mut [{}]", cell.rust_type()),
+            };
+            writeln!(out)?;
+            if seg.ends {
+                writeln!(out, "#[allow(unreachable_code)]")?;
+            }
+            writeln!(out, "#[allow(unused_mut)]")?;
+            writeln!(out, "fn seg_{}({}, mut ptr: usize) -> usize {{", i, tape)?;
+            writeln!(out, "    #[allow(unused_imports)]")?;
+            writeln!(out, "    use std::io::{{Read, Write}};")?;
+            emit_cell_macro(out, &self.options)?;
+            out.write_all(&seg.code)?;
+            writeln!(out, "    ptr")?;
+            writeln!(out, "}}")?;
+        }
+        // Bodies can define procedures of their own, which get pushed as
+        // they are emitted.
+        let mut i = 0;
+        while let Some(&(def, end)) = self.bodies.get(i) {
+            let tape = format!("Vec<{}>", cell.rust_type());
+            writeln!(out)?;
+            writeln!(
+                out,
+                "fn proc_{}(mut tape: {1}, mut ptr: usize, procs: &mut Procs) -> ({1}, usize) {{",
+                def, tape
+            )?;
+            writeln!(out, "    #[allow(unused_imports)]")?;
+            writeln!(out, "    use std::io::{{Read, Write}};")?;
+            emit_cell_macro(out, &self.options)?;
+            emit_ops(self, ops, def + 1..end, out)?;
+            writeln!(out, "    (tape, ptr)")?;
+            writeln!(out, "}}")?;
+            i += 1;
+        }
+        let options = &self.options;
+        if uses_procedures(ops) {
+            emit_procs(out, options)?;
+        }
+
+        if ops.contains(&Op::Debug) {
+            emit_debug_dump(out, options)?;
+        }
+        if ops.contains(&Op::DivMod) {
+            emit_div_mod(out, options)?;
+        }
+        let needs_tape = !ops.iter().all(|op| matches!(op, Op::OutputConst(_)));
+        if needs_tape && matches!(options.bounds, BoundsPolicy::Error | BoundsPolicy::Grow) {
+            emit_bounds_check(out, options)?;
+        }
+        if cell == CellWidth::U8
+            && !matches!(options.bounds, BoundsPolicy::Clamp | BoundsPolicy::Wrap)
+            && ops
+                .iter()
+                .any(|op| matches!(op, Op::ScanLeft | Op::ScanRight))
+        {
+            writeln!(out)?;
+            out.write_all(ZERO_SEARCH.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl RustBackend<'_> {
+    /// Writes the code of `ops[pc]`, pushing the procedure it defines onto
+    /// `bodies`.
+    fn write(&mut self, ops: &[Op], pc: usize, out: &mut Code<'_>) -> io::Result<()> {
+        let options = &self.options;
+        let cell = options.cell_width;
+        // The current cell.
+        let here = cell_at("ptr", options);
+        let op = &ops[pc];
+        if let Some(comment) = self.map.and_then(|map| map.comment(pc)) {
+            writeln!(out, "    {}", comment)?;
+        }
+        match op {
+            Op::PtrAdd(n) => {
+                writeln!(out, "    ptr = {};", index(*n, options))?;
+            }
+            Op::ValAdd(offset, n) => {
+                emit_check(out, "    ", *offset, pc, options)?;
+                let idx = cell_at(&index(*offset, options), options);
+                let n = cell.truncate(*n);
+                writeln!(out, "    {0} = {0}.wrapping_add({1});", idx, n)?;
+            }
+            Op::ValSub(offset, n) => {
+                emit_check(out, "    ", *offset, pc, options)?;
+                let idx = cell_at(&index(*offset, options), options);
+                let n = cell.truncate(*n);
+                writeln!(out, "    {0} = {0}.wrapping_sub({1});", idx, n)?;
+            }
+            Op::Output => emit_output(out, 0, 1, pc, options)?,
+            Op::OutputAt(offset) => emit_output(out, *offset, 1, pc, options)?,
+            Op::OutputRepeat(offset, count) => emit_output(out, *offset, *count, pc, options)?,
+            Op::OutputConst(bytes) => {
+                let literal: String = bytes
+                    .iter()
+                    .flat_map(|&b| std::ascii::escape_default(b))
+                    .map(char::from)
+                    .collect();
+                writeln!(
+                    out,
+                    "    std::io::stdout().write_all(b\"{}\").unwrap();",
+                    literal
+                )?;
+            }
+            Op::Input => emit_input(out, 0, pc, options)?,
+            Op::InputAt(offset) => emit_input(out, *offset, pc, options)?,
+            Op::Jz(_) => {
+                match check(0, pc, options) {
+                    Some(check) => writeln!(out, "    while {{ {} {} != 0 }} {{", check, here)?,
+                    None => writeln!(out, "    while {} != 0 {{", here)?,
+                }
+                out.depth += 1;
+            }
+            Op::Jnz(_) => {
+                emit_check(out, "    ", 0, pc, options)?;
+                out.depth -= 1;
+                writeln!(out, "    }}")?;
+            }
+            Op::Clear(offset) => {
+                emit_check(out, "    ", *offset, pc, options)?;
+                writeln!(
+                    out,
+                    "    {} = 0;",
+                    cell_at(&index(*offset, options), options)
+                )?;
+            }
+            Op::Set(offset, n) => {
+                emit_check(out, "    ", *offset, pc, options)?;
+                let idx = cell_at(&index(*offset, options), options);
+                writeln!(out, "    {} = {};", idx, cell.truncate(*n))?;
+            }
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) => {
+                // MulAdd adds (tape[ptr] * factor) to tape[ptr + offset];
+                // AddTo and SubFrom add or take away tape[ptr] itself.
+                let (offset, update) = match *op {
+                    Op::MulAdd(offset, factor) => (
+                        offset,
+                        format!(
+                            "wrapping_add({}.wrapping_mul({}))",
+                            here,
+                            cell.truncate(factor)
+                        ),
+                    ),
+                    Op::AddTo(offset) => (offset, format!("wrapping_add({})", here)),
+                    Op::SubFrom(offset) => (offset, format!("wrapping_sub({})", here)),
+                    _ => unreachable!(),
+                };
+                emit_check(out, "    ", 0, pc, options)?;
+                writeln!(out, "    if {} != 0 {{", here)?;
+                emit_check(out, "        ", offset, pc, options)?;
+                writeln!(out, "        let target_idx = {};", index(offset, options))?;
+                writeln!(
+                    out,
+                    "        {0} = {0}.{1};",
+                    cell_at("target_idx", options),
+                    update
+                )?;
+                writeln!(out, "    }}")?;
+            }
+            Op::Transfer(targets) => {
+                emit_check(out, "    ", 0, pc, options)?;
+                writeln!(out, "    if {} != 0 {{", here)?;
+                writeln!(out, "        let value = {};", here)?;
+                for &(offset, factor) in targets {
+                    emit_check(out, "        ", offset, pc, options)?;
+                    writeln!(out, "        let target_idx = {};", index(offset, options))?;
+                    let update = match factor {
+                        1 => "wrapping_add(value)".to_string(),
+                        u32::MAX => "wrapping_sub(value)".to_string(),
+                        _ => format!(
+                            "wrapping_add(value.wrapping_mul({}))",
+                            cell.truncate(factor)
+                        ),
+                    };
+                    writeln!(
+                        out,
+                        "        {0} = {0}.{1};",
+                        cell_at("target_idx", options),
+                        update
+                    )?;
+                }
+                writeln!(out, "        {} = 0;", here)?;
+                writeln!(out, "    }}")?;
+            }
+            Op::ScanLeft => match options.bounds {
+                BoundsPolicy::Clamp | BoundsPolicy::Wrap => {
+                    writeln!(out, "    while {} != 0 {{", here)?;
+                    writeln!(out, "        ptr = {};", index(-1, options))?;
+                    writeln!(out, "    }}")?;
+                }
+                _ => {
+                    emit_check(out, "    ", 0, pc, options)?;
+                    let search = if cell == CellWidth::U8 {
+                        "memrchr0(&tape[..=ptr])"
+                    } else {
+                        "tape[..=ptr].iter().rposition(|&x| x == 0)"
+                    };
+                    writeln!(out, "    if let Some(pos) = {} {{", search)?;
+                    writeln!(out, "        ptr = pos;")?;
+                    writeln!(out, "    }} else {{")?;
+                    match options.bounds {
+                        BoundsPolicy::Unchecked => {
+                            writeln!(out, "        ptr = ptr.wrapping_sub(ptr + 1);")?
+                        }
+                        _ => writeln!(out, "        out_of_bounds({});", pc)?,
+                    }
+                    writeln!(out, "    }}")?;
+                }
+            },
+            Op::ScanRight => match options.bounds {
+                BoundsPolicy::Clamp | BoundsPolicy::Wrap => {
+                    writeln!(out, "    while {} != 0 {{", here)?;
+                    writeln!(out, "        ptr = {};", index(1, options))?;
+                    writeln!(out, "    }}")?;
+                }
+                _ => {
+                    emit_check(out, "    ", 0, pc, options)?;
+                    let search = if cell == CellWidth::U8 {
+                        "memchr0(&tape[ptr..])"
+                    } else {
+                        "tape[ptr..].iter().position(|&x| x == 0)"
+                    };
+                    writeln!(out, "    if let Some(pos) = {} {{", search)?;
+                    writeln!(out, "        ptr += pos;")?;
+                    writeln!(out, "    }} else {{")?;
+                    // A growing tape is all zeros past its end.
+                    match options.bounds {
+                        BoundsPolicy::Error => writeln!(out, "        out_of_bounds({});", pc)?,
+                        _ => writeln!(out, "        ptr = tape.len();")?,
+                    }
+                    writeln!(out, "    }}")?;
+                }
+            },
+            Op::Scan(stride) => {
+                match check(0, pc, options) {
+                    Some(check) => writeln!(out, "    while {{ {} {} != 0 }} {{", check, here)?,
+                    None => writeln!(out, "    while {} != 0 {{", here)?,
+                }
+                writeln!(out, "        ptr = {};", index(*stride, options))?;
+                writeln!(out, "    }}")?;
+            }
+            Op::BulkAdd(deltas) => {
+                writeln!(out, "    {{")?;
+                for (offset, n) in deltas {
+                    emit_check(out, "        ", *offset, pc, options)?;
+                    let idx = cell_at(&index(*offset, options), options);
+                    let n = cell.truncate(*n);
+                    writeln!(out, "        {0} = {0}.wrapping_add({1});", idx, n)?;
+                }
+                writeln!(out, "    }}")?;
+            }
+            Op::BulkClear(offsets) => {
+                writeln!(out, "    {{")?;
+                for offset in offsets {
+                    emit_check(out, "        ", *offset, pc, options)?;
+                    writeln!(
+                        out,
+                        "        {} = 0;",
+                        cell_at(&index(*offset, options), options)
+                    )?;
+                }
+                writeln!(out, "    }}")?;
+            }
+            Op::ClearRange(offset, n) => {
+                writeln!(out, "    {{")?;
+                match options.bounds {
+                    // The range may wrap around or stop at an end.
+                    BoundsPolicy::Clamp | BoundsPolicy::Wrap => {
+                        for offset in (0..*n as isize).map(|i| offset + i) {
+                            writeln!(
+                                out,
+                                "        {} = 0;",
+                                cell_at(&index(offset, options), options)
+                            )?;
+                        }
+                    }
+                    _ => {
+                        // Checking both ends checks the range.
+                        emit_check(out, "        ", *offset, pc, options)?;
+                        emit_check(out, "        ", offset + *n as isize - 1, pc, options)?;
+                        writeln!(out, "        let start = {};", index(*offset, options))?;
+                        if options.unsafe_indexing {
+                            writeln!(
+                                out,
+                                "        unsafe {{ tape.get_unchecked_mut(start..start + {}) }}.fill(0);",
+                                n
+                            )?;
+                        } else {
+                            writeln!(out, "        tape[start..start + {}].fill(0);", n)?;
+                        }
+                    }
+                }
+                writeln!(out, "    }}")?;
+            }
+            Op::ClearUntilZero(stride) => {
+                match check(0, pc, options) {
+                    Some(check) => writeln!(out, "    while {{ {} {} != 0 }} {{", check, here)?,
+                    None => writeln!(out, "    while {} != 0 {{", here)?,
+                }
+                writeln!(out, "        {} = 0;", here)?;
+                writeln!(out, "        ptr = {};", index(*stride, options))?;
+                writeln!(out, "    }}")?;
+            }
+            Op::Debug => writeln!(out, "    debug_dump({}, ptr, &tape);", pc)?,
+            Op::DefProc(end) => {
+                emit_check(out, "    ", 0, pc, options)?;
+                writeln!(out, "    procs.0.insert({} as u32, proc_{});", here, pc)?;
+                self.bodies.push((pc, *end));
+            }
+            // Bodies end before their `EndProc`.
+            Op::EndProc => {}
+            Op::CallProc => {
+                emit_check(out, "    ", 0, pc, options)?;
+                writeln!(out, "    (tape, ptr) = call(procs, tape, ptr, {});", pc)?;
+            }
+            // Rejected by `emit_rust_with`.
+            Op::Fork | Op::Random => {}
+            Op::SwitchTape(n) => {
+                writeln!(
+                    out,
+                    "    let (next, at) = tapes.remove(&(current + {})).unwrap_or_else(|| (fresh(), 0));",
+                    n
+                )?;
+                writeln!(
+                    out,
+                    "    tapes.insert(current, (std::mem::replace(&mut tape, next), std::mem::replace(&mut ptr, at)));"
+                )?;
+                writeln!(out, "    current += {};", n)?;
+            }
+            // Exiting flushes stdout.
+            Op::End => writeln!(out, "    std::process::exit(0);")?,
+            Op::Store => {
+                emit_check(out, "    ", 0, pc, options)?;
+                writeln!(out, "    storage = {};", here)?;
+            }
+            Op::Retrieve => {
+                emit_check(out, "    ", 0, pc, options)?;
+                writeln!(out, "    {} = storage;", here)?;
+            }
+            // Does nothing off the tape, where the loop after it fails.
+            Op::DivMod => writeln!(
+                out,
+                "    if let Some(cells) = tape.get_mut(ptr..ptr.saturating_add(7)) {{ div_mod(cells); }}"
+            )?,
+            Op::Bitwise(bit) => {
+                emit_check(out, "    ", 0, pc, options)?;
+                let code = match bit {
+                    BitOp::ShiftLeft => "{0} <<= 1;",
+                    BitOp::ShiftRight => "{0} >>= 1;",
+                    BitOp::Not => "{0} = !{0};",
+                    BitOp::Xor => "{0} ^= storage;",
+                    BitOp::And => "{0} &= storage;",
+                    BitOp::Or => "{0} |= storage;",
+                };
+                writeln!(out, "    {}", code.replace("{0}", &here))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `Procs`, the table of a program's procedures, and `call`, which
+/// runs the one the current cell numbers or stops the program like the
+/// interpreter.
+fn emit_procs<W: Write + ?Sized>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let tape = format!("Vec<{}>", options.cell_width.rust_type());
+    writeln!(out)?;
+    writeln!(out, "#[derive(Default)]")?;
+    writeln!(
+        out,
+        "struct Procs(std::collections::HashMap<u32, fn({0}, usize, &mut Procs) -> ({0}, usize)>);",
+        tape
+    )?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "fn call(procs: &mut Procs, tape: {0}, ptr: usize, op: usize) -> ({0}, usize) {{",
+        tape
+    )?;
+    writeln!(out, "    let id = tape[ptr] as u32;")?;
+    writeln!(out, "    match procs.0.get(&id).copied() {{")?;
+    writeln!(out, "        Some(body) => body(tape, ptr, procs),")?;
+    writeln!(out, "        None => {{")?;
+    writeln!(
+        out,
+        "            eprintln!(\"error: op {{}} called procedure {{}}, which isn't defined\", op, id);"
+    )?;
+    writeln!(out, "            std::process::exit(1);")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")
+}
+
+/// The byte searches byte-cell scans use. The generated program is built
+/// with plain `rustc`, so there is no memchr crate; these test a word at a
+/// time instead.
+const ZERO_SEARCH: &str = "\
+const LO: u64 = 0x0101_0101_0101_0101;
+const HI: u64 = 0x8080_8080_8080_8080;
+
+fn has_zero(word: &[u8]) -> bool {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(word);
+    let w = u64::from_le_bytes(bytes);
+    w.wrapping_sub(LO) & !w & HI != 0
+}
+
+fn memchr0(cells: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while start + 8 <= cells.len() && !has_zero(&cells[start..start + 8]) {
+        start += 8;
+    }
+    cells[start..].iter().position(|&x| x == 0).map(|i| start + i)
+}
+
+fn memrchr0(cells: &[u8]) -> Option<usize> {
+    let mut end = cells.len();
+    while end >= 8 && !has_zero(&cells[end - 8..end]) {
+        end -= 8;
+    }
+    cells[..end].iter().rposition(|&x| x == 0)
+}
+";
+
+/// Writes `check`, which stops the program with the op's index when a cell
+/// is off the tape, or grows the tape to make room for it.
+fn emit_bounds_check<W: Write + ?Sized>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let cell = options.cell_width.rust_type();
+    writeln!(out)?;
+    if options.bounds == BoundsPolicy::Grow {
+        writeln!(
+            out,
+            "fn check(tape: &mut Vec<{}>, idx: usize, op: usize) {{",
+            cell
+        )?;
+        writeln!(
+            out,
+            "    // Left of cell 0, the index wraps around to a huge one."
+        )?;
+        writeln!(out, "    if idx as isize >= 0 && idx >= tape.len() {{")?;
+        writeln!(out, "        let len = (idx + 1).max(tape.len() * 2);")?;
+        writeln!(out, "        tape.resize(len, 0);")?;
+        writeln!(out, "    }}")?;
+    } else {
+        writeln!(out, "fn check(tape: &[{}], idx: usize, op: usize) {{", cell)?;
+    }
+    writeln!(out, "    if idx >= tape.len() {{")?;
+    writeln!(out, "        out_of_bounds(op);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "fn out_of_bounds(op: usize) -> ! {{")?;
+    writeln!(
+        out,
+        "    eprintln!(\"error: op {{}} touched a cell outside the tape\", op);"
+    )?;
+    writeln!(out, "    std::process::exit(1);")?;
+    writeln!(out, "}}")
+}
+
+/// Writes the function `Op::Debug` calls, which prints the same line as
+/// the interpreter does.
+/// Writes the function `Op::DivMod` calls, which does what
+/// [`div_mod`](crate::div_mod) does.
+fn emit_div_mod<W: Write + ?Sized>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let cell = options.cell_width.rust_type();
+    writeln!(out)?;
+    writeln!(out, "fn div_mod(c: &mut [{}]) {{", cell)?;
+    writeln!(
+        out,
+        "    let (turns, left, moved) = (c[0] as u64, c[2] as u64, c[3] as u64);"
+    )?;
+    writeln!(out, "    let divisor = left + moved;")?;
+    writeln!(
+        out,
+        "    if turns == 0 || left == 0 || !(2..={}::MAX as u64).contains(&divisor) || c[5] != 0 || c[6] != 0 {{",
+        cell
+    )?;
+    writeln!(out, "        return;")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    if turns < left {{")?;
+    writeln!(out, "        c[2] = (left - turns) as {};", cell)?;
+    writeln!(out, "        c[3] = (moved + turns) as {};", cell)?;
+    writeln!(out, "    }} else {{")?;
+    writeln!(out, "        let past = turns - left;")?;
+    writeln!(
+        out,
+        "        c[2] = (divisor - past % divisor) as {};",
+        cell
+    )?;
+    writeln!(out, "        c[3] = (past % divisor) as {};", cell)?;
+    writeln!(
+        out,
+        "        c[4] = c[4].wrapping_add((1 + past / divisor) as {});",
+        cell
+    )?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    c[1] = c[1].wrapping_add(turns as {});", cell)?;
+    writeln!(out, "    c[0] = 0;")?;
+    writeln!(out, "}}")
+}
+
+fn emit_debug_dump<W: Write + ?Sized>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let cell = options.cell_width.rust_type();
+    writeln!(out)?;
+    writeln!(
+        out,
+        "fn debug_dump(op: usize, ptr: usize, tape: &[{}]) {{",
+        cell
+    )?;
+    writeln!(out, "    use std::io::Write;")?;
+    writeln!(out, "    std::io::stdout().flush().unwrap();")?;
+    writeln!(
+        out,
+        "    let start = ptr.saturating_sub(8).min(tape.len().saturating_sub(16));"
+    )?;
+    writeln!(out, "    let end = (start + 16).min(tape.len());")?;
+    writeln!(
+        out,
+        "    let mut line = format!(\"# op {{}}, ptr {{}}, cells {{}}..{{}}:\", op, ptr, start, end);"
+    )?;
+    writeln!(out, "    for i in start..end {{")?;
+    writeln!(out, "        if i == ptr {{")?;
+    writeln!(
+        out,
+        "            line.push_str(&format!(\" [{{}}]\", tape[i]));"
+    )?;
+    writeln!(out, "        }} else {{")?;
+    writeln!(
+        out,
+        "            line.push_str(&format!(\" {{}}\", tape[i]));"
+    )?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    eprintln!(\"{{}}\", line);")?;
+    writeln!(out, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseOptions, parse_with};
+
+    #[test]
+    fn test_compile_to_rust() {
+        let code = compile_to_rust(b"+[>,.<-]", &CodegenOptions::default()).unwrap();
+        let mut out = Vec::new();
+        emit_rust(
+            &optimize(parse(b"+[>,.<-]").unwrap()),
+            &CodegenOptions::default(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(code.as_bytes(), out);
+        assert!(matches!(
+            compile_to_rust(b"[", &CodegenOptions::default()),
+            Err(CompileError::Parse(_))
+        ));
+        let options = CodegenOptions {
+            bounds: BoundsPolicy::Bidirectional,
+            ..CodegenOptions::default()
+        };
+        assert!(matches!(
+            compile_to_rust(b"+.", &options),
+            Err(CompileError::Emit(e)) if e.kind() == io::ErrorKind::Unsupported
+        ));
+    }
+
+    #[test]
+    fn test_emit_debug() {
+        let ops = parse_with(
+            b">#",
+            &ParseOptions {
+                debug_char: true,
+                ..ParseOptions::default()
+            },
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.starts_with("#[allow(unused_assignments)]\nfn main() {\n"));
+        assert!(src.contains("    debug_dump(1, ptr, &tape);\n"));
+        assert!(src.contains("fn debug_dump(op: usize, ptr: usize, tape: &[u8]) {"));
+    }
+
+    #[test]
+    fn test_emit_loop_is_closed() {
+        let ops = optimize(parse(b"+[>,.<-]").unwrap());
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.starts_with("#[allow(unused_assignments)]\nfn main() {\n"));
+        assert!(src.contains("let mut tape = [0u8; 30000];"));
+        assert_eq!(src.matches('{').count(), src.matches('}').count());
+        assert!(src.contains("while { check(&tape, ptr, 1); tape[ptr] != 0 } {"));
+        assert!(src.contains("fn out_of_bounds(op: usize) -> ! {"));
+    }
+
+    #[test]
+    fn test_emit_indents_loops() {
+        let ops = optimize(parse(b",[>,[.,]<-]").unwrap());
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("\n        while { check(&tape, ptr, 4); tape[ptr] != 0 } {\n"));
+        assert!(
+            src.contains("\n            std::io::stdout().write_all(&[tape[ptr]]).unwrap();\n")
+        );
+        assert!(src.contains("\n            check(&tape, ptr, 7);\n        }\n"));
+        assert!(src.contains("\n        check(&tape, ptr, 10);\n    }\n}\n"));
+    }
+
+    #[test]
+    fn test_emit_source_map() {
+        let source = b"+, copy: [->+<]\n>.";
+        let (ops, spans) = crate::parse_with_spans(source, &ParseOptions::default()).unwrap();
+        let (ops, spans) = crate::optimize_with_spans(ops, spans, crate::OptLevel::O2);
+        let map = SourceMap {
+            source,
+            spans: &spans,
+        };
+        let mut out = Vec::new();
+        emit_rust_mapped(&ops, &map, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("    // bf: 1..2 ,\n"));
+        // The `AddTo` and the clear after it share one comment.
+        assert_eq!(src.matches("// bf: 9..15 [->+<]\n").count(), 1);
+        assert!(src.contains("    // bf: 16..17 >\n"));
+    }
+
+    #[test]
+    fn test_emit_procedures() {
+        let options = ParseOptions {
+            dialect: crate::Dialect::Pbrain,
+            ..ParseOptions::default()
+        };
+        let ops = parse_with(b"+(>(-)<):", &options).unwrap();
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("    let mut tape = vec![0u8; 30000];\n"));
+        assert!(src.contains("    procs.0.insert(tape[ptr] as u32, proc_1);\n"));
+        assert!(src.contains("    (tape, ptr) = call(procs, tape, ptr, 8);\n"));
+        // The nested definition runs when procedure 1 does.
+        let outer = src.find("fn proc_1(").unwrap();
+        let inner = src.find("fn proc_3(").unwrap();
+        assert!(src[outer..inner].contains("procs.0.insert(tape[ptr] as u32, proc_3);"));
+        assert!(src.contains("fn call(procs: &mut Procs, tape: Vec<u8>, ptr: usize, op: usize)"));
+    }
+
+    #[test]
+    fn test_emit_byte_scans() {
+        let ops = optimize(parse(b"+[>]<[<]").unwrap());
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("if let Some(pos) = memchr0(&tape[ptr..]) {"));
+        assert!(src.contains("if let Some(pos) = memrchr0(&tape[..=ptr]) {"));
+        assert!(src.contains("fn memchr0(cells: &[u8]) -> Option<usize> {"));
+
+        let options = CodegenOptions {
+            cell_width: CellWidth::U16,
+            ..CodegenOptions::default()
+        };
+        let mut out = Vec::new();
+        emit_rust(&ops, &options, &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("tape[ptr..].iter().position(|&x| x == 0)"));
+        assert!(!src.contains("fn memchr0"));
+    }
+
+    #[test]
+    fn test_emit_tape_size() {
+        let options = CodegenOptions {
+            tape_size: 65536,
+            ..CodegenOptions::default()
+        };
+        let mut out = Vec::new();
+        emit_rust(&[Op::Output], &options, &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("let mut tape = [0u8; 65536];"));
+    }
+
+    #[test]
+    fn test_emit_heap_tape() {
+        let emit = |tape_size, cell_width| {
+            let options = CodegenOptions {
+                tape_size,
+                cell_width,
+                ..CodegenOptions::default()
+            };
+            let mut out = Vec::new();
+            emit_rust(&optimize(parse(b",.").unwrap()), &options, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(emit(262_144, CellWidth::U8).contains("let mut tape = [0u8; 262144];"));
+        assert!(emit(262_145, CellWidth::U8).contains("let mut tape = vec![0u8; 262145];"));
+        assert!(emit(65_537, CellWidth::U32).contains("let mut tape = vec![0u32; 65537];"));
+    }
+
+    #[test]
+    fn test_emit_wrap_indices() {
+        let options = CodegenOptions {
+            tape_size: 100,
+            bounds: BoundsPolicy::Wrap,
+            ..CodegenOptions::default()
+        };
+        let ops = parse(b"<<+>>>-").unwrap();
+        let mut out = Vec::new();
+        emit_rust(&ops, &options, &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("tape[(ptr + 98) % 100] = tape[(ptr + 98) % 100].wrapping_add(1);"));
+        assert!(src.contains("tape[(ptr + 1) % 100] = tape[(ptr + 1) % 100].wrapping_sub(1);"));
+        assert!(src.contains("ptr = (ptr + 1) % 100;"));
+    }
+
+    #[test]
+    fn test_emit_clamp_and_grow() {
+        let ops = parse(b"<<+>>>.").unwrap();
+        let emit = |bounds| {
+            let options = CodegenOptions {
+                tape_size: 100,
+                bounds,
+                ..CodegenOptions::default()
+            };
+            let mut out = Vec::new();
+            emit_rust(&ops, &options, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let src = emit(BoundsPolicy::Clamp);
+        assert!(src.contains("ptr = ptr.saturating_add_signed(1isize).min(99);"));
+        assert!(!src.contains("fn check"));
+
+        let src = emit(BoundsPolicy::Grow);
+        assert!(src.contains("let mut tape = vec![0u8; 100];"));
+        assert!(src.contains("check(&mut tape, ptr.wrapping_add_signed(-2isize), 0);"));
+        assert!(src.contains("tape.resize(len, 0);"));
+    }
+
+    #[test]
+    fn test_emit_wide_cells() {
+        let options = CodegenOptions {
+            cell_width: CellWidth::U16,
+            ..CodegenOptions::default()
+        };
+        // Read first, so the printed value isn't known ahead of time.
+        let mut code = vec![b','];
+        code.extend([b'+'; 300]);
+        code.push(b'.');
+        let ops = optimize(parse(&code).unwrap());
+        let mut out = Vec::new();
+        emit_rust(&ops, &options, &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("let mut tape = [0u16; 30000];"));
+        assert!(src.contains("wrapping_add(300)"));
+        assert!(src.contains("write_all(&[tape[ptr] as u8])"));
+
+        let options = CodegenOptions::default();
+        let mut out = Vec::new();
+        emit_rust(&ops, &options, &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("wrapping_add(44)"));
+    }
+
+    #[test]
+    fn test_emit_segments() {
+        let code = format!(",[{}[-]]{}", ",.".repeat(600), ",.".repeat(600));
+        let ops = optimize(parse(code.as_bytes()).unwrap());
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        // The loop stays in `main`, with its long body split in two.
+        let main = &src[..src.find("\n}\n").unwrap()];
+        assert!(main.contains("    while { check(&tape, ptr, 1); tape[ptr] != 0 } {\n"));
+        assert_eq!(main.matches("ptr = seg_").count(), 5);
+        assert!(src.contains("fn seg_4(tape: &mut [u8], mut ptr: usize) -> usize {"));
+        assert_eq!(src.matches('{').count(), src.matches('}').count());
+
+        let small = optimize(parse(b"+[>,.<-]").unwrap());
+        let mut out = Vec::new();
+        emit_rust(&small, &CodegenOptions::default(), &mut out).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("seg_"));
+    }
+
+    #[test]
+    fn test_emit_unsafe_indexing() {
+        let emit = |code: &[u8], bounds| {
+            let options = CodegenOptions {
+                bounds,
+                unsafe_indexing: true,
+                ..CodegenOptions::default()
+            };
+            let mut out = Vec::new();
+            emit_rust(&optimize(parse(code).unwrap()), &options, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let src = emit(b",[->+<]>.", BoundsPolicy::Error);
+        assert!(src.contains("            *unsafe { tape.get_unchecked_mut($idx) }\n"));
+        assert!(src.contains("    cell!(ptr) = 0;\n"));
+        assert!(!src.contains("tape[ptr]"));
+
+        // Known to leave the tape, so indexed as usual.
+        let src = emit(b",<+", BoundsPolicy::Unchecked);
+        assert!(src.contains("tape[ptr.wrapping_add_signed(-1isize)]"));
+        assert!(!src.contains("cell!"));
+        assert!(emit(b",>+", BoundsPolicy::Unchecked).contains("cell!"));
+    }
+
+    #[test]
+    fn test_emit_set() {
+        let ops = optimize(parse(b",[-]++++.").unwrap());
+        let mut out = Vec::new();
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("    tape[ptr] = 4;\n"));
+        assert!(!src.contains("wrapping_add"));
+    }
+
+    #[test]
+    fn test_emit_output_const() {
+        let mut out = Vec::new();
+        let ops = [Op::OutputConst(b"hi \"bf\"\n".to_vec())];
+        emit_rust(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains(r#"write_all(b"hi \"bf\"\n").unwrap();"#));
+        assert!(!src.contains("let mut tape"));
+    }
+
+    #[test]
+    fn test_emit_eof() {
+        let options = CodegenOptions {
+            eof: EofBehavior::MinusOne,
+            ..CodegenOptions::default()
+        };
+        let mut out = Vec::new();
+        emit_rust(&[Op::Input], &options, &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("} else {\n            tape[ptr] = 255;"));
+    }
+
+    /// Builds `ops` with each backend whose toolchain is installed and
+    /// runs it, returning the backend's name with its exit status, output
+    /// and error output.
+    #[cfg(unix)]
+    fn run_backends(ops: &[Op]) -> Vec<(&'static str, Option<i32>, Vec<u8>, String)> {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::{Command, Output, Stdio};
+
+        let dir = std::env::temp_dir().join(format!("bf-backends-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let options = CodegenOptions::default();
+        let binary = dir.join("program");
+        let run = |command: &mut Command| command.stdin(Stdio::null()).output().ok();
+        let build = |compiler: &str, args: &[&str], source: &str| {
+            let status = Command::new(compiler)
+                .args(args)
+                .arg(dir.join(source))
+                .arg("-o")
+                .arg(&binary)
+                .stderr(Stdio::null())
+                .status()
+                .ok()?;
+            assert!(status.success(), "{} failed", compiler);
+            run(&mut Command::new(&binary))
+        };
+
+        let mut results = Vec::new();
+        let mut report = |name, output: Option<Output>| {
+            if let Some(output) = output {
+                let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+                results.push((name, output.status.code(), output.stdout, stderr));
+            }
+        };
+        let mut rust = Vec::new();
+        emit_rust(ops, &options, &mut rust).unwrap();
+        fs::write(dir.join("program.rs"), rust).unwrap();
+        report(
+            "rust",
+            build("rustc", &["--cap-lints", "allow"], "program.rs"),
+        );
+        let mut c = Vec::new();
+        emit_c(ops, &options, &mut c).unwrap();
+        fs::write(dir.join("program.c"), c).unwrap();
+        report("c", build("cc", &[], "program.c"));
+        let mut llvm = Vec::new();
+        emit_llvm_ir(ops, &options, &mut llvm).unwrap();
+        fs::write(dir.join("program.ll"), llvm).unwrap();
+        // LLVM 14 only reads opaque pointers when asked to.
+        let ll = dir.join("program.ll");
+        let lli = run(Command::new("lli").arg(&ll))
+            .filter(|output| !String::from_utf8_lossy(&output.stderr).contains("opaque-pointers"));
+        report(
+            "llvm",
+            lli.or_else(|| run(Command::new("lli").arg("-opaque-pointers").arg(&ll))),
+        );
+        if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+            let mut elf = Vec::new();
+            emit_elf(ops, &options, &mut elf).unwrap();
+            let path = dir.join("program.elf");
+            fs::write(&path, elf).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            report("elf", run(&mut Command::new(&path)));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        results
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_backends_stop_off_the_tape() {
+        // A leftward MulAdd from cell 0, after some output and an input that
+        // leaves the cell as it was.
+        let ops = optimize(parse(b"+++.,[-<++>]").unwrap());
+        assert!(
+            ops.iter()
+                .any(|op| op.mul_add().is_some_and(|(offset, _)| offset < 0))
+        );
+        for (name, status, stdout, stderr) in run_backends(&ops) {
+            assert_eq!(status, Some(1), "{}: {}", name, stderr);
+            assert_eq!(stdout, b"\x03", "{}", name);
+            assert!(stderr.starts_with("error: "), "{}: {}", name, stderr);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! The [`Backend`] trait the code generators are written against, and
+//! [`emit_program`], which walks a program's ops through one.
+
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::Op;
+
+/// A target language: what is written at the start and end of a program,
+/// for each op, and at each end of a loop.
+///
+/// [`emit_program`] calls the methods in program order. The body of an
+/// [`Op::DefProc`] is skipped after [`op`](Self::op) is called on it; a
+/// backend that supports procedures writes the body itself, usually from
+/// [`end`](Self::end) with [`emit_ops`].
+pub trait Backend {
+    /// Writes what comes before the first op, or fails if the backend
+    /// can't compile `ops`.
+    fn begin(&mut self, ops: &[Op], out: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes the code of `ops[pc]`, which isn't an end of a loop.
+    fn op(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes the start of the loop whose [`Op::Jz`] is at `pc`.
+    fn loop_begin(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes the end of the loop whose [`Op::Jnz`] is at `pc`.
+    fn loop_end(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Writes what comes after the last op.
+    fn end(&mut self, ops: &[Op], out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Writes the whole of `ops` to `out` with `backend`.
+pub fn emit_program<B: Backend + ?Sized>(
+    backend: &mut B,
+    ops: &[Op],
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    backend.begin(ops, out)?;
+    emit_ops(backend, ops, 0..ops.len(), out)?;
+    backend.end(ops, out)
+}
+
+/// Writes the ops of `ops` in `range`, which must close every loop it
+/// opens, with `backend`, skipping the bodies of procedures.
+pub fn emit_ops<B: Backend + ?Sized>(
+    backend: &mut B,
+    ops: &[Op],
+    range: Range<usize>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut pc = range.start;
+    while pc < range.end {
+        match ops[pc] {
+            Op::Jz(_) => backend.loop_begin(ops, pc, out)?,
+            Op::Jnz(_) => backend.loop_end(ops, pc, out)?,
+            Op::DefProc(end) => {
+                backend.op(ops, pc, out)?;
+                pc = end + 1;
+                continue;
+            }
+            _ => backend.op(ops, pc, out)?,
+        }
+        pc += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimize, parse};
+
+    /// Writes loops as parentheses and every other op as its name.
+    struct Outline;
+
+    impl Backend for Outline {
+        fn begin(&mut self, _: &[Op], out: &mut dyn Write) -> io::Result<()> {
+            write!(out, "<")
+        }
+
+        fn op(&mut self, ops: &[Op], pc: usize, out: &mut dyn Write) -> io::Result<()> {
+            write!(out, " {}", crate::op_name(&ops[pc]))
+        }
+
+        fn loop_begin(&mut self, _: &[Op], _: usize, out: &mut dyn Write) -> io::Result<()> {
+            write!(out, " (")
+        }
+
+        fn loop_end(&mut self, _: &[Op], _: usize, out: &mut dyn Write) -> io::Result<()> {
+            write!(out, " )")
+        }
+
+        fn end(&mut self, _: &[Op], out: &mut dyn Write) -> io::Result<()> {
+            write!(out, " >")
+        }
+    }
+
+    #[test]
+    fn test_emit_program() {
+        let ops = optimize(parse(b",[>,[.,]<-]").unwrap());
+        let mut out = Vec::new();
+        emit_program(&mut Outline, &ops, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "< Input ( InputAt PtrAdd ( Output Input ) BulkAdd PtrAdd ) >"
+        );
+    }
+}
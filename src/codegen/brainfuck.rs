@@ -0,0 +1,250 @@
+use crate::Op;
+use crate::optimizer::Known;
+
+/// Lowers ops back to plain brainfuck, for using the optimizer as a
+/// brainfuck-to-brainfuck pass.
+///
+/// The result is correct for every cell width. Two ops need help from the
+/// code around them, which [`optimize`](crate::optimize) and
+/// [`partial_eval`](crate::partial_eval) always provide:
+///
+/// - a run of `MulAdd`s, `AddTo`s and `SubFrom`s becomes one `[-...]`
+///   loop, so it must be followed by a clear of the counter cell
+///   (`Clear(0)`, `Set(0, _)`, or a `BulkClear` or `ClearRange` of offset
+///   0);
+/// - `OutputConst` builds its bytes in the current cell and puts its value
+///   back afterwards, so the code before it must leave that value known, as
+///   straight-line code from the start of the program or a clear does.
+pub fn to_brainfuck(ops: &[Op]) -> String {
+    let mut out = Writer::default();
+    let mut known = Known::zeroed();
+    let mut i = 0;
+    while i < ops.len() {
+        let start = i;
+        match &ops[i] {
+            Op::PtrAdd(n) => out.shift(*n),
+            Op::ValAdd(offset, n) => out.add(*offset, *n),
+            Op::ValSub(offset, n) => out.add(*offset, n.wrapping_neg()),
+            Op::Output => out.at(0, "."),
+            Op::Input => out.at(0, ","),
+            Op::OutputAt(offset) => out.at(*offset, "."),
+            Op::OutputRepeat(offset, count) => out.at(*offset, &".".repeat(*count)),
+            Op::InputAt(offset) => out.at(*offset, ","),
+            Op::OutputConst(bytes) => {
+                // Track the exact value added so it can be taken away again;
+                // only its low byte is printed.
+                let base = known.get(0).unwrap_or(0) as u8;
+                let mut value = 0i64;
+                for &byte in bytes {
+                    let delta = byte.wrapping_sub(base.wrapping_add(value as u8)) as i8;
+                    value += i64::from(delta);
+                    out.add(0, delta as u32);
+                    out.at(0, ".");
+                }
+                out.add(0, value.wrapping_neg() as u32);
+            }
+            Op::Jz(_) => out.at(0, "["),
+            Op::Jnz(_) => out.at(0, "]"),
+            Op::Clear(offset) => out.at(*offset, "[-]"),
+            Op::Set(offset, n) => {
+                out.at(*offset, "[-]");
+                out.add(*offset, *n);
+            }
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) => {
+                let muls: Vec<(isize, u32)> = ops[i..].iter().map_while(Op::mul_add).collect();
+                out.move_loop(&muls);
+                i += muls.len();
+                // The loop leaves the counter at zero, so a clear of it that
+                // follows is redundant.
+                match ops.get(i) {
+                    Some(Op::Clear(0)) => i += 1,
+                    Some(Op::Set(0, n)) => {
+                        out.add(0, *n);
+                        i += 1;
+                    }
+                    Some(Op::BulkClear(offsets)) if offsets.contains(&0) => {
+                        for &offset in offsets.iter().filter(|&&offset| offset != 0) {
+                            out.at(offset, "[-]");
+                        }
+                        i += 1;
+                    }
+                    Some(&Op::ClearRange(offset, n))
+                        if (offset..offset + n as isize).contains(&0) =>
+                    {
+                        for offset in (offset..offset + n as isize).filter(|&offset| offset != 0) {
+                            out.at(offset, "[-]");
+                        }
+                        i += 1;
+                    }
+                    _ => {}
+                }
+                ops[start..i].iter().for_each(|op| known.apply(op));
+                continue;
+            }
+            Op::Transfer(targets) => out.move_loop(targets),
+            // The loop after it does the same.
+            Op::DivMod => {}
+            Op::Debug => out.at(0, "#"),
+            Op::Random => out.at(0, "?"),
+            Op::DefProc(_) => out.at(0, "("),
+            Op::EndProc => out.at(0, ")"),
+            Op::CallProc => out.at(0, ":"),
+            Op::Fork => out.at(0, "Y"),
+            Op::End => out.at(0, "@"),
+            Op::Store => out.at(0, "$"),
+            Op::Retrieve => out.at(0, "!"),
+            Op::Bitwise(bit) => out.at(0, &bit.command().to_string()),
+            Op::SwitchTape(n) => {
+                let step = if *n > 0 { "v" } else { "^" };
+                out.at(0, &step.repeat(n.unsigned_abs()));
+            }
+            Op::ScanLeft => out.at(0, "[<]"),
+            Op::ScanRight => out.at(0, "[>]"),
+            Op::Scan(stride) => {
+                let step = if *stride > 0 { ">" } else { "<" };
+                out.at(0, &format!("[{}]", step.repeat(stride.unsigned_abs())));
+            }
+            Op::BulkAdd(deltas) => {
+                for &(offset, n) in deltas {
+                    out.add(offset, n);
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for &offset in offsets {
+                    out.at(offset, "[-]");
+                }
+            }
+            Op::ClearRange(offset, n) => {
+                for offset in (0..*n as isize).map(|i| offset + i) {
+                    out.at(offset, "[-]");
+                }
+            }
+            Op::ClearUntilZero(stride) => {
+                let step = if *stride > 0 { ">" } else { "<" };
+                out.at(0, &format!("[[-]{}]", step.repeat(stride.unsigned_abs())));
+            }
+        }
+        known.apply(&ops[i]);
+        i += 1;
+    }
+    out.code
+}
+
+/// The step of the counter that writes the loop for `muls` shortest. A
+/// counter stepping by an odd `step` runs for its value times the negated
+/// inverse of `step` passes, so a `MulAdd` of `factor` adds `factor * -step`
+/// each pass. Move loops with a step other than -1 come back out with it.
+fn counter_step(muls: &[(isize, u32)]) -> u32 {
+    let size = |n: u32| u64::from(n.min(n.wrapping_neg()));
+    (1..=0xffu32)
+        .step_by(2)
+        .flat_map(|step| [step.wrapping_neg(), step])
+        .min_by_key(|&step| {
+            let adds: u64 = muls
+                .iter()
+                .map(|&(_, factor)| size(factor.wrapping_mul(step.wrapping_neg())))
+                .sum();
+            adds + size(step)
+        })
+        .unwrap_or(u32::MAX)
+}
+
+/// Brainfuck being written, with the pointer moved lazily: `pos` is where
+/// the written code has left the pointer, relative to where the ops expect
+/// it.
+#[derive(Default)]
+struct Writer {
+    code: String,
+    pos: isize,
+}
+
+impl Writer {
+    /// Moves the ops' pointer by `n` without writing anything yet.
+    fn shift(&mut self, n: isize) {
+        self.pos -= n;
+    }
+
+    /// Writes `commands` with the pointer on the cell `offset` away.
+    fn at(&mut self, offset: isize, commands: &str) {
+        let step = if offset > self.pos { '>' } else { '<' };
+        self.code
+            .extend(std::iter::repeat_n(step, offset.abs_diff(self.pos)));
+        self.pos = offset;
+        self.code.push_str(commands);
+    }
+
+    /// Writes the loop that adds the current cell times each factor of
+    /// `muls` to the cell at its offset, and leaves it zero.
+    fn move_loop(&mut self, muls: &[(isize, u32)]) {
+        let step = counter_step(muls);
+        self.at(0, "[");
+        self.add(0, step);
+        for &(offset, factor) in muls {
+            self.add(offset, factor.wrapping_mul(step.wrapping_neg()));
+        }
+        self.at(0, "]");
+    }
+
+    /// Adds `n`, modulo 2^32, to the cell `offset` away, counting down
+    /// instead of up when that is shorter.
+    fn add(&mut self, offset: isize, n: u32) {
+        if n == 0 {
+            return;
+        }
+        let commands = if n <= u32::MAX / 2 {
+            "+".repeat(n as usize)
+        } else {
+            "-".repeat(n.wrapping_neg() as usize)
+        };
+        self.at(offset, &commands);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{OptLevel, execute, optimize, optimize_with, parse};
+
+    fn run(ops: &[Op]) -> Vec<u8> {
+        let mut output = Vec::new();
+        execute(ops, &b"xyz"[..], &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sources: [&[u8]; 7] = [
+            include_bytes!("../../examples/helloworld.bf"),
+            b"+++[->++>+++<<]>.>.<<,[.-]>>[<]",
+            b",>,>,<<[.>]",
+            b",[--->++>+<<]>.>.",
+            b",[->>>>+<<<<]>[-]>[-]>[-]>.",
+            b"+>+>+>,<<<[[-]>]<.",
+            b",..>,...<.",
+        ];
+        for source in sources {
+            let ops = optimize_with(parse(source).unwrap(), OptLevel::O2);
+            let lowered = to_brainfuck(&ops);
+            let reparsed = parse(lowered.as_bytes()).unwrap();
+            assert_eq!(run(&reparsed), run(&ops), "{}", lowered);
+        }
+        // A move loop with a step of 3 comes back out with it.
+        let ops = optimize(parse(b",[--->++<]").unwrap());
+        assert!(to_brainfuck(&ops).contains("[--->++<]"));
+    }
+
+    #[test]
+    fn test_output_const() {
+        let lowered = to_brainfuck(&[Op::OutputConst(b"AB\xff".to_vec()), Op::Output]);
+        let ops = parse(lowered.as_bytes()).unwrap();
+        assert_eq!(run(&ops), b"AB\xff\0");
+    }
+
+    #[test]
+    fn test_move_loop() {
+        let ops = [Op::MulAdd(2, 3), Op::MulAdd(-1, 1), Op::Set(0, 4)];
+        assert_eq!(to_brainfuck(&ops), "[->>+++<<<+>]++++");
+        let ops = [Op::Transfer(vec![(2, 3), (-1, 1)])];
+        assert_eq!(to_brainfuck(&ops), "[->>+++<<<+>]");
+    }
+}
@@ -0,0 +1,494 @@
+//! The C backend.
+//!
+//! Output is plain C99 that depends only on the standard library. Under
+//! [`BoundsPolicy::Error`] every cell the program touches goes through
+//! `at`, which exits with status 1 and an error off the tape, as the Rust
+//! backend's `check` does; under [`BoundsPolicy::Unchecked`] walking off
+//! the tape is undefined behavior, as it is for most C brainfuck compilers.
+//! The tape is a fixed array, so [`BoundsPolicy::Clamp`],
+//! [`BoundsPolicy::Grow`] and [`BoundsPolicy::Bidirectional`] aren't
+//! supported.
+
+use std::io::{self, Write};
+
+use super::{CodegenOptions, eof_value};
+use crate::cell::CellWidth;
+use crate::interpreter::BoundsPolicy;
+use crate::{BitOp, Op, uses_forks, uses_procedures, uses_random, uses_storage, uses_tapes};
+
+/// C expression for the tape index `offset` cells from `ptr`.
+fn index(offset: isize, options: &CodegenOptions) -> String {
+    match options.bounds {
+        _ if offset == 0 => "ptr".to_string(),
+        BoundsPolicy::Wrap => {
+            let len = options.tape_size as isize;
+            format!("(ptr + {}) % {}", offset.rem_euclid(len), len)
+        }
+        _ if offset < 0 => format!("ptr - {}", offset.unsigned_abs()),
+        _ => format!("ptr + {}", offset),
+    }
+}
+
+/// C expression for the cell `offset` away from `ptr`, as the op at `pc`
+/// touches it.
+fn cell_at(offset: isize, pc: usize, options: &CodegenOptions) -> String {
+    match options.bounds {
+        BoundsPolicy::Error => format!("tape[at({}, {})]", index(offset, options), pc),
+        _ => format!("tape[{}]", index(offset, options)),
+    }
+}
+
+fn c_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    }
+}
+
+/// A C string literal for `bytes`. Octal escapes, unlike `\x`, can't
+/// swallow the digits after them.
+fn string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' | b'?' => {
+                literal.push('\\');
+                literal.push(b as char);
+            }
+            b'\n' => literal.push_str("\\n"),
+            b' '..=b'~' => literal.push(b as char),
+            _ => literal.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn emit_input<W: Write>(
+    out: &mut W,
+    offset: isize,
+    pc: usize,
+    options: &CodegenOptions,
+) -> io::Result<()> {
+    let cell = cell_at(offset, pc, options);
+    writeln!(out, "    fflush(stdout);")?;
+    writeln!(out, "    c = getchar();")?;
+    match eof_value(options) {
+        None => writeln!(out, "    if (c != EOF) {} = c;", cell),
+        Some(v) => writeln!(out, "    {} = c != EOF ? c : {}u;", cell, v),
+    }
+}
+
+/// Writes the function every cell goes through under
+/// [`BoundsPolicy::Error`], which exits like the Rust backend's
+/// `out_of_bounds` when the op at `op` touches `idx` off the tape.
+fn emit_bounds_check<W: Write>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    writeln!(out, "static size_t at(size_t idx, size_t op) {{")?;
+    writeln!(out, "    if (idx >= {}) {{", options.tape_size)?;
+    writeln!(out, "        fflush(stdout);")?;
+    writeln!(
+        out,
+        "        fprintf(stderr, \"error: op %zu touched a cell outside the tape\\n\", op);"
+    )?;
+    writeln!(out, "        exit(1);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    return idx;")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes the function `Op::Debug` calls, which prints the same line as
+/// the interpreter does.
+fn emit_debug_dump<W: Write>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let len = options.tape_size;
+    writeln!(out, "static void debug_dump(size_t op, size_t ptr) {{")?;
+    writeln!(out, "    size_t start = ptr >= 8 ? ptr - 8 : 0;")?;
+    let last_start = len.saturating_sub(16);
+    writeln!(out, "    if (start > {0}) start = {0};", last_start)?;
+    writeln!(
+        out,
+        "    size_t end = start + 16 < {0} ? start + 16 : {0};",
+        len
+    )?;
+    writeln!(out, "    fflush(stdout);")?;
+    writeln!(
+        out,
+        "    fprintf(stderr, \"# op %zu, ptr %zu, cells %zu..%zu:\", op, ptr, start, end);"
+    )?;
+    writeln!(out, "    for (size_t i = start; i < end; i++)")?;
+    writeln!(
+        out,
+        "        fprintf(stderr, i == ptr ? \" [%lu]\" : \" %lu\", (unsigned long)tape[i]);"
+    )?;
+    writeln!(out, "    fputc('\\n', stderr);")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes the function `Op::DivMod` calls, which does what
+/// [`div_mod`](crate::div_mod) does.
+fn emit_div_mod<W: Write>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let cell = c_type(options.cell_width);
+    writeln!(out, "static void div_mod({} *c) {{", cell)?;
+    writeln!(
+        out,
+        "    uint64_t turns = c[0], left = c[2], moved = c[3], divisor = left + moved;"
+    )?;
+    writeln!(
+        out,
+        "    if (turns == 0 || left == 0 || divisor < 2 || divisor > {}u || c[5] || c[6]) return;",
+        options.cell_width.truncate(u32::MAX)
+    )?;
+    writeln!(out, "    if (turns < left) {{")?;
+    writeln!(out, "        c[2] = ({})(left - turns);", cell)?;
+    writeln!(out, "        c[3] = ({})(moved + turns);", cell)?;
+    writeln!(out, "    }} else {{")?;
+    writeln!(out, "        uint64_t past = turns - left;")?;
+    writeln!(out, "        c[2] = ({})(divisor - past % divisor);", cell)?;
+    writeln!(out, "        c[3] = ({})(past % divisor);", cell)?;
+    writeln!(out, "        c[4] = ({})(c[4] + 1 + past / divisor);", cell)?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    c[1] = ({})(c[1] + turns);", cell)?;
+    writeln!(out, "    c[0] = 0;")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes a standalone C program equivalent to `ops` to `out`.
+pub fn emit_c<W: Write>(ops: &[Op], options: &CodegenOptions, out: &mut W) -> io::Result<()> {
+    if matches!(
+        options.bounds,
+        BoundsPolicy::Clamp | BoundsPolicy::Grow | BoundsPolicy::Bidirectional
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the C backend only supports the error, wrap and unchecked bounds policies",
+        ));
+    }
+    if uses_procedures(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the C backend doesn't support pbrain procedures",
+        ));
+    }
+    if uses_forks(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the C backend doesn't support Brainfork's forks",
+        ));
+    }
+    if uses_tapes(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the C backend doesn't support switching tapes",
+        ));
+    }
+    if uses_random(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the C backend doesn't support '?', the random byte",
+        ));
+    }
+    let cell = options.cell_width;
+    writeln!(out, "#include <stdint.h>")?;
+    writeln!(out, "#include <stdio.h>")?;
+    // A fully evaluated program only prints, and needs no tape.
+    let needs_tape = !ops.iter().all(|op| matches!(op, Op::OutputConst(_)));
+    let checked = needs_tape && options.bounds == BoundsPolicy::Error;
+    if checked {
+        writeln!(out, "#include <stdlib.h>")?;
+    }
+    if ops
+        .iter()
+        .any(|op| matches!(op, Op::ClearRange(..) | Op::OutputRepeat(..)))
+    {
+        writeln!(out, "#include <string.h>")?;
+    }
+    writeln!(out)?;
+    if needs_tape {
+        writeln!(out, "static {} tape[{}];", c_type(cell), options.tape_size)?;
+        writeln!(out)?;
+    }
+    if checked {
+        emit_bounds_check(out, options)?;
+    }
+    if ops.contains(&Op::Debug) {
+        emit_debug_dump(out, options)?;
+    }
+    if ops.contains(&Op::DivMod) {
+        emit_div_mod(out, options)?;
+    }
+    writeln!(out, "int main(void) {{")?;
+    if needs_tape {
+        writeln!(out, "    size_t ptr = 0;")?;
+        writeln!(out, "    int c;")?;
+        writeln!(out, "    (void)c;")?;
+    }
+    if uses_storage(ops) {
+        writeln!(out, "    {} storage = 0;", c_type(cell))?;
+        writeln!(out, "    (void)storage;")?;
+    }
+
+    for (pc, op) in ops.iter().enumerate() {
+        // The current cell.
+        let here = cell_at(0, pc, options);
+        match op {
+            Op::PtrAdd(n) => writeln!(out, "    ptr = {};", index(*n, options))?,
+            Op::ValAdd(offset, n) => {
+                let target = cell_at(*offset, pc, options);
+                writeln!(out, "    {} += {}u;", target, cell.truncate(*n))?;
+            }
+            Op::ValSub(offset, n) => {
+                let target = cell_at(*offset, pc, options);
+                writeln!(out, "    {} -= {}u;", target, cell.truncate(*n))?;
+            }
+            Op::Output => writeln!(out, "    putchar((unsigned char){});", here)?,
+            Op::OutputAt(offset) => writeln!(
+                out,
+                "    putchar((unsigned char){});",
+                cell_at(*offset, pc, options)
+            )?,
+            Op::OutputRepeat(offset, count) => {
+                writeln!(out, "    {{")?;
+                writeln!(out, "        unsigned char run[{}];", count)?;
+                writeln!(
+                    out,
+                    "        memset(run, (unsigned char){}, sizeof run);",
+                    cell_at(*offset, pc, options)
+                )?;
+                writeln!(out, "        fwrite(run, 1, sizeof run, stdout);")?;
+                writeln!(out, "    }}")?;
+            }
+            Op::OutputConst(bytes) => writeln!(
+                out,
+                "    fwrite({}, 1, {}, stdout);",
+                string_literal(bytes),
+                bytes.len()
+            )?,
+            Op::Input => emit_input(out, 0, pc, options)?,
+            Op::InputAt(offset) => emit_input(out, *offset, pc, options)?,
+            Op::Jz(_) => writeln!(out, "    while ({}) {{", here)?,
+            Op::Jnz(_) => writeln!(out, "    }}")?,
+            Op::Clear(offset) => writeln!(out, "    {} = 0;", cell_at(*offset, pc, options))?,
+            Op::Set(offset, n) => {
+                let target = cell_at(*offset, pc, options);
+                writeln!(out, "    {} = {}u;", target, cell.truncate(*n))?;
+            }
+            Op::MulAdd(offset, factor) => {
+                // Multiply as uint32_t: narrower cells would be promoted to
+                // int, where overflow is undefined. The target is only
+                // touched when there is something to add, as in the
+                // interpreter, since it may be off the tape otherwise.
+                writeln!(
+                    out,
+                    "    if ({0}) {1} += (uint32_t){0} * {2}u;",
+                    here,
+                    cell_at(*offset, pc, options),
+                    cell.truncate(*factor)
+                )?;
+            }
+            Op::AddTo(offset) => writeln!(
+                out,
+                "    if ({0}) {1} += {0};",
+                here,
+                cell_at(*offset, pc, options)
+            )?,
+            Op::SubFrom(offset) => writeln!(
+                out,
+                "    if ({0}) {1} -= {0};",
+                here,
+                cell_at(*offset, pc, options)
+            )?,
+            Op::Transfer(targets) => {
+                writeln!(out, "    if ({}) {{", here)?;
+                writeln!(out, "        uint32_t value = {};", here)?;
+                for &(offset, factor) in targets {
+                    writeln!(
+                        out,
+                        "        {} += value * {}u;",
+                        cell_at(offset, pc, options),
+                        cell.truncate(factor)
+                    )?;
+                }
+                writeln!(out, "        {} = 0;", here)?;
+                writeln!(out, "    }}")?;
+            }
+            Op::ScanLeft => writeln!(out, "    while ({}) ptr = {};", here, index(-1, options))?,
+            Op::ScanRight => writeln!(out, "    while ({}) ptr = {};", here, index(1, options))?,
+            Op::Scan(stride) => writeln!(
+                out,
+                "    while ({}) ptr = {};",
+                here,
+                index(*stride, options)
+            )?,
+            Op::BulkAdd(deltas) => {
+                for (offset, n) in deltas {
+                    let target = cell_at(*offset, pc, options);
+                    writeln!(out, "    {} += {}u;", target, cell.truncate(*n))?;
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for offset in offsets {
+                    writeln!(out, "    {} = 0;", cell_at(*offset, pc, options))?;
+                }
+            }
+            Op::ClearRange(offset, n) => match options.bounds {
+                BoundsPolicy::Wrap => {
+                    for offset in (0..*n as isize).map(|i| offset + i) {
+                        writeln!(out, "    tape[{}] = 0;", index(offset, options))?;
+                    }
+                }
+                // Both ends are checked before anything is cleared.
+                BoundsPolicy::Error => {
+                    let last = offset + *n as isize - 1;
+                    writeln!(out, "    (void)at({}, {});", index(last, options), pc)?;
+                    writeln!(
+                        out,
+                        "    memset(tape + at({}, {}), 0, {} * sizeof tape[0]);",
+                        index(*offset, options),
+                        pc,
+                        n
+                    )?;
+                }
+                _ => writeln!(
+                    out,
+                    "    memset(tape + {}, 0, {} * sizeof tape[0]);",
+                    index(*offset, options),
+                    n
+                )?,
+            },
+            Op::ClearUntilZero(stride) => writeln!(
+                out,
+                "    while ({0}) {{ {0} = 0; ptr = {1}; }}",
+                here,
+                index(*stride, options)
+            )?,
+            Op::Debug => writeln!(out, "    debug_dump({}, ptr);", pc)?,
+            // Does nothing off the tape, where the loop after it runs.
+            Op::DivMod => {
+                if let Some(last) = options.tape_size.checked_sub(7) {
+                    writeln!(out, "    if (ptr <= {}) div_mod(tape + ptr);", last)?;
+                }
+            }
+            // Rejected above.
+            Op::DefProc(_)
+            | Op::EndProc
+            | Op::CallProc
+            | Op::Fork
+            | Op::SwitchTape(_)
+            | Op::Random => {}
+            Op::End => writeln!(out, "    return 0;")?,
+            Op::Store => writeln!(out, "    storage = {};", here)?,
+            Op::Retrieve => writeln!(out, "    {} = storage;", here)?,
+            Op::Bitwise(bit) => {
+                let code = match bit {
+                    BitOp::ShiftLeft => format!("{} <<= 1;", here),
+                    BitOp::ShiftRight => format!("{} >>= 1;", here),
+                    BitOp::Not => format!("{0} = ~{0};", here),
+                    BitOp::Xor => format!("{} ^= storage;", here),
+                    BitOp::And => format!("{} &= storage;", here),
+                    BitOp::Or => format!("{} |= storage;", here),
+                };
+                writeln!(out, "    {}", code)?;
+            }
+        }
+    }
+
+    writeln!(out, "    return 0;")?;
+    writeln!(out, "}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseOptions, optimize, parse, parse_with};
+
+    fn emit(code: &[u8], options: &CodegenOptions) -> String {
+        let ops = optimize(parse(code).unwrap());
+        let mut out = Vec::new();
+        emit_c(&ops, options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_emit_c_program() {
+        let src = emit(b"+[>,.<-]<[-]", &CodegenOptions::default());
+        assert!(src.contains("static uint8_t tape[30000];"));
+        assert!(src.contains("static size_t at(size_t idx, size_t op) {"));
+        assert!(src.contains("while (tape[at(ptr, 1)]) {"));
+        assert!(src.contains("if (c != EOF) tape[at(ptr + 1, 2)] = c;"));
+        assert!(src.contains("tape[at(ptr - 1, 6)] = 0;"));
+        assert_eq!(src.matches('{').count(), src.matches('}').count());
+
+        let options = CodegenOptions {
+            bounds: BoundsPolicy::Unchecked,
+            ..CodegenOptions::default()
+        };
+        let src = emit(b"+[>,.<-]<[-]", &options);
+        assert!(src.contains("if (c != EOF) tape[ptr + 1] = c;"));
+        assert!(!src.contains("at("));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_c_stops_off_the_tape() {
+        use std::process::{Command, Stdio};
+
+        let dir = std::env::temp_dir().join(format!("bf-c-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, binary) = (dir.join("oob.c"), dir.join("oob"));
+        std::fs::write(&source, emit(b"+.<-[>+<-]", &CodegenOptions::default())).unwrap();
+        // Without a C compiler there is nothing to run.
+        let Ok(status) = Command::new("cc")
+            .arg(&source)
+            .arg("-o")
+            .arg(&binary)
+            .status()
+        else {
+            return;
+        };
+        assert!(status.success());
+        let output = Command::new(&binary).stdin(Stdio::null()).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(output.stdout, b"\x01");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "error: op 2 touched a cell outside the tape\n"
+        );
+    }
+
+    #[test]
+    fn test_emit_c_wide_wrap() {
+        let options = CodegenOptions {
+            tape_size: 100,
+            bounds: BoundsPolicy::Wrap,
+            cell_width: CellWidth::U16,
+            ..CodegenOptions::default()
+        };
+        let src = emit(b",[<+>-]", &options);
+        assert!(src.contains("static uint16_t tape[100];"));
+        assert!(src.contains("if (tape[ptr]) tape[(ptr + 99) % 100] += tape[ptr];"));
+    }
+
+    #[test]
+    fn test_emit_c_debug() {
+        let parse_options = ParseOptions {
+            debug_char: true,
+            ..ParseOptions::default()
+        };
+        let ops = parse_with(b"+#", &parse_options).unwrap();
+        let mut out = Vec::new();
+        emit_c(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("static void debug_dump(size_t op, size_t ptr) {"));
+        assert!(src.contains("    debug_dump(1, ptr);\n"));
+        assert!(!emit(b"+", &CodegenOptions::default()).contains("debug_dump"));
+    }
+
+    #[test]
+    fn test_string_literal() {
+        assert_eq!(string_literal(b"a\"?\n\x01"), r#""a\"\?\n\001""#);
+    }
+}
@@ -0,0 +1,334 @@
+//! The ELF backend: a static x86-64 Linux executable, with no assembler or
+//! linker involved.
+//!
+//! The program is lowered by the same code generator as the JIT, so tape
+//! accesses are bounds-checked. In place of the JIT's Rust callbacks, the
+//! binary carries small I/O routines that make `read` and `write` system
+//! calls directly, one per byte except for constant output.
+//!
+//! The file holds a read-only, executable segment with the headers, the I/O
+//! routines, the constant output and the code, followed by a zero-filled
+//! writable segment for the tape.
+
+use std::io::{self, Write};
+
+use super::{CodegenOptions, eof_value};
+use crate::Op;
+use crate::cell::CellWidth;
+use crate::interpreter::BoundsPolicy;
+use crate::x86::asm::{Assembler, Cond};
+use crate::x86::{self, Runtime, STATUS_OUT_OF_BOUNDS};
+
+/// Where the executable segment is loaded.
+const BASE: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const PHDR_COUNT: u64 = 3;
+const HEADERS_SIZE: u64 = EHDR_SIZE + PHDR_COUNT * PHDR_SIZE;
+
+/// Printed when the program leaves the tape.
+const OUT_OF_BOUNDS_MESSAGE: &[u8] = b"error: data pointer left the tape\n";
+
+/// `mov eax, imm`, `mov edi, imm` and `mov edx, imm`.
+fn mov_imm(asm: &mut Assembler, opcode: u8, imm: u32) {
+    asm.bytes(&[opcode]);
+    asm.bytes(&imm.to_le_bytes());
+}
+
+const EAX: u8 = 0xb8;
+const EDI: u8 = 0xbf;
+const EDX: u8 = 0xba;
+const SYS_WRITE: u32 = 1;
+const SYS_EXIT: u32 = 60;
+
+/// `put_cell(ctx, cell)`: writes the low byte of the cell to stdout.
+fn put_cell() -> Vec<u8> {
+    let mut asm = Assembler::new();
+    mov_imm(&mut asm, EAX, SYS_WRITE);
+    mov_imm(&mut asm, EDI, 1);
+    mov_imm(&mut asm, EDX, 1);
+    asm.bytes(&[0x0f, 0x05]); // syscall
+    asm.bytes(&[0x48, 0x83, 0xf8, 0x01]); // cmp rax, 1
+    asm.bytes(&[0x0f, 0x95, 0xc0]); // setne al
+    asm.bytes(&[0x0f, 0xb6, 0xc0]); // movzx eax, al
+    asm.bytes(&[0xc3]);
+    asm.finish()
+}
+
+/// `put_bytes(ctx, data, len)`: writes `len` bytes to stdout.
+fn put_bytes() -> Vec<u8> {
+    let mut asm = Assembler::new();
+    let top = asm.new_label();
+    let done = asm.new_label();
+    let fail = asm.new_label();
+    asm.bind(top);
+    asm.bytes(&[0x48, 0x85, 0xd2]); // test rdx, rdx
+    asm.jcc(Cond::Equal, done);
+    mov_imm(&mut asm, EAX, SYS_WRITE);
+    mov_imm(&mut asm, EDI, 1);
+    asm.bytes(&[0x0f, 0x05]); // syscall
+    asm.bytes(&[0x48, 0x85, 0xc0]); // test rax, rax
+    asm.jcc(Cond::LessEqual, fail);
+    asm.bytes(&[0x48, 0x01, 0xc6]); // add rsi, rax
+    asm.bytes(&[0x48, 0x29, 0xc2]); // sub rdx, rax
+    asm.jmp(top);
+    asm.bind(done);
+    asm.bytes(&[0x31, 0xc0, 0xc3]); // xor eax, eax; ret
+    asm.bind(fail);
+    mov_imm(&mut asm, EAX, 1);
+    asm.bytes(&[0xc3]);
+    asm.finish()
+}
+
+/// `put_repeat(ctx, cell, count)`: writes the low byte of the cell to
+/// stdout `count` times.
+fn put_repeat() -> Vec<u8> {
+    let mut asm = Assembler::new();
+    let top = asm.new_label();
+    let done = asm.new_label();
+    let fail = asm.new_label();
+    asm.bytes(&[0x49, 0x89, 0xd0]); // mov r8, rdx
+    asm.bind(top);
+    asm.bytes(&[0x4d, 0x85, 0xc0]); // test r8, r8
+    asm.jcc(Cond::Equal, done);
+    mov_imm(&mut asm, EAX, SYS_WRITE);
+    mov_imm(&mut asm, EDI, 1);
+    mov_imm(&mut asm, EDX, 1);
+    asm.bytes(&[0x0f, 0x05]); // syscall
+    asm.bytes(&[0x48, 0x83, 0xf8, 0x01]); // cmp rax, 1
+    asm.jcc(Cond::NotEqual, fail);
+    asm.bytes(&[0x49, 0xff, 0xc8]); // dec r8
+    asm.jmp(top);
+    asm.bind(done);
+    asm.bytes(&[0x31, 0xc0, 0xc3]); // xor eax, eax; ret
+    asm.bind(fail);
+    mov_imm(&mut asm, EAX, 1);
+    asm.bytes(&[0xc3]);
+    asm.finish()
+}
+
+/// `get_cell(ctx, cell)`: reads a byte from stdin into the cell, storing
+/// `eof` (if any) at end of input.
+fn get_cell(width: CellWidth, eof: Option<u32>) -> Vec<u8> {
+    // Operand-size prefix (if any) and REX.B prefix for `[r8]` operands.
+    let prefix: &[u8] = match width {
+        CellWidth::U16 => &[0x66, 0x41],
+        _ => &[0x41],
+    };
+    let wide = width != CellWidth::U8;
+    let mut asm = Assembler::new();
+    let store = asm.new_label();
+    let fail = asm.new_label();
+    asm.bytes(&[0x49, 0x89, 0xf0]); // mov r8, rsi
+    asm.bytes(&[0x50]); // push rax, as a one-byte buffer
+    asm.bytes(&[0x31, 0xc0]); // xor eax, eax: SYS_READ
+    asm.bytes(&[0x31, 0xff]); // xor edi, edi
+    asm.bytes(&[0x48, 0x89, 0xe6]); // mov rsi, rsp
+    mov_imm(&mut asm, EDX, 1);
+    asm.bytes(&[0x0f, 0x05]); // syscall
+    asm.bytes(&[0x0f, 0xb6, 0x0c, 0x24]); // movzx ecx, byte [rsp]
+    asm.bytes(&[0x5a]); // pop rdx
+    asm.bytes(&[0x48, 0x83, 0xf8, 0x01]); // cmp rax, 1
+    asm.jcc(Cond::Equal, store);
+    asm.bytes(&[0x48, 0x85, 0xc0]); // test rax, rax
+    asm.jcc(Cond::NotEqual, fail);
+    if let Some(v) = eof {
+        // mov cell [r8], v
+        asm.bytes(prefix);
+        asm.bytes(&[if wide { 0xc7 } else { 0xc6 }, 0x00]);
+        match width {
+            CellWidth::U8 => asm.bytes(&[v as u8]),
+            CellWidth::U16 => asm.bytes(&(v as u16).to_le_bytes()),
+            CellWidth::U32 => asm.bytes(&v.to_le_bytes()),
+        }
+    }
+    asm.bytes(&[0x31, 0xc0, 0xc3]); // xor eax, eax; ret
+    asm.bind(store);
+    // mov cell [r8], cl/cx/ecx
+    asm.bytes(prefix);
+    asm.bytes(&[if wide { 0x89 } else { 0x88 }, 0x08]);
+    asm.bytes(&[0x31, 0xc0, 0xc3]); // xor eax, eax; ret
+    asm.bind(fail);
+    mov_imm(&mut asm, EAX, 1);
+    asm.bytes(&[0xc3]);
+    asm.finish()
+}
+
+/// The entry point: runs the program on the tape and exits with its status.
+fn start(main: u64, ctx: u64, tape: u64, len: u64, message: u64) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    let exit = asm.new_label();
+    asm.bytes(&[0x48, 0xbf]); // mov rdi, tape
+    asm.bytes(&tape.to_le_bytes());
+    asm.bytes(&[0x31, 0xf6]); // xor esi, esi
+    asm.bytes(&[0x48, 0xba]); // mov rdx, len
+    asm.bytes(&len.to_le_bytes());
+    asm.bytes(&[0x48, 0xb9]); // mov rcx, ctx
+    asm.bytes(&ctx.to_le_bytes());
+    asm.bytes(&[0x48, 0xb8]); // mov rax, main
+    asm.bytes(&main.to_le_bytes());
+    asm.bytes(&[0xff, 0xd0]); // call rax
+    asm.bytes(&[0x89, 0xc7]); // mov edi, eax
+    asm.bytes(&[0x83, 0xf8, STATUS_OUT_OF_BOUNDS as u8]); // cmp eax, STATUS_OUT_OF_BOUNDS
+    asm.jcc(Cond::NotEqual, exit);
+    mov_imm(&mut asm, EAX, SYS_WRITE);
+    mov_imm(&mut asm, EDI, 2);
+    asm.bytes(&[0x48, 0xbe]); // mov rsi, message
+    asm.bytes(&message.to_le_bytes());
+    mov_imm(&mut asm, EDX, OUT_OF_BOUNDS_MESSAGE.len() as u32);
+    asm.bytes(&[0x0f, 0x05]); // syscall
+    mov_imm(&mut asm, EDI, 1);
+    asm.bind(exit);
+    mov_imm(&mut asm, EAX, SYS_EXIT);
+    asm.bytes(&[0x0f, 0x05]); // syscall
+    asm.finish()
+}
+
+fn program_header(
+    out: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    vaddr: u64,
+    file_size: u64,
+    mem_size: u64,
+) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    // Both segments start at file offset 0; the tape's has no file data.
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&vaddr.to_le_bytes());
+    out.extend_from_slice(&vaddr.to_le_bytes());
+    out.extend_from_slice(&file_size.to_le_bytes());
+    out.extend_from_slice(&mem_size.to_le_bytes());
+    out.extend_from_slice(&PAGE.to_le_bytes());
+}
+
+/// Writes a static x86-64 Linux executable equivalent to `ops` to `out`.
+/// Only [`BoundsPolicy::Error`] is supported; [`BoundsPolicy::Unchecked`]
+/// gets the same checks, which native code can't do without.
+pub fn emit_elf<W: Write>(ops: &[Op], options: &CodegenOptions, out: &mut W) -> io::Result<()> {
+    if !matches!(
+        options.bounds,
+        BoundsPolicy::Error | BoundsPolicy::Unchecked
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the ELF backend only supports a fixed tape",
+        ));
+    }
+    let width = options.cell_width;
+
+    // The routines, then the constant output and the message.
+    let routines = [
+        put_cell(),
+        put_bytes(),
+        put_repeat(),
+        get_cell(width, eof_value(options)),
+    ];
+    let mut addrs = [0; 4];
+    let mut text = Vec::new();
+    for (routine, addr) in routines.iter().zip(&mut addrs) {
+        *addr = BASE + HEADERS_SIZE + text.len() as u64;
+        text.extend_from_slice(routine);
+    }
+    let runtime = Runtime {
+        put_cell: addrs[0],
+        put_bytes: addrs[1],
+        put_repeat: addrs[2],
+        get_cell: addrs[3],
+    };
+    let message = BASE + HEADERS_SIZE + text.len() as u64;
+    text.extend_from_slice(OUT_OF_BOUNDS_MESSAGE);
+    let mut consts = Vec::new();
+    for op in ops {
+        if let Op::OutputConst(bytes) = op {
+            consts.push(BASE + HEADERS_SIZE + text.len() as u64);
+            text.extend_from_slice(bytes);
+        }
+    }
+
+    let mut consts = consts.into_iter();
+    let main = BASE + HEADERS_SIZE + text.len() as u64;
+    let code = x86::compile(ops, width, &runtime, &mut |_| consts.next().unwrap_or(0))?;
+    text.extend_from_slice(&code);
+
+    // The entry point's size doesn't depend on the addresses in it, so
+    // assemble it once to find where the tape goes.
+    let entry = BASE + HEADERS_SIZE + text.len() as u64;
+    let file_size = HEADERS_SIZE + text.len() as u64 + start(0, 0, 0, 0, 0).len() as u64;
+    let data = (BASE + file_size).div_ceil(PAGE) * PAGE;
+    // Two words for the context the code stores the data pointer and the
+    // failing op to, then the tape.
+    let tape = data + 16;
+    let tape_bytes = options.tape_size as u64 * (width.bits() / 8) as u64;
+    text.extend_from_slice(&start(main, data, tape, options.tape_size as u64, message));
+
+    let mut file = Vec::with_capacity(file_size as usize);
+    file.extend_from_slice(b"\x7fELF");
+    file.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V
+    file.extend_from_slice(&[0; 8]);
+    file.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    file.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&entry.to_le_bytes());
+    file.extend_from_slice(&EHDR_SIZE.to_le_bytes()); // e_phoff
+    file.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    file.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    file.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(PHDR_COUNT as u16).to_le_bytes());
+    file.extend_from_slice(&[0; 6]); // no section headers
+    // PT_LOAD (R+X) for the whole file, PT_LOAD (R+W) for the tape and
+    // PT_GNU_STACK to keep the stack non-executable.
+    program_header(&mut file, 1, 5, BASE, file_size, file_size);
+    program_header(&mut file, 1, 6, data, 0, 16 + tape_bytes);
+    program_header(&mut file, 0x6474_e551, 6, 0, 0, 0);
+    file.extend_from_slice(&text);
+    out.write_all(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit_elf_header() {
+        let mut out = Vec::new();
+        emit_elf(&[Op::Output], &CodegenOptions::default(), &mut out).unwrap();
+        assert_eq!(&out[..4], b"\x7fELF");
+        let entry = u64::from_le_bytes(out[24..32].try_into().unwrap());
+        assert!(entry > BASE + HEADERS_SIZE && entry < BASE + out.len() as u64);
+        let file_size = u64::from_le_bytes(out[96..104].try_into().unwrap());
+        assert_eq!(file_size, out.len() as u64);
+    }
+
+    #[test]
+    fn test_emit_elf_rejects_wrap() {
+        let options = CodegenOptions {
+            bounds: BoundsPolicy::Wrap,
+            ..CodegenOptions::default()
+        };
+        let err = emit_elf(&[], &options, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_elf_runs() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::{Command, Stdio};
+
+        let code = std::fs::read("examples/helloworld.bf").unwrap();
+        let ops = crate::optimize(crate::parse(&code).unwrap());
+        let path = std::env::temp_dir().join(format!("bf-elf-test-{}", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        emit_elf(&ops, &CodegenOptions::default(), &mut file).unwrap();
+        drop(file);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let output = Command::new(&path).stdin(Stdio::null()).output().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"Hello, World!");
+    }
+}
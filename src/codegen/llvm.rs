@@ -0,0 +1,825 @@
+//! The LLVM IR backend.
+//!
+//! Output is a textual `.ll` module for LLVM 15 or newer (opaque pointers)
+//! that `clang` or `llc` can compile. Loops become basic blocks that
+//! re-test the current cell at their head. The data pointer lives in an
+//! `alloca` and cells are plain loads and stores, so there are no phi nodes
+//! to build; LLVM's `mem2reg` promotes the pointer to a register. As in the
+//! C backend, under [`BoundsPolicy::Error`] every cell index goes through
+//! `@at`, which exits with status 1 and an error off the tape, and
+//! [`BoundsPolicy::Clamp`], [`BoundsPolicy::Grow`] and
+//! [`BoundsPolicy::Bidirectional`] aren't supported.
+
+use std::io::{self, Write};
+
+use super::{CodegenOptions, eof_value};
+use crate::cell::CellWidth;
+use crate::interpreter::BoundsPolicy;
+use crate::{Op, uses_extended1, uses_forks, uses_procedures, uses_random, uses_tapes};
+
+fn int_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "i8",
+        CellWidth::U16 => "i16",
+        CellWidth::U32 => "i32",
+    }
+}
+
+/// An LLVM string constant body (`c"..."`) for `bytes`.
+fn string_constant(bytes: &[u8]) -> String {
+    let mut literal = String::from("c\"");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => literal.push_str(&format!("\\{:02X}", b)),
+            b' '..=b'~' => literal.push(b as char),
+            _ => literal.push_str(&format!("\\{:02X}", b)),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+struct Emitter<'a, W> {
+    out: &'a mut W,
+    options: &'a CodegenOptions,
+    /// Array type of the tape, e.g. `[30000 x i8]`.
+    tape_type: String,
+    cell: &'static str,
+    /// The op being emitted.
+    pc: usize,
+    next_value: usize,
+    next_block: usize,
+    /// Block number of each open loop.
+    loops: Vec<usize>,
+    /// `OutputConst` payloads, emitted as globals after `main`.
+    strings: Vec<Vec<u8>>,
+}
+
+impl<W: Write> Emitter<'_, W> {
+    fn value(&mut self) -> String {
+        self.next_value += 1;
+        format!("%v{}", self.next_value)
+    }
+
+    fn block(&mut self) -> usize {
+        self.next_block += 1;
+        self.next_block
+    }
+
+    /// Emits code for the address of the cell `offset` away from the
+    /// pointer and returns the value holding it.
+    fn cell_addr(&mut self, offset: isize) -> io::Result<String> {
+        let ptr = self.value();
+        writeln!(self.out, "  {} = load i64, ptr %ptr", ptr)?;
+        let idx = match self.options.bounds {
+            _ if offset == 0 => ptr,
+            BoundsPolicy::Wrap => {
+                let len = self.options.tape_size as isize;
+                let sum = self.value();
+                let idx = self.value();
+                writeln!(
+                    self.out,
+                    "  {} = add i64 {}, {}",
+                    sum,
+                    ptr,
+                    offset.rem_euclid(len)
+                )?;
+                writeln!(self.out, "  {} = urem i64 {}, {}", idx, sum, len)?;
+                idx
+            }
+            _ => {
+                let idx = self.value();
+                writeln!(self.out, "  {} = add i64 {}, {}", idx, ptr, offset)?;
+                idx
+            }
+        };
+        let idx = match self.options.bounds {
+            BoundsPolicy::Error => {
+                let checked = self.value();
+                writeln!(
+                    self.out,
+                    "  {} = call i64 @at(i64 {}, i64 {})",
+                    checked, idx, self.pc
+                )?;
+                checked
+            }
+            _ => idx,
+        };
+        let addr = self.value();
+        writeln!(
+            self.out,
+            "  {} = getelementptr {}, ptr @tape, i64 0, i64 {}",
+            addr, self.tape_type, idx
+        )?;
+        Ok(addr)
+    }
+
+    fn load(&mut self, addr: &str) -> io::Result<String> {
+        let v = self.value();
+        writeln!(self.out, "  {} = load {}, ptr {}", v, self.cell, addr)?;
+        Ok(v)
+    }
+
+    fn store(&mut self, addr: &str, v: &str) -> io::Result<()> {
+        writeln!(self.out, "  store {} {}, ptr {}", self.cell, v, addr)
+    }
+
+    fn add(&mut self, offset: isize, n: u32) -> io::Result<()> {
+        let addr = self.cell_addr(offset)?;
+        let old = self.load(&addr)?;
+        let new = self.value();
+        let n = self.options.cell_width.truncate(n);
+        writeln!(self.out, "  {} = add {} {}, {}", new, self.cell, old, n)?;
+        self.store(&addr, &new)
+    }
+
+    fn set(&mut self, offset: isize, n: u32) -> io::Result<()> {
+        let addr = self.cell_addr(offset)?;
+        let n = self.options.cell_width.truncate(n);
+        self.store(&addr, &n.to_string())
+    }
+
+    /// Moves the pointer by `n` cells.
+    fn move_ptr(&mut self, n: isize) -> io::Result<()> {
+        let ptr = self.value();
+        writeln!(self.out, "  {} = load i64, ptr %ptr", ptr)?;
+        let new = match self.options.bounds {
+            BoundsPolicy::Wrap => {
+                let len = self.options.tape_size as isize;
+                let sum = self.value();
+                let new = self.value();
+                writeln!(
+                    self.out,
+                    "  {} = add i64 {}, {}",
+                    sum,
+                    ptr,
+                    n.rem_euclid(len)
+                )?;
+                writeln!(self.out, "  {} = urem i64 {}, {}", new, sum, len)?;
+                new
+            }
+            _ => {
+                let new = self.value();
+                writeln!(self.out, "  {} = add i64 {}, {}", new, ptr, n)?;
+                new
+            }
+        };
+        writeln!(self.out, "  store i64 {}, ptr %ptr", new)
+    }
+
+    /// Emits a test of the current cell, branching to `nonzero` or `zero`.
+    fn branch_on_cell(&mut self, nonzero: &str, zero: &str) -> io::Result<()> {
+        let addr = self.cell_addr(0)?;
+        let v = self.load(&addr)?;
+        let test = self.value();
+        writeln!(self.out, "  {} = icmp ne {} {}, 0", test, self.cell, v)?;
+        writeln!(
+            self.out,
+            "  br i1 {}, label %{}, label %{}",
+            test, nonzero, zero
+        )
+    }
+
+    /// Prints the cell `offset` away `count` times over.
+    fn output(&mut self, offset: isize, count: usize) -> io::Result<()> {
+        let addr = self.cell_addr(offset)?;
+        let v = self.load(&addr)?;
+        let arg = if self.options.cell_width == CellWidth::U32 {
+            v
+        } else {
+            let wide = self.value();
+            writeln!(self.out, "  {} = zext {} {} to i32", wide, self.cell, v)?;
+            wide
+        };
+        if count > 1 {
+            return writeln!(
+                self.out,
+                "  call void @write_repeat(i32 {}, i64 {})",
+                arg, count
+            );
+        }
+        let ret = self.value();
+        writeln!(self.out, "  {} = call i32 @putchar(i32 {})", ret, arg)
+    }
+
+    fn input(&mut self, offset: isize) -> io::Result<()> {
+        let flushed = self.value();
+        writeln!(self.out, "  {} = call i32 @fflush(ptr null)", flushed)?;
+        let c = self.value();
+        writeln!(self.out, "  {} = call i32 @getchar()", c)?;
+        let addr = self.cell_addr(offset)?;
+        let byte = if self.options.cell_width == CellWidth::U32 {
+            c.clone()
+        } else {
+            let byte = self.value();
+            writeln!(self.out, "  {} = trunc i32 {} to {}", byte, c, self.cell)?;
+            byte
+        };
+        let at_eof = self.value();
+        writeln!(self.out, "  {} = icmp eq i32 {}, -1", at_eof, c)?;
+        let on_eof = match eof_value(self.options) {
+            Some(v) => v.to_string(),
+            None => self.load(&addr)?,
+        };
+        let new = self.value();
+        writeln!(
+            self.out,
+            "  {} = select i1 {}, {} {}, {} {}",
+            new, at_eof, self.cell, on_eof, self.cell, byte
+        )?;
+        self.store(&addr, &new)
+    }
+
+    fn op(&mut self, pc: usize, op: &Op) -> io::Result<()> {
+        self.pc = pc;
+        match op {
+            Op::PtrAdd(n) => self.move_ptr(*n)?,
+            Op::ValAdd(offset, n) => self.add(*offset, *n)?,
+            Op::ValSub(offset, n) => self.add(*offset, 0u32.wrapping_sub(*n))?,
+            Op::Output => self.output(0, 1)?,
+            Op::OutputAt(offset) => self.output(*offset, 1)?,
+            Op::OutputRepeat(offset, count) => self.output(*offset, *count)?,
+            Op::OutputConst(bytes) => {
+                self.strings.push(bytes.clone());
+                writeln!(
+                    self.out,
+                    "  call void @write_bytes(ptr @str{}, i64 {})",
+                    self.strings.len() - 1,
+                    bytes.len()
+                )?;
+            }
+            Op::Input => self.input(0)?,
+            Op::InputAt(offset) => self.input(*offset)?,
+            Op::Jz(_) => {
+                let n = self.block();
+                self.loops.push(n);
+                writeln!(self.out, "  br label %loop{}", n)?;
+                writeln!(self.out, "loop{}:", n)?;
+                self.branch_on_cell(&format!("body{}", n), &format!("end{}", n))?;
+                writeln!(self.out, "body{}:", n)?;
+            }
+            Op::Jnz(_) => {
+                let n = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unmatched ']'"))?;
+                writeln!(self.out, "  br label %loop{}", n)?;
+                writeln!(self.out, "end{}:", n)?;
+            }
+            Op::Clear(offset) => self.set(*offset, 0)?,
+            Op::Set(offset, n) => self.set(*offset, *n)?,
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) => {
+                // Only touch the target when the source is non-zero, as the
+                // interpreter does.
+                let n = self.block();
+                self.branch_on_cell(&format!("mul{}", n), &format!("mul{}.end", n))?;
+                writeln!(self.out, "mul{}:", n)?;
+                let src_addr = self.cell_addr(0)?;
+                let src = self.load(&src_addr)?;
+                let (offset, instr, operand) = match *op {
+                    Op::MulAdd(offset, factor) => {
+                        let product = self.value();
+                        let factor = self.options.cell_width.truncate(factor);
+                        writeln!(
+                            self.out,
+                            "  {} = mul {} {}, {}",
+                            product, self.cell, src, factor
+                        )?;
+                        (offset, "add", product)
+                    }
+                    Op::AddTo(offset) => (offset, "add", src),
+                    Op::SubFrom(offset) => (offset, "sub", src),
+                    _ => unreachable!(),
+                };
+                let addr = self.cell_addr(offset)?;
+                let old = self.load(&addr)?;
+                let new = self.value();
+                writeln!(
+                    self.out,
+                    "  {} = {} {} {}, {}",
+                    new, instr, self.cell, old, operand
+                )?;
+                self.store(&addr, &new)?;
+                writeln!(self.out, "  br label %mul{}.end", n)?;
+                writeln!(self.out, "mul{}.end:", n)?;
+            }
+            Op::Transfer(targets) => {
+                let n = self.block();
+                self.branch_on_cell(&format!("mul{}", n), &format!("mul{}.end", n))?;
+                writeln!(self.out, "mul{}:", n)?;
+                let src_addr = self.cell_addr(0)?;
+                let src = self.load(&src_addr)?;
+                for &(offset, factor) in targets {
+                    let product = self.value();
+                    let factor = self.options.cell_width.truncate(factor);
+                    writeln!(
+                        self.out,
+                        "  {} = mul {} {}, {}",
+                        product, self.cell, src, factor
+                    )?;
+                    let addr = self.cell_addr(offset)?;
+                    let old = self.load(&addr)?;
+                    let new = self.value();
+                    writeln!(
+                        self.out,
+                        "  {} = add {} {}, {}",
+                        new, self.cell, old, product
+                    )?;
+                    self.store(&addr, &new)?;
+                }
+                self.set(0, 0)?;
+                writeln!(self.out, "  br label %mul{}.end", n)?;
+                writeln!(self.out, "mul{}.end:", n)?;
+            }
+            Op::ScanLeft | Op::ScanRight | Op::Scan(_) => {
+                let step = match op {
+                    Op::ScanLeft => -1,
+                    Op::ScanRight => 1,
+                    Op::Scan(stride) => *stride,
+                    _ => unreachable!(),
+                };
+                let n = self.block();
+                writeln!(self.out, "  br label %scan{}", n)?;
+                writeln!(self.out, "scan{}:", n)?;
+                self.branch_on_cell(&format!("scan{}.step", n), &format!("scan{}.end", n))?;
+                writeln!(self.out, "scan{}.step:", n)?;
+                self.move_ptr(step)?;
+                writeln!(self.out, "  br label %scan{}", n)?;
+                writeln!(self.out, "scan{}.end:", n)?;
+            }
+            Op::BulkAdd(deltas) => {
+                for (offset, n) in deltas {
+                    self.add(*offset, *n)?;
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for offset in offsets {
+                    self.set(*offset, 0)?;
+                }
+            }
+            // LLVM turns the stores into a memset.
+            Op::ClearRange(offset, n) => {
+                for offset in (0..*n as isize).map(|i| offset + i) {
+                    self.set(offset, 0)?;
+                }
+            }
+            Op::ClearUntilZero(stride) => {
+                let n = self.block();
+                writeln!(self.out, "  br label %sweep{}", n)?;
+                writeln!(self.out, "sweep{}:", n)?;
+                self.branch_on_cell(&format!("sweep{}.step", n), &format!("sweep{}.end", n))?;
+                writeln!(self.out, "sweep{}.step:", n)?;
+                self.set(0, 0)?;
+                self.move_ptr(*stride)?;
+                writeln!(self.out, "  br label %sweep{}", n)?;
+                writeln!(self.out, "sweep{}.end:", n)?;
+            }
+            Op::Debug => {
+                let ptr = self.value();
+                writeln!(self.out, "  {} = load i64, ptr %ptr", ptr)?;
+                writeln!(self.out, "  call void @debug_dump(i64 {}, i64 {})", pc, ptr)?;
+            }
+            // Does nothing off the tape, where the loop after it runs.
+            Op::DivMod => {
+                if let Some(last) = self.options.tape_size.checked_sub(7) {
+                    let ptr = self.value();
+                    let test = self.value();
+                    let addr = self.value();
+                    let call = self.block();
+                    let done = self.block();
+                    writeln!(self.out, "  {} = load i64, ptr %ptr", ptr)?;
+                    writeln!(self.out, "  {} = icmp ule i64 {}, {}", test, ptr, last)?;
+                    writeln!(
+                        self.out,
+                        "  br i1 {}, label %divmod{}, label %divmod{}",
+                        test, call, done
+                    )?;
+                    writeln!(self.out, "divmod{}:", call)?;
+                    writeln!(
+                        self.out,
+                        "  {} = getelementptr {}, ptr @tape, i64 0, i64 {}",
+                        addr, self.tape_type, ptr
+                    )?;
+                    writeln!(self.out, "  call void @div_mod(ptr {})", addr)?;
+                    writeln!(self.out, "  br label %divmod{}", done)?;
+                    writeln!(self.out, "divmod{}:", done)?;
+                }
+            }
+            // Rejected by `emit_llvm_ir`.
+            Op::DefProc(_)
+            | Op::EndProc
+            | Op::CallProc
+            | Op::Fork
+            | Op::End
+            | Op::Store
+            | Op::Retrieve
+            | Op::Bitwise(_)
+            | Op::SwitchTape(_)
+            | Op::Random => {}
+        }
+        Ok(())
+    }
+}
+
+/// Writes the function `Op::OutputRepeat` calls, which prints `byte`
+/// `count` times; the count is always at least 2.
+fn emit_write_repeat<W: Write>(out: &mut W) -> io::Result<()> {
+    writeln!(
+        out,
+        "define internal void @write_repeat(i32 %byte, i64 %count) {{"
+    )?;
+    writeln!(out, "entry:")?;
+    writeln!(out, "  br label %loop")?;
+    writeln!(out, "loop:")?;
+    writeln!(out, "  %i = phi i64 [ 0, %entry ], [ %next, %loop ]")?;
+    writeln!(out, "  %ret = call i32 @putchar(i32 %byte)")?;
+    writeln!(out, "  %next = add i64 %i, 1")?;
+    writeln!(out, "  %more = icmp ult i64 %next, %count")?;
+    writeln!(out, "  br i1 %more, label %loop, label %done")?;
+    writeln!(out, "done:")?;
+    writeln!(out, "  ret void")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes the function `Op::Debug` calls, which prints the same line as
+/// the interpreter does to stderr.
+fn emit_debug_dump<W: Write>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let cell = int_type(options.cell_width);
+    let len = options.tape_size;
+    let head = b"# op %llu, ptr %llu, cells %llu..%llu:\0";
+    writeln!(
+        out,
+        "@debug.head = private constant [{} x i8] {}",
+        head.len(),
+        string_constant(head)
+    )?;
+    writeln!(out, "@debug.cell = private constant [4 x i8] c\" %u\\00\"")?;
+    writeln!(
+        out,
+        "@debug.here = private constant [6 x i8] c\" [%u]\\00\""
+    )?;
+    writeln!(
+        out,
+        "@debug.newline = private constant [2 x i8] c\"\\0A\\00\""
+    )?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "define internal void @debug_dump(i64 %op, i64 %ptr) {{"
+    )?;
+    writeln!(out, "entry:")?;
+    writeln!(out, "  %flushed = call i32 @fflush(ptr null)")?;
+    writeln!(out, "  %near = icmp ult i64 %ptr, 8")?;
+    writeln!(out, "  %back = sub i64 %ptr, 8")?;
+    writeln!(out, "  %from = select i1 %near, i64 0, i64 %back")?;
+    let last_start = len.saturating_sub(16);
+    writeln!(out, "  %late = icmp ugt i64 %from, {}", last_start)?;
+    writeln!(
+        out,
+        "  %start = select i1 %late, i64 {}, i64 %from",
+        last_start
+    )?;
+    writeln!(out, "  %to = add i64 %start, 16")?;
+    writeln!(out, "  %past = icmp ugt i64 %to, {}", len)?;
+    writeln!(out, "  %end = select i1 %past, i64 {}, i64 %to", len)?;
+    writeln!(
+        out,
+        "  %head = call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @debug.head, i64 %op, i64 %ptr, i64 %start, i64 %end)"
+    )?;
+    writeln!(out, "  br label %loop")?;
+    writeln!(out, "loop:")?;
+    writeln!(out, "  %i = phi i64 [ %start, %entry ], [ %next, %loop ]")?;
+    writeln!(
+        out,
+        "  %addr = getelementptr [{} x {}], ptr @tape, i64 0, i64 %i",
+        len, cell
+    )?;
+    writeln!(out, "  %cell = load {}, ptr %addr", cell)?;
+    // Variadic arguments are promoted to at least `int`.
+    let wide = if options.cell_width == CellWidth::U32 {
+        "%cell"
+    } else {
+        writeln!(out, "  %wide = zext {} %cell to i32", cell)?;
+        "%wide"
+    };
+    writeln!(out, "  %here = icmp eq i64 %i, %ptr")?;
+    writeln!(
+        out,
+        "  %format = select i1 %here, ptr @debug.here, ptr @debug.cell"
+    )?;
+    writeln!(
+        out,
+        "  %printed = call i32 (i32, ptr, ...) @dprintf(i32 2, ptr %format, i32 {})",
+        wide
+    )?;
+    writeln!(out, "  %next = add i64 %i, 1")?;
+    writeln!(out, "  %more = icmp ult i64 %next, %end")?;
+    writeln!(out, "  br i1 %more, label %loop, label %done")?;
+    writeln!(out, "done:")?;
+    writeln!(
+        out,
+        "  %tail = call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @debug.newline)"
+    )?;
+    writeln!(out, "  ret void")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes `@at`, which every cell index goes through under
+/// [`BoundsPolicy::Error`]: it returns `%idx`, or exits like the C
+/// backend's `at` when the op at `%op` touches it off the tape.
+fn emit_bounds_check<W: Write>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let message = b"error: op %llu touched a cell outside the tape\n\0";
+    writeln!(
+        out,
+        "@oob.message = private constant [{} x i8] {}",
+        message.len(),
+        string_constant(message)
+    )?;
+    writeln!(out, "declare void @exit(i32) noreturn")?;
+    writeln!(out)?;
+    writeln!(out, "define internal i64 @at(i64 %idx, i64 %op) {{")?;
+    writeln!(out, "entry:")?;
+    writeln!(out, "  %off = icmp uge i64 %idx, {}", options.tape_size)?;
+    writeln!(out, "  br i1 %off, label %fail, label %ok")?;
+    writeln!(out, "ok:")?;
+    writeln!(out, "  ret i64 %idx")?;
+    writeln!(out, "fail:")?;
+    writeln!(out, "  %flushed = call i32 @fflush(ptr null)")?;
+    writeln!(
+        out,
+        "  %printed = call i32 (i32, ptr, ...) @dprintf(i32 2, ptr @oob.message, i64 %op)"
+    )?;
+    writeln!(out, "  call void @exit(i32 1)")?;
+    writeln!(out, "  unreachable")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes the function `Op::DivMod` calls, which does what
+/// [`div_mod`](crate::div_mod) does to the cells from `%c`.
+fn emit_div_mod<W: Write>(out: &mut W, options: &CodegenOptions) -> io::Result<()> {
+    let cell = int_type(options.cell_width);
+    let max = options.cell_width.truncate(u32::MAX);
+    writeln!(out, "define internal void @div_mod(ptr %c) {{")?;
+    writeln!(out, "entry:")?;
+    for (i, name) in ["turns", "", "left", "moved", "count", "gap5", "gap6"]
+        .iter()
+        .enumerate()
+    {
+        if name.is_empty() {
+            continue;
+        }
+        writeln!(
+            out,
+            "  %{}.addr = getelementptr {}, ptr %c, i64 {}",
+            name, cell, i
+        )?;
+        writeln!(out, "  %{}.cell = load {}, ptr %{}.addr", name, cell, name)?;
+        writeln!(out, "  %{} = zext {} %{}.cell to i64", name, cell, name)?;
+    }
+    writeln!(out, "  %divisor = add i64 %left, %moved")?;
+    writeln!(out, "  %no.turns = icmp eq i64 %turns, 0")?;
+    writeln!(out, "  %no.left = icmp eq i64 %left, 0")?;
+    writeln!(out, "  %small = icmp ult i64 %divisor, 2")?;
+    writeln!(out, "  %big = icmp ugt i64 %divisor, {}", max)?;
+    writeln!(out, "  %gaps = or i64 %gap5, %gap6")?;
+    writeln!(out, "  %busy = icmp ne i64 %gaps, 0")?;
+    writeln!(out, "  %bad1 = or i1 %no.turns, %no.left")?;
+    writeln!(out, "  %bad2 = or i1 %bad1, %small")?;
+    writeln!(out, "  %bad3 = or i1 %bad2, %big")?;
+    writeln!(out, "  %bad = or i1 %bad3, %busy")?;
+    writeln!(out, "  br i1 %bad, label %done, label %run")?;
+    writeln!(out, "run:")?;
+    writeln!(out, "  %short = icmp ult i64 %turns, %left")?;
+    writeln!(out, "  br i1 %short, label %within, label %past")?;
+    writeln!(out, "within:")?;
+    writeln!(out, "  %left.new = sub i64 %left, %turns")?;
+    writeln!(out, "  %moved.new = add i64 %moved, %turns")?;
+    writeln!(out, "  %left.cell.new = trunc i64 %left.new to {}", cell)?;
+    writeln!(out, "  %moved.cell.new = trunc i64 %moved.new to {}", cell)?;
+    writeln!(out, "  store {} %left.cell.new, ptr %left.addr", cell)?;
+    writeln!(out, "  store {} %moved.cell.new, ptr %moved.addr", cell)?;
+    writeln!(out, "  br label %finish")?;
+    writeln!(out, "past:")?;
+    writeln!(out, "  %over = sub i64 %turns, %left")?;
+    writeln!(out, "  %rem = urem i64 %over, %divisor")?;
+    writeln!(out, "  %quot = udiv i64 %over, %divisor")?;
+    writeln!(out, "  %rest = sub i64 %divisor, %rem")?;
+    writeln!(out, "  %gained = add i64 %quot, 1")?;
+    writeln!(out, "  %count.new = add i64 %count, %gained")?;
+    writeln!(out, "  %rest.cell = trunc i64 %rest to {}", cell)?;
+    writeln!(out, "  %rem.cell = trunc i64 %rem to {}", cell)?;
+    writeln!(out, "  %count.cell.new = trunc i64 %count.new to {}", cell)?;
+    writeln!(out, "  store {} %rest.cell, ptr %left.addr", cell)?;
+    writeln!(out, "  store {} %rem.cell, ptr %moved.addr", cell)?;
+    writeln!(out, "  store {} %count.cell.new, ptr %count.addr", cell)?;
+    writeln!(out, "  br label %finish")?;
+    writeln!(out, "finish:")?;
+    writeln!(out, "  %sum.addr = getelementptr {}, ptr %c, i64 1", cell)?;
+    writeln!(out, "  %sum.cell = load {}, ptr %sum.addr", cell)?;
+    writeln!(out, "  %turns.cell.wrapped = trunc i64 %turns to {}", cell)?;
+    writeln!(
+        out,
+        "  %sum.new = add {} %sum.cell, %turns.cell.wrapped",
+        cell
+    )?;
+    writeln!(out, "  store {} %sum.new, ptr %sum.addr", cell)?;
+    writeln!(out, "  store {} 0, ptr %turns.addr", cell)?;
+    writeln!(out, "  br label %done")?;
+    writeln!(out, "done:")?;
+    writeln!(out, "  ret void")?;
+    writeln!(out, "}}")?;
+    writeln!(out)
+}
+
+/// Writes an LLVM IR module equivalent to `ops` to `out`.
+pub fn emit_llvm_ir<W: Write>(ops: &[Op], options: &CodegenOptions, out: &mut W) -> io::Result<()> {
+    if matches!(
+        options.bounds,
+        BoundsPolicy::Clamp | BoundsPolicy::Grow | BoundsPolicy::Bidirectional
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the LLVM backend only supports the error, wrap and unchecked bounds policies",
+        ));
+    }
+    if uses_procedures(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the LLVM backend doesn't support pbrain procedures",
+        ));
+    }
+    if uses_forks(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the LLVM backend doesn't support Brainfork's forks",
+        ));
+    }
+    if uses_extended1(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the LLVM backend doesn't support Extended Type I's commands",
+        ));
+    }
+    if uses_tapes(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the LLVM backend doesn't support switching tapes",
+        ));
+    }
+    if uses_random(ops) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the LLVM backend doesn't support '?', the random byte",
+        ));
+    }
+    let cell = int_type(options.cell_width);
+    let tape_type = format!("[{} x {}]", options.tape_size, cell);
+    writeln!(out, "; Generated by bfc")?;
+    writeln!(out)?;
+    writeln!(out, "@tape = internal global {} zeroinitializer", tape_type)?;
+    writeln!(out)?;
+    writeln!(out, "declare i32 @putchar(i32)")?;
+    writeln!(out, "declare i32 @getchar()")?;
+    writeln!(out, "declare i32 @fflush(ptr)")?;
+    let needs_tape = !ops.iter().all(|op| matches!(op, Op::OutputConst(_)));
+    let checked = needs_tape && options.bounds == BoundsPolicy::Error;
+    if checked || ops.contains(&Op::Debug) {
+        writeln!(out, "declare i32 @dprintf(i32, ptr, ...)")?;
+    }
+    writeln!(out)?;
+    // Prints `len` bytes from `data`, for `OutputConst`.
+    writeln!(
+        out,
+        "define internal void @write_bytes(ptr %data, i64 %len) {{"
+    )?;
+    writeln!(out, "entry:")?;
+    writeln!(out, "  %empty = icmp eq i64 %len, 0")?;
+    writeln!(out, "  br i1 %empty, label %done, label %loop")?;
+    writeln!(out, "loop:")?;
+    writeln!(out, "  %i = phi i64 [ 0, %entry ], [ %next, %loop ]")?;
+    writeln!(out, "  %addr = getelementptr i8, ptr %data, i64 %i")?;
+    writeln!(out, "  %byte = load i8, ptr %addr")?;
+    writeln!(out, "  %wide = zext i8 %byte to i32")?;
+    writeln!(out, "  %ret = call i32 @putchar(i32 %wide)")?;
+    writeln!(out, "  %next = add i64 %i, 1")?;
+    writeln!(out, "  %more = icmp ult i64 %next, %len")?;
+    writeln!(out, "  br i1 %more, label %loop, label %done")?;
+    writeln!(out, "done:")?;
+    writeln!(out, "  ret void")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    if ops.iter().any(|op| matches!(op, Op::OutputRepeat(..))) {
+        emit_write_repeat(out)?;
+    }
+    if ops.contains(&Op::Debug) {
+        emit_debug_dump(out, options)?;
+    }
+    if checked {
+        emit_bounds_check(out, options)?;
+    }
+    if ops.contains(&Op::DivMod) {
+        emit_div_mod(out, options)?;
+    }
+    writeln!(out, "define i32 @main() {{")?;
+    writeln!(out, "entry:")?;
+    writeln!(out, "  %ptr = alloca i64")?;
+    writeln!(out, "  store i64 0, ptr %ptr")?;
+
+    let mut emitter = Emitter {
+        out,
+        options,
+        tape_type,
+        cell,
+        pc: 0,
+        next_value: 0,
+        next_block: 0,
+        loops: Vec::new(),
+        strings: Vec::new(),
+    };
+    for (pc, op) in ops.iter().enumerate() {
+        emitter.op(pc, op)?;
+    }
+    let Emitter { out, strings, .. } = emitter;
+
+    writeln!(out, "  ret i32 0")?;
+    writeln!(out, "}}")?;
+    for (i, bytes) in strings.iter().enumerate() {
+        if i == 0 {
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            "@str{} = private constant [{} x i8] {}",
+            i,
+            bytes.len(),
+            string_constant(bytes)
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseOptions, optimize, parse, parse_with};
+
+    fn emit(code: &[u8], options: &CodegenOptions) -> String {
+        let ops = optimize(parse(code).unwrap());
+        let mut out = Vec::new();
+        emit_llvm_ir(&ops, options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_emit_llvm_loops_are_blocks() {
+        let src = emit(b"+[>,.<-]", &CodegenOptions::default());
+        assert!(src.contains("@tape = internal global [30000 x i8] zeroinitializer"));
+        assert!(src.contains("loop1:\n"));
+        assert!(src.contains("body1:\n"));
+        assert!(src.contains("  br label %loop1\nend1:\n"));
+        assert!(src.ends_with("  ret i32 0\n}\n"));
+        // Every cell index is checked under the default bounds.
+        assert!(src.contains("define internal i64 @at(i64 %idx, i64 %op) {"));
+        assert!(src.contains("  %v2 = call i64 @at(i64 %v1, i64 0)\n"));
+    }
+
+    #[test]
+    fn test_emit_llvm_wide_wrap() {
+        let options = CodegenOptions {
+            tape_size: 100,
+            bounds: BoundsPolicy::Wrap,
+            cell_width: CellWidth::U16,
+            ..CodegenOptions::default()
+        };
+        let src = emit(b"<-", &options);
+        assert!(src.contains("%v2 = add i64 %v1, 99\n"));
+        assert!(src.contains("%v3 = urem i64 %v2, 100\n"));
+        assert!(src.contains("%v6 = add i16 %v5, 65535\n"));
+    }
+
+    #[test]
+    fn test_emit_llvm_debug() {
+        let ops = parse_with(
+            b"#",
+            &ParseOptions {
+                debug_char: true,
+                ..ParseOptions::default()
+            },
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        emit_llvm_ir(&ops, &CodegenOptions::default(), &mut out).unwrap();
+        let src = String::from_utf8(out).unwrap();
+        assert!(src.contains("define internal void @debug_dump(i64 %op, i64 %ptr) {"));
+        assert!(src.contains("  call void @debug_dump(i64 0, i64 %v1)\n"));
+        assert!(!emit(b"+", &CodegenOptions::default()).contains("@debug"));
+    }
+
+    #[test]
+    fn test_string_constant() {
+        assert_eq!(string_constant(b"a\"\n"), r#"c"a\22\0A""#);
+    }
+}
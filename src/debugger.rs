@@ -0,0 +1,624 @@
+//! Running a program under control: single steps, breakpoints, watchpoints
+//! and conditional breakpoints on top of [`Interpreter::step`], for front
+//! ends like `bfi --debug`.
+//!
+//! While recording, the debugger can also run backwards. It saves the
+//! machine state every so many ops, and since a program's behavior only
+//! depends on its input, which the debugger keeps, going back means
+//! restoring the last checkpoint before the target and running forwards to
+//! it. Output produced again on the way is not written a second time.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::Interpreter;
+use crate::cell::Cell;
+use crate::interpreter::State;
+
+/// How often [`Debugger::start_recording`] checkpoints by default, in ops.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 10_000;
+
+/// Checkpoints kept before every other one is dropped.
+const MAX_CHECKPOINTS: usize = 1024;
+
+/// Why [`Debugger::resume`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The next op to execute has a breakpoint.
+    Breakpoint(usize),
+    /// The last op changed a watched cell.
+    Watchpoint { cell: usize, old: u32, new: u32 },
+    /// The condition at this index in
+    /// [`conditions`](Debugger::conditions) became true.
+    Condition(usize),
+    /// The program ended.
+    Finished,
+    /// Running backwards reached the point where recording started.
+    RecordingStart,
+}
+
+/// The program's input as the debugger sees it: every byte read is kept so
+/// it can be read again after going back.
+pub struct InputLog<R> {
+    inner: R,
+    log: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for InputLog<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.log.len() {
+            let n = self.inner.read(buf)?;
+            self.log.extend_from_slice(&buf[..n]);
+        }
+        let n = buf.len().min(self.log.len() - self.pos);
+        buf[..n].copy_from_slice(&self.log[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// The program's output as the debugger sees it: after going back, bytes
+/// that were already written are dropped.
+pub struct OutputLog<W> {
+    inner: W,
+    /// Bytes written to `inner` so far.
+    written: u64,
+    /// Bytes the program has written at its current point.
+    pos: u64,
+}
+
+impl<W> OutputLog<W> {
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for OutputLog<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let seen = (self.written - self.pos).min(buf.len() as u64) as usize;
+        let n = if seen < buf.len() {
+            seen + self.inner.write(&buf[seen..])?
+        } else {
+            seen
+        };
+        self.pos += n as u64;
+        self.written = self.written.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The machine and I/O position at some point of the recording.
+struct Checkpoint<C> {
+    state: State<C>,
+    input: usize,
+    output: u64,
+}
+
+/// A value a [`Condition`] looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// The data pointer, `ptr`.
+    Ptr,
+    /// The cell under the pointer, `cell`.
+    Current,
+    /// A cell by index, `tape[N]`.
+    Cell(usize),
+}
+
+/// How a [`Condition`] compares its operand with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Eq),
+        ("!=", Comparison::Ne),
+        ("<=", Comparison::Le),
+        (">=", Comparison::Ge),
+        ("<", Comparison::Lt),
+        (">", Comparison::Gt),
+    ];
+
+    fn holds(self, a: u64, b: u64) -> bool {
+        match self {
+            Comparison::Eq => a == b,
+            Comparison::Ne => a != b,
+            Comparison::Lt => a < b,
+            Comparison::Le => a <= b,
+            Comparison::Gt => a > b,
+            Comparison::Ge => a >= b,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        Comparison::ALL
+            .iter()
+            .find(|(_, cmp)| *cmp == self)
+            .map_or("", |(symbol, _)| symbol)
+    }
+}
+
+/// A condition on the machine state, such as `ptr>1000` or `tape[3]==7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub operand: Operand,
+    pub comparison: Comparison,
+    pub value: u64,
+}
+
+impl Condition {
+    /// Parses `OPERAND CMP VALUE`, where the operand is `ptr`, `cell` or
+    /// `tape[N]` and the comparison is one of `==`, `!=`, `<`, `<=`, `>`
+    /// and `>=`. Spaces are optional.
+    pub fn parse(text: &str) -> Option<Condition> {
+        let (at, symbol, comparison) = Comparison::ALL
+            .iter()
+            .filter_map(|&(symbol, cmp)| text.find(symbol).map(|at| (at, symbol, cmp)))
+            .min_by_key(|&(at, symbol, _)| (at, usize::MAX - symbol.len()))?;
+        let operand = match text[..at].trim() {
+            "ptr" => Operand::Ptr,
+            "cell" => Operand::Current,
+            lhs => Operand::Cell(
+                lhs.strip_prefix("tape[")?
+                    .strip_suffix(']')?
+                    .trim()
+                    .parse()
+                    .ok()?,
+            ),
+        };
+        let value = text[at + symbol.len()..].trim().parse().ok()?;
+        Some(Condition {
+            operand,
+            comparison,
+            value,
+        })
+    }
+
+    /// Whether the condition holds for `interp`. A cell off the tape never
+    /// matches.
+    pub fn holds<R: Read, W: Write, C: Cell>(&self, interp: &Interpreter<R, W, C>) -> bool {
+        let tape = interp.tape();
+        let actual = match self.operand {
+            Operand::Ptr => Some(interp.ptr() as u64),
+            Operand::Current => tape.get(interp.ptr()).map(|c| u64::from(c.to_u32())),
+            Operand::Cell(i) => tape.get(i).map(|c| u64::from(c.to_u32())),
+        };
+        actual.is_some_and(|actual| self.comparison.holds(actual, self.value))
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.operand {
+            Operand::Ptr => write!(f, "ptr")?,
+            Operand::Current => write!(f, "cell")?,
+            Operand::Cell(i) => write!(f, "tape[{}]", i)?,
+        }
+        write!(f, " {} {}", self.comparison.symbol(), self.value)
+    }
+}
+
+/// An [`Interpreter`] with breakpoints on op indices, watchpoints on cells
+/// and conditional breakpoints, which can also run backwards while
+/// recording. It wraps the interpreter's I/O in an [`InputLog`] and an
+/// [`OutputLog`].
+pub struct Debugger<R, W, C = u8> {
+    interp: Interpreter<InputLog<R>, OutputLog<W>, C>,
+    breakpoints: BTreeSet<usize>,
+    /// Watched cells and the values they last had.
+    watchpoints: BTreeMap<usize, u32>,
+    /// Conditions with whether each held after the last op, since they
+    /// only stop the program when they become true.
+    conditions: Vec<(Condition, bool)>,
+    /// Ops between checkpoints while recording.
+    interval: Option<u64>,
+    /// Saved states, oldest first.
+    checkpoints: Vec<Checkpoint<C>>,
+}
+
+impl<R: Read, W: Write, C: Cell> Debugger<R, W, C> {
+    pub fn new(interp: Interpreter<R, W, C>) -> Self {
+        let interp = interp.map_io(
+            |inner| InputLog {
+                inner,
+                log: Vec::new(),
+                pos: 0,
+            },
+            |inner| OutputLog {
+                inner,
+                written: 0,
+                pos: 0,
+            },
+        );
+        Debugger {
+            interp,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            conditions: Vec::new(),
+            interval: None,
+            checkpoints: Vec::new(),
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<InputLog<R>, OutputLog<W>, C> {
+        &self.interp
+    }
+
+    /// Changes made through this aren't recorded, so going back to before
+    /// them loses them.
+    pub fn interpreter_mut(&mut self) -> &mut Interpreter<InputLog<R>, OutputLog<W>, C> {
+        &mut self.interp
+    }
+
+    /// Starts recording from here, saving the state every `interval` ops
+    /// (at least 1). Going back costs up to `interval` ops of running
+    /// forwards, and each checkpoint holds a copy of the tape. To bound
+    /// memory, the interval doubles whenever 1,024 checkpoints have piled
+    /// up, and every other one is dropped.
+    pub fn start_recording(&mut self, interval: u64) {
+        self.interval = Some(interval.max(1));
+        self.checkpoints.clear();
+        self.checkpoint();
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.interval = None;
+        self.checkpoints.clear();
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    /// Stops [`resume`](Self::resume) before the op at `pc` runs. Returns
+    /// `false` if `pc` is past the end of the program.
+    pub fn set_breakpoint(&mut self, pc: usize) -> bool {
+        if pc >= self.interp.ops().len() {
+            return false;
+        }
+        self.breakpoints.insert(pc);
+        true
+    }
+
+    /// Returns whether there was a breakpoint at `pc`.
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stops [`resume`](Self::resume) after an op changes the cell at
+    /// `cell`. Returns `false` if `cell` is off the tape.
+    pub fn set_watchpoint(&mut self, cell: usize) -> bool {
+        let Some(value) = self.interp.tape().get(cell) else {
+            return false;
+        };
+        self.watchpoints.insert(cell, value.to_u32());
+        true
+    }
+
+    /// Returns whether `cell` was watched.
+    pub fn remove_watchpoint(&mut self, cell: usize) -> bool {
+        self.watchpoints.remove(&cell).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.watchpoints.keys().copied()
+    }
+
+    /// Stops [`resume`](Self::resume) when `condition` becomes true. One
+    /// that already holds waits until it has been false again. Returns the
+    /// condition's index.
+    pub fn add_condition(&mut self, condition: Condition) -> usize {
+        let holds = condition.holds(&self.interp);
+        self.conditions.push((condition, holds));
+        self.conditions.len() - 1
+    }
+
+    /// Removes the condition at `index`, moving the later ones down.
+    pub fn remove_condition(&mut self, index: usize) -> Option<Condition> {
+        (index < self.conditions.len()).then(|| self.conditions.remove(index).0)
+    }
+
+    pub fn conditions(&self) -> impl Iterator<Item = &Condition> + '_ {
+        self.conditions.iter().map(|(condition, _)| condition)
+    }
+
+    /// Executes one op. Returns the watchpoint or condition it triggered,
+    /// if any.
+    pub fn step(&mut self) -> io::Result<Option<Stop>> {
+        self.advance()?;
+        Ok(self.check())
+    }
+
+    /// Runs until the program ends, reaches a breakpoint, changes a watched
+    /// cell or makes a condition true. At least one op runs, so resuming
+    /// from a breakpoint moves past it.
+    pub fn resume(&mut self) -> io::Result<Stop> {
+        loop {
+            if let Some(stop) = self.resume_for(u64::MAX)? {
+                return Ok(stop);
+            }
+        }
+    }
+
+    /// Like [`resume`](Self::resume), but returns `None` after `limit` ops
+    /// without a stop, so a front end can do other work in between.
+    pub fn resume_for(&mut self, limit: u64) -> io::Result<Option<Stop>> {
+        for _ in 0..limit {
+            self.advance()?;
+            if let Some(stop) = self.check() {
+                return Ok(Some(stop));
+            }
+            if self.interp.is_finished() {
+                return Ok(Some(Stop::Finished));
+            }
+            let pc = self.interp.pc();
+            if self.breakpoints.contains(&pc) {
+                return Ok(Some(Stop::Breakpoint(pc)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Goes back `n` ops, or to where recording started if that is closer.
+    /// Returns how many ops it went back, which is 0 when not recording.
+    pub fn step_back(&mut self, n: u64) -> io::Result<u64> {
+        let Some(first) = self.checkpoints.first() else {
+            return Ok(0);
+        };
+        let now = self.interp.steps();
+        let target = now.saturating_sub(n).max(first.state.steps());
+        self.rewind(target)?;
+        Ok(now - target)
+    }
+
+    /// Runs backwards to the last point where [`resume`](Self::resume)
+    /// would have stopped: a breakpoint, a change to a watched cell or a
+    /// condition becoming true. Stops at the start of the recording if
+    /// there is none, or right away when not recording.
+    pub fn reverse_resume(&mut self) -> io::Result<Stop> {
+        let now = self.interp.steps();
+        let mut end = now;
+        for k in (0..self.checkpoints.len()).rev() {
+            let start = self.checkpoints[k].state.steps();
+            if start >= end {
+                continue;
+            }
+            // Replay this stretch and keep the last stop before `end`.
+            self.rewind(start)?;
+            let mut last = self.breakpoint().map(|stop| (start, stop));
+            while self.interp.steps() < end && !self.interp.is_finished() {
+                self.interp.step()?;
+                if let Some(stop) = self.check().or_else(|| self.breakpoint()) {
+                    last = Some((self.interp.steps(), stop));
+                }
+            }
+            if let Some((at, stop)) = last.filter(|&(at, _)| at < now) {
+                self.rewind(at)?;
+                return Ok(stop);
+            }
+            end = start;
+        }
+        if let Some(first) = self.checkpoints.first() {
+            let start = first.state.steps();
+            self.rewind(start)?;
+        }
+        Ok(Stop::RecordingStart)
+    }
+
+    /// Executes one op, saving a checkpoint when one is due.
+    fn advance(&mut self) -> io::Result<()> {
+        self.interp.step()?;
+        if let Some(interval) = self.interval
+            && self.interp.steps().is_multiple_of(interval)
+        {
+            self.checkpoint();
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) {
+        let steps = self.interp.steps();
+        if self
+            .checkpoints
+            .last()
+            .is_some_and(|last| last.state.steps() >= steps)
+        {
+            return;
+        }
+        let (input, output) = self.interp.io();
+        let checkpoint = Checkpoint {
+            state: self.interp.snapshot(),
+            input: input.pos,
+            output: output.pos,
+        };
+        self.checkpoints.push(checkpoint);
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let mut i = 0;
+            self.checkpoints.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.interval = self.interval.map(|interval| interval.saturating_mul(2));
+        }
+    }
+
+    /// Puts the program back to after its first `steps` ops, which must be
+    /// within the recording.
+    fn rewind(&mut self, steps: u64) -> io::Result<()> {
+        let k = self
+            .checkpoints
+            .iter()
+            .rposition(|checkpoint| checkpoint.state.steps() <= steps)
+            .unwrap_or(0);
+        self.checkpoints.truncate(k + 1);
+        let Some(checkpoint) = self.checkpoints.get(k) else {
+            return Ok(());
+        };
+        self.interp.restore(&checkpoint.state);
+        let (input, output) = self.interp.io_mut();
+        input.pos = checkpoint.input;
+        output.pos = checkpoint.output;
+        while self.interp.steps() < steps && !self.interp.is_finished() {
+            self.interp.step()?;
+        }
+        self.sync();
+        Ok(())
+    }
+
+    /// A breakpoint stop if the next op has one.
+    fn breakpoint(&self) -> Option<Stop> {
+        let pc = self.interp.pc();
+        (!self.interp.is_finished() && self.breakpoints.contains(&pc))
+            .then_some(Stop::Breakpoint(pc))
+    }
+
+    /// Takes the watched values and conditions from the current state, as
+    /// after jumping to it.
+    fn sync(&mut self) {
+        let tape = self.interp.tape();
+        for (&cell, value) in &mut self.watchpoints {
+            if let Some(cell) = tape.get(cell) {
+                *value = cell.to_u32();
+            }
+        }
+        for (condition, held) in &mut self.conditions {
+            *held = condition.holds(&self.interp);
+        }
+    }
+
+    /// Updates the watched values and conditions after an op, returning
+    /// the first one that should stop the program.
+    fn check(&mut self) -> Option<Stop> {
+        let mut stop = None;
+        let tape = self.interp.tape();
+        for (&cell, old) in &mut self.watchpoints {
+            let new = tape.get(cell).map_or(*old, |value| value.to_u32());
+            if new != *old {
+                stop = stop.or(Some(Stop::Watchpoint {
+                    cell,
+                    old: *old,
+                    new,
+                }));
+                *old = new;
+            }
+        }
+        for (i, (condition, held)) in self.conditions.iter_mut().enumerate() {
+            let holds = condition.holds(&self.interp);
+            if holds && !*held {
+                stop = stop.or(Some(Stop::Condition(i)));
+            }
+            *held = holds;
+        }
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, EofBehavior, parse};
+
+    #[test]
+    fn test_breakpoints() {
+        let ops = parse(b"++[>+<-]>.").unwrap();
+        let interp = Interpreter::new(ops, io::empty(), Vec::new());
+        let mut debugger = Debugger::new(interp);
+        assert!(debugger.set_breakpoint(2));
+        assert!(!debugger.set_breakpoint(100));
+
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(2));
+        assert_eq!(debugger.interpreter().tape()[1], 0);
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(2));
+        assert_eq!(debugger.interpreter().tape()[1], 1);
+
+        assert!(debugger.remove_breakpoint(2));
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+        assert_eq!(debugger.interpreter().tape()[1], 2);
+    }
+
+    #[test]
+    fn test_watchpoints_and_conditions() {
+        let ops = parse(b"++[>+<-]>>>").unwrap();
+        let interp = Interpreter::new(ops, io::empty(), Vec::new());
+        let mut debugger = Debugger::new(interp);
+        assert!(debugger.set_watchpoint(1));
+        assert!(!debugger.set_watchpoint(1 << 20));
+        let watched = Stop::Watchpoint {
+            cell: 1,
+            old: 0,
+            new: 1,
+        };
+        assert_eq!(debugger.resume().unwrap(), watched);
+        debugger.remove_watchpoint(1);
+
+        debugger.add_condition(Condition::parse("cell==0").unwrap());
+        debugger.add_condition(Condition::parse("ptr >= 3").unwrap());
+        assert_eq!(debugger.resume().unwrap(), Stop::Condition(0));
+        assert_eq!(debugger.interpreter().tape()[0], 0);
+        assert_eq!(debugger.resume().unwrap(), Stop::Condition(1));
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let ops = parse(b",[.,]").unwrap();
+        let config = Config {
+            eof: EofBehavior::Zero,
+            ..Config::default()
+        };
+        let interp = Interpreter::<_, _, u8>::with_config(ops, &b"abc"[..], Vec::new(), &config);
+        let mut debugger = Debugger::new(interp);
+        assert_eq!(debugger.step_back(1).unwrap(), 0);
+        debugger.start_recording(2);
+        debugger.set_breakpoint(3);
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(3));
+        assert_eq!(debugger.resume().unwrap(), Stop::Breakpoint(3));
+        assert_eq!(debugger.interpreter().tape()[0], b'b');
+
+        assert_eq!(debugger.step_back(3).unwrap(), 3);
+        assert_eq!(debugger.interpreter().tape()[0], b'a');
+        assert_eq!(debugger.interpreter().steps(), 3);
+        assert_eq!(debugger.reverse_resume().unwrap(), Stop::RecordingStart);
+        assert_eq!(debugger.interpreter().steps(), 0);
+
+        debugger.remove_breakpoint(3);
+        assert_eq!(debugger.resume().unwrap(), Stop::Finished);
+        let (_, output) = debugger.interp.into_inner();
+        assert_eq!(output.inner, b"abc");
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        let condition = Condition::parse("ptr>1000").unwrap();
+        assert_eq!(condition.operand, Operand::Ptr);
+        assert_eq!(condition.comparison, Comparison::Gt);
+        assert_eq!(condition.value, 1000);
+        assert_eq!(
+            Condition::parse("tape[42] <= 7").unwrap().to_string(),
+            "tape[42] <= 7"
+        );
+        assert_eq!(Condition::parse("ptr"), None);
+        assert_eq!(Condition::parse("pc>1"), None);
+    }
+}
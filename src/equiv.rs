@@ -0,0 +1,236 @@
+//! Whether two programs do the same thing: [`canonicalize`] writes a
+//! program's ops in one standard form, and [`equivalent`] compares two
+//! programs by that form and, where it differs, by running both on the
+//! same inputs.
+//!
+//! Equal canonical forms are a proof; runs can only find a difference or
+//! fail to. That's enough to check that a transpiled or obfuscated
+//! program still does what its source did, or to drop duplicates from a
+//! set of generated programs.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use crate::Op;
+use crate::optimizer::{OptLevel, link_jumps, optimize_with};
+#[cfg(feature = "std")]
+use crate::{Config, Generator, Interpreter, RuntimeError};
+
+/// Inputs [`equivalent`] runs both programs on: no input, then random
+/// bytes of growing length.
+#[cfg(feature = "std")]
+pub const RUNS: u64 = 16;
+
+/// What a straight-line run of ops leaves in a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    /// Adds to it.
+    Add(u32),
+    /// Sets it.
+    Set(u32),
+}
+
+/// `ops` in a standard form: optimized at [`OptLevel::O2`], which folds
+/// what's known about the cells into constants, then with each stretch of
+/// moves, adds, clears and sets rewritten as what it does to each cell, in
+/// the order of the cells, and the move that ends it. Two programs with
+/// the same canonical form do the same on every input, though the first
+/// cell each touches off the tape may differ.
+pub fn canonicalize(ops: &[Op]) -> Vec<Op> {
+    let ops = optimize_with(ops.to_vec(), OptLevel::O2);
+    let mut out = Vec::with_capacity(ops.len());
+    let mut effects: BTreeMap<isize, Effect> = BTreeMap::new();
+    let mut ptr = 0;
+    let add = |effects: &mut BTreeMap<isize, Effect>, cell: isize, n: u32| {
+        let effect = effects.entry(cell).or_insert(Effect::Add(0));
+        *effect = match *effect {
+            Effect::Add(d) => Effect::Add(d.wrapping_add(n)),
+            Effect::Set(v) => Effect::Set(v.wrapping_add(n)),
+        };
+    };
+    for op in ops {
+        match op {
+            Op::PtrAdd(n) => ptr += n,
+            Op::ValAdd(offset, n) => add(&mut effects, ptr + offset, n),
+            Op::ValSub(offset, n) => add(&mut effects, ptr + offset, n.wrapping_neg()),
+            Op::BulkAdd(adds) => {
+                for (offset, n) in adds {
+                    add(&mut effects, ptr + offset, n);
+                }
+            }
+            Op::Clear(offset) => {
+                effects.insert(ptr + offset, Effect::Set(0));
+            }
+            Op::BulkClear(offsets) => {
+                for offset in offsets {
+                    effects.insert(ptr + offset, Effect::Set(0));
+                }
+            }
+            Op::ClearRange(offset, n) => {
+                for i in 0..n as isize {
+                    effects.insert(ptr + offset + i, Effect::Set(0));
+                }
+            }
+            Op::Set(offset, n) => {
+                effects.insert(ptr + offset, Effect::Set(n));
+            }
+            op => {
+                flush(&mut out, &mut effects, &mut ptr);
+                out.push(op);
+            }
+        }
+    }
+    flush(&mut out, &mut effects, &mut ptr);
+    link_jumps(&mut out);
+    out
+}
+
+/// Writes the `effects` of a straight-line stretch and the move `ptr` it
+/// ends with, and starts a new one.
+fn flush(out: &mut Vec<Op>, effects: &mut BTreeMap<isize, Effect>, ptr: &mut isize) {
+    for (cell, effect) in core::mem::take(effects) {
+        match effect {
+            Effect::Add(0) => {}
+            Effect::Add(n) => out.push(Op::ValAdd(cell, n)),
+            Effect::Set(0) => out.push(Op::Clear(cell)),
+            Effect::Set(n) => out.push(Op::Set(cell, n)),
+        }
+    }
+    if *ptr != 0 {
+        out.push(Op::PtrAdd(*ptr));
+    }
+    *ptr = 0;
+}
+
+/// What [`equivalent`] found out about two programs.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Equivalence {
+    /// Their canonical forms are the same, so they do the same on every
+    /// input.
+    Proved,
+    /// Every run agreed, as far as each got within the budget, but that
+    /// doesn't prove they always will.
+    Likely,
+    /// On `input`, one wrote something the other didn't, or only one
+    /// failed.
+    Different { input: Vec<u8> },
+}
+
+/// Whether `a` and `b` do the same: by their [`canonicalize`]d forms, and
+/// if those differ, by running both with the default [`Config`] on up to
+/// [`RUNS`] inputs for at most `budget` ops each. Runs agree if they write
+/// the same bytes and either both end, the same way, or the one still
+/// going when the budget ran out had written the start of what the other
+/// did.
+///
+/// [`Config`]: crate::Config
+#[cfg(feature = "std")]
+pub fn equivalent(a: &[Op], b: &[Op], budget: u64) -> Equivalence {
+    let (a, b) = (canonicalize(a), canonicalize(b));
+    if a == b {
+        return Equivalence::Proved;
+    }
+    let mut generator = Generator::new(0);
+    for run in 0..RUNS {
+        let len = generator.below(run * 8 + 1);
+        let input: Vec<u8> = (0..len).map(|_| generator.below(256) as u8).collect();
+        if !agree(&outcome(&a, &input, budget), &outcome(&b, &input, budget)) {
+            return Equivalence::Different { input };
+        }
+    }
+    Equivalence::Likely
+}
+
+/// What a run wrote, and how it ended: `None` if it ran out of budget,
+/// or whether it failed.
+#[cfg(feature = "std")]
+type Outcome = (Vec<u8>, Option<bool>);
+
+#[cfg(feature = "std")]
+fn outcome(ops: &[Op], input: &[u8], budget: u64) -> Outcome {
+    let config = Config {
+        max_steps: Some(budget),
+        ..Config::default()
+    };
+    let mut output = Vec::new();
+    let mut interp =
+        Interpreter::<_, _, u8>::with_config(ops.to_vec(), input, &mut output, &config);
+    let ended = match interp.run() {
+        Err(RuntimeError::StepLimitExceeded(_)) => None,
+        result => Some(result.is_err()),
+    };
+    drop(interp);
+    (output, ended)
+}
+
+#[cfg(feature = "std")]
+fn agree((a, a_ended): &Outcome, (b, b_ended): &Outcome) -> bool {
+    match (a_ended, b_ended) {
+        (Some(a_failed), Some(b_failed)) => a == b && a_failed == b_failed,
+        (Some(_), None) => a.starts_with(b),
+        (None, Some(_)) => b.starts_with(a),
+        (None, None) => a.starts_with(b) || b.starts_with(a),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_canonicalize() {
+        let canonical = |source: &[u8]| canonicalize(&parse(source).unwrap());
+        // The same adds in another order, and a clear written two ways.
+        assert_eq!(canonical(b",>++<+>-.[-]"), canonical(b",+>+.[+]"));
+        assert_eq!(
+            canonical(b",>,++<+>-."),
+            [
+                Op::Input,
+                Op::InputAt(1),
+                Op::ValAdd(0, 1),
+                Op::ValAdd(1, 1),
+                Op::OutputAt(1),
+                Op::PtrAdd(1)
+            ]
+        );
+        assert_ne!(canonical(b",+."), canonical(b",-."));
+        let ops = canonical(b",[>,<-[>+<-]]");
+        assert_eq!(crate::verify(&ops), Ok(()));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_equivalent() {
+        let ops = |source: &[u8]| parse(source).unwrap();
+        assert_eq!(
+            equivalent(&ops(b">+<+."), &ops(b"+>+<."), 1000),
+            Equivalence::Proved
+        );
+        // Doubling by adding to itself, and by a move loop.
+        assert_eq!(
+            equivalent(
+                &ops(b",[->++<]>."),
+                &ops(b",[->+>+<<]>[-<+>]>[-<<+>>]<<."),
+                10_000
+            ),
+            Equivalence::Likely
+        );
+        // Even no input tells these apart.
+        assert_eq!(
+            equivalent(&ops(b",."), &ops(b",+."), 1000),
+            Equivalence::Different { input: vec![] }
+        );
+        // Only one leaves the tape.
+        assert!(matches!(
+            equivalent(&ops(b",<,."), &ops(b",,."), 1000),
+            Equivalence::Different { .. }
+        ));
+        // Both loop forever, writing the same.
+        assert_eq!(
+            equivalent(&ops(b"+[.]"), &ops(b"+[..]"), 1000),
+            Equivalence::Likely
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Compile-time evaluation of the part of a program that runs before it
+//! reads any input.
+
+use crate::Op;
+use crate::cell::{Cell, CellWidth};
+use crate::interpreter::{BoundsPolicy, Config, cell_bytes};
+use crate::optimizer::link_jumps;
+
+/// Ops [`partial_eval`] executes before giving up, when no other budget is
+/// given.
+pub const DEFAULT_FUEL: u64 = 1_000_000;
+
+/// Runs `ops` ahead of time until the first `,`, the end of the program, or
+/// until `fuel` ops have executed, and replaces what ran with its effect: an
+/// `OutputConst` of everything printed, `Set`s recreating the tape, and a
+/// `PtrAdd` to where the pointer ended up.
+///
+/// Evaluation only stops between top-level ops; if it runs out inside a
+/// loop, it backs up to the start of that loop. A program that runs to
+/// completion becomes a single `OutputConst`. An access outside the tape
+/// also stops evaluation, so the generated program still fails, or grows its
+/// tape, at run time.
+pub fn partial_eval(ops: Vec<Op>, config: &Config, cell_width: CellWidth, fuel: u64) -> Vec<Op> {
+    match cell_width {
+        CellWidth::U8 => eval_prefix::<u8>(ops, config, fuel),
+        CellWidth::U16 => eval_prefix::<u16>(ops, config, fuel),
+        CellWidth::U32 => eval_prefix::<u32>(ops, config, fuel),
+    }
+}
+
+/// Machine state at the point evaluation stopped.
+struct State<C> {
+    pc: usize,
+    ptr: usize,
+    tape: Vec<C>,
+    output: Vec<u8>,
+}
+
+/// State to return to if evaluation stops inside a top-level loop: where the
+/// loop started, and the tape writes made since, newest last.
+struct Checkpoint<C> {
+    pc: usize,
+    ptr: usize,
+    output_len: usize,
+    undo: Vec<(usize, C)>,
+}
+
+struct Evaluator<C> {
+    state: State<C>,
+    policy: BoundsPolicy,
+    /// [`Config::unicode`].
+    unicode: bool,
+    depth: usize,
+    checkpoint: Option<Checkpoint<C>>,
+}
+
+impl<C: Cell> Evaluator<C> {
+    fn index(&self, offset: isize) -> Option<usize> {
+        let len = self.state.tape.len();
+        match self.policy {
+            BoundsPolicy::Clamp => Some(self.state.ptr.saturating_add_signed(offset).min(len - 1)),
+            BoundsPolicy::Wrap => {
+                Some((self.state.ptr as isize + offset).rem_euclid(len as isize) as usize)
+            }
+            // Evaluation stops before a tape would grow, so the program
+            // does it when it runs.
+            BoundsPolicy::Error
+            | BoundsPolicy::Grow
+            | BoundsPolicy::Bidirectional
+            | BoundsPolicy::Unchecked => self
+                .state
+                .ptr
+                .checked_add_signed(offset)
+                .filter(|&i| i < len),
+        }
+    }
+
+    fn get(&self, offset: isize) -> Option<C> {
+        self.index(offset).map(|i| self.state.tape[i])
+    }
+
+    fn set(&mut self, offset: isize, value: C) -> Option<()> {
+        let idx = self.index(offset)?;
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.undo.push((idx, self.state.tape[idx]));
+        }
+        self.state.tape[idx] = value;
+        Some(())
+    }
+
+    fn add(&mut self, offset: isize, n: u32) -> Option<()> {
+        let v = self.get(offset)?.wrapping_add(C::from_u32(n));
+        self.set(offset, v)
+    }
+
+    fn move_ptr(&mut self, offset: isize) {
+        self.state.ptr = match self.policy {
+            BoundsPolicy::Clamp | BoundsPolicy::Wrap => self.index(offset).unwrap(),
+            _ => self.state.ptr.wrapping_add_signed(offset),
+        };
+    }
+
+    /// Executes the op at `pc`, or returns `None` if it can't be evaluated
+    /// ahead of time. `fuel` pays for each cell a scan passes.
+    fn step(&mut self, ops: &[Op], fuel: &mut u64) -> Option<()> {
+        match &ops[self.state.pc] {
+            Op::PtrAdd(n) => self.move_ptr(*n),
+            Op::ValAdd(offset, n) => self.add(*offset, *n)?,
+            Op::ValSub(offset, n) => self.add(*offset, 0u32.wrapping_sub(*n))?,
+            Op::Output | Op::OutputAt(_) | Op::OutputRepeat(..) => {
+                let (offset, count) = match ops[self.state.pc] {
+                    Op::OutputAt(offset) => (offset, 1),
+                    Op::OutputRepeat(offset, count) => (offset, count),
+                    _ => (0, 1),
+                };
+                let mut buf = [0; 4];
+                let bytes = cell_bytes(self.get(offset)?, self.unicode, &mut buf);
+                for _ in 0..count {
+                    self.state.output.extend_from_slice(bytes);
+                }
+            }
+            Op::OutputConst(bytes) => self.state.output.extend_from_slice(bytes),
+            // Debug output has to appear when the program runs.
+            Op::Input | Op::InputAt(_) | Op::Debug => return None,
+            // The residual program couldn't know what was defined.
+            Op::DefProc(_) | Op::EndProc | Op::CallProc => return None,
+            // Threads interleave their output as they run.
+            Op::Fork => return None,
+            // The residual program would need the register, and ends the
+            // same way.
+            Op::End | Op::Store | Op::Retrieve | Op::Bitwise(_) => return None,
+            // There is only the one tape.
+            Op::SwitchTape(_) => return None,
+            // The bytes depend on the seed the program runs with.
+            Op::Random => return None,
+            Op::Jz(target) => {
+                if self.get(0)?.is_zero() {
+                    self.state.pc = *target;
+                } else {
+                    if self.depth == 0 {
+                        self.checkpoint = Some(Checkpoint {
+                            pc: self.state.pc,
+                            ptr: self.state.ptr,
+                            output_len: self.state.output.len(),
+                            undo: Vec::new(),
+                        });
+                    }
+                    self.depth += 1;
+                }
+            }
+            Op::Jnz(target) => {
+                if self.get(0)?.is_zero() {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        self.checkpoint = None;
+                    }
+                } else {
+                    self.state.pc = *target;
+                }
+            }
+            Op::Clear(offset) => self.set(*offset, C::default())?,
+            Op::Set(offset, n) => self.set(*offset, C::from_u32(*n))?,
+            Op::MulAdd(offset, factor) => {
+                let v = self.get(0)?;
+                if !v.is_zero() {
+                    let product = v.wrapping_mul(C::from_u32(*factor));
+                    let target = self.get(*offset)?.wrapping_add(product);
+                    self.set(*offset, target)?;
+                }
+            }
+            Op::AddTo(offset) => {
+                let v = self.get(0)?;
+                if !v.is_zero() {
+                    let target = self.get(*offset)?.wrapping_add(v);
+                    self.set(*offset, target)?;
+                }
+            }
+            Op::SubFrom(offset) => {
+                let v = self.get(0)?;
+                if !v.is_zero() {
+                    let target = self.get(*offset)?.wrapping_sub(v);
+                    self.set(*offset, target)?;
+                }
+            }
+            // Check every target first, so a failed op writes nothing.
+            Op::Transfer(targets) => {
+                let v = self.get(0)?;
+                if !v.is_zero() {
+                    for &(offset, _) in targets {
+                        self.index(offset)?;
+                    }
+                    for &(offset, factor) in targets {
+                        let product = v.wrapping_mul(C::from_u32(factor));
+                        let target = self.get(offset)?.wrapping_add(product);
+                        self.set(offset, target)?;
+                    }
+                    self.set(0, C::default())?;
+                }
+            }
+            Op::ScanLeft | Op::ScanRight | Op::Scan(_) => {
+                let step = match ops[self.state.pc] {
+                    Op::ScanLeft => -1,
+                    Op::ScanRight => 1,
+                    Op::Scan(stride) => stride,
+                    _ => unreachable!(),
+                };
+                while !self.get(0)?.is_zero() {
+                    *fuel = fuel.checked_sub(1)?;
+                    self.move_ptr(step);
+                }
+            }
+            // Check every cell first, so a failed op writes nothing.
+            Op::BulkAdd(deltas) => {
+                for (offset, _) in deltas {
+                    self.index(*offset)?;
+                }
+                for (offset, n) in deltas {
+                    self.add(*offset, *n)?;
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for offset in offsets {
+                    self.index(*offset)?;
+                }
+                for offset in offsets {
+                    self.set(*offset, C::default())?;
+                }
+            }
+            Op::ClearRange(offset, n) => {
+                let offsets = (0..*n as isize).map(|i| offset + i);
+                for offset in offsets.clone() {
+                    self.index(offset)?;
+                }
+                for offset in offsets {
+                    self.set(offset, C::default())?;
+                }
+            }
+            Op::ClearUntilZero(stride) => {
+                while !self.get(0)?.is_zero() {
+                    *fuel = fuel.checked_sub(1)?;
+                    self.set(0, C::default())?;
+                    self.move_ptr(*stride);
+                }
+            }
+            // Worked out on a copy, so the cells it changes can be undone.
+            Op::DivMod => {
+                let ptr = self.state.ptr;
+                if let Some(cells) = self.state.tape.get(ptr..ptr.saturating_add(7)) {
+                    let mut cells = cells.to_vec();
+                    crate::div_mod(&mut cells, 0);
+                    for (i, cell) in cells.into_iter().enumerate() {
+                        if cell != self.state.tape[ptr + i] {
+                            self.set(i as isize, cell)?;
+                        }
+                    }
+                }
+            }
+        }
+        self.state.pc += 1;
+        Some(())
+    }
+
+    /// Undoes everything since the start of the current top-level loop.
+    fn rollback(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            for (idx, old) in checkpoint.undo.into_iter().rev() {
+                self.state.tape[idx] = old;
+            }
+            self.state.pc = checkpoint.pc;
+            self.state.ptr = checkpoint.ptr;
+            self.state.output.truncate(checkpoint.output_len);
+        }
+    }
+}
+
+fn eval_prefix<C: Cell>(ops: Vec<Op>, config: &Config, mut fuel: u64) -> Vec<Op> {
+    let mut eval = Evaluator {
+        state: State {
+            pc: 0,
+            ptr: 0,
+            tape: vec![C::default(); config.tape_size],
+            output: Vec::new(),
+        },
+        policy: config.bounds,
+        unicode: config.unicode,
+        depth: 0,
+        checkpoint: None,
+    };
+
+    while eval.state.pc < ops.len() {
+        let ptr = eval.state.ptr;
+        let stepped = fuel.checked_sub(1).and_then(|left| {
+            fuel = left;
+            eval.step(&ops, &mut fuel)
+        });
+        if stepped.is_none() {
+            // Leave the op to run again at run time from where it started.
+            eval.state.ptr = ptr;
+            break;
+        }
+    }
+    let finished = eval.state.pc >= ops.len();
+    if !finished && eval.depth > 0 {
+        eval.rollback();
+    }
+    let State {
+        pc,
+        ptr,
+        tape,
+        output,
+    } = eval.state;
+
+    let mut new_ops = Vec::new();
+    if !output.is_empty() {
+        new_ops.push(Op::OutputConst(output));
+    }
+    if finished {
+        return new_ops;
+    }
+    for (i, cell) in tape.iter().enumerate() {
+        if !cell.is_zero() {
+            new_ops.push(Op::Set(i as isize, cell.to_u32()));
+        }
+    }
+    if ptr != 0 {
+        new_ops.push(Op::PtrAdd(ptr as isize));
+    }
+    new_ops.extend_from_slice(&ops[pc..]);
+    link_jumps(&mut new_ops);
+    new_ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimize, parse};
+
+    fn eval(code: &[u8], fuel: u64) -> Vec<Op> {
+        let ops = optimize(parse(code).unwrap());
+        partial_eval(ops, &Config::default(), CellWidth::U8, fuel)
+    }
+
+    #[test]
+    fn test_eval_pure_program() {
+        let code = std::fs::read("examples/helloworld.bf").unwrap();
+        assert_eq!(
+            eval(&code, DEFAULT_FUEL),
+            vec![Op::OutputConst(b"Hello, World!".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_eval_stops_at_input() {
+        assert_eq!(
+            eval(b"++++++++[>++++++++<-]>+.>+,.", DEFAULT_FUEL),
+            vec![
+                Op::OutputConst(b"A".to_vec()),
+                Op::Set(1, 65),
+                Op::Set(2, 1),
+                Op::InputAt(2),
+                Op::OutputAt(2),
+                Op::PtrAdd(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_eval_out_of_fuel_backs_up_to_loop() {
+        // The `.` keeps the optimizer from working the loop out itself.
+        let ops = eval(b"+.++[>+++[>+<-]<.-]", 10);
+        assert_eq!(ops[..2], [Op::OutputConst(vec![1]), Op::Set(0, 3)]);
+        assert!(matches!(ops[2], Op::Jz(_)));
+
+        // The residual program still does the rest of the work.
+        let mut interp = crate::Interpreter::new(ops, std::io::empty(), Vec::new());
+        interp.run().unwrap();
+        assert_eq!(&interp.tape()[..3], &[0, 0, 9]);
+    }
+}
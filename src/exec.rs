@@ -0,0 +1,324 @@
+//! A minimal executor that doesn't need `std` or allocate: it runs ops on
+//! a tape the caller provides, reading and writing through callbacks. This is what the crate offers without its `std` feature, for
+//! embedded targets; [`Interpreter`] is faster and has limits, profiling
+//! and tracing.
+//!
+//! Leaving the tape is always an [`OutOfBounds`] error, even with
+//! [`BoundsPolicy::Unchecked`]. A tape the caller provides can't grow, so
+//! [`BoundsPolicy::Grow`] and [`BoundsPolicy::Bidirectional`] fail like
+//! [`BoundsPolicy::Error`] too.
+//!
+//! pbrain's procedures need a table and a call stack, so they aren't
+//! supported either: definitions are skipped and calls do nothing.
+//! Brainfork's forks need threads, so `Y` only clears the current cell, as
+//! it does in the thread that forks. There is only the caller's tape, so
+//! switching tapes does nothing. `?`'s random bytes always come from seed
+//! 0, the interpreter's default.
+//!
+//! [`Interpreter`]: crate::Interpreter
+
+use core::fmt;
+
+use crate::Op;
+use crate::cell::Cell;
+use crate::testgen::Generator;
+
+/// What happens when the data pointer moves past either end of the tape.
+///
+/// The policy applies to each op's pointer arithmetic, after the parser and
+/// optimizer have folded moves into offsets. That is the same as moving one
+/// cell at a time for every policy but [`Clamp`](BoundsPolicy::Clamp).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BoundsPolicy {
+    /// Touching a cell outside the tape stops the run with an
+    /// [`OutOfBounds`] error naming the op. The pointer itself may wander
+    /// off the tape as long as nothing is read or written there.
+    #[default]
+    Error,
+    /// The pointer stops at the edges: moving, or reaching with an offset,
+    /// past either end lands on the cell at that end. Folded moves are
+    /// clamped as a whole, so `<+>` on cell 0 leaves the pointer on cell 0.
+    /// The optimizer would fold more moves, so optimize for it with
+    /// [`Pipeline::for_bounds`](crate::Pipeline::for_bounds).
+    Clamp,
+    /// The tape is circular: moving left of cell 0 lands on the last cell
+    /// and moving right of the last cell lands on cell 0. Offsets a tape's
+    /// length apart name the same cell, so optimize for it with
+    /// [`Pipeline::for_bounds`](crate::Pipeline::for_bounds).
+    Wrap,
+    /// The tape gets longer when a cell past its right end is touched;
+    /// touching a cell left of cell 0 is an error, as with
+    /// [`Error`](BoundsPolicy::Error).
+    Grow,
+    /// The tape gets longer at whichever end a cell past it is touched, so
+    /// the pointer may go left of cell 0 as far as it likes. The
+    /// [`Interpreter`](crate::Interpreter) keeps the cells left of cell 0
+    /// at the end of its tape, with cell -1 last.
+    Bidirectional,
+    /// Nothing is checked beyond what the host does anyway: the
+    /// [`Interpreter`](crate::Interpreter) and Rust code from `bfc` panic,
+    /// and C or LLVM output has undefined behavior.
+    Unchecked,
+}
+
+/// What `,` stores when the input is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EofBehavior {
+    /// Leave the current cell as it was.
+    #[default]
+    Unchanged,
+    /// Store 0.
+    Zero,
+    /// Store -1, i.e. every bit set (255 for 8-bit cells).
+    MinusOne,
+}
+
+impl EofBehavior {
+    /// The value to store at EOF, or `None` to keep the cell.
+    pub fn value<C: Cell>(self) -> Option<C> {
+        match self {
+            EofBehavior::Unchanged => None,
+            EofBehavior::Zero => Some(C::default()),
+            EofBehavior::MinusOne => Some(C::from_u32(u32::MAX)),
+        }
+    }
+}
+
+/// Why [`run`] stopped: the op at `pc` touched a cell outside the tape.
+/// An [`Interpreter`](crate::Interpreter) run fails with this too, wrapped
+/// in an `io::Error`, unless the policy is [`BoundsPolicy::Unchecked`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds {
+    pub pc: usize,
+}
+
+impl fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "op {} touched a cell outside the tape", self.pc)
+    }
+}
+
+impl core::error::Error for OutOfBounds {}
+
+/// Runs `ops` to the end on `tape`, starting at cell 0. `input` returns the
+/// next byte for `,`, or `None` at the end of input; `output` receives each
+/// byte `.` writes. Returns where the data pointer ended up.
+pub fn run<C: Cell>(
+    ops: &[Op],
+    tape: &mut [C],
+    policy: BoundsPolicy,
+    eof: EofBehavior,
+    mut input: impl FnMut() -> Option<u8>,
+    mut output: impl FnMut(u8),
+) -> Result<usize, OutOfBounds> {
+    let len = tape.len();
+    let eof = eof.value::<C>();
+    let mut pc = 0;
+    let mut ptr = 0usize;
+    let mut storage = C::default();
+    let mut random = Generator::new(0);
+    while pc < ops.len() {
+        let at = |offset: isize| index(ptr, offset, len, policy).ok_or(OutOfBounds { pc });
+        match &ops[pc] {
+            Op::PtrAdd(n) => {
+                // Only the pointer moves that settle on a cell check it;
+                // the others wait until a cell is used, like the
+                // interpreter.
+                ptr = match policy {
+                    BoundsPolicy::Clamp | BoundsPolicy::Wrap => at(*n)?,
+                    _ => ptr.wrapping_add_signed(*n),
+                };
+            }
+            Op::ValAdd(offset, n) => {
+                let i = at(*offset)?;
+                tape[i] = tape[i].wrapping_add(C::from_u32(*n));
+            }
+            Op::ValSub(offset, n) => {
+                let i = at(*offset)?;
+                tape[i] = tape[i].wrapping_sub(C::from_u32(*n));
+            }
+            Op::Output => output(tape[at(0)?].to_byte()),
+            Op::OutputAt(offset) => output(tape[at(*offset)?].to_byte()),
+            Op::OutputConst(bytes) => bytes.iter().for_each(|&b| output(b)),
+            Op::OutputRepeat(offset, count) => {
+                let byte = tape[at(*offset)?].to_byte();
+                (0..*count).for_each(|_| output(byte));
+            }
+            Op::Input | Op::InputAt(_) => {
+                let offset = match ops[pc] {
+                    Op::InputAt(offset) => offset,
+                    _ => 0,
+                };
+                let i = at(offset)?;
+                if let Some(v) = input().map(C::from_byte).or(eof) {
+                    tape[i] = v;
+                }
+            }
+            Op::Jz(target) => {
+                if tape[at(0)?].is_zero() {
+                    pc = *target;
+                }
+            }
+            Op::Jnz(target) => {
+                if !tape[at(0)?].is_zero() {
+                    pc = *target;
+                }
+            }
+            Op::Clear(offset) => tape[at(*offset)?] = C::default(),
+            Op::Set(offset, n) => tape[at(*offset)?] = C::from_u32(*n),
+            Op::MulAdd(offset, factor) => {
+                let v = tape[at(0)?];
+                if !v.is_zero() {
+                    let i = at(*offset)?;
+                    tape[i] = tape[i].wrapping_add(v.wrapping_mul(C::from_u32(*factor)));
+                }
+            }
+            Op::AddTo(offset) => {
+                let v = tape[at(0)?];
+                if !v.is_zero() {
+                    let i = at(*offset)?;
+                    tape[i] = tape[i].wrapping_add(v);
+                }
+            }
+            Op::SubFrom(offset) => {
+                let v = tape[at(0)?];
+                if !v.is_zero() {
+                    let i = at(*offset)?;
+                    tape[i] = tape[i].wrapping_sub(v);
+                }
+            }
+            Op::Transfer(targets) => {
+                let src = at(0)?;
+                let v = tape[src];
+                if !v.is_zero() {
+                    for (offset, factor) in targets {
+                        let i = at(*offset)?;
+                        tape[i] = tape[i].wrapping_add(v.wrapping_mul(C::from_u32(*factor)));
+                    }
+                    tape[src] = C::default();
+                }
+            }
+            Op::ScanLeft | Op::ScanRight => {
+                let step = if ops[pc] == Op::ScanLeft { -1 } else { 1 };
+                let mut i = at(0)?;
+                // A wrapping tape without a zero cell spins forever, like
+                // the loop the scan replaces.
+                while !tape[i].is_zero() {
+                    i = index(i, step, len, policy).ok_or(OutOfBounds { pc })?;
+                }
+                ptr = i;
+            }
+            Op::Scan(stride) => {
+                let mut i = at(0)?;
+                while !tape[i].is_zero() {
+                    i = index(i, *stride, len, policy).ok_or(OutOfBounds { pc })?;
+                }
+                ptr = i;
+            }
+            Op::BulkAdd(deltas) => {
+                for (offset, n) in deltas {
+                    let i = at(*offset)?;
+                    tape[i] = tape[i].wrapping_add(C::from_u32(*n));
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for offset in offsets {
+                    tape[at(*offset)?] = C::default();
+                }
+            }
+            Op::ClearRange(offset, n) => match ptr.checked_add_signed(*offset) {
+                Some(start) if start.checked_add(*n).is_some_and(|end| end <= len) => {
+                    tape[start..start + n].fill(C::default());
+                }
+                // Off the tape, each cell goes by the policy.
+                _ => {
+                    for i in 0..*n {
+                        tape[at(offset + i as isize)?] = C::default();
+                    }
+                }
+            },
+            Op::ClearUntilZero(stride) => {
+                let mut i = at(0)?;
+                while !tape[i].is_zero() {
+                    tape[i] = C::default();
+                    i = index(i, *stride, len, policy).ok_or(OutOfBounds { pc })?;
+                }
+                ptr = i;
+            }
+            // There is no stderr to print to.
+            Op::Debug => {}
+            Op::DefProc(end) => pc = *end,
+            Op::EndProc | Op::CallProc => {}
+            // The forking thread carries on alone.
+            Op::Fork => tape[at(0)?] = C::default(),
+            Op::End => break,
+            Op::Store => storage = tape[at(0)?],
+            Op::Retrieve => tape[at(0)?] = storage,
+            Op::Bitwise(bit) => {
+                let i = at(0)?;
+                tape[i] = C::from_u32(bit.apply(tape[i].to_u32(), storage.to_u32()));
+            }
+            Op::SwitchTape(_) => {}
+            Op::DivMod => crate::div_mod(tape, ptr),
+            Op::Random => tape[at(0)?] = C::from_u32(u32::from(random.next() as u8)),
+        }
+        pc += 1;
+    }
+    Ok(ptr)
+}
+
+/// The cell `offset` away from `ptr`, if there is one.
+pub(crate) fn index(ptr: usize, offset: isize, len: usize, policy: BoundsPolicy) -> Option<usize> {
+    match policy {
+        BoundsPolicy::Clamp | BoundsPolicy::Wrap if len == 0 => None,
+        BoundsPolicy::Clamp => Some(ptr.saturating_add_signed(offset).min(len - 1)),
+        BoundsPolicy::Wrap => {
+            Some((ptr as isize).wrapping_add(offset).rem_euclid(len as isize) as usize)
+        }
+        BoundsPolicy::Error
+        | BoundsPolicy::Grow
+        | BoundsPolicy::Bidirectional
+        | BoundsPolicy::Unchecked => ptr.checked_add_signed(offset).filter(|&i| i < len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::{optimize, parse};
+
+    #[test]
+    fn test_run() {
+        let code = b"++++++++[>++++++++<-]>+.,[.,]<<";
+        let mut tape = [0u8; 4];
+        let mut input = b"hi".iter().copied();
+        let mut output = Vec::new();
+        let result = run(
+            &optimize(parse(code).unwrap()),
+            &mut tape,
+            BoundsPolicy::Wrap,
+            EofBehavior::Zero,
+            || input.next(),
+            |b| output.push(b),
+        );
+        assert_eq!(result, Ok(3));
+        assert_eq!(output, b"Ahi");
+
+        let mut tape = [0u8; 4];
+        let ops = parse(b"+[>+]").unwrap();
+        let result = run(
+            &ops,
+            &mut tape,
+            BoundsPolicy::Error,
+            EofBehavior::Unchanged,
+            || None,
+            |_| {},
+        );
+        assert_eq!(result, Err(OutOfBounds { pc: 2 }));
+        assert_eq!(tape, [1, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,512 @@
+//! A C interface to the interpreter, for embedding it in programs written
+//! in other languages. Build it as a shared library with
+//! `cargo rustc --release --lib --crate-type cdylib` (a `cdylib` in
+//! `Cargo.toml` would break `no_std` builds); `include/brainfuck.h`
+//! declares the functions.
+//!
+//! Every function returns one of the `BF_*` codes, 0 on success, and
+//! [`bf_strerror`] describes them. Programs come from [`bf_parse`] and are
+//! freed with [`bf_program_free`]; output buffers are allocated here and
+//! freed with [`bf_free`]. Programs run on the default 30,000-cell tape of
+//! bytes, either in one call or step by step on a [`BfInterp`].
+
+use std::collections::VecDeque;
+use std::ffi::{CStr, c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::{mem, ptr, slice, thread};
+
+use crate::{Config, Interpreter, Op, OptLevel, RuntimeError, optimize_with, parse};
+
+pub const BF_OK: c_int = 0;
+/// A required pointer argument was null.
+pub const BF_ERR_NULL: c_int = -1;
+/// The program has an unmatched bracket.
+pub const BF_ERR_PARSE: c_int = -2;
+/// The program ran for more ops than allowed.
+pub const BF_ERR_STEP_LIMIT: c_int = -3;
+/// The program moved the pointer off the tape.
+pub const BF_ERR_OUT_OF_BOUNDS: c_int = -4;
+/// Running the program failed some other way.
+pub const BF_ERR_IO: c_int = -5;
+/// An argument is out of range.
+pub const BF_ERR_ARG: c_int = -6;
+
+/// A parsed and optimized program.
+pub struct BfProgram {
+    ops: Vec<Op>,
+}
+
+/// A program being run step by step, with its pending input and output.
+pub struct BfInterp {
+    interp: Interpreter<VecDeque<u8>, Vec<u8>, u8>,
+    /// Set once the program left the tape, which leaves the interpreter
+    /// mid-op.
+    failed: bool,
+}
+
+/// A static, NUL-terminated description of `code`.
+#[unsafe(no_mangle)]
+pub extern "C" fn bf_strerror(code: c_int) -> *const c_char {
+    let message: &'static CStr = match code {
+        BF_OK => c"success",
+        BF_ERR_NULL => c"null pointer argument",
+        BF_ERR_PARSE => c"unmatched bracket",
+        BF_ERR_STEP_LIMIT => c"step limit exceeded",
+        BF_ERR_OUT_OF_BOUNDS => c"pointer left the tape",
+        BF_ERR_IO => c"I/O error",
+        BF_ERR_ARG => c"argument out of range",
+        _ => c"unknown error",
+    };
+    message.as_ptr()
+}
+
+/// Parses and optimizes the `len` bytes at `code`, storing the program in
+/// `*out`.
+///
+/// # Safety
+///
+/// `code` must point to `len` readable bytes and `out` to writable storage
+/// for a pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_parse(code: *const u8, len: usize, out: *mut *mut BfProgram) -> c_int {
+    // SAFETY: the caller upholds the same requirements.
+    unsafe { bf_parse_level(code, len, 2, out) }
+}
+
+/// [`bf_parse`] at optimization level `level`, 0 to 3.
+///
+/// # Safety
+///
+/// As for [`bf_parse`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_parse_level(
+    code: *const u8,
+    len: usize,
+    level: c_int,
+    out: *mut *mut BfProgram,
+) -> c_int {
+    if code.is_null() || out.is_null() {
+        return BF_ERR_NULL;
+    }
+    let level = match level {
+        0 => OptLevel::O0,
+        1 => OptLevel::O1,
+        2 => OptLevel::O2,
+        3 => OptLevel::O3,
+        _ => return BF_ERR_ARG,
+    };
+    // SAFETY: the caller guarantees `code` points to `len` bytes.
+    let source = unsafe { slice::from_raw_parts(code, len) };
+    match parse(source) {
+        Ok(ops) => {
+            let program = Box::new(BfProgram {
+                ops: optimize_with(ops, level),
+            });
+            // SAFETY: the caller guarantees `out` is writable.
+            unsafe { out.write(Box::into_raw(program)) };
+            BF_OK
+        }
+        Err(_) => BF_ERR_PARSE,
+    }
+}
+
+/// The number of ops in `program`.
+///
+/// # Safety
+///
+/// `program` must come from [`bf_parse`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_program_len(program: *const BfProgram) -> usize {
+    // SAFETY: the caller guarantees `program` is live.
+    unsafe { program.as_ref() }.map_or(0, |program| program.ops.len())
+}
+
+/// Frees a program from [`bf_parse`]. Null is ignored.
+///
+/// # Safety
+///
+/// `program` must be null or come from [`bf_parse`], and not be used
+/// afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_program_free(program: *mut BfProgram) {
+    if !program.is_null() {
+        // SAFETY: `program` came from `Box::into_raw` in `bf_parse`.
+        drop(unsafe { Box::from_raw(program) });
+    }
+}
+
+/// Runs `program` on the `input_len` bytes at `input` (which may be null
+/// if there are none), stopping after `max_steps` ops unless it is 0. On
+/// success, and also after [`BF_ERR_STEP_LIMIT`] and
+/// [`BF_ERR_OUT_OF_BOUNDS`], stores the output written so far in `*out`
+/// and `*out_len`, to be freed with [`bf_free`].
+///
+/// Leaving the tape is caught, but Rust still reports it on stderr.
+///
+/// # Safety
+///
+/// `program` must come from [`bf_parse`], `input` must point to
+/// `input_len` readable bytes unless it is null, and `out` and `out_len`
+/// must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_run_program(
+    program: *const BfProgram,
+    input: *const u8,
+    input_len: usize,
+    max_steps: u64,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    if program.is_null() || out.is_null() || out_len.is_null() || (input.is_null() && input_len > 0)
+    {
+        return BF_ERR_NULL;
+    }
+    // SAFETY: the caller guarantees these point to live values.
+    let (program, input) = unsafe {
+        let input = if input.is_null() {
+            &[][..]
+        } else {
+            slice::from_raw_parts(input, input_len)
+        };
+        (&*program, input)
+    };
+    let config = Config {
+        max_steps: (max_steps > 0).then_some(max_steps),
+        ..Config::default()
+    };
+    let mut output = Vec::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        Interpreter::<_, _, u8>::with_config(program.ops.clone(), input, &mut output, &config).run()
+    }));
+    // SAFETY: the caller guarantees `out` and `out_len` are writable.
+    unsafe { hand_over(output, out, out_len) };
+    status(result).err().unwrap_or(BF_OK)
+}
+
+/// The error code for a run that failed, or what it returned.
+fn status<T>(result: thread::Result<Result<T, RuntimeError>>) -> Result<T, c_int> {
+    match result {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(RuntimeError::StepLimitExceeded(_))) => Err(BF_ERR_STEP_LIMIT),
+        Ok(Err(RuntimeError::PointerOutOfBounds { .. })) => Err(BF_ERR_OUT_OF_BOUNDS),
+        Ok(Err(_)) => Err(BF_ERR_IO),
+        Err(_) => Err(BF_ERR_OUT_OF_BOUNDS),
+    }
+}
+
+/// Stores `output` in `*out` and `*out_len` for [`bf_free`].
+///
+/// # Safety
+///
+/// `out` and `out_len` must be writable.
+unsafe fn hand_over(output: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) {
+    let output = Box::into_raw(output.into_boxed_slice());
+    // SAFETY: the caller guarantees `out` and `out_len` are writable.
+    unsafe {
+        out_len.write(output.len());
+        out.write(output.cast());
+    }
+}
+
+/// Parses `code` and runs it, like [`bf_parse`] followed by
+/// [`bf_run_program`].
+///
+/// # Safety
+///
+/// As for [`bf_parse`] and [`bf_run_program`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_run(
+    code: *const u8,
+    code_len: usize,
+    input: *const u8,
+    input_len: usize,
+    max_steps: u64,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    let mut program = ptr::null_mut();
+    // SAFETY: the caller upholds both functions' requirements.
+    unsafe {
+        let code = bf_parse(code, code_len, &mut program);
+        if code != BF_OK {
+            return code;
+        }
+        let code = bf_run_program(program, input, input_len, max_steps, out, out_len);
+        bf_program_free(program);
+        code
+    }
+}
+
+/// Loads `program` into a new interpreter, storing it in `*out`. It stops
+/// after `max_steps` ops in total unless that is 0.
+///
+/// # Safety
+///
+/// `program` must come from [`bf_parse`] and `out` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_new(
+    program: *const BfProgram,
+    max_steps: u64,
+    out: *mut *mut BfInterp,
+) -> c_int {
+    if program.is_null() || out.is_null() {
+        return BF_ERR_NULL;
+    }
+    // SAFETY: the caller guarantees `program` is live.
+    let ops = unsafe { &*program }.ops.clone();
+    let config = Config {
+        max_steps: (max_steps > 0).then_some(max_steps),
+        ..Config::default()
+    };
+    let interp = Box::new(BfInterp {
+        interp: Interpreter::with_config(ops, VecDeque::new(), Vec::new(), &config),
+        failed: false,
+    });
+    // SAFETY: the caller guarantees `out` is writable.
+    unsafe { out.write(Box::into_raw(interp)) };
+    BF_OK
+}
+
+/// Appends the `len` bytes at `input` to what `,` reads. Once they are
+/// used up, `,` sees EOF.
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`] and `input` must point to
+/// `len` readable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_feed(
+    interp: *mut BfInterp,
+    input: *const u8,
+    len: usize,
+) -> c_int {
+    if interp.is_null() || input.is_null() {
+        return BF_ERR_NULL;
+    }
+    // SAFETY: the caller guarantees both are live.
+    let (interp, input) = unsafe { (&mut *interp, slice::from_raw_parts(input, len)) };
+    interp.interp.io_mut().0.extend(input);
+    BF_OK
+}
+
+/// Runs at most `steps` more ops, storing in `*finished` (unless it is
+/// null) whether the program ended. Pass `UINT64_MAX` to run it to the end.
+/// After [`BF_ERR_OUT_OF_BOUNDS`] every further run fails the same way.
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`] and `finished` must be null or
+/// writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_run_for(
+    interp: *mut BfInterp,
+    steps: u64,
+    finished: *mut c_int,
+) -> c_int {
+    // SAFETY: the caller guarantees `interp` is null or live.
+    let Some(interp) = (unsafe { interp.as_mut() }) else {
+        return BF_ERR_NULL;
+    };
+    if interp.failed {
+        return BF_ERR_OUT_OF_BOUNDS;
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(|| interp.interp.run_for(steps)));
+    match status(result) {
+        Ok(done) => {
+            if !finished.is_null() {
+                // SAFETY: the caller guarantees `finished` is writable.
+                unsafe { finished.write(c_int::from(done)) };
+            }
+            BF_OK
+        }
+        Err(code) => {
+            interp.failed = code == BF_ERR_OUT_OF_BOUNDS;
+            code
+        }
+    }
+}
+
+/// Stores the output written since the last call in `*out` and `*out_len`,
+/// to be freed with [`bf_free`].
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`], and `out` and `out_len` must
+/// be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_take_output(
+    interp: *mut BfInterp,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    if interp.is_null() || out.is_null() || out_len.is_null() {
+        return BF_ERR_NULL;
+    }
+    // SAFETY: the caller guarantees all three are live.
+    unsafe {
+        let output = mem::take((*interp).interp.io_mut().1);
+        hand_over(output, out, out_len);
+    }
+    BF_OK
+}
+
+/// The cells of the tape, storing their number in `*len`. The pointer is
+/// valid until `interp` runs again or is freed.
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`] and `len` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_tape(interp: *const BfInterp, len: *mut usize) -> *const u8 {
+    if interp.is_null() || len.is_null() {
+        return ptr::null();
+    }
+    // SAFETY: the caller guarantees both are live.
+    unsafe {
+        let tape = (*interp).interp.tape();
+        len.write(tape.len());
+        tape.as_ptr()
+    }
+}
+
+/// The data pointer.
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_ptr(interp: *const BfInterp) -> usize {
+    // SAFETY: the caller guarantees `interp` is null or live.
+    unsafe { interp.as_ref() }.map_or(0, |interp| interp.interp.ptr())
+}
+
+/// The index of the next op to execute.
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_pc(interp: *const BfInterp) -> usize {
+    // SAFETY: the caller guarantees `interp` is null or live.
+    unsafe { interp.as_ref() }.map_or(0, |interp| interp.interp.pc())
+}
+
+/// The number of ops executed so far.
+///
+/// # Safety
+///
+/// `interp` must come from [`bf_interp_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_steps(interp: *const BfInterp) -> u64 {
+    // SAFETY: the caller guarantees `interp` is null or live.
+    unsafe { interp.as_ref() }.map_or(0, |interp| interp.interp.steps())
+}
+
+/// Frees an interpreter from [`bf_interp_new`]. Null is ignored.
+///
+/// # Safety
+///
+/// `interp` must be null or come from [`bf_interp_new`], and not be used
+/// afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_interp_free(interp: *mut BfInterp) {
+    if !interp.is_null() {
+        // SAFETY: `interp` came from `Box::into_raw` in `bf_interp_new`.
+        drop(unsafe { Box::from_raw(interp) });
+    }
+}
+
+/// Frees an output buffer of `len` bytes from [`bf_run`],
+/// [`bf_run_program`] or [`bf_interp_take_output`]. Null is ignored.
+///
+/// # Safety
+///
+/// `buf` must be null or a buffer from this library with its length, and
+/// not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bf_free(buf: *mut u8, len: usize) {
+    if !buf.is_null() {
+        // SAFETY: `buf` and `len` describe a boxed slice made by
+        // `hand_over`.
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(buf, len)) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(code: &[u8], input: &[u8], max_steps: u64) -> (c_int, Vec<u8>) {
+        let mut out = ptr::null_mut();
+        let mut len = 0;
+        // SAFETY: every pointer refers to a live local.
+        unsafe {
+            let code = bf_run(
+                code.as_ptr(),
+                code.len(),
+                input.as_ptr(),
+                input.len(),
+                max_steps,
+                &mut out,
+                &mut len,
+            );
+            let output = if out.is_null() {
+                Vec::new()
+            } else {
+                slice::from_raw_parts(out, len).to_vec()
+            };
+            bf_free(out, len);
+            (code, output)
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run(b",.,.", b"hi", 0), (BF_OK, b"hi".to_vec()));
+        assert_eq!(run(b"+.[]", b"", 100), (BF_ERR_STEP_LIMIT, vec![1]));
+        assert_eq!(run(b"[", b"", 0), (BF_ERR_PARSE, vec![]));
+        assert_eq!(run(b"<+", b"", 0).0, BF_ERR_OUT_OF_BOUNDS);
+
+        // SAFETY: `bf_strerror` returns a static C string.
+        let message = unsafe { CStr::from_ptr(bf_strerror(BF_ERR_PARSE)) };
+        assert_eq!(message.to_str(), Ok("unmatched bracket"));
+        // SAFETY: null arguments are rejected before use.
+        assert_eq!(
+            unsafe { bf_parse(ptr::null(), 0, ptr::null_mut()) },
+            BF_ERR_NULL
+        );
+    }
+
+    #[test]
+    fn test_interp() {
+        let code = b",[.,]";
+        let mut program = ptr::null_mut();
+        let mut interp = ptr::null_mut();
+        let mut finished = 0;
+        let mut out = ptr::null_mut();
+        let mut len = 0;
+        // SAFETY: every pointer refers to a live local or handle.
+        unsafe {
+            assert_eq!(bf_parse(code.as_ptr(), code.len(), &mut program), BF_OK);
+            assert_eq!(bf_interp_new(program, 0, &mut interp), BF_OK);
+            bf_program_free(program);
+            assert_eq!(bf_interp_feed(interp, b"ab".as_ptr(), 2), BF_OK);
+            assert_eq!(bf_interp_run_for(interp, 2, &mut finished), BF_OK);
+            assert_eq!(
+                (finished, bf_interp_pc(interp), bf_interp_steps(interp)),
+                (0, 2, 2)
+            );
+
+            assert_eq!(bf_interp_feed(interp, [b'c', 0].as_ptr(), 2), BF_OK);
+            assert_eq!(bf_interp_run_for(interp, u64::MAX, &mut finished), BF_OK);
+            assert_eq!(finished, 1);
+            assert_eq!(bf_interp_take_output(interp, &mut out, &mut len), BF_OK);
+            assert_eq!(slice::from_raw_parts(out, len), b"abc");
+            bf_free(out, len);
+
+            let tape = bf_interp_tape(interp, &mut len);
+            assert_eq!((len, *tape, bf_interp_ptr(interp)), (30_000, 0, 0));
+            bf_interp_free(interp);
+        }
+    }
+}
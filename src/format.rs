@@ -0,0 +1,253 @@
+//! Minifying and pretty-printing programs, for `bf fmt` and other tools.
+//!
+//! [`pieces`] splits a source into commands and the comments between
+//! them, and [`minify`] and [`pretty`] print the pieces back out in the
+//! standard spelling.
+
+use alloc::vec::Vec;
+
+use crate::{ParseOptions, TokenKind, is_command};
+
+/// A piece of a program's source, as [`pieces`] splits it for printing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Piece<'a> {
+    /// A command, in its standard spelling.
+    Command(u8),
+    /// Text between commands.
+    Comment(&'a [u8]),
+}
+
+/// Splits `source` into the commands `options` parse and the comments
+/// around them, as [`crate::tokenize`] does, ready to print. With a token
+/// map, comments that spell standard commands are split around them, so
+/// printing the pieces doesn't add commands.
+pub fn pieces<'a>(source: &'a [u8], options: &ParseOptions) -> Vec<Piece<'a>> {
+    let standard = ParseOptions {
+        tokens: None,
+        ..options.clone()
+    };
+    let mut pieces = Vec::new();
+    for token in crate::tokenize(source, options) {
+        match token.kind {
+            TokenKind::Command(command) => pieces.push(Piece::Command(command)),
+            TokenKind::Comment => push_comment(&mut pieces, &source[token.span], &standard),
+        }
+    }
+    pieces
+}
+
+fn push_comment<'a>(pieces: &mut Vec<Piece<'a>>, text: &'a [u8], standard: &ParseOptions) {
+    pieces.extend(
+        text.split(|&b| is_command(b, standard))
+            .filter(|text| !text.is_empty())
+            .map(Piece::Comment),
+    );
+}
+
+/// How to lay out a program with [`pretty`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// The longest a line of commands gets, indentation included, unless a
+    /// single loop doesn't fit.
+    pub width: usize,
+    /// Spaces of indentation per loop.
+    pub indent: usize,
+    /// Keep comments, each line on a line of its own.
+    pub comments: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            width: 80,
+            indent: 4,
+            comments: false,
+        }
+    }
+}
+
+/// The commands in `pieces`, without comments.
+pub fn minify(pieces: &[Piece<'_>]) -> Vec<u8> {
+    pieces
+        .iter()
+        .filter_map(|piece| match piece {
+            Piece::Command(command) => Some(*command),
+            Piece::Comment(_) => None,
+        })
+        .collect()
+}
+
+/// Lays `pieces` out with the brackets of each loop on lines of their own
+/// and its body indented. Loops without loops inside stay on one line if
+/// they fit, and run on with the commands around them. pbrain procedures
+/// are indented like loops.
+pub fn pretty(pieces: &[Piece<'_>], options: &FormatOptions) -> Vec<u8> {
+    let mut out = Printer {
+        out: Vec::new(),
+        line: Vec::new(),
+        depth: 0,
+        options,
+    };
+    let mut i = 0;
+    while i < pieces.len() {
+        match pieces[i] {
+            Piece::Command(open @ (b'[' | b'(')) => match out.inline_loop(&pieces[i..]) {
+                Some(len) => {
+                    out.push(&minify(&pieces[i..i + len]));
+                    i += len;
+                    continue;
+                }
+                None => {
+                    out.write_line(&[open]);
+                    out.depth += 1;
+                }
+            },
+            Piece::Command(close @ (b']' | b')')) => {
+                out.flush();
+                out.depth = out.depth.saturating_sub(1);
+                out.write_line(&[close]);
+            }
+            Piece::Command(command) => out.push(&[command]),
+            Piece::Comment(_) if options.comments => {
+                // Comments split around commands continue each other.
+                let mut text = Vec::new();
+                while let Some(Piece::Comment(part)) = pieces.get(i) {
+                    text.extend_from_slice(part);
+                    i += 1;
+                }
+                for line in text.split(|&b| b == b'\n') {
+                    let line = line.trim_ascii();
+                    if !line.is_empty() {
+                        out.write_line(line);
+                    }
+                }
+                continue;
+            }
+            Piece::Comment(_) => {}
+        }
+        i += 1;
+    }
+    out.flush();
+    out.out
+}
+
+/// The output of [`pretty`] and the line of commands it is filling.
+struct Printer<'a> {
+    out: Vec<u8>,
+    line: Vec<u8>,
+    depth: usize,
+    options: &'a FormatOptions,
+}
+
+impl Printer<'_> {
+    fn indent(&self) -> usize {
+        self.depth * self.options.indent
+    }
+
+    /// Adds `commands` to the current line, starting a new one if they
+    /// don't fit.
+    fn push(&mut self, commands: &[u8]) {
+        if !self.line.is_empty()
+            && self.indent() + self.line.len() + commands.len() > self.options.width
+        {
+            self.flush();
+        }
+        self.line.extend_from_slice(commands);
+    }
+
+    /// Ends the current line, if there is one.
+    fn flush(&mut self) {
+        if !self.line.is_empty() {
+            let line = core::mem::take(&mut self.line);
+            self.write(&line);
+        }
+    }
+
+    /// Writes `text` on a line of its own.
+    fn write_line(&mut self, text: &[u8]) {
+        self.flush();
+        self.write(text);
+    }
+
+    fn write(&mut self, text: &[u8]) {
+        self.out.extend(core::iter::repeat_n(b' ', self.indent()));
+        self.out.extend_from_slice(text);
+        self.out.push(b'\n');
+    }
+
+    /// The number of pieces in the loop `pieces` starts with, if it has no
+    /// loops or kept comments inside and fits on a line.
+    fn inline_loop(&self, pieces: &[Piece<'_>]) -> Option<usize> {
+        let room = self.options.width.saturating_sub(self.indent());
+        let mut len = 1;
+        for (i, piece) in pieces.iter().enumerate().skip(1) {
+            match piece {
+                Piece::Command(b'[' | b'(') => return None,
+                Piece::Command(b']' | b')') => return (len < room).then_some(i + 1),
+                Piece::Command(_) => len += 1,
+                Piece::Comment(text) if self.options.comments && !text.trim_ascii().is_empty() => {
+                    return None;
+                }
+                Piece::Comment(_) => {}
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenMap;
+
+    fn pretty_default(source: &[u8]) -> Vec<u8> {
+        pretty(
+            &pieces(source, &ParseOptions::default()),
+            &FormatOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_minify() {
+        let pieces = pieces(b"add [->+<] # done.\n", &ParseOptions::default());
+        assert_eq!(minify(&pieces), b"[->+<].");
+    }
+
+    #[test]
+    fn test_pretty() {
+        assert_eq!(
+            pretty_default(b"++[>++[-]<-]>."),
+            b"++\n[\n    >++[-]<-\n]\n>.\n"
+        );
+        // Lines wrap, but short loops stay whole.
+        let options = FormatOptions {
+            width: 12,
+            ..FormatOptions::default()
+        };
+        let pieces = pieces(b"++++++++[->+<]>+++++.", &ParseOptions::default());
+        assert_eq!(pretty(&pieces, &options), b"++++++++\n[->+<]>+++++\n.\n");
+        assert_eq!(pretty_default(b""), b"");
+    }
+
+    #[test]
+    fn test_pretty_comments() {
+        let options = FormatOptions {
+            comments: true,
+            ..FormatOptions::default()
+        };
+        let source = b"clear the cell [-]\nmove it: [\n  ->+< right\n]";
+        assert_eq!(
+            pretty(&pieces(source, &ParseOptions::default()), &options),
+            b"clear the cell\n[-]\nmove it:\n[\n    ->+<\n    right\n]\n"
+        );
+
+        // Standard commands are comments under a token map, and dropped.
+        let mut map = TokenMap::new();
+        map.insert(b'+', "inc");
+        let parse = ParseOptions {
+            tokens: Some(map),
+            ..ParseOptions::default()
+        };
+        assert_eq!(pretty(&pieces(b"inc 1+1", &parse), &options), b"+\n11\n");
+    }
+}
@@ -0,0 +1,101 @@
+//! Callbacks from [`Interpreter::run_hooked`] as a program runs, for GUIs
+//! and analysis tools that want to watch it or supply its input without
+//! writing [`Read`](std::io::Read) and [`Write`](std::io::Write) adapters.
+//!
+//! Hooks cost nothing when a program runs without them: only a hooked run
+//! takes the instrumented loop that calls them, like a traced one.
+//!
+//! [`Interpreter::run_hooked`]: crate::Interpreter::run_hooked
+
+/// What a hooked run reports. Every method does nothing by default, so an
+/// implementation only needs the ones it cares about.
+///
+/// Loops are the `[`..`]` pairs left after optimizing; a loop the optimizer
+/// turned into a single op, such as `[-]` or a multiplication loop, isn't
+/// entered or exited. Run an unoptimized program to see every one.
+pub trait Hooks {
+    /// The op at `pc` is about to execute with the pointer at `ptr`.
+    fn on_step(&mut self, pc: usize, ptr: usize) {
+        let _ = (pc, ptr);
+    }
+
+    /// The program wrote `byte`, which also goes to the interpreter's
+    /// writer.
+    fn on_output(&mut self, byte: u8) {
+        let _ = byte;
+    }
+
+    /// The program is about to read a byte. Returning one gives it that
+    /// instead of the next byte of the interpreter's reader; returning
+    /// `None` reads as usual.
+    fn on_input(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// The loop opened at `pc` was entered, its cell being non-zero.
+    fn on_loop_enter(&mut self, pc: usize) {
+        let _ = pc;
+    }
+
+    /// The loop opened at `pc` ended, its cell being zero at the `]`.
+    fn on_loop_exit(&mut self, pc: usize) {
+        let _ = pc;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Interpreter, parse};
+
+    #[derive(Default)]
+    struct Recorder {
+        steps: usize,
+        output: Vec<u8>,
+        input: Vec<u8>,
+        loops: Vec<(bool, usize)>,
+    }
+
+    impl Hooks for Recorder {
+        fn on_step(&mut self, _: usize, _: usize) {
+            self.steps += 1;
+        }
+
+        fn on_output(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+
+        fn on_input(&mut self) -> Option<u8> {
+            self.input.pop()
+        }
+
+        fn on_loop_enter(&mut self, pc: usize) {
+            self.loops.push((true, pc));
+        }
+
+        fn on_loop_exit(&mut self, pc: usize) {
+            self.loops.push((false, pc));
+        }
+    }
+
+    #[test]
+    fn test_run_hooked() {
+        // Echoes two bytes, the first from the hooks and the second from
+        // the reader once the hooks have none left.
+        let ops = parse(b"++[>,.<-]").unwrap();
+        let steps = ops.len();
+        let mut out = Vec::new();
+        let mut interp = Interpreter::new(ops, &b"y"[..], &mut out);
+        let mut hooks = Recorder {
+            input: b"x".to_vec(),
+            ..Recorder::default()
+        };
+        interp.run_hooked(&mut hooks).unwrap();
+        assert_eq!(hooks.output, b"xy");
+        assert_eq!(hooks.steps as u64, interp.steps());
+        assert!(hooks.steps > steps);
+        assert_eq!(hooks.loops, [(true, 1), (false, 1)]);
+        drop(interp);
+        assert_eq!(out, b"xy");
+    }
+}
@@ -0,0 +1,3294 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::{Range, RangeInclusive};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Scope};
+use std::time::{Duration, Instant};
+
+use crate::cell::Cell;
+use crate::exec::OutOfBounds;
+pub use crate::exec::{BoundsPolicy, EofBehavior};
+use crate::hooks::Hooks;
+#[cfg(all(unix, target_arch = "x86_64"))]
+use crate::jit::{HOT_LOOP_THRESHOLD, JitError, JitProgram};
+use crate::profile::Profile;
+use crate::testgen::Generator;
+use crate::trace::{End, Event, Tracer};
+use crate::{Op, ParseError, optimize, parse};
+
+mod executor;
+mod packed;
+mod threaded;
+mod unchecked;
+
+pub use executor::{BfIo, BuiltinExecutor, Executor, Program, Stats, UncheckedExecutor};
+pub use packed::{PackedProgram, lower_to_bytecode};
+pub(crate) use unchecked::tape_reach;
+
+/// Executes an optimized program with the default [`Config`], reading `,`
+/// from `input` and writing `.` to `output`.
+pub fn execute<R: Read, W: Write>(ops: &[Op], input: R, output: W) -> Result<(), RuntimeError> {
+    Interpreter::new(ops.to_vec(), input, output).run()
+}
+
+/// Parses and optimizes `source`, runs it with the default [`Config`] on
+/// `input`, and returns what it wrote.
+pub fn run_program(source: &[u8], input: &[u8]) -> Result<Vec<u8>, Error> {
+    let ops = optimize(parse(source)?);
+    let mut output = Vec::new();
+    execute(&ops, input, &mut output)?;
+    Ok(output)
+}
+
+/// Why [`run_program`] failed.
+#[derive(Debug)]
+pub enum Error {
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => e.fmt(f),
+            Error::Runtime(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Runtime(e) => Some(e),
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(e: RuntimeError) -> Self {
+        Error::Runtime(e)
+    }
+}
+
+/// Tape cells allocated when no size is requested.
+pub const DEFAULT_TAPE_SIZE: usize = 30_000;
+
+/// Ops run between looks at the clock when there is a
+/// [`Config::timeout`].
+const TIME_SLICE: u64 = 1 << 20;
+
+/// Ops each Brainfork thread runs before the next one gets a turn.
+const THREAD_SLICE: u64 = 1 << 12;
+
+/// Execution settings for an [`Interpreter`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Config {
+    /// Number of cells on the tape. Must be non-zero.
+    pub tape_size: usize,
+    pub bounds: BoundsPolicy,
+    pub eof: EofBehavior,
+    /// Ops the interpreter may execute before failing with
+    /// [`StepLimitExceeded`], or `None` for no limit. The same as
+    /// [`ResourceLimits::max_steps`].
+    pub max_steps: Option<u64>,
+    /// Wall-clock time a [`run`](Interpreter::run) may take before failing
+    /// with [`TimeLimitExceeded`], or `None` for no limit. Time spent
+    /// waiting for input counts, but a blocked read isn't interrupted.
+    pub timeout: Option<Duration>,
+    /// When the output handle is flushed.
+    pub flush: FlushPolicy,
+    /// What `,` reads at the end of an input line. Unless it's
+    /// [`Newline::Lf`], which leaves the input alone, each LF, CR or CRLF
+    /// in the input reads as this.
+    pub input_newline: Newline,
+    /// What the program writes at the end of a line, which goes to the
+    /// output as an LF.
+    pub output_newline: Newline,
+    /// Whether `.` writes the cell as a Unicode character, in UTF-8, and
+    /// `,` reads one, for programs on 32-bit cells that work with text
+    /// rather than bytes. A cell that isn't a character writes U+FFFD, and
+    /// so does a malformed sequence in the input. Only the plain
+    /// interpreter runs in this mode; every other engine falls back to it.
+    ///
+    /// The `const-output` pass prints known cells as bytes, so optimize
+    /// without it: [`BuiltinPass::ConstOutput`](crate::BuiltinPass).
+    pub unicode: bool,
+    /// Seeds the random bytes `?` stores, so the same seed gives the same
+    /// run. Like the input, the sequence isn't part of a [`State`]: a
+    /// restored interpreter carries on drawing from where it was, and
+    /// Brainfork threads run with `--threads` each draw from where the
+    /// first one was when they started.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tape_size: DEFAULT_TAPE_SIZE,
+            bounds: BoundsPolicy::default(),
+            eof: EofBehavior::default(),
+            max_steps: None,
+            timeout: None,
+            flush: FlushPolicy::default(),
+            input_newline: Newline::default(),
+            output_newline: Newline::default(),
+            unicode: false,
+            seed: 0,
+        }
+    }
+}
+
+/// A line ending, for the programs that expect one other than the host's:
+/// [`Config::input_newline`] and [`Config::output_newline`] translate
+/// between it and LF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Newline {
+    /// 10, as on Unix: no translation.
+    #[default]
+    Lf,
+    /// 13, as on classic Mac OS.
+    Cr,
+    /// 13 then 10, as on DOS and Windows.
+    CrLf,
+}
+
+/// When an [`Interpreter`] flushes its output handle. Whatever the policy,
+/// the output is flushed when a run stops; every policy but
+/// [`Exit`](FlushPolicy::Exit) also flushes before `,` reads, so prompts
+/// show up.
+///
+/// Flushing only pays off with a buffered handle: wrap the output in an
+/// [`io::BufWriter`] for anything but [`Always`](FlushPolicy::Always).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FlushPolicy {
+    /// After every write.
+    #[default]
+    Always,
+    /// After writing a newline.
+    Newline,
+    /// Once this many bytes have been written since the last flush.
+    Bytes(usize),
+    /// Only before reading input.
+    Input,
+    /// Only when a run stops.
+    Exit,
+}
+
+/// Bounds on what a run may use, for programs that can't be trusted. Set
+/// them with [`Interpreter::set_limits`]; a run that goes over one fails
+/// with the matching [`RuntimeError`]. Any limit but the step limit makes
+/// every engine interpret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResourceLimits {
+    /// Ops to execute before failing with [`StepLimitExceeded`].
+    pub max_steps: Option<u64>,
+    /// Cells the tape may have. A [`BoundsPolicy::Grow`] tape fails with
+    /// [`TapeLimitExceeded`] instead of growing past it, and so does a run
+    /// on a fixed tape that is already bigger.
+    pub max_tape_cells: Option<usize>,
+    /// Bytes to write before failing with [`OutputLimitExceeded`]. Output
+    /// up to the limit is written.
+    pub max_output_bytes: Option<u64>,
+    /// How deeply the program's loops may nest, checked before it runs,
+    /// with [`LoopDepthExceeded`].
+    pub max_loop_depth: Option<usize>,
+}
+
+impl ResourceLimits {
+    /// The limits of `bfi --sandbox`: 10^9 ops, a million cells, 1 MiB of
+    /// output and loops nested 1,000 deep.
+    pub fn sandbox() -> Self {
+        ResourceLimits {
+            max_steps: Some(1_000_000_000),
+            max_tape_cells: Some(1 << 20),
+            max_output_bytes: Some(1 << 20),
+            max_loop_depth: Some(1000),
+        }
+    }
+
+    /// Whether any limit is set.
+    pub(crate) fn any(&self) -> bool {
+        self.max_steps.is_some() || self.beyond_steps()
+    }
+
+    /// Whether any limit but the step limit is set.
+    fn beyond_steps(&self) -> bool {
+        self.max_tape_cells.is_some()
+            || self.max_output_bytes.is_some()
+            || self.max_loop_depth.is_some()
+    }
+}
+
+/// An input handle that translates line endings as
+/// [`Config::input_newline`] asks.
+pub(crate) struct Input<R> {
+    inner: R,
+    newline: Newline,
+    /// Whether the last byte read was a CR, so an LF after it ends the
+    /// same line.
+    after_cr: bool,
+    /// The LF of a CRLF, read next.
+    pending: Option<u8>,
+}
+
+impl<R: Read> Input<R> {
+    pub(crate) fn new(inner: R, newline: Newline) -> Self {
+        Input {
+            inner,
+            newline,
+            after_cr: false,
+            pending: None,
+        }
+    }
+}
+
+impl<R: Read> Read for Input<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.newline == Newline::Lf {
+            return self.inner.read(buf);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(byte) = self.pending.take() {
+            buf[0] = byte;
+            return Ok(1);
+        }
+        loop {
+            let mut byte = 0;
+            if self.inner.read(std::slice::from_mut(&mut byte))? == 0 {
+                return Ok(0);
+            }
+            let after_cr = mem::replace(&mut self.after_cr, byte == b'\r');
+            buf[0] = match byte {
+                b'\n' if after_cr => continue,
+                b'\r' | b'\n' => b'\r',
+                _ => byte,
+            };
+            if byte == b'\r' || byte == b'\n' {
+                self.pending = (self.newline == Newline::CrLf).then_some(b'\n');
+            }
+            return Ok(1);
+        }
+    }
+}
+
+/// An output handle that flushes itself as its [`FlushPolicy`] asks and
+/// translates line endings as [`Config::output_newline`] does. `flush`
+/// always flushes.
+pub(crate) struct Output<W> {
+    inner: W,
+    policy: FlushPolicy,
+    newline: Newline,
+    /// Whether a CR is held back to see if an LF follows it.
+    held_cr: bool,
+    /// Bytes written since the last flush.
+    pending: usize,
+    /// Bytes written in all.
+    written: u64,
+    /// [`ResourceLimits::max_output_bytes`].
+    limit: Option<u64>,
+}
+
+impl<W: Write> Output<W> {
+    pub(crate) fn new(inner: W, policy: FlushPolicy, newline: Newline) -> Self {
+        Output {
+            inner,
+            policy,
+            newline,
+            held_cr: false,
+            pending: 0,
+            written: 0,
+            limit: None,
+        }
+    }
+
+    /// Flushes unless the policy waits for the end of the run. A held CR
+    /// stays held, as the LF to go with it may come after the input.
+    fn flush_for_input(&mut self) -> io::Result<()> {
+        if self.policy == FlushPolicy::Exit {
+            return Ok(());
+        }
+        self.flush_inner()
+    }
+
+    fn flush_inner(&mut self) -> io::Result<()> {
+        self.pending = 0;
+        self.inner.flush()
+    }
+
+    /// Writes `buf` as it is, minding the limit and the policy.
+    fn write_raw(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let buf = match self.limit {
+            Some(limit) if self.written == limit && !buf.is_empty() => {
+                return Err(io::Error::other(OutputLimitExceeded { limit }));
+            }
+            Some(limit) => &buf[..(limit - self.written).min(buf.len() as u64) as usize],
+            None => buf,
+        };
+        let n = self.inner.write(buf)?;
+        self.pending += n;
+        self.written += n as u64;
+        let due = match self.policy {
+            FlushPolicy::Always => true,
+            FlushPolicy::Newline => buf[..n].contains(&b'\n'),
+            FlushPolicy::Bytes(limit) => self.pending >= limit,
+            FlushPolicy::Input | FlushPolicy::Exit => false,
+        };
+        if due {
+            self.flush_inner()?;
+        }
+        Ok(n)
+    }
+
+    fn write_all_raw(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_raw(buf)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Output<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let translated: Vec<u8> = match self.newline {
+            Newline::Lf => return self.write_raw(buf),
+            Newline::Cr => buf
+                .iter()
+                .map(|&b| if b == b'\r' { b'\n' } else { b })
+                .collect(),
+            Newline::CrLf => {
+                let mut translated = Vec::with_capacity(buf.len() + 1);
+                for &b in buf {
+                    if mem::take(&mut self.held_cr) && b != b'\n' {
+                        translated.push(b'\r');
+                    }
+                    match b {
+                        b'\r' => self.held_cr = true,
+                        _ => translated.push(b),
+                    }
+                }
+                translated
+            }
+        };
+        self.write_all_raw(&translated)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if mem::take(&mut self.held_cr) {
+            self.write_all_raw(b"\r")?;
+        }
+        self.flush_inner()
+    }
+}
+
+/// The output as compiled code writes it: flushing before input is its
+/// only flush, and it mustn't give up a held CR.
+#[cfg(all(unix, target_arch = "x86_64"))]
+struct JitOutput<'a, W>(&'a mut Output<W>);
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+impl<W: Write> Write for JitOutput<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush_inner()
+    }
+}
+
+/// Why an [`Interpreter`] run stopped once it has executed
+/// [`Config::max_steps`] ops, in [`RuntimeError::StepLimitExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepLimitExceeded {
+    pub limit: u64,
+}
+
+impl fmt::Display for StepLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "step limit of {} exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for StepLimitExceeded {}
+
+/// Why an [`Interpreter`] run stopped once it has taken longer than
+/// [`Config::timeout`], in [`RuntimeError::TimeLimitExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeLimitExceeded {
+    pub limit: Duration,
+}
+
+impl fmt::Display for TimeLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "time limit of {:?} exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for TimeLimitExceeded {}
+
+/// Why an [`Interpreter`] run stopped when the tape would have grown past
+/// [`ResourceLimits::max_tape_cells`], in
+/// [`RuntimeError::TapeLimitExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapeLimitExceeded {
+    pub limit: usize,
+}
+
+impl fmt::Display for TapeLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "tape limit of {} cells exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for TapeLimitExceeded {}
+
+/// Why an [`Interpreter`] run stopped once it had written
+/// [`ResourceLimits::max_output_bytes`], in
+/// [`RuntimeError::OutputLimitExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputLimitExceeded {
+    pub limit: u64,
+}
+
+impl fmt::Display for OutputLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "output limit of {} bytes exceeded", self.limit)
+    }
+}
+
+impl std::error::Error for OutputLimitExceeded {}
+
+/// Why an [`Interpreter`] didn't start a program whose loops nest `depth`
+/// deep, past [`ResourceLimits::max_loop_depth`], in
+/// [`RuntimeError::LoopDepthExceeded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopDepthExceeded {
+    pub limit: usize,
+    pub depth: usize,
+}
+
+impl fmt::Display for LoopDepthExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "loops nest {} deep, past the limit of {}",
+            self.depth, self.limit
+        )
+    }
+}
+
+impl std::error::Error for LoopDepthExceeded {}
+
+/// Why an [`Interpreter`] run stopped when the `CallProc` at `pc` called
+/// procedure `id`, which no `DefProc` had defined, in
+/// [`RuntimeError::UndefinedProcedure`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndefinedProcedure {
+    pub pc: usize,
+    pub id: u32,
+}
+
+impl fmt::Display for UndefinedProcedure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "op {} called procedure {}, which isn't defined",
+            self.pc, self.id
+        )
+    }
+}
+
+impl std::error::Error for UndefinedProcedure {}
+
+/// What an [`Interpreter`] run fails with. The machine state is left at the
+/// op that failed, or between ops for the limits, so a run can be inspected
+/// or resumed afterwards.
+///
+/// Code that works in [`io::Result`]s can use `?`: the conversion yields
+/// the I/O error itself, or wraps the other variants' payload, so
+/// [`StepLimitExceeded`], [`TimeLimitExceeded`], [`UndefinedProcedure`],
+/// [`OutOfBounds`] and the other limits' errors can be downcast from it.
+#[derive(Debug)]
+pub enum RuntimeError {
+    /// The op at `pc` touched a cell outside the tape, with the data
+    /// pointer at `ptr`.
+    PointerOutOfBounds {
+        pc: usize,
+        ptr: usize,
+    },
+    StepLimitExceeded(StepLimitExceeded),
+    TimeLimitExceeded(TimeLimitExceeded),
+    TapeLimitExceeded(TapeLimitExceeded),
+    OutputLimitExceeded(OutputLimitExceeded),
+    LoopDepthExceeded(LoopDepthExceeded),
+    UndefinedProcedure(UndefinedProcedure),
+    /// Reading input, writing output or tracing failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::PointerOutOfBounds { pc, .. } => OutOfBounds { pc: *pc }.fmt(f),
+            RuntimeError::StepLimitExceeded(e) => e.fmt(f),
+            RuntimeError::TimeLimitExceeded(e) => e.fmt(f),
+            RuntimeError::TapeLimitExceeded(e) => e.fmt(f),
+            RuntimeError::OutputLimitExceeded(e) => e.fmt(f),
+            RuntimeError::LoopDepthExceeded(e) => e.fmt(f),
+            RuntimeError::UndefinedProcedure(e) => e.fmt(f),
+            RuntimeError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RuntimeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RuntimeError {
+    /// Takes the output limit back out of the error the output handle
+    /// failed with.
+    fn from(e: io::Error) -> Self {
+        match e
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<OutputLimitExceeded>())
+        {
+            Some(&limit) => RuntimeError::OutputLimitExceeded(limit),
+            None => RuntimeError::Io(e),
+        }
+    }
+}
+
+impl From<RuntimeError> for io::Error {
+    fn from(e: RuntimeError) -> Self {
+        match e {
+            RuntimeError::PointerOutOfBounds { pc, .. } => io::Error::other(OutOfBounds { pc }),
+            RuntimeError::StepLimitExceeded(e) => io::Error::other(e),
+            RuntimeError::TimeLimitExceeded(e) => io::Error::other(e),
+            RuntimeError::TapeLimitExceeded(e) => io::Error::other(e),
+            RuntimeError::OutputLimitExceeded(e) => io::Error::other(e),
+            RuntimeError::LoopDepthExceeded(e) => io::Error::other(e),
+            RuntimeError::UndefinedProcedure(e) => io::Error::other(e),
+            RuntimeError::Io(e) => e,
+        }
+    }
+}
+
+/// Where [`Interpreter::run_steps`] stopped.
+#[derive(Debug)]
+pub enum StepResult {
+    /// The budget ran out; call [`Interpreter::run_steps`] again to go on.
+    Running,
+    /// The reader failed at a `,` with `WouldBlock`. The `,` runs again
+    /// once the reader has input.
+    NeedsInput,
+    /// The program has ended.
+    Finished,
+    Error(RuntimeError),
+}
+
+/// Pointer arithmetic for one [`BoundsPolicy`], monomorphized into the
+/// execute loop so the default policy pays nothing for the others.
+trait Addressing {
+    /// Tape index `offset` cells away from `ptr`, or `None` if that cell is
+    /// off the tape. May grow the tape to make room.
+    fn index<C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize>;
+
+    /// Like [`index`](Self::index), without growing the tape past `max`
+    /// cells.
+    #[inline(always)]
+    fn index_within<C: Cell>(
+        tape: &mut Vec<C>,
+        ptr: usize,
+        offset: isize,
+        _max: usize,
+    ) -> Option<usize> {
+        Self::index(tape, ptr, offset)
+    }
+
+    /// Where moving the pointer `n` cells leaves it. Only the policies that
+    /// keep the pointer on the tape check anything here.
+    #[inline(always)]
+    fn move_ptr(ptr: usize, n: isize, _len: usize) -> usize {
+        ptr.wrapping_add_signed(n)
+    }
+
+    /// Where a scan for a zero cell stops, or `None` if it leaves the tape.
+    #[inline(always)]
+    fn scan<C: Cell>(tape: &mut Vec<C>, mut ptr: usize, step: isize) -> Option<usize> {
+        loop {
+            let idx = Self::index(tape, ptr, 0)?;
+            if tape[idx].is_zero() {
+                return Some(ptr);
+            }
+            ptr = Self::move_ptr(ptr, step, tape.len());
+        }
+    }
+
+    #[inline(always)]
+    fn scan_left<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        Self::scan(tape, ptr, -1)
+    }
+
+    #[inline(always)]
+    fn scan_right<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        Self::scan(tape, ptr, 1)
+    }
+}
+
+/// [`BoundsPolicy::Error`].
+struct CheckedTape;
+
+impl Addressing for CheckedTape {
+    #[inline(always)]
+    fn index<C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize> {
+        let idx = ptr.wrapping_add_signed(offset);
+        (idx < tape.len()).then_some(idx)
+    }
+
+    #[inline(always)]
+    fn scan_left<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        C::rfind_zero(tape.get(..=ptr)?)
+    }
+
+    #[inline(always)]
+    fn scan_right<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        Some(ptr + C::find_zero(tape.get(ptr..)?)?)
+    }
+}
+
+/// [`BoundsPolicy::Clamp`].
+struct ClampTape;
+
+impl Addressing for ClampTape {
+    #[inline(always)]
+    fn index<C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize> {
+        Some(Self::move_ptr(ptr, offset, tape.len()))
+    }
+
+    #[inline(always)]
+    fn move_ptr(ptr: usize, n: isize, len: usize) -> usize {
+        ptr.saturating_add_signed(n).min(len - 1)
+    }
+
+    // A scan stuck at an edge on a non-zero cell spins forever, exactly
+    // like the loop it replaces.
+}
+
+/// [`BoundsPolicy::Wrap`].
+struct WrapTape;
+
+impl Addressing for WrapTape {
+    #[inline(always)]
+    fn index<C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize> {
+        Some(Self::move_ptr(ptr, offset, tape.len()))
+    }
+
+    #[inline(always)]
+    fn move_ptr(ptr: usize, n: isize, len: usize) -> usize {
+        (ptr as isize + n).rem_euclid(len as isize) as usize
+    }
+
+    // A tape without a zero cell makes scans spin forever, exactly like the
+    // `[<]` / `[>]` loops they replace.
+}
+
+/// [`BoundsPolicy::Grow`].
+struct GrowTape;
+
+impl Addressing for GrowTape {
+    fn index<C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize> {
+        Self::index_within(tape, ptr, offset, usize::MAX)
+    }
+
+    fn index_within<C: Cell>(
+        tape: &mut Vec<C>,
+        ptr: usize,
+        offset: isize,
+        max: usize,
+    ) -> Option<usize> {
+        let idx = ptr.wrapping_add_signed(offset);
+        // Left of cell 0, the index wraps around to a huge one.
+        if idx as isize >= 0 && idx >= tape.len() && idx < max {
+            tape.resize((idx + 1).max(tape.len() * 2).min(max), C::default());
+        }
+        (idx < tape.len()).then_some(idx)
+    }
+
+    fn scan_left<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        CheckedTape::scan_left(tape, ptr)
+    }
+
+    // Every cell past the end is zero, so a scan right stops there at the
+    // latest.
+    fn scan_right<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        Some(ptr + C::find_zero(tape.get(ptr..)?).unwrap_or(tape.len() - ptr))
+    }
+}
+
+/// [`BoundsPolicy::Bidirectional`]. Cells from 0 on are kept at their own
+/// index in the first half of the tape, and cell `-k` at `len - k` in the
+/// second half, so the pointer wraps around to reach them.
+struct BidirectionalTape;
+
+impl Addressing for BidirectionalTape {
+    fn index<C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize> {
+        Self::index_within(tape, ptr, offset, usize::MAX)
+    }
+
+    fn index_within<C: Cell>(
+        tape: &mut Vec<C>,
+        ptr: usize,
+        offset: isize,
+        max: usize,
+    ) -> Option<usize> {
+        bidirectional_index(tape, ptr.wrapping_add_signed(offset) as isize, max)
+    }
+
+    // Every cell past either end is zero, so the default scans stop there
+    // at the latest.
+}
+
+/// The index of `cell` on a [`BoundsPolicy::Bidirectional`] tape, growing
+/// it to at most `max` cells to make room, or `None` if it can't.
+pub(crate) fn bidirectional_index<C: Cell>(
+    tape: &mut Vec<C>,
+    cell: isize,
+    max: usize,
+) -> Option<usize> {
+    let len = tape.len();
+    let right = len - len / 2;
+    // The length with room for `cell`: twice its distance from the middle.
+    let needed = match cell {
+        0.. if (cell as usize) < right => return Some(cell as usize),
+        0.. => (cell as usize).checked_mul(2)?.checked_add(1)?,
+        _ if cell.unsigned_abs() <= len / 2 => return Some(len - cell.unsigned_abs()),
+        _ => cell.unsigned_abs().checked_mul(2)?,
+    };
+    if needed > max {
+        return None;
+    }
+    let new_len = needed.max(len * 2).min(max);
+    let left = tape.split_off(right);
+    tape.resize(new_len - left.len(), C::default());
+    tape.extend(left);
+    bidirectional_index(tape, cell, max)
+}
+
+/// [`BoundsPolicy::Unchecked`]: indexing panics on a cell off the tape.
+struct UncheckedTape;
+
+impl Addressing for UncheckedTape {
+    #[inline(always)]
+    fn index<C: Cell>(_tape: &mut Vec<C>, ptr: usize, offset: isize) -> Option<usize> {
+        Some(ptr.wrapping_add_signed(offset))
+    }
+
+    // A scan that finds no zero leaves the pointer just past the edge.
+    #[inline(always)]
+    fn scan_left<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        Some(C::rfind_zero(&tape[..=ptr]).unwrap_or(usize::MAX))
+    }
+
+    #[inline(always)]
+    fn scan_right<C: Cell>(tape: &mut Vec<C>, ptr: usize) -> Option<usize> {
+        Some(C::find_zero(&tape[ptr..]).map_or(tape.len(), |pos| ptr + pos))
+    }
+}
+
+/// Reads the value for one `,`: the next input byte, or `eof` once the
+/// input is exhausted (`None` leaves the cell unchanged).
+pub(crate) fn read_cell<C: Cell>(input: &mut impl Read, eof: Option<C>) -> io::Result<Option<C>> {
+    let mut byte = 0u8;
+    match input.read_exact(std::slice::from_mut(&mut byte)) {
+        Ok(()) => Ok(Some(C::from_byte(byte))),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(eof),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads the value for one `,` in [`Config::unicode`] mode: the next
+/// UTF-8 character of the input, or `eof` once the input is exhausted.
+pub(crate) fn read_char<C: Cell>(input: &mut impl Read, eof: Option<C>) -> io::Result<Option<C>> {
+    let mut buf = [0u8; 4];
+    match input.read_exact(&mut buf[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(eof),
+        Err(e) => return Err(e),
+    }
+    let len = match buf[0] {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return Ok(Some(C::from_u32(char::REPLACEMENT_CHARACTER.into()))),
+    };
+    let c = match input.read_exact(&mut buf[1..len]) {
+        Ok(()) => std::str::from_utf8(&buf[..len]).map_or(char::REPLACEMENT_CHARACTER, |s| {
+            s.chars().next().unwrap_or(char::REPLACEMENT_CHARACTER)
+        }),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => char::REPLACEMENT_CHARACTER,
+        Err(e) => return Err(e),
+    };
+    Ok(Some(C::from_u32(c.into())))
+}
+
+/// The bytes `.` writes for `cell`: its low byte, or in [`Config::unicode`]
+/// mode the UTF-8 of its character.
+pub(crate) fn cell_bytes<C: Cell>(cell: C, unicode: bool, buf: &mut [u8; 4]) -> &[u8] {
+    if !unicode {
+        buf[0] = cell.to_byte();
+        return &buf[..1];
+    }
+    char::from_u32(cell.to_u32())
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+        .encode_utf8(buf)
+        .as_bytes()
+}
+
+/// The line [`Op::Debug`] prints for the op at `pc`: the pointer and the
+/// 16 cells around it, with the current one in brackets.
+pub(crate) fn debug_line<C: Cell>(pc: usize, ptr: usize, tape: &[C]) -> String {
+    let window = window(ptr, tape.len());
+    format!("# op {}, ptr {}, {}", pc, ptr, fmt_cells(tape, ptr, window))
+}
+
+/// The 16 cells around `ptr` on a tape of `len` cells, or the last 16 if
+/// `ptr` is past its end.
+pub(crate) fn window(ptr: usize, len: usize) -> Range<usize> {
+    let start = ptr.saturating_sub(8).min(len.saturating_sub(16));
+    start..(start + 16).min(len)
+}
+
+/// `cells {start}..{end}:` and the cells in that range, with the one at
+/// `ptr` in brackets.
+pub(crate) fn fmt_cells<C: Cell>(tape: &[C], ptr: usize, range: Range<usize>) -> String {
+    let mut line = format!("cells {}..{}:", range.start, range.end);
+    for (i, cell) in tape.iter().enumerate().take(range.end).skip(range.start) {
+        if i == ptr {
+            line.push_str(&format!(" [{}]", cell.to_u32()));
+        } else {
+            line.push_str(&format!(" {}", cell.to_u32()));
+        }
+    }
+    line
+}
+
+/// The machine state of an [`Interpreter`], without its I/O: the tape, the
+/// data pointer, the program counter and the step count. Taken with
+/// [`Interpreter::snapshot`] and put back with [`Interpreter::restore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct State<C = u8> {
+    pc: usize,
+    ptr: usize,
+    steps: u64,
+    tape: Vec<C>,
+    procs: Procedures,
+    storage: C,
+    tapes: Tapes<C>,
+}
+
+impl<C: Cell> State<C> {
+    pub(crate) fn new(pc: usize, ptr: usize, steps: u64, tape: Vec<C>) -> Self {
+        State {
+            pc,
+            ptr,
+            steps,
+            procs: Procedures::default(),
+            storage: C::default(),
+            tapes: Tapes::new(tape.len()),
+            tape,
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn tape(&self) -> &[C] {
+        &self.tape
+    }
+}
+
+/// The pbrain procedures a run has defined, and the calls it is in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Procedures {
+    /// The pc of each procedure's `DefProc`, by number.
+    defined: HashMap<u32, usize>,
+    /// The pc of each `CallProc` still running, innermost last.
+    calls: Vec<usize>,
+}
+
+/// The tapes of the multitape dialect other than the current one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Tapes<C> {
+    /// The current tape's number; the first is 0.
+    current: isize,
+    /// The tapes switched away from, by number, with their pointers.
+    parked: BTreeMap<isize, (Vec<C>, usize)>,
+    /// The length of a tape switched to for the first time.
+    size: usize,
+}
+
+impl<C: Cell> Tapes<C> {
+    fn new(size: usize) -> Self {
+        Tapes {
+            current: 0,
+            parked: BTreeMap::new(),
+            size,
+        }
+    }
+
+    /// Parks `tape` and `ptr` and puts the tape `n` below in their place.
+    fn switch(&mut self, n: isize, tape: &mut Vec<C>, ptr: &mut usize) {
+        let next = self.current + n;
+        let (next_tape, next_ptr) = self
+            .parked
+            .remove(&next)
+            .unwrap_or_else(|| (vec![C::default(); self.size], 0));
+        let parked = (mem::replace(tape, next_tape), mem::replace(ptr, next_ptr));
+        self.parked.insert(self.current, parked);
+        self.current = next;
+    }
+}
+
+/// An embeddable interpreter for an optimized program.
+///
+/// The interpreter owns the program, the tape and its I/O handles, so the
+/// tape can be inspected after (or between) runs and the handles recovered
+/// with [`Interpreter::into_inner`]. The cell type `C` defaults to `u8`;
+/// construct other widths with e.g. `Interpreter::<_, _, u16>::with_config`.
+pub struct Interpreter<R, W, C = u8> {
+    ops: Vec<Op>,
+    pc: usize,
+    ptr: usize,
+    tape: Vec<C>,
+    bounds: BoundsPolicy,
+    eof: Option<C>,
+    /// [`Config::unicode`].
+    unicode: bool,
+    /// Ops executed so far.
+    steps: u64,
+    procs: Procedures,
+    /// Extended Type I's storage register.
+    storage: C,
+    /// Where `?` draws its bytes from.
+    random: Generator,
+    tapes: Tapes<C>,
+    /// Brainfork threads waiting for their turn, with the step counts they
+    /// forked at.
+    threads: VecDeque<State<C>>,
+    limits: ResourceLimits,
+    /// How deeply the program's loops nest.
+    loop_depth: usize,
+    timeout: Option<Duration>,
+    /// Counts for [`Interpreter::enable_profiling`].
+    profile: Option<Profile>,
+    input: Input<R>,
+    output: Output<W>,
+    /// Where [`Op::Debug`] writes its lines, if anywhere.
+    debug: Option<Arc<Mutex<dyn Write + Send>>>,
+    /// Back-edge counts and compiled loops for [`Interpreter::run_tiered`].
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    tiers: Tiers<C>,
+}
+
+/// Loops the tiered interpreter has seen and compiled, indexed by the pc of
+/// their `Jz`.
+#[cfg(all(unix, target_arch = "x86_64"))]
+struct Tiers<C> {
+    counts: Vec<u32>,
+    compiled: Vec<Option<JitProgram<C>>>,
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+impl<C> Default for Tiers<C> {
+    fn default() -> Self {
+        Tiers {
+            counts: Vec::new(),
+            compiled: Vec::new(),
+        }
+    }
+}
+
+/// A tracer and the steps it wants, for [`Interpreter::run_traced`], or
+/// the hooks of [`Interpreter::run_hooked`].
+struct Trace<'a> {
+    tracer: Option<&'a mut dyn Tracer>,
+    steps: RangeInclusive<u64>,
+    hooks: Option<&'a mut dyn Hooks>,
+}
+
+/// An I/O handle that the threads of [`Interpreter::run_threaded`] take
+/// turns with.
+struct Shared<'a, T>(&'a Mutex<T>);
+
+impl<T> Shared<'_, T> {
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, T>> {
+        self.0
+            .lock()
+            .map_err(|_| io::Error::other("a thread panicked while using an I/O handle"))
+    }
+}
+
+impl<T: Read> Read for Shared<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock()?.read(buf)
+    }
+}
+
+impl<T: Write> Write for Shared<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+}
+
+/// What the threads of [`Interpreter::run_threaded`] share.
+struct Threads<'a, R, W, C> {
+    ops: &'a [Op],
+    input: &'a Mutex<R>,
+    output: &'a Mutex<W>,
+    flush: FlushPolicy,
+    newline: Newline,
+    bounds: BoundsPolicy,
+    eof: Option<C>,
+    unicode: bool,
+    random: Generator,
+    debug: Option<Arc<Mutex<dyn Write + Send>>>,
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    start: Instant,
+    /// Set once a thread has failed.
+    stopped: AtomicBool,
+    /// The first failure.
+    error: Mutex<Option<RuntimeError>>,
+}
+
+impl<'a, R: Read + Send, W: Write + Send, C: Cell> Threads<'a, R, W, C> {
+    /// An interpreter for one thread, starting in `state`.
+    fn interpreter(&self, state: State<C>) -> Interpreter<Shared<'a, R>, Shared<'a, W>, C> {
+        Interpreter {
+            ops: self.ops.to_vec(),
+            pc: state.pc,
+            ptr: state.ptr,
+            tape: state.tape,
+            bounds: self.bounds,
+            eof: self.eof,
+            unicode: self.unicode,
+            steps: state.steps,
+            procs: state.procs,
+            storage: state.storage,
+            random: self.random.clone(),
+            tapes: state.tapes,
+            threads: VecDeque::new(),
+            limits: ResourceLimits {
+                max_steps: self.max_steps,
+                ..ResourceLimits::default()
+            },
+            loop_depth: 0,
+            timeout: self.timeout,
+            profile: None,
+            // The shared input already translates line endings.
+            input: Input::new(Shared(self.input), Newline::Lf),
+            output: Output::new(Shared(self.output), self.flush, self.newline),
+            debug: self.debug.clone(),
+            #[cfg(all(unix, target_arch = "x86_64"))]
+            tiers: Tiers::default(),
+        }
+    }
+
+    /// Starts a thread in `state`.
+    fn spawn<'scope>(&'scope self, scope: &'scope Scope<'scope, '_>, state: State<C>) {
+        scope.spawn(move || {
+            let mut interp = self.interpreter(state);
+            if let Err(e) = self.run(scope, &mut interp) {
+                self.fail(e);
+            }
+        });
+    }
+
+    /// Runs `interp` until it ends, starting a thread for each fork.
+    fn run<'scope>(
+        &'scope self,
+        scope: &'scope Scope<'scope, '_>,
+        interp: &mut Interpreter<Shared<'a, R>, Shared<'a, W>, C>,
+    ) -> Result<(), RuntimeError> {
+        loop {
+            if self.stopped.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let slice = interp.steps.saturating_add(TIME_SLICE);
+            let limit = self.max_steps.map_or(slice, |max| max.min(slice));
+            let ended = interp.run_thread(Some(limit), None)?;
+            for state in interp.threads.drain(..) {
+                self.spawn(scope, state);
+            }
+            if ended {
+                return Ok(());
+            }
+            if self.max_steps.is_some_and(|max| interp.steps >= max) {
+                return Err(interp.step_limit_exceeded());
+            }
+            if let Some(limit) = self.timeout
+                && self.start.elapsed() >= limit
+            {
+                return Err(RuntimeError::TimeLimitExceeded(TimeLimitExceeded { limit }));
+            }
+        }
+    }
+
+    /// Records a thread's failure and stops the others.
+    fn fail(&self, error: RuntimeError) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Ok(mut first) = self.error.lock()
+            && first.is_none()
+        {
+            *first = Some(error);
+        }
+    }
+}
+
+/// Cells from `from` rightwards to `to`, around a wrapping tape if need be.
+fn cells_between(from: usize, to: usize, len: usize) -> u64 {
+    if to >= from {
+        (to - from) as u64
+    } else {
+        to.wrapping_add(len).wrapping_sub(from) as u64
+    }
+}
+
+/// Unwraps the result of a JIT run under [`BoundsPolicy::Unchecked`],
+/// panicking where the interpreter would.
+#[cfg(all(unix, target_arch = "x86_64"))]
+fn jit_outcome(result: Result<usize, JitError>, tape_len: usize) -> io::Result<usize> {
+    match result {
+        Ok(ptr) => Ok(ptr),
+        Err(JitError::Io(e)) => Err(e),
+        Err(JitError::OutOfBounds { ptr, .. }) => panic!(
+            "data pointer at cell {} touched a cell outside the {}-cell tape",
+            ptr as isize, tape_len
+        ),
+    }
+}
+
+impl<R: Read, W: Write> Interpreter<R, W> {
+    pub fn new(ops: Vec<Op>, input: R, output: W) -> Self {
+        Self::with_config(ops, input, output, &Config::default())
+    }
+}
+
+impl<R: Read, W: Write, C: Cell> Interpreter<R, W, C> {
+    pub fn with_config(ops: Vec<Op>, input: R, output: W, config: &Config) -> Self {
+        Interpreter {
+            loop_depth: crate::loop_depth(&ops),
+            ops,
+            pc: 0,
+            ptr: 0,
+            tape: vec![C::default(); config.tape_size],
+            bounds: config.bounds,
+            eof: config.eof.value(),
+            unicode: config.unicode,
+            steps: 0,
+            procs: Procedures::default(),
+            storage: C::default(),
+            random: Generator::new(config.seed),
+            tapes: Tapes::new(config.tape_size),
+            threads: VecDeque::new(),
+            limits: ResourceLimits {
+                max_steps: config.max_steps,
+                ..ResourceLimits::default()
+            },
+            timeout: config.timeout,
+            profile: None,
+            input: Input::new(input, config.input_newline),
+            output: Output::new(output, config.flush, config.output_newline),
+            debug: None,
+            #[cfg(all(unix, target_arch = "x86_64"))]
+            tiers: Tiers::default(),
+        }
+    }
+
+    /// Runs from the current instruction until the program ends.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.run_until_end(None)
+    }
+
+    /// Runs like [`run`](Self::run), reporting each op whose step number
+    /// (as [`steps`](Self::steps) counts them) falls in `steps` to `tracer`
+    /// before it executes, and the final state once the run stops. Traced
+    /// programs are always interpreted.
+    pub fn run_traced(
+        &mut self,
+        tracer: &mut dyn Tracer,
+        steps: RangeInclusive<u64>,
+    ) -> Result<(), RuntimeError> {
+        let result = self.run_until_end(Some(&mut Trace {
+            tracer: Some(&mut *tracer),
+            steps,
+            hooks: None,
+        }));
+        // A bidirectional tape keeps the cells left of cell 0 at its end.
+        let right = match self.bounds {
+            BoundsPolicy::Bidirectional => self.tape.len() - self.tape.len() / 2,
+            _ => self.tape.len(),
+        };
+        let (tape, left) = self.tape.split_at(right);
+        fn used<'a, C: Cell + 'a>(cells: impl Iterator<Item = &'a C>) -> Vec<u32> {
+            let mut cells: Vec<u32> = cells.map(|v| v.to_u32()).collect();
+            let used = cells.iter().rposition(|&v| v != 0).map_or(0, |i| i + 1);
+            cells.truncate(used);
+            cells
+        }
+        tracer.end(&End {
+            finished: result.is_ok(),
+            steps: self.steps,
+            pc: self.pc,
+            ptr: self.ptr,
+            tape: used(tape.iter()),
+            left: used(left.iter().rev()),
+        })?;
+        result
+    }
+
+    /// Runs like [`run`](Self::run), calling `hooks` as the program steps,
+    /// reads, writes and enters and leaves loops. Hooked programs are
+    /// always interpreted.
+    pub fn run_hooked(&mut self, hooks: &mut dyn Hooks) -> Result<(), RuntimeError> {
+        self.run_until_end(Some(&mut Trace {
+            tracer: None,
+            steps: 0..=0,
+            hooks: Some(hooks),
+        }))
+    }
+
+    fn run_until_end(&mut self, mut trace: Option<&mut Trace<'_>>) -> Result<(), RuntimeError> {
+        let start = Instant::now();
+        loop {
+            // With a timeout, run in slices so the clock gets checked.
+            let limit = match self.timeout {
+                Some(_) => {
+                    let slice = self.steps.saturating_add(TIME_SLICE);
+                    Some(self.limits.max_steps.map_or(slice, |max| max.min(slice)))
+                }
+                None => self.limits.max_steps,
+            };
+            if self.run_limited(limit, trace.as_deref_mut())? {
+                return Ok(());
+            }
+            if self.limits.max_steps.is_some_and(|max| self.steps >= max) {
+                return Err(self.step_limit_exceeded());
+            }
+            if let Some(limit) = self.timeout
+                && start.elapsed() >= limit
+            {
+                return Err(RuntimeError::TimeLimitExceeded(TimeLimitExceeded { limit }));
+            }
+        }
+    }
+
+    /// Interprets until the program ends or the step count reaches `limit`,
+    /// taking turns with the threads forked so far. Returns whether the
+    /// program ended.
+    fn run_limited(
+        &mut self,
+        limit: Option<u64>,
+        trace: Option<&mut Trace<'_>>,
+    ) -> Result<bool, RuntimeError> {
+        let limit = if self.threads.is_empty() {
+            limit
+        } else {
+            let turn = self.steps.saturating_add(THREAD_SLICE);
+            Some(limit.map_or(turn, |limit| limit.min(turn)))
+        };
+        let ended = self.run_thread(limit, trace)?;
+        // A thread that forks stops there, so the child starts promptly.
+        let Some(mut next) = self.threads.pop_front() else {
+            return Ok(ended);
+        };
+        self.switch_thread(&mut next);
+        if !ended {
+            self.threads.push_back(next);
+        }
+        Ok(false)
+    }
+
+    /// Swaps the current thread's machine state with `state`'s. The step
+    /// count stays, so limits count the ops of every thread.
+    fn switch_thread(&mut self, state: &mut State<C>) {
+        mem::swap(&mut self.pc, &mut state.pc);
+        mem::swap(&mut self.ptr, &mut state.ptr);
+        mem::swap(&mut self.tape, &mut state.tape);
+        mem::swap(&mut self.procs, &mut state.procs);
+        mem::swap(&mut self.storage, &mut state.storage);
+        mem::swap(&mut self.tapes, &mut state.tapes);
+    }
+
+    /// Interprets the current thread until it ends, forks, or the step
+    /// count reaches `limit`. Returns whether the thread ended.
+    fn run_thread(
+        &mut self,
+        limit: Option<u64>,
+        trace: Option<&mut Trace<'_>>,
+    ) -> Result<bool, RuntimeError> {
+        if let Some(max) = self.limits.max_loop_depth
+            && self.loop_depth > max
+        {
+            return Err(RuntimeError::LoopDepthExceeded(LoopDepthExceeded {
+                limit: max,
+                depth: self.loop_depth,
+            }));
+        }
+        if let Some(max) = self.limits.max_tape_cells
+            && self.tape.len() > max
+        {
+            return Err(RuntimeError::TapeLimitExceeded(TapeLimitExceeded {
+                limit: max,
+            }));
+        }
+        // Traces number their steps, so they need the counting loop.
+        let limited = limit.is_some() || trace.is_some();
+        let limit = limit.unwrap_or(u64::MAX);
+        let instrumented = self.profile.is_some() || trace.is_some();
+        match self.bounds {
+            BoundsPolicy::Error => {
+                self.run_policy::<CheckedTape>(limited, instrumented, limit, trace)
+            }
+            BoundsPolicy::Clamp => {
+                self.run_policy::<ClampTape>(limited, instrumented, limit, trace)
+            }
+            BoundsPolicy::Wrap => self.run_policy::<WrapTape>(limited, instrumented, limit, trace),
+            BoundsPolicy::Grow => self.run_policy::<GrowTape>(limited, instrumented, limit, trace),
+            BoundsPolicy::Bidirectional => {
+                self.run_policy::<BidirectionalTape>(limited, instrumented, limit, trace)
+            }
+            BoundsPolicy::Unchecked => {
+                self.run_policy::<UncheckedTape>(limited, instrumented, limit, trace)
+            }
+        }
+    }
+
+    fn run_policy<A: Addressing>(
+        &mut self,
+        limited: bool,
+        instrumented: bool,
+        limit: u64,
+        trace: Option<&mut Trace<'_>>,
+    ) -> Result<bool, RuntimeError> {
+        match (limited, instrumented) {
+            (false, false) => self.run_with::<A, false, false, false>(0, trace),
+            (true, false) => self.run_with::<A, false, true, false>(limit, trace),
+            (false, true) => self.run_with::<A, false, false, true>(0, trace),
+            (true, true) => self.run_with::<A, false, true, true>(limit, trace),
+        }
+    }
+
+    /// Runs at most `steps` more ops, stopping early if the program ends,
+    /// and returns whether it did. Steps count against the step limit like
+    /// those of [`run`](Self::run); the timeout doesn't apply.
+    pub fn run_for(&mut self, steps: u64) -> Result<bool, RuntimeError> {
+        let slice = self.steps.saturating_add(steps);
+        let limit = self.limits.max_steps.map_or(slice, |max| max.min(slice));
+        let finished = self.run_limited(Some(limit), None)?;
+        if !finished && self.limits.max_steps.is_some_and(|max| self.steps >= max) {
+            return Err(self.step_limit_exceeded());
+        }
+        Ok(finished)
+    }
+
+    /// Runs at most `budget` more ops like [`run_for`](Self::run_for), for
+    /// an event loop that takes turns with the program instead of giving it
+    /// a thread. A reader that fails with `WouldBlock` while it has no input
+    /// yet makes the run stop before that `,` with
+    /// [`StepResult::NeedsInput`].
+    pub fn run_steps(&mut self, budget: u64) -> StepResult {
+        match self.run_for(budget) {
+            Ok(true) => StepResult::Finished,
+            Ok(false) => StepResult::Running,
+            Err(RuntimeError::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {
+                StepResult::NeedsInput
+            }
+            Err(e) => StepResult::Error(e),
+        }
+    }
+
+    /// Executes the op at the current instruction, if the program hasn't
+    /// ended. Steps count against the step limit like those of
+    /// [`run`](Self::run).
+    pub fn step(&mut self) -> Result<(), RuntimeError> {
+        if self.is_finished() {
+            return Ok(());
+        }
+        if self
+            .limits
+            .max_steps
+            .is_some_and(|limit| self.steps >= limit)
+        {
+            return Err(self.step_limit_exceeded());
+        }
+        self.run_limited(Some(self.steps + 1), None)?;
+        Ok(())
+    }
+
+    fn step_limit_exceeded(&self) -> RuntimeError {
+        RuntimeError::StepLimitExceeded(StepLimitExceeded {
+            limit: self.limits.max_steps.unwrap_or(self.steps),
+        })
+    }
+
+    /// With `TIERED`, counts loop back-edges and hands loops that get hot to
+    /// the JIT; otherwise this is the plain interpreter. With `LIMITED`,
+    /// counts executed ops and stops once `limit` have run. With
+    /// `INSTRUMENTED`, counts each op into the profile and reports it to the
+    /// trace, if there are any. Returns whether the program ended.
+    fn run_with<
+        A: Addressing,
+        const TIERED: bool,
+        const LIMITED: bool,
+        const INSTRUMENTED: bool,
+    >(
+        &mut self,
+        limit: u64,
+        mut trace: Option<&mut Trace<'_>>,
+    ) -> Result<bool, RuntimeError> {
+        let ops = &self.ops;
+        let tape = &mut self.tape;
+        let profile = &mut self.profile;
+        let mut pc = self.pc;
+        let mut ptr = self.ptr;
+        // Kept out of the unlimited loop, where it would tie up a register.
+        let mut steps = if LIMITED { self.steps } else { 0 };
+        let max_cells = self.limits.max_tape_cells.unwrap_or(usize::MAX);
+        let grows = self.bounds == BoundsPolicy::Grow;
+        let bidirectional = self.bounds == BoundsPolicy::Bidirectional;
+
+        // Stops the run at the current op with `error`.
+        macro_rules! stop {
+            ($error:expr) => {{
+                self.pc = pc;
+                self.ptr = ptr;
+                if LIMITED {
+                    self.steps = steps;
+                }
+                self.output.flush()?;
+                return Err($error);
+            }};
+        }
+        // Stops the run at the current op, which touched a cell off the
+        // tape.
+        macro_rules! out_of_bounds {
+            () => {
+                stop!(RuntimeError::PointerOutOfBounds { pc, ptr })
+            };
+        }
+        // The tape index `offset` cells from the pointer.
+        macro_rules! at {
+            ($offset:expr) => {{
+                let offset: isize = $offset;
+                match A::index_within(tape, ptr, offset, max_cells) {
+                    Some(idx) => idx,
+                    // A growing tape only runs out to the right, at the
+                    // limit, and a bidirectional one either way.
+                    None if bidirectional
+                        || (grows && ptr.wrapping_add_signed(offset) as isize >= 0) =>
+                    {
+                        stop!(RuntimeError::TapeLimitExceeded(TapeLimitExceeded {
+                            limit: max_cells
+                        }))
+                    }
+                    None => out_of_bounds!(),
+                }
+            }};
+        }
+        // The hooks of a hooked run.
+        macro_rules! hooks {
+            () => {
+                if !INSTRUMENTED {
+                    None
+                } else if let Some(Trace {
+                    hooks: Some(hooks), ..
+                }) = trace.as_deref_mut()
+                {
+                    Some(&mut **hooks)
+                } else {
+                    None
+                }
+            };
+        }
+        // Writes `bytes`, stopping the run at the current op if that fails.
+        macro_rules! output {
+            ($bytes:expr) => {{
+                let bytes: &[u8] = $bytes;
+                if let Err(e) = self.output.write_all(bytes) {
+                    stop!(e.into());
+                }
+                if let Some(hooks) = hooks!() {
+                    bytes.iter().for_each(|&b| hooks.on_output(b));
+                }
+            }};
+        }
+
+        while pc < ops.len() {
+            if LIMITED {
+                if steps == limit {
+                    self.pc = pc;
+                    self.ptr = ptr;
+                    self.steps = steps;
+                    self.output.flush()?;
+                    return Ok(false);
+                }
+                steps += 1;
+            }
+            if INSTRUMENTED {
+                if let Some(profile) = profile {
+                    profile.counts[pc] += 1;
+                }
+                if let Some(hooks) = hooks!() {
+                    hooks.on_step(pc, ptr);
+                }
+                // `steps` already counts this op.
+                if LIMITED
+                    && let Some(trace) = trace.as_deref_mut()
+                    && let Some(tracer) = trace.tracer.as_deref_mut()
+                    && trace.steps.contains(&(steps - 1))
+                {
+                    tracer.trace(&Event {
+                        step: steps - 1,
+                        pc,
+                        op: &ops[pc],
+                        ptr,
+                        value: tape.get(ptr).map_or(0, |v| v.to_u32()),
+                    })?;
+                }
+            }
+            match &ops[pc] {
+                Op::PtrAdd(n) => {
+                    ptr = A::move_ptr(ptr, *n, tape.len());
+                }
+                Op::ValAdd(offset, n) => {
+                    let idx = at!(*offset);
+                    tape[idx] = tape[idx].wrapping_add(C::from_u32(*n));
+                }
+                Op::ValSub(offset, n) => {
+                    let idx = at!(*offset);
+                    tape[idx] = tape[idx].wrapping_sub(C::from_u32(*n));
+                }
+                Op::Output => {
+                    let idx = at!(0);
+                    let mut buf = [0; 4];
+                    output!(cell_bytes(tape[idx], self.unicode, &mut buf));
+                }
+                Op::OutputAt(offset) => {
+                    let idx = at!(*offset);
+                    let mut buf = [0; 4];
+                    output!(cell_bytes(tape[idx], self.unicode, &mut buf));
+                }
+                Op::OutputConst(bytes) => {
+                    output!(bytes);
+                }
+                Op::OutputRepeat(offset, count) => {
+                    let idx = at!(*offset);
+                    let mut buf = [0; 4];
+                    output!(&cell_bytes(tape[idx], self.unicode, &mut buf).repeat(*count));
+                }
+                Op::Input | Op::InputAt(_) => {
+                    let offset = match ops[pc] {
+                        Op::InputAt(offset) => offset,
+                        _ => 0,
+                    };
+                    let idx = at!(offset);
+                    let supplied = hooks!().and_then(|hooks| hooks.on_input());
+                    match self.output.flush_for_input().and_then(|()| match supplied {
+                        Some(b) => Ok(Some(C::from_u32(u32::from(b)))),
+                        None if self.unicode => read_char(&mut self.input, self.eof),
+                        None => read_cell(&mut self.input, self.eof),
+                    }) {
+                        Ok(Some(v)) => tape[idx] = v,
+                        Ok(None) => {}
+                        Err(e) => {
+                            // Stop before this `,`, so the next run reads it
+                            // again: a reader can return `WouldBlock` until
+                            // more input arrives.
+                            self.pc = pc;
+                            self.ptr = ptr;
+                            if LIMITED {
+                                self.steps = steps - 1;
+                            }
+                            if INSTRUMENTED && let Some(profile) = profile {
+                                profile.counts[pc] -= 1;
+                            }
+                            return Err(RuntimeError::Io(e));
+                        }
+                    }
+                }
+                Op::Jz(target) => {
+                    #[cfg(all(unix, target_arch = "x86_64"))]
+                    if TIERED && let Some(program) = &self.tiers.compiled[pc] {
+                        let flush = self.output.policy != FlushPolicy::Exit;
+                        let result = program.run(
+                            tape,
+                            ptr,
+                            &mut self.input,
+                            &mut JitOutput(&mut self.output),
+                            self.eof,
+                            flush,
+                        );
+                        match result {
+                            Err(JitError::OutOfBounds { pc: at, ptr: p })
+                                if self.bounds == BoundsPolicy::Error =>
+                            {
+                                pc = *target + at;
+                                ptr = p;
+                                out_of_bounds!();
+                            }
+                            result => ptr = jit_outcome(result, tape.len())?,
+                        }
+                        pc = *target + 1;
+                        continue;
+                    }
+                    let idx = at!(0);
+                    if tape[idx].is_zero() {
+                        pc = *target;
+                    } else if let Some(hooks) = hooks!() {
+                        hooks.on_loop_enter(pc);
+                    }
+                }
+                Op::Jnz(target) => {
+                    let idx = at!(0);
+                    if !tape[idx].is_zero() {
+                        #[cfg(all(unix, target_arch = "x86_64"))]
+                        if TIERED {
+                            let count = &mut self.tiers.counts[*target];
+                            *count = count.saturating_add(1);
+                            if *count == HOT_LOOP_THRESHOLD {
+                                // Re-enter the loop at its `Jz`, which now
+                                // dispatches to the compiled code.
+                                self.tiers.compiled[*target] =
+                                    JitProgram::compile(&ops[*target..=pc]).ok();
+                                pc = *target;
+                                continue;
+                            }
+                        }
+                        pc = *target;
+                    } else if let Some(hooks) = hooks!() {
+                        hooks.on_loop_exit(*target);
+                    }
+                }
+                Op::Clear(offset) => {
+                    let idx = at!(*offset);
+                    tape[idx] = C::default();
+                }
+                Op::Set(offset, n) => {
+                    let idx = at!(*offset);
+                    tape[idx] = C::from_u32(*n);
+                }
+                Op::MulAdd(offset, factor) => {
+                    let src = at!(0);
+                    if !tape[src].is_zero() {
+                        let target_idx = at!(*offset);
+                        tape[target_idx] = tape[target_idx]
+                            .wrapping_add(tape[src].wrapping_mul(C::from_u32(*factor)));
+                    }
+                }
+                Op::AddTo(offset) => {
+                    let src = at!(0);
+                    if !tape[src].is_zero() {
+                        let target_idx = at!(*offset);
+                        tape[target_idx] = tape[target_idx].wrapping_add(tape[src]);
+                    }
+                }
+                Op::SubFrom(offset) => {
+                    let src = at!(0);
+                    if !tape[src].is_zero() {
+                        let target_idx = at!(*offset);
+                        tape[target_idx] = tape[target_idx].wrapping_sub(tape[src]);
+                    }
+                }
+                Op::Transfer(targets) => {
+                    let src = at!(0);
+                    let value = tape[src];
+                    if !value.is_zero() {
+                        for &(offset, factor) in targets {
+                            let target_idx = at!(offset);
+                            tape[target_idx] = tape[target_idx]
+                                .wrapping_add(value.wrapping_mul(C::from_u32(factor)));
+                        }
+                        tape[src] = C::default();
+                    }
+                }
+                Op::ScanLeft => {
+                    let from = ptr;
+                    ptr = match A::scan_left(tape, ptr) {
+                        Some(ptr) => ptr,
+                        None => out_of_bounds!(),
+                    };
+                    if INSTRUMENTED && let Some(profile) = profile {
+                        profile.scanned[pc] += cells_between(ptr, from, tape.len());
+                    }
+                }
+                Op::ScanRight => {
+                    let from = ptr;
+                    ptr = match A::scan_right(tape, ptr) {
+                        Some(ptr) => ptr,
+                        None => out_of_bounds!(),
+                    };
+                    if INSTRUMENTED && let Some(profile) = profile {
+                        profile.scanned[pc] += cells_between(from, ptr, tape.len());
+                    }
+                }
+                Op::Scan(stride) => {
+                    let mut moves = 0u64;
+                    loop {
+                        let idx = at!(0);
+                        if tape[idx].is_zero() {
+                            break;
+                        }
+                        ptr = A::move_ptr(ptr, *stride, tape.len());
+                        moves += 1;
+                    }
+                    if INSTRUMENTED && let Some(profile) = profile {
+                        profile.scanned[pc] += moves * stride.unsigned_abs() as u64;
+                    }
+                }
+                Op::BulkAdd(deltas) => {
+                    for (offset, n) in deltas {
+                        let idx = at!(*offset);
+                        tape[idx] = tape[idx].wrapping_add(C::from_u32(*n));
+                    }
+                }
+                Op::BulkClear(offsets) => {
+                    for offset in offsets {
+                        let idx = at!(*offset);
+                        tape[idx] = C::default();
+                    }
+                }
+                Op::ClearRange(offset, n) => {
+                    let start = ptr.wrapping_add_signed(*offset);
+                    if start < tape.len() && tape.len() - start >= *n {
+                        tape[start..start + n].fill(C::default());
+                    } else {
+                        // Off the tape, each cell goes by the policy.
+                        for i in 0..*n {
+                            let idx = at!(offset + i as isize);
+                            tape[idx] = C::default();
+                        }
+                    }
+                }
+                Op::ClearUntilZero(stride) => {
+                    let mut moves = 0u64;
+                    loop {
+                        let idx = at!(0);
+                        if tape[idx].is_zero() {
+                            break;
+                        }
+                        // Rightwards, the cells up to the next zero go in
+                        // one fill. A bidirectional tape keeps the cells
+                        // from 0 on apart from the ones left of it.
+                        let end = if bidirectional && idx < tape.len() - tape.len() / 2 {
+                            tape.len() - tape.len() / 2
+                        } else {
+                            tape.len()
+                        };
+                        if *stride == 1
+                            && let Some(n) = C::find_zero(&tape[idx..end])
+                        {
+                            tape[idx..idx + n].fill(C::default());
+                            ptr = ptr.wrapping_add(n);
+                            moves += n as u64;
+                            break;
+                        }
+                        tape[idx] = C::default();
+                        ptr = A::move_ptr(ptr, *stride, tape.len());
+                        moves += 1;
+                    }
+                    if INSTRUMENTED && let Some(profile) = profile {
+                        profile.scanned[pc] += moves * stride.unsigned_abs() as u64;
+                    }
+                }
+                Op::Debug => {
+                    if let Some(debug) = &self.debug {
+                        self.output.flush()?;
+                        let mut debug = debug.lock().unwrap_or_else(|e| e.into_inner());
+                        writeln!(debug, "{}", debug_line(pc, ptr, tape))?;
+                    }
+                }
+                Op::DefProc(end) => {
+                    let idx = at!(0);
+                    self.procs.defined.insert(tape[idx].to_u32(), pc);
+                    pc = *end;
+                }
+                // Only reached through a call; `DefProc` jumps past it.
+                Op::EndProc => {
+                    if let Some(call) = self.procs.calls.pop() {
+                        pc = call;
+                    }
+                }
+                Op::CallProc => {
+                    let idx = at!(0);
+                    let id = tape[idx].to_u32();
+                    let Some(&def) = self.procs.defined.get(&id) else {
+                        self.pc = pc;
+                        self.ptr = ptr;
+                        if LIMITED {
+                            self.steps = steps;
+                        }
+                        self.output.flush()?;
+                        return Err(RuntimeError::UndefinedProcedure(UndefinedProcedure {
+                            pc,
+                            id,
+                        }));
+                    };
+                    self.procs.calls.push(pc);
+                    pc = def;
+                }
+                Op::Fork => {
+                    let idx = at!(0);
+                    let child_idx = at!(1);
+                    let mut child = tape.clone();
+                    child[child_idx] = C::from_u32(1);
+                    tape[idx] = C::default();
+                    self.threads.push_back(State {
+                        pc: pc + 1,
+                        ptr: A::move_ptr(ptr, 1, tape.len()),
+                        steps: if LIMITED { steps } else { self.steps },
+                        tape: child,
+                        procs: self.procs.clone(),
+                        storage: self.storage,
+                        tapes: self.tapes.clone(),
+                    });
+                    // Stop, so the child gets a turn.
+                    self.pc = pc + 1;
+                    self.ptr = ptr;
+                    if LIMITED {
+                        self.steps = steps;
+                    }
+                    self.output.flush()?;
+                    return Ok(false);
+                }
+                Op::End => {
+                    pc = ops.len();
+                    continue;
+                }
+                Op::Store => {
+                    let idx = at!(0);
+                    self.storage = tape[idx];
+                }
+                Op::Random => {
+                    let idx = at!(0);
+                    tape[idx] = C::from_u32(u32::from(self.random.next() as u8));
+                }
+                Op::Retrieve => {
+                    let idx = at!(0);
+                    tape[idx] = self.storage;
+                }
+                Op::Bitwise(bit) => {
+                    let idx = at!(0);
+                    tape[idx] = C::from_u32(bit.apply(tape[idx].to_u32(), self.storage.to_u32()));
+                }
+                Op::SwitchTape(n) => self.tapes.switch(*n, tape, &mut ptr),
+                Op::DivMod => crate::div_mod(tape, ptr),
+            }
+            pc += 1;
+        }
+
+        self.pc = pc;
+        self.ptr = ptr;
+        if LIMITED {
+            self.steps = steps;
+        }
+        self.output.flush()?;
+        Ok(true)
+    }
+
+    /// Compiles the program to native code and runs it until it ends, like
+    /// [`run`](Self::run) but much faster for long-running programs. The JIT
+    /// only handles [`BoundsPolicy::Error`] and [`BoundsPolicy::Unchecked`]
+    /// without a step or time limit, profiling, [`Op::Debug`] or the ops of
+    /// dialects and extensions; anything else is interpreted.
+    ///
+    /// # Panics
+    ///
+    /// If the program touches a cell outside the tape under
+    /// [`BoundsPolicy::Unchecked`].
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    pub fn run_jit(&mut self) -> Result<(), RuntimeError> {
+        if !matches!(self.bounds, BoundsPolicy::Error | BoundsPolicy::Unchecked)
+            || self.limits.any()
+            || self.timeout.is_some()
+            || self.profile.is_some()
+            || self.unicode
+            || self.is_finished()
+            || self.ops.contains(&Op::Debug)
+            || crate::uses_procedures(&self.ops)
+            || crate::uses_forks(&self.ops)
+            || crate::uses_extended1(&self.ops)
+            || crate::uses_tapes(&self.ops)
+            || crate::uses_random(&self.ops)
+        {
+            return self.run();
+        }
+        let program = JitProgram::<C>::compile(&self.ops[self.pc..])?;
+        let flush = self.output.policy != FlushPolicy::Exit;
+        let result = program.run(
+            &mut self.tape,
+            self.ptr,
+            &mut self.input,
+            &mut JitOutput(&mut self.output),
+            self.eof,
+            flush,
+        );
+        match result {
+            Err(JitError::OutOfBounds { pc, ptr }) if self.bounds == BoundsPolicy::Error => {
+                self.pc += pc;
+                self.ptr = ptr;
+                self.output.flush()?;
+                return Err(RuntimeError::PointerOutOfBounds { pc: self.pc, ptr });
+            }
+            result => self.ptr = jit_outcome(result, self.tape.len())?,
+        }
+        self.pc = self.ops.len();
+        Ok(self.output.flush()?)
+    }
+
+    /// Interprets the program, compiling each loop to native code once it
+    /// has run [`HOT_LOOP_THRESHOLD`] times. Short programs start as fast as
+    /// under [`run`](Self::run), and long ones approach
+    /// [`run_jit`](Self::run_jit). Like `run_jit`, this only tiers up with
+    /// [`BoundsPolicy::Error`] or [`BoundsPolicy::Unchecked`], no step or
+    /// time limit and no profiling.
+    ///
+    /// # Panics
+    ///
+    /// If the program touches a cell outside the tape under
+    /// [`BoundsPolicy::Unchecked`].
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    pub fn run_tiered(&mut self) -> Result<(), RuntimeError> {
+        if !matches!(self.bounds, BoundsPolicy::Error | BoundsPolicy::Unchecked)
+            || self.limits.any()
+            || self.timeout.is_some()
+            || self.profile.is_some()
+            || self.unicode
+            || crate::uses_forks(&self.ops)
+        {
+            return self.run();
+        }
+        self.size_tiers();
+        if self.bounds == BoundsPolicy::Error {
+            self.run_with::<CheckedTape, true, false, false>(0, None)?;
+        } else {
+            self.run_with::<UncheckedTape, true, false, false>(0, None)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles the loops that ran [`HOT_LOOP_THRESHOLD`] times or more in
+    /// `profile`, an earlier run's profile of the same ops, so that
+    /// [`run_tiered`](Self::run_tiered) runs them natively from their first
+    /// iteration. A hot loop inside another is left to the outer one's
+    /// code. Returns how many loops were compiled, none if the profile is
+    /// of other ops.
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    pub fn compile_hot_loops(&mut self, profile: &Profile) -> usize {
+        if profile.counts.len() != self.ops.len() {
+            return 0;
+        }
+        self.size_tiers();
+        let mut hot: Vec<_> = profile
+            .loops(&self.ops)
+            .into_iter()
+            .filter(|l| l.iterations >= u64::from(HOT_LOOP_THRESHOLD))
+            .collect();
+        hot.sort_by_key(|l| l.start);
+        let mut compiled = 0;
+        let mut covered = 0;
+        for l in hot {
+            if l.start < covered {
+                continue;
+            }
+            if let Ok(program) = JitProgram::compile(&self.ops[l.start..=l.end]) {
+                self.tiers.compiled[l.start] = Some(program);
+                compiled += 1;
+                covered = l.end;
+            }
+        }
+        compiled
+    }
+
+    /// Sizes the tiers for the program, if they aren't already.
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    fn size_tiers(&mut self) {
+        if self.tiers.counts.len() != self.ops.len() {
+            self.tiers.counts = vec![0; self.ops.len()];
+            self.tiers.compiled = self.ops.iter().map(|_| None).collect();
+        }
+    }
+
+    /// Runs like [`run`](Self::run), but on a thread of its own for each
+    /// Brainfork thread instead of taking turns. The threads share the I/O
+    /// handles, a `,` or a write at a time, and the first to fail stops the
+    /// others at their next look at the clock. The step limit and timeout
+    /// apply to each thread, whose steps include its parent's before the
+    /// fork. Threads aren't profiled or compiled. With any
+    /// [`ResourceLimits`] but the step limit, the threads take turns as
+    /// usual.
+    ///
+    /// The interpreter is left in the state of the thread it was running.
+    pub fn run_threaded(&mut self) -> Result<(), RuntimeError>
+    where
+        R: Send,
+        W: Send,
+    {
+        if self.limits.beyond_steps() {
+            return self.run();
+        }
+        let main = State {
+            pc: self.pc,
+            ptr: self.ptr,
+            steps: self.steps,
+            tape: mem::take(&mut self.tape),
+            procs: mem::take(&mut self.procs),
+            storage: self.storage,
+            tapes: mem::replace(&mut self.tapes, Tapes::new(0)),
+        };
+        let waiting = mem::take(&mut self.threads);
+        let input = Mutex::new(&mut self.input);
+        let output = Mutex::new(&mut self.output.inner);
+        let threads = Threads {
+            ops: &self.ops,
+            input: &input,
+            output: &output,
+            flush: self.output.policy,
+            newline: self.output.newline,
+            bounds: self.bounds,
+            eof: self.eof,
+            unicode: self.unicode,
+            random: self.random.clone(),
+            debug: self.debug.clone(),
+            max_steps: self.limits.max_steps,
+            timeout: self.timeout,
+            start: Instant::now(),
+            stopped: AtomicBool::new(false),
+            error: Mutex::new(None),
+        };
+        let main = thread::scope(|scope| {
+            for state in waiting {
+                threads.spawn(scope, state);
+            }
+            let mut interp = threads.interpreter(main);
+            if let Err(e) = threads.run(scope, &mut interp) {
+                threads.fail(e);
+            }
+            State {
+                pc: interp.pc,
+                ptr: interp.ptr,
+                steps: interp.steps,
+                tape: interp.tape,
+                procs: interp.procs,
+                storage: interp.storage,
+                tapes: interp.tapes,
+            }
+        });
+        let error = threads
+            .error
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        self.pc = main.pc;
+        self.ptr = main.ptr;
+        self.steps = main.steps;
+        self.tape = main.tape;
+        self.procs = main.procs;
+        self.storage = main.storage;
+        self.tapes = main.tapes;
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Replaces the limits the interpreter was configured with, including
+    /// [`Config::max_steps`], for the runs from now on. Output written so
+    /// far counts towards [`ResourceLimits::max_output_bytes`].
+    pub fn set_limits(&mut self, limits: ResourceLimits) {
+        self.limits = limits;
+        self.output.limit = limits.max_output_bytes;
+    }
+
+    /// Makes [`Op::Debug`] write its line to `output`, after flushing the
+    /// program's output so the two interleave in order. Without one, `#`
+    /// does nothing.
+    pub fn set_debug_output(&mut self, output: impl Write + Send + 'static) {
+        self.debug = Some(Arc::new(Mutex::new(output)));
+    }
+
+    pub fn limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+
+    /// Bytes of output written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.output.written
+    }
+
+    /// Starts counting how often each op runs, from now on, into
+    /// [`profile`](Self::profile). Profiled programs are always interpreted.
+    pub fn enable_profiling(&mut self) {
+        if self.profile.is_none() {
+            self.profile = Some(Profile::new(self.ops.len()));
+        }
+    }
+
+    /// The counts since [`enable_profiling`](Self::enable_profiling), if it
+    /// was called.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn tape(&self) -> &[C] {
+        &self.tape
+    }
+
+    pub fn tape_mut(&mut self) -> &mut [C] {
+        &mut self.tape
+    }
+
+    /// Index of the cell under the data pointer.
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// Index of the next op to execute.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Number of ops executed so far. Only counted under a step limit or
+    /// while [`step`](Self::step)ping.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.ops.len()
+    }
+
+    /// Consumes the interpreter and returns its input and output handles.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input.inner, self.output.inner)
+    }
+
+    /// Copies the machine state, to [`restore`](Self::restore) later: for
+    /// save games, backtracking searches, or running two variations from
+    /// the same point. Only the current Brainfork thread is copied.
+    pub fn snapshot(&self) -> State<C> {
+        State {
+            pc: self.pc,
+            ptr: self.ptr,
+            steps: self.steps,
+            tape: self.tape.clone(),
+            procs: self.procs.clone(),
+            storage: self.storage,
+            tapes: self.tapes.clone(),
+        }
+    }
+
+    /// Puts the machine back in `state`, which should come from an
+    /// interpreter for the same program. The I/O handles are left alone, so
+    /// input already read stays consumed and output stays written. Brainfork
+    /// threads waiting for their turn are dropped.
+    pub fn restore(&mut self, state: &State<C>) {
+        self.threads.clear();
+        self.pc = state.pc;
+        self.ptr = state.ptr;
+        self.steps = state.steps;
+        self.tape.clone_from(&state.tape);
+        self.procs.clone_from(&state.procs);
+        self.storage = state.storage;
+        self.tapes.clone_from(&state.tapes);
+    }
+
+    pub(crate) fn io(&self) -> (&R, &W) {
+        (&self.input.inner, &self.output.inner)
+    }
+
+    pub(crate) fn io_mut(&mut self) -> (&mut R, &mut W) {
+        (&mut self.input.inner, &mut self.output.inner)
+    }
+
+    /// Wraps the I/O handles, keeping the machine state.
+    pub(crate) fn map_io<R2, W2>(
+        self,
+        input: impl FnOnce(R) -> R2,
+        output: impl FnOnce(W) -> W2,
+    ) -> Interpreter<R2, W2, C> {
+        Interpreter {
+            ops: self.ops,
+            pc: self.pc,
+            ptr: self.ptr,
+            tape: self.tape,
+            bounds: self.bounds,
+            eof: self.eof,
+            unicode: self.unicode,
+            steps: self.steps,
+            procs: self.procs,
+            storage: self.storage,
+            random: self.random,
+            tapes: self.tapes,
+            threads: self.threads,
+            limits: self.limits,
+            loop_depth: self.loop_depth,
+            timeout: self.timeout,
+            profile: self.profile,
+            input: Input {
+                inner: input(self.input.inner),
+                newline: self.input.newline,
+                after_cr: self.input.after_cr,
+                pending: self.input.pending,
+            },
+            output: Output {
+                inner: output(self.output.inner),
+                policy: self.output.policy,
+                newline: self.output.newline,
+                held_cr: self.output.held_cr,
+                pending: self.output.pending,
+                written: self.output.written,
+                limit: self.output.limit,
+            },
+            debug: self.debug,
+            #[cfg(all(unix, target_arch = "x86_64"))]
+            tiers: self.tiers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Dialect, ParseOptions, optimize, parse, parse_with};
+
+    fn run(code: &[u8], input: &[u8]) -> Vec<u8> {
+        let ops = optimize(parse(code).unwrap());
+        let mut out = Vec::new();
+        execute(&ops, input, &mut out).unwrap();
+        out
+    }
+
+    /// One way to run an interpreter's program, such as
+    /// [`Interpreter::run_packed`].
+    pub(super) type Engine =
+        fn(&mut Interpreter<&'static [u8], Vec<u8>>) -> Result<(), RuntimeError>;
+
+    /// Programs that stay on a 64-cell tape, which
+    /// [`Interpreter::run_unchecked`] can run.
+    pub(super) const ON_TAPE: [&[u8]; 8] = [
+        include_bytes!("../examples/helloworld.bf"),
+        b",[.>,]<[-]<[->>+<<]++[>+++<-]>>[<+>-]",
+        b",[->+>+++<<]>>[-<<++>->]<<.",
+        b",...>,..<...",
+        b">+>+>+<[<]>[>]",
+        b"+[>>+<<-]>>[<+>-]<<<",
+        b"-->>-<<[>+>+<<-]",
+        b"+>+>+>+>+<<<<[[-]>]<[-]<[-]<[-]<[-]+[[-]<]",
+    ];
+
+    /// Programs that leave a 64-cell tape, most of them by scanning off
+    /// either end.
+    pub(super) const OFF_TAPE: [&[u8]; 5] = [
+        b"+>>+>+<<<[>[>]+<[<]>-]>[>]",
+        b"+[<+]",
+        b">>>+[>+]",
+        b"+[>>+]",
+        b"++[>+<-]<+",
+    ];
+
+    /// How a run ended, its output, and the tape, pointer and program
+    /// counter it left.
+    type Outcome = (Result<(), String>, Vec<u8>, Vec<u8>, usize, usize);
+
+    fn run_on(code: &[u8], engine: Engine) -> Outcome {
+        let ops = optimize(parse(code).unwrap());
+        let config = Config {
+            tape_size: 64,
+            ..Config::default()
+        };
+        let mut interp = Interpreter::with_config(ops, &b"hi"[..], Vec::new(), &config);
+        let result = engine(&mut interp).map_err(|e| e.to_string());
+        let state = (interp.tape().to_vec(), interp.ptr(), interp.pc());
+        (result, interp.into_inner().1, state.0, state.1, state.2)
+    }
+
+    /// Checks that `engine` runs each of `programs` on a 64-cell tape the
+    /// way [`Interpreter::run`] does, ending the same way with the same
+    /// output and machine.
+    pub(super) fn assert_conforms(engine: Engine, programs: &[&[u8]]) {
+        for &code in programs {
+            assert_eq!(
+                run_on(code, engine),
+                run_on(code, Interpreter::run),
+                "{}",
+                String::from_utf8_lossy(code)
+            );
+        }
+        assert_eq!(run_on(ON_TAPE[0], engine).1, b"Hello, World!");
+    }
+
+    #[test]
+    fn test_conformance_programs() {
+        for code in ON_TAPE {
+            assert!(run_on(code, Interpreter::run).0.is_ok());
+        }
+        for code in OFF_TAPE {
+            assert!(run_on(code, Interpreter::run).0.is_err());
+        }
+    }
+
+    #[test]
+    fn test_execute_hello() {
+        let code = std::fs::read("examples/helloworld.bf").unwrap();
+        assert_eq!(run(&code, b""), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_run_program() {
+        assert_eq!(run_program(b",[.-]", b"\x03").unwrap(), [3, 2, 1]);
+        assert!(matches!(
+            run_program(b"+[", b""),
+            Err(Error::Parse(ParseError::UnmatchedOpen { offset: 1, .. }))
+        ));
+        assert!(matches!(
+            run_program(b"<+", b""),
+            Err(Error::Runtime(RuntimeError::PointerOutOfBounds { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_interpreter_tape_access() {
+        let ops = optimize(parse(b"+++>++<").unwrap());
+        let mut interp = Interpreter::new(ops, io::empty(), Vec::new());
+        interp.run().unwrap();
+        assert!(interp.is_finished());
+        assert_eq!(interp.ptr(), 0);
+        assert_eq!(&interp.tape()[..3], &[3, 2, 0]);
+    }
+
+    #[test]
+    fn test_interpreter_captures_output() {
+        let ops = optimize(parse(b",.,.,.").unwrap());
+        let mut interp = Interpreter::new(ops, &b"xyz"[..], Vec::new());
+        interp.run().unwrap();
+        let (_, out) = interp.into_inner();
+        assert_eq!(out, b"xyz");
+    }
+
+    #[test]
+    fn test_interpreter_tape_size() {
+        let config = Config {
+            tape_size: 70_000,
+            ..Config::default()
+        };
+        let mut code = vec![b'>'; 40_000];
+        code.push(b'+');
+        let ops = optimize(parse(&code).unwrap());
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops, io::empty(), io::sink(), &config);
+        interp.run().unwrap();
+        assert_eq!(interp.tape().len(), 70_000);
+        assert_eq!(interp.tape()[40_000], 1);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let config = Config {
+            max_steps: Some(100),
+            ..Config::default()
+        };
+        let ops = optimize(parse(b"+[]").unwrap());
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops.clone(), io::empty(), io::sink(), &config);
+        assert!(matches!(
+            interp.run(),
+            Err(RuntimeError::StepLimitExceeded(StepLimitExceeded {
+                limit: 100
+            }))
+        ));
+        assert_eq!(interp.steps(), 100);
+        assert!(!interp.is_finished());
+
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops[..1].to_vec(), io::empty(), io::sink(), &config);
+        interp.run().unwrap();
+        assert_eq!(interp.steps(), 1);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let ops = parse(b"++++++[>++++++++<-]>.+.").unwrap();
+        let mut interp = Interpreter::new(ops, io::empty(), Vec::new());
+        for _ in 0..20 {
+            interp.step().unwrap();
+        }
+        let state = interp.snapshot();
+        assert_eq!((state.pc(), state.steps()), (interp.pc(), 20));
+
+        interp.run().unwrap();
+        interp.restore(&state);
+        assert_eq!(interp.snapshot(), state);
+        assert_eq!(interp.tape(), state.tape());
+        interp.run().unwrap();
+        // Restoring keeps the output, and the replay writes it again.
+        let (_, out) = interp.into_inner();
+        assert_eq!(out, b"0101");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        // A run picks up from a state and config read back from JSON.
+        let ops = optimize(parse(b"++++++[>++++++++<-]>.+.").unwrap());
+        let config = Config {
+            bounds: BoundsPolicy::Wrap,
+            ..Config::default()
+        };
+        let mut interp = Interpreter::with_config(ops.clone(), io::empty(), Vec::new(), &config);
+        interp.run_for(3).unwrap();
+        let json = serde_json::to_string(&(&ops, &config, interp.snapshot())).unwrap();
+        let (ops, config, state): (Vec<Op>, Config, State) = serde_json::from_str(&json).unwrap();
+        assert_eq!(state, interp.snapshot());
+
+        let mut resumed = Interpreter::with_config(ops, io::empty(), Vec::new(), &config);
+        resumed.restore(&state);
+        resumed.run().unwrap();
+        interp.run().unwrap();
+        assert_eq!(resumed.into_inner().1, interp.into_inner().1);
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let limit = Duration::from_millis(20);
+        let config = Config {
+            timeout: Some(limit),
+            ..Config::default()
+        };
+        let ops = optimize(parse(b"+[]").unwrap());
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops.clone(), io::empty(), io::sink(), &config);
+        assert!(matches!(
+            interp.run(),
+            Err(RuntimeError::TimeLimitExceeded(exceeded)) if exceeded.limit == limit
+        ));
+        assert!(!interp.is_finished());
+
+        // The step limit still applies between clock checks.
+        let config = Config {
+            max_steps: Some(100),
+            ..config
+        };
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops, io::empty(), io::sink(), &config);
+        assert!(matches!(
+            interp.run(),
+            Err(RuntimeError::StepLimitExceeded(_))
+        ));
+        assert_eq!(interp.steps(), 100);
+    }
+
+    #[test]
+    fn test_step() {
+        let ops = parse(b"++[>+<-]").unwrap();
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        interp.step().unwrap();
+        assert_eq!((interp.pc(), interp.tape()[0]), (1, 2));
+        while !interp.is_finished() {
+            interp.step().unwrap();
+        }
+        assert_eq!(interp.steps(), 8);
+        assert_eq!(&interp.tape()[..2], &[0, 2]);
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let limited = |code: &[u8], bounds, limits| {
+            let config = Config {
+                tape_size: 4,
+                bounds,
+                ..Config::default()
+            };
+            let ops = optimize(parse(code).unwrap());
+            let mut interp =
+                Interpreter::<_, _, u8>::with_config(ops, io::empty(), Vec::new(), &config);
+            interp.set_limits(limits);
+            let result = interp.run();
+            (result, interp)
+        };
+
+        let limits = ResourceLimits {
+            max_tape_cells: Some(10),
+            ..ResourceLimits::default()
+        };
+        let (result, interp) = limited(b"+[>+]", BoundsPolicy::Grow, limits);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::TapeLimitExceeded(TapeLimitExceeded {
+                limit: 10
+            }))
+        ));
+        assert_eq!(interp.tape().len(), 10);
+        // A bidirectional tape runs out at the limit either way.
+        let (result, _) = limited(b"+[<+]", BoundsPolicy::Bidirectional, limits);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::TapeLimitExceeded(TapeLimitExceeded {
+                limit: 10
+            }))
+        ));
+        let (result, _) = limited(
+            b"+",
+            BoundsPolicy::Error,
+            ResourceLimits {
+                max_tape_cells: Some(3),
+                ..ResourceLimits::default()
+            },
+        );
+        assert!(matches!(result, Err(RuntimeError::TapeLimitExceeded(_))));
+
+        let limits = ResourceLimits {
+            max_output_bytes: Some(3),
+            ..ResourceLimits::default()
+        };
+        let (result, interp) = limited(b"+.+.+.+.+.", BoundsPolicy::Error, limits);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::OutputLimitExceeded(OutputLimitExceeded {
+                limit: 3
+            }))
+        ));
+        assert_eq!(interp.bytes_written(), 3);
+        assert_eq!(interp.into_inner().1, [1, 2, 3]);
+
+        let limits = ResourceLimits {
+            max_loop_depth: Some(1),
+            ..ResourceLimits::default()
+        };
+        let (result, interp) = limited(b"+[>+[.-]<-]", BoundsPolicy::Error, limits);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::LoopDepthExceeded(LoopDepthExceeded {
+                limit: 1,
+                depth: 2
+            }))
+        ));
+        assert_eq!(interp.steps(), 0);
+    }
+
+    #[test]
+    fn test_run_steps() {
+        /// Has input only once `ready` is set.
+        struct Later {
+            ready: bool,
+        }
+        impl Read for Later {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if !self.ready {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                (&b"a"[..]).read(buf)
+            }
+        }
+
+        let ops = parse(b"+.,.<-").unwrap();
+        let config = Config {
+            tape_size: 1,
+            ..Config::default()
+        };
+        let mut interp =
+            Interpreter::<_, _, u8>::with_config(ops, Later { ready: false }, Vec::new(), &config);
+        assert!(matches!(interp.run_steps(1), StepResult::Running));
+        assert!(matches!(interp.run_steps(10), StepResult::NeedsInput));
+        assert_eq!((interp.pc(), interp.steps()), (2, 2));
+        assert!(matches!(interp.run_steps(10), StepResult::NeedsInput));
+        assert_eq!((interp.pc(), interp.steps()), (2, 2));
+        interp.io_mut().0.ready = true;
+        assert!(matches!(
+            interp.run_steps(10),
+            StepResult::Error(RuntimeError::PointerOutOfBounds { pc: 4, .. })
+        ));
+        assert_eq!(interp.into_inner().1, [1, b'a']);
+
+        let mut interp = Interpreter::new(parse(b"+[-]").unwrap(), io::empty(), io::sink());
+        assert!(matches!(interp.run_steps(100), StepResult::Finished));
+        assert!(matches!(interp.run_steps(100), StepResult::Finished));
+    }
+
+    #[test]
+    fn test_debug_line() {
+        let mut tape = [0u8; 20];
+        tape[19] = 7;
+        assert_eq!(
+            debug_line(3, 19, &tape),
+            "# op 3, ptr 19, cells 4..20: 0 0 0 0 0 0 0 0 0 0 0 0 0 0 0 [7]"
+        );
+        assert_eq!(
+            debug_line(0, 1, &tape[..3]),
+            "# op 0, ptr 1, cells 0..3: 0 [0] 0"
+        );
+    }
+
+    #[test]
+    fn test_debug_output() {
+        #[derive(Clone, Default)]
+        struct Lines(Arc<Mutex<Vec<u8>>>);
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let options = ParseOptions {
+            debug_char: true,
+            ..ParseOptions::default()
+        };
+        let config = Config {
+            tape_size: 4,
+            ..Config::default()
+        };
+        let ops = parse_with(b"+.#>", &options).unwrap();
+        // Without a debug output, `#` is skipped.
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops.clone(), io::empty(), Vec::new(), &config);
+        interp.run().unwrap();
+        assert_eq!(interp.into_inner().1, [1]);
+
+        let lines = Lines::default();
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops, io::empty(), Vec::new(), &config);
+        interp.set_debug_output(lines.clone());
+        interp.run().unwrap();
+        assert_eq!(interp.into_inner().1, [1]);
+        assert_eq!(
+            *lines.0.lock().unwrap(),
+            b"# op 2, ptr 0, cells 0..4: [1] 0 0 0\n"
+        );
+    }
+
+    #[test]
+    fn test_execute_echo_input() {
+        assert_eq!(run(b",.,.", b"ab"), b"ab");
+    }
+
+    #[test]
+    fn test_wrap_policy() {
+        let config = Config {
+            tape_size: 8,
+            bounds: BoundsPolicy::Wrap,
+            ..Config::default()
+        };
+        // Step left off cell 0, back over the edge, then scan left across it.
+        let ops = optimize(parse(b"<+>>+<<[<]").unwrap());
+        let mut interp: Interpreter<_, _> =
+            Interpreter::with_config(ops, io::empty(), io::sink(), &config);
+        interp.run().unwrap();
+        assert_eq!(interp.tape()[7], 1);
+        assert_eq!(interp.tape()[1], 1);
+        assert_eq!(interp.ptr(), 6);
+    }
+
+    fn run_bounded(
+        code: &[u8],
+        bounds: BoundsPolicy,
+    ) -> (Result<(), RuntimeError>, Interpreter<io::Empty, Vec<u8>>) {
+        let config = Config {
+            tape_size: 4,
+            bounds,
+            ..Config::default()
+        };
+        let ops = optimize(parse(code).unwrap());
+        let mut interp = Interpreter::with_config(ops, io::empty(), Vec::new(), &config);
+        (interp.run(), interp)
+    }
+
+    #[test]
+    fn test_error_policy() {
+        // The scan runs off the right end; op 2 is the scan.
+        let (result, interp) = run_bounded(b"+>+>+>+[>]", BoundsPolicy::Error);
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err,
+            RuntimeError::PointerOutOfBounds { pc: 2, ptr: 3 }
+        ));
+        assert_eq!((interp.pc(), interp.tape()), (2, &[1; 4][..]));
+
+        // As an `io::Error`, it still says which op failed.
+        let err = io::Error::from(err);
+        let oob = err.get_ref().unwrap().downcast_ref::<OutOfBounds>();
+        assert_eq!(oob, Some(&OutOfBounds { pc: 2 }));
+    }
+
+    #[test]
+    fn test_mul_add_left_of_the_tape() {
+        // `[-<+>]` at cell 0 moves into the cell left of the tape, the
+        // last one on a wrapping tape. The `,` leaves the counter alone at
+        // the end of the input, but the optimizer doesn't know its value.
+        let (result, interp) = run_bounded(b"++,[-<+>]", BoundsPolicy::Error);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds { pc: 2, ptr: 0 })
+        ));
+        assert_eq!(interp.tape(), [2, 0, 0, 0]);
+
+        let (result, interp) = run_bounded(b"++,[-<+>]", BoundsPolicy::Wrap);
+        result.unwrap();
+        assert_eq!(interp.tape(), [0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_clamp_policy() {
+        // Moves stop at either end instead of leaving the tape.
+        let (result, interp) = run_bounded(b"<<+>>>>>>+", BoundsPolicy::Clamp);
+        result.unwrap();
+        assert_eq!((interp.ptr(), interp.tape()), (3, &[1, 0, 0, 1][..]));
+    }
+
+    #[test]
+    fn test_grow_policy() {
+        // The tape doubles as the pointer runs off its end, but the left
+        // end is still an error.
+        let (result, interp) = run_bounded(b">>>>>+.", BoundsPolicy::Grow);
+        result.unwrap();
+        assert_eq!(interp.ptr(), 5);
+        assert_eq!(interp.tape().len(), 8);
+        assert_eq!(interp.into_inner().1, [1]);
+        let (result, _) = run_bounded(b"<+", BoundsPolicy::Grow);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_bidirectional_policy() {
+        // The scan stops on cell -3, past the left end, and cell -8 makes
+        // the tape grow again. Cells left of cell 0 are kept at the end.
+        let code = b"+<+<+[<]>.<<<<<<+";
+        let mut expected = vec![0; 16];
+        for idx in [0, 8, 14, 15] {
+            expected[idx] = 1;
+        }
+        let ops = optimize(parse(code).unwrap());
+        let config = Config {
+            tape_size: 4,
+            bounds: BoundsPolicy::Bidirectional,
+            ..Config::default()
+        };
+        for engine in 0..3 {
+            let mut interp: Interpreter<_, _> =
+                Interpreter::with_config(ops.clone(), io::empty(), Vec::new(), &config);
+            match engine {
+                0 => interp.run(),
+                1 => interp.run_threaded_code(),
+                _ => interp.run_packed(),
+            }
+            .unwrap();
+            assert_eq!(interp.ptr(), 0usize.wrapping_sub(8), "engine {}", engine);
+            assert_eq!(interp.tape(), expected, "engine {}", engine);
+            assert_eq!(interp.into_inner().1, [1], "engine {}", engine);
+        }
+    }
+
+    /// Records what was written at each flush.
+    #[derive(Default)]
+    struct Flushes {
+        buf: Vec<u8>,
+        flushed: Vec<Vec<u8>>,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if !self.buf.is_empty() {
+                self.flushed.push(std::mem::take(&mut self.buf));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let flushes = |code: &[u8], flush: FlushPolicy| {
+            let config = Config {
+                flush,
+                ..Config::default()
+            };
+            let ops = parse(code).unwrap();
+            let mut interp: Interpreter<_, _> =
+                Interpreter::with_config(ops, &b"x"[..], Flushes::default(), &config);
+            interp.run().unwrap();
+            interp.into_inner().1.flushed
+        };
+        // Prints "ab\nc", reads a byte and prints it.
+        let code = b"++++++++++[>++++++++++>+<<-]>---.+.>.<+.>>,.";
+        let chunks = |chunks: &[&[u8]]| chunks.iter().map(|c| c.to_vec()).collect::<Vec<_>>();
+        assert_eq!(
+            flushes(code, FlushPolicy::Always),
+            chunks(&[b"a", b"b", b"\n", b"c", b"x"])
+        );
+        assert_eq!(
+            flushes(code, FlushPolicy::Newline),
+            chunks(&[b"ab\n", b"c", b"x"])
+        );
+        assert_eq!(
+            flushes(code, FlushPolicy::Bytes(2)),
+            chunks(&[b"ab", b"\nc", b"x"])
+        );
+        assert_eq!(flushes(code, FlushPolicy::Input), chunks(&[b"ab\nc", b"x"]));
+        assert_eq!(flushes(code, FlushPolicy::Exit), chunks(&[b"ab\ncx"]));
+    }
+
+    #[test]
+    fn test_unicode() {
+        let config = Config {
+            eof: EofBehavior::Zero,
+            unicode: true,
+            ..Config::default()
+        };
+        let run = |ops: Vec<Op>, input: &[u8]| {
+            let mut out = Vec::new();
+            let mut interp = Interpreter::<_, _, u32>::with_config(ops, input, &mut out, &config);
+            interp.run_packed().unwrap();
+            let cell = interp.tape()[0];
+            drop(interp);
+            (out, cell)
+        };
+        let echo = optimize(parse(b",[.,]").unwrap());
+        assert_eq!(run(echo.clone(), "aé€😀".as_bytes()).0, "aé€😀".as_bytes());
+        // A malformed or cut-off sequence reads as U+FFFD.
+        assert_eq!(
+            run(echo, b"\xffa\xe2\x82").0,
+            "\u{fffd}a\u{fffd}".as_bytes()
+        );
+        let read = parse(b",").unwrap();
+        assert_eq!(run(read, "😀".as_bytes()).1, 0x1f600);
+        // So does a cell that isn't a character.
+        let write = |n| vec![Op::ValAdd(0, n), Op::Output];
+        assert_eq!(run(write(0x1f600), b"").0, "😀".as_bytes());
+        assert_eq!(run(write(0xd800), b"").0, "\u{fffd}".as_bytes());
+    }
+
+    #[test]
+    fn test_newline() {
+        let echo = |input: &[u8], input_newline, output_newline| {
+            let config = Config {
+                eof: EofBehavior::Zero,
+                input_newline,
+                output_newline,
+                ..Config::default()
+            };
+            let ops = optimize(parse(b",[.,]").unwrap());
+            let mut out = Vec::new();
+            Interpreter::<_, _, u8>::with_config(ops, input, &mut out, &config)
+                .run()
+                .unwrap();
+            out
+        };
+        let input = b"a\nb\r\nc\rd\r";
+        assert_eq!(echo(input, Newline::Lf, Newline::Lf), input);
+        assert_eq!(echo(input, Newline::Cr, Newline::Lf), b"a\rb\rc\rd\r");
+        assert_eq!(
+            echo(input, Newline::CrLf, Newline::Lf),
+            b"a\r\nb\r\nc\r\nd\r\n"
+        );
+        assert_eq!(echo(input, Newline::Lf, Newline::Cr), b"a\nb\n\nc\nd\n");
+        // A CR without an LF after it, even the last byte, is left alone.
+        assert_eq!(echo(input, Newline::Lf, Newline::CrLf), b"a\nb\nc\rd\r");
+        // Translating both ways gets the input back with LFs.
+        assert_eq!(echo(input, Newline::CrLf, Newline::CrLf), b"a\nb\nc\nd\n");
+    }
+
+    #[test]
+    fn test_stride_scan() {
+        // `[>>]` from cell 0 passes the set cells 2 and 4 and stops on 6;
+        // `[<<<]` from there stops on 3.
+        let ops = optimize(parse(b"+>>+>>+<<<<[>>]+[<<<]").unwrap());
+        assert!(ops.contains(&Op::Scan(2)) && ops.contains(&Op::Scan(-3)));
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        interp.run().unwrap();
+        assert_eq!(interp.ptr(), 3);
+    }
+
+    #[test]
+    fn test_clear_ranges() {
+        for code in [
+            &b"+>+>+>+>+>+>+>+<<<<<<<[-]>[-]>[-]>[-]>[-]"[..],
+            // Past the end of the tape.
+            b"+>+>+>+>+>+>+>+<<[-]>[-]>[-]>[-]>[-]",
+            b"+>+>+>+>+>+>+>+<<<<<<<[[-]>]",
+            b"+>+>+>+>+>+>+>+<<<<<<<[[-]<]",
+            b"+>+>+>+>+>+>+>+<<<<<<<[[-]>>>]",
+        ] {
+            let ops = optimize(parse(code).unwrap());
+            assert!(
+                ops.iter()
+                    .any(|op| matches!(op, Op::ClearRange(..) | Op::ClearUntilZero(_)))
+            );
+            for bounds in [BoundsPolicy::Error, BoundsPolicy::Wrap, BoundsPolicy::Clamp] {
+                let config = Config {
+                    tape_size: 8,
+                    bounds,
+                    ..Config::default()
+                };
+                let expected =
+                    crate::reference::run::<u8, _, _>(code, &config, io::empty(), io::sink()).ok();
+                let mut interp =
+                    Interpreter::with_config(ops.clone(), io::empty(), io::sink(), &config);
+                let ran = interp
+                    .run()
+                    .ok()
+                    .map(|()| (interp.tape().to_vec(), interp.ptr()));
+                let label = (String::from_utf8_lossy(code), bounds);
+                assert_eq!(ran, expected, "{:?}", label);
+
+                let mut tape = [0u8; 8];
+                let ptr = crate::exec::run(&ops, &mut tape, bounds, config.eof, || None, |_| {});
+                let ran = ptr.ok().map(|ptr| (tape.to_vec(), ptr));
+                assert_eq!(ran, expected, "{:?}", label);
+            }
+        }
+    }
+
+    #[test]
+    fn test_wide_cells() {
+        // 256 increments overflow a u8 cell back to zero but not a u16 one.
+        let mut code = vec![b'+'; 256];
+        code.extend_from_slice(b"[>+<-]");
+        let ops = optimize(parse(&code).unwrap());
+
+        let mut narrow = Interpreter::new(ops.clone(), io::empty(), io::sink());
+        narrow.run().unwrap();
+        assert_eq!(&narrow.tape()[..2], &[0, 0]);
+
+        let config = Config::default();
+        let mut wide = Interpreter::<_, _, u16>::with_config(ops, io::empty(), io::sink(), &config);
+        wide.run().unwrap();
+        assert_eq!(&wide.tape()[..2], &[0, 256]);
+    }
+
+    #[test]
+    fn test_eof_behavior() {
+        let ops = optimize(parse(b"+++,").unwrap());
+        for (eof, expected) in [
+            (EofBehavior::Unchanged, 3),
+            (EofBehavior::Zero, 0),
+            (EofBehavior::MinusOne, 255),
+        ] {
+            let config = Config {
+                eof,
+                ..Config::default()
+            };
+            let mut interp: Interpreter<_, _> =
+                Interpreter::with_config(ops.clone(), io::empty(), io::sink(), &config);
+            interp.run().unwrap();
+            assert_eq!(interp.tape()[0], expected);
+        }
+
+        let config = Config {
+            eof: EofBehavior::MinusOne,
+            ..Config::default()
+        };
+        let mut wide = Interpreter::<_, _, u16>::with_config(ops, io::empty(), io::sink(), &config);
+        wide.run().unwrap();
+        assert_eq!(wide.tape()[0], 0xffff);
+    }
+
+    #[test]
+    fn test_procedures() {
+        let options = ParseOptions {
+            dialect: Dialect::Pbrain,
+            ..ParseOptions::default()
+        };
+        // Procedure 1 prints cell 2 once per count in cell 1, recursively.
+        let code = format!("+(>[->.<<:>]<)>+++>{}<<:", "+".repeat(42));
+        let ops = optimize(parse_with(code.as_bytes(), &options).unwrap());
+        let mut output = Vec::new();
+        execute(&ops, &[][..], &mut output).unwrap();
+        assert_eq!(output, b"***");
+
+        let ops = optimize(parse_with(b"+(-)+:-:", &options).unwrap());
+        let mut interp = Interpreter::new(ops, io::empty(), Vec::new());
+        assert!(matches!(
+            interp.run(),
+            Err(RuntimeError::UndefinedProcedure(UndefinedProcedure {
+                id: 2,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_extended1() {
+        let options = ParseOptions {
+            dialect: Dialect::Extended1,
+            ..ParseOptions::default()
+        };
+        // 'A' is stored, shifted and retrieved, then combined with itself.
+        let code = b"++++++++[>++++++++<-]>+$.{.!}.~^.!.@+++++.";
+        let ops = optimize(parse_with(code, &options).unwrap());
+        let mut output = Vec::new();
+        execute(&ops, &[][..], &mut output).unwrap();
+        assert_eq!(output, [65, 130, 32, 158, 65]);
+
+        let mut tape = [0u8; 2];
+        let mut exec_output = Vec::new();
+        crate::exec::run(
+            &ops,
+            &mut tape,
+            BoundsPolicy::Error,
+            EofBehavior::Unchanged,
+            || None,
+            |b| exec_output.push(b),
+        )
+        .unwrap();
+        assert_eq!(exec_output, output);
+
+        // The shifts and `~` keep to the cell width.
+        let ops = parse_with(b"~}", &options).unwrap();
+        let mut interp =
+            Interpreter::<_, _, u16>::with_config(ops, io::empty(), io::sink(), &Config::default());
+        interp.run().unwrap();
+        assert_eq!(interp.tape()[0], 0x7fff);
+    }
+
+    #[test]
+    fn test_random() {
+        let options = ParseOptions {
+            random: true,
+            ..ParseOptions::default()
+        };
+        let ops = parse_with(b"?.?.?.", &options).unwrap();
+        let run = |seed| {
+            let config = Config {
+                seed,
+                ..Config::default()
+            };
+            let mut output = Vec::new();
+            let mut interp = Interpreter::<_, _, u16>::with_config(
+                ops.clone(),
+                io::empty(),
+                &mut output,
+                &config,
+            );
+            interp.run().unwrap();
+            assert!(interp.tape()[0] < 256);
+            drop(interp);
+            output
+        };
+        // The same seed gives the same bytes, and the executor draws from
+        // seed 0.
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        let mut output = Vec::new();
+        crate::exec::run(
+            &ops,
+            &mut [0u8; 1],
+            BoundsPolicy::Error,
+            EofBehavior::Unchanged,
+            || None,
+            |b| output.push(b),
+        )
+        .unwrap();
+        assert_eq!(output, run(0));
+        assert_eq!(parse(b"?").unwrap(), []);
+    }
+
+    #[test]
+    fn test_multitape() {
+        let options = ParseOptions {
+            dialect: Dialect::Multitape,
+            ..ParseOptions::default()
+        };
+        // Each tape keeps its own cells and pointer.
+        let code = b"+++>++v>+++++^.<.v.^^.";
+        let ops = optimize(parse_with(code, &options).unwrap());
+        let mut output = Vec::new();
+        execute(&ops, &[][..], &mut output).unwrap();
+        assert_eq!(output, [2, 3, 5, 0]);
+
+        // A snapshot keeps the tapes switched away from.
+        let mut interp = Interpreter::new(ops, io::empty(), Vec::new());
+        interp.run_for(3).unwrap();
+        let state = interp.snapshot();
+        interp.run().unwrap();
+        interp.restore(&state);
+        interp.run().unwrap();
+        assert_eq!(interp.into_inner().1, [2, 3, 5, 0, 2, 3, 5, 0]);
+    }
+
+    #[test]
+    fn test_forks() {
+        let options = ParseOptions {
+            dialect: Dialect::Brainfork,
+            ..ParseOptions::default()
+        };
+        // The forked thread prints 'C' and both print '!'.
+        let code = b"Y[->++++++++[<++++++++>-]<+++.[-]]>+++++[<++++++>-]<+++.";
+        let ops = optimize(parse_with(code, &options).unwrap());
+        let mut output = Vec::new();
+        execute(&ops, &[][..], &mut output).unwrap();
+        assert_eq!(output, b"C!!");
+
+        let mut interp = Interpreter::new(ops, io::empty(), Vec::new());
+        interp.run_threaded().unwrap();
+        assert_eq!(interp.ptr(), 0);
+        let (_, mut output) = interp.into_inner();
+        output.sort_unstable();
+        assert_eq!(output, b"!!C");
+
+        // A fork on the last cell fails in the thread that forks.
+        let config = Config {
+            tape_size: 1,
+            ..Config::default()
+        };
+        let ops = parse_with(b"Y", &options).unwrap();
+        let mut interp =
+            Interpreter::<_, _, u8>::with_config(ops, io::empty(), Vec::new(), &config);
+        assert!(matches!(
+            interp.run_threaded(),
+            Err(RuntimeError::PointerOutOfBounds { pc: 0, ptr: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_div_mod() {
+        let config = Config {
+            tape_size: 16,
+            ..Config::default()
+        };
+        let reference = |code: &[u8]| {
+            crate::reference::run::<u8, _, _>(code, &config, io::empty(), Vec::new()).ok()
+        };
+        let mut applied = 0;
+        for cells in
+            [
+                [7, 0, 3, 0, 0, 0],
+                [1, 250, 1, 0, 255, 0],
+                [9, 0, 1, 0, 0, 0],
+                [2, 0, 0, 3, 0, 0],
+                [5, 0, 2, 2, 0, 1],
+            ]
+            .into_iter()
+            .chain((0..30).flat_map(|n| {
+                (1..6).flat_map(move |d| (0..d).map(move |m| [n, 4, d - m, m, 1, 0]))
+            }))
+        {
+            let mut setup = b">".to_vec();
+            for &c in &cells {
+                setup.extend(std::iter::repeat_n(b'+', c));
+                setup.push(b'>');
+            }
+            setup.extend_from_slice(b"<<<<<<");
+            let code = [&setup[..], b"[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]"].concat();
+            let ops = optimize(parse(&code).unwrap());
+            let mut interp = Interpreter::with_config(ops, io::empty(), Vec::new(), &config);
+            // Where the cells aren't laid out for it, the loop may well
+            // leave the tape.
+            let ran = interp
+                .run()
+                .ok()
+                .map(|()| (interp.tape().to_vec(), interp.ptr()));
+            assert_eq!(ran, reference(&code), "{:?}", cells);
+
+            let (mut tape, _) = reference(&setup).unwrap();
+            crate::div_mod(&mut tape, 1);
+            applied += usize::from(tape[1] == 0 && cells[0] != 0);
+        }
+        // The first, and the rest but for nothing to divide or dividing by
+        // 1.
+        assert_eq!(applied, 1 + 29 * 14);
+    }
+
+    #[test]
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    fn test_tiered_matches_interpreter() {
+        // The innermost loop takes well over HOT_LOOP_THRESHOLD back-edges.
+        let mut code = Vec::new();
+        for _ in 0..2 {
+            code.extend_from_slice(b"++++++++++++++++[>");
+        }
+        code.extend_from_slice(b"++++++++[>.+<-]<-]<-]");
+        let ops = optimize(parse(&code).unwrap());
+
+        let mut interp = Interpreter::new(ops.clone(), io::empty(), Vec::new());
+        interp.run().unwrap();
+        let mut tiered = Interpreter::new(ops, io::empty(), Vec::new());
+        tiered.run_tiered().unwrap();
+        assert!(tiered.tiers.compiled.iter().any(Option::is_some));
+        assert_eq!(tiered.tape(), interp.tape());
+        assert_eq!(tiered.ptr(), interp.ptr());
+        assert_eq!(tiered.into_inner().1, interp.into_inner().1);
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn test_compile_hot_loops() {
+        // Only the innermost loop runs HOT_LOOP_THRESHOLD times.
+        let code = b"++++++++++++++++[>++++++++++++++++[>++++++++[>.+<-]<-]<-]";
+        let ops = optimize(parse(code).unwrap());
+        let mut profiled = Interpreter::new(ops.clone(), io::empty(), Vec::new());
+        profiled.enable_profiling();
+        profiled.run().unwrap();
+        let profile = profiled.profile().unwrap().clone();
+
+        let mut tiered = Interpreter::new(ops.clone(), io::empty(), Vec::new());
+        assert_eq!(tiered.compile_hot_loops(&profile), 1);
+        let inner = ops.iter().rposition(|op| matches!(op, Op::Jz(_))).unwrap();
+        assert!(tiered.tiers.compiled[inner].is_some());
+        tiered.run_tiered().unwrap();
+        assert_eq!(tiered.tape(), profiled.tape());
+        assert_eq!(tiered.into_inner().1, profiled.into_inner().1);
+
+        let mut other =
+            Interpreter::new(optimize(parse(b"+[-]").unwrap()), io::empty(), io::sink());
+        assert_eq!(other.compile_hot_loops(&profile), 0);
+    }
+}
@@ -0,0 +1,273 @@
+//! Engines as values: an [`Executor`] runs a [`Program`] on a [`BfIo`], so
+//! the engine can be picked at run time, by name or by type, and other
+//! crates can bring their own.
+
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use super::{Config, Interpreter, RuntimeError};
+use crate::Op;
+use crate::cell::Cell;
+
+/// An optimized program and the settings to run it under.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Program {
+    pub ops: Vec<Op>,
+    pub config: Config,
+}
+
+impl Program {
+    pub fn new(ops: Vec<Op>, config: Config) -> Self {
+        Program { ops, config }
+    }
+}
+
+/// The input `,` reads and the output `.` writes, for an [`Executor`].
+/// A pair of a reader and a writer is one, so `&mut (&input[..], Vec::new())`
+/// runs a program on a byte string and collects its output.
+///
+/// The handles must be [`Send`], so that the `threads` engine can share
+/// them between its threads: [`io::stdin`](std::io::stdin) and
+/// [`io::stdout`](std::io::stdout) are, their locks aren't.
+pub trait BfIo {
+    fn handles(&mut self) -> (&mut (dyn Read + Send), &mut (dyn Write + Send));
+}
+
+impl<R: Read + Send, W: Write + Send> BfIo for (R, W) {
+    fn handles(&mut self) -> (&mut (dyn Read + Send), &mut (dyn Write + Send)) {
+        (&mut self.0, &mut self.1)
+    }
+}
+
+/// What a finished run did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Stats {
+    /// Ops executed, as [`Interpreter::steps`] counts them: only under a
+    /// step limit.
+    pub steps: u64,
+    /// Where the data pointer ended up.
+    pub ptr: usize,
+    /// How long the run took, setting up the engine included.
+    pub elapsed: Duration,
+}
+
+/// A way to run a [`Program`] to the end on cells of type `C`, such as one
+/// of the [`BuiltinExecutor`]s.
+///
+/// One of its own can wrap a built-in one, or drive an [`Interpreter`] op
+/// by op with [`step`](Interpreter::step). It takes `&mut self`, so it may
+/// keep state between runs, such as code it compiled.
+pub trait Executor<C = u8> {
+    fn name(&self) -> &str;
+
+    /// A one-line summary, for listings. Empty by default.
+    fn description(&self) -> &str {
+        ""
+    }
+
+    fn run(&mut self, program: &Program, io: &mut dyn BfIo) -> Result<Stats, RuntimeError>;
+}
+
+/// The crate's engines, as `--engine` names them, but for `unchecked`,
+/// which is [`UncheckedExecutor`]. Each one that can't run a program as
+/// asked, such as the JIT with a step limit, falls back to the
+/// interpreter, as its `run_*` method documents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BuiltinExecutor {
+    /// [`Interpreter::run`].
+    #[default]
+    Interpreter,
+    /// [`Interpreter::run_threaded_code`].
+    Threaded,
+    /// [`Interpreter::run_packed`].
+    Packed,
+    /// [`Interpreter::run_threaded`], a thread for each Brainfork thread.
+    Threads,
+    /// [`Interpreter::run_jit`]. Only x86-64 Unix has a JIT; elsewhere
+    /// this interprets.
+    Jit,
+    /// [`Interpreter::run_tiered`], which like the JIT interprets where
+    /// there is none.
+    Tiered,
+}
+
+impl BuiltinExecutor {
+    pub const ALL: [BuiltinExecutor; 6] = [
+        BuiltinExecutor::Interpreter,
+        BuiltinExecutor::Threaded,
+        BuiltinExecutor::Packed,
+        BuiltinExecutor::Threads,
+        BuiltinExecutor::Jit,
+        BuiltinExecutor::Tiered,
+    ];
+
+    /// The name `--engine` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinExecutor::Interpreter => "interpreter",
+            BuiltinExecutor::Threaded => "threaded",
+            BuiltinExecutor::Packed => "packed",
+            BuiltinExecutor::Threads => "threads",
+            BuiltinExecutor::Jit => "jit",
+            BuiltinExecutor::Tiered => "tiered",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            BuiltinExecutor::Interpreter => "interprets the ops one at a time",
+            BuiltinExecutor::Threaded => "runs the ops lowered to a closure each",
+            BuiltinExecutor::Packed => "runs the ops lowered to a 64-bit word each",
+            BuiltinExecutor::Threads => "interprets, with a thread for each Brainfork thread",
+            BuiltinExecutor::Jit => "compiles the program to native code up front",
+            BuiltinExecutor::Tiered => "interprets, compiling the loops that run often",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|&e| e.name() == name)
+    }
+
+    /// Runs `interp` from where it is to the end, the way this engine
+    /// does, for callers that look at the machine afterwards.
+    pub(crate) fn run_interpreter<R: Read + Send, W: Write + Send, C: Cell>(
+        self,
+        interp: &mut Interpreter<R, W, C>,
+    ) -> Result<(), RuntimeError> {
+        match self {
+            BuiltinExecutor::Interpreter => interp.run(),
+            BuiltinExecutor::Threaded => interp.run_threaded_code(),
+            BuiltinExecutor::Packed => interp.run_packed(),
+            BuiltinExecutor::Threads => interp.run_threaded(),
+            #[cfg(all(unix, target_arch = "x86_64"))]
+            BuiltinExecutor::Jit => interp.run_jit(),
+            #[cfg(all(unix, target_arch = "x86_64"))]
+            BuiltinExecutor::Tiered => interp.run_tiered(),
+            #[cfg(not(all(unix, target_arch = "x86_64")))]
+            BuiltinExecutor::Jit | BuiltinExecutor::Tiered => interp.run(),
+        }
+    }
+}
+
+impl<C: Cell> Executor<C> for BuiltinExecutor {
+    fn name(&self) -> &str {
+        BuiltinExecutor::name(*self)
+    }
+
+    fn description(&self) -> &str {
+        BuiltinExecutor::description(*self)
+    }
+
+    fn run(&mut self, program: &Program, io: &mut dyn BfIo) -> Result<Stats, RuntimeError> {
+        let start = Instant::now();
+        let (input, output) = io.handles();
+        let mut interp = Interpreter::<_, _, C>::with_config(
+            program.ops.clone(),
+            input,
+            output,
+            &program.config,
+        );
+        self.run_interpreter(&mut interp)?;
+        Ok(stats(&interp, start))
+    }
+}
+
+/// [`Interpreter::run_unchecked`] as an executor. It is `unsafe` to make,
+/// since every program it runs is trusted to stay on the tape.
+#[derive(Debug)]
+pub struct UncheckedExecutor(());
+
+impl UncheckedExecutor {
+    /// # Safety
+    ///
+    /// Every program the executor runs must meet the requirements of
+    /// [`Interpreter::run_unchecked`].
+    pub unsafe fn new() -> Self {
+        UncheckedExecutor(())
+    }
+}
+
+impl<C: Cell> Executor<C> for UncheckedExecutor {
+    fn name(&self) -> &str {
+        "unchecked"
+    }
+
+    fn description(&self) -> &str {
+        "interprets without bounds checks"
+    }
+
+    fn run(&mut self, program: &Program, io: &mut dyn BfIo) -> Result<Stats, RuntimeError> {
+        let start = Instant::now();
+        let (input, output) = io.handles();
+        let mut interp = Interpreter::<_, _, C>::with_config(
+            program.ops.clone(),
+            input,
+            output,
+            &program.config,
+        );
+        // SAFETY: whoever made the executor vouched for its programs.
+        unsafe { interp.run_unchecked() }?;
+        Ok(stats(&interp, start))
+    }
+}
+
+fn stats<R: Read, W: Write, C: Cell>(interp: &Interpreter<R, W, C>, start: Instant) -> Stats {
+    Stats {
+        steps: interp.steps(),
+        ptr: interp.ptr(),
+        elapsed: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{optimize, parse};
+
+    /// Runs the interpreter, counting its runs.
+    struct Counted(u32);
+
+    impl Executor for Counted {
+        fn name(&self) -> &str {
+            "counted"
+        }
+
+        fn run(&mut self, program: &Program, io: &mut dyn BfIo) -> Result<Stats, RuntimeError> {
+            self.0 += 1;
+            Executor::<u8>::run(&mut BuiltinExecutor::Interpreter, program, io)
+        }
+    }
+
+    #[test]
+    fn test_executors() {
+        let hello = include_bytes!("../../examples/helloworld.bf");
+        let program = Program::new(optimize(parse(hello).unwrap()), Config::default());
+        let run = |executor: &mut dyn Executor| {
+            let mut io = (&b""[..], Vec::new());
+            let stats = executor.run(&program, &mut io).unwrap();
+            (stats.ptr, io.1)
+        };
+        let (expected, _) = run(&mut BuiltinExecutor::Interpreter);
+        for mut executor in BuiltinExecutor::ALL {
+            assert_eq!(BuiltinExecutor::from_name(executor.name()), Some(executor));
+            assert_eq!(run(&mut executor), (expected, b"Hello, World!".to_vec()));
+        }
+        // SAFETY: the program stays on the tape.
+        let mut unchecked = unsafe { UncheckedExecutor::new() };
+        assert_eq!(run(&mut unchecked).1, b"Hello, World!");
+
+        let mut counted = Counted(0);
+        assert_eq!(run(&mut counted), (expected, b"Hello, World!".to_vec()));
+        assert_eq!(Executor::<u8>::description(&counted), "");
+        assert_eq!(counted.0, 1);
+        assert_eq!(BuiltinExecutor::from_name("unchecked"), None);
+
+        let program = Program::new(parse(b"<+").unwrap(), Config::default());
+        let mut io = (&b""[..], Vec::new());
+        assert!(matches!(
+            Executor::<u16>::run(&mut BuiltinExecutor::Packed, &program, &mut io),
+            Err(RuntimeError::PointerOutOfBounds { .. })
+        ));
+    }
+}
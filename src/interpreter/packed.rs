@@ -0,0 +1,417 @@
+//! The packed engine behind [`Interpreter::run_packed`]: the ops are
+//! lowered to one 64-bit word each by [`lower_to_bytecode`], which the
+//! run decodes as it dispatches. A word is a quarter the size of an
+//! [`Op`], so more of a loop stays in the cache.
+//!
+//! The low byte of a word is its opcode. Ops on a cell keep its offset
+//! from the pointer in the next 24 bits, signed, and their operand in the
+//! high 32; moves and scans keep their distance in the high 56 bits,
+//! signed. Runs of output and bulk ops keep the index of their operands
+//! in side tables.
+
+use std::io::{Read, Write};
+
+use super::{
+    Addressing, BidirectionalTape, CheckedTape, ClampTape, GrowTape, Interpreter, RuntimeError,
+    UncheckedTape, WrapTape, read_cell,
+};
+use crate::cell::Cell;
+use crate::{BoundsPolicy, Op};
+
+const ADD: u8 = 0;
+const MOVE: u8 = 1;
+const OUTPUT: u8 = 2;
+const INPUT: u8 = 3;
+const OUTPUT_CONST: u8 = 4;
+const JZ: u8 = 5;
+const JNZ: u8 = 6;
+const SET: u8 = 7;
+const MUL_ADD: u8 = 8;
+const SCAN_LEFT: u8 = 9;
+const SCAN_RIGHT: u8 = 10;
+const SCAN: u8 = 11;
+const BULK_ADD: u8 = 12;
+const BULK_CLEAR: u8 = 13;
+const END: u8 = 14;
+const DIV_MOD: u8 = 15;
+const CLEAR_RANGE: u8 = 16;
+const CLEAR_UNTIL_ZERO: u8 = 17;
+const ADD_TO: u8 = 18;
+const SUB_FROM: u8 = 19;
+const TRANSFER: u8 = 20;
+const OUTPUT_REPEAT: u8 = 21;
+
+/// A program lowered by [`lower_to_bytecode`], for
+/// [`Interpreter::run_packed`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedProgram {
+    /// One word per op, so jump targets stay the same.
+    words: Vec<u64>,
+    /// The bytes of each `OutputConst`.
+    consts: Vec<Vec<u8>>,
+    /// The deltas of each `BulkAdd`, and the targets of each `Transfer`.
+    adds: Vec<Vec<(isize, u32)>>,
+    /// The offsets of each `BulkClear`.
+    clears: Vec<Vec<isize>>,
+}
+
+impl PackedProgram {
+    /// The number of words, one per op.
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+}
+
+/// A word for an op on the cell `offset` cells from the pointer, if the
+/// offset fits.
+fn cell_word(opcode: u8, offset: isize, operand: u32) -> Option<u64> {
+    let offset = i32::try_from(offset)
+        .ok()
+        .filter(|offset| (-(1 << 23)..1 << 23).contains(offset))?;
+    Some(u64::from(opcode) | u64::from(offset as u32 & 0xff_ffff) << 8 | u64::from(operand) << 32)
+}
+
+/// A word for a move or scan of `n` cells, if it fits.
+fn wide_word(opcode: u8, n: isize) -> Option<u64> {
+    let n = i64::try_from(n).ok()?;
+    ((n << 8) >> 8 == n).then_some((n << 8) as u64 | u64::from(opcode))
+}
+
+/// A word whose operand indexes a side table, or is a jump target.
+fn index_word(opcode: u8, index: usize) -> Option<u64> {
+    cell_word(opcode, 0, u32::try_from(index).ok()?)
+}
+
+#[inline(always)]
+fn opcode(word: u64) -> u8 {
+    word as u8
+}
+
+#[inline(always)]
+fn offset(word: u64) -> isize {
+    (word as u32 as i32 >> 8) as isize
+}
+
+#[inline(always)]
+fn operand(word: u64) -> u32 {
+    (word >> 32) as u32
+}
+
+#[inline(always)]
+fn wide(word: u64) -> isize {
+    (word as i64 >> 8) as isize
+}
+
+/// Lowers `ops` to packed words, or returns `None` if one doesn't fit: an
+/// offset past 2^23 cells, a move past 2^55, a jump or repeated `.` past
+/// 2^32, or an op of pbrain, Brainfork, Extended Type I or multitape,
+/// [`Op::Debug`] or [`Op::Random`].
+pub fn lower_to_bytecode(ops: &[Op]) -> Option<PackedProgram> {
+    let mut program = PackedProgram {
+        words: Vec::with_capacity(ops.len()),
+        consts: Vec::new(),
+        adds: Vec::new(),
+        clears: Vec::new(),
+    };
+    for op in ops {
+        let word = match *op {
+            Op::PtrAdd(n) => wide_word(MOVE, n)?,
+            Op::ValAdd(offset, n) => cell_word(ADD, offset, n)?,
+            // Subtracting is adding the negation, in any cell width.
+            Op::ValSub(offset, n) => cell_word(ADD, offset, n.wrapping_neg())?,
+            Op::Output => cell_word(OUTPUT, 0, 0)?,
+            Op::OutputAt(offset) => cell_word(OUTPUT, offset, 0)?,
+            Op::OutputRepeat(offset, count) => {
+                cell_word(OUTPUT_REPEAT, offset, u32::try_from(count).ok()?)?
+            }
+            Op::Input => cell_word(INPUT, 0, 0)?,
+            Op::InputAt(offset) => cell_word(INPUT, offset, 0)?,
+            Op::OutputConst(ref bytes) => {
+                program.consts.push(bytes.clone());
+                index_word(OUTPUT_CONST, program.consts.len() - 1)?
+            }
+            Op::Jz(target) => index_word(JZ, target)?,
+            Op::Jnz(target) => index_word(JNZ, target)?,
+            Op::Clear(offset) => cell_word(SET, offset, 0)?,
+            Op::Set(offset, n) => cell_word(SET, offset, n)?,
+            Op::MulAdd(offset, factor) => cell_word(MUL_ADD, offset, factor)?,
+            Op::AddTo(offset) => cell_word(ADD_TO, offset, 0)?,
+            Op::SubFrom(offset) => cell_word(SUB_FROM, offset, 0)?,
+            Op::ScanLeft => cell_word(SCAN_LEFT, 0, 0)?,
+            Op::ScanRight => cell_word(SCAN_RIGHT, 0, 0)?,
+            Op::Scan(stride) => wide_word(SCAN, stride)?,
+            Op::BulkAdd(ref deltas) => {
+                program.adds.push(deltas.clone());
+                index_word(BULK_ADD, program.adds.len() - 1)?
+            }
+            Op::Transfer(ref targets) => {
+                program.adds.push(targets.clone());
+                index_word(TRANSFER, program.adds.len() - 1)?
+            }
+            Op::BulkClear(ref offsets) => {
+                program.clears.push(offsets.clone());
+                index_word(BULK_CLEAR, program.clears.len() - 1)?
+            }
+            Op::ClearRange(offset, n) => cell_word(CLEAR_RANGE, offset, u32::try_from(n).ok()?)?,
+            Op::ClearUntilZero(stride) => wide_word(CLEAR_UNTIL_ZERO, stride)?,
+            Op::End => cell_word(END, 0, 0)?,
+            Op::DivMod => cell_word(DIV_MOD, 0, 0)?,
+            Op::Debug
+            | Op::DefProc(_)
+            | Op::EndProc
+            | Op::CallProc
+            | Op::Fork
+            | Op::Store
+            | Op::Retrieve
+            | Op::Bitwise(_)
+            | Op::SwitchTape(_)
+            | Op::Random => return None,
+        };
+        program.words.push(word);
+    }
+    Some(program)
+}
+
+impl<R: Read, W: Write, C: Cell> Interpreter<R, W, C> {
+    /// Runs the program until it ends like [`run`](Self::run), but from
+    /// packed words: the ops are first lowered with [`lower_to_bytecode`].
+    /// Programs with a step or time limit, profiling, or ops that don't
+    /// lower are interpreted.
+    ///
+    /// # Panics
+    ///
+    /// If the program touches a cell outside the tape under
+    /// [`BoundsPolicy::Unchecked`].
+    pub fn run_packed(&mut self) -> Result<(), RuntimeError> {
+        let program = lower_to_bytecode(&self.ops)
+            .filter(|_| !self.limits.any() && self.timeout.is_none())
+            .filter(|_| self.profile.is_none() && !self.unicode);
+        let Some(program) = program else {
+            return self.run();
+        };
+        match self.bounds {
+            BoundsPolicy::Error => self.run_words::<CheckedTape>(&program),
+            BoundsPolicy::Clamp => self.run_words::<ClampTape>(&program),
+            BoundsPolicy::Wrap => self.run_words::<WrapTape>(&program),
+            BoundsPolicy::Grow => self.run_words::<GrowTape>(&program),
+            BoundsPolicy::Bidirectional => self.run_words::<BidirectionalTape>(&program),
+            BoundsPolicy::Unchecked => self.run_words::<UncheckedTape>(&program),
+        }
+    }
+
+    fn run_words<A: Addressing>(&mut self, program: &PackedProgram) -> Result<(), RuntimeError> {
+        let words = &program.words[..];
+        let tape = &mut self.tape;
+        let mut pc = self.pc;
+        let mut ptr = self.ptr;
+
+        // Stops the run at the current op, which touched a cell off the
+        // tape.
+        macro_rules! out_of_bounds {
+            () => {{
+                self.pc = pc;
+                self.ptr = ptr;
+                self.output.flush()?;
+                return Err(RuntimeError::PointerOutOfBounds { pc, ptr });
+            }};
+        }
+        // The tape index `offset` cells from the pointer.
+        macro_rules! at {
+            ($offset:expr) => {
+                match A::index(tape, ptr, $offset) {
+                    Some(idx) => idx,
+                    None => out_of_bounds!(),
+                }
+            };
+        }
+
+        while let Some(&word) = words.get(pc) {
+            match opcode(word) {
+                ADD => {
+                    let idx = at!(offset(word));
+                    tape[idx] = tape[idx].wrapping_add(C::from_u32(operand(word)));
+                }
+                MOVE => ptr = A::move_ptr(ptr, wide(word), tape.len()),
+                OUTPUT => {
+                    let idx = at!(offset(word));
+                    self.output.write_all(&[tape[idx].to_byte()])?;
+                }
+                OUTPUT_REPEAT => {
+                    let idx = at!(offset(word));
+                    let count = operand(word) as usize;
+                    self.output.write_all(&vec![tape[idx].to_byte(); count])?;
+                }
+                INPUT => {
+                    let idx = at!(offset(word));
+                    match self
+                        .output
+                        .flush_for_input()
+                        .and_then(|()| read_cell(&mut self.input, self.eof))
+                    {
+                        Ok(Some(v)) => tape[idx] = v,
+                        Ok(None) => {}
+                        Err(e) => {
+                            // Stop before this `,`, like the interpreter.
+                            self.pc = pc;
+                            self.ptr = ptr;
+                            return Err(RuntimeError::Io(e));
+                        }
+                    }
+                }
+                OUTPUT_CONST => {
+                    self.output
+                        .write_all(&program.consts[operand(word) as usize])?;
+                }
+                JZ => {
+                    let idx = at!(0);
+                    if tape[idx].is_zero() {
+                        pc = operand(word) as usize;
+                    }
+                }
+                JNZ => {
+                    let idx = at!(0);
+                    if !tape[idx].is_zero() {
+                        pc = operand(word) as usize;
+                    }
+                }
+                SET => {
+                    let idx = at!(offset(word));
+                    tape[idx] = C::from_u32(operand(word));
+                }
+                MUL_ADD => {
+                    let src = at!(0);
+                    if !tape[src].is_zero() {
+                        let idx = at!(offset(word));
+                        tape[idx] = tape[idx]
+                            .wrapping_add(tape[src].wrapping_mul(C::from_u32(operand(word))));
+                    }
+                }
+                ADD_TO => {
+                    let src = at!(0);
+                    if !tape[src].is_zero() {
+                        let idx = at!(offset(word));
+                        tape[idx] = tape[idx].wrapping_add(tape[src]);
+                    }
+                }
+                SUB_FROM => {
+                    let src = at!(0);
+                    if !tape[src].is_zero() {
+                        let idx = at!(offset(word));
+                        tape[idx] = tape[idx].wrapping_sub(tape[src]);
+                    }
+                }
+                SCAN_LEFT => {
+                    ptr = match A::scan_left(tape, ptr) {
+                        Some(ptr) => ptr,
+                        None => out_of_bounds!(),
+                    };
+                }
+                SCAN_RIGHT => {
+                    ptr = match A::scan_right(tape, ptr) {
+                        Some(ptr) => ptr,
+                        None => out_of_bounds!(),
+                    };
+                }
+                SCAN => loop {
+                    let idx = at!(0);
+                    if tape[idx].is_zero() {
+                        break;
+                    }
+                    ptr = A::move_ptr(ptr, wide(word), tape.len());
+                },
+                BULK_ADD => {
+                    for &(offset, n) in &program.adds[operand(word) as usize] {
+                        let idx = at!(offset);
+                        tape[idx] = tape[idx].wrapping_add(C::from_u32(n));
+                    }
+                }
+                TRANSFER => {
+                    let src = at!(0);
+                    let value = tape[src];
+                    if !value.is_zero() {
+                        for &(offset, factor) in &program.adds[operand(word) as usize] {
+                            let idx = at!(offset);
+                            tape[idx] =
+                                tape[idx].wrapping_add(value.wrapping_mul(C::from_u32(factor)));
+                        }
+                        tape[src] = C::default();
+                    }
+                }
+                BULK_CLEAR => {
+                    for &offset in &program.clears[operand(word) as usize] {
+                        let idx = at!(offset);
+                        tape[idx] = C::default();
+                    }
+                }
+                CLEAR_RANGE => {
+                    let (start, n) = (
+                        ptr.wrapping_add_signed(offset(word)),
+                        operand(word) as usize,
+                    );
+                    if start < tape.len() && tape.len() - start >= n {
+                        tape[start..start + n].fill(C::default());
+                    } else {
+                        for i in 0..n {
+                            let idx = at!(offset(word) + i as isize);
+                            tape[idx] = C::default();
+                        }
+                    }
+                }
+                CLEAR_UNTIL_ZERO => loop {
+                    let idx = at!(0);
+                    if tape[idx].is_zero() {
+                        break;
+                    }
+                    tape[idx] = C::default();
+                    ptr = A::move_ptr(ptr, wide(word), tape.len());
+                },
+                DIV_MOD => crate::div_mod(tape, ptr),
+                // `END`, the only opcode left.
+                _ => pc = words.len() - 1,
+            }
+            pc += 1;
+        }
+        self.pc = pc;
+        self.ptr = ptr;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::tests::{OFF_TAPE, ON_TAPE, assert_conforms};
+
+    #[test]
+    fn test_packed() {
+        assert_conforms(Interpreter::run_packed, &ON_TAPE);
+        assert_conforms(Interpreter::run_packed, &OFF_TAPE);
+    }
+
+    #[test]
+    fn test_lower_to_bytecode() {
+        let ops = [
+            Op::PtrAdd(-3),
+            Op::ValSub(-5, 2),
+            Op::Set(1 << 20, u32::MAX),
+            Op::Scan(isize::MIN >> 8),
+        ];
+        let program = lower_to_bytecode(&ops).unwrap();
+        assert_eq!(wide(program.words[0]), -3);
+        assert_eq!(offset(program.words[1]), -5);
+        assert_eq!(operand(program.words[1]), 2u32.wrapping_neg());
+        assert_eq!(
+            (offset(program.words[2]), operand(program.words[2])),
+            (1 << 20, u32::MAX)
+        );
+        assert_eq!(wide(program.words[3]), isize::MIN >> 8);
+        assert_eq!(lower_to_bytecode(&[Op::Clear(1 << 23)]), None);
+        assert_eq!(lower_to_bytecode(&[Op::PtrAdd(isize::MAX)]), None);
+        assert_eq!(lower_to_bytecode(&[Op::Fork]), None);
+    }
+}
@@ -0,0 +1,352 @@
+//! The threaded-code engine behind [`Interpreter::run_threaded_code`]:
+//! each op is lowered once to a closure that does its work and returns the
+//! next op's index, so running the program is a loop of indirect calls
+//! instead of a `match` on every op.
+
+use std::io::{self, Read, Write};
+
+use super::{
+    Addressing, BidirectionalTape, CheckedTape, ClampTape, GrowTape, Input, Interpreter, Output,
+    RuntimeError, UncheckedTape, WrapTape, read_cell,
+};
+use crate::cell::Cell;
+use crate::{BoundsPolicy, Op};
+
+/// What the lowered ops run on.
+struct Machine<'a, R, W, C> {
+    tape: &'a mut Vec<C>,
+    ptr: usize,
+    input: &'a mut Input<R>,
+    output: &'a mut Output<W>,
+    eof: Option<C>,
+}
+
+/// Why an op stopped the run.
+enum Stop {
+    OutOfBounds,
+    Io(io::Error),
+}
+
+impl From<io::Error> for Stop {
+    fn from(e: io::Error) -> Self {
+        Stop::Io(e)
+    }
+}
+
+/// A lowered op: runs it and returns the index of the next one.
+type Thunk<'a, R, W, C> = Box<dyn Fn(&mut Machine<'_, R, W, C>) -> Result<usize, Stop> + 'a>;
+
+impl<R: Read, W: Write, C: Cell> Interpreter<R, W, C> {
+    /// Runs the program until it ends like [`run`](Self::run), but as
+    /// threaded code: the ops are first lowered to a closure each. Programs
+    /// with a step or time limit, profiling, [`Op::Debug`], [`Op::Random`]
+    /// or the ops of pbrain, Brainfork, Extended Type I or multitape are
+    /// interpreted.
+    ///
+    /// # Panics
+    ///
+    /// If the program touches a cell outside the tape under
+    /// [`BoundsPolicy::Unchecked`].
+    pub fn run_threaded_code(&mut self) -> Result<(), RuntimeError> {
+        if self.limits.any()
+            || self.timeout.is_some()
+            || self.profile.is_some()
+            || self.unicode
+            || self.ops.contains(&Op::Debug)
+            || crate::uses_procedures(&self.ops)
+            || crate::uses_forks(&self.ops)
+            || crate::uses_extended1(&self.ops)
+            || crate::uses_tapes(&self.ops)
+            || crate::uses_random(&self.ops)
+        {
+            return self.run();
+        }
+        match self.bounds {
+            BoundsPolicy::Error => self.run_lowered::<CheckedTape>(),
+            BoundsPolicy::Clamp => self.run_lowered::<ClampTape>(),
+            BoundsPolicy::Wrap => self.run_lowered::<WrapTape>(),
+            BoundsPolicy::Grow => self.run_lowered::<GrowTape>(),
+            BoundsPolicy::Bidirectional => self.run_lowered::<BidirectionalTape>(),
+            BoundsPolicy::Unchecked => self.run_lowered::<UncheckedTape>(),
+        }
+    }
+
+    fn run_lowered<A: Addressing>(&mut self) -> Result<(), RuntimeError> {
+        let code: Vec<Thunk<'_, R, W, C>> = self
+            .ops
+            .iter()
+            .enumerate()
+            .map(|(pc, op)| lower::<A, R, W, C>(pc, op, self.ops.len()))
+            .collect();
+        let mut machine = Machine {
+            tape: &mut self.tape,
+            ptr: self.ptr,
+            input: &mut self.input,
+            output: &mut self.output,
+            eof: self.eof,
+        };
+        let mut pc = self.pc;
+        let result = loop {
+            let Some(op) = code.get(pc) else {
+                break Ok(());
+            };
+            match op(&mut machine) {
+                Ok(next) => pc = next,
+                Err(stop) => break Err(stop),
+            }
+        };
+        self.pc = pc;
+        self.ptr = machine.ptr;
+        self.output.flush()?;
+        match result {
+            Ok(()) => Ok(()),
+            Err(Stop::OutOfBounds) => Err(RuntimeError::PointerOutOfBounds { pc, ptr: self.ptr }),
+            // A failed `,` stops before itself, like the interpreter.
+            Err(Stop::Io(e)) => Err(RuntimeError::Io(e)),
+        }
+    }
+}
+
+/// The tape index `offset` cells from the pointer.
+#[inline(always)]
+fn at<A: Addressing, C: Cell>(tape: &mut Vec<C>, ptr: usize, offset: isize) -> Result<usize, Stop> {
+    A::index(tape, ptr, offset).ok_or(Stop::OutOfBounds)
+}
+
+/// Lowers the op at `pc` of a program of `len` ops.
+fn lower<'a, A: Addressing, R: Read + 'a, W: Write + 'a, C: Cell + 'a>(
+    pc: usize,
+    op: &Op,
+    len: usize,
+) -> Thunk<'a, R, W, C> {
+    let next = pc + 1;
+    match *op {
+        Op::PtrAdd(n) => Box::new(move |m| {
+            m.ptr = A::move_ptr(m.ptr, n, m.tape.len());
+            Ok(next)
+        }),
+        Op::ValAdd(offset, n) => {
+            let n = C::from_u32(n);
+            Box::new(move |m| {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.tape[idx] = m.tape[idx].wrapping_add(n);
+                Ok(next)
+            })
+        }
+        Op::ValSub(offset, n) => {
+            let n = C::from_u32(n);
+            Box::new(move |m| {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.tape[idx] = m.tape[idx].wrapping_sub(n);
+                Ok(next)
+            })
+        }
+        Op::Output | Op::OutputAt(_) => {
+            let offset = match *op {
+                Op::OutputAt(offset) => offset,
+                _ => 0,
+            };
+            Box::new(move |m| {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.output.write_all(&[m.tape[idx].to_byte()])?;
+                Ok(next)
+            })
+        }
+        Op::OutputRepeat(offset, count) => Box::new(move |m| {
+            let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+            m.output.write_all(&vec![m.tape[idx].to_byte(); count])?;
+            Ok(next)
+        }),
+        Op::OutputConst(ref bytes) => {
+            let bytes = bytes.clone();
+            Box::new(move |m| {
+                m.output.write_all(&bytes)?;
+                Ok(next)
+            })
+        }
+        Op::Input | Op::InputAt(_) => {
+            let offset = match *op {
+                Op::InputAt(offset) => offset,
+                _ => 0,
+            };
+            Box::new(move |m| {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.output.flush_for_input()?;
+                if let Some(v) = read_cell(m.input, m.eof)? {
+                    m.tape[idx] = v;
+                }
+                Ok(next)
+            })
+        }
+        // Both ends of a loop jump just past the other one.
+        Op::Jz(target) => Box::new(move |m| {
+            let idx = at::<A, C>(m.tape, m.ptr, 0)?;
+            Ok(if m.tape[idx].is_zero() {
+                target + 1
+            } else {
+                next
+            })
+        }),
+        Op::Jnz(target) => Box::new(move |m| {
+            let idx = at::<A, C>(m.tape, m.ptr, 0)?;
+            Ok(if m.tape[idx].is_zero() {
+                next
+            } else {
+                target + 1
+            })
+        }),
+        Op::Clear(offset) => Box::new(move |m| {
+            let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+            m.tape[idx] = C::default();
+            Ok(next)
+        }),
+        Op::Set(offset, n) => {
+            let n = C::from_u32(n);
+            Box::new(move |m| {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.tape[idx] = n;
+                Ok(next)
+            })
+        }
+        Op::MulAdd(offset, factor) => {
+            let factor = C::from_u32(factor);
+            Box::new(move |m| {
+                let src = at::<A, C>(m.tape, m.ptr, 0)?;
+                let value = m.tape[src];
+                if !value.is_zero() {
+                    let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                    m.tape[idx] = m.tape[idx].wrapping_add(value.wrapping_mul(factor));
+                }
+                Ok(next)
+            })
+        }
+        Op::AddTo(offset) => Box::new(move |m| {
+            let src = at::<A, C>(m.tape, m.ptr, 0)?;
+            let value = m.tape[src];
+            if !value.is_zero() {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.tape[idx] = m.tape[idx].wrapping_add(value);
+            }
+            Ok(next)
+        }),
+        Op::Transfer(ref targets) => {
+            let targets: Vec<(isize, C)> = targets
+                .iter()
+                .map(|&(offset, factor)| (offset, C::from_u32(factor)))
+                .collect();
+            Box::new(move |m| {
+                let src = at::<A, C>(m.tape, m.ptr, 0)?;
+                let value = m.tape[src];
+                if !value.is_zero() {
+                    for &(offset, factor) in &targets {
+                        let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                        m.tape[idx] = m.tape[idx].wrapping_add(value.wrapping_mul(factor));
+                    }
+                    m.tape[src] = C::default();
+                }
+                Ok(next)
+            })
+        }
+        Op::SubFrom(offset) => Box::new(move |m| {
+            let src = at::<A, C>(m.tape, m.ptr, 0)?;
+            let value = m.tape[src];
+            if !value.is_zero() {
+                let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                m.tape[idx] = m.tape[idx].wrapping_sub(value);
+            }
+            Ok(next)
+        }),
+        Op::ScanLeft => Box::new(move |m| {
+            m.ptr = A::scan_left(m.tape, m.ptr).ok_or(Stop::OutOfBounds)?;
+            Ok(next)
+        }),
+        Op::ScanRight => Box::new(move |m| {
+            m.ptr = A::scan_right(m.tape, m.ptr).ok_or(Stop::OutOfBounds)?;
+            Ok(next)
+        }),
+        // Moves as it goes, so a scan that leaves the tape fails where it
+        // left, as interpreted.
+        Op::Scan(stride) => Box::new(move |m| {
+            loop {
+                let idx = at::<A, C>(m.tape, m.ptr, 0)?;
+                if m.tape[idx].is_zero() {
+                    return Ok(next);
+                }
+                m.ptr = A::move_ptr(m.ptr, stride, m.tape.len());
+            }
+        }),
+        Op::BulkAdd(ref deltas) => {
+            let deltas: Vec<(isize, C)> = deltas
+                .iter()
+                .map(|&(offset, n)| (offset, C::from_u32(n)))
+                .collect();
+            Box::new(move |m| {
+                for &(offset, n) in &deltas {
+                    let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                    m.tape[idx] = m.tape[idx].wrapping_add(n);
+                }
+                Ok(next)
+            })
+        }
+        Op::BulkClear(ref offsets) => {
+            let offsets = offsets.clone();
+            Box::new(move |m| {
+                for &offset in &offsets {
+                    let idx = at::<A, C>(m.tape, m.ptr, offset)?;
+                    m.tape[idx] = C::default();
+                }
+                Ok(next)
+            })
+        }
+        Op::ClearRange(offset, n) => Box::new(move |m| {
+            let start = m.ptr.wrapping_add_signed(offset);
+            if start < m.tape.len() && m.tape.len() - start >= n {
+                m.tape[start..start + n].fill(C::default());
+            } else {
+                for i in 0..n {
+                    let idx = at::<A, C>(m.tape, m.ptr, offset + i as isize)?;
+                    m.tape[idx] = C::default();
+                }
+            }
+            Ok(next)
+        }),
+        Op::ClearUntilZero(stride) => Box::new(move |m| {
+            loop {
+                let idx = at::<A, C>(m.tape, m.ptr, 0)?;
+                if m.tape[idx].is_zero() {
+                    return Ok(next);
+                }
+                m.tape[idx] = C::default();
+                m.ptr = A::move_ptr(m.ptr, stride, m.tape.len());
+            }
+        }),
+        Op::DivMod => Box::new(move |m| {
+            crate::div_mod(m.tape, m.ptr);
+            Ok(next)
+        }),
+        Op::End => Box::new(move |_| Ok(len)),
+        // Interpreted instead; see `run_threaded_code`.
+        Op::Debug
+        | Op::DefProc(_)
+        | Op::EndProc
+        | Op::CallProc
+        | Op::Fork
+        | Op::Store
+        | Op::Retrieve
+        | Op::Bitwise(_)
+        | Op::SwitchTape(_)
+        | Op::Random => Box::new(move |_| Ok(next)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Interpreter;
+    use crate::interpreter::tests::{OFF_TAPE, ON_TAPE, assert_conforms};
+
+    #[test]
+    fn test_threaded_code() {
+        assert_conforms(Interpreter::run_threaded_code, &ON_TAPE);
+        assert_conforms(Interpreter::run_threaded_code, &OFF_TAPE);
+    }
+}
@@ -0,0 +1,325 @@
+//! The unchecked engine behind [`Interpreter::run_unchecked`]: the
+//! interpreter's loop without the bounds checks, for trusted programs.
+
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+
+use super::{Interpreter, RuntimeError, read_cell};
+use crate::cell::Cell;
+use crate::{BoundsPolicy, Op};
+
+impl<R: Read, W: Write, C: Cell> Interpreter<R, W, C> {
+    /// Runs the program until it ends like [`run`](Self::run), but without
+    /// checking that the cells it touches are on the tape.
+    ///
+    /// The jumps are checked with [`verify`](crate::verify) first, and a
+    /// program whose pointer can be followed from its start, through loops
+    /// that end each pass where they started and no scans, has the cells it
+    /// touches worked out up front: if one is off the tape, the program is
+    /// run checked instead, and fails like it would with `run`. So are
+    /// programs with a step or time limit, profiling, [`Op::Debug`],
+    /// [`Op::Random`] or the ops of pbrain, Brainfork, Extended Type I or
+    /// multitape, and any run
+    /// under [`BoundsPolicy::Clamp`] or [`BoundsPolicy::Wrap`], which keep
+    /// the pointer on the tape however it moves, or
+    /// [`BoundsPolicy::Bidirectional`], which keeps the cells left of cell
+    /// 0 at the other end.
+    ///
+    /// # Safety
+    ///
+    /// Every cell the program touches, counting from where the pointer
+    /// starts and with scans stopping on a zero cell, must be on the tape as
+    /// it is: `run_unchecked` never grows it. Touching any other cell is
+    /// undefined behaviour. Programs whose cells are worked out up front
+    /// are always safe to run.
+    pub unsafe fn run_unchecked(&mut self) -> Result<(), RuntimeError> {
+        if self.runs_checked() {
+            return self.run();
+        }
+        // The cells are only known from the start of the program.
+        if self.pc == 0
+            && let Some(reach) = tape_reach(&self.ops)
+            && (self.ptr.checked_add_signed(*reach.start()).is_none()
+                || self
+                    .ptr
+                    .checked_add_signed(*reach.end())
+                    .is_none_or(|high| high >= self.tape.len()))
+        {
+            return self.run();
+        }
+        // SAFETY: the caller promises every cell touched is on the tape, and
+        // `verify` has checked that every jump lands on an op.
+        unsafe { self.run_trusted() }
+    }
+
+    /// Whether [`run_unchecked`](Self::run_unchecked) is safe to call
+    /// whatever the program does: it would be run checked, or the cells it
+    /// touches can be worked out up front.
+    pub fn unchecked_is_safe(&self) -> bool {
+        self.runs_checked() || (self.pc == 0 && tape_reach(&self.ops).is_some())
+    }
+
+    /// Whether [`run_unchecked`](Self::run_unchecked) runs the program
+    /// checked, whatever cells it touches.
+    fn runs_checked(&self) -> bool {
+        self.limits.any()
+            || self.timeout.is_some()
+            || self.profile.is_some()
+            || self.unicode
+            || matches!(
+                self.bounds,
+                BoundsPolicy::Clamp | BoundsPolicy::Wrap | BoundsPolicy::Bidirectional
+            )
+            || self.ops.contains(&Op::Debug)
+            || crate::uses_procedures(&self.ops)
+            || crate::uses_forks(&self.ops)
+            || crate::uses_extended1(&self.ops)
+            || crate::uses_tapes(&self.ops)
+            || crate::uses_random(&self.ops)
+            || crate::verify(&self.ops).is_err()
+    }
+
+    /// # Safety
+    ///
+    /// As [`run_unchecked`](Self::run_unchecked), for programs with only
+    /// the ops of standard brainfuck and verified jumps.
+    unsafe fn run_trusted(&mut self) -> Result<(), RuntimeError> {
+        let ops = &self.ops;
+        let tape = &mut self.tape;
+        let mut pc = self.pc;
+        let mut ptr = self.ptr;
+
+        // The cell `offset` cells from the pointer.
+        macro_rules! cell {
+            ($offset:expr) => {
+                // SAFETY: the caller promises it is on the tape.
+                *unsafe { tape.get_unchecked_mut(ptr.wrapping_add_signed($offset)) }
+            };
+        }
+
+        while pc < ops.len() {
+            // SAFETY: checked just above; jumps land on ops, so `pc` only
+            // passes the end by one.
+            match unsafe { ops.get_unchecked(pc) } {
+                Op::PtrAdd(n) => ptr = ptr.wrapping_add_signed(*n),
+                Op::ValAdd(offset, n) => {
+                    cell!(*offset) = cell!(*offset).wrapping_add(C::from_u32(*n));
+                }
+                Op::ValSub(offset, n) => {
+                    cell!(*offset) = cell!(*offset).wrapping_sub(C::from_u32(*n));
+                }
+                Op::Output => self.output.write_all(&[cell!(0).to_byte()])?,
+                Op::OutputAt(offset) => self.output.write_all(&[cell!(*offset).to_byte()])?,
+                Op::OutputConst(bytes) => self.output.write_all(bytes)?,
+                Op::OutputRepeat(offset, count) => {
+                    self.output
+                        .write_all(&vec![cell!(*offset).to_byte(); *count])?
+                }
+                Op::Input | Op::InputAt(_) => {
+                    let offset = match ops[pc] {
+                        Op::InputAt(offset) => offset,
+                        _ => 0,
+                    };
+                    match self
+                        .output
+                        .flush_for_input()
+                        .and_then(|()| read_cell(&mut self.input, self.eof))
+                    {
+                        Ok(Some(v)) => cell!(offset) = v,
+                        Ok(None) => {}
+                        Err(e) => {
+                            // Stop before this `,`, like the interpreter.
+                            self.pc = pc;
+                            self.ptr = ptr;
+                            return Err(RuntimeError::Io(e));
+                        }
+                    }
+                }
+                Op::Jz(target) => {
+                    if cell!(0).is_zero() {
+                        pc = *target;
+                    }
+                }
+                Op::Jnz(target) => {
+                    if !cell!(0).is_zero() {
+                        pc = *target;
+                    }
+                }
+                Op::Clear(offset) => cell!(*offset) = C::default(),
+                Op::Set(offset, n) => cell!(*offset) = C::from_u32(*n),
+                Op::MulAdd(offset, factor) => {
+                    let value = cell!(0);
+                    if !value.is_zero() {
+                        cell!(*offset) =
+                            cell!(*offset).wrapping_add(value.wrapping_mul(C::from_u32(*factor)));
+                    }
+                }
+                Op::AddTo(offset) => {
+                    let value = cell!(0);
+                    if !value.is_zero() {
+                        cell!(*offset) = cell!(*offset).wrapping_add(value);
+                    }
+                }
+                Op::SubFrom(offset) => {
+                    let value = cell!(0);
+                    if !value.is_zero() {
+                        cell!(*offset) = cell!(*offset).wrapping_sub(value);
+                    }
+                }
+                Op::Transfer(targets) => {
+                    let value = cell!(0);
+                    if !value.is_zero() {
+                        for &(offset, factor) in targets {
+                            cell!(offset) =
+                                cell!(offset).wrapping_add(value.wrapping_mul(C::from_u32(factor)));
+                        }
+                        cell!(0) = C::default();
+                    }
+                }
+                // SAFETY: the scans start on a cell the program touches,
+                // and find a zero before leaving the tape.
+                Op::ScanLeft => {
+                    let before = unsafe { tape.get_unchecked(..=ptr) };
+                    ptr = C::rfind_zero(before).unwrap_or(usize::MAX);
+                }
+                Op::ScanRight => {
+                    let after = unsafe { tape.get_unchecked(ptr..) };
+                    ptr = C::find_zero(after).map_or(tape.len(), |pos| ptr + pos);
+                }
+                Op::Scan(stride) => {
+                    while !cell!(0).is_zero() {
+                        ptr = ptr.wrapping_add_signed(*stride);
+                    }
+                }
+                Op::BulkAdd(deltas) => {
+                    for &(offset, n) in deltas {
+                        cell!(offset) = cell!(offset).wrapping_add(C::from_u32(n));
+                    }
+                }
+                Op::BulkClear(offsets) => {
+                    for &offset in offsets {
+                        cell!(offset) = C::default();
+                    }
+                }
+                Op::ClearRange(offset, n) => {
+                    let start = ptr.wrapping_add_signed(*offset);
+                    // SAFETY: the caller promises the cells are on the tape.
+                    unsafe { tape.get_unchecked_mut(start..start + n) }.fill(C::default());
+                }
+                Op::ClearUntilZero(stride) => {
+                    while !cell!(0).is_zero() {
+                        cell!(0) = C::default();
+                        ptr = ptr.wrapping_add_signed(*stride);
+                    }
+                }
+                // Checks its own cells.
+                Op::DivMod => crate::div_mod(tape, ptr),
+                Op::End => pc = ops.len() - 1,
+                // Run checked instead; see `run_unchecked`.
+                Op::Debug
+                | Op::DefProc(_)
+                | Op::EndProc
+                | Op::CallProc
+                | Op::Fork
+                | Op::Store
+                | Op::Retrieve
+                | Op::Bitwise(_)
+                | Op::SwitchTape(_)
+                | Op::Random => {}
+            }
+            pc += 1;
+        }
+        self.pc = pc;
+        self.ptr = ptr;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// The cells `ops` touch, relative to the one the pointer starts on, if
+/// the pointer can be followed: no scans, and every loop ends each pass on
+/// the cell it started on.
+pub(crate) fn tape_reach(ops: &[Op]) -> Option<RangeInclusive<isize>> {
+    let mut ptr = 0isize;
+    let (mut low, mut high) = (0, 0);
+    let mut touch = |cell: isize| {
+        low = cell.min(low);
+        high = cell.max(high);
+    };
+    let mut open = Vec::new();
+    for op in ops {
+        match op {
+            Op::PtrAdd(n) => ptr = ptr.checked_add(*n)?,
+            Op::ValAdd(offset, _)
+            | Op::ValSub(offset, _)
+            | Op::OutputAt(offset)
+            | Op::OutputRepeat(offset, _)
+            | Op::InputAt(offset)
+            | Op::Clear(offset)
+            | Op::Set(offset, _) => touch(ptr.checked_add(*offset)?),
+            Op::Output | Op::Input => touch(ptr),
+            Op::MulAdd(offset, _) | Op::AddTo(offset) | Op::SubFrom(offset) => {
+                touch(ptr);
+                touch(ptr.checked_add(*offset)?);
+            }
+            Op::BulkAdd(deltas) => {
+                for &(offset, _) in deltas {
+                    touch(ptr.checked_add(offset)?);
+                }
+            }
+            Op::Transfer(targets) => {
+                touch(ptr);
+                for &(offset, _) in targets {
+                    touch(ptr.checked_add(offset)?);
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for &offset in offsets {
+                    touch(ptr.checked_add(offset)?);
+                }
+            }
+            Op::ClearRange(offset, n) if *n > 0 => {
+                let start = ptr.checked_add(*offset)?;
+                touch(start);
+                touch(start.checked_add_unsigned(n - 1)?);
+            }
+            Op::ClearRange(..) => {}
+            Op::Jz(_) => {
+                touch(ptr);
+                open.push(ptr);
+            }
+            Op::Jnz(_) => {
+                if open.pop() != Some(ptr) {
+                    return None;
+                }
+                touch(ptr);
+            }
+            // Only touches cells the loop after it does.
+            Op::OutputConst(_) | Op::End | Op::DivMod => {}
+            _ => return None,
+        }
+    }
+    Some(low..=high)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::tests::{ON_TAPE, assert_conforms};
+    use crate::{optimize, parse};
+
+    #[test]
+    fn test_unchecked() {
+        // SAFETY: the programs stay on the tape, or are known not to.
+        let unchecked = |interp: &mut _| unsafe { Interpreter::run_unchecked(interp) };
+        assert_conforms(unchecked, &ON_TAPE);
+        // Known to leave the tape, so run checked.
+        assert_conforms(unchecked, &[b"++[>+<-]<+"]);
+
+        let reach = |code: &[u8]| tape_reach(&optimize(parse(code).unwrap()));
+        assert_eq!(reach(b"<+>>>.[-<+>]"), Some(-1..=2));
+        assert_eq!(reach(b">>[-]<[-]<[-]<[-]"), Some(-1..=2));
+        assert_eq!(reach(b"+[>+]"), None);
+        assert_eq!(reach(b",[<->>]"), None);
+    }
+}
@@ -0,0 +1,312 @@
+//! A baseline JIT that lowers ops to x86-64 machine code.
+//!
+//! Each op becomes a short fixed instruction sequence; loops become
+//! conditional jumps and I/O calls back into Rust. Every cell access is
+//! checked against the tape length, so a program that walks off the tape
+//! stops with the index of the op that did it.
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+
+use crate::Op;
+use crate::cell::Cell;
+use crate::x86::{self, Runtime, STATUS_IO_ERROR, STATUS_OK, STATUS_OUT_OF_BOUNDS};
+
+/// Back-edges a loop takes under
+/// [`Interpreter::run_tiered`](crate::Interpreter::run_tiered) before it is
+/// compiled.
+pub const HOT_LOOP_THRESHOLD: u32 = 1000;
+
+/// State shared between generated code and the I/O callbacks. The code
+/// stores the final data pointer to `ptr` and the op that left the tape to
+/// `pc`, which must stay the first two fields.
+#[repr(C)]
+struct Context<'a, C> {
+    ptr: usize,
+    pc: u32,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    eof: Option<C>,
+    /// Whether to flush the output before reading input.
+    flush_before_input: bool,
+    error: Option<io::Error>,
+}
+
+impl<C> Context<'_, C> {
+    fn status(&mut self, result: io::Result<()>) -> u32 {
+        match result {
+            Ok(()) => STATUS_OK,
+            Err(e) => {
+                self.error = Some(e);
+                STATUS_IO_ERROR
+            }
+        }
+    }
+}
+
+extern "C" fn put_cell<C: Cell>(ctx: &mut Context<'_, C>, cell: &C) -> u32 {
+    let result = ctx.output.write_all(&[cell.to_byte()]);
+    ctx.status(result)
+}
+
+extern "C" fn put_bytes<C: Cell>(ctx: &mut Context<'_, C>, data: *const u8, len: usize) -> u32 {
+    // SAFETY: `data` and `len` come from a `Box<[u8]>` owned by the
+    // `JitProgram` running this code.
+    let bytes = unsafe { std::slice::from_raw_parts(data, len) };
+    let result = ctx.output.write_all(bytes);
+    ctx.status(result)
+}
+
+extern "C" fn put_repeat<C: Cell>(ctx: &mut Context<'_, C>, cell: &C, count: usize) -> u32 {
+    let result = ctx.output.write_all(&vec![cell.to_byte(); count]);
+    ctx.status(result)
+}
+
+extern "C" fn get_cell<C: Cell>(ctx: &mut Context<'_, C>, cell: &mut C) -> u32 {
+    if ctx.flush_before_input
+        && let Err(e) = ctx.output.flush()
+    {
+        return ctx.status(Err(e));
+    }
+    let mut byte = 0u8;
+    let result = match ctx.input.read_exact(std::slice::from_mut(&mut byte)) {
+        Ok(()) => {
+            *cell = C::from_byte(byte);
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            if let Some(v) = ctx.eof {
+                *cell = v;
+            }
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+    ctx.status(result)
+}
+
+/// Executable memory holding generated code.
+struct CodeBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl CodeBuffer {
+    fn new(code: &[u8]) -> io::Result<Self> {
+        let len = code.len().max(1);
+        // SAFETY: a fresh private anonymous mapping; it is only written
+        // before being made read-only, and unmapped on drop.
+        unsafe {
+            let ptr = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr.cast::<u8>(), code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                let err = io::Error::last_os_error();
+                libc::munmap(ptr, len);
+                return Err(err);
+            }
+            Ok(CodeBuffer {
+                ptr: NonNull::new_unchecked(ptr.cast::<u8>()),
+                len,
+            })
+        }
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe the mapping made in `new`.
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
+
+type EntryFn<C> = unsafe extern "C" fn(*mut C, usize, usize, *mut Context<'_, C>) -> u32;
+
+/// A program compiled to native code for cells of type `C`.
+pub struct JitProgram<C> {
+    code: CodeBuffer,
+    /// Bytes printed by `OutputConst`, which the code refers to by address.
+    _consts: Vec<Box<[u8]>>,
+    _cell: PhantomData<C>,
+}
+
+/// How a JIT run ended.
+#[derive(Debug)]
+pub enum JitError {
+    Io(io::Error),
+    /// The op at index `pc` of the compiled ops touched a cell outside the
+    /// tape while the data pointer was at `ptr`.
+    OutOfBounds {
+        pc: usize,
+        ptr: usize,
+    },
+}
+
+impl<C: Cell> JitProgram<C> {
+    /// Compiles `ops`, which must have balanced loops. Fails if an offset
+    /// doesn't fit the 32-bit displacements the code uses, or if executable
+    /// memory can't be mapped.
+    pub fn compile(ops: &[Op]) -> io::Result<Self> {
+        let runtime = Runtime {
+            put_cell: put_cell::<C> as *const () as u64,
+            put_bytes: put_bytes::<C> as *const () as u64,
+            put_repeat: put_repeat::<C> as *const () as u64,
+            get_cell: get_cell::<C> as *const () as u64,
+        };
+        let mut consts = Vec::new();
+        let code = x86::compile(ops, C::WIDTH, &runtime, &mut |bytes| {
+            let bytes: Box<[u8]> = bytes.into();
+            let addr = bytes.as_ptr() as u64;
+            consts.push(bytes);
+            addr
+        })?;
+
+        Ok(JitProgram {
+            code: CodeBuffer::new(&code)?,
+            _consts: consts,
+            _cell: PhantomData,
+        })
+    }
+
+    /// Runs the program on `tape` starting at cell `ptr`, and returns where
+    /// the data pointer ended up. Output is only flushed before reading
+    /// input, with `flush_before_input`; the caller flushes at the end.
+    pub fn run(
+        &self,
+        tape: &mut [C],
+        ptr: usize,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        eof: Option<C>,
+        flush_before_input: bool,
+    ) -> Result<usize, JitError> {
+        let mut ctx = Context {
+            ptr,
+            pc: 0,
+            input,
+            output,
+            eof,
+            flush_before_input,
+            error: None,
+        };
+        // SAFETY: the buffer holds code emitted by `compile` with the
+        // `EntryFn` signature; it only touches `tape` within its length and
+        // `ctx` through the callbacks above.
+        let status = unsafe {
+            let entry: EntryFn<C> = std::mem::transmute(self.code.ptr.as_ptr());
+            entry(tape.as_mut_ptr(), ptr, tape.len(), &mut ctx)
+        };
+        match status {
+            STATUS_OK => Ok(ctx.ptr),
+            STATUS_OUT_OF_BOUNDS => Err(JitError::OutOfBounds {
+                pc: ctx.pc as usize,
+                ptr: ctx.ptr,
+            }),
+            _ => Err(JitError::Io(
+                ctx.error
+                    .take()
+                    .unwrap_or_else(|| io::Error::other("JIT I/O failed")),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::{BoundsPolicy, Config, Interpreter, RuntimeError, optimize, parse};
+
+    fn run_jit(code: &[u8], input: &[u8]) -> Vec<u8> {
+        let ops = optimize(parse(code).unwrap());
+        let mut interp = Interpreter::new(ops, input, Vec::new());
+        interp.run_jit().unwrap();
+        assert!(interp.is_finished());
+        interp.into_inner().1
+    }
+
+    #[test]
+    fn test_jit_hello() {
+        let code = std::fs::read("examples/helloworld.bf").unwrap();
+        assert_eq!(run_jit(&code, b""), b"Hello, World!");
+    }
+
+    #[test]
+    fn test_jit_input_and_scan() {
+        // Read until a zero byte, then scan back to the start.
+        assert_eq!(run_jit(b">,[>,]<[<]>[.>]", b"jit\0"), b"jit");
+        // Repeated output is one call.
+        assert_eq!(run_jit(b",...>,..<.", b"ab"), b"aaabba");
+    }
+
+    #[test]
+    fn test_jit_matches_interpreter_tape() {
+        let code = b"++++[>+++[>++<-]<-]>>[->+>+<<]>[-<+>]<<<<";
+        let ops = optimize(parse(code).unwrap());
+        let config = Config {
+            tape_size: 16,
+            ..Config::default()
+        };
+
+        let mut interp =
+            Interpreter::<_, _, u16>::with_config(ops.clone(), io::empty(), io::sink(), &config);
+        interp.run().unwrap();
+        let mut jitted =
+            Interpreter::<_, _, u16>::with_config(ops, io::empty(), io::sink(), &config);
+        jitted.run_jit().unwrap();
+        assert_eq!(jitted.tape(), interp.tape());
+        assert_eq!(jitted.ptr(), interp.ptr());
+    }
+
+    #[test]
+    fn test_jit_mul_add_skips_zero_source() {
+        // The move loop's target is off the tape, but it never runs.
+        assert_eq!(run_jit(b",[-<+>]+.", b""), [1]);
+
+        // With a non-zero source, the target left of cell 0 is checked. The
+        // `,` hides the source's value from the optimizer.
+        let ops = optimize(parse(b"+,[-<+>]").unwrap());
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        assert!(matches!(
+            interp.run_jit(),
+            Err(RuntimeError::PointerOutOfBounds { pc: 2, ptr: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_jit_out_of_bounds() {
+        let ops = optimize(parse(b">+.<<+").unwrap());
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        assert!(matches!(
+            interp.run_jit(),
+            Err(RuntimeError::PointerOutOfBounds { pc: 2, ptr: 0 })
+        ));
+        assert_eq!((interp.pc(), interp.ptr()), (2, 0));
+        assert_eq!(interp.tape()[1], 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the 30000-cell tape")]
+    fn test_jit_unchecked_out_of_bounds() {
+        let ops = optimize(parse(b"<+").unwrap());
+        let config = Config {
+            bounds: BoundsPolicy::Unchecked,
+            ..Config::default()
+        };
+        let mut interp =
+            Interpreter::<_, _, u8>::with_config(ops, io::empty(), io::sink(), &config);
+        let _ = interp.run_jit();
+    }
+}
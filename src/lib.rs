@@ -1,656 +1,1846 @@
-use std::collections::HashMap;
-
+//! A brainfuck toolchain: a parser and optimizer, an interpreter with a
+//! JIT, code generators and the command-line tools built on them.
+//!
+//! Everything but [`parse`], the [`optimizer`] and the small [`exec`]
+//! executor needs the `std` feature, which is on by default. Without it the
+//! crate is `no_std` and only needs `alloc`. The [`cli`] module, which the
+//! binaries are built on, needs the `cli` feature, also on by default.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+#[cfg(feature = "std")]
+pub mod analysis;
+pub mod asm;
+#[cfg(feature = "async")]
+pub mod async_io;
+#[cfg(feature = "image")]
+pub mod brainloller;
+#[cfg(feature = "std")]
+pub mod bytecode;
+pub mod cell;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "std")]
+pub mod codegen;
+#[cfg(feature = "std")]
+pub mod debugger;
+pub mod equiv;
+#[cfg(feature = "std")]
+pub mod eval;
+pub mod exec;
+#[cfg(feature = "std")]
+pub mod ffi;
+pub mod format;
+#[cfg(feature = "std")]
+pub mod hooks;
+#[cfg(feature = "std")]
+pub mod interpreter;
+#[cfg(all(feature = "std", unix, target_arch = "x86_64"))]
+pub mod jit;
+pub mod lint;
+pub mod obfuscate;
+pub mod optimizer;
+#[cfg(feature = "std")]
+pub mod playground;
+pub mod pragma;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod reference;
+pub mod testgen;
+pub mod textgen;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "std")]
+mod x86;
+
+#[cfg(feature = "std")]
+pub use analysis::{Analysis, Verdict, analyze};
+pub use asm::{AsmError, AsmErrorKind, assemble};
+#[cfg(feature = "std")]
+pub use bytecode::{BytecodeError, load_bytecode, save_bytecode};
+pub use cell::{Cell, CellWidth};
+#[cfg(feature = "std")]
+pub use codegen::{
+    Backend, CodegenOptions, CompileError, SourceMap, compile_to_rust, emit_c, emit_elf,
+    emit_llvm_ir, emit_ops, emit_program, emit_rust, emit_rust_mapped, to_brainfuck,
+};
+pub use equiv::canonicalize;
+#[cfg(feature = "std")]
+pub use equiv::{Equivalence, equivalent};
+#[cfg(feature = "std")]
+pub use eval::partial_eval;
+pub use exec::{BoundsPolicy, EofBehavior, OutOfBounds};
+#[cfg(feature = "std")]
+pub use hooks::Hooks;
+#[cfg(feature = "std")]
+pub use interpreter::{
+    BfIo, BuiltinExecutor, Config, Error, Executor, FlushPolicy, Interpreter, LoopDepthExceeded,
+    Newline, OutputLimitExceeded, PackedProgram, Program, ResourceLimits, RuntimeError, State,
+    Stats, StepLimitExceeded, StepResult, TapeLimitExceeded, TimeLimitExceeded, UncheckedExecutor,
+    UndefinedProcedure, execute, lower_to_bytecode, run_program,
+};
+pub use lint::{Lint, LintKind, lint};
+pub use obfuscate::{ObfuscateOptions, obfuscate};
+pub use optimizer::{
+    BuiltinPass, OptLevel, Pass, PassStats, Pipeline, Semantics, VerifyError, optimize,
+    optimize_with, optimize_with_spans, verify,
+};
+pub use pragma::{PragmaError, Pragmas, pragmas};
+#[cfg(feature = "std")]
+pub use reference::execute_reference;
+#[cfg(feature = "std")]
+pub use testgen::RandomInput;
+pub use testgen::{GenOptions, Generator};
+pub use textgen::text_to_brainfuck;
+
+#[cfg(feature = "macros")]
+pub use brainfuck_rs_macros::bf;
+
+/// An instruction of the optimized program.
+///
+/// Cell operands are `u32` and wrap modulo 2^32; executing them on a
+/// narrower [`Cell`] keeps only the low bits, so one op stream serves every
+/// cell width.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Op {
     PtrAdd(isize),
-    ValAdd(isize, u8),
-    ValSub(isize, u8),
+    ValAdd(isize, u32),
+    ValSub(isize, u32),
     Output,
     Input,
+    /// `.` on the cell `offset` away from the pointer.
+    OutputAt(isize),
+    /// `,` into the cell `offset` away from the pointer.
+    InputAt(isize),
+    /// Writes these bytes, whatever is on the tape.
+    OutputConst(Vec<u8>),
+    /// `.` `count` times in a row on the cell `offset` away from the
+    /// pointer, as one write: what `....` becomes.
+    OutputRepeat(isize, usize),
     Jz(usize),
     Jnz(usize),
     Clear(isize),
-    MulAdd(isize, u8),
+    /// Stores a constant in the cell `offset` away from the pointer.
+    Set(isize, u32),
+    /// Adds the current cell times `factor` to the cell `offset` away,
+    /// which is left of the pointer when `offset` is negative. The target
+    /// is only touched, and bounds-checked, when the current cell is
+    /// non-zero.
+    MulAdd(isize, u32),
+    /// A `MulAdd` with a factor of 1, what `[->+<]` becomes: adds the
+    /// current cell to the one `offset` away, with no multiply.
+    AddTo(isize),
+    /// A `MulAdd` with a factor of -1, what `[->-<]` becomes: subtracts
+    /// the current cell from the one `offset` away.
+    SubFrom(isize),
+    /// A whole move loop with several targets, like `[->+>++<<]`: adds the
+    /// current cell times each `factor` to the cell `offset` away, then
+    /// clears it. The targets are only touched when the current cell is
+    /// non-zero, and are reached in order.
+    Transfer(Vec<(isize, u32)>),
     ScanLeft,
     ScanRight,
-    BulkAdd(Vec<(isize, u8)>),
+    /// `[>>]`, `[<<<]` and the like: moves `stride` cells at a time until
+    /// the pointer is on a zero cell. `[<]` and `[>]` are `ScanLeft` and
+    /// `ScanRight` instead.
+    Scan(isize),
+    BulkAdd(Vec<(isize, u32)>),
     BulkClear(Vec<isize>),
+    /// Clears `len` cells rightwards from the one `offset` away from the
+    /// pointer, as one fill: what `[-]>[-]>[-]>[-]>` and the like become.
+    ClearRange(isize, usize),
+    /// `[[-]>]`, `[[-]<<]` and the like: clears the current cell and moves
+    /// `stride` cells, until the pointer is on a zero cell.
+    ClearUntilZero(isize),
+    /// `#`: prints the op's index, the pointer and the cells around it.
+    /// The interpreter writes them to its
+    /// [`set_debug_output`](Interpreter::set_debug_output), and compiled
+    /// code to stderr. Only parsed with [`ParseOptions::debug_char`].
+    Debug,
+    /// pbrain's `(`: makes the ops up to the `EndProc` at `end` the
+    /// procedure numbered by the current cell, and jumps past them.
+    DefProc(usize),
+    /// pbrain's `)`: returns from the procedure to the op after its call.
+    EndProc,
+    /// pbrain's `:`: calls the procedure numbered by the current cell.
+    CallProc,
+    /// Brainfork's `Y`: starts a thread with a copy of the tape, whose
+    /// pointer is one cell right, where it sets the cell to 1 and carries
+    /// on after the fork. The forking thread clears its current cell.
+    Fork,
+    /// Extended Type I's `@`: ends the program.
+    End,
+    /// Extended Type I's `$`: copies the current cell to the storage
+    /// register.
+    Store,
+    /// Extended Type I's `!`: copies the storage register to the current
+    /// cell.
+    Retrieve,
+    /// One of Extended Type I's bitwise commands on the current cell.
+    Bitwise(BitOp),
+    /// The multitape dialect's `v` and `^`: moves to the tape `n` below the
+    /// current one, or above it when `n` is negative. Each tape keeps its
+    /// own pointer.
+    SwitchTape(isize),
+    /// Goes just before the divmod idiom's loop,
+    /// `[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]`, and does what it would in one
+    /// step: from the current cell, `n 0 d 0 0 0 0` becomes
+    /// `0 n d-n%d n%d n/d 0 0`. Where the cells aren't laid out for that,
+    /// or aren't all on the tape, it does nothing and the loop runs.
+    DivMod,
+    /// `?`: stores a random byte in the current cell, drawn from
+    /// [`Config::seed`]. Only parsed with [`ParseOptions::random`].
+    Random,
 }
 
-pub fn parse(code: Vec<u8>) -> Vec<Op> {
-    let mut ops = Vec::new();
-    let mut loop_stack = Vec::new();
-    let mut i = 0;
-    let len = code.len();
-    let mut current_offset: isize = 0;
-
-    while i < len {
-        let b = code[i];
-
-        // Sequence points flush the pointer update
-        let is_sequence_point = matches!(b, b'.' | b',' | b'[' | b']');
-
-        // Handle clear loop [-] or [+] specially
-        if b == b'['
-            && i + 2 < len
-            && code[i + 2] == b']'
-            && (code[i + 1] == b'-' || code[i + 1] == b'+')
-        {
-            if current_offset != 0 {
-                ops.push(Op::PtrAdd(current_offset));
-                current_offset = 0;
-            }
-            // Clear applies to current pointer, which is implicitly offset 0 after flush
-            ops.push(Op::Clear(0));
-            i += 3;
-            continue;
-        }
-
-        if is_sequence_point && current_offset != 0 {
-            ops.push(Op::PtrAdd(current_offset));
-            current_offset = 0;
+impl Op {
+    /// The offset and factor of a `MulAdd`, or of an `AddTo` or `SubFrom`
+    /// as the `MulAdd` it stands for.
+    pub(crate) fn mul_add(&self) -> Option<(isize, u32)> {
+        match *self {
+            Op::MulAdd(offset, factor) => Some((offset, factor)),
+            Op::AddTo(offset) => Some((offset, 1)),
+            Op::SubFrom(offset) => Some((offset, u32::MAX)),
+            _ => None,
         }
+    }
+}
 
-        match b {
-            b'>' => {
-                let mut count = 1;
-                while i + count < len && code[i + count] == b'>' {
-                    count += 1;
-                }
-                current_offset += count as isize;
-                i += count;
-            }
-            b'<' => {
-                let mut count = 1;
-                while i + count < len && code[i + count] == b'<' {
-                    count += 1;
-                }
-                current_offset -= count as isize;
-                i += count;
-            }
-            b'+' => {
-                let mut count = 1;
-                while i + count < len && code[i + count] == b'+' {
-                    count += 1;
-                }
-                let val = (count % 256) as u8;
-                if let Some(Op::ValAdd(off, prev_val)) = ops.last_mut() {
-                    if *off == current_offset {
-                        *prev_val = prev_val.wrapping_add(val);
-                    } else {
-                        ops.push(Op::ValAdd(current_offset, val));
-                    }
-                } else if let Some(Op::ValSub(off, prev_val)) = ops.last_mut() {
-                    if *off == current_offset {
-                        if *prev_val > val {
-                            *prev_val -= val;
-                        } else {
-                            let rem = val - *prev_val;
-                            ops.pop();
-                            if rem > 0 {
-                                ops.push(Op::ValAdd(current_offset, rem));
-                            }
-                        }
-                    } else {
-                        ops.push(Op::ValAdd(current_offset, val));
-                    }
-                } else {
-                    ops.push(Op::ValAdd(current_offset, val));
-                }
-                i += count;
-            }
-            b'-' => {
-                let mut count = 1;
-                while i + count < len && code[i + count] == b'-' {
-                    count += 1;
-                }
-                let val = (count % 256) as u8;
-                if let Some(Op::ValSub(off, prev_val)) = ops.last_mut() {
-                    if *off == current_offset {
-                        *prev_val = prev_val.wrapping_add(val);
-                    } else {
-                        ops.push(Op::ValSub(current_offset, val));
-                    }
-                } else if let Some(Op::ValAdd(off, prev_val)) = ops.last_mut() {
-                    if *off == current_offset {
-                        if *prev_val > val {
-                            *prev_val -= val;
-                        } else {
-                            let rem = val - *prev_val;
-                            ops.pop();
-                            if rem > 0 {
-                                ops.push(Op::ValSub(current_offset, rem));
-                            }
-                        }
-                    } else {
-                        ops.push(Op::ValSub(current_offset, val));
-                    }
-                } else {
-                    ops.push(Op::ValSub(current_offset, val));
-                }
-                i += count;
-            }
-            b'.' => {
-                ops.push(Op::Output);
-                i += 1;
-            }
-            b',' => {
-                ops.push(Op::Input);
-                i += 1;
-            }
-            b'[' => {
-                ops.push(Op::Jz(0));
-                loop_stack.push(ops.len() - 1);
-                i += 1;
-            }
-            b']' => {
-                let start = loop_stack.pop().expect("Unmatched '['");
-                let end = ops.len();
-                ops.push(Op::Jnz(start));
+/// A bitwise command of Extended Type I, which replaces the current cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BitOp {
+    /// `{`: shifts the cell one bit left, dropping its top bit.
+    ShiftLeft,
+    /// `}`: shifts the cell one bit right, filling in a zero.
+    ShiftRight,
+    /// `~`: flips every bit.
+    Not,
+    /// `^`: the cell xor the storage register.
+    Xor,
+    /// `&`: the cell and the storage register.
+    And,
+    /// `|`: the cell or the storage register.
+    Or,
+}
 
-                match &mut ops[start] {
-                    Op::Jz(target) => *target = end,
-                    _ => unreachable!(),
-                }
-                i += 1;
-            }
-            _ => {
-                i += 1;
-            }
+impl BitOp {
+    /// The command's result on a cell holding `cell` with `storage` in the
+    /// register, both zero-extended from the cell width. Truncate it back.
+    pub fn apply(self, cell: u32, storage: u32) -> u32 {
+        match self {
+            BitOp::ShiftLeft => cell << 1,
+            BitOp::ShiftRight => cell >> 1,
+            BitOp::Not => !cell,
+            BitOp::Xor => cell ^ storage,
+            BitOp::And => cell & storage,
+            BitOp::Or => cell | storage,
         }
     }
 
-    if !loop_stack.is_empty() {
-        panic!("Unmatched '['");
+    /// Whether the command reads the storage register.
+    pub fn reads_storage(self) -> bool {
+        matches!(self, BitOp::Xor | BitOp::And | BitOp::Or)
     }
 
-    if current_offset != 0 {
-        ops.push(Op::PtrAdd(current_offset));
+    /// The command's character.
+    #[cfg(feature = "std")]
+    pub(crate) fn command(self) -> char {
+        match self {
+            BitOp::ShiftLeft => '{',
+            BitOp::ShiftRight => '}',
+            BitOp::Not => '~',
+            BitOp::Xor => '^',
+            BitOp::And => '&',
+            BitOp::Or => '|',
+        }
     }
-
-    ops
 }
 
-pub fn optimize(ops: Vec<Op>) -> Vec<Op> {
-    let ops = optimize_loops(ops);
-
-    let ops = optimize_dce(ops);
-
-    optimize_bulk(ops)
+/// What [`Op::DivMod`] does to the seven cells from `ptr`: as many turns
+/// of its loop as the current cell counts, if every turn would end where it
+/// started and all seven cells are on `tape`.
+///
+/// Each turn moves one from the first cell to the second, and one from the
+/// third to the fourth while it lasts; when the third cell runs out, the
+/// fourth is moved back, plus one, and the fifth counts the round. So the
+/// third and fourth add up to the divisor throughout, which must be at
+/// least 2 and fit the cell, the third must not start at zero, and the
+/// last two cells, where the loop checks for zero, must be zero.
+pub(crate) fn div_mod<C: Cell>(tape: &mut [C], ptr: usize) {
+    let Some(cells) = ptr.checked_add(7).and_then(|end| tape.get_mut(ptr..end)) else {
+        return;
+    };
+    let value = |i: usize| u64::from(cells[i].to_u32());
+    let max = u64::from(C::from_u32(u32::MAX).to_u32());
+    let (turns, left, moved) = (value(0), value(2), value(3));
+    let divisor = left + moved;
+    if turns == 0
+        || left == 0
+        || !(2..=max).contains(&divisor)
+        || !cells[5].is_zero()
+        || !cells[6].is_zero()
+    {
+        return;
+    }
+    let cell = |v: u64| C::from_u32(v as u32);
+    if turns < left {
+        cells[2] = cell(left - turns);
+        cells[3] = cell(moved + turns);
+    } else {
+        let past = turns - left;
+        cells[2] = cell(divisor - past % divisor);
+        cells[3] = cell(past % divisor);
+        cells[4] = cells[4].wrapping_add(cell(1 + past / divisor));
+    }
+    cells[1] = cells[1].wrapping_add(cell(turns));
+    cells[0] = C::default();
 }
 
-fn optimize_bulk(ops: Vec<Op>) -> Vec<Op> {
-    let mut new_ops = Vec::new();
-
-    let mut pending_adds: HashMap<isize, u8> = HashMap::new();
-
-    let mut pending_clears: Vec<isize> = Vec::new();
+/// A brainfuck variant with commands of its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Just the eight commands.
+    #[default]
+    Standard,
+    /// pbrain: `(` and `)` define a procedure numbered by the current
+    /// cell, and `:` calls the one the current cell numbers.
+    Pbrain,
+    /// Brainfork: `Y` forks the run into two threads.
+    Brainfork,
+    /// Extended Brainfuck Type I: `@` ends the program, `$` and `!` store
+    /// the current cell in a register and retrieve it, and `{`, `}`, `~`,
+    /// `^`, `&` and `|` are bitwise commands.
+    Extended1,
+    /// Multitape: `v` and `^` move to the tape below or above the current
+    /// one.
+    Multitape,
+}
 
-    let mut loop_stack = Vec::new();
+/// Source syntax beyond the eight standard commands.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Parse `#` as [`Op::Debug`] instead of a comment.
+    pub debug_char: bool,
+    /// Parse `?` as [`Op::Random`] instead of a comment.
+    pub random: bool,
+    /// Spell the commands with these tokens instead of `+-<>.,[]`, for
+    /// substitutions such as Ook! or Blub.
+    pub tokens: Option<TokenMap>,
+    /// The commands beyond the standard eight.
+    pub dialect: Dialect,
+    /// How deeply loops and procedures may nest, or `None` for no limit.
+    /// A bracket that opens one level deeper is a [`ParseError::TooDeep`].
+    pub max_depth: Option<usize>,
+    /// The most bytes of source to parse, or `None` for no limit. A longer
+    /// source is a [`ParseError::TooLarge`] before anything is allocated
+    /// for it.
+    pub max_size: Option<usize>,
+    /// Under [`Semantics::Strict`], adds that cancel out, like `+-`, are
+    /// kept as an add of 0, so a run still touches the cell they are on.
+    pub semantics: Semantics,
+}
 
-    for op in ops {
-        match op {
-            Op::ValAdd(off, v) => {
-                if !pending_clears.is_empty() {
-                    new_ops.push(Op::BulkClear(pending_clears.clone()));
+/// Custom spellings of the commands. Where several tokens match at the
+/// same place the longest wins, so `Ook. Ook?` isn't read as `Ook.`; the
+/// bytes outside tokens are comments, standard commands included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenMap {
+    /// Longest token first.
+    tokens: Vec<(Vec<u8>, u8)>,
+}
 
-                    pending_clears.clear();
-                }
+/// A line of a token map that [`TokenMap::from_text`] couldn't read.
+/// `line` is 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenMapError {
+    /// The line doesn't start with one of `+-<>.,[]#`.
+    UnknownCommand { line: usize },
+    /// Nothing follows the command.
+    MissingToken { line: usize },
+    /// The token already spells another command.
+    DuplicateToken { line: usize },
+}
 
-                *pending_adds.entry(off).or_insert(0) =
-                    pending_adds.entry(off).or_insert(0).wrapping_add(v);
+impl fmt::Display for TokenMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenMapError::UnknownCommand { line } => {
+                write!(f, "line {}: expected one of '+-<>.,[]#'", line)
             }
-
-            Op::ValSub(off, v) => {
-                if !pending_clears.is_empty() {
-                    new_ops.push(Op::BulkClear(pending_clears.clone()));
-
-                    pending_clears.clear();
-                }
-
-                let v_neg = (0u8).wrapping_sub(v);
-
-                *pending_adds.entry(off).or_insert(0) =
-                    pending_adds.entry(off).or_insert(0).wrapping_add(v_neg);
+            TokenMapError::MissingToken { line } => {
+                write!(f, "line {}: missing the token after the command", line)
             }
-
-            Op::Clear(off) => {
-                if !pending_adds.is_empty() {
-                    let mut sorted_adds: Vec<_> = pending_adds.drain().collect();
-
-                    sorted_adds.sort_by_key(|k| k.0);
-
-                    new_ops.push(Op::BulkAdd(sorted_adds));
-                }
-
-                if !pending_clears.contains(&off) {
-                    pending_clears.push(off);
-                }
+            TokenMapError::DuplicateToken { line } => {
+                write!(f, "line {}: the token already spells another command", line)
             }
+        }
+    }
+}
 
-            Op::Jz(_) => {
-                if !pending_adds.is_empty() {
-                    let mut sorted_adds: Vec<_> = pending_adds.drain().collect();
-
-                    sorted_adds.sort_by_key(|k| k.0);
-
-                    new_ops.push(Op::BulkAdd(sorted_adds));
-                }
-
-                if !pending_clears.is_empty() {
-                    new_ops.push(Op::BulkClear(pending_clears.clone()));
-
-                    pending_clears.clear();
-                }
+impl core::error::Error for TokenMapError {}
 
-                new_ops.push(Op::Jz(0));
+impl TokenMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-                loop_stack.push(new_ops.len() - 1);
+    /// Reads a map with a command, whitespace and its token on each line,
+    /// such as `+ Ook. Ook.`. Blank lines are skipped, and a command may
+    /// be given more than one token.
+    pub fn from_text(text: &str) -> Result<Self, TokenMapError> {
+        let mut map = TokenMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut chars = line.chars();
+            let command = chars.next().unwrap_or_default();
+            let rest = chars.as_str();
+            let token = rest.trim();
+            if token.is_empty() && "+-<>.,[]#".contains(command) {
+                return Err(TokenMapError::MissingToken { line: line_no });
+            }
+            if !"+-<>.,[]#".contains(command) || !rest.starts_with(char::is_whitespace) {
+                return Err(TokenMapError::UnknownCommand { line: line_no });
             }
+            if !map.insert(command as u8, token) {
+                return Err(TokenMapError::DuplicateToken { line: line_no });
+            }
+        }
+        Ok(map)
+    }
 
-            Op::Jnz(_) => {
-                if !pending_adds.is_empty() {
-                    let mut sorted_adds: Vec<_> = pending_adds.drain().collect();
+    /// Adds `token` as a spelling of `command`. Returns `false`, leaving
+    /// the map unchanged, if `token` is empty or already in the map.
+    ///
+    /// # Panics
+    ///
+    /// If `command` isn't one of `+-<>.,[]#`.
+    pub fn insert(&mut self, command: u8, token: impl Into<Vec<u8>>) -> bool {
+        assert!(
+            b"+-<>.,[]#".contains(&command),
+            "not a command: {:?}",
+            command as char
+        );
+        let token = token.into();
+        if token.is_empty() || self.tokens.iter().any(|(t, _)| *t == token) {
+            return false;
+        }
+        let at = self
+            .tokens
+            .iter()
+            .position(|(t, _)| t.len() < token.len())
+            .unwrap_or(self.tokens.len());
+        self.tokens.insert(at, (token, command));
+        true
+    }
 
-                    sorted_adds.sort_by_key(|k| k.0);
+    /// The longest token at the start of `source`, with its command.
+    fn match_at(&self, source: &[u8]) -> Option<(usize, u8)> {
+        self.tokens
+            .iter()
+            .find(|(token, _)| source.starts_with(token))
+            .map(|(token, command)| (token.len(), *command))
+    }
 
-                    new_ops.push(Op::BulkAdd(sorted_adds));
-                }
+    /// `source` with its tokens turned back into standard commands and
+    /// everything else dropped.
+    pub fn translate(&self, source: &[u8]) -> Vec<u8> {
+        let options = ParseOptions {
+            debug_char: true,
+            tokens: Some(self.clone()),
+            ..ParseOptions::default()
+        };
+        commands(source, &options)
+            .map(|(_, command)| command)
+            .collect()
+    }
+}
 
-                if !pending_clears.is_empty() {
-                    new_ops.push(Op::BulkClear(pending_clears.clone()));
+/// A bracket mismatch found by [`parse`].
+///
+/// `offset` is the byte offset of the offending bracket in the source passed
+/// to `parse`; `line` and `column` are 1-based and count bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnmatchedOpen {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    UnmatchedClose {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    /// A pbrain `(` without its `)`.
+    UnmatchedProcStart {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    /// A pbrain `)` without its `(`.
+    UnmatchedProcEnd {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    /// A `[` or `(` nested deeper than [`ParseOptions::max_depth`].
+    TooDeep {
+        offset: usize,
+        line: usize,
+        column: usize,
+    },
+    /// A source of `size` bytes, over [`ParseOptions::max_size`].
+    TooLarge { size: usize, limit: usize },
+    /// Reading the source failed after `offset` bytes, in
+    /// [`parse_reader`].
+    Read { offset: usize, message: String },
+}
 
-                    pending_clears.clear();
-                }
+impl ParseError {
+    /// The byte offset in the source of what the error is about, unless
+    /// it's about the whole program.
+    pub fn offset(&self) -> Option<usize> {
+        match *self {
+            ParseError::UnmatchedOpen { offset, .. }
+            | ParseError::UnmatchedClose { offset, .. }
+            | ParseError::UnmatchedProcStart { offset, .. }
+            | ParseError::UnmatchedProcEnd { offset, .. }
+            | ParseError::TooDeep { offset, .. } => Some(offset),
+            ParseError::TooLarge { .. } | ParseError::Read { .. } => None,
+        }
+    }
 
-                let start = loop_stack.pop().expect("Optimizer: Unmatched ']'");
+    fn unmatched_open(source: &[u8], offset: usize) -> Self {
+        let (line, column) = line_column(source, offset);
+        ParseError::UnmatchedOpen {
+            offset,
+            line,
+            column,
+        }
+    }
+
+    fn unmatched_close(source: &[u8], offset: usize) -> Self {
+        let (line, column) = line_column(source, offset);
+        ParseError::UnmatchedClose {
+            offset,
+            line,
+            column,
+        }
+    }
 
-                let end = new_ops.len();
+    fn unmatched_proc_start(source: &[u8], offset: usize) -> Self {
+        let (line, column) = line_column(source, offset);
+        ParseError::UnmatchedProcStart {
+            offset,
+            line,
+            column,
+        }
+    }
 
-                new_ops.push(Op::Jnz(start));
+    fn unmatched_proc_end(source: &[u8], offset: usize) -> Self {
+        let (line, column) = line_column(source, offset);
+        ParseError::UnmatchedProcEnd {
+            offset,
+            line,
+            column,
+        }
+    }
+}
 
-                if let Op::Jz(t) = &mut new_ops[start] {
-                    *t = end;
-                }
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedOpen { line, column, .. } => {
+                write!(f, "unmatched '[' at line {}, column {}", line, column)
+            }
+            ParseError::UnmatchedClose { line, column, .. } => {
+                write!(f, "unmatched ']' at line {}, column {}", line, column)
             }
+            ParseError::UnmatchedProcStart { line, column, .. } => {
+                write!(f, "unmatched '(' at line {}, column {}", line, column)
+            }
+            ParseError::UnmatchedProcEnd { line, column, .. } => {
+                write!(f, "unmatched ')' at line {}, column {}", line, column)
+            }
+            ParseError::TooDeep { line, column, .. } => {
+                write!(f, "nested too deeply at line {}, column {}", line, column)
+            }
+            ParseError::TooLarge { size, limit } => {
+                write!(
+                    f,
+                    "the program is {} bytes, over the limit of {}",
+                    size, limit
+                )
+            }
+            ParseError::Read { offset, message } => {
+                write!(
+                    f,
+                    "reading the program failed after {} bytes: {}",
+                    offset, message
+                )
+            }
+        }
+    }
+}
 
-            _ => {
-                if !pending_adds.is_empty() {
-                    let mut sorted_adds: Vec<_> = pending_adds.drain().collect();
+impl core::error::Error for ParseError {}
+
+/// The 1-based line and byte column of `offset` in `source`.
+pub(crate) fn line_column(source: &[u8], offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let line_start = before
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |p| p + 1);
+    (line, offset - line_start + 1)
+}
 
-                    sorted_adds.sort_by_key(|k| k.0);
+/// Splits a program stored as `code!input`, the convention of many
+/// brainfuck archives, at its first `!`: returns the code and, if there is
+/// a `!`, the input after it. The `!` of the [`pragma`] lines at the top,
+/// such as a `#!/usr/bin/env bfi` shebang, isn't that `!`.
+pub fn split_input(source: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let header = pragma::header_len(source);
+    match source[header..].iter().position(|&b| b == b'!') {
+        Some(bang) => {
+            let bang = header + bang;
+            (&source[..bang], Some(&source[bang + 1..]))
+        }
+        None => (source, None),
+    }
+}
 
-                    new_ops.push(Op::BulkAdd(sorted_adds));
-                }
+/// Parses brainfuck source into ops. Every byte other than the eight
+/// commands is a comment and is skipped.
+pub fn parse(source: &[u8]) -> Result<Vec<Op>, ParseError> {
+    parse_with(source, &ParseOptions::default())
+}
 
-                if !pending_clears.is_empty() {
-                    new_ops.push(Op::BulkClear(pending_clears.clone()));
+/// Like [`parse`], with extra syntax enabled by `options`.
+pub fn parse_with(source: &[u8], options: &ParseOptions) -> Result<Vec<Op>, ParseError> {
+    parse_with_spans(source, options).map(|(ops, _)| ops)
+}
 
-                    pending_clears.clear();
-                }
+/// Like [`parse_with`], but also returns the byte range of `source` each op
+/// came from, for pointing back into the program while it runs.
+///
+/// A range runs from the first to the last command folded into the op, so
+/// the ranges of an offset update and the pointer move around it overlap.
+pub fn parse_with_spans(
+    source: &[u8],
+    options: &ParseOptions,
+) -> Result<(Vec<Op>, Vec<Range<usize>>), ParseError> {
+    if let Some(limit) = options.max_size
+        && source.len() > limit
+    {
+        return Err(ParseError::TooLarge {
+            size: source.len(),
+            limit,
+        });
+    }
+    let mut builder = OpBuilder::new(options, true);
+    // The run of commands not yet pushed, and its span.
+    let mut run: Option<(u8, usize, Range<usize>)> = None;
+    for (span, command) in commands(source, options) {
+        if let Some((b, count, run_span)) = &mut run
+            && *b == command
+            && is_run(command)
+        {
+            *count += 1;
+            run_span.end = span.end;
+            continue;
+        }
+        if let Some((b, count, span)) = run.replace((command, 1, span)) {
+            builder
+                .push(b, count, span.clone(), span.start)
+                .map_err(|error| error.at(source, span.start))?;
+        }
+    }
+    if let Some((b, count, span)) = run {
+        builder
+            .push(b, count, span.clone(), span.start)
+            .map_err(|error| error.at(source, span.start))?;
+    }
+    builder
+        .finish()
+        .map_err(|(error, offset)| error.at(source, offset))
+}
 
-                new_ops.push(op);
+/// Parses standard brainfuck from `reader` as it arrives, like [`parse`]
+/// but without holding the whole source in memory: comments are dropped as
+/// they're read, so only the ops are kept. Failing to read is a
+/// [`ParseError::Read`].
+#[cfg(feature = "std")]
+pub fn parse_reader<R: std::io::Read>(mut reader: R) -> Result<Vec<Op>, ParseError> {
+    let options = ParseOptions::default();
+    let mut builder = OpBuilder::new(&options, false);
+    let mut buf = [0; 8192];
+    // Where the next byte is: its offset, line, and the offset its line
+    // starts at.
+    let (mut offset, mut line, mut line_start) = (0, 1, 0);
+    // The run of commands not yet pushed, with its span and where it
+    // starts.
+    let mut run: Option<(u8, usize, Range<usize>, Location)> = None;
+    // Whether it's still in the pragma lines at the top, which start with
+    // `#!`.
+    let mut header = true;
+    let locate = |error: BracketError, (offset, line, column)| error.located(offset, line, column);
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                return Err(ParseError::Read {
+                    offset,
+                    message: e.to_string(),
+                });
+            }
+        };
+        for &b in &buf[..n] {
+            let here = (offset, line, offset - line_start + 1);
+            offset += 1;
+            if b == b'\n' {
+                line += 1;
+                line_start = offset;
+            }
+            header &= match here.2 {
+                1 => b == b'#',
+                2 => b == b'!',
+                _ => true,
+            };
+            if header || !is_command(b, &options) {
+                continue;
+            }
+            if let Some((command, count, span, _)) = &mut run
+                && *command == b
+                && is_run(b)
+            {
+                *count += 1;
+                span.end = offset;
+                continue;
+            }
+            if let Some((command, count, span, start)) = run.replace((b, 1, here.0..offset, here)) {
+                builder
+                    .push(command, count, span, start)
+                    .map_err(|error| locate(error, start))?;
             }
         }
     }
+    if let Some((command, count, span, start)) = run {
+        builder
+            .push(command, count, span, start)
+            .map_err(|error| locate(error, start))?;
+    }
+    builder
+        .finish()
+        .map(|(ops, _)| ops)
+        .map_err(|(error, start)| locate(error, start))
+}
 
-    if !pending_adds.is_empty() {
-        let mut sorted_adds: Vec<_> = pending_adds.drain().collect();
+/// A byte offset in a source, with its 1-based line and column.
+#[cfg(feature = "std")]
+type Location = (usize, usize, usize);
 
-        sorted_adds.sort_by_key(|k| k.0);
+/// Ops and the spans of source they came from.
+type Spanned = (Vec<Op>, Vec<Range<usize>>);
 
-        new_ops.push(Op::BulkAdd(sorted_adds));
+/// Whether repeats of `command` are folded into one op, and so pushed to
+/// an [`OpBuilder`] as a run.
+fn is_run(command: u8) -> bool {
+    matches!(command, b'>' | b'<' | b'+' | b'-' | b'v' | b'^')
+}
+
+/// A bracket an [`OpBuilder`] can't accept, to be reported wherever its
+/// caller says it is.
+#[derive(Debug, Clone, Copy)]
+enum BracketError {
+    UnmatchedOpen,
+    UnmatchedClose,
+    UnmatchedProcStart,
+    UnmatchedProcEnd,
+    TooDeep,
+}
+
+impl BracketError {
+    fn located(self, offset: usize, line: usize, column: usize) -> ParseError {
+        match self {
+            BracketError::UnmatchedOpen => ParseError::UnmatchedOpen {
+                offset,
+                line,
+                column,
+            },
+            BracketError::UnmatchedClose => ParseError::UnmatchedClose {
+                offset,
+                line,
+                column,
+            },
+            BracketError::UnmatchedProcStart => ParseError::UnmatchedProcStart {
+                offset,
+                line,
+                column,
+            },
+            BracketError::UnmatchedProcEnd => ParseError::UnmatchedProcEnd {
+                offset,
+                line,
+                column,
+            },
+            BracketError::TooDeep => ParseError::TooDeep {
+                offset,
+                line,
+                column,
+            },
+        }
     }
 
-    if !pending_clears.is_empty() {
-        new_ops.push(Op::BulkClear(pending_clears));
+    /// The error at `offset` in `source`.
+    fn at(self, source: &[u8], offset: usize) -> ParseError {
+        let (line, column) = line_column(source, offset);
+        self.located(offset, line, column)
     }
+}
 
-    new_ops
+/// Turns commands into ops as they come, folding runs and pointer moves
+/// the way [`parse_with_spans`] does. Each command comes with where it is,
+/// `T`, which is handed back for an unmatched opening bracket.
+struct OpBuilder<T> {
+    ops: Vec<Op>,
+    /// The span of each op, unless they aren't wanted.
+    spans: Option<Vec<Range<usize>>>,
+    /// The `Jz` or `DefProc` of each open bracket, and where it is.
+    open: Vec<(usize, T)>,
+    current_offset: isize,
+    /// Where the pointer moves folded into `current_offset` are.
+    offset_span: Option<Range<usize>>,
+    max_depth: Option<usize>,
+    multitape: bool,
+    /// Whether adds that cancel out leave an add of 0.
+    strict: bool,
 }
 
-fn optimize_loops(ops: Vec<Op>) -> Vec<Op> {
-    let mut new_ops = Vec::new();
-    let mut loop_stack = Vec::new();
-    let mut i = 0;
+impl<T: Copy> OpBuilder<T> {
+    fn new(options: &ParseOptions, spans: bool) -> Self {
+        OpBuilder {
+            ops: Vec::new(),
+            spans: spans.then(Vec::new),
+            open: Vec::new(),
+            current_offset: 0,
+            offset_span: None,
+            max_depth: options.max_depth,
+            multitape: options.dialect == Dialect::Multitape,
+            strict: options.semantics == Semantics::Strict,
+        }
+    }
 
-    while i < ops.len() {
-        match &ops[i] {
-            Op::Jz(target) => {
-                let body = &ops[i + 1..*target];
-                if let Some(scan_op) = check_scan_loop(body) {
-                    new_ops.push(scan_op);
-                    i = target + 1;
-                } else if let Some(mul_ops) = check_move_loop(body) {
-                    new_ops.extend(mul_ops);
-                    // Move loop implicitly ends with Clear(0)
-                    new_ops.push(Op::Clear(0));
-                    i = target + 1;
-                } else {
-                    new_ops.push(Op::Jz(0));
-                    loop_stack.push(new_ops.len() - 1);
-                    i += 1;
-                }
-            }
-            Op::Jnz(_) => {
-                let start = loop_stack.pop().expect("Optimizer: Unmatched ']'");
-                let end = new_ops.len();
-                new_ops.push(Op::Jnz(start));
+    fn emit(&mut self, op: Op, span: Range<usize>) {
+        self.ops.push(op);
+        if let Some(spans) = &mut self.spans {
+            spans.push(span);
+        }
+    }
 
-                if let Op::Jz(t) = &mut new_ops[start] {
-                    *t = end;
-                }
-                i += 1;
-            }
-            other => {
-                new_ops.push(other.clone());
-                i += 1;
-            }
+    /// Folds `span` into the last op, which `op` has changed.
+    fn extend_last(&mut self, span: Range<usize>) {
+        if let Some(spans) = &mut self.spans {
+            extend_last(spans, span);
         }
     }
-    new_ops
-}
 
-fn optimize_dce(ops: Vec<Op>) -> Vec<Op> {
-    let mut new_ops = Vec::new();
-    let mut loop_stack = Vec::new();
-    let mut i = 0;
-    let mut known_zero = true;
+    /// Replaces the last op, whose effect `op` cancels out, with `op`, or
+    /// drops it if `op` is `None`.
+    fn replace_last(&mut self, op: Option<Op>, span: Range<usize>) {
+        self.ops.pop();
+        let prev = self.spans.as_mut().and_then(|spans| spans.pop());
+        if let Some(op) = op {
+            self.emit(op, prev.map_or(span.clone(), |prev| prev.start..span.end));
+        }
+    }
 
-    while i < ops.len() {
-        match &ops[i] {
-            Op::Jz(target) => {
-                if known_zero {
-                    i = target + 1;
-                } else {
-                    new_ops.push(Op::Jz(0));
-                    loop_stack.push(new_ops.len() - 1);
-                    known_zero = false;
-                    i += 1;
-                }
+    /// Adds `count` repeats of `command`, spelled at `span` and starting
+    /// at `at`.
+    fn push(
+        &mut self,
+        command: u8,
+        count: usize,
+        span: Range<usize>,
+        at: T,
+    ) -> Result<(), BracketError> {
+        // Sequence points flush the pointer update
+        if !matches!(command, b'>' | b'<' | b'+' | b'-') {
+            if let Some(span) = self.offset_span.take()
+                && self.current_offset != 0
+            {
+                self.emit(Op::PtrAdd(self.current_offset), span);
             }
-            Op::Jnz(_) => {
-                let start = loop_stack.pop().expect("Optimizer: Unmatched ']'");
-                let end = new_ops.len();
-                new_ops.push(Op::Jnz(start));
+            self.current_offset = 0;
+        }
+        if matches!(command, b'[' | b'(')
+            && self.max_depth.is_some_and(|max| self.open.len() >= max)
+        {
+            return Err(BracketError::TooDeep);
+        }
 
-                if let Op::Jz(t) = &mut new_ops[start] {
-                    *t = end;
-                }
-                known_zero = true;
-                i += 1;
-            }
-            Op::Clear(offset) => {
-                let offset = *offset;
-                if offset == 0 {
-                    if !known_zero {
-                        new_ops.push(Op::Clear(0));
-                        known_zero = true;
-                    }
+        let offset = self.current_offset;
+        match command {
+            b'>' | b'<' => {
+                if command == b'>' {
+                    self.current_offset += count as isize;
                 } else {
-                    new_ops.push(Op::Clear(offset));
+                    self.current_offset -= count as isize;
                 }
-                i += 1;
+                self.offset_span = Some(match self.offset_span.take() {
+                    Some(prev) => prev.start..span.end,
+                    None => span,
+                });
             }
-            Op::MulAdd(offset, factor) => {
-                if !known_zero {
-                    new_ops.push(Op::MulAdd(*offset, *factor));
-                    known_zero = false;
-                }
-                i += 1;
-            }
-            Op::ScanLeft | Op::ScanRight => {
-                if !known_zero {
-                    new_ops.push(ops[i].clone());
-                    known_zero = true;
-                }
-                i += 1;
-            }
-            Op::PtrAdd(n) => {
-                let n = *n;
-                if let Some(Op::PtrAdd(prev)) = new_ops.last_mut() {
-                    *prev += n;
-                } else {
-                    new_ops.push(Op::PtrAdd(n));
-                }
-                if n != 0 {
-                    known_zero = false;
-                }
-                i += 1;
-            }
-            Op::ValAdd(offset, n) => {
-                let offset = *offset;
-                let n = *n;
-                if let Some(Op::ValAdd(prev_off, prev_val)) = new_ops.last_mut() {
-                    if *prev_off == offset {
-                        *prev_val = prev_val.wrapping_add(n);
-                        if *prev_val == 0 {
-                            new_ops.pop();
-                        }
-                    } else {
-                        new_ops.push(Op::ValAdd(offset, n));
+            b'+' => {
+                let val = count as u32;
+                match self.ops.last_mut() {
+                    Some(Op::ValAdd(off, prev_val)) if *off == offset => {
+                        *prev_val = prev_val.wrapping_add(val);
+                        self.extend_last(span);
                     }
-                } else if let Some(Op::ValSub(prev_off, prev_val)) = new_ops.last_mut() {
-                    if *prev_off == offset {
-                        if *prev_val > n {
-                            *prev_val -= n;
-                        } else if *prev_val < n {
-                            let rem = n - *prev_val;
-                            new_ops.pop();
-                            new_ops.push(Op::ValAdd(offset, rem));
+                    Some(Op::ValSub(off, prev_val)) if *off == offset => {
+                        if *prev_val > val {
+                            *prev_val -= val;
+                            self.extend_last(span);
                         } else {
-                            new_ops.pop();
+                            let rem = val - *prev_val;
+                            let keep = rem > 0 || self.strict;
+                            self.replace_last(keep.then_some(Op::ValAdd(offset, rem)), span);
                         }
-                    } else {
-                        new_ops.push(Op::ValAdd(offset, n));
                     }
-                } else {
-                    new_ops.push(Op::ValAdd(offset, n));
+                    _ => self.emit(Op::ValAdd(offset, val), span),
                 }
-                if offset == 0 {
-                    known_zero = false;
-                }
-                i += 1;
-            }
-            Op::ValSub(offset, n) => {
-                let offset = *offset;
-                let n = *n;
-                if let Some(Op::ValSub(prev_off, prev_val)) = new_ops.last_mut() {
-                    if *prev_off == offset {
-                        *prev_val = prev_val.wrapping_add(n);
-                        if *prev_val == 0 {
-                            new_ops.pop();
-                        }
-                    } else {
-                        new_ops.push(Op::ValSub(offset, n));
+            }
+            b'-' => {
+                let val = count as u32;
+                match self.ops.last_mut() {
+                    Some(Op::ValSub(off, prev_val)) if *off == offset => {
+                        *prev_val = prev_val.wrapping_add(val);
+                        self.extend_last(span);
                     }
-                } else if let Some(Op::ValAdd(prev_off, prev_val)) = new_ops.last_mut() {
-                    if *prev_off == offset {
-                        if *prev_val > n {
-                            *prev_val -= n;
-                        } else if *prev_val < n {
-                            let rem = n - *prev_val;
-                            new_ops.pop();
-                            new_ops.push(Op::ValSub(offset, rem));
+                    Some(Op::ValAdd(off, prev_val)) if *off == offset => {
+                        if *prev_val > val {
+                            *prev_val -= val;
+                            self.extend_last(span);
                         } else {
-                            new_ops.pop();
+                            let rem = val - *prev_val;
+                            let keep = rem > 0 || self.strict;
+                            self.replace_last(keep.then_some(Op::ValSub(offset, rem)), span);
                         }
-                    } else {
-                        new_ops.push(Op::ValSub(offset, n));
                     }
-                } else {
-                    new_ops.push(Op::ValSub(offset, n));
+                    _ => self.emit(Op::ValSub(offset, val), span),
                 }
-                if offset == 0 {
-                    known_zero = false;
-                }
-                i += 1;
             }
-            Op::Input => {
-                new_ops.push(Op::Input);
-                known_zero = false;
-                i += 1;
+            b'.' => self.emit(Op::Output, span),
+            b',' => self.emit(Op::Input, span),
+            b'#' => self.emit(Op::Debug, span),
+            b'?' => self.emit(Op::Random, span),
+            b'[' => {
+                self.emit(Op::Jz(0), span);
+                self.open.push((self.ops.len() - 1, at));
+            }
+            b']' => {
+                let ops = &self.ops;
+                let Some((start, _)) = self
+                    .open
+                    .pop_if(|(start, _)| matches!(ops[*start], Op::Jz(_)))
+                else {
+                    return Err(BracketError::UnmatchedClose);
+                };
+                let end = self.ops.len();
+                self.emit(Op::Jnz(start), span);
+                self.ops[start] = Op::Jz(end);
+            }
+            b'(' => {
+                self.emit(Op::DefProc(0), span);
+                self.open.push((self.ops.len() - 1, at));
+            }
+            b')' => {
+                let ops = &self.ops;
+                let Some((start, _)) = self
+                    .open
+                    .pop_if(|(start, _)| matches!(ops[*start], Op::DefProc(_)))
+                else {
+                    return Err(BracketError::UnmatchedProcEnd);
+                };
+                let end = self.ops.len();
+                self.emit(Op::EndProc, span);
+                self.ops[start] = Op::DefProc(end);
             }
-            Op::Output => {
-                new_ops.push(ops[i].clone());
-                i += 1;
+            b':' => self.emit(Op::CallProc, span),
+            b'Y' => self.emit(Op::Fork, span),
+            b'v' | b'^' if self.multitape => {
+                let n = count as isize;
+                self.emit(Op::SwitchTape(if command == b'v' { n } else { -n }), span);
+            }
+            b'@' | b'$' | b'!' | b'{' | b'}' | b'~' | b'^' | b'&' | b'|' => {
+                let op = match command {
+                    b'@' => Op::End,
+                    b'$' => Op::Store,
+                    b'!' => Op::Retrieve,
+                    b'{' => Op::Bitwise(BitOp::ShiftLeft),
+                    b'}' => Op::Bitwise(BitOp::ShiftRight),
+                    b'~' => Op::Bitwise(BitOp::Not),
+                    b'^' => Op::Bitwise(BitOp::Xor),
+                    b'&' => Op::Bitwise(BitOp::And),
+                    _ => Op::Bitwise(BitOp::Or),
+                };
+                self.emit(op, span);
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// The ops and their spans, or the first bracket left open and where
+    /// it is.
+    fn finish(mut self) -> Result<Spanned, (BracketError, T)> {
+        if let Some(&(start, at)) = self.open.last() {
+            return Err(match self.ops[start] {
+                Op::DefProc(_) => (BracketError::UnmatchedProcStart, at),
+                _ => (BracketError::UnmatchedOpen, at),
+            });
+        }
+        if let Some(span) = self.offset_span.take()
+            && self.current_offset != 0
+        {
+            self.emit(Op::PtrAdd(self.current_offset), span);
+        }
+        Ok((self.ops, self.spans.unwrap_or_default()))
+    }
+}
+
+/// Every bracket mismatch in `source`, in the order they appear, where
+/// [`parse_with`] stops at the first. Nothing means the program parses.
+pub fn bracket_errors(source: &[u8], options: &ParseOptions) -> Vec<ParseError> {
+    let mut errors = Vec::new();
+    let mut open: Vec<(usize, u8)> = Vec::new();
+    for (span, command) in commands(source, options) {
+        let offset = span.start;
+        match command {
+            b'[' | b'(' => open.push((offset, command)),
+            b']' if open.pop_if(|(_, c)| *c == b'[').is_none() => {
+                errors.push(ParseError::unmatched_close(source, offset));
             }
-            _ => {
-                new_ops.push(ops[i].clone());
-                i += 1;
+            b')' if open.pop_if(|(_, c)| *c == b'(').is_none() => {
+                errors.push(ParseError::unmatched_proc_end(source, offset));
             }
+            _ => {}
         }
     }
+    errors.extend(open.into_iter().map(|(offset, command)| match command {
+        b'(' => ParseError::unmatched_proc_start(source, offset),
+        _ => ParseError::unmatched_open(source, offset),
+    }));
+    errors.sort_by_key(ParseError::offset);
+    errors
+}
 
-    // Remove PtrAdd(0)
-    new_ops.retain(|op| !matches!(op, Op::PtrAdd(0)));
+/// A piece of source as [`tokenize`] splits it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Where it is in the source.
+    pub span: Range<usize>,
+}
 
-    new_ops
+/// What a [`Token`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// A command, in its standard spelling whatever the source spells it
+    /// as.
+    Command(u8),
+    /// Text between commands, all of it up to the next one.
+    Comment,
 }
 
-fn check_scan_loop(body: &[Op]) -> Option<Op> {
-    if body.len() == 1 {
-        match body[0] {
-            Op::PtrAdd(1) => Some(Op::ScanRight),
-            Op::PtrAdd(-1) => Some(Op::ScanLeft),
-            _ => None,
+/// Splits `source` into the commands `options` parse and the comments
+/// between them, with where each is. Nothing is left out: the spans run
+/// on from each other and cover the whole source, so tools that rewrite
+/// a program can keep its comments. [`parse_with_spans`] parses from
+/// these. The [`pragma`] lines at the top are a comment.
+pub fn tokenize<'a>(source: &'a [u8], options: &'a ParseOptions) -> Tokens<'a> {
+    Tokens {
+        source,
+        options,
+        at: 0,
+        header: pragma::header_len(source),
+    }
+}
+
+/// The tokens of a source, from [`tokenize`].
+#[derive(Debug, Clone)]
+pub struct Tokens<'a> {
+    source: &'a [u8],
+    options: &'a ParseOptions,
+    at: usize,
+    /// The length of the pragma lines at the top.
+    header: usize,
+}
+
+impl Tokens<'_> {
+    /// The length of what is spelled at `i` and the command it spells, or
+    /// a byte that isn't one.
+    fn spelled_at(&self, i: usize) -> (usize, u8) {
+        match &self.options.tokens {
+            Some(map) => map.match_at(&self.source[i..]).unwrap_or((1, 0)),
+            None => (1, self.source[i]),
         }
-    } else {
-        None
     }
 }
 
-fn check_move_loop(body: &[Op]) -> Option<Vec<Op>> {
-    let mut ptr_offset: isize = 0;
-    let mut deltas: HashMap<isize, i16> = HashMap::new();
+impl Iterator for Tokens<'_> {
+    type Item = Token;
 
-    for op in body {
-        match op {
-            Op::PtrAdd(n) => ptr_offset += n,
-            Op::ValAdd(offset, n) => *deltas.entry(ptr_offset + offset).or_insert(0) += *n as i16,
-            Op::ValSub(offset, n) => *deltas.entry(ptr_offset + offset).or_insert(0) -= *n as i16,
-            _ => return None,
+    fn next(&mut self) -> Option<Token> {
+        let start = self.at;
+        if start >= self.source.len() {
+            return None;
+        }
+        if start < self.header {
+            self.at = self.header;
+        } else {
+            let (len, command) = self.spelled_at(start);
+            self.at += len;
+            if is_command(command, self.options) {
+                return Some(Token {
+                    kind: TokenKind::Command(command),
+                    span: start..self.at,
+                });
+            }
         }
+        while self.at < self.source.len() {
+            let (len, command) = self.spelled_at(self.at);
+            if is_command(command, self.options) {
+                break;
+            }
+            self.at += len;
+        }
+        Some(Token {
+            kind: TokenKind::Comment,
+            span: start..self.at,
+        })
     }
+}
 
-    if ptr_offset != 0 {
-        return None;
+/// The commands in `source` and where each is spelled, skipping comments.
+fn commands<'a>(
+    source: &'a [u8],
+    options: &'a ParseOptions,
+) -> impl Iterator<Item = (Range<usize>, u8)> + 'a {
+    tokenize(source, options).filter_map(|token| match token.kind {
+        TokenKind::Command(command) => Some((token.span, command)),
+        TokenKind::Comment => None,
+    })
+}
+
+/// Whether the standard spelling `command` is a command under `options`.
+fn is_command(command: u8, options: &ParseOptions) -> bool {
+    b"><+-.,[]".contains(&command)
+        || (options.debug_char && command == b'#')
+        || (options.random && command == b'?')
+        || (options.dialect == Dialect::Pbrain && b"():".contains(&command))
+        || (options.dialect == Dialect::Brainfork && command == b'Y')
+        || (options.dialect == Dialect::Extended1 && b"@$!{}~^&|".contains(&command))
+        || (options.dialect == Dialect::Multitape && b"v^".contains(&command))
+}
+
+/// Widens the last span to also cover `span`.
+fn extend_last(spans: &mut [Range<usize>], span: Range<usize>) {
+    if let Some(last) = spans.last_mut() {
+        last.end = span.end;
     }
+}
 
-    let start_delta = *deltas.get(&0).unwrap_or(&0);
-    if (start_delta + 1) % 256 != 0 {
-        return None;
+/// Parses a program that arrives in pieces, such as lines typed into a
+/// REPL. Pieces are collected until their brackets balance; an unclosed `[`
+/// asks for more input instead of failing like [`parse`] does.
+#[derive(Debug, Clone, Default)]
+pub struct SessionParser {
+    options: ParseOptions,
+    /// The pieces pushed since the last complete fragment.
+    pending: Vec<u8>,
+    /// Loops opened in `pending` and not closed yet.
+    depth: usize,
+}
+
+/// What [`SessionParser::push`] made of the input so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fragment {
+    /// A `[` is still open; push more input.
+    Incomplete,
+    /// The brackets balance: the pieces pushed since the last fragment,
+    /// joined, and the ops they parse to.
+    Complete { source: Vec<u8>, ops: Vec<Op> },
+}
+
+impl SessionParser {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    let mut result = Vec::new();
-    for (&offset, &delta) in deltas.iter() {
-        if offset == 0 {
-            continue;
+    pub fn with_options(options: ParseOptions) -> Self {
+        SessionParser {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Adds `code` to the current fragment. A `]` without a `[` discards
+    /// the fragment; the error's offset is into the fragment, pending input
+    /// included.
+    pub fn push(&mut self, code: &[u8]) -> Result<Fragment, ParseError> {
+        let start = self.pending.len();
+        self.pending.extend_from_slice(code);
+        let found: Vec<_> = commands(&self.pending[start..], &self.options).collect();
+        for (span, command) in found {
+            match command {
+                b'[' | b'(' => self.depth += 1,
+                b']' | b')' if self.depth == 0 => {
+                    let offset = start + span.start;
+                    let err = match command {
+                        b']' => ParseError::unmatched_close(&self.pending, offset),
+                        _ => ParseError::unmatched_proc_end(&self.pending, offset),
+                    };
+                    self.clear();
+                    return Err(err);
+                }
+                b']' | b')' => self.depth -= 1,
+                _ => {}
+            }
+        }
+        if self.depth > 0 {
+            return Ok(Fragment::Incomplete);
+        }
+        let source = core::mem::take(&mut self.pending);
+        let ops = parse_with(&source, &self.options)?;
+        Ok(Fragment::Complete { source, ops })
+    }
+
+    /// Whether a fragment is waiting for its loops to be closed.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The input pushed since the last complete fragment.
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Drops the pending fragment.
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.depth = 0;
+    }
+}
+
+/// Renders ops one per line with their index, indenting loop bodies and
+/// showing where each jump goes:
+///
+/// ```text
+///     0  ValAdd(0, 1)
+///     1  Jz -> 4
+///     2    ValSub(0, 1)
+///     3    ValAdd(1, 2)
+///     4  Jnz -> 1
+/// ```
+pub fn fmt_ops(ops: &[Op]) -> String {
+    let mut out = String::new();
+    let mut depth = 0usize;
+    for (i, op) in ops.iter().enumerate() {
+        if let Op::Jnz(_) | Op::EndProc = op {
+            depth = depth.saturating_sub(1);
+        }
+        let text = fmt_op(op);
+        out.push_str(&format!(
+            "{:5}  {:indent$}{}\n",
+            i,
+            "",
+            text,
+            indent = 2 * depth
+        ));
+        if let Op::Jz(_) | Op::DefProc(_) = op {
+            depth += 1;
         }
-        result.push(Op::MulAdd(offset, delta as u8));
     }
+    out
+}
 
-    Some(result)
+/// Whether `ops` define or call pbrain procedures.
+#[cfg(feature = "std")]
+pub(crate) fn uses_procedures(ops: &[Op]) -> bool {
+    ops.iter()
+        .any(|op| matches!(op, Op::DefProc(_) | Op::EndProc | Op::CallProc))
+}
+
+/// Whether `ops` fork Brainfork threads.
+#[cfg(feature = "std")]
+pub(crate) fn uses_forks(ops: &[Op]) -> bool {
+    ops.contains(&Op::Fork)
+}
+
+/// How deeply the loops of `ops` nest.
+#[cfg(feature = "std")]
+pub(crate) fn loop_depth(ops: &[Op]) -> usize {
+    let mut depth = 0usize;
+    let mut max = 0;
+    for op in ops {
+        match op {
+            Op::Jz(_) => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            Op::Jnz(_) => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// Whether `ops` switch between the multitape dialect's tapes.
+#[cfg(feature = "std")]
+pub(crate) fn uses_tapes(ops: &[Op]) -> bool {
+    ops.iter().any(|op| matches!(op, Op::SwitchTape(_)))
+}
+
+/// Whether `ops` use any of Extended Type I's commands.
+#[cfg(feature = "std")]
+pub(crate) fn uses_extended1(ops: &[Op]) -> bool {
+    ops.iter()
+        .any(|op| matches!(op, Op::End | Op::Store | Op::Retrieve | Op::Bitwise(_)))
+}
+
+/// Whether `ops` draw random bytes with `?`.
+#[cfg(feature = "std")]
+pub(crate) fn uses_random(ops: &[Op]) -> bool {
+    ops.contains(&Op::Random)
+}
+
+/// Whether `ops` use Extended Type I's storage register.
+#[cfg(feature = "std")]
+pub(crate) fn uses_storage(ops: &[Op]) -> bool {
+    ops.iter().any(|op| match op {
+        Op::Store | Op::Retrieve => true,
+        Op::Bitwise(bit) => bit.reads_storage(),
+        _ => false,
+    })
+}
+
+/// One op as [`fmt_ops`] shows it.
+pub(crate) fn fmt_op(op: &Op) -> String {
+    match op {
+        Op::Jz(target) => format!("Jz -> {}", target),
+        Op::Jnz(target) => format!("Jnz -> {}", target),
+        Op::DefProc(end) => format!("DefProc -> {}", end),
+        Op::OutputConst(bytes) => format!("OutputConst(\"{}\")", bytes.escape_ascii()),
+        _ => format!("{:?}", op),
+    }
+}
+
+/// Every name [`op_name`] gives, in the order of [`Op`]'s variants.
+#[cfg(feature = "std")]
+pub(crate) const OP_NAMES: [&str; 35] = [
+    "PtrAdd",
+    "ValAdd",
+    "ValSub",
+    "Output",
+    "Input",
+    "OutputAt",
+    "InputAt",
+    "OutputConst",
+    "OutputRepeat",
+    "Jz",
+    "Jnz",
+    "Clear",
+    "Set",
+    "MulAdd",
+    "AddTo",
+    "SubFrom",
+    "Transfer",
+    "ScanLeft",
+    "ScanRight",
+    "Scan",
+    "BulkAdd",
+    "BulkClear",
+    "ClearRange",
+    "ClearUntilZero",
+    "Debug",
+    "DefProc",
+    "EndProc",
+    "CallProc",
+    "Fork",
+    "End",
+    "Store",
+    "Retrieve",
+    "Bitwise",
+    "SwitchTape",
+    "DivMod",
+];
+
+/// The name [`fmt_ops`] shows `op` under, without its
+/// operands.
+pub(crate) fn op_name(op: &Op) -> &'static str {
+    match op {
+        Op::PtrAdd(_) => "PtrAdd",
+        Op::ValAdd(..) => "ValAdd",
+        Op::ValSub(..) => "ValSub",
+        Op::Output => "Output",
+        Op::Input => "Input",
+        Op::OutputAt(_) => "OutputAt",
+        Op::InputAt(_) => "InputAt",
+        Op::OutputConst(_) => "OutputConst",
+        Op::OutputRepeat(..) => "OutputRepeat",
+        Op::Jz(_) => "Jz",
+        Op::Jnz(_) => "Jnz",
+        Op::Clear(_) => "Clear",
+        Op::Set(..) => "Set",
+        Op::MulAdd(..) => "MulAdd",
+        Op::AddTo(_) => "AddTo",
+        Op::SubFrom(_) => "SubFrom",
+        Op::Transfer(_) => "Transfer",
+        Op::ScanLeft => "ScanLeft",
+        Op::ScanRight => "ScanRight",
+        Op::Scan(_) => "Scan",
+        Op::BulkAdd(_) => "BulkAdd",
+        Op::BulkClear(_) => "BulkClear",
+        Op::ClearRange(..) => "ClearRange",
+        Op::ClearUntilZero(_) => "ClearUntilZero",
+        Op::Debug => "Debug",
+        Op::DefProc(_) => "DefProc",
+        Op::EndProc => "EndProc",
+        Op::CallProc => "CallProc",
+        Op::Fork => "Fork",
+        Op::End => "End",
+        Op::Store => "Store",
+        Op::Retrieve => "Retrieve",
+        Op::Bitwise(_) => "Bitwise",
+        Op::SwitchTape(_) => "SwitchTape",
+        Op::DivMod => "DivMod",
+        Op::Random => "Random",
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use alloc::string::ToString;
+    use alloc::vec;
+
     use super::*;
 
     #[test]
     fn test_parse_offset() {
-        let code = b">+".to_vec();
-        let ops = parse(code);
+        let ops = parse(b">+").unwrap();
         assert_eq!(ops, vec![Op::ValAdd(1, 1), Op::PtrAdd(1)]);
     }
 
     #[test]
     fn test_parse_sequence_point() {
-        let code = b">.+".to_vec();
-        let ops = parse(code);
+        let ops = parse(b">.+").unwrap();
         assert_eq!(ops, vec![Op::PtrAdd(1), Op::Output, Op::ValAdd(0, 1)]);
     }
 
     #[test]
-    fn test_dce_loop_at_start() {
-        let code = b"[->+<].".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![Op::Output]);
+    fn test_split_input() {
+        assert_eq!(
+            split_input(b",[.,]!hi!"),
+            (&b",[.,]"[..], Some(&b"hi!"[..]))
+        );
+        assert_eq!(split_input(b"+."), (&b"+."[..], None));
+        assert_eq!(
+            split_input(b"#!/usr/bin/env bfi\n,.!x"),
+            (&b"#!/usr/bin/env bfi\n,."[..], Some(&b"x"[..]))
+        );
+        assert_eq!(
+            split_input(b"#!/usr/bin/env bfi --bang-input"),
+            (&b"#!/usr/bin/env bfi --bang-input"[..], None)
+        );
+    }
+
+    #[test]
+    fn test_parse_skips_comments() {
+        let ops = parse(b"a > b + c").unwrap();
+        assert_eq!(ops, vec![Op::ValAdd(1, 1), Op::PtrAdd(1)]);
     }
 
     #[test]
-    fn test_dce_redundant_clear() {
-        let code = b"+[-][-]".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
+    fn test_parse_unmatched_open() {
+        let err = parse(b"+\n [ [-]").unwrap_err();
         assert_eq!(
-            optimized,
-            vec![Op::BulkAdd(vec![(0, 1)]), Op::BulkClear(vec![0])]
+            err,
+            ParseError::UnmatchedOpen {
+                offset: 3,
+                line: 2,
+                column: 2
+            }
         );
+        assert_eq!(err.to_string(), "unmatched '[' at line 2, column 2");
     }
 
     #[test]
-    fn test_dce_scan_loop() {
-        let code = b"[<]".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![]);
+    fn test_parse_unmatched_close() {
+        let err = parse(b"+]").unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::UnmatchedClose {
+                offset: 1,
+                line: 1,
+                column: 2
+            }
+        );
+    }
 
-        let code = b"+[<]".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![Op::BulkAdd(vec![(0, 1)]), Op::ScanLeft]);
+    #[test]
+    fn test_bracket_errors() {
+        let source = b"]+[\n[-]]]\n[";
+        let errors = bracket_errors(source, &ParseOptions::default());
+        let positions: Vec<(usize, usize)> = errors
+            .iter()
+            .map(|e| match *e {
+                ParseError::UnmatchedOpen { line, column, .. }
+                | ParseError::UnmatchedClose { line, column, .. } => (line, column),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(positions, [(1, 1), (2, 5), (3, 1)]);
+        assert!(matches!(errors[0], ParseError::UnmatchedClose { .. }));
+        assert!(matches!(errors[2], ParseError::UnmatchedOpen { .. }));
+        // The first is the one parse reports.
+        assert_eq!(parse(source).unwrap_err(), errors[0]);
+        assert_eq!(bracket_errors(b"+[[-]>]", &ParseOptions::default()), []);
     }
 
     #[test]
-    fn test_dce_move_loop() {
-        let code = b"[->+<]".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![]);
-
-        let code = b"+[->+<]".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
+    fn test_session_parser() {
+        let mut parser = SessionParser::new();
         assert_eq!(
-            optimized,
-            vec![
-                Op::BulkAdd(vec![(0, 1)]),
-                Op::MulAdd(1, 1),
-                Op::BulkClear(vec![0])
+            parser.push(b"+>"),
+            Ok(Fragment::Complete {
+                source: b"+>".to_vec(),
+                ops: vec![Op::ValAdd(0, 1), Op::PtrAdd(1)]
+            })
+        );
+        assert!(!parser.is_pending());
+        assert_eq!(parser.push(b"[[-]\n"), Ok(Fragment::Incomplete));
+        assert_eq!(parser.push(b"<\n"), Ok(Fragment::Incomplete));
+        assert_eq!(parser.pending(), b"[[-]\n<\n");
+        let Ok(Fragment::Complete { source, ops }) = parser.push(b"]") else {
+            panic!("the brackets balance");
+        };
+        assert_eq!(source, b"[[-]\n<\n]");
+        assert_eq!(ops, parse(&source).unwrap());
+
+        assert_eq!(parser.push(b"[a\n"), Ok(Fragment::Incomplete));
+        assert_eq!(
+            parser.push(b"]]"),
+            Err(ParseError::UnmatchedClose {
+                offset: 4,
+                line: 2,
+                column: 2
+            })
+        );
+        assert!(!parser.is_pending());
+    }
+
+    #[test]
+    fn test_fmt_ops() {
+        let ops = parse(b"+[->++<]").unwrap();
+        assert_eq!(
+            fmt_ops(&ops),
+            "    0  ValAdd(0, 1)\n    1  Jz -> 4\n    2    ValSub(0, 1)\n    3    ValAdd(1, 2)\n    4  Jnz -> 1\n"
+        );
+        assert_eq!(
+            fmt_ops(&[Op::OutputConst(b"hi\n".to_vec())]),
+            "    0  OutputConst(\"hi\\n\")\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let (ops, spans) = parse_with_spans(b"++ >-<[-] x>>", &ParseOptions::default()).unwrap();
+        assert_eq!(ops.len(), spans.len());
+        assert_eq!(ops[0], Op::ValAdd(0, 2));
+        assert_eq!(spans[0], 0..2);
+        assert_eq!(ops[1], Op::ValSub(1, 1));
+        assert_eq!(spans[1], 4..5);
+        assert_eq!(ops[2], Op::Jz(4));
+        assert_eq!(spans[2], 6..7);
+        assert_eq!(ops.last(), Some(&Op::PtrAdd(2)));
+        assert_eq!(spans.last(), Some(&(11..13)));
+        let options = ParseOptions::default();
+        assert_eq!(parse_with_spans(b"+-", &options).unwrap(), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let options = ParseOptions::default();
+        let tokens: Vec<_> = tokenize(b"a +\n[x]", &options).collect();
+        let token = |kind, span| Token { kind, span };
+        assert_eq!(
+            tokens,
+            [
+                token(TokenKind::Comment, 0..2),
+                token(TokenKind::Command(b'+'), 2..3),
+                token(TokenKind::Comment, 3..4),
+                token(TokenKind::Command(b'['), 4..5),
+                token(TokenKind::Comment, 5..6),
+                token(TokenKind::Command(b']'), 6..7),
+            ]
+        );
+        assert_eq!(tokenize(b"", &options).count(), 0);
+
+        // Tokens from a map keep their whole spelling, and standard
+        // commands that aren't tokens are comments.
+        let mut map = TokenMap::new();
+        map.insert(b'+', "Ook.");
+        let options = ParseOptions {
+            tokens: Some(map),
+            ..ParseOptions::default()
+        };
+        let kinds: Vec<_> = tokenize(b"+Ook.-Ook.", &options)
+            .map(|token| (token.kind, token.span))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (TokenKind::Comment, 0..1),
+                (TokenKind::Command(b'+'), 1..5),
+                (TokenKind::Comment, 5..6),
+                (TokenKind::Command(b'+'), 6..10),
             ]
         );
     }
 
     #[test]
-    fn test_merge_ptr_ops() {
-        let code = b">>".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![Op::PtrAdd(2)]);
+    fn test_parse_debug_char() {
+        assert_eq!(parse(b">#").unwrap(), vec![Op::PtrAdd(1)]);
+        let options = ParseOptions {
+            debug_char: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_with(b">#+", &options).unwrap(),
+            vec![Op::PtrAdd(1), Op::Debug, Op::ValAdd(0, 1)]
+        );
+    }
 
-        let code = b">><<".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![]);
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parse_reader() {
+        use std::io::{self, Read};
+
+        /// Hands out a byte at a time, then fails if told to.
+        struct Trickle<'a>(&'a [u8], bool);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.split_first() {
+                    Some((&b, rest)) => {
+                        buf[0] = b;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    None if self.1 => Err(io::Error::other("gone")),
+                    None => Ok(0),
+                }
+            }
+        }
 
-        let code = b">>><".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![Op::PtrAdd(2)]);
+        for source in [
+            &b"++>+++++[<+>-] a comment, really.\n--<<>+>>"[..],
+            b"+++--->><<-+",
+            b"[[+]]]",
+            b"+\n [ [-]",
+            b"#!eof -1\n#!tape 9\n+-+\n#!-",
+            b"#-\n+",
+            b"",
+        ] {
+            assert_eq!(parse_reader(Trickle(source, false)), parse(source));
+            assert_eq!(parse_reader(source), parse(source));
+        }
+        assert_eq!(
+            parse_reader(Trickle(b"+.", true)),
+            Err(ParseError::Read {
+                offset: 2,
+                message: "gone".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let options = ParseOptions {
+            max_depth: Some(2),
+            max_size: Some(8),
+            ..ParseOptions::default()
+        };
+        assert!(parse_with(b"[[-]]", &options).is_ok());
+        let err = parse_with(b"[[\n[]]]", &options).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::TooDeep {
+                offset: 3,
+                line: 2,
+                column: 1
+            }
+        );
+        assert_eq!(err.offset(), Some(3));
+        let err = parse_with(b"+++++++++", &options).unwrap_err();
+        assert_eq!(err, ParseError::TooLarge { size: 9, limit: 8 });
+        assert_eq!(
+            err.to_string(),
+            "the program is 9 bytes, over the limit of 8"
+        );
+        assert_eq!(err.offset(), None);
+    }
+
+    #[test]
+    fn test_token_map() {
+        let mut map = TokenMap::new();
+        assert!(map.insert(b'+', "Ook."));
+        assert!(map.insert(b'[', "Ook. Ook?"));
+        assert!(map.insert(b']', "]]"));
+        assert!(!map.insert(b'-', "Ook."));
+        let options = ParseOptions {
+            tokens: Some(map.clone()),
+            ..ParseOptions::default()
+        };
+        let (ops, spans) = parse_with_spans(b"Ook. Ook? Ook.+ ]]", &options).unwrap();
+        assert_eq!(ops, vec![Op::Jz(2), Op::ValAdd(0, 1), Op::Jnz(0)]);
+        assert_eq!(spans, vec![0..9, 10..14, 16..18]);
+        assert_eq!(map.translate(b"Ook.Ook.] Ook. Ook?"), b"++[");
+
+        let err = parse_with(b"x ]]", &options).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::UnmatchedClose {
+                offset: 2,
+                line: 1,
+                column: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_token_map_from_text() {
+        let map = TokenMap::from_text("+ pi\n\n> pipi\n+  plus  \n").unwrap();
+        assert_eq!(map.translate(b"pipi pi plus"), b">++");
+        assert_eq!(
+            TokenMap::from_text("+ a\n?? b"),
+            Err(TokenMapError::UnknownCommand { line: 2 })
+        );
+        assert_eq!(
+            TokenMap::from_text("+"),
+            Err(TokenMapError::MissingToken { line: 1 })
+        );
+        assert_eq!(
+            TokenMap::from_text("+ a\n- a"),
+            Err(TokenMapError::DuplicateToken { line: 2 })
+        );
     }
 
     #[test]
-    fn test_merge_val_ops() {
-        let code = b"++".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![Op::BulkAdd(vec![(0, 2)])]);
-
-        let code = b"++--".to_vec();
-        let ops = parse(code);
-        let optimized = optimize(ops);
-        assert_eq!(optimized, vec![]);
+    fn test_parse_pbrain() {
+        let options = ParseOptions {
+            dialect: Dialect::Pbrain,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_with(b"+(>-):", &options).unwrap(),
+            vec![
+                Op::ValAdd(0, 1),
+                Op::DefProc(4),
+                Op::ValSub(1, 1),
+                Op::PtrAdd(1),
+                Op::EndProc,
+                Op::CallProc
+            ]
+        );
+        assert_eq!(parse(b"(:)").unwrap(), vec![]);
+        assert!(matches!(
+            parse_with(b"([)]", &options),
+            Err(ParseError::UnmatchedProcEnd { offset: 2, .. })
+        ));
+        let err = parse_with(b"[](", &options).unwrap_err();
+        assert_eq!(err.to_string(), "unmatched '(' at line 1, column 3");
+    }
+
+    #[test]
+    fn test_parse_brainfork() {
+        let options = ParseOptions {
+            dialect: Dialect::Brainfork,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_with(b">Y>Y", &options).unwrap(),
+            vec![Op::PtrAdd(1), Op::Fork, Op::PtrAdd(1), Op::Fork]
+        );
+        assert_eq!(parse(b"Y").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_parse_extended1() {
+        let options = ParseOptions {
+            dialect: Dialect::Extended1,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_with(b">$!{}~^&|@", &options).unwrap(),
+            vec![
+                Op::PtrAdd(1),
+                Op::Store,
+                Op::Retrieve,
+                Op::Bitwise(BitOp::ShiftLeft),
+                Op::Bitwise(BitOp::ShiftRight),
+                Op::Bitwise(BitOp::Not),
+                Op::Bitwise(BitOp::Xor),
+                Op::Bitwise(BitOp::And),
+                Op::Bitwise(BitOp::Or),
+                Op::End
+            ]
+        );
+        assert_eq!(parse(b"@$!").unwrap(), vec![]);
+        assert_eq!(BitOp::Not.apply(0x0f, 0), 0xffff_fff0);
+        assert_eq!(BitOp::And.apply(0x0f, 0x3c), 0x0c);
+    }
+
+    #[test]
+    fn test_parse_multitape() {
+        let options = ParseOptions {
+            dialect: Dialect::Multitape,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            parse_with(b"+vv>^+^^^", &options).unwrap(),
+            vec![
+                Op::ValAdd(0, 1),
+                Op::SwitchTape(2),
+                Op::PtrAdd(1),
+                Op::SwitchTape(-1),
+                Op::ValAdd(0, 1),
+                Op::SwitchTape(-3)
+            ]
+        );
+        assert_eq!(parse(b"v^").unwrap(), vec![]);
     }
 }
@@ -0,0 +1,225 @@
+//! Warnings about code that is valid but almost certainly not what was
+//! meant: loops that never end or never run, code that can't be reached,
+//! and a pointer that must leave the tape.
+//!
+//! The checks follow the program the way the optimizer's known-value
+//! analysis does, so they only report what holds on every run, whatever
+//! the input.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use crate::Op;
+use crate::optimizer::Known;
+
+/// A warning from [`lint`] about the source at `span`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    pub kind: LintKind,
+    pub span: Range<usize>,
+}
+
+/// What a [`Lint`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintKind {
+    /// A loop whose cell is always zero when it's reached, such as a
+    /// comment loop at the start of a program. The optimizer drops it.
+    DeadLoop,
+    /// A loop that's reached with a non-zero cell its body never changes.
+    EndlessLoop,
+    /// A loop whose body never changes its cell, such as `[]`, so it never
+    /// ends if it's entered.
+    EndlessIfEntered,
+    /// `[+]`, which only ends once the cell has counted up past its largest
+    /// value and wrapped around to zero.
+    WrappingClear,
+    /// Code after an [`EndlessLoop`](LintKind::EndlessLoop).
+    UnreachableCode,
+    /// A move or update left of the first cell, which every run makes.
+    PointerUnderflow,
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LintKind::DeadLoop => "loop never runs",
+            LintKind::EndlessLoop => "endless loop",
+            LintKind::EndlessIfEntered => "loop never ends once entered",
+            LintKind::WrappingClear => "loop only ends by wrapping its cell around",
+            LintKind::UnreachableCode => "unreachable code",
+            LintKind::PointerUnderflow => "the pointer leaves the tape",
+        })
+    }
+}
+
+/// Warnings about `ops`, unoptimized as [`parse_with_spans`] returns them
+/// with their `spans`, in the order they come up in the program.
+///
+/// [`parse_with_spans`]: crate::parse_with_spans
+pub fn lint(ops: &[Op], spans: &[Range<usize>]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut warn = |kind, span: Range<usize>| lints.push(Lint { kind, span });
+    let mut known = Known::at_start();
+    // Where the pointer is, while that's the same on every run.
+    let mut ptr = Some(0isize);
+    // Whether every run gets this far, each loop around here being known
+    // to be entered.
+    let mut certain = true;
+    // The `Jnz` of each loop the walk is in, and `certain` outside it.
+    let mut loops: Vec<(usize, bool)> = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let op = &ops[i];
+        match *op {
+            Op::Jz(end) => {
+                let body = &ops[i + 1..end];
+                let whole = spans[i].start..spans[end].end;
+                match known.get(0) {
+                    Some(0) => {
+                        warn(LintKind::DeadLoop, whole);
+                        i = end + 1;
+                        continue;
+                    }
+                    Some(_) if never_changes_cell(body) => {
+                        warn(LintKind::EndlessLoop, whole);
+                        // Nothing after it runs, up to the end of the loop
+                        // around it.
+                        let stop = loops.last().map_or(ops.len(), |&(end, _)| end);
+                        if end + 1 < stop {
+                            warn(
+                                LintKind::UnreachableCode,
+                                spans[end + 1].start..spans[stop - 1].end,
+                            );
+                        }
+                        i = stop;
+                        continue;
+                    }
+                    None if never_changes_cell(body) => {
+                        warn(LintKind::EndlessIfEntered, whole.clone())
+                    }
+                    _ => {}
+                }
+                if let [Op::ValAdd(0, _)] = body {
+                    warn(LintKind::WrappingClear, whole);
+                }
+                loops.push((end, certain));
+                certain &= known.get(0).is_some();
+                if !is_balanced(body) {
+                    ptr = None;
+                }
+            }
+            Op::Jnz(_) => {
+                if let Some((_, outside)) = loops.pop() {
+                    certain = outside;
+                }
+            }
+            Op::PtrAdd(n) => {
+                ptr = ptr.map(|ptr| ptr + n);
+                if certain && ptr.is_some_and(|ptr| ptr < 0) {
+                    warn(LintKind::PointerUnderflow, spans[i].clone());
+                    ptr = None;
+                }
+            }
+            Op::ValAdd(offset, _) | Op::ValSub(offset, _) => {
+                if certain && ptr.is_some_and(|ptr| ptr + offset < 0) {
+                    warn(LintKind::PointerUnderflow, spans[i].clone());
+                    ptr = None;
+                }
+            }
+            Op::Input | Op::Random | Op::Output | Op::Debug => {}
+            // Procedures, threads and other tapes move the pointer in
+            // ways a walk through the source can't follow.
+            _ => ptr = None,
+        }
+        known.apply(op);
+        i += 1;
+    }
+    lints
+}
+
+/// Whether a loop with `body` leaves the pointer where it found it, each
+/// loop inside it doing the same.
+fn is_balanced(body: &[Op]) -> bool {
+    let mut moved = alloc::vec![0isize];
+    for op in body {
+        match *op {
+            Op::PtrAdd(n) => *moved.last_mut().unwrap() += n,
+            Op::Jz(_) => moved.push(0),
+            Op::Jnz(_) => {
+                if moved.pop() != Some(0) {
+                    return false;
+                }
+            }
+            Op::ValAdd(..) | Op::ValSub(..) | Op::Input | Op::Random | Op::Output | Op::Debug => {}
+            _ => return false,
+        }
+    }
+    moved == [0]
+}
+
+/// Whether a loop with `body` comes back to its cell without changing it,
+/// so once entered it never ends.
+fn never_changes_cell(body: &[Op]) -> bool {
+    let mut at = 0isize;
+    for op in body {
+        match *op {
+            Op::PtrAdd(n) => at += n,
+            Op::ValAdd(offset, _) | Op::ValSub(offset, _) if at + offset != 0 => {}
+            Op::Input | Op::Random if at != 0 => {}
+            Op::Output | Op::Debug => {}
+            _ => return false,
+        }
+    }
+    at == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseOptions, parse_with_spans};
+
+    fn lints(source: &[u8]) -> Vec<(LintKind, &str)> {
+        let (ops, spans) = parse_with_spans(source, &ParseOptions::default()).unwrap();
+        lint(&ops, &spans)
+            .into_iter()
+            .map(|lint| {
+                let text = core::str::from_utf8(&source[lint.span.clone()]).unwrap();
+                (lint.kind, text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        // A comment loop at the start, and a clear right after a loop.
+        assert_eq!(
+            lints(b"[comment, really.]+[->+<][-]"),
+            [
+                (LintKind::DeadLoop, "[comment, really.]"),
+                (LintKind::DeadLoop, "[-]")
+            ]
+        );
+        // An endless loop and the code after it, up to the end of the loop
+        // around it.
+        assert_eq!(
+            lints(b",[[-]+[>.<]>,.]+"),
+            [
+                (LintKind::EndlessLoop, "[>.<]"),
+                (LintKind::UnreachableCode, ">,.")
+            ]
+        );
+        assert_eq!(
+            lints(b",[]>[+]"),
+            [
+                (LintKind::EndlessIfEntered, "[]"),
+                (LintKind::WrappingClear, "[+]")
+            ]
+        );
+        // Only a move every run makes underflows.
+        assert_eq!(lints(b",[<<+>>-]<<"), [(LintKind::PointerUnderflow, "<<")]);
+        assert_eq!(lints(b"+[-<+>]"), [(LintKind::PointerUnderflow, "+")]);
+        assert_eq!(lints(b",[<]<"), []);
+        assert_eq!(lints(b"++++++++[>++++++++<-]>+.,[.,]"), []);
+    }
+}
@@ -0,0 +1,353 @@
+//! Scrambling programs without changing what they do, for `bf obfuscate`.
+//!
+//! [`obfuscate`] writes a program's commands back out with pairs of
+//! commands that cancel each other, runs of `+` or `-` built with a
+//! multiplication loop and junk text in between, all drawn from a seeded
+//! [`Generator`], so the same seed scrambles a program the same way.
+
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{Generator, ParseError, ParseOptions, TokenKind, is_command, parse_with};
+
+/// How [`obfuscate`] scrambles a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObfuscateOptions {
+    /// Seeds the choices, so the same seed gives the same program.
+    pub seed: u64,
+    /// Cells on the tape the program runs on, or `None` if it grows. The
+    /// multiplication loops only borrow cells inside it.
+    pub tape_size: Option<usize>,
+}
+
+impl Default for ObfuscateOptions {
+    fn default() -> Self {
+        ObfuscateOptions {
+            seed: 0,
+            tape_size: Some(30_000),
+        }
+    }
+}
+
+/// The shortest run of `+` or `-` that may become a multiplication loop.
+const MIN_RUN: usize = 4;
+
+/// Where lines of the output wrap.
+const WIDTH: usize = 72;
+
+/// Rewrites `source`, parsed with `options`, as a program that does the
+/// same in the standard spelling but is hard to read. The program's own
+/// comments are dropped.
+///
+/// A run of `+` or `-` only becomes a loop where a neighbouring cell is
+/// known to be zero: while the pointer can be followed from the start of
+/// the program, through loops that end where they started.
+pub fn obfuscate(
+    source: &[u8],
+    options: &ParseOptions,
+    obfuscate: &ObfuscateOptions,
+) -> Result<Vec<u8>, ParseError> {
+    parse_with(source, options)?;
+    let output = ParseOptions {
+        tokens: None,
+        ..options.clone()
+    };
+    let junk: Vec<u8> = (b'a'..=b'z')
+        .chain(b'A'..=b'Z')
+        .chain(b'0'..=b'9')
+        .chain(*b"  ")
+        .filter(|&b| !is_command(b, &output))
+        .collect();
+    let commands: Vec<u8> = crate::tokenize(source, options)
+        .filter_map(|token| match token.kind {
+            TokenKind::Command(command) => Some(command),
+            TokenKind::Comment => None,
+        })
+        .collect();
+    let spans = loop_spans(&commands);
+    let mut out = Writer {
+        out: Vec::new(),
+        column: 0,
+        rng: Generator::new(obfuscate.seed),
+        junk,
+    };
+    let mut known = Some(Known {
+        ptr: 0,
+        dirty: BTreeSet::new(),
+    });
+    let mut depth = 0;
+    // The span of the outermost loop being written, if it ends where it
+    // started, once the pointer is followed into it.
+    let mut outer = None;
+    let mut i = 0;
+    while i < commands.len() {
+        let command = commands[i];
+        if depth == 0
+            && let Some(k) = known.as_mut()
+        {
+            match command {
+                b'+' | b'-' => {
+                    let run = commands[i..].iter().take_while(|&&c| c == command).count();
+                    let scratch = k.scratch(obfuscate.tape_size);
+                    k.dirty.insert(k.ptr);
+                    if let Some(scratch) = scratch.filter(|_| run >= MIN_RUN && out.chance(2)) {
+                        out.multiply(command, run, scratch > k.ptr);
+                        i += run;
+                        continue;
+                    }
+                }
+                b'>' | b'<' => {
+                    let ptr = if command == b'>' {
+                        k.ptr.checked_add(1)
+                    } else {
+                        k.ptr.checked_sub(1)
+                    };
+                    match ptr.filter(|&ptr| obfuscate.tape_size.is_none_or(|size| ptr < size)) {
+                        Some(ptr) => k.ptr = ptr,
+                        None => known = None,
+                    }
+                }
+                b',' => {
+                    k.dirty.insert(k.ptr);
+                }
+                b'.' => {}
+                b'[' => outer = Some(spans[i]),
+                _ => known = None,
+            }
+        }
+        match command {
+            b'[' | b'(' => depth += 1,
+            b']' | b')' => depth -= 1,
+            _ => {}
+        }
+        if depth == 0
+            && command == b']'
+            && let Some(span) = outer.take()
+        {
+            known = known.zip(span).and_then(|(mut k, (low, high))| {
+                let low = k.ptr.checked_add_signed(low)?;
+                let high = k.ptr.checked_add_signed(high)?;
+                if obfuscate.tape_size.is_some_and(|size| high >= size) {
+                    return None;
+                }
+                k.dirty.extend(low..=high);
+                // The loop only ends on a zero cell.
+                k.dirty.remove(&k.ptr);
+                Some(k)
+            });
+        }
+        out.command(command);
+        i += 1;
+    }
+    if out.column > 0 {
+        out.out.push(b'\n');
+    }
+    Ok(out.out)
+}
+
+/// What is known about the tape while the pointer can be followed.
+struct Known {
+    ptr: usize,
+    /// The cells that may not be zero.
+    dirty: BTreeSet<usize>,
+}
+
+impl Known {
+    /// A neighbour of the current cell that is zero, if there is one.
+    fn scratch(&self, tape_size: Option<usize>) -> Option<usize> {
+        let right = self
+            .ptr
+            .checked_add(1)
+            .filter(|&cell| tape_size.is_none_or(|size| cell < size));
+        [right, self.ptr.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .find(|cell| !self.dirty.contains(cell))
+    }
+}
+
+/// For each `[` outside loops in `commands`, the cells its loop touches
+/// relative to the cell it starts on, if it and every loop inside it end
+/// each pass where they started.
+fn loop_spans(commands: &[u8]) -> Vec<Option<(isize, isize)>> {
+    let mut spans = vec![None; commands.len()];
+    // The outermost open loop's `[` and its span so far.
+    let mut outer = None;
+    let mut span = None;
+    // The pointer offset from the outermost loop's cell, and what it was at
+    // each open `[`.
+    let mut ptr = 0isize;
+    let mut open = Vec::new();
+    for (i, &command) in commands.iter().enumerate() {
+        match command {
+            b'[' => {
+                if open.is_empty() {
+                    outer = Some(i);
+                    span = Some((0, 0));
+                    ptr = 0;
+                }
+                open.push(ptr);
+            }
+            b']' => {
+                if open.pop() != Some(ptr) {
+                    span = None;
+                }
+                if open.is_empty()
+                    && let Some(outer) = outer.take()
+                {
+                    spans[outer] = span;
+                }
+            }
+            b'>' => ptr += 1,
+            b'<' => ptr -= 1,
+            b'+' | b'-' | b',' | b'.' => {}
+            _ => span = None,
+        }
+        span = span.map(|(low, high)| (low.min(ptr), high.max(ptr)));
+    }
+    spans
+}
+
+/// The scrambled program being written.
+struct Writer {
+    out: Vec<u8>,
+    column: usize,
+    rng: Generator,
+    /// The bytes junk text is made of.
+    junk: Vec<u8>,
+}
+
+impl Writer {
+    /// One in `n` times.
+    fn chance(&mut self, n: u64) -> bool {
+        self.rng.below(n) == 0
+    }
+
+    /// Writes `command`, and sometimes a pair of commands that cancel out
+    /// or junk after it.
+    fn command(&mut self, command: u8) {
+        self.push(command);
+        if self.chance(8) {
+            // Moving back to the cell just left can't leave the tape.
+            let pair = match command {
+                b'>' => b"<>",
+                b'<' => b"><",
+                _ if self.chance(2) => b"+-",
+                _ => b"-+",
+            };
+            self.push(pair[0]);
+            self.push(pair[1]);
+        }
+        if !self.junk.is_empty() && self.chance(4) {
+            for _ in 0..=self.rng.below(6) {
+                let b = self.junk[self.rng.below(self.junk.len() as u64) as usize];
+                self.push(b);
+            }
+        }
+    }
+
+    /// Writes `run` times `command` as a loop on the zero cell to the right
+    /// of the current one, or to its left, that adds a multiple of it.
+    fn multiply(&mut self, command: u8, run: usize, right: bool) {
+        let (there, back) = if right { (b'>', b'<') } else { (b'<', b'>') };
+        let count = 2 + self.rng.below(run as u64 / 2 - 1) as usize;
+        self.command(there);
+        for _ in 0..count {
+            self.command(b'+');
+        }
+        self.command(b'[');
+        self.command(back);
+        for _ in 0..run / count {
+            self.command(command);
+        }
+        self.command(there);
+        self.command(b'-');
+        self.command(b']');
+        self.command(back);
+        for _ in 0..run % count {
+            self.command(command);
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        // Lines only break between commands and junk, and not to start a
+        // line with a space.
+        if self.column >= WIDTH && b != b' ' {
+            self.out.push(b'\n');
+            self.column = 0;
+        }
+        if self.column == 0 && b == b' ' {
+            return;
+        }
+        self.out.push(b);
+        self.column += 1;
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+    use crate::testgen::GenOptions;
+    use crate::{Config, reference};
+
+    fn run(code: &[u8], input: &[u8], max_steps: u64) -> Option<(Vec<u8>, Vec<u8>, usize)> {
+        let config = Config {
+            tape_size: 64,
+            max_steps: Some(max_steps),
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        let (tape, ptr) = reference::run::<u8, _, _>(code, &config, input, &mut output).ok()?;
+        Some((output, tape, ptr))
+    }
+
+    #[test]
+    fn test_obfuscate() {
+        let hello = include_bytes!("../examples/helloworld.bf");
+        let options = ObfuscateOptions::default();
+        let code = obfuscate(hello, &ParseOptions::default(), &options).unwrap();
+        assert_eq!(run(&code, b"", 1_000_000), run(hello, b"", 1_000_000));
+        assert_ne!(
+            code,
+            crate::format::minify(&crate::format::pieces(hello, &ParseOptions::default()))
+        );
+        assert!(code.len() > hello.len());
+        let again = obfuscate(hello, &ParseOptions::default(), &options).unwrap();
+        assert_eq!(code, again);
+
+        // A constant at the start is built with a loop on the next cell.
+        let code = obfuscate(b"++++++++++++.", &ParseOptions::default(), &options).unwrap();
+        assert!(code.contains(&b'['));
+        assert_eq!(run(&code, b"", 1_000_000), run(b"++++++++++++.", b"", 100));
+    }
+
+    #[test]
+    fn test_obfuscate_random() {
+        let gen_options = GenOptions {
+            len: 80,
+            max_depth: 3,
+            io_percent: 10,
+        };
+        let mut generator = Generator::new(3);
+        for seed in 0..200 {
+            let program = generator.program(&gen_options);
+            let Some(expected) = run(&program, b"\x05\x03\x02", 10_000) else {
+                continue;
+            };
+            let options = ObfuscateOptions {
+                seed,
+                tape_size: Some(64),
+            };
+            let code = obfuscate(&program, &ParseOptions::default(), &options).unwrap();
+            assert_eq!(
+                run(&code, b"\x05\x03\x02", 1_000_000).as_ref(),
+                Some(&expected),
+                "{}",
+                String::from_utf8_lossy(&program)
+            );
+        }
+    }
+}
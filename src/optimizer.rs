@@ -0,0 +1,2315 @@
+//! The optimization pipeline.
+//!
+//! Each transformation is a [`Pass`] over the op stream, such as one of the
+//! [`BuiltinPass`]es; a [`Pipeline`] runs an ordered list of them, and
+//! [`OptLevel`] picks the standard lists.
+//!
+//! Every pass also carries the source range of each op, as
+//! [`parse_with_spans`](crate::parse_with_spans) reports them, through to
+//! its output: an op that replaces several covers all of their ranges, so a
+//! folded loop points at the whole loop.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+mod known;
+
+pub(crate) use known::Known;
+
+use crate::{BoundsPolicy, Op};
+
+/// How hard [`optimize_with`] works on a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    /// No passes: ops execute exactly as parsed (runs of `+-<>` are still
+    /// folded by the parser).
+    O0,
+    /// Cheap local rewrites: clear and scan loops, op merging.
+    O1,
+    /// Everything that is always profitable. This is what [`optimize`] does.
+    #[default]
+    O2,
+    /// Every pass, including ones that are expensive to run. The `bfi` and
+    /// `bfc` front ends also [`partial_eval`](crate::partial_eval) the
+    /// program at this level.
+    O3,
+}
+
+impl OptLevel {
+    /// Parses `0`..`3`, with or without a leading `O`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.strip_prefix('O').unwrap_or(name) {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            "3" => Some(OptLevel::O3),
+            _ => None,
+        }
+    }
+
+    /// The passes this level runs, in order.
+    pub fn passes(self) -> &'static [BuiltinPass] {
+        use BuiltinPass::*;
+        match self {
+            OptLevel::O0 => &[],
+            OptLevel::O1 => &[Clear, Scan, Merge],
+            OptLevel::O2 | OptLevel::O3 => &[
+                DivMod,
+                Clear,
+                Scan,
+                MoveLoop,
+                DeadCode,
+                Merge,
+                Offset,
+                Set,
+                DeadStore,
+                ConstOutput,
+                Bulk,
+            ],
+        }
+    }
+}
+
+/// Which transformations a [`Pipeline`] may make.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Semantics {
+    /// Whatever gives the same output and tape for a run that stays on the
+    /// tape. A run that leaves it may trap at another command, or not at
+    /// all: [`BuiltinPass::DeadCode`] drops a loop that would have left
+    /// it, and [`BuiltinPass::MoveLoop`] touches the target before the
+    /// counter.
+    #[default]
+    Fast,
+    /// Only what also keeps every run that leaves the tape trapping at the
+    /// same command, with the same output and tape: [`BuiltinPass::Clear`],
+    /// [`BuiltinPass::Scan`] and [`BuiltinPass::Offset`], which touch the
+    /// same cells in the same order. With
+    /// [`ParseOptions::semantics`](crate::ParseOptions::semantics) set
+    /// too, the parser keeps adds that cancel out, like `+-`, so the
+    /// interpreter can serve as a reference.
+    Strict,
+}
+
+impl Semantics {
+    /// Whether `pass` is sound under these semantics.
+    pub fn allows(self, pass: BuiltinPass) -> bool {
+        match self {
+            Semantics::Fast => true,
+            Semantics::Strict => matches!(
+                pass,
+                BuiltinPass::Clear | BuiltinPass::Scan | BuiltinPass::Offset
+            ),
+        }
+    }
+}
+
+/// One of the optimizer's transformations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinPass {
+    /// `[-]` and `[+]` (any odd step) become `Clear`.
+    Clear,
+    /// `[<]` and `[>]` become `ScanLeft` / `ScanRight`, and wider strides
+    /// like `[>>]` become `Scan`. Sweeps that clear as they go, like
+    /// `[[-]>]`, become `ClearUntilZero`.
+    Scan,
+    /// Balanced loops like `[->+<]` become `MulAdd`s and a `Clear`, and so
+    /// do ones whose counter steps by any odd amount, like `[--->+<]`; a
+    /// factor of 1 or -1 makes an `AddTo` or `SubFrom` instead of a
+    /// `MulAdd`. One stepping by an even amount becomes adds and a `Clear`
+    /// where its counter is known to count down to zero.
+    MoveLoop,
+    /// Drops loops that can never be entered and redundant clears, and
+    /// works out loops run from a known counter, tracking the values
+    /// straight-line code leaves in cells.
+    DeadCode,
+    /// Merges adjacent pointer moves and adjacent adds to the same cell,
+    /// and runs of `.` on one cell, like `....`, into an `OutputRepeat`.
+    Merge,
+    /// Carries pointer moves through straight-line code as op offsets, so
+    /// `>.>.<<` is two `OutputAt`s and no pointer traffic.
+    Offset,
+    /// Folds adds into a preceding clear, so `[-]+++` is one `Set`.
+    Set,
+    /// Drops writes to a cell that straight-line code overwrites before
+    /// anything reads it, like the `+` of `+[-]`.
+    DeadStore,
+    /// Batches straight-line adds and clears into `BulkAdd` / `BulkClear`,
+    /// and clears of neighbouring cells, like `[-]>[-]>[-]>[-]`, into
+    /// `ClearRange`. A move loop with several targets, its multiplications
+    /// and the clear of its counter, becomes one `Transfer`.
+    Bulk,
+    /// Folds runs of `.` on cells whose value is known, such as `+++.+.`,
+    /// into one `OutputConst`.
+    ConstOutput,
+    /// Puts a `DivMod` before each divmod loop,
+    /// `[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]`, so it is done in one step where
+    /// it can be. Runs first, while the loop is still as written.
+    DivMod,
+}
+
+impl BuiltinPass {
+    pub const ALL: [BuiltinPass; 11] = [
+        BuiltinPass::Clear,
+        BuiltinPass::Scan,
+        BuiltinPass::MoveLoop,
+        BuiltinPass::DeadCode,
+        BuiltinPass::Merge,
+        BuiltinPass::Offset,
+        BuiltinPass::Set,
+        BuiltinPass::DeadStore,
+        BuiltinPass::Bulk,
+        BuiltinPass::ConstOutput,
+        BuiltinPass::DivMod,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BuiltinPass::Clear => "clear",
+            BuiltinPass::Scan => "scan",
+            BuiltinPass::MoveLoop => "move-loop",
+            BuiltinPass::DeadCode => "dce",
+            BuiltinPass::Merge => "rle",
+            BuiltinPass::Offset => "offset",
+            BuiltinPass::Set => "set",
+            BuiltinPass::DeadStore => "dse",
+            BuiltinPass::Bulk => "bulk",
+            BuiltinPass::ConstOutput => "const-output",
+            BuiltinPass::DivMod => "divmod",
+        }
+    }
+
+    /// One line on what the pass does.
+    pub fn description(self) -> &'static str {
+        match self {
+            BuiltinPass::Clear => "turns clear loops like [-] into Clear",
+            BuiltinPass::Scan => {
+                "turns scan loops like [<] and [>>], and sweeps like [[-]>], into scans"
+            }
+            BuiltinPass::MoveLoop => "turns move loops like [->+<] into MulAdds",
+            BuiltinPass::DeadCode => "drops code known to do nothing, and evaluates known loops",
+            BuiltinPass::Merge => "merges adjacent moves and adds, and repeated outputs",
+            BuiltinPass::Offset => "carries pointer moves through straight-line code as offsets",
+            BuiltinPass::Set => "folds adds into the clear before them",
+            BuiltinPass::DeadStore => "drops writes that are overwritten before being read",
+            BuiltinPass::Bulk => {
+                "batches adds and clears into BulkAdd, BulkClear and ClearRange, and move loops into Transfers"
+            }
+            BuiltinPass::ConstOutput => "prints cells with known values as OutputConst",
+            BuiltinPass::DivMod => "does divmod loops in one step with DivMod",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    /// Whether the pass is sound on a tape with these `bounds`. Under
+    /// [`Wrap`](BoundsPolicy::Wrap), offsets a tape's length apart name
+    /// the same cell, which the passes that track cells by offset don't
+    /// know, so only the ones [`Semantics::Strict`] allows are. Under
+    /// [`Clamp`](BoundsPolicy::Clamp), every offset past an end names the
+    /// cell there, and a move clamped in two steps can end elsewhere than
+    /// one clamped as a whole, so only [`BuiltinPass::Clear`] and
+    /// [`BuiltinPass::Scan`], which move nothing, are.
+    pub fn sound_under(self, bounds: BoundsPolicy) -> bool {
+        match bounds {
+            BoundsPolicy::Wrap => Semantics::Strict.allows(self),
+            BoundsPolicy::Clamp => matches!(self, BuiltinPass::Clear | BuiltinPass::Scan),
+            _ => true,
+        }
+    }
+
+    pub fn run(self, ops: Vec<Op>) -> Vec<Op> {
+        let spans = vec![0..0; ops.len()];
+        self.run_with_spans(ops, spans).0
+    }
+
+    /// Like [`run`](Self::run), also mapping `spans`, the source range of
+    /// each op, to the ops the pass returns.
+    ///
+    /// # Panics
+    ///
+    /// If there isn't one span per op.
+    pub fn run_with_spans(
+        self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+    ) -> (Vec<Op>, Vec<Range<usize>>) {
+        assert_eq!(ops.len(), spans.len(), "every op needs a span");
+        match self {
+            BuiltinPass::Clear => rewrite_loops(ops, spans, |body, _| check_clear_loop(body)),
+            BuiltinPass::Scan => rewrite_loops(ops, spans, |body, _| {
+                check_scan_loop(body).map(|op| vec![op])
+            }),
+            BuiltinPass::MoveLoop => rewrite_loops(ops, spans, |body, start| {
+                check_move_loop(body, start).map(|mut ops| {
+                    // Move loop implicitly ends with Clear(0)
+                    ops.push(Op::Clear(0));
+                    ops
+                })
+            }),
+            BuiltinPass::DeadCode => optimize_dce(ops, spans),
+            BuiltinPass::Merge => optimize_merge(ops, spans),
+            BuiltinPass::Offset => optimize_offsets(ops, spans),
+            BuiltinPass::Set => optimize_set(ops, spans),
+            BuiltinPass::DeadStore => optimize_dead_stores(ops, spans),
+            BuiltinPass::Bulk => optimize_bulk(ops, spans),
+            BuiltinPass::ConstOutput => optimize_const_output(ops, spans),
+            BuiltinPass::DivMod => rewrite_loops(ops, spans, |body, _| check_div_mod_loop(body)),
+        }
+    }
+}
+
+/// A transformation a [`Pipeline`] can run: one of the [`BuiltinPass`]es,
+/// or one of your own.
+///
+/// A pass must keep the program doing what it did, and the jumps of its
+/// loops pointing at each other, which [`verify`] checks.
+pub trait Pass {
+    /// A short name, for messages about the pass.
+    fn name(&self) -> &str;
+
+    /// One line on what the pass does.
+    fn description(&self) -> &str {
+        ""
+    }
+
+    fn run(&self, ops: Vec<Op>) -> Vec<Op>;
+
+    /// Like [`run`](Self::run), also mapping `spans`, the source range of
+    /// each op, to the ops the pass returns. By default, every op returned
+    /// covers the whole of the source the input did.
+    fn run_with_spans(
+        &self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+    ) -> (Vec<Op>, Vec<Range<usize>>) {
+        let mut whole = None;
+        for span in &spans {
+            cover(&mut whole, span);
+        }
+        let ops = self.run(ops);
+        let spans = vec![whole.unwrap_or(0..0); ops.len()];
+        (ops, spans)
+    }
+}
+
+impl Pass for BuiltinPass {
+    fn name(&self) -> &str {
+        BuiltinPass::name(*self)
+    }
+
+    fn description(&self) -> &str {
+        BuiltinPass::description(*self)
+    }
+
+    fn run(&self, ops: Vec<Op>) -> Vec<Op> {
+        BuiltinPass::run(*self, ops)
+    }
+
+    fn run_with_spans(
+        &self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+    ) -> (Vec<Op>, Vec<Range<usize>>) {
+        BuiltinPass::run_with_spans(*self, ops, spans)
+    }
+}
+
+/// An ordered list of passes.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    passes: Vec<Arc<dyn Pass + Send + Sync>>,
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.passes.iter().map(|pass| pass.name()))
+            .finish()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline::default()
+    }
+
+    pub fn for_level(level: OptLevel) -> Self {
+        let mut pipeline = Pipeline::new();
+        for &pass in level.passes() {
+            pipeline.push(pass);
+        }
+        pipeline
+    }
+
+    /// The passes of `level` that are sound for code that may start on a
+    /// non-zero cell, such as one loop of a larger program or a line typed
+    /// into a REPL: all but [`BuiltinPass::DeadCode`] and
+    /// [`BuiltinPass::ConstOutput`], which assume the tape starts out
+    /// zeroed.
+    pub fn for_fragment(level: OptLevel) -> Self {
+        let mut pipeline = Pipeline::new();
+        for &pass in level.passes() {
+            if !matches!(pass, BuiltinPass::DeadCode | BuiltinPass::ConstOutput) {
+                pipeline.push(pass);
+            }
+        }
+        pipeline
+    }
+
+    /// The passes of `level` that `semantics` allows.
+    pub fn for_semantics(level: OptLevel, semantics: Semantics) -> Self {
+        let mut pipeline = Pipeline::new();
+        for &pass in level.passes() {
+            if semantics.allows(pass) {
+                pipeline.push(pass);
+            }
+        }
+        pipeline
+    }
+
+    /// The passes of `level` that are sound under `bounds`.
+    pub fn for_bounds(level: OptLevel, bounds: BoundsPolicy) -> Self {
+        let mut pipeline = Pipeline::new();
+        for &pass in level.passes() {
+            if pass.sound_under(bounds) {
+                pipeline.push(pass);
+            }
+        }
+        pipeline
+    }
+
+    /// Adds `pass` to the end.
+    pub fn push(&mut self, pass: impl Pass + Send + Sync + 'static) -> &mut Self {
+        self.passes.push(Arc::new(pass));
+        self
+    }
+
+    /// Adds `pass` before the one at `index`, so custom passes can go
+    /// between the built-in ones of a level.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is past the end.
+    pub fn insert(&mut self, index: usize, pass: impl Pass + Send + Sync + 'static) -> &mut Self {
+        self.passes.insert(index, Arc::new(pass));
+        self
+    }
+
+    /// Takes out the passes named `name`.
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.passes.retain(|pass| pass.name() != name);
+        self
+    }
+
+    /// The passes, in the order they run.
+    pub fn passes(&self) -> impl Iterator<Item = &dyn Pass> {
+        self.passes.iter().map(|pass| &**pass as &dyn Pass)
+    }
+
+    /// Runs the passes in order. In debug builds, each pass's output is
+    /// [`verify`]d, unless the input already failed to.
+    pub fn run(&self, ops: Vec<Op>) -> Vec<Op> {
+        let spans = vec![0..0; ops.len()];
+        self.run_with_spans(ops, spans).0
+    }
+
+    /// Like [`run`](Self::run), also mapping `spans`, the source range of
+    /// each op, through every pass.
+    ///
+    /// # Panics
+    ///
+    /// If there isn't one span per op.
+    pub fn run_with_spans(
+        &self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+    ) -> (Vec<Op>, Vec<Range<usize>>) {
+        self.run_observed(ops, spans, None)
+    }
+
+    /// Like [`run_with_spans`](Self::run_with_spans), also reporting what
+    /// each pass did.
+    pub fn run_with_stats(
+        &self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+    ) -> (Vec<Op>, Vec<Range<usize>>, Vec<PassStats>) {
+        let mut stats = Vec::new();
+        let (ops, spans) = self.run_observed(ops, spans, Some(&mut stats));
+        (ops, spans, stats)
+    }
+
+    /// Runs the passes, adding what each did to `stats` if there are any.
+    fn run_observed(
+        &self,
+        ops: Vec<Op>,
+        spans: Vec<Range<usize>>,
+        mut stats: Option<&mut Vec<PassStats>>,
+    ) -> (Vec<Op>, Vec<Range<usize>>) {
+        let checked = cfg!(debug_assertions) && verify(&ops).is_ok();
+        self.passes.iter().fold((ops, spans), |(ops, spans), pass| {
+            let before = stats.is_some().then(|| ops.clone());
+            let (ops, spans) = pass.run_with_spans(ops, spans);
+            if checked && let Err(e) = verify(&ops) {
+                panic!("the {} pass broke the program: {}", pass.name(), e);
+            }
+            if let (Some(stats), Some(before)) = (stats.as_deref_mut(), before) {
+                stats.push(PassStats::new(pass.name(), &before, &ops));
+            }
+            (ops, spans)
+        })
+    }
+}
+
+/// What one pass of a [`Pipeline`] did, as
+/// [`run_with_stats`](Pipeline::run_with_stats) reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: String,
+    /// Ops before and after the pass.
+    pub ops: (usize, usize),
+    /// Loops before and after the pass.
+    pub loops: (usize, usize),
+    /// For each kind of op whose count the pass changed, by the name
+    /// [`fmt_ops`](crate::fmt_ops) shows it under, how many more of it
+    /// there are after the pass.
+    pub kinds: Vec<(&'static str, isize)>,
+}
+
+impl PassStats {
+    fn new(name: &str, before: &[Op], after: &[Op]) -> Self {
+        let mut kinds: BTreeMap<&'static str, isize> = BTreeMap::new();
+        for op in before {
+            *kinds.entry(crate::op_name(op)).or_default() -= 1;
+        }
+        for op in after {
+            *kinds.entry(crate::op_name(op)).or_default() += 1;
+        }
+        let loops = |ops: &[Op]| ops.iter().filter(|op| matches!(op, Op::Jz(_))).count();
+        PassStats {
+            name: name.into(),
+            ops: (before.len(), after.len()),
+            loops: (loops(before), loops(after)),
+            kinds: kinds.into_iter().filter(|&(_, n)| n != 0).collect(),
+        }
+    }
+}
+
+/// Optimizes at the default level, [`OptLevel::O2`].
+pub fn optimize(ops: Vec<Op>) -> Vec<Op> {
+    optimize_with(ops, OptLevel::default())
+}
+
+pub fn optimize_with(ops: Vec<Op>, level: OptLevel) -> Vec<Op> {
+    Pipeline::for_level(level).run(ops)
+}
+
+/// Like [`optimize_with`], also mapping the source range of each op, as
+/// [`parse_with_spans`](crate::parse_with_spans) returns them, to the
+/// optimized ops.
+pub fn optimize_with_spans(
+    ops: Vec<Op>,
+    spans: Vec<Range<usize>>,
+    level: OptLevel,
+) -> (Vec<Op>, Vec<Range<usize>>) {
+    Pipeline::for_level(level).run_with_spans(ops, spans)
+}
+
+/// The smallest range covering both `a` and `b`.
+fn join(a: &Range<usize>, b: &Range<usize>) -> Range<usize> {
+    a.start.min(b.start)..a.end.max(b.end)
+}
+
+/// Widens `range` to cover `span` too, or starts it there.
+fn cover(range: &mut Option<Range<usize>>, span: &Range<usize>) {
+    *range = Some(
+        range
+            .as_ref()
+            .map_or(span.clone(), |range| join(range, span)),
+    );
+}
+
+/// What a pass emits: ops and, in step with them, the source range each
+/// came from.
+#[derive(Default)]
+struct Emitter {
+    ops: Vec<Op>,
+    spans: Vec<Range<usize>>,
+}
+
+impl Emitter {
+    fn push(&mut self, op: Op, span: Range<usize>) {
+        self.ops.push(op);
+        self.spans.push(span);
+    }
+
+    /// Drops the last op and returns its span.
+    fn pop(&mut self) -> Option<Range<usize>> {
+        self.ops.pop();
+        self.spans.pop()
+    }
+
+    /// Widens the span of the op at `i` to cover `span` too, for an op
+    /// that has been folded into it.
+    fn widen(&mut self, i: usize, span: &Range<usize>) {
+        self.spans[i] = join(&self.spans[i], span);
+    }
+
+    fn widen_last(&mut self, span: &Range<usize>) {
+        if let Some(last) = self.spans.len().checked_sub(1) {
+            self.widen(last, span);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Links the jumps and returns the ops and spans.
+    fn finish(mut self) -> (Vec<Op>, Vec<Range<usize>>) {
+        link_jumps(&mut self.ops);
+        (self.ops, self.spans)
+    }
+}
+
+/// Recomputes every `Jz`/`Jnz` and `DefProc` target from the bracket
+/// structure, so passes can emit placeholder targets.
+pub(crate) fn link_jumps(ops: &mut [Op]) {
+    let mut loop_stack = Vec::new();
+    for i in 0..ops.len() {
+        match ops[i] {
+            Op::Jz(_) | Op::DefProc(_) => loop_stack.push(i),
+            Op::Jnz(_) => {
+                let start = loop_stack.pop().expect("Optimizer: Unmatched ']'");
+                ops[start] = Op::Jz(i);
+                ops[i] = Op::Jnz(start);
+            }
+            Op::EndProc => {
+                let start = loop_stack.pop().expect("Optimizer: Unmatched ')'");
+                ops[start] = Op::DefProc(i);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A broken loop found by [`verify`]. Ops are given by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The jump at `pc` targets `target`, past the end of the program.
+    OutOfBounds { pc: usize, target: usize },
+    /// The loop from `open` to `close` doesn't jump between its ends.
+    Mismatched { open: usize, close: usize },
+    /// The `Jz` at `pc` has no `Jnz`.
+    UnmatchedOpen { pc: usize },
+    /// The `Jnz` at `pc` has no `Jz`.
+    UnmatchedClose { pc: usize },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            VerifyError::OutOfBounds { pc, target } => {
+                write!(f, "op {} jumps to {}, past the end", pc, target)
+            }
+            VerifyError::Mismatched { open, close } => write!(
+                f,
+                "ops {} and {} enclose a loop but don't jump to each other",
+                open, close
+            ),
+            VerifyError::UnmatchedOpen { pc } => write!(f, "the loop at op {} never ends", pc),
+            VerifyError::UnmatchedClose { pc } => {
+                write!(f, "op {} ends a loop that never started", pc)
+            }
+        }
+    }
+}
+
+impl core::error::Error for VerifyError {}
+
+/// Checks that `ops` nest their loops properly and that every `Jz` targets
+/// its `Jnz` and back, which the interpreter and the code generators rely
+/// on. Procedure definitions count as loops, and must target their
+/// `EndProc`.
+pub fn verify(ops: &[Op]) -> Result<(), VerifyError> {
+    let mut open = Vec::new();
+    for (pc, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Jz(target) | Op::Jnz(target) | Op::DefProc(target) if target >= ops.len() => {
+                return Err(VerifyError::OutOfBounds { pc, target });
+            }
+            Op::Jz(_) | Op::DefProc(_) => open.push(pc),
+            Op::Jnz(target) => {
+                let start = open.pop().ok_or(VerifyError::UnmatchedClose { pc })?;
+                if target != start || ops[start] != Op::Jz(pc) {
+                    return Err(VerifyError::Mismatched {
+                        open: start,
+                        close: pc,
+                    });
+                }
+            }
+            Op::EndProc => {
+                let start = open.pop().ok_or(VerifyError::UnmatchedClose { pc })?;
+                if ops[start] != Op::DefProc(pc) {
+                    return Err(VerifyError::Mismatched {
+                        open: start,
+                        close: pc,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    match open.pop() {
+        Some(pc) => Err(VerifyError::UnmatchedOpen { pc }),
+        None => Ok(()),
+    }
+}
+
+/// Replaces each loop whose body `rewrite` recognizes with the ops it
+/// returns, along with the value of the cell the loop starts on if
+/// straight-line code before it has set it. Nothing is known of the tape
+/// to start with, so that fragments can be optimized.
+///
+/// Inner loops are rewritten first, so an outer loop is checked with the
+/// ops they became: once `[->+<]` is a `MulAdd`, a loop around it may be a
+/// move loop too.
+fn rewrite_loops(
+    ops: Vec<Op>,
+    spans: Vec<Range<usize>>,
+    rewrite: impl Fn(&[Op], Option<u32>) -> Option<Vec<Op>>,
+) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+    let mut known = Known::unknown();
+    // Where each open loop's `Jz` was emitted, and what was known before it.
+    let mut open = Vec::new();
+
+    for (op, span) in ops.into_iter().zip(spans) {
+        match op {
+            Op::Jz(_) => {
+                open.push((out.ops.len(), known.clone()));
+                known.apply(&op);
+                out.push(Op::Jz(0), span);
+            }
+            Op::Jnz(_) => {
+                let replacement = open.pop().and_then(|(start, before)| {
+                    let ops = rewrite(&out.ops[start + 1..], before.get(0))?;
+                    Some((start, before, ops))
+                });
+                if let Some((start, before, replacement)) = replacement {
+                    // The replacement stands for the whole loop.
+                    let span = join(&out.spans[start], &span);
+                    out.ops.truncate(start);
+                    out.spans.truncate(start);
+                    known = before;
+                    for op in replacement {
+                        known.apply(&op);
+                        out.push(op, span.clone());
+                    }
+                } else {
+                    known.apply(&op);
+                    out.push(Op::Jnz(0), span);
+                }
+            }
+            other => {
+                known.apply(&other);
+                out.push(other, span);
+            }
+        }
+    }
+
+    out.finish()
+}
+
+/// The body of the divmod loop as parsed, with its jumps unlinked.
+const DIV_MOD_BODY: [Op; 19] = [
+    Op::ValSub(0, 1),
+    Op::ValAdd(1, 1),
+    Op::ValSub(2, 1),
+    Op::PtrAdd(2),
+    Op::Jz(0),
+    Op::ValAdd(1, 1),
+    Op::PtrAdd(3),
+    Op::Jnz(0),
+    Op::PtrAdd(1),
+    Op::Jz(0),
+    Op::ValAdd(0, 1),
+    Op::Jz(0),
+    Op::ValSub(0, 1),
+    Op::ValAdd(-1, 1),
+    Op::Jnz(0),
+    Op::ValAdd(1, 1),
+    Op::PtrAdd(3),
+    Op::Jnz(0),
+    Op::PtrAdd(-6),
+];
+
+/// The divmod loop, kept as it is after a `DivMod` for when that can't
+/// do it.
+fn check_div_mod_loop(body: &[Op]) -> Option<Vec<Op>> {
+    let same = body.len() == DIV_MOD_BODY.len()
+        && body.iter().zip(&DIV_MOD_BODY).all(|pair| match pair {
+            (Op::Jz(_), Op::Jz(_)) | (Op::Jnz(_), Op::Jnz(_)) => true,
+            (op, expected) => op == expected,
+        });
+    if !same {
+        return None;
+    }
+    let mut ops = vec![Op::DivMod, Op::Jz(0)];
+    ops.extend(body.iter().map(|op| match op {
+        Op::Jz(_) => Op::Jz(0),
+        Op::Jnz(_) => Op::Jnz(0),
+        op => op.clone(),
+    }));
+    ops.push(Op::Jnz(0));
+    Some(ops)
+}
+
+fn check_clear_loop(body: &[Op]) -> Option<Vec<Op>> {
+    // An odd step visits every residue, so the loop always reaches zero,
+    // and one that clears its own cell, like `[-[-]]`, stops after a pass.
+    let effects = pass_effects(body)?;
+    match effects.get(&0) {
+        Some(Effect::Add(n)) if n % 2 == 1 && effects.len() == 1 => Some(vec![Op::Clear(0)]),
+        Some(Effect::Assign(0)) if effects.len() == 1 => Some(vec![Op::Clear(0)]),
+        _ => None,
+    }
+}
+
+fn optimize_bulk(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+
+    // The adds and clears not emitted yet, and the range they came from,
+    // which is `None` while there are none.
+    let mut pending_adds: BTreeMap<isize, u32> = BTreeMap::new();
+    let mut adds_span: Option<Range<usize>> = None;
+
+    let mut pending_clears: Vec<isize> = Vec::new();
+    let mut clears_span: Option<Range<usize>> = None;
+
+    // The multiplications of a move loop, kept back in case the clear of
+    // its counter follows.
+    let mut pending_muls: Vec<(Op, Range<usize>)> = Vec::new();
+
+    fn flush_muls(out: &mut Emitter, muls: &mut Vec<(Op, Range<usize>)>) {
+        for (op, span) in core::mem::take(muls) {
+            out.push(op, span);
+        }
+    }
+
+    fn flush_adds(
+        out: &mut Emitter,
+        adds: &mut BTreeMap<isize, u32>,
+        span: &mut Option<Range<usize>>,
+    ) {
+        if let Some(span) = span.take() {
+            // The map keeps the offsets sorted.
+            let adds = core::mem::take(adds).into_iter().collect();
+            out.push(Op::BulkAdd(adds), span);
+        }
+    }
+
+    // Runs of `CLEAR_RANGE_MIN` or more neighbouring cells become a
+    // `ClearRange` each and the other cells a `BulkClear`, in the order of
+    // the first clear each takes, so a counter's clear after a move loop
+    // still comes first.
+    fn flush_clears(out: &mut Emitter, clears: &mut Vec<isize>, span: &mut Option<Range<usize>>) {
+        let Some(span) = span.take() else {
+            return;
+        };
+        let clears = core::mem::take(clears);
+        let mut sorted = clears.clone();
+        sorted.sort_unstable();
+        let mut runs: Vec<(isize, usize)> = Vec::new();
+        for offset in sorted {
+            match runs.last_mut() {
+                Some((start, len)) if start.checked_add(*len as isize) == Some(offset) => *len += 1,
+                _ => runs.push((offset, 1)),
+            }
+        }
+        runs.retain(|&(_, len)| len >= CLEAR_RANGE_MIN);
+        let run_of = |offset: isize| {
+            runs.iter().position(|&(start, len)| {
+                offset
+                    .checked_sub(start)
+                    .is_some_and(|d| (0..len as isize).contains(&d))
+            })
+        };
+        let first = |run: Option<usize>| clears.iter().position(|&o| run_of(o) == run);
+        let rest: Vec<isize> = clears
+            .iter()
+            .copied()
+            .filter(|&o| run_of(o).is_none())
+            .collect();
+        let mut ops: Vec<(Option<usize>, Op)> = runs
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, len))| (first(Some(i)), Op::ClearRange(start, len)))
+            .collect();
+        if !rest.is_empty() {
+            ops.push((first(None), Op::BulkClear(rest)));
+        }
+        ops.sort_by_key(|&(first, _)| first);
+        for (_, op) in ops {
+            out.push(op, span.clone());
+        }
+    }
+
+    for (op, span) in ops.into_iter().zip(spans) {
+        if !matches!(
+            op,
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) | Op::Clear(0)
+        ) {
+            flush_muls(&mut out, &mut pending_muls);
+        }
+        match op {
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) => {
+                flush_adds(&mut out, &mut pending_adds, &mut adds_span);
+                flush_clears(&mut out, &mut pending_clears, &mut clears_span);
+
+                pending_muls.push((op, span));
+            }
+
+            // A move loop with more than one target becomes one op that
+            // reads its counter once. One with a single target is left an
+            // `AddTo`, `SubFrom` or `MulAdd`.
+            Op::Clear(0) if pending_muls.len() > 1 => {
+                let muls = core::mem::take(&mut pending_muls);
+                let targets = muls.iter().filter_map(|(op, _)| op.mul_add()).collect();
+                let span = muls.iter().fold(span, |span, (_, s)| join(&span, s));
+                out.push(Op::Transfer(targets), span);
+            }
+
+            Op::ValAdd(off, v) | Op::ValSub(off, v) => {
+                flush_clears(&mut out, &mut pending_clears, &mut clears_span);
+
+                let v = if matches!(op, Op::ValAdd(..)) {
+                    v
+                } else {
+                    0u32.wrapping_sub(v)
+                };
+                let d = pending_adds.entry(off).or_insert(0);
+                *d = d.wrapping_add(v);
+                cover(&mut adds_span, &span);
+            }
+
+            Op::Clear(off) => {
+                flush_muls(&mut out, &mut pending_muls);
+                flush_adds(&mut out, &mut pending_adds, &mut adds_span);
+
+                if !pending_clears.contains(&off) {
+                    pending_clears.push(off);
+                }
+                cover(&mut clears_span, &span);
+            }
+
+            _ => {
+                flush_adds(&mut out, &mut pending_adds, &mut adds_span);
+                flush_clears(&mut out, &mut pending_clears, &mut clears_span);
+
+                out.push(op, span);
+            }
+        }
+    }
+
+    flush_muls(&mut out, &mut pending_muls);
+    flush_adds(&mut out, &mut pending_adds, &mut adds_span);
+    flush_clears(&mut out, &mut pending_clears, &mut clears_span);
+
+    out.finish()
+}
+
+fn optimize_dce(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+    let mut i = 0;
+    let mut known = Known::at_start();
+    let mut budget = EVALUATE_BUDGET;
+    // What was known before each enclosing procedure definition, which is
+    // what is known again after it: the body doesn't run there.
+    let mut defs = Vec::new();
+    // Whether the cell `offset` away is on the tape and holds `value`, in
+    // every cell width.
+    let holds = |known: &Known, offset: isize, value: u32| {
+        known.get(offset) == Some(value) && known.is_touched(offset)
+    };
+
+    while i < ops.len() {
+        // A loop run from a known counter is worked out here, when it
+        // only does arithmetic on cells whose values it needs.
+        if let Op::Jz(target) = ops[i]
+            && let Some(folded) = evaluate_loop(&ops, i, &known, &mut budget)
+        {
+            let span = join(&spans[i], &spans[target]);
+            for op in folded {
+                known.apply(&op);
+                out.push(op, span.clone());
+            }
+            i = target + 1;
+            continue;
+        }
+        let op = match &ops[i] {
+            Op::Jz(target) if holds(&known, 0, 0) => {
+                i = target + 1;
+                continue;
+            }
+            // Storing what a cell already holds, or scanning or dividing
+            // from a zero cell, does nothing.
+            Op::Clear(offset) if holds(&known, *offset, 0) => None,
+            Op::Set(offset, n) if holds(&known, *offset, *n) => None,
+            Op::ScanLeft | Op::ScanRight | Op::Scan(_) | Op::ClearUntilZero(_) | Op::DivMod
+                if holds(&known, 0, 0) =>
+            {
+                None
+            }
+            // A multiplication by a known counter is an add to the same
+            // cell, as long as the counter is zero in every cell width or
+            // in none.
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) if holds(&known, 0, 0) => None,
+            op @ (Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_)) => {
+                match (known.get(0), op.mul_add()) {
+                    (Some(v), Some((offset, factor))) if v & 0xff != 0 && known.is_touched(0) => {
+                        Some(Op::ValAdd(offset, v.wrapping_mul(factor)))
+                    }
+                    _ => Some(op.clone()),
+                }
+            }
+            Op::Jz(_) => Some(Op::Jz(0)),
+            Op::Jnz(_) => Some(Op::Jnz(0)),
+            Op::DefProc(_) => {
+                defs.push(known.clone());
+                Some(Op::DefProc(0))
+            }
+            other => Some(other.clone()),
+        };
+        if let Some(op) = op {
+            known.apply(&op);
+            if op == Op::EndProc {
+                known = defs.pop().unwrap_or_else(Known::unknown);
+            }
+            out.push(op, spans[i].clone());
+        }
+        i += 1;
+    }
+
+    out.finish()
+}
+
+fn optimize_merge(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+
+    for (op, span) in ops.into_iter().zip(spans) {
+        match op {
+            Op::PtrAdd(n) => {
+                if let Some(Op::PtrAdd(prev)) = out.ops.last_mut() {
+                    *prev += n;
+                    out.widen_last(&span);
+                } else {
+                    out.push(Op::PtrAdd(n), span);
+                }
+            }
+            Op::ValAdd(offset, n) | Op::ValSub(offset, n) => {
+                let add = matches!(op, Op::ValAdd(..));
+                match out.ops.last_mut() {
+                    // The same direction: the amounts add up.
+                    Some(Op::ValAdd(prev_off, prev_val)) if *prev_off == offset && add => {
+                        *prev_val = prev_val.wrapping_add(n);
+                        if *prev_val == 0 {
+                            out.pop();
+                        } else {
+                            out.widen_last(&span);
+                        }
+                    }
+                    Some(Op::ValSub(prev_off, prev_val)) if *prev_off == offset && !add => {
+                        *prev_val = prev_val.wrapping_add(n);
+                        if *prev_val == 0 {
+                            out.pop();
+                        } else {
+                            out.widen_last(&span);
+                        }
+                    }
+                    // Opposite directions: the larger amount wins.
+                    Some(Op::ValAdd(prev_off, prev_val) | Op::ValSub(prev_off, prev_val))
+                        if *prev_off == offset =>
+                    {
+                        if *prev_val > n {
+                            *prev_val -= n;
+                            out.widen_last(&span);
+                        } else if *prev_val < n {
+                            let rem = n - *prev_val;
+                            let prev_span = out.pop().unwrap();
+                            let op = if add {
+                                Op::ValAdd(offset, rem)
+                            } else {
+                                Op::ValSub(offset, rem)
+                            };
+                            out.push(op, join(&prev_span, &span));
+                        } else {
+                            out.pop();
+                        }
+                    }
+                    _ => out.push(op, span),
+                }
+            }
+            Op::Output | Op::OutputAt(_) | Op::OutputRepeat(..) => {
+                let (offset, count) = printed_cell(&op);
+                match out.ops.last_mut() {
+                    Some(prev @ (Op::Output | Op::OutputAt(_) | Op::OutputRepeat(..)))
+                        if printed_cell(prev).0 == offset =>
+                    {
+                        *prev = Op::OutputRepeat(offset, printed_cell(prev).1 + count);
+                        out.widen_last(&span);
+                    }
+                    _ => out.push(op, span),
+                }
+            }
+            _ => out.push(op, span),
+        }
+    }
+
+    // Remove PtrAdd(0)
+    let (ops, spans) = out
+        .ops
+        .into_iter()
+        .zip(out.spans)
+        .filter(|(op, _)| !matches!(op, Op::PtrAdd(0)))
+        .unzip();
+    Emitter { ops, spans }.finish()
+}
+
+fn optimize_offsets(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+    // Pointer movement not yet applied; every op emitted while it is
+    // non-zero has it folded into its offset. `moves` covers the `PtrAdd`s
+    // it came from.
+    let mut pending: isize = 0;
+    let mut moves: Option<Range<usize>> = None;
+
+    for (op, span) in ops.into_iter().zip(spans) {
+        match op {
+            Op::PtrAdd(n) => {
+                pending += n;
+                cover(&mut moves, &span);
+            }
+            Op::ValAdd(off, n) => out.push(Op::ValAdd(off + pending, n), span),
+            Op::ValSub(off, n) => out.push(Op::ValSub(off + pending, n), span),
+            Op::Clear(off) => out.push(Op::Clear(off + pending), span),
+            Op::Output => out.push(output_at(pending), span),
+            Op::OutputAt(off) => out.push(output_at(off + pending), span),
+            Op::OutputRepeat(off, count) => out.push(Op::OutputRepeat(off + pending, count), span),
+            Op::Input => out.push(input_at(pending), span),
+            Op::InputAt(off) => out.push(input_at(off + pending), span),
+            Op::OutputConst(bytes) => out.push(Op::OutputConst(bytes), span),
+            Op::BulkAdd(deltas) => out.push(
+                Op::BulkAdd(
+                    deltas
+                        .into_iter()
+                        .map(|(off, n)| (off + pending, n))
+                        .collect(),
+                ),
+                span,
+            ),
+            Op::BulkClear(offsets) => out.push(
+                Op::BulkClear(offsets.into_iter().map(|off| off + pending).collect()),
+                span,
+            ),
+            Op::ClearRange(off, n) => out.push(Op::ClearRange(off + pending, n), span),
+            // Everything else reads the cell under the pointer, so the
+            // pointer has to be where the op expects it.
+            other => {
+                if let Some(moves) = moves.take()
+                    && pending != 0
+                {
+                    out.push(Op::PtrAdd(pending), moves);
+                    pending = 0;
+                }
+                out.push(other, span);
+            }
+        }
+    }
+
+    if let Some(moves) = moves
+        && pending != 0
+    {
+        out.push(Op::PtrAdd(pending), moves);
+    }
+
+    out.finish()
+}
+
+fn optimize_set(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+    // Cells stored to since the last barrier, and the index of the
+    // `Clear`/`Set` that stored them.
+    let mut stores: BTreeMap<isize, usize> = BTreeMap::new();
+
+    for (op, span) in ops.into_iter().zip(spans) {
+        match op {
+            Op::ValAdd(off, n) | Op::ValSub(off, n) if stores.contains_key(&off) => {
+                let n = if matches!(op, Op::ValAdd(..)) {
+                    n
+                } else {
+                    0u32.wrapping_sub(n)
+                };
+                let store = &mut out.ops[stores[&off]];
+                *store = match *store {
+                    Op::Set(_, v) => Op::Set(off, v.wrapping_add(n)),
+                    _ => Op::Set(off, n),
+                };
+                out.widen(stores[&off], &span);
+            }
+            Op::ValAdd(..) | Op::ValSub(..) => out.push(op, span),
+            Op::Clear(off) | Op::Set(off, _) => {
+                stores.insert(off, out.ops.len());
+                out.push(op, span);
+            }
+            // Anything else may read the stored cells or move the pointer.
+            other => {
+                stores.clear();
+                out.push(other, span);
+            }
+        }
+    }
+
+    out.finish()
+}
+
+fn optimize_dead_stores(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut spans = spans;
+    let mut dead = vec![false; ops.len()];
+    // The writes to each cell since it was last read, relative to the
+    // pointer.
+    let mut writes: BTreeMap<isize, Vec<usize>> = BTreeMap::new();
+
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Op::PtrAdd(n) => {
+                let moved = core::mem::take(&mut writes);
+                writes = moved.into_iter().map(|(k, v)| (k - n, v)).collect();
+            }
+            // A store overwrites what was there, so the writes before it
+            // only ever fed each other.
+            Op::Clear(offset) | Op::Set(offset, _) => {
+                for j in writes.insert(offset, vec![i]).unwrap_or_default() {
+                    dead[j] = true;
+                    spans[i] = join(&spans[i], &spans[j]);
+                }
+            }
+            Op::ValAdd(offset, _) | Op::ValSub(offset, _) => {
+                writes.entry(offset).or_default().push(i);
+            }
+            Op::MulAdd(offset, _) | Op::AddTo(offset) | Op::SubFrom(offset) => {
+                writes.remove(&0);
+                writes.entry(offset).or_default().push(i);
+            }
+            // Anything else may read any cell, or print or run into
+            // something that shows what cells were written so far. That
+            // includes `,`, which leaves the cell as it was at the end of
+            // the input by default.
+            _ => writes.clear(),
+        }
+    }
+
+    let mut out = Emitter::default();
+    for ((op, span), dead) in ops.into_iter().zip(spans).zip(dead) {
+        if !dead {
+            out.push(op, span);
+        }
+    }
+    out.finish()
+}
+
+fn optimize_const_output(ops: Vec<Op>, spans: Vec<Range<usize>>) -> (Vec<Op>, Vec<Range<usize>>) {
+    let mut out = Emitter::default();
+    let mut known = Known::zeroed();
+    // The ops since the first folded `.`, and what they print. The bytes
+    // are written where the run ends, so only silent ops may be in it.
+    let mut run = Emitter::default();
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (op, span) in ops.into_iter().zip(spans) {
+        let printed = match &op {
+            Op::OutputConst(b) => Some(b.clone()),
+            Op::OutputRepeat(_, count) => known.output(&op).map(|b| vec![b; *count]),
+            _ => known.output(&op).map(|b| vec![b]),
+        };
+        if let Some(printed) = printed {
+            bytes.extend(printed);
+            run.push(op, span);
+            continue;
+        }
+        if !run.is_empty() && !known.is_silent(&op) {
+            end_output_run(&mut out, &mut run, &mut bytes, &known);
+        }
+        known.apply(&op);
+        if run.is_empty() {
+            out.push(op, span);
+        } else {
+            run.push(op, span);
+        }
+    }
+    end_output_run(&mut out, &mut run, &mut bytes, &known);
+
+    out.finish()
+}
+
+/// Emits `run` with its outputs folded into one `OutputConst` after the
+/// rest, covering the outputs' ranges. [`to_brainfuck`](crate::to_brainfuck)
+/// builds the bytes in the current cell, so the run is kept as it is unless
+/// that cell is on the tape and its value is known.
+fn end_output_run(out: &mut Emitter, run: &mut Emitter, bytes: &mut Vec<u8>, known: &Known) {
+    let ops = core::mem::take(&mut run.ops);
+    let spans = core::mem::take(&mut run.spans);
+    if known.get(0).is_none() || !known.is_touched(0) {
+        for (op, span) in ops.into_iter().zip(spans) {
+            out.push(op, span);
+        }
+    } else {
+        let mut printed: Option<Range<usize>> = None;
+        for (op, span) in ops.into_iter().zip(spans) {
+            if matches!(
+                op,
+                Op::Output | Op::OutputAt(_) | Op::OutputRepeat(..) | Op::OutputConst(_)
+            ) {
+                cover(&mut printed, &span);
+            } else {
+                out.push(op, span);
+            }
+        }
+        if let Some(span) = printed
+            && !bytes.is_empty()
+        {
+            out.push(Op::OutputConst(core::mem::take(bytes)), span);
+        }
+    }
+    bytes.clear();
+}
+
+/// The cell a `.`, `OutputAt` or `OutputRepeat` prints, and how many
+/// times.
+fn printed_cell(op: &Op) -> (isize, usize) {
+    match *op {
+        Op::OutputAt(offset) => (offset, 1),
+        Op::OutputRepeat(offset, count) => (offset, count),
+        _ => (0, 1),
+    }
+}
+
+fn output_at(offset: isize) -> Op {
+    if offset == 0 {
+        Op::Output
+    } else {
+        Op::OutputAt(offset)
+    }
+}
+
+fn input_at(offset: isize) -> Op {
+    if offset == 0 {
+        Op::Input
+    } else {
+        Op::InputAt(offset)
+    }
+}
+
+fn check_scan_loop(body: &[Op]) -> Option<Op> {
+    match *body {
+        [Op::PtrAdd(1)] => Some(Op::ScanRight),
+        [Op::PtrAdd(-1)] => Some(Op::ScanLeft),
+        [Op::PtrAdd(stride)] if stride != 0 => Some(Op::Scan(stride)),
+        // A sweep like `[[-]>]`, with its inner loop already a clear.
+        [Op::Clear(0), Op::PtrAdd(stride)] if stride != 0 => Some(Op::ClearUntilZero(stride)),
+        _ => None,
+    }
+}
+
+/// The fewest neighbouring cells the bulk pass clears with a `ClearRange`
+/// instead of listing them in a `BulkClear`.
+const CLEAR_RANGE_MIN: usize = 4;
+
+/// The most ops [`evaluate_loop`] runs before giving up on a loop.
+const EVALUATE_LIMIT: usize = 10_000;
+
+/// The most ops [`evaluate_loop`] runs over a whole program, so that a
+/// program of many loops that each give up late can't stall the pass.
+const EVALUATE_BUDGET: usize = 1_000_000;
+
+/// What a loop being evaluated has left in a cell.
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    /// A known value.
+    Exact(u32),
+    /// The unknown value the cell had, plus this.
+    Offset(u32),
+}
+
+/// The cells a loop being evaluated has touched, relative to the cell it
+/// started on.
+struct Evaluation<'a> {
+    known: &'a Known,
+    cells: BTreeMap<isize, Value>,
+    /// The cells in the order the loop first touched them.
+    order: Vec<isize>,
+}
+
+impl Evaluation<'_> {
+    fn get(&mut self, offset: isize) -> Value {
+        let known = self.known;
+        *self.cells.entry(offset).or_insert_with(|| {
+            self.order.push(offset);
+            match known.get(offset) {
+                Some(v) => Value::Exact(v),
+                None => Value::Offset(0),
+            }
+        })
+    }
+
+    fn set(&mut self, offset: isize, value: Value) {
+        self.get(offset);
+        self.cells.insert(offset, value);
+    }
+
+    fn add(&mut self, offset: isize, n: u32) {
+        let value = match self.get(offset) {
+            Value::Exact(v) => Value::Exact(v.wrapping_add(n)),
+            Value::Offset(d) => Value::Offset(d.wrapping_add(n)),
+        };
+        self.cells.insert(offset, value);
+    }
+
+    /// Whether the cell `offset` away is zero, if that is known and the
+    /// same in every cell width.
+    fn is_zero(&mut self, offset: isize) -> Option<bool> {
+        match self.get(offset) {
+            Value::Exact(0) => Some(true),
+            Value::Exact(v) if v & 0xff != 0 => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// The ops that do what the loop whose `Jz` is at `ops[start]` does, when
+/// it is entered with `known` on the tape: if every cell it tests and
+/// every counter it multiplies by is known, it is run here, for at most
+/// [`EVALUATE_LIMIT`] ops, and replaced by stores of what it leaves in
+/// each cell it touches and the move it makes. Every op run comes out of
+/// `budget`, and once it is spent no loop is run.
+fn evaluate_loop(ops: &[Op], start: usize, known: &Known, budget: &mut usize) -> Option<Vec<Op>> {
+    let Op::Jz(end) = ops[start] else {
+        return None;
+    };
+    let mut eval = Evaluation {
+        known,
+        cells: BTreeMap::new(),
+        order: Vec::new(),
+    };
+    let mut ptr: isize = 0;
+    let mut pc = start;
+    let mut steps = 0;
+    while pc <= end {
+        steps += 1;
+        if steps > EVALUATE_LIMIT || *budget == 0 {
+            return None;
+        }
+        *budget -= 1;
+        match ops[pc] {
+            Op::Jz(target) if eval.is_zero(ptr)? => pc = target,
+            Op::Jnz(target) if !eval.is_zero(ptr)? => pc = target,
+            Op::Jz(_) | Op::Jnz(_) => {}
+            Op::PtrAdd(n) => ptr = ptr.checked_add(n)?,
+            Op::ValAdd(offset, n) => eval.add(ptr.checked_add(offset)?, n),
+            Op::ValSub(offset, n) => eval.add(ptr.checked_add(offset)?, n.wrapping_neg()),
+            Op::Clear(offset) => eval.set(ptr.checked_add(offset)?, Value::Exact(0)),
+            Op::Set(offset, n) => eval.set(ptr.checked_add(offset)?, Value::Exact(n)),
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) => {
+                let (offset, factor) = ops[pc].mul_add()?;
+                if !eval.is_zero(ptr)? {
+                    let Value::Exact(v) = eval.get(ptr) else {
+                        return None;
+                    };
+                    eval.add(ptr.checked_add(offset)?, v.wrapping_mul(factor));
+                }
+            }
+            _ => return None,
+        }
+        pc += 1;
+    }
+
+    // Each cell is stored to in the order the loop first touched it, so one
+    // off the tape fails the same way.
+    let mut folded = Vec::new();
+    for offset in eval.order {
+        match eval.cells[&offset] {
+            Value::Exact(v) if known.get(offset) == Some(v) && known.is_touched(offset) => {}
+            Value::Exact(0) => folded.push(Op::Clear(offset)),
+            Value::Exact(v) => folded.push(Op::Set(offset, v)),
+            Value::Offset(0) if known.is_touched(offset) => {}
+            // Only read, and skipping it could hide a failure.
+            Value::Offset(0) => return None,
+            Value::Offset(d) => folded.push(Op::ValAdd(offset, d)),
+        }
+    }
+    if ptr != 0 {
+        folded.push(Op::PtrAdd(ptr));
+    }
+    Some(folded)
+}
+
+/// The inverse of odd `n` modulo 2^32, which is its inverse modulo every
+/// smaller power of two too.
+fn inverse(n: u32) -> u32 {
+    // Newton's iteration doubles the bits that are right each time, and `n`
+    // is its own inverse modulo 8.
+    let mut inv = n;
+    for _ in 0..4 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n.wrapping_mul(inv)));
+    }
+    inv
+}
+
+/// What one pass of a loop body does to a cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    /// Adds to it.
+    Add(u32),
+    /// Sets it, to a value later ops in the body may have added to.
+    Assign(u32),
+}
+
+/// What one pass of `body` does to each cell it touches, relative to the
+/// loop's cell, if it is straight-line code that ends where it started and
+/// only multiplies cells it has set.
+fn pass_effects(body: &[Op]) -> Option<BTreeMap<isize, Effect>> {
+    let mut ptr: isize = 0;
+    let mut effects = BTreeMap::new();
+    let add = |effects: &mut BTreeMap<isize, Effect>, cell: isize, n: u32| {
+        let effect = effects.entry(cell).or_insert(Effect::Add(0));
+        *effect = match *effect {
+            Effect::Add(d) => Effect::Add(d.wrapping_add(n)),
+            Effect::Assign(v) => Effect::Assign(v.wrapping_add(n)),
+        };
+    };
+
+    for op in body {
+        match *op {
+            Op::PtrAdd(n) => ptr += n,
+            Op::ValAdd(offset, n) => add(&mut effects, ptr + offset, n),
+            Op::ValSub(offset, n) => add(&mut effects, ptr + offset, n.wrapping_neg()),
+            Op::Clear(offset) => {
+                effects.insert(ptr + offset, Effect::Assign(0));
+            }
+            Op::Set(offset, n) => {
+                effects.insert(ptr + offset, Effect::Assign(n));
+            }
+            // An inner move loop whose counter the body has set adds the
+            // same each pass.
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) => {
+                let (offset, factor) = op.mul_add()?;
+                match effects.get(&ptr) {
+                    Some(&Effect::Assign(v)) => {
+                        add(&mut effects, ptr + offset, v.wrapping_mul(factor))
+                    }
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    (ptr == 0).then_some(effects)
+}
+
+/// The op that adds the current cell times `factor` to the cell `offset`
+/// away: an `AddTo` or `SubFrom` for a factor of 1 or -1, which need no
+/// multiply, and a `MulAdd` otherwise.
+fn mul_add(offset: isize, factor: u32) -> Op {
+    match factor {
+        1 => Op::AddTo(offset),
+        u32::MAX => Op::SubFrom(offset),
+        _ => Op::MulAdd(offset, factor),
+    }
+}
+
+/// The ops before the final `Clear(0)` that a loop with `body` can be
+/// replaced by, if it runs from a cell holding `start`, when that is known.
+fn check_move_loop(body: &[Op], start: Option<u32>) -> Option<Vec<Op>> {
+    let mut effects = pass_effects(body)?;
+    let Some(Effect::Add(step)) = effects.remove(&0) else {
+        return None;
+    };
+
+    // A cell the body sets is left as the last pass sets it, so only if
+    // the loop is known to run: from a value that isn't zero in any cell
+    // width.
+    let mut ops = Vec::new();
+    let mut deltas = Vec::new();
+    for (offset, effect) in effects {
+        match effect {
+            Effect::Add(delta) => deltas.push((offset, delta)),
+            Effect::Assign(0) => ops.push(Op::Clear(offset)),
+            Effect::Assign(v) => ops.push(Op::Set(offset, v)),
+        }
+    }
+    if !ops.is_empty() && start.is_none_or(|start| start & 0xff == 0) {
+        return None;
+    }
+
+    // A counter stepping by an odd amount reaches zero from any value, in
+    // every cell width: after as many passes as its value times the
+    // negated inverse of the step. So every other cell gains its value
+    // times that times its own step.
+    if step % 2 == 1 {
+        let passes = inverse(step).wrapping_neg();
+        ops.extend(
+            deltas
+                .into_iter()
+                .map(|(offset, delta)| mul_add(offset, delta.wrapping_mul(passes))),
+        );
+        return Some(ops);
+    }
+
+    // Otherwise, a counter counting down by a step it is a multiple of
+    // takes the same number of passes in every cell width, as long as it
+    // fits the narrowest one.
+    let down = step.wrapping_neg();
+    let start = start.filter(|&start| start <= 0xff)?;
+    if down == 0 || down > 0xff || start % down != 0 {
+        return None;
+    }
+    let passes = start / down;
+    ops.extend(
+        deltas
+            .into_iter()
+            .filter(|&(_, delta)| delta.wrapping_mul(passes) != 0)
+            .map(|(offset, delta)| Op::ValAdd(offset, delta.wrapping_mul(passes))),
+    );
+    Some(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_verify() {
+        let ops = optimize(parse(b"+[>,[-]<-]").unwrap());
+        assert_eq!(verify(&ops), Ok(()));
+        assert_eq!(
+            verify(&[Op::Jz(5), Op::Jnz(0)]),
+            Err(VerifyError::OutOfBounds { pc: 0, target: 5 })
+        );
+        assert_eq!(
+            verify(&[Op::Jz(2), Op::Jz(2), Op::Jnz(1), Op::Jnz(0)]),
+            Err(VerifyError::Mismatched { open: 0, close: 3 })
+        );
+        assert_eq!(
+            verify(&[Op::Jz(1), Op::Jnz(0), Op::Jnz(0)]),
+            Err(VerifyError::UnmatchedClose { pc: 2 })
+        );
+        assert_eq!(
+            verify(&[Op::Jz(0)]).map_err(|e| e.to_string()),
+            Err("the loop at op 0 never ends".to_string())
+        );
+    }
+
+    #[test]
+    fn test_dce_loop_at_start() {
+        let ops = parse(b"[->+<].").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![Op::Output]);
+    }
+
+    #[test]
+    fn test_dce_forks() {
+        let options = crate::ParseOptions {
+            dialect: crate::Dialect::Brainfork,
+            ..crate::ParseOptions::default()
+        };
+        // The forked thread starts on a non-zero cell.
+        let ops = crate::parse_with(b"Y[-]", &options).unwrap();
+        assert_eq!(optimize_dce(ops.clone(), vec![0..0; ops.len()]).0, ops);
+    }
+
+    #[test]
+    fn test_optimize_multitape() {
+        let options = crate::ParseOptions {
+            dialect: crate::Dialect::Multitape,
+            ..crate::ParseOptions::default()
+        };
+        // The adds are to cells of different tapes, and the new tape is
+        // zeroed only the first time.
+        let ops = optimize(crate::parse_with(b"+v+^-v[-]", &options).unwrap());
+        assert_eq!(
+            ops,
+            vec![
+                Op::BulkAdd(vec![(0, 1)]),
+                Op::SwitchTape(1),
+                Op::BulkAdd(vec![(0, 1)]),
+                Op::SwitchTape(-1),
+                Op::BulkAdd(vec![(0, u32::MAX)]),
+                Op::SwitchTape(1),
+                Op::BulkClear(vec![0])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dce_procedures() {
+        let options = crate::ParseOptions {
+            dialect: crate::Dialect::Pbrain,
+            ..crate::ParseOptions::default()
+        };
+        // The body runs whenever the procedure is called, but after the
+        // definition the cell is still zero.
+        let ops = crate::parse_with(b"([-])[-]:", &options).unwrap();
+        let (ops, _) = optimize_dce(ops.clone(), vec![0..0; ops.len()]);
+        assert_eq!(
+            ops,
+            vec![
+                Op::DefProc(4),
+                Op::Jz(3),
+                Op::ValSub(0, 1),
+                Op::Jnz(1),
+                Op::EndProc,
+                Op::CallProc
+            ]
+        );
+        assert_eq!(verify(&ops), Ok(()));
+        assert_eq!(
+            verify(&[Op::DefProc(1), Op::Jnz(0)]),
+            Err(VerifyError::Mismatched { open: 0, close: 1 })
+        );
+    }
+
+    #[test]
+    fn test_dce_redundant_clear() {
+        let ops = parse(b"+[-][-]").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![Op::BulkClear(vec![0])]);
+    }
+
+    #[test]
+    fn test_dce_scan_loop() {
+        let ops = parse(b"[<]").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![]);
+
+        let ops = parse(b"+[<]").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![Op::BulkAdd(vec![(0, 1)]), Op::ScanLeft]);
+
+        let ops = parse(b"+[>>]+[<<<]").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(
+            optimized,
+            vec![
+                Op::BulkAdd(vec![(0, 1)]),
+                Op::Scan(2),
+                Op::BulkAdd(vec![(0, 1)]),
+                Op::Scan(-3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clear_ranges() {
+        let ops = parse(b",>,>,>,>,>,>>,<<<<<<<[-]>[-]>[-]>[-]>[-]>>[-]<[-]>>>>[-].").unwrap();
+        assert_eq!(
+            optimize(ops)[7..],
+            [
+                Op::ClearRange(0, 7),
+                Op::BulkClear(vec![9]),
+                Op::PtrAdd(9),
+                Op::OutputConst(vec![0])
+            ]
+        );
+
+        // The counter's clear still comes right after the move loop.
+        let ops = parse(b",[->>>>+<<<<]>[-]>[-]>[-]>.").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::Input,
+                Op::AddTo(4),
+                Op::ClearRange(0, 4),
+                Op::OutputAt(4),
+                Op::PtrAdd(4)
+            ]
+        );
+        let ops = parse(b",[->+<]>>[-]>[-]>[-]>[-]<<<<.").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::Input,
+                Op::AddTo(1),
+                Op::BulkClear(vec![0]),
+                Op::ClearRange(2, 4),
+                Op::OutputAt(1),
+                Op::PtrAdd(1)
+            ]
+        );
+
+        let ops = parse(b",[[-]>]<<[[-]<<]").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::Input,
+                Op::ClearUntilZero(1),
+                Op::PtrAdd(-2),
+                Op::ClearUntilZero(-2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dce_move_loop() {
+        let ops = parse(b"[->+<]").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![]);
+
+        // A known counter makes it an add.
+        let ops = parse(b"+[->+<]").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(
+            optimized,
+            vec![Op::BulkAdd(vec![(1, 1)]), Op::BulkClear(vec![0])]
+        );
+    }
+
+    #[test]
+    fn test_dce_known_values() {
+        // Both cells are known, so the move loop is an add and the `.`
+        // prints a constant.
+        let ops = parse(b"++>>+<<[->>+<<]>>.").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::BulkAdd(vec![(2, 3)]),
+                Op::BulkClear(vec![0]),
+                Op::PtrAdd(2),
+                Op::OutputConst(vec![3])
+            ]
+        );
+
+        // Storing what a cell is known to hold does nothing, but the cell
+        // right of the start isn't known to be on the tape.
+        let ops = vec![
+            Op::Set(0, 5),
+            Op::PtrAdd(1),
+            Op::Clear(0),
+            Op::PtrAdd(-1),
+            Op::Set(0, 5),
+            Op::Clear(1),
+        ];
+        let (ops, _) = optimize_dce(ops.clone(), vec![0..0; ops.len()]);
+        assert_eq!(
+            ops,
+            vec![Op::Set(0, 5), Op::PtrAdd(1), Op::Clear(0), Op::PtrAdd(-1)]
+        );
+    }
+
+    #[test]
+    fn test_evaluate_loop() {
+        // The counters are known, so the loops are run while optimizing.
+        let ops = parse(b"+++[>+++[>++<-]<-]").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![Op::BulkClear(vec![0, 1]), Op::Set(2, 18)]
+        );
+        let ops = parse(b">+>+<<+[>[-]>]").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::BulkAdd(vec![(0, 1), (2, 1)]),
+                Op::BulkClear(vec![1, 3, 4]),
+                Op::PtrAdd(4)
+            ]
+        );
+
+        // One that never ends is left alone.
+        let ops = parse(b"+[>+]").unwrap();
+        assert!(optimize(ops).contains(&Op::PtrAdd(1)));
+
+        // The ops run come out of one budget for the whole pass.
+        let ops = parse(b"+++[-]").unwrap();
+        let mut known = Known::at_start();
+        known.apply(&ops[0]);
+        let mut budget = 5;
+        assert_eq!(evaluate_loop(&ops, 1, &known, &mut budget), None);
+        assert_eq!(budget, 0);
+        let mut budget = 100;
+        assert_eq!(
+            evaluate_loop(&ops, 1, &known, &mut budget),
+            Some(vec![Op::Clear(0)])
+        );
+        assert_eq!(budget, 100 - 7);
+    }
+
+    #[test]
+    fn test_move_loop_leftward() {
+        let body = parse(b"-<+<<--->>>").unwrap();
+        assert_eq!(
+            check_move_loop(&body, None),
+            Some(vec![Op::MulAdd(-3, 3u32.wrapping_neg()), Op::AddTo(-1)])
+        );
+        // Factors of 1 and -1 need no multiply.
+        let body = parse(b"->->+<<").unwrap();
+        assert_eq!(
+            check_move_loop(&body, None),
+            Some(vec![Op::SubFrom(1), Op::AddTo(2)])
+        );
+
+        let ops = parse(b">,[-<+>]").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::InputAt(1),
+                Op::PtrAdd(1),
+                Op::AddTo(-1),
+                Op::BulkClear(vec![0])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_output_repeat() {
+        let ops = parse(b",...>,..<.").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::Input,
+                Op::OutputRepeat(0, 3),
+                Op::InputAt(1),
+                Op::OutputRepeat(1, 2),
+                Op::Output
+            ]
+        );
+        // A known value is still printed as a constant.
+        let ops = parse(b"+++...").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![Op::BulkAdd(vec![(0, 3)]), Op::OutputConst(vec![3; 3])]
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        // A move loop with several targets is one op, clear and all.
+        let ops = parse(b",[->+>++<<]>>.").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::Input,
+                Op::Transfer(vec![(1, 1), (2, 2)]),
+                Op::OutputAt(2),
+                Op::PtrAdd(2)
+            ]
+        );
+        // One with a single target keeps its clear.
+        let ops = parse(b",[->+<]>.").unwrap();
+        assert_eq!(
+            optimize(ops),
+            vec![
+                Op::Input,
+                Op::AddTo(1),
+                Op::BulkClear(vec![0]),
+                Op::OutputAt(1),
+                Op::PtrAdd(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        // Folded ops cover everything they replace: a whole loop, a write
+        // it overwrites, a run of moves, or the `.` whose byte is known.
+        let source = b"+[-]>>,[-<+>] +.";
+        let (ops, spans) = crate::parse_with_spans(source, &Default::default()).unwrap();
+        let (ops, spans) = optimize_with_spans(ops, spans, OptLevel::O2);
+        let text: Vec<&[u8]> = spans.iter().map(|span| &source[span.clone()]).collect();
+        assert_eq!(ops.len(), spans.len());
+        assert_eq!(ops[3], Op::AddTo(-1));
+        assert_eq!(
+            text,
+            [&b"+[-]"[..], b",", b">>", b"[-<+>]", b"[-<+>] +", b"."]
+        );
+    }
+
+    #[test]
+    fn test_move_loop_odd_step() {
+        // A counter stepping by +255 takes a different number of passes in
+        // each cell width, all of them given by the inverse of 255.
+        let mut code = vec![b'+', b'+', b'+', b'['];
+        code.extend_from_slice(&[b'+'; 255]);
+        code.extend_from_slice(b">+<]");
+        let ops = parse(&code).unwrap();
+        let optimized = optimize(ops.clone());
+        assert!(!optimized.iter().any(|op| matches!(op, Op::Jz(_))));
+        fn tape<C: crate::cell::Cell>(ops: &[Op]) -> Vec<C> {
+            let mut tape = vec![C::default(); 2];
+            crate::exec::run(
+                ops,
+                &mut tape,
+                crate::BoundsPolicy::Error,
+                crate::EofBehavior::Unchanged,
+                || None,
+                |_| {},
+            )
+            .unwrap();
+            tape
+        }
+        assert_eq!(tape::<u8>(&optimized), tape::<u8>(&ops));
+        assert_eq!(tape::<u16>(&optimized), tape::<u16>(&ops));
+        assert_eq!(tape::<u16>(&optimized), [0, 3 * 257]);
+        assert_eq!(inverse(3).wrapping_mul(3), 1);
+
+        let body = parse(b"--->++<").unwrap();
+        assert_eq!(
+            check_move_loop(&body, None),
+            Some(vec![Op::MulAdd(1, 2u32.wrapping_mul(inverse(3))),])
+        );
+    }
+
+    #[test]
+    fn test_nested_loops() {
+        // Inner loops are rewritten first, so the loops around them are
+        // checked with what they became.
+        let ops = parse(b"[-[-]]").unwrap();
+        assert_eq!(BuiltinPass::Clear.run(ops), vec![Op::Clear(0)]);
+        let code = b",[-]+++++[->[-]++[->+++<]<]";
+        let optimized = optimize(parse(code).unwrap());
+        assert!(!optimized.iter().any(|op| matches!(op, Op::Jz(_))));
+        let mut tape = [0u8; 4];
+        crate::exec::run(
+            &optimized,
+            &mut tape,
+            crate::BoundsPolicy::Error,
+            crate::EofBehavior::Unchanged,
+            || None,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(tape, [0, 0, 30, 0]);
+        // Unless the loop is known to run, the cell it sets may be left
+        // as it was.
+        let ops = parse(b",[->[-]++[->+++<]<]").unwrap();
+        assert!(optimize(ops).iter().any(|op| matches!(op, Op::Jz(_))));
+    }
+
+    #[test]
+    fn test_move_loop_even_step() {
+        // Counting down by 2 only reaches zero from an even value, so the
+        // loop stays unless its counter is known.
+        let ops = parse(b",[-->+<]").unwrap();
+        assert!(optimize(ops).iter().any(|op| matches!(op, Op::Jz(_))));
+        let ops = parse(b",[-]++++++[-->+++<]").unwrap();
+        let optimized = BuiltinPass::MoveLoop.run(BuiltinPass::Clear.run(ops));
+        assert_eq!(&optimized[3..], [Op::ValAdd(1, 9), Op::Clear(0)]);
+        let ops = parse(b",[-]+++++[-->+++<]").unwrap();
+        let optimized = BuiltinPass::MoveLoop.run(BuiltinPass::Clear.run(ops));
+        assert!(optimized.iter().any(|op| matches!(op, Op::Jz(_))));
+    }
+
+    #[test]
+    fn test_merge_ptr_ops() {
+        let ops = parse(b">>").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![Op::PtrAdd(2)]);
+
+        let ops = parse(b">><<").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![]);
+
+        let ops = parse(b">>><").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![Op::PtrAdd(2)]);
+    }
+
+    #[test]
+    fn test_merge_val_ops() {
+        let ops = parse(b"++").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![Op::BulkAdd(vec![(0, 2)])]);
+
+        let ops = parse(b"++--").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(optimized, vec![]);
+    }
+
+    #[test]
+    fn test_opt_levels() {
+        let ops = parse(b"+[-]>[<]").unwrap();
+        assert_eq!(optimize_with(ops.clone(), OptLevel::O0), ops);
+        assert_eq!(
+            optimize_with(ops.clone(), OptLevel::O1),
+            vec![Op::ValAdd(0, 1), Op::Clear(0), Op::PtrAdd(1), Op::ScanLeft]
+        );
+        assert_eq!(
+            optimize_with(ops, OptLevel::O2),
+            vec![Op::BulkClear(vec![0]), Op::PtrAdd(1), Op::ScanLeft]
+        );
+    }
+
+    #[test]
+    fn test_pipeline_runs_in_order() {
+        let ops = parse(b"[-]").unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.push(BuiltinPass::Scan);
+        assert_eq!(pipeline.run(ops.clone()), ops);
+        pipeline.push(BuiltinPass::Clear);
+        assert_eq!(pipeline.run(ops), vec![Op::Clear(0)]);
+        assert_eq!(
+            BuiltinPass::from_name("move-loop"),
+            Some(BuiltinPass::MoveLoop)
+        );
+    }
+
+    #[test]
+    fn test_strict_semantics() {
+        let pipeline = Pipeline::for_semantics(OptLevel::O2, Semantics::Strict);
+        let names: Vec<&str> = pipeline.passes().map(|pass| pass.name()).collect();
+        assert_eq!(names, ["clear", "scan", "offset"]);
+        assert_eq!(
+            Pipeline::for_semantics(OptLevel::O2, Semantics::Fast)
+                .passes()
+                .count(),
+            OptLevel::O2.passes().len()
+        );
+
+        // The move loop still touches `>` before decrementing, and the
+        // `+-` still touches its cell.
+        let options = crate::ParseOptions {
+            semantics: Semantics::Strict,
+            ..Default::default()
+        };
+        let ops = crate::parse_with(b"[>+<-]<+-", &options).unwrap();
+        assert_eq!(
+            pipeline.run(ops),
+            vec![
+                Op::Jz(3),
+                Op::ValAdd(1, 1),
+                Op::ValSub(0, 1),
+                Op::Jnz(0),
+                Op::ValSub(-1, 0),
+                Op::PtrAdd(-1),
+            ]
+        );
+        assert_eq!(parse(b"<+-").unwrap(), vec![Op::PtrAdd(-1)]);
+    }
+
+    #[test]
+    fn test_wrap_aliasing() {
+        // On a tape of 16 cells, offsets 0 and 16 are the same cell.
+        let ops = parse(b"+>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<.").unwrap();
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let ops = Pipeline::for_bounds(level, BoundsPolicy::Wrap).run(ops.clone());
+            let mut out = Vec::new();
+            crate::exec::run(
+                &ops,
+                &mut [0u8; 16],
+                BoundsPolicy::Wrap,
+                crate::EofBehavior::Unchanged,
+                || None,
+                |b| out.push(b),
+            )
+            .unwrap();
+            assert_eq!(out, [2], "at {:?}", level);
+        }
+        assert!(!BuiltinPass::ConstOutput.sound_under(BoundsPolicy::Wrap));
+    }
+
+    #[test]
+    fn test_clamp_aliasing() {
+        // Offsets left of cell 0 name cell 0, and the moves folded into
+        // `OutputAt`s would no longer be clamped one at a time.
+        for (code, expected) in [(&b"-<<+."[..], &[0][..]), (b"+<.<.>>.", &[1, 1, 0])] {
+            let ops = parse(code).unwrap();
+            for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+                let ops = Pipeline::for_bounds(level, BoundsPolicy::Clamp).run(ops.clone());
+                let mut out = Vec::new();
+                crate::exec::run(
+                    &ops,
+                    &mut [0u8; 4],
+                    BoundsPolicy::Clamp,
+                    crate::EofBehavior::Unchanged,
+                    || None,
+                    |b| out.push(b),
+                )
+                .unwrap();
+                assert_eq!(out, expected, "{:?} at {:?}", code, level);
+            }
+        }
+        assert!(BuiltinPass::ConstOutput.sound_under(BoundsPolicy::Grow));
+    }
+
+    #[test]
+    fn test_pipeline_stats() {
+        let ops = parse(b"+[-]>[->+<]").unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .push(BuiltinPass::Clear)
+            .push(BuiltinPass::MoveLoop);
+        let spans = vec![0..0; ops.len()];
+        let (ops, _, stats) = pipeline.run_with_stats(ops, spans);
+        assert_eq!(ops, pipeline.run(parse(b"+[-]>[->+<]").unwrap()));
+        assert_eq!(
+            stats[0],
+            PassStats {
+                name: "clear".into(),
+                ops: (9, 7),
+                loops: (2, 1),
+                kinds: vec![("Clear", 1), ("Jnz", -1), ("Jz", -1), ("ValSub", -1)],
+            }
+        );
+        assert_eq!(
+            (stats[1].name.as_str(), stats[1].loops),
+            ("move-loop", (1, 0))
+        );
+    }
+
+    #[test]
+    fn test_custom_pass() {
+        struct Silence;
+        impl Pass for Silence {
+            fn name(&self) -> &str {
+                "silence"
+            }
+
+            fn run(&self, ops: Vec<Op>) -> Vec<Op> {
+                ops.into_iter().filter(|op| *op != Op::Output).collect()
+            }
+        }
+
+        let mut pipeline = Pipeline::for_level(OptLevel::O2);
+        pipeline.insert(0, Silence).remove("const-output");
+        let names: Vec<&str> = pipeline.passes().map(|pass| pass.name()).collect();
+        assert_eq!(names[..2], ["silence", "divmod"]);
+        assert!(!names.contains(&"const-output"));
+        assert_eq!(
+            pipeline.run(parse(b"+.>.").unwrap()),
+            vec![Op::BulkAdd(vec![(0, 1)]), Op::PtrAdd(1)]
+        );
+
+        // Spans it can't map cover everything the input did.
+        let (ops, spans) =
+            Silence.run_with_spans(vec![Op::Output, Op::PtrAdd(1)], vec![0..1, 1..2]);
+        assert_eq!(ops, vec![Op::PtrAdd(1)]);
+        assert_eq!(spans, vec![0..2; 1]);
+    }
+
+    #[test]
+    fn test_clear_odd_step() {
+        let ops = parse(b"[---]").unwrap();
+        assert_eq!(BuiltinPass::Clear.run(ops), vec![Op::Clear(0)]);
+        // An even step may never reach zero.
+        let ops = parse(b"[--]").unwrap();
+        assert_eq!(BuiltinPass::Clear.run(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_div_mod_loop() {
+        let ops = parse(b"[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]").unwrap();
+        let rewritten = BuiltinPass::DivMod.run(ops.clone());
+        assert_eq!(rewritten[0], Op::DivMod);
+        assert_eq!(rewritten.len(), ops.len() + 1);
+        assert!(verify(&rewritten).is_ok());
+        // Any other loop is left alone.
+        let ops = parse(b"[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<]").unwrap();
+        assert_eq!(BuiltinPass::DivMod.run(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_dead_stores() {
+        let ops = parse(b">+<+>[-]<-.>.").unwrap();
+        let ops = BuiltinPass::Offset.run(BuiltinPass::Clear.run(ops));
+        assert_eq!(
+            BuiltinPass::DeadStore.run(ops),
+            vec![
+                Op::ValAdd(0, 1),
+                Op::Clear(1),
+                Op::ValSub(0, 1),
+                Op::Output,
+                Op::OutputAt(1),
+                Op::PtrAdd(1)
+            ]
+        );
+        // Reads keep the writes before them, and `,` may keep the cell.
+        let ops = vec![Op::ValAdd(0, 1), Op::Output, Op::Clear(0), Op::Input];
+        assert_eq!(BuiltinPass::DeadStore.run(ops.clone()), ops);
+        let ops = vec![Op::ValAdd(1, 1), Op::MulAdd(1, 2), Op::Clear(1)];
+        assert_eq!(BuiltinPass::DeadStore.run(ops), vec![Op::Clear(1)]);
+    }
+
+    #[test]
+    fn test_set_fuses_clear_and_add() {
+        let ops = optimize(parse(b"+[-]+++>[-]-<.").unwrap());
+        assert_eq!(
+            ops,
+            vec![
+                Op::Set(0, 3),
+                Op::Set(1, u32::MAX),
+                Op::OutputConst(vec![3])
+            ]
+        );
+
+        // An add after the cell is read can't fold into the store.
+        let ops = BuiltinPass::Set.run(vec![Op::Clear(0), Op::Output, Op::ValAdd(0, 1)]);
+        assert_eq!(ops, vec![Op::Clear(0), Op::Output, Op::ValAdd(0, 1)]);
+    }
+
+    #[test]
+    fn test_const_output() {
+        let ops = optimize(parse(b"++++.+.>++<.>.").unwrap());
+        assert_eq!(
+            ops,
+            vec![
+                Op::BulkAdd(vec![(0, 5)]),
+                Op::OutputConst(vec![4, 5]),
+                Op::BulkAdd(vec![(1, 2)]),
+                Op::PtrAdd(1),
+                Op::OutputConst(vec![5, 2])
+            ]
+        );
+
+        // The cell isn't known after it is read, and a cell that hasn't been
+        // touched may be off an unchecked tape.
+        for code in [&b",+."[..], b">."] {
+            let ops = optimize(parse(code).unwrap());
+            assert!(
+                !ops.iter().any(|op| matches!(op, Op::OutputConst(_))),
+                "{ops:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_offset_io() {
+        let ops = parse(b">.>,<<+").unwrap();
+        assert_eq!(
+            BuiltinPass::Offset.run(ops),
+            vec![Op::OutputAt(1), Op::InputAt(2), Op::ValAdd(0, 1)]
+        );
+
+        // The pointer is settled before anything that reads the current cell.
+        let ops = parse(b">>[-<+>]<.").unwrap();
+        let optimized = optimize(ops);
+        assert_eq!(
+            optimized,
+            vec![
+                Op::PtrAdd(2),
+                Op::AddTo(-1),
+                Op::BulkClear(vec![0]),
+                Op::OutputAt(-1),
+                Op::PtrAdd(-1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! The known-value analysis the optimizer's passes share: what
+//! straight-line code has left in each cell, and which cells it has shown
+//! to be on the tape.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use crate::Op;
+
+/// What straight-line code is known to have left on the tape, relative to
+/// the pointer. Starts out with every cell zero, as a program does.
+#[derive(Debug, Clone)]
+pub(crate) struct Known {
+    /// Cells whose value differs from `rest`, or isn't known.
+    values: BTreeMap<isize, Option<u32>>,
+    /// The value of every other cell, if known.
+    rest: Option<u32>,
+    /// Cells an op has touched, which must be on the tape.
+    touched: BTreeSet<isize>,
+}
+
+impl Known {
+    pub(crate) fn zeroed() -> Self {
+        Known {
+            values: BTreeMap::new(),
+            rest: Some(0),
+            touched: BTreeSet::new(),
+        }
+    }
+
+    /// Like [`zeroed`](Self::zeroed), also knowing that the current cell is
+    /// on the tape, as the first one always is.
+    pub(crate) fn at_start() -> Self {
+        let mut known = Known::zeroed();
+        known.touched.insert(0);
+        known
+    }
+
+    /// Knows nothing of the tape, as at the start of a fragment.
+    pub(crate) fn unknown() -> Self {
+        Known {
+            values: BTreeMap::new(),
+            rest: None,
+            touched: BTreeSet::new(),
+        }
+    }
+
+    /// The value of the cell `offset` away from the pointer, if known.
+    pub(crate) fn get(&self, offset: isize) -> Option<u32> {
+        self.values.get(&offset).copied().unwrap_or(self.rest)
+    }
+
+    /// Whether an op has touched the cell `offset` away from the pointer,
+    /// so it is known to be on the tape.
+    pub(crate) fn is_touched(&self, offset: isize) -> bool {
+        self.touched.contains(&offset)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<u32>) {
+        self.values.insert(offset, value);
+        self.touched.insert(offset);
+    }
+
+    fn add(&mut self, offset: isize, n: u32) {
+        self.set(offset, self.get(offset).map(|v| v.wrapping_add(n)));
+    }
+
+    fn mul_add(&mut self, offset: isize, factor: u32) {
+        match self.get(0) {
+            Some(0) => {}
+            Some(v) => {
+                let target = self.get(offset);
+                self.set(
+                    offset,
+                    target.map(|t| t.wrapping_add(v.wrapping_mul(factor))),
+                );
+            }
+            // The target may or may not have been touched.
+            None => {
+                self.values.insert(offset, None);
+            }
+        }
+    }
+
+    /// Forgets everything but that the current cell holds `value`, and
+    /// whether it is known to be on the tape.
+    fn reset(&mut self, value: Option<u32>, touched: bool) {
+        self.values = BTreeMap::from([(0, value)]);
+        self.rest = None;
+        self.touched = BTreeSet::new();
+        if touched {
+            self.touched.insert(0);
+        }
+    }
+
+    /// Whether `op` can't fail, or print anything: it only touches cells
+    /// that were touched before, which are known to be on the tape.
+    pub(crate) fn is_silent(&self, op: &Op) -> bool {
+        let touched = |offset: &isize| self.touched.contains(offset);
+        match op {
+            Op::PtrAdd(_) => true,
+            Op::ValAdd(offset, _)
+            | Op::ValSub(offset, _)
+            | Op::Clear(offset)
+            | Op::Set(offset, _) => touched(offset),
+            Op::MulAdd(offset, _) | Op::AddTo(offset) | Op::SubFrom(offset) => {
+                touched(&0) && (self.get(0) == Some(0) || touched(offset))
+            }
+            Op::Transfer(targets) => {
+                touched(&0)
+                    && (self.get(0) == Some(0) || targets.iter().all(|(offset, _)| touched(offset)))
+            }
+            Op::BulkAdd(deltas) => deltas.iter().all(|(offset, _)| touched(offset)),
+            Op::BulkClear(offsets) => offsets.iter().all(touched),
+            Op::ClearRange(offset, n) => (0..*n as isize).all(|i| touched(&(offset + i))),
+            _ => false,
+        }
+    }
+
+    /// Updates what is known for `op` having run.
+    pub(crate) fn apply(&mut self, op: &Op) {
+        match op {
+            Op::PtrAdd(n) => {
+                let values = core::mem::take(&mut self.values);
+                self.values = values.into_iter().map(|(k, v)| (k - n, v)).collect();
+                let touched = core::mem::take(&mut self.touched);
+                self.touched = touched.into_iter().map(|k| k - n).collect();
+            }
+            Op::ValAdd(offset, n) => self.add(*offset, *n),
+            Op::ValSub(offset, n) => self.add(*offset, 0u32.wrapping_sub(*n)),
+            Op::Clear(offset) => self.set(*offset, Some(0)),
+            Op::Set(offset, n) => self.set(*offset, Some(*n)),
+            Op::MulAdd(offset, factor) => self.mul_add(*offset, *factor),
+            Op::AddTo(offset) => self.mul_add(*offset, 1),
+            Op::SubFrom(offset) => self.mul_add(*offset, u32::MAX),
+            Op::Transfer(targets) => {
+                for &(offset, factor) in targets {
+                    self.mul_add(offset, factor);
+                }
+                self.set(0, Some(0));
+            }
+            Op::BulkAdd(deltas) => {
+                for &(offset, n) in deltas {
+                    self.add(offset, n);
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for &offset in offsets {
+                    self.set(offset, Some(0));
+                }
+            }
+            Op::ClearRange(offset, n) => {
+                for i in 0..*n as isize {
+                    self.set(offset + i, Some(0));
+                }
+            }
+            Op::Input | Op::Random => self.set(0, None),
+            Op::InputAt(offset) => self.set(*offset, None),
+            Op::Output => {
+                self.touched.insert(0);
+            }
+            Op::OutputAt(offset) | Op::OutputRepeat(offset, _) => {
+                self.touched.insert(*offset);
+            }
+            Op::OutputConst(_) | Op::Debug => {}
+            // It may or may not have run, so the cells may or may not have
+            // been touched.
+            Op::DivMod => {
+                for offset in 0..7 {
+                    self.values.insert(offset, None);
+                }
+            }
+            // The body is entered, or re-entered, on a non-zero cell.
+            Op::Jz(_) => self.reset(None, true),
+            // Loops and scans end on a zero cell. A scan that runs off a
+            // unchecked tape stops there without failing, so its cell hasn't
+            // been checked.
+            Op::Jnz(_) => self.reset(Some(0), true),
+            Op::ScanLeft | Op::ScanRight | Op::Scan(_) | Op::ClearUntilZero(_) => {
+                self.reset(Some(0), false)
+            }
+            // A procedure's body may be called from anywhere, and a call
+            // may do anything.
+            Op::DefProc(_) | Op::EndProc | Op::CallProc => self.reset(None, false),
+            // Each thread carries on with a pointer and tape of its own.
+            Op::Fork => self.reset(None, false),
+            Op::Store => {
+                self.touched.insert(0);
+            }
+            Op::Retrieve | Op::Bitwise(_) => self.set(0, None),
+            // Nothing runs after it.
+            Op::End => self.reset(None, false),
+            // The other tape may have been used already.
+            Op::SwitchTape(_) => self.reset(None, false),
+        }
+    }
+
+    /// The byte `op` prints, if it is a `.` of a touched cell whose value
+    /// is known. An `OutputRepeat` prints it over and over.
+    pub(crate) fn output(&self, op: &Op) -> Option<u8> {
+        let offset = match op {
+            Op::Output => 0,
+            Op::OutputAt(offset) | Op::OutputRepeat(offset, _) => *offset,
+            _ => return None,
+        };
+        if !self.touched.contains(&offset) {
+            return None;
+        }
+        // Every cell width prints the low byte, which the `u32` value has
+        // right.
+        self.get(offset).map(|v| v as u8)
+    }
+}
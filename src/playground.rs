@@ -0,0 +1,155 @@
+//! A step-wise driver around [`Interpreter`] for front ends that can't
+//! block on input, like a playground in the browser: input is fed in as it
+//! arrives, output collects in memory, and the program runs a bounded
+//! number of ops at a time so the page stays responsive and can animate
+//! the tape between calls.
+//!
+//! The `wasm` feature's `wasm` module exposes it to JavaScript.
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::mem;
+
+use crate::{
+    Cell, Config, Interpreter, Op, OptLevel, ParseError, RuntimeError, StepResult, optimize_with,
+    parse,
+};
+
+/// Where a [`Playground`] run stopped.
+#[cfg_attr(feature = "wasm", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// The step budget ran out; call [`Playground::run`] again to go on.
+    Running,
+    /// The program is waiting at a `,` for [`Playground::feed`] or
+    /// [`Playground::close_input`].
+    NeedsInput,
+    /// The program has ended.
+    Finished,
+}
+
+/// Input fed in piece by piece. Reading it while it's empty but still open
+/// fails with `WouldBlock`, which stops the interpreter before the `,`.
+#[derive(Debug, Default)]
+pub(crate) struct Feed {
+    pub(crate) buf: VecDeque<u8>,
+    pub(crate) closed: bool,
+}
+
+impl Read for Feed {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() && !self.closed {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.buf.read(out)
+    }
+}
+
+/// A program loaded for step-wise execution.
+pub struct Playground<C = u8> {
+    interp: Interpreter<Feed, Vec<u8>, C>,
+}
+
+impl Playground {
+    /// Parses and optimizes `source` for the default [`Config`].
+    pub fn compile(source: &[u8], level: OptLevel) -> Result<Self, ParseError> {
+        Ok(Self::with_config(
+            optimize_with(parse(source)?, level),
+            &Config::default(),
+        ))
+    }
+}
+
+impl<C: Cell> Playground<C> {
+    pub fn with_config(ops: Vec<Op>, config: &Config) -> Self {
+        Playground {
+            interp: Interpreter::with_config(ops, Feed::default(), Vec::new(), config),
+        }
+    }
+
+    /// Queues `input` for the program's `,`.
+    pub fn feed(&mut self, input: &[u8]) {
+        self.interp.io_mut().0.buf.extend(input);
+    }
+
+    /// Marks the end of input: once what was fed is used up, `,` sees EOF
+    /// instead of waiting.
+    pub fn close_input(&mut self) {
+        self.interp.io_mut().0.closed = true;
+    }
+
+    /// Runs at most `steps` ops. Fails if the program runs into the
+    /// [`Config::max_steps`] it was loaded with, or off the tape.
+    ///
+    /// # Panics
+    ///
+    /// If the program touches a cell outside the tape under
+    /// [`BoundsPolicy::Unchecked`](crate::BoundsPolicy::Unchecked), like
+    /// [`Interpreter::run`].
+    pub fn run(&mut self, steps: u64) -> Result<Status, RuntimeError> {
+        match self.interp.run_steps(steps) {
+            StepResult::Running => Ok(Status::Running),
+            StepResult::NeedsInput => Ok(Status::NeedsInput),
+            StepResult::Finished => Ok(Status::Finished),
+            StepResult::Error(e) => Err(e),
+        }
+    }
+
+    /// The output written since the last call.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::take(self.interp.io_mut().1)
+    }
+
+    pub fn tape(&self) -> &[C] {
+        self.interp.tape()
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.interp.ptr()
+    }
+
+    /// Index of the next op to execute.
+    pub fn pc(&self) -> usize {
+        self.interp.pc()
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        self.interp.ops()
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.interp.steps()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EofBehavior;
+
+    #[test]
+    fn test_playground() {
+        let ops = optimize_with(parse(b"+++.,[.,]").unwrap(), OptLevel::O2);
+        let config = Config {
+            eof: EofBehavior::Zero,
+            ..Config::default()
+        };
+        let mut playground = Playground::<u8>::with_config(ops, &config);
+        assert_eq!(playground.run(1).unwrap(), Status::Running);
+        assert_eq!(playground.run(100).unwrap(), Status::NeedsInput);
+        assert_eq!(playground.take_output(), [3]);
+        let waiting = (playground.pc(), playground.steps());
+
+        // Asking again without input changes nothing.
+        assert_eq!(playground.run(100).unwrap(), Status::NeedsInput);
+        assert_eq!((playground.pc(), playground.steps()), waiting);
+
+        playground.feed(b"ab");
+        assert_eq!(playground.run(100).unwrap(), Status::NeedsInput);
+        assert_eq!(playground.take_output(), b"ab");
+        playground.close_input();
+        assert_eq!(playground.run(100).unwrap(), Status::Finished);
+        assert_eq!(playground.tape()[0], 0);
+        assert!(playground.take_output().is_empty());
+    }
+}
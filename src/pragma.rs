@@ -0,0 +1,145 @@
+//! Directives a program gives in `#!` lines at its top, declaring the
+//! machine it needs:
+//!
+//! ```text
+//! #!tape 65536
+//! #!cells 16
+//! #!eof 0
+//! ```
+//!
+//! `tape` is the number of cells, `cells` their width in bits (8, 16 or
+//! 32), and `eof` what `,` stores at the end of input: `unchanged`, `0`
+//! or `-1`. A `#!/` line, such as `#!/usr/bin/env bfi`, is a shebang and
+//! is skipped. The parser treats all these lines as comments, so a `-` in
+//! `#!eof -1` isn't a command.
+
+use core::fmt;
+
+use crate::cell::CellWidth;
+use crate::exec::EofBehavior;
+
+/// What a program's [`pragmas`] ask for; `None` where it doesn't say.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pragmas {
+    pub tape_size: Option<usize>,
+    pub cell_width: Option<CellWidth>,
+    pub eof: Option<EofBehavior>,
+}
+
+/// A `#!` line that [`pragmas`] couldn't read. `line` is 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PragmaError {
+    /// The line doesn't start with `tape`, `cells` or `eof`.
+    UnknownDirective { line: usize },
+    /// The directive's value isn't one it takes.
+    BadValue { line: usize },
+}
+
+impl fmt::Display for PragmaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PragmaError::UnknownDirective { line } => {
+                write!(f, "line {}: expected '#!tape', '#!cells' or '#!eof'", line)
+            }
+            PragmaError::BadValue { line } => {
+                write!(f, "line {}: the directive's value isn't valid", line)
+            }
+        }
+    }
+}
+
+impl core::error::Error for PragmaError {}
+
+/// Reads the directives at the top of `source`. A directive given twice
+/// takes its last value.
+pub fn pragmas(source: &[u8]) -> Result<Pragmas, PragmaError> {
+    let mut pragmas = Pragmas::default();
+    let header = &source[..header_len(source)];
+    for (i, line) in header.split(|&b| b == b'\n').enumerate() {
+        let line_no = i + 1;
+        let Some(directive) = line.strip_prefix(b"#!") else {
+            continue;
+        };
+        if directive.starts_with(b"/") {
+            continue;
+        }
+        let directive = core::str::from_utf8(directive)
+            .map_err(|_| PragmaError::UnknownDirective { line: line_no })?;
+        let mut words = directive.split_whitespace();
+        let bad = PragmaError::BadValue { line: line_no };
+        let value = words.next().and_then(|name| Some((name, words.next()?)));
+        match value {
+            Some(("tape", n)) => {
+                pragmas.tape_size = Some(n.parse().ok().filter(|&n| n > 0).ok_or(bad)?);
+            }
+            Some(("cells", bits)) => {
+                let width = bits.parse().ok().and_then(CellWidth::from_bits);
+                pragmas.cell_width = Some(width.ok_or(bad)?);
+            }
+            Some(("eof", eof)) => {
+                pragmas.eof = Some(match eof {
+                    "unchanged" => EofBehavior::Unchanged,
+                    "0" => EofBehavior::Zero,
+                    "-1" => EofBehavior::MinusOne,
+                    _ => return Err(bad),
+                });
+            }
+            _ => return Err(PragmaError::UnknownDirective { line: line_no }),
+        }
+        if words.next().is_some() {
+            return Err(PragmaError::BadValue { line: line_no });
+        }
+    }
+    Ok(pragmas)
+}
+
+/// The length of the `#!` lines at the top of `source`, with their line
+/// endings.
+pub(crate) fn header_len(source: &[u8]) -> usize {
+    let mut len = 0;
+    while source[len..].starts_with(b"#!") {
+        len = match source[len..].iter().position(|&b| b == b'\n') {
+            Some(end) => len + end + 1,
+            None => source.len(),
+        };
+    }
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ParseOptions, parse_with};
+
+    #[test]
+    fn test_pragmas() {
+        let source = b"#!/usr/bin/env bfi\n#!tape 100\n#!cells 16\r\n#!eof -1\n+.\n#!eof 0\n";
+        assert_eq!(
+            pragmas(source),
+            Ok(Pragmas {
+                tape_size: Some(100),
+                cell_width: Some(CellWidth::U16),
+                eof: Some(EofBehavior::MinusOne),
+            })
+        );
+        // Only the top lines are directives, and none of them is code.
+        assert_eq!(header_len(source), 51);
+        let ops = parse_with(source, &ParseOptions::default()).unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(pragmas(b"+\n#!tape x"), Ok(Pragmas::default()));
+
+        assert_eq!(
+            pragmas(b"#!tape 10\n#!size 8\n"),
+            Err(PragmaError::UnknownDirective { line: 2 })
+        );
+        assert_eq!(
+            pragmas(b"#!cells 12"),
+            Err(PragmaError::BadValue { line: 1 })
+        );
+        assert_eq!(pragmas(b"#!tape 0"), Err(PragmaError::BadValue { line: 1 }));
+        assert_eq!(
+            pragmas(b"#!eof 0 1"),
+            Err(PragmaError::BadValue { line: 1 })
+        );
+    }
+}
@@ -0,0 +1,378 @@
+//! Execution counts gathered by [`Interpreter::enable_profiling`], the
+//! report `bfi --profile` prints from them, and the [`CostModel`] that
+//! `bfi --count-cycles` weighs them with.
+//!
+//! [`Interpreter::enable_profiling`]: crate::Interpreter::enable_profiling
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+
+use crate::{OP_NAMES, Op, op_name};
+
+/// Loops listed in [`Profile::report`].
+pub(crate) const REPORT_LOOPS: usize = 10;
+
+/// How often each op of a program ran.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Executions of each op, by index. A loop's `Jz` counts its entries and
+    /// its `Jnz` its iterations.
+    pub counts: Vec<u64>,
+    /// Cells moved over by each `ScanLeft`, `ScanRight`, `Scan` and
+    /// `ClearUntilZero`, by index; zero for every other op.
+    pub scanned: Vec<u64>,
+}
+
+/// One loop's share of a [`Profile`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// Index of the loop's `Jz`.
+    pub start: usize,
+    /// Index of the loop's `Jnz`.
+    pub end: usize,
+    /// Times the loop was reached.
+    pub entries: u64,
+    /// Times its body ran.
+    pub iterations: u64,
+    /// Ops executed inside it, nested loops included.
+    pub ops: u64,
+}
+
+impl Profile {
+    pub(crate) fn new(len: usize) -> Self {
+        Profile {
+            counts: vec![0; len],
+            scanned: vec![0; len],
+        }
+    }
+
+    /// Ops executed in total.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Every loop of `ops` that ran, hottest (most ops executed) first.
+    pub fn loops(&self, ops: &[Op]) -> Vec<LoopProfile> {
+        let mut loops: Vec<LoopProfile> = ops
+            .iter()
+            .enumerate()
+            .filter_map(|(start, op)| match *op {
+                Op::Jz(end) if self.counts.get(start).is_some_and(|&n| n > 0) => {
+                    Some(LoopProfile {
+                        start,
+                        end,
+                        entries: self.counts[start],
+                        iterations: self.counts.get(end).copied().unwrap_or(0),
+                        ops: self.counts.get(start..=end).map_or(0, |c| c.iter().sum()),
+                    })
+                }
+                _ => None,
+            })
+            .collect();
+        loops.sort_by(|a, b| b.ops.cmp(&a.ops).then(a.start.cmp(&b.start)));
+        loops
+    }
+
+    /// The cycles the run cost under `model`: each op's executions times
+    /// its cost. A scan is also charged its cost again for every cell it
+    /// moved over.
+    pub fn cycles(&self, ops: &[Op], model: &CostModel) -> u64 {
+        ops.iter()
+            .zip(self.counts.iter().zip(&self.scanned))
+            .map(|(op, (&n, &scanned))| (n + scanned) * model.cost(op))
+            .sum()
+    }
+
+    /// Executions of each kind of op, most frequent first.
+    pub fn kinds(&self, ops: &[Op]) -> Vec<(&'static str, u64)> {
+        let mut kinds: Vec<(&'static str, u64)> = Vec::new();
+        for (op, &n) in ops.iter().zip(&self.counts) {
+            let name = op_name(op);
+            match kinds.iter_mut().find(|(kind, _)| *kind == name) {
+                Some((_, total)) => *total += n,
+                None => kinds.push((name, n)),
+            }
+        }
+        kinds.retain(|&(_, n)| n > 0);
+        kinds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        kinds
+    }
+
+    /// A human-readable summary: the hottest loops, executions per kind of
+    /// op, and how far the scans went. Op indices are those `--emit-ir`
+    /// shows for the same program.
+    pub fn report(&self, ops: &[Op]) -> String {
+        let total = self.total();
+        let mut out = String::new();
+        let _ = writeln!(out, "{} ops executed", total);
+
+        let loops = self.loops(ops);
+        if !loops.is_empty() {
+            let _ = writeln!(out, "\nhottest loops:");
+            let _ = writeln!(
+                out,
+                "  {:>11}  {:>6}  {:>11}  {:>9}  loop",
+                "executed", "share", "iterations", "entries"
+            );
+            for l in loops.iter().take(REPORT_LOOPS) {
+                let _ = writeln!(
+                    out,
+                    "  {:>11}  {:>5.1}%  {:>11}  {:>9}  {}..={}",
+                    l.ops,
+                    percent(l.ops, total),
+                    l.iterations,
+                    l.entries,
+                    l.start,
+                    l.end
+                );
+            }
+            if loops.len() > REPORT_LOOPS {
+                let _ = writeln!(out, "  ... and {} more", loops.len() - REPORT_LOOPS);
+            }
+        }
+
+        let _ = writeln!(out, "\nops by kind:");
+        for (name, n) in self.kinds(ops) {
+            let _ = writeln!(out, "  {:<12} {:>11}  {:>5.1}%", name, n, percent(n, total));
+        }
+
+        for name in ["ScanLeft", "ScanRight", "Scan", "ClearUntilZero"] {
+            let (runs, cells) = ops
+                .iter()
+                .zip(self.counts.iter().zip(&self.scanned))
+                .filter(|(op, _)| op_name(op) == name)
+                .fold((0, 0), |(runs, cells), (_, (&n, &s))| (runs + n, cells + s));
+            if runs > 0 {
+                let _ = writeln!(
+                    out,
+                    "\n{} ran {} times over {} cells, {:.1} per scan",
+                    name,
+                    runs,
+                    cells,
+                    cells as f64 / runs as f64
+                );
+            }
+        }
+        out
+    }
+
+    /// The counts as collapsed stacks, the format flamegraph tools read:
+    /// a line for each loop nest that ran ops outside its inner loops,
+    /// such as `program;loop 1..=20;loop 5..=9 120`, where the count is
+    /// the ops executed directly in the innermost loop. The tools add up a
+    /// loop's inclusive count from the lines under it. pbrain procedure
+    /// bodies are frames of their own, named like `proc 3..=8`, under
+    /// `program` rather than their callers.
+    pub fn collapsed(&self, ops: &[Op]) -> String {
+        let mut stacks = BTreeMap::new();
+        let mut frames = vec![String::from("program")];
+        // Where each open frame but `program` ends.
+        let mut ends = Vec::new();
+        for (pc, op) in ops.iter().enumerate() {
+            while ends.last().is_some_and(|&end| end < pc) {
+                ends.pop();
+                frames.pop();
+            }
+            match *op {
+                Op::Jz(end) => {
+                    frames.push(format!("loop {}..={}", pc, end));
+                    ends.push(end);
+                }
+                Op::DefProc(end) => {
+                    frames.push(format!("proc {}..={}", pc, end));
+                    ends.push(end);
+                }
+                _ => {}
+            }
+            if let Some(&n) = self.counts.get(pc).filter(|&&n| n > 0) {
+                *stacks.entry(frames.join(";")).or_insert(0) += n;
+            }
+        }
+        let mut out = String::new();
+        for (stack, n) in stacks {
+            let _ = writeln!(out, "{} {}", stack, n);
+        }
+        out
+    }
+}
+
+/// Cycles charged for each kind of op, by the name [`fmt_ops`] shows it
+/// under, for scoring a run by [`Profile::cycles`] rather than by the
+/// time it took. The default charges every op one cycle, which counts
+/// the ops executed.
+///
+/// [`fmt_ops`]: crate::fmt_ops
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostModel {
+    /// Cycles for kinds without a cost of their own.
+    default: u64,
+    costs: BTreeMap<&'static str, u64>,
+}
+
+/// Why [`CostModel::from_text`] rejected a model, on a 1-based `line`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CostModelError {
+    /// The line doesn't start with `default` or the name of an op.
+    UnknownKind { line: usize },
+    /// The cycles after the name aren't a number.
+    BadCost { line: usize },
+}
+
+impl fmt::Display for CostModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CostModelError::UnknownKind { line } => {
+                write!(f, "line {}: expected 'default' or the name of an op", line)
+            }
+            CostModelError::BadCost { line } => {
+                write!(f, "line {}: expected a number of cycles", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CostModelError {}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel::uniform(1)
+    }
+}
+
+impl CostModel {
+    /// A model charging every op `cycles`.
+    pub fn uniform(cycles: u64) -> Self {
+        CostModel {
+            default: cycles,
+            costs: BTreeMap::new(),
+        }
+    }
+
+    /// Reads a model with an op's name and its cycles on each line, such
+    /// as `MulAdd 3`. `default 2` charges the kinds not listed; blank
+    /// lines and lines starting with `#` are skipped.
+    pub fn from_text(text: &str) -> Result<Self, CostModelError> {
+        let mut model = CostModel::default();
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, cycles) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let cycles = cycles
+                .trim()
+                .parse()
+                .map_err(|_| CostModelError::BadCost { line: line_no })?;
+            if kind == "default" {
+                model.default = cycles;
+            } else if !model.set(kind, cycles) {
+                return Err(CostModelError::UnknownKind { line: line_no });
+            }
+        }
+        Ok(model)
+    }
+
+    /// Charges `cycles` for each op of `kind`. Returns `false`, leaving
+    /// the model unchanged, if no op is called `kind`.
+    pub fn set(&mut self, kind: &str, cycles: u64) -> bool {
+        match OP_NAMES.iter().find(|&&name| name == kind) {
+            Some(&name) => {
+                self.costs.insert(name, cycles);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The cycles one execution of `op` costs.
+    pub fn cost(&self, op: &Op) -> u64 {
+        self.costs.get(op_name(op)).copied().unwrap_or(self.default)
+    }
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * n as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{Interpreter, optimize, parse};
+
+    #[test]
+    fn test_profile_counts() {
+        // Three outer iterations, each running the inner loop twice.
+        let ops = optimize(parse(b"+++[>++[>+>[-]<<-]>[>]<<<-]").unwrap());
+        let mut interp = Interpreter::new(ops.clone(), io::empty(), io::sink());
+        interp.enable_profiling();
+        interp.run().unwrap();
+        let profile = interp.profile().unwrap();
+
+        let loops = profile.loops(&ops);
+        let outer = loops.iter().find(|l| l.start == 1).unwrap();
+        assert_eq!((outer.entries, outer.iterations), (1, 3));
+        assert_eq!(outer.ops, profile.total() - 1);
+        assert!(loops.iter().any(|l| (l.entries, l.iterations) == (3, 6)));
+        assert!(
+            profile
+                .kinds(&ops)
+                .iter()
+                .any(|&(name, _)| name == "ScanRight")
+        );
+
+        let collapsed = profile.collapsed(&ops);
+        let lines: Vec<&str> = collapsed.lines().collect();
+        assert_eq!(lines[0], "program 1");
+        let outer = format!("program;loop 1..={}", outer.end);
+        assert!(lines[1].starts_with(&format!("{} ", outer)));
+        let total: u64 = lines
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, profile.total());
+        assert!(
+            lines
+                .iter()
+                .any(|line| line.starts_with(&format!("{};loop ", outer)))
+        );
+
+        assert_eq!(
+            profile.cycles(&ops, &CostModel::default()),
+            3 + profile.total()
+        );
+        let model = CostModel::from_text("default 0\n\n# one scan step\nScanRight 2\n").unwrap();
+        assert_eq!(profile.cycles(&ops, &model), 12);
+
+        let report = profile.report(&ops);
+        assert!(report.starts_with(&format!("{} ops executed\n", profile.total())));
+        assert!(report.contains("ScanRight ran 3 times over 3 cells, 1.0 per scan"));
+    }
+
+    #[test]
+    fn test_cost_model() {
+        let mut model = CostModel::uniform(2);
+        assert!(model.set("MulAdd", 5));
+        assert!(!model.set("Mul", 5));
+        assert_eq!(model.cost(&Op::MulAdd(1, 1)), 5);
+        assert_eq!(model.cost(&Op::Clear(0)), 2);
+        assert_eq!(
+            CostModel::from_text("Clear 1\nPtrAdd x\n"),
+            Err(CostModelError::BadCost { line: 2 })
+        );
+        assert_eq!(
+            CostModel::from_text("\nclear 1\n"),
+            Err(CostModelError::UnknownKind { line: 2 })
+        );
+        let mut names = OP_NAMES.to_vec();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), OP_NAMES.len());
+    }
+}
@@ -0,0 +1,220 @@
+//! Python bindings, with the `python` feature: a `brainfuck` extension
+//! module built on PyO3. Build it with
+//! `cargo rustc --release --lib --features python --crate-type cdylib` and
+//! copy `target/release/libbrainfuck_rs.so` to `brainfuck.so` (`.pyd` on
+//! Windows) somewhere on Python's path.
+//!
+//! ```python
+//! >>> import brainfuck
+//! >>> program = brainfuck.optimize(brainfuck.parse(b",[.,]"))
+//! >>> brainfuck.Interpreter(program).run(b"hi\0")
+//! b'hi'
+//! ```
+//!
+//! Parse and run failures raise `brainfuck.BrainfuckError` with the
+//! library's message.
+
+use std::collections::VecDeque;
+use std::mem;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::{Config, Interpreter, Op, OptLevel, RuntimeError, optimize_with};
+
+create_exception!(
+    brainfuck,
+    BrainfuckError,
+    PyException,
+    "A parse or run failure."
+);
+
+/// A parsed program, as `parse` and `optimize` return it.
+#[pyclass(name = "Program", module = "brainfuck", frozen)]
+pub struct PyProgram {
+    ops: Vec<Op>,
+}
+
+#[pymethods]
+impl PyProgram {
+    /// The number of ops.
+    fn __len__(&self) -> usize {
+        self.ops.len()
+    }
+}
+
+/// Parses `source` without optimizing it.
+#[pyfunction]
+fn parse(source: &[u8]) -> PyResult<PyProgram> {
+    crate::parse(source)
+        .map(|ops| PyProgram { ops })
+        .map_err(|e| BrainfuckError::new_err(e.to_string()))
+}
+
+/// The same program optimized at `level`, 0 to 3.
+#[pyfunction]
+#[pyo3(signature = (program, level = 2))]
+fn optimize(program: &PyProgram, level: u8) -> PyResult<PyProgram> {
+    let level = match level {
+        0 => OptLevel::O0,
+        1 => OptLevel::O1,
+        2 => OptLevel::O2,
+        3 => OptLevel::O3,
+        _ => return Err(PyValueError::new_err("level must be 0 to 3")),
+    };
+    Ok(PyProgram {
+        ops: optimize_with(program.ops.clone(), level),
+    })
+}
+
+/// Runs a program on a 30,000-cell tape of bytes, all at once or a few ops
+/// at a time. Input is queued with `feed` or `run`; once it is used up,
+/// `,` sees EOF and leaves the cell unchanged.
+#[pyclass(name = "Interpreter", module = "brainfuck", unsendable)]
+pub struct PyInterpreter {
+    interp: Interpreter<VecDeque<u8>, Vec<u8>, u8>,
+    /// Set once the program left the tape, which leaves the interpreter
+    /// mid-op.
+    failed: Option<String>,
+}
+
+#[pymethods]
+impl PyInterpreter {
+    /// Loads `program`, to stop after `max_steps` ops in total if given.
+    #[new]
+    #[pyo3(signature = (program, max_steps = None))]
+    fn new(program: &PyProgram, max_steps: Option<u64>) -> Self {
+        let config = Config {
+            max_steps,
+            ..Config::default()
+        };
+        PyInterpreter {
+            interp: Interpreter::with_config(
+                program.ops.clone(),
+                VecDeque::new(),
+                Vec::new(),
+                &config,
+            ),
+            failed: None,
+        }
+    }
+
+    /// Queues `data` for `,` to read.
+    fn feed(&mut self, data: &[u8]) {
+        self.interp.io_mut().0.extend(data);
+    }
+
+    /// Runs at most `steps` ops and returns whether the program ended.
+    /// After leaving the tape, every further run fails the same way.
+    fn run_for(&mut self, steps: u64) -> PyResult<bool> {
+        if let Some(message) = &self.failed {
+            return Err(BrainfuckError::new_err(message.clone()));
+        }
+        self.interp.run_for(steps).map_err(|e| {
+            let message = e.to_string();
+            if let RuntimeError::PointerOutOfBounds { .. } = e {
+                self.failed = Some(message.clone());
+            }
+            BrainfuckError::new_err(message)
+        })
+    }
+
+    /// Runs one op and returns whether the program ended.
+    fn step(&mut self) -> PyResult<bool> {
+        self.run_for(1)
+    }
+
+    /// Feeds `input`, runs to the end and returns the new output.
+    #[pyo3(signature = (input = &[][..]))]
+    fn run<'py>(&mut self, py: Python<'py>, input: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        self.feed(input);
+        self.run_for(u64::MAX)?;
+        Ok(self.take_output(py))
+    }
+
+    /// The output written since the last call.
+    fn take_output<'py>(&mut self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &mem::take(self.interp.io_mut().1))
+    }
+
+    #[getter]
+    fn tape<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.interp.tape())
+    }
+
+    #[getter]
+    fn ptr(&self) -> usize {
+        self.interp.ptr()
+    }
+
+    /// Index of the next op to execute.
+    #[getter]
+    fn pc(&self) -> usize {
+        self.interp.pc()
+    }
+
+    #[getter]
+    fn steps(&self) -> u64 {
+        self.interp.steps()
+    }
+}
+
+/// The `brainfuck` module.
+#[pymodule]
+fn brainfuck(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("BrainfuckError", m.py().get_type::<BrainfuckError>())?;
+    m.add_class::<PyProgram>()?;
+    m.add_class::<PyInterpreter>()?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(optimize, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    /// Runs `code` with the module imported as `brainfuck`.
+    fn run_python(code: &str) -> PyResult<()> {
+        pyo3::append_to_inittab!(brainfuck);
+        Python::initialize();
+        Python::attach(|py| {
+            let code = CString::new(format!("import brainfuck\n{}", code))?;
+            py.run(&code, None, None)
+        })
+    }
+
+    #[test]
+    fn test_python() {
+        run_python(
+            r#"
+program = brainfuck.optimize(brainfuck.parse(b",[.,]"))
+assert brainfuck.Interpreter(program).run(b"hi\0") == b"hi"
+
+interp = brainfuck.Interpreter(brainfuck.parse(b"+>++"))
+assert not interp.step()
+assert (interp.pc, interp.ptr, interp.tape[0]) == (1, 0, 1)
+assert interp.run_for(10)
+assert (interp.steps, interp.ptr, interp.tape[:2]) == (3, 1, b"\1\2")
+
+try:
+    brainfuck.parse(b"[")
+    raise AssertionError
+except brainfuck.BrainfuckError:
+    pass
+interp = brainfuck.Interpreter(brainfuck.parse(b"<+"))
+for _ in range(2):
+    try:
+        interp.run()
+        raise AssertionError
+    except brainfuck.BrainfuckError as e:
+        assert "tape" in str(e)
+"#,
+        )
+        .unwrap();
+    }
+}
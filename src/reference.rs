@@ -0,0 +1,249 @@
+//! A deliberately naive interpreter that runs brainfuck source one command
+//! at a time, with no IR and no optimization. It is the oracle the
+//! optimizer is checked against, by the tests here and by `bfi --verify`.
+
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use crate::cell::Cell;
+use crate::interpreter::{Input, Output, bidirectional_index, cell_bytes, read_cell, read_char};
+use crate::{
+    BoundsPolicy, Config, Error, FlushPolicy, ParseError, RuntimeError, StepLimitExceeded,
+    TimeLimitExceeded,
+};
+
+/// Interprets `code` with the default [`Config`], reading `,` from `input`
+/// and writing `.` to `output`.
+///
+/// Unlike [`execute`](crate::execute), it takes the source itself, so an
+/// unmatched bracket fails the run with [`Error::Parse`]. Leaving the tape
+/// fails it with [`RuntimeError::PointerOutOfBounds`], whose `pc` is the
+/// command's offset in `code`.
+pub fn execute_reference<R: Read, W: Write>(code: &[u8], input: R, output: W) -> Result<(), Error> {
+    run::<u8, _, _>(code, &Config::default(), input, output).map(|_| ())
+}
+
+/// Interprets `code` under `config`, and returns the final tape and
+/// pointer. Every command counts as one step against the limit.
+pub(crate) fn run<C: Cell, R: Read, W: Write>(
+    code: &[u8],
+    config: &Config,
+    input: R,
+    output: W,
+) -> Result<(Vec<C>, usize), Error> {
+    let jumps = match_brackets(code)?;
+    Ok(run_commands(code, &jumps, config, input, output)?)
+}
+
+fn run_commands<C: Cell, R: Read, W: Write>(
+    code: &[u8],
+    jumps: &[usize],
+    config: &Config,
+    input: R,
+    output: W,
+) -> Result<(Vec<C>, usize), RuntimeError> {
+    // Line endings translate the way the interpreter's do, so the two agree.
+    let mut input = Input::new(input, config.input_newline);
+    let mut output = Output::new(output, FlushPolicy::Exit, config.output_newline);
+    let len = config.tape_size;
+    let mut tape = vec![C::default(); len];
+    let eof = config.eof.value::<C>();
+    let start = Instant::now();
+    let mut ptr = 0usize;
+    let mut pc = 0;
+    let mut steps = 0u64;
+    while pc < code.len() {
+        let command = code[pc];
+        if !b"+-<>.,[]".contains(&command) {
+            pc += 1;
+            continue;
+        }
+        if config.max_steps.is_some_and(|limit| steps >= limit) {
+            return Err(RuntimeError::StepLimitExceeded(StepLimitExceeded {
+                limit: steps,
+            }));
+        }
+        steps += 1;
+        if let Some(limit) = config.timeout
+            && steps.is_multiple_of(1 << 20)
+            && start.elapsed() >= limit
+        {
+            return Err(RuntimeError::TimeLimitExceeded(TimeLimitExceeded { limit }));
+        }
+        match command {
+            b'<' | b'>' => {
+                let step = if command == b'<' { -1 } else { 1 };
+                ptr = match config.bounds {
+                    BoundsPolicy::Clamp => ptr.saturating_add_signed(step).min(len - 1),
+                    BoundsPolicy::Wrap => (ptr + len).wrapping_add_signed(step) % len,
+                    _ => ptr.wrapping_add_signed(step),
+                };
+                pc += 1;
+                continue;
+            }
+            _ => {}
+        }
+        if config.bounds == BoundsPolicy::Grow && ptr as isize >= 0 && ptr >= tape.len() {
+            tape.resize(ptr + 1, C::default());
+        }
+        let idx = match config.bounds {
+            BoundsPolicy::Bidirectional => {
+                bidirectional_index(&mut tape, ptr as isize, usize::MAX).unwrap_or(usize::MAX)
+            }
+            _ => ptr,
+        };
+        let Some(cell) = tape.get_mut(idx) else {
+            return Err(RuntimeError::PointerOutOfBounds { pc, ptr });
+        };
+        match command {
+            b'+' => *cell = cell.wrapping_add(C::from_u32(1)),
+            b'-' => *cell = cell.wrapping_sub(C::from_u32(1)),
+            b'.' => output.write_all(cell_bytes(*cell, config.unicode, &mut [0; 4]))?,
+            b',' => {
+                let read = match config.unicode {
+                    true => read_char(&mut input, eof)?,
+                    false => read_cell(&mut input, eof)?,
+                };
+                if let Some(v) = read {
+                    *cell = v;
+                }
+            }
+            b'[' if cell.is_zero() => pc = jumps[pc],
+            b']' if !cell.is_zero() => pc = jumps[pc],
+            _ => {}
+        }
+        pc += 1;
+    }
+    output.flush()?;
+    Ok((tape, ptr))
+}
+
+/// For each bracket in `code`, the offset of its partner.
+fn match_brackets(code: &[u8]) -> Result<Vec<usize>, ParseError> {
+    let mut jumps = vec![0; code.len()];
+    let mut open = Vec::new();
+    for (i, &byte) in code.iter().enumerate() {
+        match byte {
+            b'[' => open.push(i),
+            b']' => {
+                let start = open
+                    .pop()
+                    .ok_or_else(|| ParseError::unmatched_close(code, i))?;
+                jumps[start] = i;
+                jumps[i] = start;
+            }
+            _ => {}
+        }
+    }
+    match open.pop() {
+        Some(start) => Err(ParseError::unmatched_open(code, start)),
+        None => Ok(jumps),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EofBehavior, GenOptions, Generator, Interpreter, OptLevel, optimize_with, parse};
+
+    #[test]
+    fn test_execute_reference() {
+        let mut output = Vec::new();
+        execute_reference(b"++++++++[>++++++++<-]>+.,[.,]", &b"hi\0"[..], &mut output).unwrap();
+        assert_eq!(output, b"Ahi");
+
+        let err = execute_reference(b"+]", &b""[..], Vec::new()).unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+        let err = execute_reference(b"+ <+", &b""[..], Vec::new()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Runtime(RuntimeError::PointerOutOfBounds { pc: 3, .. })
+        ));
+        let config = Config {
+            tape_size: 4,
+            bounds: BoundsPolicy::Wrap,
+            eof: EofBehavior::Zero,
+            max_steps: Some(100),
+            ..Config::default()
+        };
+        let (tape, ptr) = run::<u8, _, _>(b"<+,+", &config, &b""[..], Vec::new()).unwrap();
+        assert_eq!((tape, ptr), (vec![0, 0, 0, 1], 3));
+        let err = run::<u8, _, _>(b"+[]", &config, &b""[..], Vec::new()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Runtime(RuntimeError::StepLimitExceeded(StepLimitExceeded {
+                limit: 100
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_matches_optimizer() {
+        let programs: [&[u8]; 5] = [
+            b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.",
+            b"+++++[>+++++<-]>[>++<-]>[[-]>+<]>.",
+            b">,[>,]<[.<]",
+            b"+>+>+<<[>[-<+>]<-]>.>.",
+            b"++>+++[<[->>+<<]>>[-<+<+>>]<-]<.",
+        ];
+        let input = b"abc\0";
+        for code in programs {
+            let mut expected = Vec::new();
+            let config = Config {
+                eof: EofBehavior::Zero,
+                ..Config::default()
+            };
+            run::<u8, _, _>(code, &config, &input[..], &mut expected).unwrap();
+            for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+                let ops = optimize_with(parse(code).unwrap(), level);
+                let mut output = Vec::new();
+                Interpreter::<_, _, u8>::with_config(ops, &input[..], &mut output, &config)
+                    .run()
+                    .unwrap();
+                assert_eq!(
+                    output,
+                    expected,
+                    "{} at {:?}",
+                    String::from_utf8_lossy(code),
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_programs() {
+        let config = Config {
+            tape_size: 64,
+            bounds: BoundsPolicy::Wrap,
+            eof: EofBehavior::Zero,
+            max_steps: Some(10_000),
+            ..Config::default()
+        };
+        let mut generator = Generator::new(1);
+        let input = b"xyz";
+        for _ in 0..300 {
+            let code = generator.program(&GenOptions::default());
+            let mut expected = Vec::new();
+            // The optimizer never takes more steps, so only programs that
+            // finish here are compared.
+            let Ok((tape, ptr)) = run::<u8, _, _>(&code, &config, &input[..], &mut expected) else {
+                continue;
+            };
+            for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+                let ops = optimize_with(parse(&code).unwrap(), level);
+                let mut output = Vec::new();
+                let mut interp =
+                    Interpreter::<_, _, u8>::with_config(ops, &input[..], &mut output, &config);
+                interp.run().unwrap();
+                let program = String::from_utf8_lossy(&code);
+                if level != OptLevel::O3 {
+                    assert_eq!(interp.tape(), tape, "{} at {:?}", program, level);
+                    assert_eq!(interp.ptr(), ptr, "{} at {:?}", program, level);
+                }
+                drop(interp);
+                assert_eq!(output, expected, "{} at {:?}", program, level);
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Random, syntactically valid programs for fuzzing and benchmarking.
+//!
+//! Nothing stops a random program from looping forever or leaving the tape,
+//! so run them with a step limit, or on a wrapping tape. Together with
+//! [`execute_reference`](crate::execute_reference) they check that
+//! optimizing never changes what a program does. [`RandomInput`] gives
+//! them something to read.
+
+use alloc::vec::Vec;
+
+/// The shape of the programs a [`Generator`] makes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenOptions {
+    /// Commands in each program, brackets included.
+    pub len: usize,
+    /// How deeply loops may nest.
+    pub max_depth: usize,
+    /// How many commands in a hundred are `,` or `.`.
+    pub io_percent: u8,
+}
+
+impl Default for GenOptions {
+    fn default() -> Self {
+        GenOptions {
+            len: 100,
+            max_depth: 4,
+            io_percent: 10,
+        }
+    }
+}
+
+/// A seeded source of programs. The same seed gives the same programs on
+/// every platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Generator {
+    state: u64,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Generator { state: seed }
+    }
+
+    /// A generator seeded from arbitrary bytes, such as a fuzzer's input.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        // FNV-1a.
+        let seed = data.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+        Generator::new(seed)
+    }
+
+    /// SplitMix64.
+    pub(crate) fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `n`, which must be non-zero.
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    /// A program of exactly `options.len` commands with balanced brackets.
+    /// Loops are never empty, since `[]` on a non-zero cell never ends.
+    pub fn program(&mut self, options: &GenOptions) -> Vec<u8> {
+        let mut code = Vec::with_capacity(options.len);
+        let mut depth = 0;
+        while code.len() < options.len {
+            let left = options.len - code.len();
+            let last = code.last().copied();
+            let can_open = depth < options.max_depth && left >= depth + 3;
+            let can_close = depth > 0 && last != Some(b'[');
+            let command = if left <= depth + 1 && can_close {
+                // Only room to close what is open.
+                b']'
+            } else if left <= depth + 1 {
+                b"+-<>"[self.below(4) as usize]
+            } else if self.below(100) < u64::from(options.io_percent) {
+                if self.below(2) == 0 { b'.' } else { b',' }
+            } else {
+                match self.below(10) {
+                    0 if can_open => b'[',
+                    1 if can_close => b']',
+                    n => b"+-<>+-<>"[n as usize % 8],
+                }
+            };
+            match command {
+                b'[' => depth += 1,
+                b']' => depth -= 1,
+                _ => {}
+            }
+            code.push(command);
+        }
+        code
+    }
+}
+
+/// Endless or limited seeded bytes to read, for stress-testing a program's
+/// input handling or giving two engines the same random input. Each byte
+/// comes from the same sequence as [`Generator`]'s, so the bytes depend only
+/// on the seed, not on how much each read asks for.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct RandomInput {
+    generator: Generator,
+    left: Option<u64>,
+}
+
+#[cfg(feature = "std")]
+impl RandomInput {
+    /// `len` bytes, or never-ending input without it.
+    pub fn new(seed: u64, len: Option<u64>) -> Self {
+        RandomInput {
+            generator: Generator::new(seed),
+            left: len,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Read for RandomInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = match self.left {
+            Some(left) => buf.len().min(usize::try_from(left).unwrap_or(usize::MAX)),
+            None => buf.len(),
+        };
+        for b in &mut buf[..n] {
+            *b = self.generator.next() as u8;
+        }
+        if let Some(left) = &mut self.left {
+            *left -= n as u64;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_program() {
+        let options = GenOptions {
+            len: 60,
+            max_depth: 3,
+            io_percent: 20,
+        };
+        let mut generator = Generator::new(7);
+        for _ in 0..100 {
+            let code = generator.program(&options);
+            assert_eq!(code.len(), 60);
+            assert!(parse(&code).is_ok());
+            assert!(!code.windows(2).any(|w| w == b"[]"));
+        }
+        let a = Generator::from_bytes(b"seed").program(&options);
+        assert_eq!(a, Generator::from_bytes(b"seed").program(&options));
+        assert_ne!(a, Generator::from_bytes(b"seee").program(&options));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_random_input() {
+        use std::io::Read;
+
+        let mut all = Vec::new();
+        RandomInput::new(7, Some(100))
+            .read_to_end(&mut all)
+            .unwrap();
+        assert_eq!(all.len(), 100);
+        // Small reads see the same bytes.
+        let mut input = RandomInput::new(7, Some(100));
+        let mut piecewise = Vec::<u8>::new();
+        let mut buf = [0; 3];
+        while let n @ 1.. = input.read(&mut buf).unwrap() {
+            piecewise.extend(&buf[..n]);
+        }
+        assert_eq!(piecewise, all);
+
+        let mut endless = RandomInput::new(8, None);
+        let mut buf = [0; 100];
+        endless.read_exact(&mut buf).unwrap();
+        assert_ne!(&buf[..], &all[..]);
+        assert_eq!(endless.read(&mut buf).unwrap(), 100);
+    }
+}
@@ -0,0 +1,193 @@
+//! Brainfuck programs that print a given text, for `bf gen`.
+//!
+//! [`text_to_brainfuck`] sets up a few cells near the values the text needs
+//! with one multiplication loop, then prints each byte from whichever cell
+//! is cheapest to move to and adjust. It tries a range of loop counts and
+//! cell counts and keeps the program with the fewest commands.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The most cells the setup loop fills.
+const MAX_CELLS: usize = 8;
+
+/// How much of the text the setup is tuned for.
+const SAMPLE: usize = 64;
+
+/// A brainfuck program that prints `text` and nothing else.
+///
+/// Cells never go above 255 or below zero, so the program prints the same
+/// whatever the cell width.
+pub fn text_to_brainfuck(text: &[u8]) -> String {
+    let sample = &text[..text.len().min(SAMPLE)];
+    let mut values = sample.to_vec();
+    values.sort_unstable();
+    // Without a setup loop, every byte is printed from the first cell.
+    let mut best = (0, Vec::new());
+    let mut best_len = program(0, &[], sample, None);
+    for count in 2..=16 {
+        for cells in 1..=MAX_CELLS.min(values.len()) {
+            let mut factors = factors(&values, count, cells);
+            let mut len = program(count, &factors, sample, None);
+            // Nudge each factor while that makes the program shorter.
+            let mut improved = true;
+            while improved {
+                improved = false;
+                for i in 0..cells {
+                    for nudged in [factors[i].checked_sub(1), Some(factors[i] + 1)]
+                        .into_iter()
+                        .flatten()
+                    {
+                        if nudged * count > 255 {
+                            continue;
+                        }
+                        let old = core::mem::replace(&mut factors[i], nudged);
+                        let nudged_len = program(count, &factors, sample, None);
+                        if nudged_len < len {
+                            len = nudged_len;
+                            improved = true;
+                        } else {
+                            factors[i] = old;
+                        }
+                    }
+                }
+            }
+            if len < best_len {
+                best_len = len;
+                best = (count, factors);
+            }
+        }
+    }
+    let mut code = String::new();
+    program(best.0, &best.1, text, Some(&mut code));
+    code
+}
+
+/// Writes the program that fills cells 1 onwards with `factors` times
+/// `count`, looping on cell 0, and then prints `text`, to `code` if there
+/// is one. Returns the program's length. A `count` of 0 means no loop.
+fn program(count: u32, factors: &[u32], text: &[u8], mut code: Option<&mut String>) -> usize {
+    let mut len = 0;
+    let mut emit = |commands: &str, times: usize| {
+        len += commands.len() * times;
+        if let Some(code) = code.as_deref_mut() {
+            code.push_str(&commands.repeat(times));
+        }
+    };
+    if count > 0 {
+        emit("+", count as usize);
+        emit("[", 1);
+        for &factor in factors {
+            emit(">", 1);
+            emit("+", factor as usize);
+        }
+        emit("<", factors.len());
+        emit("-]", 1);
+    }
+    let mut values: Vec<u32> = core::iter::once(0)
+        .chain(factors.iter().map(|&factor| factor * count))
+        .collect();
+    // Each byte is printed from the cell that makes it and the byte after
+    // it cheapest, counting a command each for moving and adjusting.
+    let cost = |ptr: usize, values: &[u32], cell: usize, byte: u8| {
+        cell.abs_diff(ptr) + values[cell].abs_diff(u32::from(byte)) as usize
+    };
+    let mut ptr = 0;
+    for (i, &byte) in text.iter().enumerate() {
+        let mut best = (usize::MAX, 0);
+        for cell in 0..values.len() {
+            let mut total = cost(ptr, &values, cell, byte);
+            if let Some(&next) = text.get(i + 1) {
+                let old = core::mem::replace(&mut values[cell], u32::from(byte));
+                total += (0..values.len())
+                    .map(|then| cost(cell, &values, then, next))
+                    .min()
+                    .unwrap_or(0);
+                values[cell] = old;
+            }
+            best = best.min((total, cell));
+        }
+        let cell = best.1;
+        emit(if cell > ptr { ">" } else { "<" }, cell.abs_diff(ptr));
+        let byte = u32::from(byte);
+        emit(
+            if byte > values[cell] { "+" } else { "-" },
+            values[cell].abs_diff(byte) as usize,
+        );
+        emit(".", 1);
+        ptr = cell;
+        values[cell] = byte;
+    }
+    len
+}
+
+/// What the setup loop adds to each of `cells` cells per turn, when it
+/// runs `count` times, for the sorted bytes `values` to be close to one of
+/// them.
+///
+/// The bytes are split into runs of about the same size, and then moved
+/// between them a few times, k-means style.
+fn factors(values: &[u8], count: u32, cells: usize) -> Vec<u32> {
+    let max = 255 / count;
+    let factor = |group: &[u8]| {
+        let median = group.get(group.len() / 2).map_or(0, |&v| u32::from(v));
+        ((median + count / 2) / count).min(max)
+    };
+    let mut factors: Vec<u32> = (0..cells)
+        .map(|i| factor(&values[i * values.len() / cells..(i + 1) * values.len() / cells]))
+        .collect();
+    for _ in 0..4 {
+        // `values` is sorted, so each cell's bytes are a run of it.
+        let mut bounds = vec![0];
+        for pair in factors.windows(2) {
+            let mid = (pair[0] + pair[1]) * count / 2;
+            bounds.push(values.partition_point(|&v| u32::from(v) < mid));
+        }
+        bounds.push(values.len());
+        for (i, factor_i) in factors.iter_mut().enumerate() {
+            let group = &values[bounds[i]..bounds[i + 1].max(bounds[i])];
+            if !group.is_empty() {
+                *factor_i = factor(group);
+            }
+        }
+        factors.sort_unstable();
+    }
+    factors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{self, BoundsPolicy, EofBehavior};
+    use crate::{optimize, parse};
+
+    fn output(code: &str) -> Vec<u8> {
+        let ops = optimize(parse(code.as_bytes()).unwrap());
+        let mut tape = [0u16; 16];
+        let mut out = Vec::new();
+        exec::run(
+            &ops,
+            &mut tape,
+            BoundsPolicy::Error,
+            EofBehavior::Unchanged,
+            || None,
+            |b| out.push(b),
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_text_to_brainfuck() {
+        let text = b"Hello, World!\n";
+        let code = text_to_brainfuck(text);
+        assert_eq!(output(&code), text);
+        // Adjusting one cell from byte to byte would take 390 commands.
+        assert!(code.len() < 140, "{} commands", code.len());
+
+        let extremes = b"\xff\0\xfe\x01\x80";
+        assert_eq!(output(&text_to_brainfuck(extremes)), extremes);
+        assert_eq!(text_to_brainfuck(b""), "");
+    }
+}
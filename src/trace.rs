@@ -0,0 +1,539 @@
+//! Instruction traces from [`Interpreter::run_traced`]: every executed op
+//! with the machine state it ran in, for diffing runs of the same program
+//! at different optimization levels, as text or as JSON Lines for other
+//! tools.
+//!
+//! [`Interpreter::run_traced`]: crate::Interpreter::run_traced
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::analysis::{reach, writes};
+use crate::exec::index;
+use crate::{BoundsPolicy, Op, fmt_op};
+
+/// One op about to execute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event<'a> {
+    /// Ops executed before this one, as [`Interpreter::steps`] counts them.
+    ///
+    /// [`Interpreter::steps`]: crate::Interpreter::steps
+    pub step: u64,
+    pub pc: usize,
+    pub op: &'a Op,
+    pub ptr: usize,
+    /// The current cell, or 0 if the pointer is off the tape.
+    pub value: u32,
+}
+
+/// The machine when a traced run stops.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct End {
+    /// Whether the program ran to its end, rather than failing.
+    pub finished: bool,
+    pub steps: u64,
+    pub pc: usize,
+    pub ptr: usize,
+    /// The tape up to its last non-zero cell.
+    pub tape: Vec<u32>,
+    /// On a [`BoundsPolicy::Bidirectional`] tape, the cells left of cell 0
+    /// up to the last non-zero one, cell -1 first. `tape` starts at cell 0.
+    pub left: Vec<u32>,
+}
+
+/// Receives the [`Event`]s of a traced run.
+pub trait Tracer {
+    fn trace(&mut self, event: &Event<'_>) -> io::Result<()>;
+
+    /// Called once the run stops, even if it failed. Tracers that buffer
+    /// their output flush it here.
+    fn end(&mut self, end: &End) -> io::Result<()> {
+        let _ = end;
+        Ok(())
+    }
+}
+
+/// An [`Event`] without its op, to keep after the run moves on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub step: u64,
+    pub pc: usize,
+    pub ptr: usize,
+    pub value: u32,
+}
+
+/// Keeps only the last `capacity` events, for reporting what led up to a
+/// failure.
+#[derive(Debug, Clone)]
+pub struct RingTracer {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl RingTracer {
+    pub fn new(capacity: usize) -> Self {
+        RingTracer {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The kept events, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// The last event, which was about to run when the trace stopped.
+    pub fn last(&self) -> Option<&Entry> {
+        self.entries.back()
+    }
+}
+
+impl Tracer for RingTracer {
+    fn trace(&mut self, event: &Event<'_>) -> io::Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(Entry {
+            step: event.step,
+            pc: event.pc,
+            ptr: event.ptr,
+            value: event.value,
+        });
+        Ok(())
+    }
+}
+
+/// Writes each event as a line of text:
+///
+/// ```text
+///        12  pc     3  ptr     1  cell   8  ValAdd(0, 1)
+/// ```
+pub struct TextTracer<W> {
+    output: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(output: W) -> Self {
+        TextTracer { output }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, event: &Event<'_>) -> io::Result<()> {
+        writeln!(
+            self.output,
+            "{:>9}  pc {:>5}  ptr {:>5}  cell {:>3}  {}",
+            event.step,
+            event.pc,
+            event.ptr,
+            event.value,
+            fmt_op(event.op)
+        )
+    }
+
+    fn end(&mut self, _end: &End) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Writes each event as a JSON object on its own line, followed by one for
+/// the final state:
+///
+/// ```text
+/// {"event":"op","step":12,"pc":3,"op":"ValAdd(0, 1)","ptr":1,"value":8}
+/// {"event":"end","finished":true,"steps":40,"pc":9,"ptr":2,"tape":[0,72]}
+/// ```
+///
+/// On a bidirectional tape, the end also has the cells left of cell 0 as
+/// `"left"`, cell -1 first, unless they are all zero.
+///
+/// `op` is spelled as [`fmt_ops`](crate::fmt_ops) shows it.
+pub struct JsonTracer<W> {
+    output: W,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(output: W) -> Self {
+        JsonTracer { output }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn trace(&mut self, event: &Event<'_>) -> io::Result<()> {
+        write!(
+            self.output,
+            "{{\"event\":\"op\",\"step\":{},\"pc\":{},\"op\":",
+            event.step, event.pc
+        )?;
+        write_json_string(&mut self.output, &fmt_op(event.op))?;
+        writeln!(
+            self.output,
+            ",\"ptr\":{},\"value\":{}}}",
+            event.ptr, event.value
+        )
+    }
+
+    fn end(&mut self, end: &End) -> io::Result<()> {
+        write!(
+            self.output,
+            "{{\"event\":\"end\",\"finished\":{},\"steps\":{},\"pc\":{},\"ptr\":{},\"tape\":",
+            end.finished, end.steps, end.pc, end.ptr
+        )?;
+        write_json_cells(&mut self.output, &end.tape)?;
+        if !end.left.is_empty() {
+            self.output.write_all(b",\"left\":")?;
+            write_json_cells(&mut self.output, &end.left)?;
+        }
+        self.output.write_all(b"}\n")?;
+        self.output.flush()
+    }
+}
+
+/// Works out how much of the tape a run used, for `bfi --mem-stats`: the
+/// rightmost cell it reached, how many cells it stored to, and which were
+/// left non-zero. It tells how big a tape the program needs.
+///
+/// Cells are counted the way the optimized ops reach them, so a move loop
+/// whose counter is zero doesn't reach its targets. On a
+/// [`BoundsPolicy::Bidirectional`] tape, cells left of cell 0 count too,
+/// with negative numbers.
+#[derive(Debug, Clone)]
+pub struct MemStats {
+    len: usize,
+    policy: BoundsPolicy,
+    /// Whether each cell from 0 on has been stored to.
+    written: Vec<bool>,
+    /// The same for the cells left of cell 0, cell -1 first.
+    written_left: Vec<bool>,
+    count: usize,
+    rightmost: usize,
+    leftmost: isize,
+    /// Where a `ClearUntilZero` started and its stride; it cleared every
+    /// cell up to where the next op finds the pointer.
+    sweep: Option<(isize, isize)>,
+    nonzero: Option<RangeInclusive<isize>>,
+}
+
+impl MemStats {
+    /// Stats for a run on a tape of `len` cells, which leaving it treats
+    /// as `policy` says.
+    pub fn new(len: usize, policy: BoundsPolicy) -> Self {
+        MemStats {
+            len,
+            policy,
+            written: Vec::new(),
+            written_left: Vec::new(),
+            count: 0,
+            rightmost: 0,
+            leftmost: 0,
+            sweep: None,
+            nonzero: None,
+        }
+    }
+
+    /// The rightmost cell the pointer or an op reached.
+    pub fn rightmost(&self) -> usize {
+        self.rightmost
+    }
+
+    /// The leftmost cell the pointer or an op reached, which is below 0
+    /// only on a bidirectional tape.
+    pub fn leftmost(&self) -> isize {
+        self.leftmost
+    }
+
+    /// The number of distinct cells stored to.
+    pub fn written(&self) -> usize {
+        self.count
+    }
+
+    /// The first and last cells that were non-zero when the run stopped,
+    /// or `None` if the tape was all zeros.
+    pub fn nonzero(&self) -> Option<RangeInclusive<isize>> {
+        self.nonzero.clone()
+    }
+
+    /// The stats as `bfi --mem-stats` prints them.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        if self.policy == BoundsPolicy::Bidirectional {
+            let _ = writeln!(out, "leftmost cell reached: {}", self.leftmost);
+        }
+        let _ = writeln!(out, "rightmost cell reached: {}", self.rightmost);
+        let _ = writeln!(out, "cells written: {}", self.count);
+        let _ = match &self.nonzero {
+            Some(cells) => writeln!(
+                out,
+                "non-zero at the end: cells {} to {}",
+                cells.start(),
+                cells.end()
+            ),
+            None => writeln!(out, "non-zero at the end: none"),
+        };
+        out
+    }
+
+    /// The cell `offset` away from `ptr`, if it is on the tape.
+    fn cell(&self, ptr: usize, offset: isize) -> Option<isize> {
+        match self.policy {
+            // The pointer wraps around to the cells left of cell 0.
+            BoundsPolicy::Bidirectional => Some(ptr.wrapping_add_signed(offset) as isize),
+            // The tape grows to whatever is reached right of cell 0.
+            BoundsPolicy::Grow => ptr
+                .checked_add_signed(offset)
+                .map(|cell| cell as isize)
+                .filter(|&cell| cell >= 0),
+            policy => index(ptr, offset, self.len, policy).map(|cell| cell as isize),
+        }
+    }
+
+    fn reach(&mut self, cell: isize) {
+        match usize::try_from(cell) {
+            Ok(cell) => self.rightmost = self.rightmost.max(cell),
+            Err(_) => self.leftmost = self.leftmost.min(cell),
+        }
+    }
+
+    fn write(&mut self, cell: isize) {
+        self.reach(cell);
+        let (written, i) = match usize::try_from(cell) {
+            Ok(cell) => (&mut self.written, cell),
+            Err(_) => (&mut self.written_left, cell.unsigned_abs() - 1),
+        };
+        if i >= written.len() {
+            written.resize(i + 1, false);
+        }
+        if !written[i] {
+            written[i] = true;
+            self.count += 1;
+        }
+    }
+
+    /// Counts the cells a `ClearUntilZero` cleared before stopping with
+    /// the pointer at `end`.
+    fn end_sweep(&mut self, end: usize) {
+        let Some((mut cell, stride)) = self.sweep.take() else {
+            return;
+        };
+        let Some(end) = self.cell(end, 0) else {
+            return;
+        };
+        // A clamped sweep can stand still at the edge.
+        for _ in 0..self.len.max(cell.abs_diff(end).saturating_add(1)) {
+            if cell == end {
+                break;
+            }
+            self.write(cell);
+            match self.cell(cell as usize, stride) {
+                Some(next) => cell = next,
+                None => break,
+            }
+        }
+    }
+}
+
+impl Tracer for MemStats {
+    fn trace(&mut self, event: &Event<'_>) -> io::Result<()> {
+        self.end_sweep(event.ptr);
+        let here = self.cell(event.ptr, 0);
+        if let Some(cell) = here {
+            self.reach(cell);
+        }
+        let op = event.op;
+        match *op {
+            Op::ClearUntilZero(stride) => self.sweep = here.map(|cell| (cell, stride)),
+            // These do nothing on a zero cell.
+            Op::MulAdd(..) | Op::AddTo(_) | Op::SubFrom(_) | Op::Transfer(_) | Op::DivMod
+                if event.value == 0 => {}
+            _ => {
+                for offset in reach(op).into_iter().flatten() {
+                    let Some(cell) = self.cell(event.ptr, offset) else {
+                        continue;
+                    };
+                    if writes(op, offset) {
+                        self.write(cell);
+                    } else {
+                        self.reach(cell);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, end: &End) -> io::Result<()> {
+        self.end_sweep(end.ptr);
+        if let Some(cell) = self.cell(end.ptr, 0) {
+            self.reach(cell);
+        }
+        // Cell -k is `end.left[k - 1]`.
+        let left = |i: usize| -(i as isize) - 1;
+        let first = (end.left.iter().rposition(|&v| v != 0).map(left))
+            .or_else(|| end.tape.iter().position(|&v| v != 0).map(|i| i as isize));
+        let last = (end.tape.iter().rposition(|&v| v != 0).map(|i| i as isize))
+            .or_else(|| end.left.iter().position(|&v| v != 0).map(left));
+        self.nonzero = first.zip(last).map(|(first, last)| first..=last);
+        Ok(())
+    }
+}
+
+fn write_json_cells(output: &mut impl Write, cells: &[u32]) -> io::Result<()> {
+    output.write_all(b"[")?;
+    for (i, v) in cells.iter().enumerate() {
+        if i > 0 {
+            output.write_all(b",")?;
+        }
+        write!(output, "{}", v)?;
+    }
+    output.write_all(b"]")
+}
+
+fn write_json_string(output: &mut impl Write, s: &str) -> io::Result<()> {
+    output.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => output.write_all(b"\\\"")?,
+            '\\' => output.write_all(b"\\\\")?,
+            c if u32::from(c) < 0x20 => write!(output, "\\u{:04x}", u32::from(c))?,
+            c => write!(output, "{}", c)?,
+        }
+    }
+    output.write_all(b"\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::{Interpreter, parse};
+
+    #[test]
+    fn test_text_trace() {
+        let ops = parse(b"++[-]>.").unwrap();
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        let mut tracer = TextTracer::new(Vec::new());
+        interp.run_traced(&mut tracer, 2..=4).unwrap();
+        assert!(interp.is_finished());
+        let trace = String::from_utf8(tracer.into_inner()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "        2  pc     2  ptr     0  cell   2  ValSub(0, 1)"
+        );
+        assert!(lines[1].ends_with("pc     3  ptr     0  cell   1  Jnz -> 1"));
+    }
+
+    #[test]
+    fn test_ring_trace() {
+        let ops = parse(b"+[>+<+]").unwrap();
+        let config = crate::Config {
+            max_steps: Some(20),
+            ..crate::Config::default()
+        };
+        let mut interp =
+            Interpreter::<_, _, u8>::with_config(ops, io::empty(), io::sink(), &config);
+        let mut tracer = RingTracer::new(3);
+        assert!(interp.run_traced(&mut tracer, 0..=u64::MAX).is_err());
+        let steps: Vec<u64> = tracer.entries().map(|e| e.step).collect();
+        assert_eq!(steps, [17, 18, 19]);
+        assert_eq!(tracer.last().map(|e| e.pc), Some(4));
+    }
+
+    #[test]
+    fn test_json_trace() {
+        let ops = parse(b"+>++.").unwrap();
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        let mut tracer = JsonTracer::new(Vec::new());
+        interp.run_traced(&mut tracer, 1..=1).unwrap();
+        let trace = String::from_utf8(tracer.into_inner()).unwrap();
+        assert_eq!(
+            trace,
+            "{\"event\":\"op\",\"step\":1,\"pc\":1,\"op\":\"ValAdd(1, 2)\",\"ptr\":0,\"value\":1}\n\
+             {\"event\":\"end\",\"finished\":true,\"steps\":4,\"pc\":4,\"ptr\":1,\"tape\":[1,2]}\n"
+        );
+
+        let mut out = Vec::new();
+        write_json_string(&mut out, "OutputConst(\"a\\n\")").unwrap();
+        assert_eq!(out, br#""OutputConst(\"a\\n\")""#);
+    }
+
+    #[test]
+    fn test_mem_stats() {
+        // The sweep clears cells 4 to 2, and the move loop stores to 1, 2
+        // and 5.
+        let ops = crate::optimize(parse(b">>+>+>+[[-]<]+[->+>>>++<<<<]>>>>>>.").unwrap());
+        let mut interp = Interpreter::new(ops, io::empty(), io::sink());
+        let mut stats = MemStats::new(30000, BoundsPolicy::Error);
+        interp.run_traced(&mut stats, 0..=u64::MAX).unwrap();
+        assert_eq!(stats.rightmost(), 7);
+        assert_eq!(stats.written(), 5);
+        assert_eq!(stats.nonzero(), Some(2..=5));
+        assert_eq!(
+            stats.report(),
+            "rightmost cell reached: 7\ncells written: 5\nnon-zero at the end: cells 2 to 5\n"
+        );
+
+        // A wrapping tape is reached from its other end.
+        let ops = parse(b"<<+").unwrap();
+        let config = crate::Config {
+            tape_size: 10,
+            bounds: BoundsPolicy::Wrap,
+            ..crate::Config::default()
+        };
+        let mut interp =
+            Interpreter::<_, _, u8>::with_config(ops, io::empty(), io::sink(), &config);
+        let mut stats = MemStats::new(10, BoundsPolicy::Wrap);
+        interp.run_traced(&mut stats, 0..=u64::MAX).unwrap();
+        assert_eq!((stats.rightmost(), stats.written()), (8, 1));
+    }
+
+    #[test]
+    fn test_mem_stats_bidirectional() {
+        // Each sweep starts left of cell 0, and the second ends there.
+        let config = crate::Config {
+            tape_size: 8,
+            bounds: BoundsPolicy::Bidirectional,
+            ..crate::Config::default()
+        };
+        for (code, ptr, report) in [
+            (
+                &b"+<<---[[-]>]"[..],
+                -1,
+                "leftmost cell reached: -2\nrightmost cell reached: 0\n\
+                 cells written: 2\nnon-zero at the end: cells 0 to 0\n",
+            ),
+            (
+                b"+<<+[[-]<]>>+.",
+                -1,
+                "leftmost cell reached: -3\nrightmost cell reached: 0\n\
+                 cells written: 3\nnon-zero at the end: cells -1 to 0\n",
+            ),
+        ] {
+            let ops = parse(code).unwrap();
+            for ops in [ops.clone(), crate::optimize(ops)] {
+                let mut interp =
+                    Interpreter::<_, _, u8>::with_config(ops, io::empty(), io::sink(), &config);
+                let mut stats = MemStats::new(8, BoundsPolicy::Bidirectional);
+                interp.run_traced(&mut stats, 0..=u64::MAX).unwrap();
+                assert_eq!(interp.ptr() as isize, ptr);
+                assert_eq!(stats.report(), report);
+            }
+        }
+    }
+}
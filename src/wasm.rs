@@ -0,0 +1,118 @@
+//! The playground's engine for JavaScript, with the `wasm` feature: build
+//! the crate with `cargo rustc --release --lib --features wasm
+//! --crate-type cdylib --target wasm32-unknown-unknown` and run
+//! `wasm-bindgen` on the module for its JavaScript bindings.
+//!
+//! JavaScript gets a `Playground` class around a [`Playground`]: its
+//! constructor compiles a source, `run` runs it to the end on an input,
+//! and `feed` and `step` run it a few ops at a time for animation, `step`
+//! returning a [`Status`]. Byte strings go in and out as `Uint8Array`s,
+//! and parse and run failures are thrown as their messages.
+
+use wasm_bindgen::prelude::*;
+
+use crate::OptLevel;
+use crate::playground::{Playground, Status};
+
+/// A compiled program, as the JavaScript `Playground` class.
+#[wasm_bindgen(js_name = Playground)]
+pub struct WasmPlayground {
+    playground: Playground,
+}
+
+#[wasm_bindgen(js_class = Playground)]
+impl WasmPlayground {
+    /// Parses and optimizes `source` for the default 30,000-cell tape.
+    #[wasm_bindgen(constructor)]
+    pub fn new(source: &[u8]) -> Result<WasmPlayground, String> {
+        match Playground::compile(source, OptLevel::O2) {
+            Ok(playground) => Ok(WasmPlayground { playground }),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Runs the program to the end on `input` and what was fed before,
+    /// with EOF after them. What it wrote is in
+    /// [`take_output`](Self::take_output) even if it fails.
+    pub fn run(&mut self, input: &[u8]) -> Result<(), String> {
+        self.feed(input);
+        self.close_input();
+        self.playground
+            .run(u64::MAX)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Appends `input` to what `,` reads.
+    pub fn feed(&mut self, input: &[u8]) {
+        self.playground.feed(input);
+    }
+
+    /// Marks the end of input: once what was fed is used up, `,` sees EOF
+    /// instead of waiting.
+    #[wasm_bindgen(js_name = closeInput)]
+    pub fn close_input(&mut self) {
+        self.playground.close_input();
+    }
+
+    /// Runs at most `steps` more ops, for animating a run between frames.
+    pub fn step(&mut self, steps: u32) -> Result<Status, String> {
+        self.playground
+            .run(u64::from(steps))
+            .map_err(|e| e.to_string())
+    }
+
+    /// The output written since the last call.
+    #[wasm_bindgen(js_name = takeOutput)]
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.playground.take_output()
+    }
+
+    /// A copy of the tape's cells.
+    #[wasm_bindgen(getter)]
+    pub fn tape(&self) -> Vec<u8> {
+        self.playground.tape().to_vec()
+    }
+
+    /// The data pointer.
+    #[wasm_bindgen(getter)]
+    pub fn ptr(&self) -> usize {
+        self.playground.ptr()
+    }
+
+    /// The index of the next op to execute.
+    #[wasm_bindgen(getter)]
+    pub fn pc(&self) -> usize {
+        self.playground.pc()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wasm() {
+        let code = b"+++.,[.,]";
+        let mut playground = WasmPlayground::new(code).unwrap();
+        assert_eq!(playground.step(1), Ok(Status::Running));
+        assert_eq!(playground.step(100), Ok(Status::NeedsInput));
+        assert_eq!(playground.take_output(), [3]);
+        playground.feed(b"a");
+        assert_eq!(playground.step(100), Ok(Status::NeedsInput));
+        assert_eq!(playground.take_output(), b"a");
+        assert_eq!(playground.run(b"bc\0"), Ok(()));
+        assert_eq!(playground.take_output(), b"bc");
+        assert_eq!(playground.tape().len(), 30_000);
+        assert_eq!((playground.tape()[0], playground.ptr()), (0, 0));
+        assert_eq!(
+            playground.pc(),
+            Playground::compile(code, OptLevel::O2).unwrap().ops().len()
+        );
+
+        assert!(WasmPlayground::new(b"[").is_err());
+        let mut playground = WasmPlayground::new(b"+.<+").unwrap();
+        assert!(playground.run(b"").is_err());
+        assert_eq!(playground.take_output(), [1]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Just enough of an x86-64 encoder for the JIT.
+//!
+//! Generated code keeps its state in fixed registers: `rbx` holds the tape
+//! base, `r12` the data pointer (a cell index), `r13` the tape length in
+//! cells and `r14` the run context. `rax`, `rcx`, `rdx`, `rsi` and `rdi` are
+//! scratch.
+
+use crate::cell::CellWidth;
+
+/// A jump target, bound to a code position with [`Assembler::bind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Equal,
+    NotEqual,
+    /// Unsigned `>=`.
+    AboveEqual,
+    /// Signed `<=`.
+    LessEqual,
+}
+
+impl Cond {
+    fn code(self) -> u8 {
+        match self {
+            Cond::Equal => 0x84,
+            Cond::NotEqual => 0x85,
+            Cond::AboveEqual => 0x83,
+            Cond::LessEqual => 0x8e,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Assembler {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    /// Positions of rel32 fields and the labels they jump to.
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Assembler::default()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    /// Appends raw machine code, for fixed sequences with no helper.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.bytes(&[0; 4]);
+    }
+
+    /// Saves the callee-saved registers and loads the state registers from
+    /// the System V arguments `(tape, ptr, len, ctx)`.
+    pub fn prologue(&mut self) {
+        self.bytes(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        self.bytes(&[0x48, 0x89, 0xfb]); // mov rbx, rdi
+        self.bytes(&[0x49, 0x89, 0xf4]); // mov r12, rsi
+        self.bytes(&[0x49, 0x89, 0xd5]); // mov r13, rdx
+        self.bytes(&[0x49, 0x89, 0xce]); // mov r14, rcx
+    }
+
+    /// Stores the data pointer to the first word of the context and returns
+    /// `eax`.
+    pub fn epilogue(&mut self) {
+        self.bytes(&[0x4d, 0x89, 0x26]); // mov [r14], r12
+        self.bytes(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b]);
+        self.bytes(&[0xc3]);
+    }
+
+    /// `mov [r14 + 8], eax`: the second word of the context.
+    pub fn store_ctx_pc(&mut self) {
+        self.bytes(&[0x41, 0x89, 0x46, 0x08]);
+    }
+
+    pub fn mov_eax_imm(&mut self, imm: u32) {
+        self.bytes(&[0xb8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `lea rax, [r12 + offset]`: the index of the cell `offset` away.
+    pub fn cell_index(&mut self, offset: i32) {
+        self.bytes(&[0x49, 0x8d, 0x84, 0x24]);
+        self.bytes(&offset.to_le_bytes());
+    }
+
+    /// `cmp rax, r13`
+    pub fn cmp_index_len(&mut self) {
+        self.bytes(&[0x4c, 0x39, 0xe8]);
+    }
+
+    /// `add r12, n`
+    pub fn add_ptr(&mut self, n: i32) {
+        self.bytes(&[0x49, 0x81, 0xc4]);
+        self.bytes(&n.to_le_bytes());
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.bytes(&[0x0f, cond.code()]);
+        self.rel32(label);
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.bytes(&[0xe9]);
+        self.rel32(label);
+    }
+
+    /// Emits the operand-size prefix for 16-bit cells, then `opcode` with a
+    /// ModRM/SIB pair addressing the cell at `[rbx + rax * width]`.
+    fn cell_operand(&mut self, width: CellWidth, opcode: &[u8], reg: u8) {
+        if width == CellWidth::U16 {
+            self.bytes(&[0x66]);
+        }
+        let scale = match width {
+            CellWidth::U8 => 0,
+            CellWidth::U16 => 1,
+            CellWidth::U32 => 2,
+        };
+        self.bytes(opcode);
+        self.bytes(&[(reg << 3) | 0b100, (scale << 6) | 0b011]);
+    }
+
+    fn cell_imm(&mut self, width: CellWidth, imm: u32) {
+        match width {
+            CellWidth::U8 => self.bytes(&[imm as u8]),
+            CellWidth::U16 => self.bytes(&(imm as u16).to_le_bytes()),
+            CellWidth::U32 => self.bytes(&imm.to_le_bytes()),
+        }
+    }
+
+    /// `add cell[rax], imm`
+    pub fn add_cell_imm(&mut self, width: CellWidth, imm: u32) {
+        let opcode: &[u8] = if width == CellWidth::U8 {
+            &[0x80]
+        } else {
+            &[0x81]
+        };
+        self.cell_operand(width, opcode, 0);
+        self.cell_imm(width, imm);
+    }
+
+    /// `mov cell[rax], imm`
+    pub fn mov_cell_imm(&mut self, width: CellWidth, imm: u32) {
+        let opcode: &[u8] = if width == CellWidth::U8 {
+            &[0xc6]
+        } else {
+            &[0xc7]
+        };
+        self.cell_operand(width, opcode, 0);
+        self.cell_imm(width, imm);
+    }
+
+    /// `cmp cell[rax], 0`
+    pub fn cmp_cell_zero(&mut self, width: CellWidth) {
+        let opcode: &[u8] = if width == CellWidth::U8 {
+            &[0x80]
+        } else {
+            &[0x83]
+        };
+        self.cell_operand(width, opcode, 7);
+        self.bytes(&[0]);
+    }
+
+    /// Zero-extends cell[rax] into `ecx`.
+    pub fn load_cell_ecx(&mut self, width: CellWidth) {
+        match width {
+            CellWidth::U8 => self.cell_operand(width, &[0x0f, 0xb6], 1),
+            // movzx ecx, word [...] takes no operand-size prefix.
+            CellWidth::U16 => {
+                self.bytes(&[0x0f, 0xb7, 0x0c, 0x43]);
+            }
+            CellWidth::U32 => self.cell_operand(width, &[0x8b], 1),
+        }
+    }
+
+    /// `test ecx, ecx`
+    pub fn test_ecx(&mut self) {
+        self.bytes(&[0x85, 0xc9]);
+    }
+
+    /// `imul ecx, ecx, imm`
+    pub fn imul_ecx_imm(&mut self, imm: u32) {
+        self.bytes(&[0x69, 0xc9]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `add cell[rax], cl/cx/ecx`
+    pub fn add_cell_ecx(&mut self, width: CellWidth) {
+        let opcode: &[u8] = if width == CellWidth::U8 {
+            &[0x00]
+        } else {
+            &[0x01]
+        };
+        self.cell_operand(width, opcode, 1);
+    }
+
+    /// `imul edx, ecx, imm`
+    pub fn imul_edx_ecx_imm(&mut self, imm: u32) {
+        self.bytes(&[0x69, 0xd1]);
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    /// `add cell[rax], dl/dx/edx`
+    pub fn add_cell_edx(&mut self, width: CellWidth) {
+        let opcode: &[u8] = if width == CellWidth::U8 {
+            &[0x00]
+        } else {
+            &[0x01]
+        };
+        self.cell_operand(width, opcode, 2);
+    }
+
+    /// `sub cell[rax], cl/cx/ecx`
+    pub fn sub_cell_ecx(&mut self, width: CellWidth) {
+        let opcode: &[u8] = if width == CellWidth::U8 {
+            &[0x28]
+        } else {
+            &[0x29]
+        };
+        self.cell_operand(width, opcode, 1);
+    }
+
+    /// `mov rdi, r14; lea rsi, cell[rax]`: the arguments `(ctx, &cell)`.
+    pub fn cell_call_args(&mut self, width: CellWidth) {
+        self.bytes(&[0x4c, 0x89, 0xf7]);
+        let scale = match width {
+            CellWidth::U8 => 0,
+            CellWidth::U16 => 1,
+            CellWidth::U32 => 2,
+        };
+        self.bytes(&[0x48, 0x8d, 0x34, (scale << 6) | 0b011]);
+    }
+
+    /// `mov rdx, count`: the third argument, after [`cell_call_args`](Self::cell_call_args).
+    pub fn count_call_arg(&mut self, count: u64) {
+        self.bytes(&[0x48, 0xba]);
+        self.bytes(&count.to_le_bytes());
+    }
+
+    /// `mov rdi, r14; mov rsi, data; mov rdx, len`: the arguments
+    /// `(ctx, data, len)`.
+    pub fn slice_call_args(&mut self, data: u64, len: u64) {
+        self.bytes(&[0x4c, 0x89, 0xf7]);
+        self.bytes(&[0x48, 0xbe]);
+        self.bytes(&data.to_le_bytes());
+        self.bytes(&[0x48, 0xba]);
+        self.bytes(&len.to_le_bytes());
+    }
+
+    /// Calls the function at `addr`, then jumps to `on_error` if it returned
+    /// non-zero, leaving the status in `eax`.
+    pub fn call(&mut self, addr: u64, on_error: Label) {
+        self.bytes(&[0x48, 0xb8]);
+        self.bytes(&addr.to_le_bytes());
+        self.bytes(&[0xff, 0xd0]); // call rax
+        self.bytes(&[0x85, 0xc0]); // test eax, eax
+        self.jcc(Cond::NotEqual, on_error);
+    }
+
+    /// Resolves every jump and returns the machine code.
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(pos, label) in &self.fixups {
+            let target = self.labels[label.0].expect("jump to an unbound label");
+            let rel = target as i64 - (pos as i64 + 4);
+            self.code[pos..pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.code
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings() {
+        let mut asm = Assembler::new();
+        asm.cell_index(-1);
+        asm.add_cell_imm(CellWidth::U8, 3);
+        asm.mov_cell_imm(CellWidth::U16, 0x1234);
+        asm.cmp_cell_zero(CellWidth::U32);
+        asm.sub_cell_ecx(CellWidth::U8);
+        asm.imul_edx_ecx_imm(3);
+        asm.add_cell_edx(CellWidth::U16);
+        assert_eq!(
+            asm.finish(),
+            [
+                0x49, 0x8d, 0x84, 0x24, 0xff, 0xff, 0xff, 0xff, // lea rax, [r12 - 1]
+                0x80, 0x04, 0x03, 0x03, // add byte [rbx + rax], 3
+                0x66, 0xc7, 0x04, 0x43, 0x34, 0x12, // mov word [rbx + rax*2], 0x1234
+                0x83, 0x3c, 0x83, 0x00, // cmp dword [rbx + rax*4], 0
+                0x28, 0x0c, 0x03, // sub byte [rbx + rax], cl
+                0x69, 0xd1, 0x03, 0x00, 0x00, 0x00, // imul edx, ecx, 3
+                0x66, 0x01, 0x14, 0x43, // add word [rbx + rax*2], dx
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jumps_resolve() {
+        let mut asm = Assembler::new();
+        let top = asm.new_label();
+        let end = asm.new_label();
+        asm.bind(top);
+        asm.jcc(Cond::Equal, end);
+        asm.jmp(top);
+        asm.bind(end);
+        assert_eq!(
+            asm.finish(),
+            [0x0f, 0x84, 5, 0, 0, 0, 0xe9, 0xf5, 0xff, 0xff, 0xff]
+        );
+    }
+}
@@ -0,0 +1,321 @@
+//! Lowering of ops to x86-64 machine code, shared by the JIT and the ELF
+//! backend.
+//!
+//! The generated function takes `(tape, ptr, len, ctx)` in the System V
+//! argument registers, stores the final data pointer to the first word of
+//! `ctx` and returns one of the `STATUS_*` codes. On
+//! [`STATUS_OUT_OF_BOUNDS`] it also stores the index of the failing op, as a
+//! `u32`, to the second word. I/O goes through runtime
+//! functions called with a pointer to the cell (or, for constant output, a
+//! byte slice), which return non-zero on failure.
+
+pub(crate) mod asm;
+
+use std::io;
+
+use crate::Op;
+use crate::cell::CellWidth;
+use asm::{Assembler, Cond, Label};
+
+/// Status returned by generated code.
+pub(crate) const STATUS_OK: u32 = 0;
+pub(crate) const STATUS_IO_ERROR: u32 = 1;
+pub(crate) const STATUS_OUT_OF_BOUNDS: u32 = 2;
+
+/// Addresses of the functions generated code calls for I/O.
+pub(crate) struct Runtime {
+    /// `fn(ctx, *const cell) -> u32`
+    pub put_cell: u64,
+    /// `fn(ctx, *const u8, usize) -> u32`
+    pub put_bytes: u64,
+    /// `fn(ctx, *const cell, usize) -> u32`
+    pub put_repeat: u64,
+    /// `fn(ctx, *mut cell) -> u32`
+    pub get_cell: u64,
+}
+
+/// Compiles `ops`, which must have balanced loops, to a function for cells
+/// of `width`. `place` is given each `OutputConst` payload and returns the
+/// address the bytes will live at while the code runs. Fails if an offset
+/// doesn't fit the 32-bit displacements the code uses.
+pub(crate) fn compile(
+    ops: &[Op],
+    width: CellWidth,
+    runtime: &Runtime,
+    place: &mut dyn FnMut(&[u8]) -> u64,
+) -> io::Result<Vec<u8>> {
+    let mut asm = Assembler::new();
+    let out_of_bounds = asm.new_label();
+    let exit = asm.new_label();
+    asm.prologue();
+    let mut compiler = Compiler {
+        asm,
+        width,
+        runtime,
+        place,
+        loops: Vec::new(),
+        pc: 0,
+        bounds: Vec::new(),
+        exit,
+    };
+    for (pc, op) in ops.iter().enumerate() {
+        compiler.pc = u32::try_from(pc).map_err(|_| {
+            io::Error::new(io::ErrorKind::Unsupported, "too many ops for native code")
+        })?;
+        compiler.op(op)?;
+    }
+    let bounds = compiler.bounds;
+    let mut asm = compiler.asm;
+    asm.mov_eax_imm(STATUS_OK);
+    asm.jmp(exit);
+    for (stub, pc) in bounds {
+        asm.bind(stub);
+        asm.mov_eax_imm(pc);
+        asm.jmp(out_of_bounds);
+    }
+    asm.bind(out_of_bounds);
+    asm.store_ctx_pc();
+    asm.mov_eax_imm(STATUS_OUT_OF_BOUNDS);
+    asm.bind(exit);
+    asm.epilogue();
+    Ok(asm.finish())
+}
+
+struct Compiler<'a> {
+    asm: Assembler,
+    width: CellWidth,
+    runtime: &'a Runtime,
+    place: &'a mut dyn FnMut(&[u8]) -> u64,
+    /// `(body, end)` labels of the open loops.
+    loops: Vec<(Label, Label)>,
+    /// Index of the op being compiled.
+    pc: u32,
+    /// Where each op that checks a cell jumps when it is off the tape, and
+    /// the op's index.
+    bounds: Vec<(Label, u32)>,
+    exit: Label,
+}
+
+fn displacement(offset: isize) -> io::Result<i32> {
+    i32::try_from(offset).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("offset {} is too large for native code", offset),
+        )
+    })
+}
+
+impl Compiler<'_> {
+    /// Points `rax` at the cell `offset` away, bailing out if it is off the
+    /// tape.
+    fn cell(&mut self, offset: isize) -> io::Result<()> {
+        self.asm.cell_index(displacement(offset)?);
+        self.asm.cmp_index_len();
+        let stub = match self.bounds.last() {
+            Some(&(stub, pc)) if pc == self.pc => stub,
+            _ => {
+                let stub = self.asm.new_label();
+                self.bounds.push((stub, self.pc));
+                stub
+            }
+        };
+        self.asm.jcc(Cond::AboveEqual, stub);
+        Ok(())
+    }
+
+    /// Moves the pointer by `step` until it is on a zero cell. Every cell
+    /// visited is checked, so stepping off the tape fails like it does in
+    /// the interpreter.
+    fn scan(&mut self, step: isize) -> io::Result<()> {
+        self.sweep(step, false)
+    }
+
+    /// Like [`scan`](Self::scan), clearing each cell it passes over if
+    /// `clear`.
+    fn sweep(&mut self, step: isize, clear: bool) -> io::Result<()> {
+        let step = displacement(step)?;
+        let top = self.asm.new_label();
+        let done = self.asm.new_label();
+        self.asm.bind(top);
+        self.cell(0)?;
+        self.asm.cmp_cell_zero(self.width);
+        self.asm.jcc(Cond::Equal, done);
+        if clear {
+            self.asm.mov_cell_imm(self.width, 0);
+        }
+        self.asm.add_ptr(step);
+        self.asm.jmp(top);
+        self.asm.bind(done);
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op) -> io::Result<()> {
+        let width = self.width;
+        let truncate = |n: u32| width.truncate(n);
+        match op {
+            Op::PtrAdd(n) => self.asm.add_ptr(displacement(*n)?),
+            Op::ValAdd(offset, n) => {
+                self.cell(*offset)?;
+                self.asm.add_cell_imm(width, truncate(*n));
+            }
+            Op::ValSub(offset, n) => {
+                self.cell(*offset)?;
+                self.asm
+                    .add_cell_imm(width, truncate(0u32.wrapping_sub(*n)));
+            }
+            Op::Output | Op::OutputAt(_) => {
+                let offset = if let Op::OutputAt(offset) = op {
+                    *offset
+                } else {
+                    0
+                };
+                self.cell(offset)?;
+                self.asm.cell_call_args(width);
+                self.asm.call(self.runtime.put_cell, self.exit);
+            }
+            Op::Input | Op::InputAt(_) => {
+                let offset = if let Op::InputAt(offset) = op {
+                    *offset
+                } else {
+                    0
+                };
+                self.cell(offset)?;
+                self.asm.cell_call_args(width);
+                self.asm.call(self.runtime.get_cell, self.exit);
+            }
+            Op::OutputRepeat(offset, count) => {
+                self.cell(*offset)?;
+                self.asm.cell_call_args(width);
+                self.asm.count_call_arg(*count as u64);
+                self.asm.call(self.runtime.put_repeat, self.exit);
+            }
+            Op::OutputConst(bytes) => {
+                let addr = (self.place)(bytes);
+                self.asm.slice_call_args(addr, bytes.len() as u64);
+                self.asm.call(self.runtime.put_bytes, self.exit);
+            }
+            Op::Jz(_) => {
+                let body = self.asm.new_label();
+                let end = self.asm.new_label();
+                self.cell(0)?;
+                self.asm.cmp_cell_zero(width);
+                self.asm.jcc(Cond::Equal, end);
+                self.asm.bind(body);
+                self.loops.push((body, end));
+            }
+            Op::Jnz(_) => {
+                let (body, end) = self.loops.pop().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "unmatched ']' in native code")
+                })?;
+                self.cell(0)?;
+                self.asm.cmp_cell_zero(width);
+                self.asm.jcc(Cond::NotEqual, body);
+                self.asm.bind(end);
+            }
+            Op::Clear(offset) => {
+                self.cell(*offset)?;
+                self.asm.mov_cell_imm(width, 0);
+            }
+            Op::Set(offset, n) => {
+                self.cell(*offset)?;
+                self.asm.mov_cell_imm(width, truncate(*n));
+            }
+            Op::MulAdd(offset, _) | Op::AddTo(offset) | Op::SubFrom(offset) => {
+                // Like the interpreter, leave the target alone (and
+                // unchecked) when the source is zero.
+                let skip = self.asm.new_label();
+                self.cell(0)?;
+                self.asm.load_cell_ecx(width);
+                self.asm.test_ecx();
+                self.asm.jcc(Cond::Equal, skip);
+                if let Op::MulAdd(_, factor) = op {
+                    self.asm.imul_ecx_imm(truncate(*factor));
+                }
+                self.cell(*offset)?;
+                if let Op::SubFrom(_) = op {
+                    self.asm.sub_cell_ecx(width);
+                } else {
+                    self.asm.add_cell_ecx(width);
+                }
+                self.asm.bind(skip);
+            }
+            Op::Transfer(targets) => {
+                // The counter stays in ecx, each product going through edx.
+                let skip = self.asm.new_label();
+                self.cell(0)?;
+                self.asm.load_cell_ecx(width);
+                self.asm.test_ecx();
+                self.asm.jcc(Cond::Equal, skip);
+                for &(offset, factor) in targets {
+                    self.asm.imul_edx_ecx_imm(truncate(factor));
+                    self.cell(offset)?;
+                    self.asm.add_cell_edx(width);
+                }
+                self.cell(0)?;
+                self.asm.mov_cell_imm(width, 0);
+                self.asm.bind(skip);
+            }
+            Op::ScanLeft => self.scan(-1)?,
+            Op::ScanRight => self.scan(1)?,
+            Op::Scan(stride) => self.scan(*stride)?,
+            Op::ClearUntilZero(stride) => self.sweep(*stride, true)?,
+            Op::ClearRange(offset, n) => {
+                for offset in (0..*n as isize).map(|i| offset + i) {
+                    self.cell(offset)?;
+                    self.asm.mov_cell_imm(width, 0);
+                }
+            }
+            Op::Debug => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the `#` debug op isn't supported in native code",
+                ));
+            }
+            Op::DefProc(_) | Op::EndProc | Op::CallProc => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "pbrain procedures aren't supported in native code",
+                ));
+            }
+            Op::Fork => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Brainfork's forks aren't supported in native code",
+                ));
+            }
+            Op::SwitchTape(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "switching tapes isn't supported in native code",
+                ));
+            }
+            Op::Random => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "'?', the random byte, isn't supported in native code",
+                ));
+            }
+            // The loop after it gets there too.
+            Op::DivMod => {}
+            Op::End | Op::Store | Op::Retrieve | Op::Bitwise(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Extended Type I's commands aren't supported in native code",
+                ));
+            }
+            Op::BulkAdd(deltas) => {
+                for (offset, n) in deltas {
+                    self.cell(*offset)?;
+                    self.asm.add_cell_imm(width, truncate(*n));
+                }
+            }
+            Op::BulkClear(offsets) => {
+                for offset in offsets {
+                    self.cell(*offset)?;
+                    self.asm.mov_cell_imm(width, 0);
+                }
+            }
+        }
+        Ok(())
+    }
+}